@@ -0,0 +1,55 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tempdir::TempDir;
+use uplog::{devlog, session_init, Level};
+use uplog_tools::{CBORSequenceReader, ReadOptions, RecordWriter, Storage, StorageReader};
+
+const RECORDS: usize = 1000;
+
+/// kvの大きいレコードを持つセッション
+fn kv_heavy_fixture(storage: &Storage) {
+    let mut session = storage.create_session("heavy").unwrap();
+    let payload = "x".repeat(256);
+    for i in 0..RECORDS {
+        let r = devlog!(
+            Level::Info,
+            "bench::read",
+            "kv heavy record",
+            "index",
+            i as u64,
+            "payload",
+            payload.as_str(),
+            "samples",
+            (0..64_u64).collect::<Vec<_>>(),
+            "flag",
+            i % 2 == 0,
+            "ratio",
+            i as f64 / 3.0
+        );
+        session.push(&r).unwrap();
+    }
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    session_init();
+    let dir = TempDir::new("bench-read").unwrap();
+    let storage = Storage::new(dir.path()).unwrap();
+    kv_heavy_fixture(&storage);
+    let mut reader = CBORSequenceReader::new(dir.path().join("heavy")).unwrap();
+
+    let mut group = c.benchmark_group("read_page kv heavy");
+    group.throughput(Throughput::Elements(RECORDS as u64));
+    for lazy_kv in [false, true] {
+        let options = ReadOptions::default().lazy_kv(lazy_kv);
+        let name = if lazy_kv { "lazy" } else { "eager" };
+        group.bench_with_input(BenchmarkId::from_parameter(name), &options, |b, options| {
+            b.iter(|| {
+                let page = reader.read_page(0, RECORDS, *options).unwrap();
+                assert_eq!(page.records.len(), RECORDS);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,529 @@
+//! セッション単位のアクセス制御
+//!
+//! `--acl-file`でbearerトークンごとに扱えるセッション名のパターンと操作を決める。
+//! [`Authorize`]がリクエストのトークンから[`Scope`]を求めて付け、読み出しと変更の各APIがそれを確かめる。
+//! ファイルを指定しないか、トークンが1つもない場合は今まで通り誰でも全てのセッションを扱える
+//!
+//! トークンに`tenant`を指定すると、そのトークンはテナントの保存先だけを扱い、そのトークンで
+//! 受信したセッションもテナントの保存先に作る。`[[tenant]]`では受信パスのラベルをテナントに対応させる。
+//! テナントを指定しない場合は今まで通りデータディレクトリに直接置く
+//!
+//! ```toml
+//! [[token]]
+//! token = "team-a-secret"
+//! sessions = ["team-a-*"]
+//! permissions = ["read", "tail"]
+//!
+//! [[token]]
+//! token = "acme-secret"
+//! sessions = ["*"]
+//! permissions = ["read"]
+//! tenant = "acme"
+//!
+//! [[tenant]]
+//! id = "acme"
+//! labels = ["acme"]
+//! ```
+use std::{
+    collections::HashMap,
+    fmt, io,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::AUTHORIZATION,
+    Error, FromRequest, HttpMessage, HttpRequest,
+};
+use futures::future::{ok, Ready};
+
+use crate::{listing::glob_matches, tenant::is_valid_id};
+
+/// Operation on a session allowed by a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    /// list, read, search and download sessions
+    Read,
+    /// read the records after a cursor, following a live session
+    Tail,
+    /// change notes, tags and clients, and trim sessions
+    Mutate,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Tail => "tail",
+            Permission::Mutate => "mutate",
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Permission::Read),
+            "tail" => Ok(Permission::Tail),
+            "mutate" => Ok(Permission::Mutate),
+            _ => Err(format!("expected one of read, tail, mutate, got {}", s)),
+        }
+    }
+}
+
+/// Sessions and operations allowed to a token.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Grant {
+    /// session names as [`glob_matches`] patterns
+    pub sessions: Vec<String>,
+    pub permissions: Vec<Permission>,
+    /// storage root of the token, see [`crate::Storage::tenant`]. The data dir itself when `None`
+    pub tenant: Option<String>,
+}
+
+impl Grant {
+    pub fn allows(&self, name: &str, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+            && self.sessions.iter().any(|x| glob_matches(x, name))
+    }
+}
+
+/// Tokens of an ACL file and their grants.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    grants: HashMap<String, Arc<Grant>>,
+    /// テナントに対応させた受信パスのラベル
+    label_tenants: HashMap<String, String>,
+}
+
+fn invalid_acl<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// テナントIDを読む
+fn tenant_id<'a>(table: &'a toml_edit::Table, key: &str) -> io::Result<&'a str> {
+    table
+        .get(key)
+        .and_then(|x| x.as_str())
+        .filter(|x| is_valid_id(x))
+        .ok_or_else(|| {
+            invalid_acl(format!(
+                "{} must be a non-empty string without path separators",
+                key
+            ))
+        })
+}
+
+/// 文字列の配列を読む
+fn strings<'a>(table: &'a toml_edit::Table, key: &str) -> io::Result<Vec<&'a str>> {
+    let array = table
+        .get(key)
+        .and_then(|x| x.as_array())
+        .ok_or_else(|| invalid_acl(format!("{} must be an array of strings", key)))?;
+    array
+        .iter()
+        .map(|x| {
+            x.as_str()
+                .ok_or_else(|| invalid_acl(format!("{} must be an array of strings", key)))
+        })
+        .collect()
+}
+
+impl FromStr for Acl {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let doc = s.parse::<toml_edit::Document>().map_err(invalid_acl)?;
+        let mut acl = Self::default();
+        for (key, item) in doc.iter() {
+            if key != "token" && key != "tenant" {
+                return Err(invalid_acl(format!("unknown item {}", key)));
+            }
+            let tables = item
+                .as_array_of_tables()
+                .ok_or_else(|| invalid_acl(format!("{} must be an array of tables", key)))?;
+            if key == "tenant" {
+                for table in tables.iter() {
+                    let id = tenant_id(table, "id")?;
+                    for label in strings(table, "labels")? {
+                        acl = acl.label_tenant(label, id);
+                    }
+                }
+                continue;
+            }
+            for table in tables.iter() {
+                let token = table
+                    .get("token")
+                    .and_then(|x| x.as_str())
+                    .filter(|x| !x.is_empty())
+                    .ok_or_else(|| invalid_acl("token must be a non-empty string"))?;
+                let grant = Grant {
+                    sessions: strings(table, "sessions")?
+                        .into_iter()
+                        .map(String::from)
+                        .collect(),
+                    permissions: strings(table, "permissions")?
+                        .into_iter()
+                        .map(|x| x.parse().map_err(invalid_acl))
+                        .collect::<io::Result<_>>()?,
+                    tenant: table
+                        .contains_key("tenant")
+                        .then(|| tenant_id(table, "tenant").map(String::from))
+                        .transpose()?,
+                };
+                if acl.grants.contains_key(token) {
+                    return Err(invalid_acl("duplicate token"));
+                }
+                acl = acl.grant(token, grant);
+            }
+        }
+        Ok(acl)
+    }
+}
+
+impl Acl {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        std::fs::read_to_string(path.as_ref())?
+            .parse()
+            .map_err(|e: io::Error| {
+                io::Error::new(e.kind(), format!("{}: {}", path.as_ref().display(), e))
+            })
+    }
+
+    /// Adds or replaces the grant of `token`.
+    pub fn grant(mut self, token: &str, grant: Grant) -> Self {
+        self.grants.insert(token.to_string(), Arc::new(grant));
+        self
+    }
+
+    /// Creates the sessions received on the ingest path labeled `label` in the storage of
+    /// `tenant`.
+    pub fn label_tenant(mut self, label: &str, tenant: &str) -> Self {
+        self.label_tenants
+            .insert(label.to_string(), tenant.to_string());
+        self
+    }
+
+    /// Tenant of the ingest path labeled `label`.
+    pub fn tenant_of_label(&self, label: &str) -> Option<&str> {
+        self.label_tenants.get(label).map(String::as_str)
+    }
+
+    /// No tokens, so every request may access everything.
+    pub fn is_empty(&self) -> bool {
+        self.grants.is_empty()
+    }
+
+    /// Scope of a request with the bearer `token`.
+    pub fn scope(&self, token: Option<&str>) -> Scope {
+        if self.is_empty() {
+            return Scope::Open;
+        }
+        match token.and_then(|x| self.grants.get(x)) {
+            Some(x) => Scope::Granted(x.clone()),
+            None => Scope::Unauthenticated,
+        }
+    }
+}
+
+/// What a request may access.
+///
+/// Handlers take it as an extractor. It is [`Scope::Open`] without the [`Authorize`] middleware.
+#[derive(Debug, Clone, Default)]
+pub enum Scope {
+    /// no ACL is configured
+    #[default]
+    Open,
+    Granted(Arc<Grant>),
+    /// the ACL does not know the token, or there is none
+    Unauthenticated,
+}
+
+/// Access out of the [`Scope`] of a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessDenied {
+    Unauthenticated,
+    Forbidden {
+        session: String,
+        permission: Permission,
+    },
+}
+
+impl AccessDenied {
+    /// `code` of the GraphQL error and the HTTP response
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccessDenied::Unauthenticated => "UNAUTHENTICATED",
+            AccessDenied::Forbidden { .. } => "FORBIDDEN",
+        }
+    }
+}
+
+impl fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessDenied::Unauthenticated => write!(f, "a valid bearer token is required"),
+            AccessDenied::Forbidden {
+                session,
+                permission,
+            } => write!(
+                f,
+                "{} of session {} is not allowed",
+                permission.as_str(),
+                session
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AccessDenied {}
+
+impl Scope {
+    pub fn allows(&self, name: &str, permission: Permission) -> bool {
+        match self {
+            Scope::Open => true,
+            Scope::Granted(x) => x.allows(name, permission),
+            Scope::Unauthenticated => false,
+        }
+    }
+
+    /// Tenant whose storage the request accesses. The data dir itself when `None`.
+    pub fn tenant(&self) -> Option<&str> {
+        match self {
+            Scope::Granted(x) => x.tenant.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Fails unless the request has a known token or no ACL is configured.
+    pub fn authenticate(&self) -> Result<(), AccessDenied> {
+        match self {
+            Scope::Unauthenticated => Err(AccessDenied::Unauthenticated),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check(&self, name: &str, permission: Permission) -> Result<(), AccessDenied> {
+        self.authenticate()?;
+        if self.allows(name, permission) {
+            Ok(())
+        } else {
+            Err(AccessDenied::Forbidden {
+                session: name.to_string(),
+                permission,
+            })
+        }
+    }
+
+    /// Session name patterns for [`crate::SessionQuery::name_globs`] to list the sessions
+    /// allowed `permission`. `None` when every session is.
+    pub fn session_globs(&self, permission: Permission) -> Option<Vec<String>> {
+        match self {
+            Scope::Open => None,
+            Scope::Granted(x) if x.permissions.contains(&permission) => Some(x.sessions.clone()),
+            _ => Some(Vec::new()),
+        }
+    }
+}
+
+impl FromRequest for Scope {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ok(req.extensions().get::<Scope>().cloned().unwrap_or_default())
+    }
+}
+
+/// `Authorization: Bearer <token>`のトークン
+fn bearer_token(req: &ServiceRequest) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Middleware that gives each request the [`Scope`] of its bearer token.
+///
+/// It does not reject requests by itself, so the ingest routes stay open.
+#[derive(Debug, Clone)]
+pub struct Authorize {
+    acl: Arc<Acl>,
+}
+
+impl Authorize {
+    pub fn new(acl: Acl) -> Self {
+        Self { acl: Arc::new(acl) }
+    }
+}
+
+impl<S, B> Transform<S> for Authorize
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AuthorizeMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuthorizeMiddleware {
+            service,
+            acl: self.acl.clone(),
+        })
+    }
+}
+
+/// Service of [`Authorize`].
+pub struct AuthorizeMiddleware<S> {
+    service: S,
+    acl: Arc<Acl>,
+}
+
+impl<S, B> Service for AuthorizeMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let scope = self.acl.scope(bearer_token(&req));
+        req.extensions_mut().insert(scope);
+        self.service.call(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessDenied, Acl, Grant, Permission, Scope};
+
+    const ACL: &str = r#"
+[[token]]
+token = "a"
+sessions = ["team-a-*"]
+permissions = ["read", "tail"]
+
+[[token]]
+token = "admin"
+sessions = ["*"]
+permissions = ["read", "tail", "mutate"]
+"#;
+
+    #[test]
+    fn test_parse_acl() {
+        let acl = ACL.parse::<Acl>().unwrap();
+        assert!(!acl.is_empty());
+        let a = acl.scope(Some("a"));
+        assert!(a.allows("team-a-1", Permission::Read));
+        assert!(a.allows("team-a-1", Permission::Tail));
+        assert!(!a.allows("team-a-1", Permission::Mutate));
+        assert!(!a.allows("team-b-1", Permission::Read));
+        assert_eq!(
+            a.check("team-b-1", Permission::Read),
+            Err(AccessDenied::Forbidden {
+                session: "team-b-1".to_string(),
+                permission: Permission::Read
+            })
+        );
+        assert_eq!(
+            a.session_globs(Permission::Read),
+            Some(vec!["team-a-*".to_string()])
+        );
+        assert_eq!(a.session_globs(Permission::Mutate), Some(Vec::new()));
+        assert!(acl
+            .scope(Some("admin"))
+            .check("anything", Permission::Mutate)
+            .is_ok());
+
+        // 知らないトークンとトークンなし
+        for scope in [acl.scope(Some("b")), acl.scope(None)] {
+            assert_eq!(scope.authenticate(), Err(AccessDenied::Unauthenticated));
+            assert!(!scope.allows("team-a-1", Permission::Read));
+        }
+
+        // 空のファイルは誰でも全て扱える
+        let acl = "".parse::<Acl>().unwrap();
+        assert!(acl.is_empty());
+        assert!(matches!(acl.scope(None), Scope::Open));
+        assert!(acl.scope(None).check("x", Permission::Mutate).is_ok());
+        assert_eq!(acl.scope(None).session_globs(Permission::Read), None);
+
+        let acl = Acl::default().grant(
+            "t",
+            Grant {
+                sessions: vec!["x".to_string()],
+                permissions: vec![Permission::Mutate],
+                tenant: None,
+            },
+        );
+        assert!(acl.scope(Some("t")).allows("x", Permission::Mutate));
+    }
+
+    #[test]
+    fn test_parse_tenants() {
+        let acl = r#"
+[[token]]
+token = "acme"
+sessions = ["*"]
+permissions = ["read"]
+tenant = "acme"
+
+[[token]]
+token = "admin"
+sessions = ["*"]
+permissions = ["read"]
+
+[[tenant]]
+id = "acme"
+labels = ["acme", "acme-staging"]
+"#
+        .parse::<Acl>()
+        .unwrap();
+        assert_eq!(acl.scope(Some("acme")).tenant(), Some("acme"));
+        assert_eq!(acl.scope(Some("admin")).tenant(), None);
+        assert_eq!(acl.scope(None).tenant(), None);
+        assert_eq!(acl.tenant_of_label("acme-staging"), Some("acme"));
+        assert_eq!(acl.tenant_of_label("other"), None);
+
+        // トークンなしでもラベルの対応は使える
+        let acl = "[[tenant]]\nid = \"acme\"\nlabels = [\"acme\"]"
+            .parse::<Acl>()
+            .unwrap();
+        assert!(acl.is_empty());
+        assert_eq!(acl.tenant_of_label("acme"), Some("acme"));
+    }
+
+    #[test]
+    fn test_invalid_acl() {
+        for s in [
+            "token = 1",
+            "[[token]]\nsessions = [\"*\"]\npermissions = [\"read\"]",
+            "[[token]]\ntoken = \"a\"\nsessions = \"*\"\npermissions = [\"read\"]",
+            "[[token]]\ntoken = \"a\"\nsessions = [\"*\"]\npermissions = [\"write\"]",
+            "[[token]]\ntoken = \"a\"\nsessions = [\"*\"]\npermissions = []\n[[token]]\ntoken = \"a\"\nsessions = [\"*\"]\npermissions = []",
+            "[users]",
+            "[[token]]\ntoken = \"a\"\nsessions = [\"*\"]\npermissions = []\ntenant = \"../x\"",
+            "[[tenant]]\nid = \"\"\nlabels = [\"a\"]",
+            "[[tenant]]\nid = \"a\"",
+        ] {
+            assert!(s.parse::<Acl>().is_err(), "{}", s);
+        }
+    }
+}
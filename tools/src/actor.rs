@@ -1,23 +1,428 @@
-use crate::{writer::RecordWriter, Session, Storage};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    acl::Scope,
+    attachment::Assembler,
+    decode::{DecodeError, DecodeLimits, FrameDecoder},
+    diskwatch::DiskGuard,
+    health::HealthScan,
+    ingest::{IngestContext, IngestPipeline},
+    kvtype::KvTypeRegistry,
+    lifecycle::{closed_record, count_close, opened_record, CloseReason},
+    meta::BuildInfo,
+    reader::render_diagnostic,
+    retry::{RetryPolicy, RetryQueue},
+    Session, Storage,
+};
 use actix::prelude::*;
+use actix_web::{web, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
+use async_graphql::SimpleObject;
 use log::{debug, error, info, warn};
-use uplog::Record;
+use uplog::{
+    precision::Precision,
+    protocol::{
+        self, Ack, Codec, ControlCommand, DecodeErrorReport, ServerMessage, BUILD_INFO_HEADER,
+        CLIENT_TIME_HEADER, SESSION_QUERY, SUBPROTOCOL_HEADER, TIME_PRECISION_HEADER,
+    },
+    wire::WireDecoder,
+};
 use uuid::Uuid;
 
+/// デコードできなかったバイト列をログに出す上限
+const DECODE_DUMP_BYTES: usize = 64;
+
+/// WebSocketでレコードを受け付けるパスと、そこで作るセッションに付けるラベル
+///
+/// `PATH[=LABEL]`の形式で指定する
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IngestEndpoint {
+    pub path: String,
+    pub label: Option<String>,
+    /// 廃止予定のパス。接続があれば警告する
+    pub deprecated: bool,
+    /// セッションを作るテナント。なければトークンのテナント
+    pub tenant: Option<String>,
+}
+
+impl IngestEndpoint {
+    pub fn new<S: Into<String>>(path: S) -> Self {
+        Self {
+            path: path.into(),
+            label: None,
+            deprecated: false,
+            tenant: None,
+        }
+    }
+
+    pub fn label<S: Into<String>>(mut self, label: S) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// このパスで受信したセッションを`tenant`の保存先に作る
+    pub fn tenant<S: Into<String>>(mut self, tenant: S) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    /// 以前の受信パス。1リリースの間だけ残す
+    pub fn legacy() -> Self {
+        Self {
+            deprecated: true,
+            ..Self::new(uplog::WS_PATH)
+        }
+    }
+
+    /// 指定したパスに以前のパスを加える。同じパスを指定していれば加えない
+    pub fn with_legacy(mut endpoints: Vec<Self>) -> Vec<Self> {
+        let legacy = Self::legacy();
+        if endpoints.iter().all(|x| x.path != legacy.path) {
+            endpoints.push(legacy);
+        }
+        endpoints
+    }
+
+    /// このパスで[`ws_index`]に繋ぐ
+    pub fn resource(&self) -> actix_web::Resource {
+        web::resource(self.path.as_str())
+            .data(self.clone())
+            .route(web::get().to(ws_index))
+    }
+}
+
+impl Default for IngestEndpoint {
+    fn default() -> Self {
+        Self::new(uplog::INGEST_PATH)
+    }
+}
+
+impl FromStr for IngestEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, label) = match s.split_once('=') {
+            Some((path, label)) => (path, Some(label)),
+            None => (s, None),
+        };
+        if !path.starts_with('/') {
+            return Err(format!("ingest path {} does not start with '/'", path));
+        }
+        if label.is_some_and(|x| x.is_empty()) {
+            return Err(format!("empty label for ingest path {}", path));
+        }
+        Ok(Self {
+            path: path.to_string(),
+            label: label.map(String::from),
+            deprecated: false,
+            tenant: None,
+        })
+    }
+}
+
+/// Handle websocket request
+pub async fn ws_index(
+    req: HttpRequest,
+    stream: web::Payload,
+    srv: web::Data<Addr<StorageActor>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let ip_addr: String = req
+        .connection_info()
+        .realip_remote_addr()
+        .map(String::from)
+        .unwrap_or_else(|| String::from("unknown"));
+    let policy = req
+        .app_data::<web::Data<DecodePolicy>>()
+        .map(|x| *x.get_ref())
+        .unwrap_or_default();
+    let ingest = req
+        .app_data::<web::Data<IngestPipeline>>()
+        .map(|x| x.get_ref().clone())
+        .unwrap_or_default();
+    let limits = req
+        .app_data::<web::Data<DecodeLimits>>()
+        .map(|x| *x.get_ref())
+        .unwrap_or_default();
+    let idle_timeout = req
+        .app_data::<web::Data<IdleTimeout>>()
+        .map(|x| x.get_ref().0);
+    let quota = req
+        .app_data::<web::Data<ByteQuota>>()
+        .map(|x| x.get_ref().0);
+    let disk = req
+        .app_data::<web::Data<DiskGuard>>()
+        .map(|x| x.get_ref().clone());
+    let handshake = req
+        .app_data::<web::Data<HandshakePolicy>>()
+        .map(|x| *x.get_ref())
+        .unwrap_or_default();
+    // IngestEndpoint::resourceで登録していなければラベルはない
+    let endpoint = req
+        .app_data::<web::Data<IngestEndpoint>>()
+        .map(|x| x.get_ref().clone());
+    if endpoint.as_ref().is_some_and(|x| x.deprecated) {
+        warn!(
+            "{} connected to the deprecated path {}, use {}",
+            ip_addr,
+            req.path(),
+            uplog::INGEST_PATH
+        );
+    }
+    // 受信パスのテナントを優先し、なければ接続のトークンのテナントにする
+    let tenant = endpoint
+        .as_ref()
+        .and_then(|x| x.tenant.clone())
+        .or_else(|| {
+            req.extensions()
+                .get::<Scope>()
+                .and_then(|x| x.tenant().map(String::from))
+        });
+    // 古いクライアントは送ってこない
+    let client_session = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|x| x.get(SESSION_QUERY).and_then(|x| Uuid::parse_str(x).ok()));
+    // デフォルトでは64KBのペイロードのため拡張する
+    let max_size = uplog::DEFAULT_BUFFER_SIZE.max(limits.max_record_bytes);
+    // subprotocolを提示しない古いクライアントは従来のCBORとする
+    let offered = req
+        .headers()
+        .get(SUBPROTOCOL_HEADER)
+        .and_then(|x| x.to_str().ok());
+    let (mut res, codec) = match offered {
+        None => (ws::handshake(&req)?, Codec::Cbor),
+        Some(offered) => match Codec::negotiate(offered, &Codec::ALL) {
+            Some(codec) => (
+                ws::handshake_with_protocols(&req, &[codec.subprotocol()])?,
+                codec,
+            ),
+            None => {
+                warn!("no supported subprotocol from {}: {}", ip_addr, offered);
+                return Ok(HttpResponse::BadRequest().body(format!(
+                    "unsupported subprotocol: offered [{}], supported [{}]",
+                    offered,
+                    Codec::offer(&Codec::ALL)
+                )));
+            }
+        },
+    };
+    // 単位を受け付けたことを同じ値を返して伝える
+    let precision = req
+        .headers()
+        .get(TIME_PRECISION_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<Precision>().ok());
+    if let Some(precision) = precision {
+        res.header(TIME_PRECISION_HEADER, precision.as_str());
+    }
+    // クライアントの時計のずれ。時刻を送ってきたクライアントには応答に時刻を入れて返す
+    let clock_offset_ms = req
+        .headers()
+        .get(CLIENT_TIME_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<i64>().ok())
+        .map(|x| chrono::Utc::now().timestamp_millis().saturating_sub(x));
+    // 知らないキーは無視する
+    let build_info = req
+        .headers()
+        .get(BUILD_INFO_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| web::Query::<BuildInfo>::from_query(x).ok())
+        .map(web::Query::into_inner);
+    debug!("accept {} with {}", ip_addr, codec.subprotocol());
+    let actor = WsConn::new(Uuid::new_v4(), ip_addr, srv.get_ref().clone().recipient())
+        .registry(srv.get_ref().clone().recipient())
+        .client_session(client_session)
+        .decode_policy(policy)
+        .decode_limits(limits)
+        .ingest(ingest)
+        .idle_timeout(idle_timeout)
+        .byte_quota(quota)
+        .disk_guard(disk)
+        .handshake_policy(handshake)
+        .time_precision(precision)
+        .clock_offset(clock_offset_ms)
+        .label(endpoint.and_then(|x| x.label))
+        .tenant(tenant)
+        .build_info(build_info)
+        .codec(codec, max_size);
+    let codec = actix_http::ws::Codec::new().max_size(max_size);
+    let out_stream = ws::WebsocketContext::with_codec(actor, stream, codec);
+    let res = res.streaming(out_stream);
+    Ok(res)
+}
+
+/// この時間何も受け取らなかった接続を閉じる。app_dataに登録する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleTimeout(pub Duration);
+
+/// 1接続で受け取るメッセージの合計バイト数の上限。超えたら閉じる。app_dataに登録する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteQuota(pub u64);
+
+/// 最初のレコードを受け取るまでの扱い。app_dataに登録する
+///
+/// セッションは最初のレコードを受け取ってから作るので、
+/// uplogのクライアントではない接続はディレクトリを残さずに閉じる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakePolicy {
+    /// この時間内にレコードが届かなければ閉じる
+    pub grace: Duration,
+    /// 最初のレコードより前にこの数のメッセージを解釈できなければ閉じる
+    pub max_invalid_frames: u64,
+}
+
+impl Default for HandshakePolicy {
+    fn default() -> Self {
+        Self {
+            grace: Duration::from_secs(10),
+            max_invalid_frames: 3,
+        }
+    }
+}
+
+static REJECTED_HANDSHAKES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of websocket connections closed before sending a valid record.
+pub fn rejected_handshakes() -> u64 {
+    REJECTED_HANDSHAKES.load(Ordering::Relaxed)
+}
+
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static SESSION_ACTORS: AtomicUsize = AtomicUsize::new(0);
+/// 送った側で足し、書いた側で引く。テストで直接送った分は負になりうる
+static QUEUED_RECORDS: AtomicI64 = AtomicI64::new(0);
+static ROUTED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+static LIVE_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// 値が生きている間だけ数える
+struct Counted(&'static AtomicUsize);
+
+impl Counted {
+    fn new(counter: &'static AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What the server holds for its connections now. These return to zero when all clients are gone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, SimpleObject)]
+pub struct ActorGauges {
+    /// open WebSocket and Unix domain socket connections
+    pub connections: u64,
+    /// sessions being written
+    pub session_actors: u64,
+    /// records sent to the sessions and not written yet
+    pub queued_records: u64,
+    /// sessions being written with the connection that receives their control commands
+    pub routed_clients: u64,
+    /// client sessions that a new connection can take over
+    pub live_sessions: u64,
+}
+
+pub fn actor_gauges() -> ActorGauges {
+    ActorGauges {
+        connections: CONNECTIONS.load(Ordering::Relaxed) as u64,
+        session_actors: SESSION_ACTORS.load(Ordering::Relaxed) as u64,
+        queued_records: QUEUED_RECORDS.load(Ordering::Relaxed).max(0) as u64,
+        routed_clients: ROUTED_CLIENTS.load(Ordering::Relaxed) as u64,
+        live_sessions: LIVE_SESSIONS.load(Ordering::Relaxed) as u64,
+    }
+}
+
+/// クライアントから受け取ったデータを解釈できなかった場合の扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodePolicy {
+    /// クライアントに失敗を報告する最短の間隔
+    pub report_interval: Duration,
+    /// 連続して失敗したらprotocol errorとして切断する
+    pub max_consecutive_failures: u64,
+}
+
+impl Default for DecodePolicy {
+    fn default() -> Self {
+        Self {
+            report_interval: Duration::from_secs(5),
+            max_consecutive_failures: 10,
+        }
+    }
+}
+
+/// 同じクライアントのセッションIDで接続中の接続がある場合の扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// 新しい接続を閉じる
+    Reject,
+    /// 古い接続を閉じ、書き込み中のセッションを新しい接続で引き継ぐ
+    Takeover,
+    /// それぞれ別のセッションに書き込む
+    #[default]
+    Parallel,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "takeover" => Ok(Self::Takeover),
+            "parallel" => Ok(Self::Parallel),
+            _ => Err(format!(
+                "unknown duplicate policy {}, expected reject, takeover or parallel",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct StorageRequest {
-    addr: Recipient<StorageResponse>,
-    self_id: Uuid,
-    /// TODO store session info
-    remote_addr: String,
+    pub(crate) addr: Recipient<StorageResponse>,
+    /// 接続中のクライアントに設定変更を送る宛先
+    pub(crate) control: Recipient<ClientControl>,
+    /// 同じクライアントの新しい接続に引き継ぐときに閉じる宛先
+    pub(crate) takeover: Recipient<Takeover>,
+    pub(crate) self_id: Uuid,
+    /// クライアントが送ってきたセッションID
+    pub(crate) client_session: Option<Uuid>,
+    pub(crate) remote_addr: String,
+    /// 開始のレコードに書く
+    pub(crate) codec: Codec,
+    /// セッションの付加情報に書く
+    pub(crate) time_precision: Option<Precision>,
+    /// ハンドシェイクで求めたクライアントの時計のずれ。セッションの付加情報に書く
+    pub(crate) clock_offset_ms: Option<i64>,
+    /// 接続した受信パスのラベル。セッションの付加情報に書く
+    pub(crate) label: Option<String>,
+    /// セッションを作るテナント。なければデータディレクトリに直接作る
+    pub(crate) tenant: Option<String>,
+    /// クライアントのセッションの開始時刻。セッションの付加情報に書く
+    pub(crate) start_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// ハンドシェイクで受け取ったクライアントのビルド情報。セッションの付加情報に書く
+    pub(crate) build_info: Option<BuildInfo>,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub enum StorageResponse {
     Accept(Recipient<SessionCommand>),
+    /// 重複した接続を受け付けない
+    Reject(String),
     Error(String),
 }
 
@@ -25,171 +430,2423 @@ pub enum StorageResponse {
 #[rtype(result = "()")]
 pub enum SessionCommand {
     Record(uplog::Record),
-    Close,
+    /// 接続がデコードできなかったメッセージ数。閉じる前に送る
+    DecodeFailures(u64),
+    Close(CloseReason),
+}
+
+/// セッションを書き終えたので接続中のクライアントの登録を除く
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SessionEnded {
+    /// 登録したときのセッション名
+    pub(crate) name: String,
+}
+
+/// 新しい接続にセッションを引き継いだので閉じる
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Takeover;
+
+/// 接続中のクライアントに送る設定変更
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClientControl(pub ControlCommand);
+
+/// 運用者の求めで接続を閉じる
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect;
+
+/// 接続ごとに受け取った量。接続が足し、一覧を返すときに読む
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionCounters {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+    /// 最後に受け取ったUNIX時刻(ミリ秒)。0は未受信
+    last_activity_ms: AtomicI64,
+}
+
+impl ConnectionCounters {
+    fn received(&self, len: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn touch(&self) {
+        self.last_activity_ms
+            .store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+}
+
+/// 接続の一覧を更新する
+#[derive(Message)]
+#[rtype(result = "()")]
+pub enum ConnectionEvent {
+    Opened(Box<ConnectionEntry>),
+    Closed(Uuid),
+}
+
+/// 一覧に載せる接続
+pub struct ConnectionEntry {
+    id: Uuid,
+    remote_addr: String,
+    client_session: Option<Uuid>,
+    tenant: Option<String>,
+    connected_at: chrono::DateTime<chrono::Utc>,
+    /// 書き込み先のセッション名。最初のレコードを受け取るまではない
+    session: Option<String>,
+    counters: Arc<ConnectionCounters>,
+    disconnect: Recipient<Disconnect>,
+}
+
+impl ConnectionEntry {
+    fn view(&self) -> LiveConnection {
+        let last_activity_ms = self.counters.last_activity_ms.load(Ordering::Relaxed);
+        LiveConnection {
+            uuid: self.id,
+            client_ip: self.remote_addr.clone(),
+            client_session: self.client_session,
+            session: self.session.clone(),
+            tenant: self.tenant.clone(),
+            connected_at: self.connected_at,
+            frames: self.counters.frames.load(Ordering::Relaxed),
+            bytes: self.counters.bytes.load(Ordering::Relaxed),
+            last_activity_at: (last_activity_ms > 0)
+                .then(|| chrono::DateTime::from_timestamp_millis(last_activity_ms))
+                .flatten(),
+        }
+    }
+}
+
+/// A WebSocket connection open now.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveConnection {
+    /// id of the connection, also the name of the session it created
+    pub uuid: Uuid,
+    pub client_ip: String,
+    /// id the client sends on every connection, when it sent one
+    pub client_session: Option<Uuid>,
+    /// session being written; none until the first record
+    pub session: Option<String>,
+    pub tenant: Option<String>,
+    pub connected_at: chrono::DateTime<chrono::Utc>,
+    /// messages received
+    pub frames: u64,
+    /// bytes of the messages received
+    pub bytes: u64,
+    /// when the last message arrived; none before the first one
+    pub last_activity_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// 接続中の一覧を返すか、1つを閉じさせる
+#[derive(Message)]
+#[rtype(result = "Result<Vec<LiveConnection>, String>")]
+pub enum LiveConnections {
+    /// 接続した順
+    List,
+    /// 閉じさせた接続を返す
+    Disconnect(Uuid),
+}
+
+/// セッション名で指定したクライアントに設定変更を送る
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct RouteControl {
+    pub session: String,
+    /// テナントのセッションの場合はそのテナント
+    pub tenant: Option<String>,
+    pub command: ControlCommand,
 }
 
 pub struct StorageActor {
     storage: Storage,
+    blob_threshold: Option<usize>,
+    split_on_boundary: bool,
+    duplicate_policy: DuplicatePolicy,
+    retry_policy: RetryPolicy,
+    /// セッション名ごとの書き込み中のセッションと接続中のクライアント
+    clients: HashMap<String, ClientRoute>,
+    /// クライアントのセッションIDごとの書き込み中の接続
+    live: HashMap<Uuid, LiveSession>,
+    /// 接続中の全ての接続
+    connections: HashMap<Uuid, ConnectionEntry>,
+}
+
+/// 切断を見回る間隔
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+struct ClientRoute {
+    /// 設定変更を送る接続。引き継いだら新しい接続に替える
+    control: Recipient<ClientControl>,
+    /// セッションが止まるまで持っておく。
+    /// 送り手が全ていなくなると、actixは受け取ったレコードを書く前にセッションを止めるため
+    session: Recipient<SessionCommand>,
+}
+
+struct LiveSession {
+    /// 書き込み先のセッション名
+    name: String,
+    session: Recipient<SessionCommand>,
+    conn: Recipient<Takeover>,
+}
+
+impl LiveSession {
+    fn is_live(&self) -> bool {
+        self.session.connected() && self.conn.connected()
+    }
 }
 
 impl StorageActor {
     pub fn new(storage: Storage) -> Self {
-        Self { storage }
+        Self {
+            storage,
+            blob_threshold: None,
+            split_on_boundary: false,
+            duplicate_policy: DuplicatePolicy::default(),
+            retry_policy: RetryPolicy::default(),
+            clients: HashMap::new(),
+            live: HashMap::new(),
+            connections: HashMap::new(),
+        }
+    }
+
+    /// このバイト数より大きい`Value::Bytes`をレコードから分離して保存する
+    pub fn blob_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.blob_threshold = threshold;
+        self
+    }
+
+    /// 区切りのレコードを受け取ったら新しいセッションに切り替える
+    pub fn split_on_boundary(mut self, split: bool) -> Self {
+        self.split_on_boundary = split;
+        self
+    }
+
+    pub fn duplicate_policy(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicate_policy = policy;
+        self
+    }
+
+    /// 書き込みに失敗したレコードを書き直す方針
+    pub fn write_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// 終了を知らせずに切断した接続のセッションを閉じる。登録はセッションが止まってから除く
+    fn sweep(&mut self) {
+        for route in self.clients.values().filter(|x| !x.control.connected()) {
+            route
+                .session
+                .do_send(SessionCommand::Close(CloseReason::ConnectionLost))
+                .ok();
+        }
+        self.live.retain(|_, x| x.is_live());
+        // 止まるときに知らせずに消えた接続
+        self.connections.retain(|_, x| x.disconnect.connected());
+        self.update_gauges();
+    }
+
+    fn update_gauges(&self) {
+        ROUTED_CLIENTS.store(self.clients.len(), Ordering::Relaxed);
+        LIVE_SESSIONS.store(self.live.len(), Ordering::Relaxed);
+    }
+
+    /// 同じクライアントの接続中の接続があれば方針に従って応答を返す
+    fn handle_duplicate(&mut self, msg: &StorageRequest) -> Option<StorageResponse> {
+        let client_session = msg.client_session?;
+        let live = self.live.get_mut(&client_session)?;
+        match self.duplicate_policy {
+            DuplicatePolicy::Reject => {
+                warn!(
+                    "reject duplicate connection [{}] of client {}",
+                    msg.self_id, client_session
+                );
+                Some(StorageResponse::Reject(format!(
+                    "client {} is already connected",
+                    client_session
+                )))
+            }
+            DuplicatePolicy::Takeover => {
+                info!(
+                    "connection [{}] takes over session {} of client {}",
+                    msg.self_id, live.name, client_session
+                );
+                live.conn.do_send(Takeover).ok();
+                live.conn = msg.takeover.clone();
+                if let Some(x) = self.connections.get_mut(&msg.self_id) {
+                    x.session = Some(live.name.clone());
+                }
+                self.clients.insert(
+                    live.name.clone(),
+                    ClientRoute {
+                        control: msg.control.clone(),
+                        session: live.session.clone(),
+                    },
+                );
+                Some(StorageResponse::Accept(live.session.clone()))
+            }
+            DuplicatePolicy::Parallel => None,
+        }
     }
 
-    pub fn get_session(&self, uuid: Uuid) -> std::io::Result<Session> {
-        self.storage.create_session(uuid.to_string().as_str())
+    /// `elapsed`を整数で受け取る場合はその単位を、時計のずれや受信パスのラベル、開始時刻、ビルド情報がわかる場合はその値を付加情報に残す
+    pub fn get_session(&self, msg: &StorageRequest) -> std::io::Result<Session> {
+        let name = msg.self_id.to_string();
+        let storage = self.storage.scoped(msg.tenant.as_deref())?;
+        // 一覧から途中まで書いた付加情報が見えないように1回で書く
+        storage.create_session_with(&name, |meta| {
+            if let Some(precision) = msg.time_precision {
+                meta.time_precision = Some(precision.as_str().to_string());
+            }
+            if let Some(offset) = msg.clock_offset_ms {
+                meta.clock_offset_ms = Some(offset);
+            }
+            if let Some(label) = msg.label.as_deref() {
+                meta.label = Some(label.to_string());
+            }
+            if let Some(start_at) = msg.start_at {
+                meta.start_at = Some(start_at);
+            }
+            if let Some(build_info) = msg.build_info.as_ref() {
+                meta.build_info = Some(build_info.clone());
+            }
+        })
     }
 }
 
 impl Actor for StorageActor {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(SWEEP_INTERVAL, |act, _| act.sweep());
+    }
 }
 
 impl Handler<StorageRequest> for StorageActor {
     type Result = ();
 
-    fn handle(&mut self, msg: StorageRequest, _ctx: &mut Self::Context) -> Self::Result {
-        let res = match self.get_session(msg.self_id) {
+    fn handle(&mut self, msg: StorageRequest, ctx: &mut Self::Context) -> Self::Result {
+        self.sweep();
+        if let Some(res) = self.handle_duplicate(&msg) {
+            self.update_gauges();
+            respond(&msg.addr, msg.self_id, res);
+            return;
+        }
+        let res = match self.get_session(&msg) {
             Ok(session) => {
-                let addr = SessionActor::new(session).start().recipient();
+                let mut actor = SessionActor::new(session, self.blob_threshold)
+                    .retry_policy(self.retry_policy)
+                    .opened(&msg.remote_addr, msg.codec)
+                    .on_end(ctx.address().recipient(), msg.self_id.to_string());
+                if self.split_on_boundary {
+                    match self.storage.scoped(msg.tenant.as_deref()) {
+                        Ok(storage) => {
+                            actor = actor.split_on_boundary(storage, msg.self_id.to_string())
+                        }
+                        Err(e) => error!("failed to split sessions of {}: {}", msg.self_id, e),
+                    }
+                }
+                let addr = actor.start().recipient::<SessionCommand>();
+                if let Some(x) = self.connections.get_mut(&msg.self_id) {
+                    x.session = Some(msg.self_id.to_string());
+                }
+                self.clients.insert(
+                    msg.self_id.to_string(),
+                    ClientRoute {
+                        control: msg.control,
+                        session: addr.clone(),
+                    },
+                );
+                if let Some(client_session) = msg.client_session {
+                    self.live.insert(
+                        client_session,
+                        LiveSession {
+                            name: msg.self_id.to_string(),
+                            session: addr.clone(),
+                            conn: msg.takeover,
+                        },
+                    );
+                }
                 StorageResponse::Accept(addr)
             }
             Err(e) => StorageResponse::Error(format!("failed to create {}", e)),
         };
-        msg.addr.do_send(res).unwrap();
+        self.update_gauges();
+        respond(&msg.addr, msg.self_id, res);
     }
 }
 
-struct SessionActor {
-    session: Session,
-}
+impl Handler<SessionEnded> for StorageActor {
+    type Result = ();
 
-impl SessionActor {
-    fn new(session: Session) -> Self {
-        Self { session }
+    fn handle(&mut self, msg: SessionEnded, _ctx: &mut Self::Context) -> Self::Result {
+        self.clients.remove(&msg.name);
+        self.live.retain(|_, x| x.name != msg.name);
+        self.update_gauges();
     }
 }
 
-impl Actor for SessionActor {
-    type Context = Context<Self>;
-}
-
-impl Handler<SessionCommand> for SessionActor {
+impl Handler<ConnectionEvent> for StorageActor {
     type Result = ();
 
-    fn handle(&mut self, msg: SessionCommand, ctx: &mut Self::Context) -> Self::Result {
-        use SessionCommand::*;
+    fn handle(&mut self, msg: ConnectionEvent, _ctx: &mut Self::Context) -> Self::Result {
         match msg {
-            Record(record) => {
-                self.session
-                    .push(&record)
-                    .map_err(|e| error!("failed to write {}", e))
-                    .ok();
+            ConnectionEvent::Opened(entry) => {
+                self.connections.insert(entry.id, *entry);
+            }
+            ConnectionEvent::Closed(id) => {
+                self.connections.remove(&id);
             }
-            Close => ctx.stop(),
         }
     }
 }
 
-pub struct WsConn {
-    id: uuid::Uuid,
-    remote_addr: String,
-    storage_addr: Recipient<StorageRequest>,
-    session_addr: Option<Recipient<SessionCommand>>,
+impl Handler<LiveConnections> for StorageActor {
+    type Result = Result<Vec<LiveConnection>, String>;
+
+    fn handle(&mut self, msg: LiveConnections, _ctx: &mut Self::Context) -> Self::Result {
+        self.connections.retain(|_, x| x.disconnect.connected());
+        match msg {
+            LiveConnections::List => {
+                let mut list = self
+                    .connections
+                    .values()
+                    .map(ConnectionEntry::view)
+                    .collect::<Vec<_>>();
+                list.sort_by_key(|x| x.connected_at);
+                Ok(list)
+            }
+            LiveConnections::Disconnect(id) => {
+                let entry = self
+                    .connections
+                    .get(&id)
+                    .ok_or_else(|| format!("connection {} is not open", id))?;
+                entry
+                    .disconnect
+                    .do_send(Disconnect)
+                    .map_err(|_| format!("connection {} is closing", id))?;
+                Ok(vec![entry.view()])
+            }
+        }
+    }
 }
 
-impl WsConn {
-    pub fn new(id: Uuid, remote_addr: String, storage_addr: Recipient<StorageRequest>) -> Self {
-        Self {
-            id,
-            remote_addr,
-            storage_addr,
-            session_addr: None,
+/// 応答を待たずに切断した接続のセッションは閉じる
+fn respond(addr: &Recipient<StorageResponse>, id: Uuid, res: StorageResponse) {
+    if let Err(SendError::Closed(res) | SendError::Full(res)) = addr.do_send(res) {
+        warn!("connection [{}] closed before the session", id);
+        if let StorageResponse::Accept(session) = res {
+            session
+                .do_send(SessionCommand::Close(CloseReason::ConnectionLost))
+                .ok();
         }
     }
 }
 
-impl Actor for WsConn {
-    type Context = ws::WebsocketContext<Self>;
+impl Handler<RouteControl> for StorageActor {
+    type Result = Result<(), String>;
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        self.storage_addr
-            .send(StorageRequest {
-                addr: ctx.address().recipient(),
-                self_id: self.id,
-                remote_addr: self.remote_addr.clone(),
-            })
-            .into_actor(self)
-            .then(|res, _, ctx| {
-                match res {
-                    Ok(_res) => (),
-                    _ => ctx.stop(),
-                }
-                fut::ready(())
-            })
-            .wait(ctx);
+    fn handle(&mut self, msg: RouteControl, _ctx: &mut Self::Context) -> Self::Result {
+        let not_connected = || format!("session {} is not connected", msg.session);
+        // 分割したセッションは最初のセッション名で登録している
+        let mut name = msg.session.clone();
+        while !self.clients.contains_key(&name) {
+            let meta = self
+                .storage
+                .scoped(msg.tenant.as_deref())
+                .and_then(|x| x.session_meta(&name))
+                .ok();
+            name = meta.and_then(|x| x.parent).ok_or_else(not_connected)?;
+        }
+        self.clients[&name]
+            .control
+            .do_send(ClientControl(msg.command))
+            .map_err(|_| not_connected())
     }
+}
 
-    fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
-        // 即座に送信して終了する(待たない)ためdo_send
-        self.session_addr.as_ref().and_then(|r| {
-            r.do_send(SessionCommand::Close)
-                .map_err(|e| {
-                    warn!("failed to send close signal [{}], cause {}", self.id, e);
-                })
-                .ok()
-        });
-        Running::Stop
-    }
+struct SessionActor {
+    session: Session,
+    blob_threshold: Option<usize>,
+    /// 書き込みに失敗したレコード
+    retry: RetryQueue,
+    /// 書き直しを予約しているか
+    retry_scheduled: bool,
+    split: Option<SplitState>,
+    /// 開始時に書くレコード
+    opened: Option<uplog::Record>,
+    started_at: Instant,
+    /// 受け取ったレコード数
+    records: u64,
+    /// 受け取ったレコードのelapsedの最大
+    last_elapsed: Duration,
+    /// 終了のレコードを書いたか
+    closed: bool,
+    /// 受け取った添付ファイルの部分
+    attachments: Assembler,
+    /// 閉じるときに付加情報に残す異常の印
+    health: HealthScan,
+    /// 閉じるときに付加情報に残すkvの値の型
+    kv_types: KvTypeRegistry,
+    /// 接続がデコードできなかったメッセージ数
+    decode_failures: u64,
+    /// 止まったときに知らせる宛先と登録したセッション名
+    ended: Option<(Recipient<SessionEnded>, String)>,
+    _counted: Counted,
 }
 
-impl Handler<StorageResponse> for WsConn {
-    type Result = ();
+/// 区切りで分割するための状態
+struct SplitState {
+    storage: Storage,
+    /// 最初のセッション名。分割したセッションは`{base}-{n}`
+    base: String,
+    /// 書き込み中のセッション名
+    current: String,
+    count: u32,
+}
 
-    fn handle(&mut self, msg: StorageResponse, ctx: &mut Self::Context) -> Self::Result {
-        match msg {
-            StorageResponse::Accept(a) => self.session_addr = Some(a),
-            StorageResponse::Error(e) => {
-                error!("failed to create session {}", e);
-                ctx.stop();
-            }
-        };
+/// 暗号化したセッションでは溢れたレコードを平文で書き出さない
+fn retry_queue(policy: RetryPolicy, session: &Session) -> RetryQueue {
+    let queue = RetryQueue::new(policy, session.dir());
+    if session.is_encrypted() {
+        queue.without_spill()
+    } else {
+        queue
     }
 }
 
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
-    fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        match item {
-            Ok(ws::Message::Binary(bin)) => {
-                let iter = serde_cbor::Deserializer::from_slice(&bin).into_iter::<Record>();
-                for v in iter {
-                    match v {
-                        Ok(v) => {
-                            debug!("accept data [{}] {}", self.id, v);
-                            self.session_addr.as_ref().and_then(|r| {
-                                r.do_send(SessionCommand::Record(v))
-                                    .map_err(|e| {
-                                        error!("session write error [{}] {:?}", self.id, e)
-                                    })
-                                    .ok()
-                            });
-                        }
-                        Err(e) => {
-                            warn!("format error [{}] {:?}", self.id, e);
-                        }
-                    };
-                }
-            }
-            Ok(ws::Message::Close(reason)) => {
-                info!("close by client [{}] {:?}", self.id, reason);
-                ctx.stop();
-            }
-            Ok(_msg) => {}
-            Err(e) => {
-                warn!("connection error [{}] {:?}", self.id, e);
-                ctx.stop()
-            }
+impl SessionActor {
+    fn new(session: Session, blob_threshold: Option<usize>) -> Self {
+        let retry = retry_queue(RetryPolicy::default(), &session);
+        Self {
+            session,
+            blob_threshold,
+            retry,
+            retry_scheduled: false,
+            split: None,
+            opened: None,
+            started_at: Instant::now(),
+            records: 0,
+            last_elapsed: Duration::ZERO,
+            closed: false,
+            attachments: Assembler::default(),
+            health: HealthScan::default(),
+            kv_types: KvTypeRegistry::default(),
+            decode_failures: 0,
+            ended: None,
+            _counted: Counted::new(&SESSION_ACTORS),
         }
     }
+
+    fn on_end(mut self, addr: Recipient<SessionEnded>, name: String) -> Self {
+        self.ended = Some((addr, name));
+        self
+    }
+
+    fn opened(mut self, remote_addr: &str, codec: Codec) -> Self {
+        self.opened = Some(opened_record(remote_addr, codec.subprotocol()));
+        self
+    }
+
+    fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = retry_queue(policy, &self.session);
+        self
+    }
+
+    /// 書き込みに失敗した場合は書き直しを待つレコードの後ろに並べる
+    fn write(&mut self, record: uplog::Record) {
+        self.retry.write(&mut self.session, record);
+        self.session.flush_if_watched();
+    }
+
+    /// 書き直しを待つレコードがあれば間隔をあけて書き直す
+    fn schedule_retry(&mut self, ctx: &mut Context<Self>) {
+        if self.retry.is_empty() || self.retry_scheduled {
+            return;
+        }
+        self.retry_scheduled = true;
+        ctx.run_later(self.retry.backoff(), |act, ctx| {
+            act.retry_scheduled = false;
+            act.retry.retry(&mut act.session);
+            act.session.flush_if_watched();
+            record_lost(&mut act.retry, &act.session);
+            act.schedule_retry(ctx);
+        });
+    }
+
+    /// 終了のレコードを書く。2回目以降は何もしない
+    fn close(&mut self, reason: CloseReason) {
+        if std::mem::replace(&mut self.closed, true) {
+            return;
+        }
+        let record = closed_record(
+            reason,
+            self.last_elapsed,
+            self.records,
+            self.started_at.elapsed(),
+        );
+        self.write(record);
+        finish_retry(&mut self.retry, &mut self.session);
+        finish_attachments(&mut self.attachments, &self.session);
+        count_close(reason);
+        if let Err(e) = self.session.set_end_reason(&reason.to_string()) {
+            error!("failed to record the end reason {}: {}", reason, e);
+        }
+        if let Err(e) = self
+            .session
+            .record_health(self.decode_failures, &self.health)
+        {
+            error!("failed to record the health: {}", e);
+        }
+        if let Err(e) = self.session.record_kv_types(&self.kv_types) {
+            error!("failed to record the kv types: {}", e);
+        }
+    }
+
+    fn split_on_boundary(mut self, storage: Storage, name: String) -> Self {
+        self.split = Some(SplitState {
+            storage,
+            base: name.clone(),
+            current: name,
+            count: 0,
+        });
+        self
+    }
+
+    /// 次のセッションに切り替える。失敗した場合は今のセッションに書き続ける
+    fn split(&mut self) {
+        let state = match self.split.as_mut() {
+            Some(x) => x,
+            None => return,
+        };
+        let next = format!("{}-{}", state.base, state.count + 1);
+        match state.storage.split_session(&state.current, &next) {
+            Ok(session) => {
+                info!("split session {} -> {}", state.current, next);
+                // 前のセッションの書き直しは切り替える前に終える
+                finish_retry(&mut self.retry, &mut self.session);
+                finish_attachments(&mut self.attachments, &self.session);
+                self.retry = retry_queue(self.retry.policy(), &session);
+                // 前のセッションはdropで書き出される
+                self.session = session;
+                self.health = HealthScan::default();
+                self.kv_types = KvTypeRegistry::default();
+                state.current = next;
+                state.count += 1;
+            }
+            Err(e) => error!("failed to split session {}: {}", state.current, e),
+        }
+    }
+}
+
+/// 失ったレコード数を付加情報に残す
+fn record_lost(retry: &mut RetryQueue, session: &Session) {
+    let lost = retry.take_lost();
+    if lost > 0 {
+        if let Err(e) = session.add_lost_records(lost) {
+            error!("failed to record {} lost records: {}", lost, e);
+        }
+    }
+}
+
+/// 揃った添付ファイルの部分をつなげる
+fn finish_attachments(attachments: &mut Assembler, session: &Session) {
+    if let Err(e) = attachments.finish(session) {
+        error!("failed to join attachments: {}", e);
+    }
+}
+
+/// 待たずにもう1度だけ書き直し、残りは諦める
+fn finish_retry(retry: &mut RetryQueue, session: &mut Session) {
+    if !retry.is_empty() {
+        retry.retry(session);
+        retry.abandon();
+    }
+    record_lost(retry, session);
+}
+
+impl Actor for SessionActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(record) = self.opened.take() {
+            self.write(record);
+            self.schedule_retry(ctx);
+        }
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some((addr, name)) = self.ended.take() {
+            addr.do_send(SessionEnded { name }).ok();
+        }
+    }
+}
+
+impl Drop for SessionActor {
+    fn drop(&mut self) {
+        // 閉じる前にサーバーが止まった
+        self.close(CloseReason::Shutdown);
+    }
+}
+
+impl Handler<SessionCommand> for SessionActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SessionCommand, ctx: &mut Self::Context) -> Self::Result {
+        use SessionCommand::*;
+        match msg {
+            Record(mut record) => {
+                QUEUED_RECORDS.fetch_sub(1, Ordering::Relaxed);
+                // 区切りのレコードは新しいセッションの先頭に書く
+                if record.category == uplog::BOUNDARY_CATEGORY {
+                    self.split();
+                }
+                // 暗号化したセッションには平文のblobを書かない
+                if !self.session.is_encrypted() {
+                    if let Err(e) = self.attachments.add(&mut record, self.session.blobs()) {
+                        error!("failed to write attachment {}", e);
+                    }
+                }
+                if let Some(threshold) =
+                    self.blob_threshold.filter(|_| !self.session.is_encrypted())
+                {
+                    if let Err(e) = self.session.blobs().offload(&mut record, threshold) {
+                        // 分離できなかった分はそのまま書き込む
+                        error!("failed to write blob {}", e);
+                    }
+                }
+                self.records += 1;
+                self.last_elapsed = self.last_elapsed.max(record.elapsed);
+                self.health.observe(&record);
+                self.kv_types.observe(&record);
+                self.write(record);
+                self.schedule_retry(ctx);
+            }
+            DecodeFailures(n) => self.decode_failures += n,
+            Close(reason) => {
+                self.close(reason);
+                ctx.stop()
+            }
+        }
+    }
+}
+
+/// 受け取ったメッセージをレコードに分けてセッションに送る
+///
+/// 接続の種類によらず同じように扱うため、WsConnとUdsConnで共有する
+pub(crate) struct Inbound {
+    pub(crate) id: Uuid,
+    pub(crate) remote_addr: String,
+    pub(crate) session_addr: Option<Recipient<SessionCommand>>,
+    pub(crate) decode_policy: DecodePolicy,
+    /// 連続してデコードに失敗したメッセージ数
+    decode_failures: u64,
+    /// デコードに失敗したメッセージの合計
+    total_decode_failures: u64,
+    last_report_at: Option<Instant>,
+    pub(crate) decode_limits: DecodeLimits,
+    /// 上限を超えて捨てたレコード数
+    rejected_records: u64,
+    pub(crate) ingest: IngestPipeline,
+    /// セッションを閉じるときに終了のレコードに書く理由
+    pub(crate) close_reason: CloseReason,
+    pub(crate) idle_timeout: Option<Duration>,
+    last_received_at: Instant,
+    /// 辞書を交渉した接続の文字列の表
+    pub(crate) wire: Option<WireDecoder>,
+    /// 整数の`elapsed`の単位
+    pub(crate) time_precision: Option<Precision>,
+    /// セッションが決まる前に受け取ったレコード
+    pending: Vec<uplog::Record>,
+    /// 最初のレコードの受信時刻から経過時間を引いた、クライアントのセッションの開始時刻
+    client_started_at: Option<chrono::DateTime<chrono::Utc>>,
+    _counted: Counted,
+}
+
+/// セッションにレコードを送る。書かれるまで数えておく
+fn send_record(session: &Recipient<SessionCommand>, record: uplog::Record, id: Uuid) {
+    // セッションは別のスレッドで書くので送る前に数える
+    QUEUED_RECORDS.fetch_add(1, Ordering::Relaxed);
+    if let Err(e) = session.do_send(SessionCommand::Record(record)) {
+        QUEUED_RECORDS.fetch_sub(1, Ordering::Relaxed);
+        error!("session write error [{}] {:?}", id, e);
+    }
+}
+
+/// デコードに失敗したメッセージへの応答
+pub(crate) struct DecodeFailure {
+    /// クライアントに送る報告。間隔をあけるので毎回はない
+    pub(crate) report: Option<Vec<u8>>,
+    /// 接続を閉じる理由
+    pub(crate) close: Option<String>,
+}
+
+impl Inbound {
+    pub(crate) fn new(id: Uuid, remote_addr: String) -> Self {
+        Self {
+            id,
+            remote_addr,
+            session_addr: None,
+            decode_policy: DecodePolicy::default(),
+            decode_failures: 0,
+            total_decode_failures: 0,
+            last_report_at: None,
+            decode_limits: DecodeLimits::default(),
+            rejected_records: 0,
+            ingest: IngestPipeline::default(),
+            // 閉じる理由がわからないまま止まった場合は切断とする
+            close_reason: CloseReason::ConnectionLost,
+            idle_timeout: None,
+            last_received_at: Instant::now(),
+            wire: None,
+            time_precision: None,
+            pending: Vec::new(),
+            client_started_at: None,
+            _counted: Counted::new(&CONNECTIONS),
+        }
+    }
+
+    /// 書き込み先のセッションを決め、それまでに受け取ったレコードを送る
+    pub(crate) fn attach(&mut self, session: Recipient<SessionCommand>) {
+        for record in std::mem::take(&mut self.pending) {
+            send_record(&session, record, self.id);
+        }
+        self.session_addr = Some(session);
+    }
+
+    /// クライアントのセッションの開始時刻。最初のレコードを受け取るまではわからない
+    pub(crate) fn client_started_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        self.client_started_at
+    }
+
+    /// セッションが決まるのを待っているレコードがあるか
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// 何かを受け取った
+    pub(crate) fn touch(&mut self) {
+        self.last_received_at = Instant::now();
+    }
+
+    /// 無通信の時間が上限を超えたか
+    pub(crate) fn is_idle(&self) -> bool {
+        self.idle_timeout
+            .is_some_and(|x| self.last_received_at.elapsed() >= x)
+    }
+
+    /// CBOR Sequenceのメッセージを1つ処理する
+    pub(crate) fn feed(&mut self, bin: &[u8]) -> Result<(), DecodeFailure> {
+        let mut iter = FrameDecoder::new(bin, self.decode_limits);
+        if let Some(wire) = self.wire.as_mut() {
+            iter = iter.with_wire(wire);
+        }
+        iter = iter.time_precision(self.time_precision);
+        let ingest_ctx = IngestContext {
+            connection_id: self.id,
+            remote_addr: &self.remote_addr,
+            received_at: chrono::Utc::now(),
+        };
+        while let Some(v) = iter.next() {
+            match v {
+                Ok(mut v) => {
+                    if self.client_started_at.is_none() {
+                        self.client_started_at = chrono::Duration::from_std(v.elapsed)
+                            .ok()
+                            .and_then(|x| ingest_ctx.received_at.checked_sub_signed(x));
+                    }
+                    self.ingest.apply(&mut v, &ingest_ctx);
+                    debug!("accept data [{}] {}", self.id, v);
+                    match self.session_addr.as_ref() {
+                        Some(r) => send_record(r, v, self.id),
+                        None => self.pending.push(v),
+                    }
+                }
+                Err(DecodeError::Oversize(e)) => {
+                    // 確保せずに捨てる。区切りがわかれば次のレコードから続ける
+                    self.rejected_records += 1;
+                    warn!(
+                        "reject record [{}] {} ({} rejected)",
+                        self.id, e, self.rejected_records
+                    );
+                }
+                Err(DecodeError::Cbor(e)) => {
+                    // 以降の区切りは信用できないのでこのメッセージの残りは捨てる
+                    let offset = iter.byte_offset();
+                    if log::log_enabled!(log::Level::Debug) {
+                        let rest = bin.get(offset..).unwrap_or_default();
+                        debug!(
+                            "undecodable bytes [{}] at {}: {}",
+                            self.id,
+                            offset,
+                            render_diagnostic(&rest[..rest.len().min(DECODE_DUMP_BYTES)])
+                                .trim_end()
+                        );
+                    }
+                    return Err(self.decode_failed(e, offset));
+                }
+            };
+        }
+        self.decode_failures = 0;
+        Ok(())
+    }
+
+    /// デコードの失敗を数え、間隔をあけてクライアントに報告する
+    pub(crate) fn decode_failed<E: std::fmt::Debug + std::fmt::Display>(
+        &mut self,
+        e: E,
+        byte_offset: usize,
+    ) -> DecodeFailure {
+        warn!("format error [{}] {:?}", self.id, e);
+        self.decode_failures += 1;
+        self.total_decode_failures += 1;
+        let now = Instant::now();
+        let report_due = self
+            .last_report_at
+            .map(|x| now.duration_since(x) >= self.decode_policy.report_interval)
+            .unwrap_or(true);
+        let mut report = None;
+        if report_due {
+            let msg = ServerMessage::DecodeError(DecodeErrorReport {
+                count: self.decode_failures,
+                last_error: e.to_string(),
+                byte_offset: byte_offset as u64,
+            });
+            match serde_cbor::to_vec(&msg) {
+                Ok(buf) => report = Some(buf),
+                Err(e) => error!("failed to encode report [{}] {}", self.id, e),
+            }
+            self.last_report_at = Some(now);
+        }
+        let mut close = None;
+        if self.decode_failures >= self.decode_policy.max_consecutive_failures {
+            warn!(
+                "close connection [{}] by {} consecutive decode failures",
+                self.id, self.decode_failures
+            );
+            close = Some(format!(
+                "{} consecutive decode failures",
+                self.decode_failures
+            ));
+        }
+        DecodeFailure { report, close }
+    }
+
+    /// 接続を閉じるときにセッションも閉じ、セッションが決まる前のレコードと文字列の表を手放す
+    pub(crate) fn close_session(&mut self) {
+        if !self.pending.is_empty() {
+            warn!(
+                "drop {} records received before the session [{}]",
+                self.pending.len(),
+                self.id
+            );
+        }
+        self.pending = Vec::new();
+        self.wire = None;
+        // 即座に送信して終了する(待たない)ためdo_send
+        self.session_addr.as_ref().and_then(|r| {
+            if self.total_decode_failures > 0 {
+                r.do_send(SessionCommand::DecodeFailures(self.total_decode_failures))
+                    .ok();
+            }
+            r.do_send(SessionCommand::Close(self.close_reason))
+                .map_err(|e| {
+                    warn!("failed to send close signal [{}], cause {}", self.id, e);
+                })
+                .ok()
+        });
+    }
+}
+
+pub struct WsConn {
+    inbound: Inbound,
+    /// 再接続しても変わらないクライアントのID
+    client_session: Option<Uuid>,
+    storage_addr: Recipient<StorageRequest>,
+    codec: Codec,
+    /// 展開後のメッセージの上限
+    max_message_bytes: usize,
+    handshake: HandshakePolicy,
+    /// セッションを要求したか
+    session_requested: bool,
+    /// 最初のレコードより前に解釈できなかったメッセージ数
+    invalid_frames: u64,
+    /// ハンドシェイクで求めたクライアントの時計のずれ
+    clock_offset_ms: Option<i64>,
+    /// 受け取ったメッセージ数。時刻を送ってきたクライアントにだけ応答する
+    acked: Option<u64>,
+    /// 接続した受信パスのラベル
+    label: Option<String>,
+    /// セッションを作るテナント
+    tenant: Option<String>,
+    /// ハンドシェイクで受け取ったビルド情報
+    build_info: Option<BuildInfo>,
+    /// 受け取るメッセージの合計バイト数の上限
+    byte_quota: Option<u64>,
+    /// 受け取ったメッセージの合計バイト数
+    received_bytes: u64,
+    /// ストレージが読み取り専用か
+    disk: Option<DiskGuard>,
+    /// 読み取り専用になってから受け取ったバイト数
+    read_only_bytes: u64,
+    /// 接続の一覧の送り先。登録したら`registered`に移す
+    registry: Option<Recipient<ConnectionEvent>>,
+    registered: Option<Registered>,
+    counters: Arc<ConnectionCounters>,
+}
+
+/// 破棄されたら接続の一覧から除く。異常終了で止まった場合も除けるようにする
+struct Registered {
+    id: Uuid,
+    registry: Recipient<ConnectionEvent>,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.registry.do_send(ConnectionEvent::Closed(self.id)).ok();
+    }
+}
+
+impl WsConn {
+    pub fn new(id: Uuid, remote_addr: String, storage_addr: Recipient<StorageRequest>) -> Self {
+        Self {
+            inbound: Inbound::new(id, remote_addr),
+            client_session: None,
+            storage_addr,
+            codec: Codec::Cbor,
+            max_message_bytes: uplog::DEFAULT_BUFFER_SIZE,
+            handshake: HandshakePolicy::default(),
+            session_requested: false,
+            invalid_frames: 0,
+            clock_offset_ms: None,
+            acked: None,
+            label: None,
+            tenant: None,
+            build_info: None,
+            byte_quota: None,
+            received_bytes: 0,
+            disk: None,
+            read_only_bytes: 0,
+            registry: None,
+            registered: None,
+            counters: Arc::default(),
+        }
+    }
+
+    /// 接続中の一覧に載せる
+    pub fn registry(mut self, registry: Recipient<ConnectionEvent>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    pub fn client_session(mut self, client_session: Option<Uuid>) -> Self {
+        self.client_session = client_session;
+        self
+    }
+
+    pub fn decode_policy(mut self, policy: DecodePolicy) -> Self {
+        self.inbound.decode_policy = policy;
+        self
+    }
+
+    pub fn decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.inbound.decode_limits = limits;
+        self
+    }
+
+    /// 保存前にレコードに適用する加工
+    pub fn ingest(mut self, pipeline: IngestPipeline) -> Self {
+        self.inbound.ingest = pipeline;
+        self
+    }
+
+    /// ハンドシェイクで決めたcodecと、展開後のメッセージの上限
+    pub fn codec(mut self, codec: Codec, max_message_bytes: usize) -> Self {
+        self.codec = codec;
+        self.max_message_bytes = max_message_bytes;
+        self.inbound.wire = (codec == Codec::CborDict).then(WireDecoder::new);
+        self
+    }
+
+    /// ハンドシェイクで受け付けた`elapsed`の単位
+    pub fn time_precision(mut self, precision: Option<Precision>) -> Self {
+        self.inbound.time_precision = precision;
+        self
+    }
+
+    /// ハンドシェイクで求めたクライアントの時計のずれ。`Some`の場合は受け取ったメッセージに応答する
+    pub fn clock_offset(mut self, offset_ms: Option<i64>) -> Self {
+        self.clock_offset_ms = offset_ms;
+        self.acked = offset_ms.map(|_| 0);
+        self
+    }
+
+    /// 接続した受信パスのラベル。セッションの付加情報に書く
+    pub fn label(mut self, label: Option<String>) -> Self {
+        self.label = label;
+        self
+    }
+
+    /// セッションを作るテナント。なければデータディレクトリに直接作る
+    pub fn tenant(mut self, tenant: Option<String>) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    /// クライアントのビルド情報。セッションの付加情報に書く
+    pub fn build_info(mut self, build_info: Option<BuildInfo>) -> Self {
+        self.build_info = build_info;
+        self
+    }
+
+    /// 無通信の時間を超えたら閉じる
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.inbound.idle_timeout = timeout;
+        self
+    }
+
+    /// 受け取ったメッセージの合計がこのバイト数を超えたら閉じる
+    pub fn byte_quota(mut self, quota: Option<u64>) -> Self {
+        self.byte_quota = quota;
+        self
+    }
+
+    /// ストレージが読み取り専用の間は新しいセッションを作らず、既存のセッションは予約した量まで書く
+    pub fn disk_guard(mut self, guard: Option<DiskGuard>) -> Self {
+        self.disk = guard;
+        self
+    }
+
+    /// 読み取り専用の間に受け取った量が予約を超えたか
+    fn over_reserve(&mut self, len: usize) -> bool {
+        match self.disk.as_ref().filter(|x| x.is_read_only()) {
+            Some(disk) => {
+                self.read_only_bytes += len as u64;
+                self.read_only_bytes > disk.reserved_bytes()
+            }
+            None => {
+                self.read_only_bytes = 0;
+                false
+            }
+        }
+    }
+
+    pub fn handshake_policy(mut self, policy: HandshakePolicy) -> Self {
+        self.handshake = policy;
+        self
+    }
+
+    /// 最初のレコードを受け取ったらセッションを要求する
+    fn request_session(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if self.session_requested || !self.inbound.has_pending() {
+            return;
+        }
+        if self.disk.as_ref().is_some_and(|x| x.is_read_only()) {
+            info!(
+                "reject connection [{}] from {}: storage is read-only",
+                self.inbound.id, self.inbound.remote_addr
+            );
+            self.close_with(
+                protocol::CloseReason::StorageFull,
+                Some("storage is almost full"),
+                ctx,
+            );
+            return;
+        }
+        self.session_requested = true;
+        self.storage_addr
+            .send(StorageRequest {
+                addr: ctx.address().recipient(),
+                control: ctx.address().recipient(),
+                takeover: ctx.address().recipient(),
+                self_id: self.inbound.id,
+                client_session: self.client_session,
+                remote_addr: self.inbound.remote_addr.clone(),
+                codec: self.codec,
+                time_precision: self.inbound.time_precision,
+                clock_offset_ms: self.clock_offset_ms,
+                label: self.label.clone(),
+                tenant: self.tenant.clone(),
+                start_at: self.inbound.client_started_at(),
+                build_info: self.build_info.clone(),
+            })
+            .into_actor(self)
+            .then(|res, _, ctx| {
+                match res {
+                    Ok(_res) => (),
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// セッションを作らずに閉じる
+    fn reject_handshake(&mut self, reason: &str, ctx: &mut <Self as Actor>::Context) {
+        let count = REJECTED_HANDSHAKES.fetch_add(1, Ordering::Relaxed) + 1;
+        info!(
+            "reject connection [{}] from {}: {} ({} rejected)",
+            self.inbound.id, self.inbound.remote_addr, reason, count
+        );
+        self.close_with(protocol::CloseReason::Rejected, Some(reason), ctx);
+    }
+
+    /// 理由を付けて閉じる。`detail`は理由の名前に続けて説明に入れる
+    fn close_with(
+        &mut self,
+        reason: protocol::CloseReason,
+        detail: Option<&str>,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let description = match detail {
+            Some(x) => format!("{}: {}", reason, x),
+            None => reason.to_string(),
+        };
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Other(reason.code()),
+            description: Some(description),
+        }));
+        ctx.stop();
+    }
+
+    /// 受け取ったことをサーバーの時刻と合わせて返す
+    fn ack(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let received = match self.acked.as_mut() {
+            Some(x) => {
+                *x += 1;
+                *x
+            }
+            None => return,
+        };
+        let msg = ServerMessage::Ack(Ack {
+            received,
+            server_time_ms: chrono::Utc::now().timestamp_millis(),
+        });
+        match serde_cbor::to_vec(&msg) {
+            Ok(buf) => ctx.binary(buf),
+            Err(e) => error!("failed to encode ack [{}] {}", self.inbound.id, e),
+        }
+    }
+
+    fn on_decode_failure(&mut self, failure: DecodeFailure, ctx: &mut <Self as Actor>::Context) {
+        if !self.session_requested {
+            self.invalid_frames += 1;
+            if self.invalid_frames >= self.handshake.max_invalid_frames {
+                let reason = format!("{} invalid messages before a record", self.invalid_frames);
+                self.reject_handshake(&reason, ctx);
+                return;
+            }
+        }
+        if let Some(buf) = failure.report {
+            ctx.binary(buf);
+        }
+        if let Some(reason) = failure.close {
+            self.inbound.close_reason = CloseReason::DecodeError;
+            self.close_with(protocol::CloseReason::DecodeError, Some(&reason), ctx);
+        }
+    }
+}
+
+impl Actor for WsConn {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(registry) = self.registry.take() {
+            let entry = ConnectionEntry {
+                id: self.inbound.id,
+                remote_addr: self.inbound.remote_addr.clone(),
+                client_session: self.client_session,
+                tenant: self.tenant.clone(),
+                connected_at: chrono::Utc::now(),
+                session: None,
+                counters: self.counters.clone(),
+                disconnect: ctx.address().recipient(),
+            };
+            registry
+                .do_send(ConnectionEvent::Opened(Box::new(entry)))
+                .ok();
+            self.registered = Some(Registered {
+                id: self.inbound.id,
+                registry,
+            });
+        }
+        ctx.run_later(self.handshake.grace, |act, ctx| {
+            if !act.session_requested {
+                act.reject_handshake("no record in the grace period", ctx);
+            }
+        });
+        if let Some(timeout) = self.inbound.idle_timeout {
+            ctx.run_interval(timeout / 2, |act, ctx| {
+                if act.inbound.is_idle() {
+                    info!("close idle connection [{}]", act.inbound.id);
+                    act.inbound.close_reason = CloseReason::IdleTimeout;
+                    act.close_with(protocol::CloseReason::IdleTimeout, None, ctx);
+                }
+            });
+        }
+    }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        self.inbound.close_session();
+        Running::Stop
+    }
+}
+
+impl Handler<StorageResponse> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: StorageResponse, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            StorageResponse::Accept(a) => self.inbound.attach(a),
+            StorageResponse::Reject(reason) => {
+                self.close_with(protocol::CloseReason::Rejected, Some(&reason), ctx);
+            }
+            StorageResponse::Error(e) => {
+                error!("failed to create session {}", e);
+                ctx.stop();
+            }
+        };
+    }
+}
+
+impl Handler<Takeover> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Takeover, ctx: &mut Self::Context) -> Self::Result {
+        info!(
+            "close connection [{}] taken over by a new one",
+            self.inbound.id
+        );
+        // セッションは新しい接続が使い続けるので閉じない
+        self.inbound.session_addr = None;
+        self.close_with(
+            protocol::CloseReason::TakenOver,
+            Some("taken over by a new connection"),
+            ctx,
+        );
+    }
+}
+
+impl Handler<Disconnect> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Disconnect, ctx: &mut Self::Context) -> Self::Result {
+        info!("disconnect connection [{}]", self.inbound.id);
+        self.inbound.close_reason = CloseReason::Disconnected;
+        self.close_with(
+            protocol::CloseReason::Disconnected,
+            Some("closed by the server operator"),
+            ctx,
+        );
+    }
+}
+
+impl Handler<ClientControl> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientControl, ctx: &mut Self::Context) -> Self::Result {
+        info!("send command [{}] {:?}", self.inbound.id, msg.0);
+        match serde_cbor::to_vec(&ServerMessage::Control(msg.0)) {
+            Ok(buf) => ctx.binary(buf),
+            Err(e) => error!("failed to encode command [{}] {}", self.inbound.id, e),
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
+    fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        self.inbound.touch();
+        self.counters.touch();
+        match item {
+            Ok(ws::Message::Binary(bin)) => {
+                self.counters.received(bin.len());
+                self.received_bytes += bin.len() as u64;
+                if let Some(quota) = self.byte_quota.filter(|x| self.received_bytes > *x) {
+                    // 上限を超えたメッセージは書き込まない
+                    info!(
+                        "close connection [{}] over the quota of {} bytes",
+                        self.inbound.id, quota
+                    );
+                    self.inbound.close_reason = CloseReason::Quota;
+                    let detail = format!("received more than {} bytes", quota);
+                    self.close_with(protocol::CloseReason::Quota, Some(&detail), ctx);
+                    return;
+                }
+                if self.session_requested && self.over_reserve(bin.len()) {
+                    info!(
+                        "close connection [{}] over the reserve of the read-only storage",
+                        self.inbound.id
+                    );
+                    self.inbound.close_reason = CloseReason::StorageFull;
+                    self.close_with(
+                        protocol::CloseReason::StorageFull,
+                        Some("storage is almost full"),
+                        ctx,
+                    );
+                    return;
+                }
+                let result = match self.codec.decode(&bin, self.max_message_bytes) {
+                    Ok(bin) => self.inbound.feed(&bin),
+                    Err(e) => Err(self.inbound.decode_failed(e, 0)),
+                };
+                self.request_session(ctx);
+                match result {
+                    Ok(()) => self.ack(ctx),
+                    Err(failure) => self.on_decode_failure(failure, ctx),
+                }
+            }
+            Ok(ws::Message::Close(reason)) => {
+                info!("close by client [{}] {:?}", self.inbound.id, reason);
+                // 新しいクライアントの知らない理由は番号のまま残す
+                let reason = reason.map(|x| protocol::CloseReason::from_code(x.code.into()));
+                self.inbound.close_reason = CloseReason::Client(reason);
+                ctx.stop();
+            }
+            Ok(_msg) => {}
+            Err(e) => {
+                warn!("connection error [{}] {:?}", self.inbound.id, e);
+                ctx.stop()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::channel, thread, time::Duration};
+
+    use actix::Actor;
+    use actix_web::{
+        web::{self, Data},
+        App, HttpServer,
+    };
+    use tempdir::TempDir;
+    use tungstenite::{connect, protocol::frame::coding::CloseCode, Message};
+    use uplog::protocol::ServerMessage;
+
+    use super::{ws_index, DecodePolicy, IngestEndpoint, StorageActor};
+    use crate::{
+        lifecycle::is_server_record,
+        server::{run_server, ServerHandle, ServerOptions},
+        testing::wait_for,
+        Storage,
+    };
+
+    /// 既定の設定で保存先を`dir`にしたサーバー
+    fn start_server(dir: &TempDir, f: impl FnOnce(ServerOptions) -> ServerOptions) -> ServerHandle {
+        run_server(f(ServerOptions::new(dir.path()))).unwrap()
+    }
+
+    /// 以前のパスで接続するURL
+    fn ws_url(server: &ServerHandle) -> String {
+        format!("ws://127.0.0.1:{}{}", server.port(), uplog::WS_PATH)
+    }
+
+    /// 接続中のクライアントの出力レベルをGraphQLから変更する
+    #[test]
+    fn test_set_client_level() {
+        use crate::{
+            reader::{CBORSequenceReader, StorageReader},
+            webapi::{Mutation, Query},
+        };
+        use async_graphql::{EmptySubscription, Schema};
+        use uplog::Level;
+
+        let dir = TempDir::new("control").unwrap();
+        let server = start_server(&dir, |x| x);
+        let storage = server.storage().clone();
+        uplog::Builder::default()
+            .host("127.0.0.1")
+            .port(server.port())
+            .duration(Duration::from_millis(10))
+            .level(Level::Info)
+            .try_init()
+            .unwrap();
+        uplog::info!("control", "before");
+        uplog::trace!("control", "hidden");
+
+        let session = wait_for(|| {
+            let records = storage.records().ok()?;
+            let path = records.first()?.path().to_owned();
+            Some(path.file_name()?.to_string_lossy().to_string())
+        });
+        let schema = Schema::build(
+            Query::new(storage.clone()),
+            Mutation::new(storage.clone()).control(server.storage_actor().clone().recipient()),
+            EmptySubscription,
+        )
+        .finish();
+        let query = format!(
+            r#"mutation {{ setClientLevel(session: "{}", level: TRACE, category: "") }}"#,
+            session
+        );
+        let res = actix_web::rt::System::new("control")
+            .block_on(async move { schema.execute(query.as_str()).await });
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        wait_for(|| (uplog::health().applied_commands > 0).then_some(()));
+
+        uplog::trace!("control", "after");
+        uplog::flush();
+
+        let messages = wait_for(|| {
+            let records = CBORSequenceReader::new(dir.path().join(&session))
+                .ok()?
+                .read_at(0, 100)
+                .ok()?;
+            let messages = records
+                .into_iter()
+                .filter(|x| x.record.category == "control")
+                .map(|x| x.record.message)
+                .collect::<Vec<_>>();
+            (messages.len() == 2).then_some(messages)
+        });
+        assert_eq!(messages, ["before", "after"]);
+        server.stop(false).unwrap();
+    }
+
+    #[test]
+    fn test_decode_error_report_and_close() {
+        let dir = TempDir::new("decode").unwrap();
+        let server = start_server(&dir, |x| ServerOptions {
+            decode_policy: DecodePolicy {
+                report_interval: Duration::from_secs(3600),
+                max_consecutive_failures: 3,
+            },
+            ..x
+        });
+
+        uplog::devinit!();
+        let (mut client, _) = connect(ws_url(&server)).unwrap();
+        // レコードを送った後の接続での失敗
+        let record = uplog::devlog!(uplog::Level::Info, "app", "valid");
+        client
+            .write_message(Message::binary(serde_cbor::to_vec(&record).unwrap()))
+            .unwrap();
+        for _ in 0..3 {
+            // CBORのbreakコードから始まるデータはRecordとして解釈できない
+            client
+                .write_message(Message::binary(vec![0xff, 0x00, 0x01]))
+                .unwrap();
+        }
+
+        let mut reports = Vec::new();
+        let close = loop {
+            match client.read_message().unwrap() {
+                Message::Binary(bin) => {
+                    reports.push(serde_cbor::from_slice::<ServerMessage>(&bin).unwrap())
+                }
+                Message::Close(frame) => break frame,
+                _ => {}
+            }
+        };
+        // 報告は間隔をあけるので1回だけ
+        assert_eq!(reports.len(), 1);
+        match &reports[0] {
+            ServerMessage::DecodeError(report) => {
+                assert_eq!(report.count, 1);
+                assert!(!report.last_error.is_empty());
+            }
+            x => panic!("unexpected message {:?}", x),
+        }
+        assert_eq!(close.unwrap().code, CloseCode::from(4103));
+        server.stop(false).unwrap();
+    }
+    /// 同じクライアントのセッションIDで続けて接続したときの扱い
+    #[test]
+    fn test_duplicate_policy() {
+        use super::DuplicatePolicy;
+        use crate::reader::{CBORSequenceReader, StorageReader};
+        use uplog::{devinit, devlog, protocol::SESSION_QUERY, Level};
+
+        devinit!();
+        let record = |message: &str| {
+            Message::binary(serde_cbor::to_vec(&devlog!(Level::Info, "app", message)).unwrap())
+        };
+        let cases = [
+            DuplicatePolicy::Reject,
+            DuplicatePolicy::Takeover,
+            DuplicatePolicy::Parallel,
+        ];
+        for policy in cases {
+            let dir = TempDir::new("duplicate").unwrap();
+            let server = start_server(&dir, |x| ServerOptions {
+                duplicate_policy: policy,
+                ..x
+            });
+            let storage = server.storage().clone();
+            // セッションごとのメッセージ
+            let read_all = |count: usize| {
+                wait_for(|| {
+                    let mut sessions = storage
+                        .records()
+                        .ok()?
+                        .iter()
+                        .map(|x| {
+                            let records = CBORSequenceReader::new(x.path()).ok()?.read_at(0, 10);
+                            let messages = records
+                                .ok()?
+                                .into_iter()
+                                .filter(|x| !is_server_record(&x.record))
+                                .map(|x| x.record.message);
+                            Some(messages.collect::<Vec<_>>())
+                        })
+                        .collect::<Option<Vec<_>>>()?;
+                    sessions.sort();
+                    (sessions.iter().map(|x| x.len()).sum::<usize>() == count).then_some(sessions)
+                })
+            };
+            let read_close = |client: &mut tungstenite::WebSocket<_>| loop {
+                if let Message::Close(frame) = client.read_message().unwrap() {
+                    break frame.unwrap().code;
+                }
+            };
+
+            let url = format!(
+                "{}?{}={}",
+                ws_url(&server),
+                SESSION_QUERY,
+                uuid::Uuid::new_v4()
+            );
+            let (mut first, _) = connect(url.as_str()).unwrap();
+            first.write_message(record("first")).unwrap();
+            // 閉じるまで書き出されないのでセッションが作られるのを待つ
+            wait_for(|| (storage.records().ok()?.len() == 1).then_some(()));
+            // セッションは最初のレコードを受け取ってから決める
+            let (mut second, _) = connect(url.as_str()).unwrap();
+            second.write_message(record("second")).unwrap();
+            match policy {
+                DuplicatePolicy::Reject => {
+                    assert_eq!(read_close(&mut second), CloseCode::from(4104));
+                    first.write_message(record("first 2")).unwrap();
+                    first.close(None).unwrap();
+                    assert_eq!(read_all(2), vec![vec!["first", "first 2"]]);
+                }
+                DuplicatePolicy::Takeover => {
+                    assert_eq!(read_close(&mut first), CloseCode::from(4105));
+                    second.close(None).unwrap();
+                    assert_eq!(read_all(2), vec![vec!["first", "second"]]);
+                }
+                DuplicatePolicy::Parallel => {
+                    first.close(None).unwrap();
+                    second.close(None).unwrap();
+                    assert_eq!(read_all(2), vec![vec!["first"], vec!["second"]]);
+                }
+            }
+            server.stop(false).unwrap();
+        }
+    }
+
+    /// 1つの接続で2回区切りを送ると3つのセッションに続けて書き込む
+    #[test]
+    fn test_split_on_boundary() {
+        use crate::reader::{CBORSequenceReader, StorageReader};
+        use uplog::{devinit, devlog, Level, BOUNDARY_CATEGORY};
+
+        devinit!();
+        let dir = TempDir::new("split").unwrap();
+        let server = start_server(&dir, |x| ServerOptions {
+            split_on_boundary: true,
+            ..x
+        });
+        let storage = server.storage().clone();
+        let encode = |records: &[uplog::Record]| {
+            records
+                .iter()
+                .flat_map(|x| serde_cbor::to_vec(x).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let (mut client, _) = connect(ws_url(&server)).unwrap();
+        client
+            .write_message(Message::binary(encode(&[devlog!(Level::Info, "app", "0")])))
+            .unwrap();
+        let base = wait_for(|| {
+            let records = storage.records().ok()?;
+            let path = records.first()?.path().to_owned();
+            Some(path.file_name()?.to_string_lossy().to_string())
+        });
+        // メモは分割したセッションに引き継ぐ
+        storage.set_session_note(&base, "bench").unwrap();
+
+        let records = [
+            devlog!(Level::Info, BOUNDARY_CATEGORY, "run 1"),
+            devlog!(Level::Info, "app", "1"),
+            devlog!(Level::Info, "app", "2"),
+            devlog!(Level::Info, BOUNDARY_CATEGORY, "run 2"),
+            devlog!(Level::Info, "app", "3"),
+        ];
+        client
+            .write_message(Message::binary(encode(&records)))
+            .unwrap();
+        client.close(None).unwrap();
+
+        let names = [base.clone(), format!("{}-1", base), format!("{}-2", base)];
+        let read = |name: &str| {
+            CBORSequenceReader::new(dir.path().join(name))
+                .ok()?
+                .read_at(0, 10)
+                .ok()
+                .map(|x| {
+                    x.into_iter()
+                        .filter(|x| !is_server_record(&x.record))
+                        .map(|x| x.record.message)
+                        .collect::<Vec<_>>()
+                })
+        };
+        let messages = wait_for(|| {
+            let messages = names.iter().map(|x| read(x)).collect::<Option<Vec<_>>>()?;
+            (messages[2].len() == 2).then_some(messages)
+        });
+        assert_eq!(
+            messages,
+            vec![vec!["0"], vec!["run 1", "1", "2"], vec!["run 2", "3"]]
+        );
+
+        let meta = storage.session_meta(&names[2]).unwrap();
+        assert_eq!(meta.parent.as_ref(), Some(&names[1]));
+        assert_eq!(meta.note.as_deref(), Some("bench"));
+        let meta = storage.session_meta(&names[1]).unwrap();
+        assert_eq!(meta.parent.as_ref(), Some(&names[0]));
+        assert_eq!(storage.session_meta(&base).unwrap().parent, None);
+        server.stop(false).unwrap();
+    }
+
+    /// subprotocolの有無と組み合わせごとの接続
+    #[test]
+    fn test_subprotocol_negotiation() {
+        use crate::reader::{CBORSequenceReader, StorageReader};
+        use tungstenite::client::IntoClientRequest;
+        use uplog::{
+            devinit, devlog,
+            protocol::{Codec, SUBPROTOCOL_HEADER},
+            wire::WireEncoder,
+            Level,
+        };
+
+        devinit!();
+        let dir = TempDir::new("subprotocol").unwrap();
+        let server = start_server(&dir, |x| x);
+        let storage = server.storage().clone();
+        let url = ws_url(&server);
+        let request = |offer: &str| {
+            let mut request = url.as_str().into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert(SUBPROTOCOL_HEADER, offer.parse().unwrap());
+            request
+        };
+        let record =
+            |message: &str| serde_cbor::to_vec(&devlog!(Level::Info, "app", message)).unwrap();
+
+        // 古いクライアントは提示しない
+        let (mut client, response) = connect(url.as_str()).unwrap();
+        assert!(response.headers().get(SUBPROTOCOL_HEADER).is_none());
+        client
+            .write_message(Message::binary(record("plain")))
+            .unwrap();
+        client.close(None).unwrap();
+
+        // サーバーの優先順で選ぶ
+        let (mut client, response) =
+            connect(request("uplog.cbor.v1, uplog.cbor.deflate.v1")).unwrap();
+        assert_eq!(
+            response.headers()[SUBPROTOCOL_HEADER],
+            Codec::CborDeflate.subprotocol()
+        );
+        let frame = Codec::CborDeflate
+            .encode(&record("deflate"))
+            .unwrap()
+            .into_owned();
+        client.write_message(Message::binary(frame)).unwrap();
+        client.close(None).unwrap();
+
+        // 辞書は接続の間だけ引き継ぐ
+        let (mut client, response) = connect(request("uplog.cbor.dict.v1, uplog.cbor.v1")).unwrap();
+        assert_eq!(
+            response.headers()[SUBPROTOCOL_HEADER],
+            Codec::CborDict.subprotocol()
+        );
+        let mut encoder = WireEncoder::new();
+        for message in ["dict1", "dict2"] {
+            let frame = encoder.encode(&record(message)).unwrap();
+            client.write_message(Message::binary(frame)).unwrap();
+        }
+        client.close(None).unwrap();
+
+        match connect(request("uplog.msgpack.v1")) {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), 400)
+            }
+            x => panic!("unexpected result {:?}", x.map(|x| x.1)),
+        }
+
+        let mut messages = wait_for(|| {
+            let mut messages = Vec::new();
+            for session in storage.records().ok()? {
+                let records = CBORSequenceReader::new(session.path())
+                    .ok()?
+                    .read_at(0, 10)
+                    .ok()?;
+                messages.extend(
+                    records
+                        .into_iter()
+                        .filter(|x| !is_server_record(&x.record))
+                        .map(|x| x.record.message),
+                );
+            }
+            (messages.len() == 4).then_some(messages)
+        });
+        messages.sort();
+        assert_eq!(messages, vec!["deflate", "dict1", "dict2", "plain"]);
+        server.stop(false).unwrap();
+    }
+
+    /// 正常に閉じた場合、無通信で閉じた場合と上限を超えて閉じた場合の開始と終了のレコード
+    #[test]
+    fn test_session_open_close_records() {
+        use crate::{
+            lifecycle::SESSION_CATEGORY,
+            reader::{CBORSequenceReader, StorageReader},
+        };
+        use tungstenite::protocol::CloseFrame;
+        use uplog::protocol::CloseReason;
+        use uplog::{devinit, devlog, Level, Value};
+
+        devinit!();
+        let dir = TempDir::new("lifecycle").unwrap();
+        let server = start_server(&dir, |x| ServerOptions {
+            idle_timeout: Some(Duration::from_millis(200)),
+            max_connection_bytes: Some(1024),
+            ..x
+        });
+        let storage = server.storage().clone();
+        let url = ws_url(&server);
+        let send = |client: &mut tungstenite::WebSocket<_>, message: &str| {
+            let mut r = devlog!(
+                Level::Info,
+                "app",
+                message,
+                "pad",
+                "x".repeat(message.len() * 100)
+            );
+            r.elapsed = Duration::from_secs(5);
+            client
+                .write_message(Message::binary(serde_cbor::to_vec(&r).unwrap()))
+                .unwrap();
+        };
+        let (mut clean, _) = connect(url.as_str()).unwrap();
+        send(&mut clean, "clean");
+        clean
+            .close(Some(CloseFrame {
+                code: CloseCode::from(CloseReason::Flush.code()),
+                reason: "flush".into(),
+            }))
+            .unwrap();
+        let read_close = |client: &mut tungstenite::WebSocket<_>| loop {
+            if let Message::Close(frame) = client.read_message().unwrap() {
+                break frame.unwrap().code;
+            }
+        };
+        let (mut idle, _) = connect(url.as_str()).unwrap();
+        send(&mut idle, "idle");
+        assert_eq!(
+            read_close(&mut idle),
+            CloseCode::from(CloseReason::IdleTimeout.code())
+        );
+        // 2つ目のメッセージで上限を超える
+        let (mut quota, _) = connect(url.as_str()).unwrap();
+        send(&mut quota, "quota");
+        send(&mut quota, "quota");
+        assert_eq!(
+            read_close(&mut quota),
+            CloseCode::from(CloseReason::Quota.code())
+        );
+
+        // 閉じたレコードまで揃ったセッションをクライアントのメッセージで引く
+        let sessions = wait_for(|| {
+            let sessions = storage
+                .records()
+                .ok()?
+                .iter()
+                .map(|x| {
+                    let records = CBORSequenceReader::new(x.path())
+                        .ok()?
+                        .read_at(0, 10)
+                        .ok()?;
+                    Some((records, x.meta().end_reason.clone()?))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            sessions
+                .iter()
+                .all(|x| x.0.last().is_some_and(|x| x.record.message == "closed"))
+                .then_some(sessions)
+        });
+        assert_eq!(sessions.len(), 3);
+        for (records, end_reason) in sessions {
+            let (opened, closed) = (&records[0].record, &records[2].record);
+            assert_eq!(records.len(), 3);
+            assert!(is_server_record(opened) && is_server_record(closed));
+            assert!(!is_server_record(&records[1].record));
+            assert_eq!(opened.category, SESSION_CATEGORY);
+            assert_eq!(opened.message, "opened");
+            let kv = opened.kv.as_ref().unwrap();
+            assert!(matches!(&kv["client_addr"], Value::Text(x) if x.starts_with("127.0.0.1")));
+            assert_eq!(kv["codec"], Value::Text("uplog.cbor.v1".to_string()));
+
+            let kv = closed.kv.as_ref().unwrap();
+            let (reason, code, end) = match records[1].record.message.as_str() {
+                "clean" => ("client", 4000, "client:flush"),
+                "idle" => ("idle_timeout", 4100, "idle_timeout"),
+                "quota" => ("quota", 4101, "quota"),
+                x => panic!("unexpected session {}", x),
+            };
+            assert_eq!(kv["reason"], Value::Text(reason.to_string()));
+            assert_eq!(kv["close_code"], Value::U64(code));
+            assert_eq!(end_reason, end);
+            assert_eq!(kv["records"], Value::U64(1));
+            assert_eq!(closed.elapsed, Duration::from_secs(5));
+        }
+        server.stop(false).unwrap();
+    }
+
+    /// 時刻を送ったクライアントには応答に時刻を入れて返し、ずれを付加情報に残す
+    #[test]
+    fn test_client_clock_offset() {
+        use tungstenite::client::IntoClientRequest;
+        use uplog::{devinit, devlog, protocol::CLIENT_TIME_HEADER, Level};
+
+        devinit!();
+        let dir = TempDir::new("clock").unwrap();
+        let server = start_server(&dir, |x| x);
+        let storage = server.storage().clone();
+
+        // クライアントの時計が5秒遅れている
+        let client_time = chrono::Utc::now().timestamp_millis() - 5000;
+        let mut request = ws_url(&server).into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert(CLIENT_TIME_HEADER, client_time.to_string().parse().unwrap());
+        let (mut client, _) = connect(request).unwrap();
+        let record = devlog!(Level::Info, "app", "clock");
+        client
+            .write_message(Message::binary(serde_cbor::to_vec(&record).unwrap()))
+            .unwrap();
+        let ack = loop {
+            if let Message::Binary(bin) = client.read_message().unwrap() {
+                break serde_cbor::from_slice::<ServerMessage>(&bin).unwrap();
+            }
+        };
+        match ack {
+            ServerMessage::Ack(ack) => {
+                assert_eq!(ack.received, 1);
+                let offset = ack.server_time_ms - client_time;
+                assert!((offset - 5000).abs() < 1000, "{}", offset);
+            }
+            x => panic!("unexpected message {:?}", x),
+        }
+
+        let offset = wait_for(|| storage.records().ok()?.first()?.meta.clock_offset_ms);
+        assert!((offset - 5000).abs() < 1000, "{}", offset);
+        client.close(None).unwrap();
+        server.stop(false).unwrap();
+    }
+
+    #[test]
+    fn test_ingest_endpoint_parse() {
+        let endpoint = "/staging=staging".parse::<IngestEndpoint>().unwrap();
+        assert_eq!(endpoint, IngestEndpoint::new("/staging").label("staging"));
+        assert_eq!(
+            "/ingest".parse::<IngestEndpoint>().unwrap(),
+            IngestEndpoint::default()
+        );
+        assert!("ingest".parse::<IngestEndpoint>().is_err());
+        assert!("/staging=".parse::<IngestEndpoint>().is_err());
+
+        // 以前のパスを指定していれば加えない
+        let endpoints = IngestEndpoint::with_legacy(vec![IngestEndpoint::default()]);
+        assert_eq!(
+            endpoints,
+            vec![IngestEndpoint::default(), IngestEndpoint::legacy()]
+        );
+        let endpoints = IngestEndpoint::with_legacy(vec![IngestEndpoint::new(uplog::WS_PATH)]);
+        assert_eq!(endpoints, vec![IngestEndpoint::new(uplog::WS_PATH)]);
+    }
+
+    /// ラベルを付けたパスと以前のパスのどちらでも受け付ける
+    #[test]
+    fn test_ingest_endpoints() {
+        use uplog::{devinit, devlog, Level};
+
+        devinit!();
+        let dir = TempDir::new("endpoints").unwrap();
+        let server = start_server(&dir, |x| ServerOptions {
+            ingest_endpoints: IngestEndpoint::with_legacy(vec![
+                IngestEndpoint::default(),
+                IngestEndpoint::new("/staging").label("staging"),
+            ]),
+            ..x
+        });
+        let storage = server.storage().clone();
+        let addr = format!("127.0.0.1:{}", server.port());
+
+        let mut labels = Vec::new();
+        for path in ["/staging", uplog::WS_PATH, uplog::INGEST_PATH] {
+            let (mut client, _) = connect(format!("ws://{}{}", addr, path)).unwrap();
+            let record = devlog!(Level::Info, "app", path);
+            client
+                .write_message(Message::binary(serde_cbor::to_vec(&record).unwrap()))
+                .unwrap();
+            client.close(None).unwrap();
+            let session = wait_for(|| {
+                storage
+                    .records()
+                    .ok()?
+                    .into_iter()
+                    .find(|x| !labels.iter().any(|(name, _)| *name == x.name()))
+                    // 最初のレコードから開始時刻を求めている
+                    .filter(|x| x.meta.start_at.is_some())
+            });
+            labels.push((session.name(), session.meta.label));
+        }
+        let labels = labels.into_iter().map(|(_, x)| x).collect::<Vec<_>>();
+        assert_eq!(labels, vec![Some("staging".to_string()), None, None]);
+
+        // 登録していないパスは受け付けない
+        assert!(connect(format!("ws://{}/unknown", addr)).is_err());
+        server.stop(false).unwrap();
+    }
+
+    /// レコードを送らない接続はセッションを作らずに閉じる
+    #[test]
+    fn test_handshake_validation() {
+        use super::{rejected_handshakes, HandshakePolicy};
+        use uplog::{devinit, devlog, Level};
+
+        devinit!();
+        let dir = TempDir::new("handshake").unwrap();
+        let server = start_server(&dir, |x| ServerOptions {
+            handshake: HandshakePolicy {
+                grace: Duration::from_millis(200),
+                max_invalid_frames: 2,
+            },
+            ..x
+        });
+        let storage = server.storage().clone();
+        let url = ws_url(&server);
+        let read_close = |client: &mut tungstenite::WebSocket<_>| loop {
+            if let Message::Close(frame) = client.read_message().unwrap() {
+                break frame.unwrap().code;
+            }
+        };
+        let before = rejected_handshakes();
+
+        // 何も送らずに閉じる
+        let (mut client, _) = connect(url.as_str()).unwrap();
+        client.close(None).unwrap();
+        // 何も送らないまま待つ
+        let (mut idle, _) = connect(url.as_str()).unwrap();
+        assert_eq!(read_close(&mut idle), CloseCode::from(4104));
+        // uplogのレコードではないデータを送る
+        let (mut garbage, _) = connect(url.as_str()).unwrap();
+        for _ in 0..2 {
+            garbage
+                .write_message(Message::binary(b"GET / HTTP/1.1\r\n".to_vec()))
+                .unwrap();
+        }
+        assert_eq!(read_close(&mut garbage), CloseCode::from(4104));
+        assert!(rejected_handshakes() >= before + 2);
+        assert!(storage.records().unwrap().is_empty());
+
+        // レコードを送ればセッションを作る
+        let (mut valid, _) = connect(url.as_str()).unwrap();
+        let record = devlog!(Level::Info, "app", "valid");
+        valid
+            .write_message(Message::binary(serde_cbor::to_vec(&record).unwrap()))
+            .unwrap();
+        let name = wait_for(|| Some(storage.records().ok()?.first()?.name()));
+        // 猶予を過ぎても閉じない
+        thread::sleep(Duration::from_millis(300));
+        valid.write_message(Message::Ping(Vec::new())).unwrap();
+        valid.close(None).unwrap();
+        let records = wait_for(|| {
+            let records = storage
+                .session_records(&name)
+                .ok()?
+                .filter_map(Result::ok)
+                .filter(|x| !is_server_record(x))
+                .collect::<Vec<_>>();
+            (!records.is_empty()).then_some(records)
+        });
+        assert_eq!(records[0].message, "valid");
+        assert_eq!(storage.records().unwrap().len(), 1);
+        server.stop(false).unwrap();
+    }
+
+    /// 同じ5MBのバイト列を2回書き込み、1ファイルだけ保存されて読み戻せることを確認する
+    #[test]
+    fn test_blob_offload() {
+        use super::{SessionActor, SessionCommand};
+        use crate::{
+            blob::{blob_hash, placeholder, BLOB_DIR},
+            lifecycle::CloseReason,
+            reader::{CBORSequenceReader, StorageReader},
+        };
+        use uplog::{devinit, devlog, Level, Value};
+
+        devinit!();
+        let dir = TempDir::new("blob").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let session = storage.create_session("blob").unwrap();
+        let blob = (0..5 * 1024 * 1024).map(|x| x as u8).collect::<Vec<_>>();
+        let small = vec![1_u8; 16];
+        let records = (0..2)
+            .map(|_| {
+                devlog!(
+                    Level::Info,
+                    "camera",
+                    "frame",
+                    "image",
+                    blob.clone(),
+                    "thumb",
+                    small.clone()
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut sys = actix_web::rt::System::new("blob");
+        sys.block_on(async move {
+            let addr = SessionActor::new(session, Some(1024)).start();
+            for r in records {
+                addr.send(SessionCommand::Record(r)).await.unwrap();
+            }
+            addr.send(SessionCommand::Close(CloseReason::Client(None)))
+                .await
+                .unwrap();
+        });
+        drop(sys);
+
+        let session_dir = dir.path().join("blob");
+        let blobs = std::fs::read_dir(session_dir.join(BLOB_DIR))
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        let hash = blob_hash(&blob);
+        assert_eq!(blobs, vec![hash.clone()]);
+
+        let records = CBORSequenceReader::new(&session_dir)
+            .unwrap()
+            .read_at(0, 10)
+            .unwrap()
+            .into_iter()
+            .filter(|x| !is_server_record(&x.record))
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        for r in records.iter() {
+            let kv = r.record.kv.as_ref().unwrap();
+            assert_eq!(
+                placeholder(&kv["image"]),
+                Some((hash.as_str(), blob.len() as u64))
+            );
+            // 閾値以下はそのまま
+            assert_eq!(kv["thumb"], Value::Bytes(small.clone()));
+        }
+        assert_eq!(storage.read_blob("blob", &hash).unwrap(), blob);
+        assert_eq!(storage.blob_len("blob", &hash).unwrap(), blob.len() as u64);
+        assert!(storage.read_blob("blob", "../seqdata").is_err());
+
+        // 書き出し時に戻せる
+        let manifest = storage
+            .archive_session_reinlined("blob", Vec::new())
+            .unwrap();
+        assert!(manifest.files.iter().all(|x| !x.name.starts_with(BLOB_DIR)));
+        let manifest = storage.archive_session("blob", Vec::new()).unwrap();
+        assert!(manifest
+            .files
+            .iter()
+            .any(|x| x.name == format!("{}/{}", BLOB_DIR, hash)));
+        let mut record = records[0].record.clone();
+        crate::BlobStore::new(&session_dir)
+            .reinline(&mut record)
+            .unwrap();
+        assert_eq!(record.kv.unwrap()["image"], Value::Bytes(blob));
+    }
+
+    /// 閉じたときに異常の印を残し、一覧をその印で絞り込めることを確認する
+    #[test]
+    fn test_health_on_close() {
+        use super::{SessionActor, SessionCommand};
+        use crate::{lifecycle::CloseReason, HealthFlag, SessionQuery, SessionSortKey, SortOrder};
+        use uplog::{devinit, devlog, Level, CLIENT_CATEGORY};
+
+        devinit!();
+        let dir = TempDir::new("health").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let info = || devlog!(Level::Info, "app", "ok");
+        let sessions = vec![
+            ("clean", vec![info()], 0, CloseReason::Client(None)),
+            (
+                "errors",
+                vec![devlog!(Level::Error, "app", "failed")],
+                0,
+                CloseReason::Client(None),
+            ),
+            ("decode", vec![info()], 2, CloseReason::DecodeError),
+            ("idle", vec![info()], 0, CloseReason::IdleTimeout),
+            (
+                "gaps",
+                vec![
+                    devlog!(
+                        Level::Warn,
+                        CLIENT_CATEGORY,
+                        "dropped 3 records",
+                        "dropped",
+                        3_u64
+                    ),
+                    info(),
+                ],
+                0,
+                CloseReason::Client(None),
+            ),
+            ("skew", vec![info()], 0, CloseReason::Client(None)),
+        ];
+        let mut sys = actix_web::rt::System::new("health");
+        for (name, records, failures, reason) in sessions {
+            let session = storage.create_session(name).unwrap();
+            if name == "skew" {
+                storage.set_session_clock_offset(name, -5000).unwrap();
+            }
+            sys.block_on(async move {
+                let addr = SessionActor::new(session, None).start();
+                for r in records {
+                    addr.send(SessionCommand::Record(r)).await.unwrap();
+                }
+                if failures > 0 {
+                    addr.send(SessionCommand::DecodeFailures(failures))
+                        .await
+                        .unwrap();
+                }
+                addr.send(SessionCommand::Close(reason)).await.unwrap();
+            });
+        }
+        drop(sys);
+
+        let health = |name: &str| storage.session_meta(name).unwrap().health.unwrap();
+        let clean = health("clean");
+        assert_eq!(clean.score, 100);
+        assert!(health("errors").has_errors);
+        // kvの値の型も閉じるときに残す
+        let kv_types = storage.session_meta("gaps").unwrap().kv_types.unwrap();
+        assert_eq!(kv_types.column("dropped"), crate::kvtype::ColumnType::U64);
+        assert_eq!(storage.kv_types("gaps").unwrap(), kv_types);
+        assert!(health("decode").had_decode_failures);
+        assert!(health("decode").ended_uncleanly);
+        assert_eq!(storage.session_meta("decode").unwrap().decode_failures, 2);
+        assert!(health("idle").ended_uncleanly);
+        assert!(health("gaps").gaps_detected);
+        assert!(!health("gaps").has_errors);
+        assert!(health("skew").clock_skew_detected);
+        for name in ["errors", "decode", "idle", "gaps", "skew"] {
+            assert!(health(name).score < 100, "{}", name);
+        }
+
+        let flagged = |flag| {
+            storage
+                .records_paged(&SessionQuery {
+                    sort_by: SessionSortKey::Name,
+                    order: SortOrder::Asc,
+                    health: Some(flag),
+                    ..Default::default()
+                })
+                .unwrap()
+                .sessions
+                .iter()
+                .map(|x| x.name())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(flagged(HealthFlag::HasErrors), ["errors"]);
+        assert_eq!(flagged(HealthFlag::HadDecodeFailures), ["decode"]);
+        assert_eq!(flagged(HealthFlag::EndedUncleanly), ["decode", "idle"]);
+        assert_eq!(flagged(HealthFlag::GapsDetected), ["gaps"]);
+        assert_eq!(flagged(HealthFlag::ClockSkewDetected), ["skew"]);
+    }
+
+    /// 3つに分けて送られた添付ファイルを閉じたときにつなげ、元のファイルをダウンロードできる
+    #[test]
+    fn test_attachment_download() {
+        use super::{SessionActor, SessionCommand};
+        use crate::{blob::blob_hash, lifecycle::CloseReason, webapi};
+        use actix_web::{test, web, App};
+        use uplog::{devinit, Attachment};
+
+        devinit!();
+        let dir = TempDir::new("attachment").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let session = storage.create_session("dump").unwrap();
+        let dump = (0..3000_u32).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        let attachment = Attachment::new("core.dmp", &dump).chunk_size(1024);
+        let records = attachment.records("app.crash");
+        assert_eq!(records.len(), 3);
+        let id = attachment.id().to_string();
+
+        let mut sys = actix_web::rt::System::new("attachment");
+        let downloaded = sys.block_on(async move {
+            // 閾値より小さくても部分はblobにする
+            let addr = SessionActor::new(session, Some(1 << 20)).start();
+            for r in records {
+                addr.send(SessionCommand::Record(r)).await.unwrap();
+            }
+            addr.send(SessionCommand::Close(CloseReason::Client(None)))
+                .await
+                .unwrap();
+
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(storage.clone()))
+                    .service(
+                        web::resource(format!("{}/{{name}}/{{id}}", webapi::ATTACHMENT_PATH))
+                            .route(web::get().to(webapi::download_attachment)),
+                    ),
+            )
+            .await;
+            let uri = |id: &str| format!("{}/dump/{}", webapi::ATTACHMENT_PATH, id);
+            let res = test::call_service(
+                &mut app,
+                test::TestRequest::get().uri(&uri("missing")).to_request(),
+            )
+            .await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+            let res = test::call_service(
+                &mut app,
+                test::TestRequest::get().uri(&uri(&id)).to_request(),
+            )
+            .await;
+            assert!(res.status().is_success());
+            assert_eq!(
+                res.headers().get("content-type").unwrap(),
+                "application/x-dmp"
+            );
+            assert_eq!(
+                res.headers().get("content-disposition").unwrap(),
+                "attachment; filename=\"core.dmp\""
+            );
+            (test::read_body(res).await.to_vec(), storage)
+        });
+        drop(sys);
+        let (body, storage) = downloaded;
+        assert_eq!(body, dump);
+        let attachments = storage.attachments("dump").unwrap();
+        assert_eq!(attachments.len(), 1);
+        assert_eq!((attachments[0].parts, attachments[0].received), (3, 3));
+        assert_eq!(attachments[0].hash, Some(blob_hash(&dump)));
+    }
+
+    /// ストレージが読み取り専用の間は新しいセッションを断り、既存の接続は予約した量まで書く
+    #[test]
+    fn test_read_only_storage() {
+        use std::sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        };
+
+        use crate::{
+            diskwatch::{DiskGuard, DiskPolicy, FreeSpace},
+            reader::{CBORSequenceReader, StorageReader},
+        };
+        use uplog::{devinit, devlog, Level};
+
+        struct FakeSpace(Arc<AtomicU64>);
+        impl FreeSpace for FakeSpace {
+            fn free_bytes(&self) -> std::io::Result<u64> {
+                Ok(self.0.load(Ordering::SeqCst))
+            }
+        }
+
+        devinit!();
+        let dir = TempDir::new("readonly").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let guard = DiskGuard::new(DiskPolicy {
+            reserved_bytes: 1024,
+            ..DiskPolicy::new(100)
+        });
+        let free = Arc::new(AtomicU64::new(1000));
+        let space = FakeSpace(free.clone());
+        guard.check(&space, None).unwrap();
+        // run_serverは空き容量の取得を差し替えられないので、判定するDiskGuardを直接渡す
+        let (sender, receiver) = channel();
+        {
+            let storage = storage.clone();
+            let guard = guard.clone();
+            thread::spawn(move || {
+                let mut sys = actix_web::rt::System::new("readonly");
+                sys.block_on(async move {
+                    let storage_addr = StorageActor::new(storage).start();
+                    let server = HttpServer::new(move || {
+                        App::new()
+                            .data(storage_addr.clone())
+                            .app_data(Data::new(guard.clone()))
+                            .service(web::resource(uplog::WS_PATH).route(web::get().to(ws_index)))
+                    })
+                    .bind("127.0.0.1:0")
+                    .unwrap();
+                    sender.send(server.addrs()[0]).unwrap();
+                    server.run().await.unwrap();
+                });
+            });
+        }
+        let addr = receiver.recv().unwrap();
+        let url = format!("ws://{}{}", addr, uplog::WS_PATH);
+        // 閉じた後は送れないので結果だけ返す
+        let send = |client: &mut tungstenite::WebSocket<_>| {
+            let r = devlog!(Level::Info, "app", "msg", "pad", "x".repeat(200));
+            client
+                .write_message(Message::binary(serde_cbor::to_vec(&r).unwrap()))
+                .is_ok()
+        };
+        let read_close = |client: &mut tungstenite::WebSocket<_>| loop {
+            if let Message::Close(frame) = client.read_message().unwrap() {
+                break frame.unwrap().code;
+            }
+        };
+
+        let (mut existing, _) = connect(url.as_str()).unwrap();
+        assert!(send(&mut existing));
+        wait_for(|| (storage.records().ok()?.len() == 1).then_some(()));
+
+        free.store(10, Ordering::SeqCst);
+        assert!(guard.check(&space, None).unwrap());
+        let (mut new, _) = connect(url.as_str()).unwrap();
+        assert!(send(&mut new));
+        assert_eq!(read_close(&mut new), CloseCode::from(4106));
+
+        // 予約した量を超えるまでは書ける
+        for _ in 0..10 {
+            if !send(&mut existing) {
+                break;
+            }
+        }
+        assert_eq!(read_close(&mut existing), CloseCode::from(4106));
+        // 終了のレコードまで書き出されるのを待つ
+        let (sessions, records) = wait_for(|| {
+            let sessions = storage.records().ok()?;
+            let records = CBORSequenceReader::new(sessions[0].path())
+                .ok()?
+                .read_at(0, 20)
+                .ok()?;
+            (sessions[0].meta().end_reason.is_some() && records.last()?.record.message == "closed")
+                .then_some((sessions, records))
+        });
+        assert_eq!(sessions.len(), 1);
+        // 最初のレコードと予約に収まった分だけ書いている
+        let written = records
+            .iter()
+            .filter(|x| !is_server_record(&x.record))
+            .count();
+        assert!(written > 1 && written < 11, "{}", written);
+        assert_eq!(
+            sessions[0].meta().end_reason.as_deref(),
+            Some("storage_full")
+        );
+    }
 }
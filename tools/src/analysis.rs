@@ -0,0 +1,70 @@
+//! actixとGraphQLを使わずにデータファイルを読んで調べるためのAPI
+//!
+//! `web`機能を外してもビルドできるモジュールだけをまとめる。依存はserde_cborとuplogとストレージの最低限
+//!
+//! ```
+//! use uplog_tools::analysis::{self, filter::Filter, stats::SessionStats};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! # let dir = tempdir::TempDir::new("doc")?;
+//! # uplog_tools::Storage::new_shared(dir.path())?.create_session("run1")?;
+//! let session = dir.path().join("run1");
+//! let errors: Filter = "level >= error".parse().unwrap();
+//! for record in analysis::open(&session)? {
+//!     let record = record?;
+//!     if errors.matches(&record) {
+//!         println!("{}", record.message);
+//!     }
+//! }
+//! println!("{:?}", SessionStats::collect(&session)?.categories);
+//! # Ok(())
+//! # }
+//! ```
+use std::{io, path::Path};
+
+pub use crate::{
+    filter, reader, stats, verify,
+    view::{RecordTime, RecordView},
+    RecordIter, SessionInfo, Storage,
+};
+
+/// Iterates the records of a session directory, or of a data file copied out of one.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<RecordIter> {
+    if path.as_ref().is_dir() {
+        RecordIter::new(path)
+    } else {
+        RecordIter::from_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level};
+
+    use super::open;
+    use crate::{writer::RecordWriter, Storage};
+
+    #[test]
+    fn test_open() {
+        devinit!();
+        let dir = TempDir::new("analysis").unwrap();
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        let mut session = storage.create_session("run").unwrap();
+        for i in 0..3_u32 {
+            session
+                .push(&devlog!(Level::Info, "cat", "msg", "i", i))
+                .unwrap();
+        }
+        drop(session);
+
+        let session_dir = dir.path().join("run");
+        let copy = dir.path().join("copy.cbor");
+        std::fs::copy(session_dir.join("seqdata"), &copy).unwrap();
+        for path in [&session_dir, &copy] {
+            let records = open(path).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(records.len(), 3, "{}", path.display());
+        }
+        assert!(open(dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+//! セッションを外部に渡す前の匿名化
+//!
+//! ルールファイルでkvのキーと正規表現ごとに削除、ハッシュ、マスクを指定する。
+//! ハッシュは1回の実行の中では同じ値から同じ結果になるので、値の対応関係は残る
+//!
+//! ```toml
+//! # 省略すると実行ごとに作る
+//! salt = "vendor-2026"
+//!
+//! [keys]
+//! serial = "hash"
+//! comment = "drop"
+//! user = "mask"
+//!
+//! [patterns]
+//! '\b\d{1,3}(\.\d{1,3}){3}\b' = "hash"
+//! ```
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    fmt::{self, Debug},
+    hash::{BuildHasher, Hasher},
+    io,
+    path::Path,
+    str::FromStr,
+    time::SystemTime,
+};
+
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use uplog::{Record, Value};
+
+/// Rewrites the records of a session on their way out, into a new session with
+/// [`crate::Storage::transform_session`] or into an archive with
+/// [`crate::Storage::archive_session_transformed`].
+pub trait ExportTransform: Debug {
+    fn transform(&self, record: &mut Record);
+}
+
+/// マスクした値
+pub const MASK: &str = "***";
+
+/// ハッシュした値の16進の桁数
+const HASH_DIGITS: usize = 16;
+
+/// What to do with a value matched by a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// remove the kv entry, or the matched part of a text
+    Drop,
+    /// replace with a salted hash, the same for the same value within one run
+    Hash,
+    /// replace with [`MASK`]
+    Mask,
+}
+
+impl FromStr for Action {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(Self::Drop),
+            "hash" => Ok(Self::Hash),
+            "mask" => Ok(Self::Mask),
+            _ => Err(invalid_rules(format!(
+                "unknown action {}, expected drop, hash or mask",
+                s
+            ))),
+        }
+    }
+}
+
+fn invalid_rules<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
+
+/// Rules of [`Anonymizer`], read from a TOML file.
+///
+/// `[keys]` maps kv keys to an action, at any depth of nested maps. `[patterns]` maps regular
+/// expressions to an action on the matched part of the message and of text values not covered
+/// by `[keys]`. Patterns are applied in the order of the file.
+#[derive(Debug, Clone, Default)]
+pub struct AnonymizeRules {
+    pub salt: Option<String>,
+    pub keys: BTreeMap<String, Action>,
+    pub patterns: Vec<(Regex, Action)>,
+}
+
+impl AnonymizeRules {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        std::fs::read_to_string(path.as_ref())?
+            .parse()
+            .map_err(|e: io::Error| {
+                io::Error::new(e.kind(), format!("{}: {}", path.as_ref().display(), e))
+            })
+    }
+}
+
+impl FromStr for AnonymizeRules {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let doc = s.parse::<toml_edit::Document>().map_err(invalid_rules)?;
+        let mut rules = Self::default();
+        for (key, item) in doc.iter() {
+            match key {
+                "salt" => {
+                    let salt = item
+                        .as_str()
+                        .ok_or_else(|| invalid_rules("salt must be a string"))?;
+                    rules.salt = Some(salt.to_string());
+                }
+                "keys" => {
+                    for (k, action) in actions(key, item)? {
+                        rules.keys.insert(k.to_string(), action);
+                    }
+                }
+                "patterns" => {
+                    for (pattern, action) in actions(key, item)? {
+                        let re = Regex::new(pattern).map_err(invalid_rules)?;
+                        rules.patterns.push((re, action));
+                    }
+                }
+                _ => return Err(invalid_rules(format!("unknown rule {}", key))),
+            }
+        }
+        Ok(rules)
+    }
+}
+
+/// `[keys]`と`[patterns]`の表を順に読む
+fn actions<'a>(name: &str, item: &'a toml_edit::Item) -> io::Result<Vec<(&'a str, Action)>> {
+    let table = item
+        .as_table_like()
+        .ok_or_else(|| invalid_rules(format!("{} must be a table", name)))?;
+    table
+        .iter()
+        .map(|(k, v)| {
+            let action = v
+                .as_str()
+                .ok_or_else(|| invalid_rules(format!("action of {} must be a string", k)))?;
+            Ok((k, action.parse()?))
+        })
+        .collect()
+}
+
+/// 実行ごとのsalt。時刻とプロセスとハッシュの乱数から作る
+fn run_salt() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |x| x.as_nanos());
+    hasher.write_u128(nanos);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Scrubs records by [`AnonymizeRules`].
+pub struct Anonymizer {
+    rules: AnonymizeRules,
+    salt: String,
+}
+
+impl Debug for Anonymizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // saltは出さない
+        f.debug_struct("Anonymizer")
+            .field("keys", &self.rules.keys)
+            .field("patterns", &self.rules.patterns.len())
+            .finish()
+    }
+}
+
+impl Anonymizer {
+    /// Without a salt in the rules, a new one is made for this anonymizer.
+    pub fn new(rules: AnonymizeRules) -> Self {
+        let salt = rules.salt.clone().unwrap_or_else(run_salt);
+        Self { rules, salt }
+    }
+
+    /// テキストはそのまま、それ以外はエンコードした値をハッシュする
+    fn hash(&self, value: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update([0]);
+        hasher.update(value);
+        let digest = hasher.finalize();
+        digest
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()[..HASH_DIGITS]
+            .to_string()
+    }
+
+    fn hash_value(&self, value: &Value) -> String {
+        match value {
+            Value::Text(x) => self.hash(x.as_bytes()),
+            x => self.hash(&serde_cbor::to_vec(x).unwrap_or_default()),
+        }
+    }
+
+    fn scrub_text(&self, text: &str) -> Option<String> {
+        let mut result = None;
+        for (re, action) in self.rules.patterns.iter() {
+            let current = result.as_deref().unwrap_or(text);
+            if !re.is_match(current) {
+                continue;
+            }
+            let replaced = re.replace_all(current, |x: &regex::Captures| match action {
+                Action::Drop => String::new(),
+                Action::Hash => self.hash(x[0].as_bytes()),
+                Action::Mask => MASK.to_string(),
+            });
+            result = Some(replaced.into_owned());
+        }
+        result
+    }
+
+    fn scrub_value(&self, value: &mut Value) {
+        match value {
+            Value::Text(x) => {
+                if let Some(scrubbed) = self.scrub_text(x) {
+                    *x = scrubbed;
+                }
+            }
+            Value::Array(x) => x.iter_mut().for_each(|x| self.scrub_value(x)),
+            Value::Map(x) => self.scrub_map(x),
+            _ => {}
+        }
+    }
+
+    fn scrub_map(&self, map: &mut BTreeMap<String, Value>) {
+        map.retain(|k, _| self.rules.keys.get(k) != Some(&Action::Drop));
+        for (k, v) in map.iter_mut() {
+            match self.rules.keys.get(k) {
+                Some(Action::Hash) => *v = Value::Text(self.hash_value(v)),
+                Some(Action::Mask) => *v = Value::Text(MASK.to_string()),
+                _ => self.scrub_value(v),
+            }
+        }
+    }
+}
+
+impl ExportTransform for Anonymizer {
+    fn transform(&self, record: &mut Record) {
+        if let Some(scrubbed) = self.scrub_text(&record.message) {
+            record.message = scrubbed;
+        }
+        if let Some(kv) = record.kv.as_mut() {
+            self.scrub_map(kv);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level, Record, Value};
+
+    use super::{Action, AnonymizeRules, Anonymizer, ExportTransform, MASK};
+    use crate::{writer::RecordWriter, Storage};
+
+    const RULES: &str = r#"
+salt = "fixture"
+
+[keys]
+serial = "hash"
+comment = "drop"
+user = "mask"
+
+[patterns]
+'\b\d{1,3}(\.\d{1,3}){3}\b' = "hash"
+'token=\w+' = "drop"
+"#;
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = RULES.parse::<AnonymizeRules>().unwrap();
+        assert_eq!(rules.salt.as_deref(), Some("fixture"));
+        assert_eq!(rules.keys["serial"], Action::Hash);
+        assert_eq!(rules.keys["comment"], Action::Drop);
+        assert_eq!(rules.patterns.len(), 2);
+        assert_eq!(rules.patterns[1].1, Action::Drop);
+
+        for (rules, error) in [
+            ("[keys]\nserial = \"shred\"", "unknown action shred"),
+            ("[patterns]\n'(' = \"drop\"", "regex parse error"),
+            ("salt = 1", "salt must be a string"),
+            ("[filters]\na = \"drop\"", "unknown rule filters"),
+            ("keys = \"serial\"", "keys must be a table"),
+        ] {
+            let e = rules.parse::<AnonymizeRules>().unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+            assert!(e.to_string().contains(error), "{}: {}", rules, e);
+        }
+    }
+
+    #[test]
+    fn test_hash_is_deterministic() {
+        let rules = "[keys]\nserial = \"hash\""
+            .parse::<AnonymizeRules>()
+            .unwrap();
+        let record = || Record::builder().message("m").kv("serial", "SN-1").build();
+        let hashed = |anonymizer: &Anonymizer| {
+            let mut r = record();
+            anonymizer.transform(&mut r);
+            r.kv.unwrap()["serial"].clone()
+        };
+        let a = Anonymizer::new(rules.clone());
+        assert_eq!(hashed(&a), hashed(&a));
+        // saltがなければ実行ごとに変わる
+        let b = Anonymizer::new(rules);
+        assert_ne!(hashed(&a), hashed(&b));
+    }
+
+    #[test]
+    fn test_anonymize_session() {
+        devinit!();
+        let dir = TempDir::new("anonymize").unwrap();
+        let rules_path = dir.path().join("rules.toml");
+        std::fs::write(&rules_path, RULES).unwrap();
+        let storage = Storage::new(dir.path().join("data")).unwrap();
+        let mut session = storage.create_session("field").unwrap();
+        for (i, ip) in ["10.0.0.1", "10.0.0.2", "10.0.0.1"].into_iter().enumerate() {
+            let mut r = devlog!(
+                Level::Info,
+                "net",
+                &format!("connected from {} token=abc{}", ip, i),
+                "ip",
+                ip
+            );
+            let kv = r.kv.as_mut().unwrap();
+            kv.insert("serial".to_string(), Value::from("SN-0042"));
+            kv.insert("comment".to_string(), Value::from("call Alice"));
+            kv.insert("user".to_string(), Value::from("alice"));
+            kv.insert("retries".to_string(), Value::U64(i as u64));
+            kv.insert(
+                "peer".to_string(),
+                Value::Map(
+                    [
+                        ("serial".to_string(), Value::from("SN-0099")),
+                        ("port".to_string(), Value::U64(8040)),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+            );
+            session.push(&r).unwrap();
+        }
+        drop(session);
+
+        let anonymizer = Anonymizer::new(AnonymizeRules::from_file(&rules_path).unwrap());
+        let count = storage
+            .transform_session("field", "shared", &anonymizer)
+            .unwrap();
+        assert_eq!(count, 3);
+        let records = storage
+            .session_records("shared")
+            .unwrap()
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records.len(), 3);
+
+        let ip = |r: &Record| r.kv.as_ref().unwrap()["ip"].as_str().unwrap().to_string();
+        for (i, r) in records.iter().enumerate() {
+            let kv = r.kv.as_ref().unwrap();
+            // 残すもの
+            assert_eq!(r.category, "net");
+            assert_eq!(kv["retries"], Value::U64(i as u64));
+            assert_eq!(kv["peer"].as_map().unwrap()["port"], Value::U64(8040));
+            // 消すもの
+            assert!(!kv.contains_key("comment"));
+            assert_eq!(kv["user"], Value::from(MASK));
+            assert!(!r.message.contains("10.0.0"), "{}", r.message);
+            assert!(!r.message.contains("token"), "{}", r.message);
+            assert!(r.message.starts_with("connected from "));
+            let serial = kv["serial"].as_str().unwrap();
+            assert_eq!(serial.len(), 16);
+            assert_ne!(serial, "SN-0042");
+            assert_ne!(
+                kv["peer"].as_map().unwrap()["serial"],
+                Value::from("SN-0099")
+            );
+            // メッセージとkvの同じIPは同じ値になる
+            assert!(r.message.contains(&ip(r)), "{}", r.message);
+        }
+        // 同じ値の対応関係は残る
+        assert_eq!(ip(&records[0]), ip(&records[2]));
+        assert_ne!(ip(&records[0]), ip(&records[1]));
+        assert_eq!(
+            storage.session_meta("shared").unwrap().parent.as_deref(),
+            Some("field")
+        );
+        // 書き出し先が既にあればエラー
+        assert!(storage
+            .transform_session("field", "shared", &anonymizer)
+            .is_err());
+    }
+
+    #[test]
+    fn test_archive_transformed() {
+        devinit!();
+        let dir = TempDir::new("anonymize").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let mut session = storage.create_session("field").unwrap();
+        session
+            .push(&devlog!(Level::Info, "app", "hello", "user", "alice"))
+            .unwrap();
+        drop(session);
+
+        let anonymizer = Anonymizer::new("[keys]\nuser = \"drop\"".parse().unwrap());
+        let mut buf = Vec::new();
+        storage
+            .archive_session_transformed("field", &mut buf, &anonymizer)
+            .unwrap();
+        let restored = Storage::new(dir.path().join("restored")).unwrap();
+        restored.restore_archive(&buf[..]).unwrap();
+        let records = restored
+            .session_records("field")
+            .unwrap()
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records[0].message, "hello");
+        assert!(!records[0].kv.as_ref().unwrap().contains_key("user"));
+    }
+}
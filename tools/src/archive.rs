@@ -0,0 +1,266 @@
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use uplog::Record;
+
+use crate::{
+    anonymize::ExportTransform,
+    blob::{is_valid_hash, BlobStore, BLOB_DIR},
+    writer::CBORSequenceWriter,
+};
+
+/// 1セッションを1ファイルにまとめる独自形式
+///
+/// | MAGIC (8byte) | manifest length (u64 LE) | manifest (CBOR) | file data ... |
+pub(crate) const MAGIC: &[u8; 8] = b"UPLOGARC";
+/// 互換性のない変更をしたら上げる
+pub const ARCHIVE_FORMAT_VERSION: u32 = 1;
+/// アーカイブファイルの拡張子
+pub const ARCHIVE_EXTENSION: &str = "uplog";
+
+/// アーカイブの目録
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub session: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+/// アーカイブに含まれるファイル
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub len: u64,
+    /// FNV-1a 64bit
+    pub checksum: u64,
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// 壊れていないことの確認用。暗号学的な強度は求めない
+pub(crate) fn checksum(data: &[u8]) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    data.iter()
+        .fold(OFFSET, |h, b| (h ^ *b as u64).wrapping_mul(PRIME))
+}
+
+/// セッションディレクトリ内のファイルと`blobs/`以下をまとめて書き出す
+///
+/// reinline_blobsの場合はblobをレコードに戻したseqdataを書き出し、blobとindexは含めない。
+/// `transform`はblobを戻したレコードを書き換える
+pub(crate) fn write_archive<W: Write>(
+    session_dir: &Path,
+    session: &str,
+    mut writer: W,
+    reinline_blobs: bool,
+    transform: Option<&dyn ExportTransform>,
+) -> io::Result<Manifest> {
+    let encrypted = crate::SessionMeta::load(session_dir)?.encryption.is_some();
+    if encrypted && transform.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "session {} is encrypted, transform it into a new session first",
+                session
+            ),
+        ));
+    }
+    // 暗号化したセッションはblobを分離しないので、復号せずにそのまま書き出す
+    let reinline_blobs = (reinline_blobs || transform.is_some()) && !encrypted;
+    let mut names = list_files(session_dir)?;
+    if reinline_blobs {
+        // つなげた添付ファイルもblobなので、レコードの部分から組み立て直させる。
+        // 位置を持つindexと概要は書き直したデータに合わないので含めない
+        names.retain(|x| {
+            x != CBORSequenceWriter::INDEX_FILENAME
+                && x != crate::overview::OVERVIEW_FILENAME
+                && x != crate::attachment::ATTACHMENTS_FILENAME
+        });
+    } else if session_dir.join(BLOB_DIR).is_dir() {
+        names.extend(
+            list_files(&session_dir.join(BLOB_DIR))?
+                .into_iter()
+                .filter(|x| is_valid_hash(x))
+                .map(|x| format!("{}/{}", BLOB_DIR, x)),
+        );
+    }
+    names.sort();
+
+    let mut contents = Vec::with_capacity(names.len());
+    let mut files = Vec::with_capacity(names.len());
+    for name in names {
+        let buf = if reinline_blobs && name == CBORSequenceWriter::FILENAME {
+            reinline_seqdata(session_dir, transform)?
+        } else {
+            let mut buf = Vec::new();
+            File::open(session_dir.join(&name))?.read_to_end(&mut buf)?;
+            buf
+        };
+        files.push(ManifestEntry {
+            name,
+            len: buf.len() as u64,
+            checksum: checksum(&buf),
+        });
+        contents.push(buf);
+    }
+    let manifest = Manifest {
+        version: ARCHIVE_FORMAT_VERSION,
+        session: session.to_string(),
+        files,
+    };
+    let encoded = serde_cbor::to_vec(&manifest).map_err(invalid_data)?;
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(encoded.len() as u64).to_le_bytes())?;
+    writer.write_all(&encoded)?;
+    for buf in contents {
+        writer.write_all(&buf)?;
+    }
+    writer.flush()?;
+    Ok(manifest)
+}
+
+fn list_files(dir: &Path) -> io::Result<Vec<String>> {
+    Ok(std::fs::read_dir(dir)?
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .map(|x| x.file_name().to_string_lossy().to_string())
+        .collect())
+}
+
+/// blobをレコードに戻して書き直す
+fn reinline_seqdata(
+    session_dir: &Path,
+    transform: Option<&dyn ExportTransform>,
+) -> io::Result<Vec<u8>> {
+    let store = BlobStore::new(session_dir);
+    let f = File::open(session_dir.join(CBORSequenceWriter::FILENAME))?;
+    let mut buf = Vec::new();
+    for record in serde_cbor::Deserializer::from_reader(io::BufReader::new(f)).into_iter::<Record>()
+    {
+        let mut record = record.map_err(invalid_data)?;
+        store.reinline(&mut record)?;
+        if let Some(t) = transform {
+            t.transform(&mut record);
+        }
+        serde_cbor::to_writer(&mut buf, &record).map_err(invalid_data)?;
+    }
+    Ok(buf)
+}
+
+/// アーカイブ内のファイル名として受け付けるか
+fn is_valid_entry_name(name: &str) -> bool {
+    match name.split_once('/') {
+        Some((dir, hash)) => dir == BLOB_DIR && is_valid_hash(hash),
+        None => !name.contains('\\') && !name.starts_with('.') && !name.is_empty(),
+    }
+}
+
+/// `len`バイト読む。長さは信用できないので先に確保せず、読めた分だけ大きくする
+fn read_exact_len<R: Read>(reader: &mut R, len: u64, what: &str) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("archive ends in {}: {} of {} bytes", what, buf.len(), len),
+        ));
+    }
+    Ok(buf)
+}
+
+/// アーカイブを読み込みチェックサムを検証する
+pub(crate) fn read_archive<R: Read>(mut reader: R) -> io::Result<(Manifest, Vec<Vec<u8>>)> {
+    let mut magic = [0_u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a uplog archive"));
+    }
+    let mut len = [0_u8; 8];
+    reader.read_exact(&mut len)?;
+    let encoded = read_exact_len(&mut reader, u64::from_le_bytes(len), "manifest")?;
+    let manifest: Manifest = serde_cbor::from_slice(&encoded).map_err(invalid_data)?;
+    if manifest.version != ARCHIVE_FORMAT_VERSION {
+        return Err(invalid_data(format!(
+            "unsupported archive version {}",
+            manifest.version
+        )));
+    }
+
+    let mut contents = Vec::with_capacity(manifest.files.len());
+    for entry in manifest.files.iter() {
+        if !is_valid_entry_name(&entry.name) {
+            return Err(invalid_data(format!("invalid file name {}", entry.name)));
+        }
+        let buf = read_exact_len(&mut reader, entry.len, &entry.name)?;
+        if checksum(&buf) != entry.checksum {
+            return Err(invalid_data(format!("checksum mismatch {}", entry.name)));
+        }
+        contents.push(buf);
+    }
+    Ok((manifest, contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        checksum, read_archive, write_archive, Manifest, ManifestEntry, ARCHIVE_FORMAT_VERSION,
+        MAGIC,
+    };
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_checksum_mismatch() -> std::io::Result<()> {
+        let dir = TempDir::new("archive")?;
+        std::fs::write(dir.path().join("seqdata"), b"nkmm drawings")?;
+        let mut buf = Vec::new();
+        let manifest = write_archive(dir.path(), "s", &mut buf, false, None)?;
+        assert_eq!(manifest.files[0].checksum, checksum(b"nkmm drawings"));
+
+        let (restored, contents) = read_archive(&buf[..])?;
+        assert_eq!(restored, manifest);
+        assert_eq!(contents[0], b"nkmm drawings");
+
+        // 末尾のデータを壊す
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        let err = read_archive(&buf[..]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    /// 長さを偽ったアーカイブは長さの分を確保せずに失敗する
+    #[test]
+    fn test_oversized_lengths() {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&u64::MAX.to_le_bytes());
+        buf.extend_from_slice(b"short");
+        let err = read_archive(&buf[..]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let manifest = Manifest {
+            version: ARCHIVE_FORMAT_VERSION,
+            session: "s".to_string(),
+            files: vec![ManifestEntry {
+                name: "seqdata".to_string(),
+                len: u64::MAX / 2,
+                checksum: 0,
+            }],
+        };
+        let encoded = serde_cbor::to_vec(&manifest).unwrap();
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+        buf.extend_from_slice(&encoded);
+        buf.extend_from_slice(b"nkmm");
+        let err = read_archive(&buf[..]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(err.to_string().contains("seqdata"), "{}", err);
+    }
+}
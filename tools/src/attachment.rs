@@ -0,0 +1,333 @@
+//! クライアントが`uplog::attach`で送ったファイル
+//!
+//! 部分のバイト列は閾値によらずblobとして保存し、セッションを閉じるときに
+//! 揃った部分をつなげて1つのblobにする。つなげた結果は`attachments.json`に残す。
+//! 閉じる前のセッションや暗号化したセッションはダウンロードのときにレコードから組み立てる
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use uplog::{Record, Value, ATTACHMENT_KEY};
+
+use crate::{
+    blob::{placeholder, BlobStore},
+    RecordIter,
+};
+
+/// つなげた結果を残すセッションディレクトリ内のファイル
+pub const ATTACHMENTS_FILENAME: &str = "attachments.json";
+
+/// A file a client attached to the session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: String,
+    pub filename: String,
+    pub mime: String,
+    /// category of the records
+    pub category: String,
+    /// bytes of the whole file
+    pub len: u64,
+    pub parts: u32,
+    /// parts found in the session
+    pub received: u32,
+    /// blob of the joined file, written when the session is closed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl Attachment {
+    pub fn is_complete(&self) -> bool {
+        self.received == self.parts
+    }
+}
+
+/// 部分のバイト列
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PartData {
+    Blob(String),
+    Inline(Vec<u8>),
+    /// 一覧を作るときは読まない
+    Skipped,
+}
+
+/// レコードの`_attachment`の内容
+#[derive(Debug)]
+struct Part {
+    attachment: Attachment,
+    part: u32,
+    data: PartData,
+}
+
+fn part(record: &Record, keep_data: bool) -> Option<Part> {
+    let map = record.kv.as_ref()?.get(ATTACHMENT_KEY)?.as_map()?;
+    let text = |key: &str| map.get(key).and_then(Value::as_str).map(str::to_string);
+    let number = |key: &str| map.get(key).and_then(Value::as_u64);
+    let parts = number("parts")? as u32;
+    let part = number("part")? as u32;
+    if part == 0 || part > parts {
+        return None;
+    }
+    let data = match map.get("bytes")? {
+        _ if !keep_data => PartData::Skipped,
+        Value::Bytes(x) => PartData::Inline(x.clone()),
+        x => PartData::Blob(placeholder(x)?.0.to_string()),
+    };
+    Some(Part {
+        attachment: Attachment {
+            id: text("id")?,
+            filename: text("filename")?,
+            mime: text("mime").unwrap_or_else(|| "application/octet-stream".to_string()),
+            category: record.category.clone(),
+            len: number("len")?,
+            parts,
+            received: 0,
+            hash: None,
+        },
+        part,
+        data,
+    })
+}
+
+/// 同じIDの部分を集めたもの
+#[derive(Debug)]
+struct Pending {
+    attachment: Attachment,
+    data: BTreeMap<u32, PartData>,
+}
+
+#[derive(Debug, Default)]
+struct Collector {
+    pending: BTreeMap<String, Pending>,
+}
+
+impl Collector {
+    fn add(&mut self, part: Part) {
+        let entry = self
+            .pending
+            .entry(part.attachment.id.clone())
+            .or_insert_with(|| Pending {
+                attachment: part.attachment,
+                data: BTreeMap::new(),
+            });
+        entry.data.insert(part.part, part.data);
+        entry.attachment.received = entry.data.len() as u32;
+    }
+
+    fn into_attachments(self) -> impl Iterator<Item = Pending> {
+        self.pending.into_values()
+    }
+}
+
+impl Pending {
+    /// 揃っていれば部分をつなげる
+    fn join(&self, blobs: &BlobStore) -> io::Result<Vec<u8>> {
+        if !self.attachment.is_complete() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "attachment {} is incomplete, {} of {} parts",
+                    self.attachment.id, self.attachment.received, self.attachment.parts
+                ),
+            ));
+        }
+        let mut buf = Vec::with_capacity(self.attachment.len as usize);
+        for data in self.data.values() {
+            match data {
+                PartData::Blob(hash) => buf.extend(blobs.get(hash)?),
+                PartData::Inline(x) => buf.extend_from_slice(x),
+                PartData::Skipped => unreachable!("joined a listed attachment"),
+            }
+        }
+        Ok(buf)
+    }
+}
+
+/// 書き込み中のセッションで受け取った部分
+#[cfg(feature = "web")]
+#[derive(Debug, Default)]
+pub(crate) struct Assembler {
+    collector: Collector,
+}
+
+#[cfg(feature = "web")]
+impl Assembler {
+    /// 添付ファイルの部分であればバイト列をblobに書き出して覚えておく
+    pub(crate) fn add(&mut self, record: &mut Record, blobs: &BlobStore) -> io::Result<()> {
+        let value = match record
+            .kv
+            .as_mut()
+            .and_then(|x| x.get_mut(ATTACHMENT_KEY))
+            .and_then(|x| match x {
+                Value::Map(map) => map.get_mut("bytes"),
+                _ => None,
+            }) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        blobs.offload_value(value, 0)?;
+        if let Some(part) = part(record, true) {
+            self.collector.add(part);
+        }
+        Ok(())
+    }
+
+    /// 揃ったものをつなげて`attachments.json`に書く
+    pub(crate) fn finish(&mut self, session: &crate::Session) -> io::Result<()> {
+        let collector = std::mem::take(&mut self.collector);
+        if collector.pending.is_empty() {
+            return Ok(());
+        }
+        let mut joined = load(session.dir())?;
+        for pending in collector.into_attachments() {
+            let mut attachment = pending.attachment.clone();
+            if attachment.is_complete() {
+                attachment.hash = Some(session.blobs().put(&pending.join(session.blobs())?)?);
+            }
+            joined.insert(attachment.id.clone(), attachment);
+        }
+        let f = File::create(session.dir().join(ATTACHMENTS_FILENAME))?;
+        serde_json::to_writer_pretty(f, &joined.into_values().collect::<Vec<_>>())?;
+        Ok(())
+    }
+}
+
+/// 閉じたときにつなげたもの。ファイルがなければ空
+fn load(session_dir: &Path) -> io::Result<BTreeMap<String, Attachment>> {
+    let f = match File::open(session_dir.join(ATTACHMENTS_FILENAME)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+    let list: Vec<Attachment> = serde_json::from_reader(BufReader::new(f))?;
+    Ok(list.into_iter().map(|x| (x.id.clone(), x)).collect())
+}
+
+/// `keep`のIDの部分だけバイト列を持ってレコードから集める
+fn collect(session_dir: &Path, keep: Option<&str>) -> io::Result<Collector> {
+    let mut collector = Collector::default();
+    for record in RecordIter::new(session_dir)? {
+        let record = record?;
+        let keep_data = keep.is_some_and(|id| {
+            record
+                .kv
+                .as_ref()
+                .and_then(|x| x.get(ATTACHMENT_KEY))
+                .and_then(Value::as_map)
+                .and_then(|x| x.get("id"))
+                .and_then(Value::as_str)
+                == Some(id)
+        });
+        if let Some(part) = part(&record, keep_data) {
+            if keep.is_none() || keep_data {
+                collector.add(part);
+            }
+        }
+    }
+    Ok(collector)
+}
+
+/// セッションの添付ファイルをIDの順に返す
+pub fn list(session_dir: &Path) -> io::Result<Vec<Attachment>> {
+    let joined = load(session_dir)?;
+    Ok(collect(session_dir, None)?
+        .into_attachments()
+        .map(|x| Attachment {
+            hash: joined.get(&x.attachment.id).and_then(|x| x.hash.clone()),
+            ..x.attachment
+        })
+        .collect())
+}
+
+/// 添付ファイルの内容を読む。閉じたときにつなげたものがなければ部分から組み立てる
+pub fn read(session_dir: &Path, id: &str) -> io::Result<(Attachment, Vec<u8>)> {
+    let blobs = BlobStore::new(session_dir);
+    if let Some(attachment) = load(session_dir)?.remove(id) {
+        if let Some(hash) = attachment.hash.as_ref() {
+            let buf = blobs.get(hash)?;
+            return Ok((attachment, buf));
+        }
+    }
+    let pending = collect(session_dir, Some(id))?
+        .pending
+        .remove(id)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("attachment not found: {}", id),
+            )
+        })?;
+    let buf = pending.join(&blobs)?;
+    Ok((pending.attachment, buf))
+}
+
+#[cfg(all(test, feature = "web"))]
+mod tests {
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Attachment as ClientAttachment, Level};
+
+    use super::{list, read, Assembler, ATTACHMENTS_FILENAME};
+    use crate::{blob::BLOB_DIR, writer::RecordWriter, Storage};
+
+    #[test]
+    fn test_assemble_attachment() {
+        devinit!();
+        let dir = TempDir::new("attachment").unwrap();
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        let mut session = storage.create_session("run").unwrap();
+        let dump = (0..2500_u32).map(|x| x as u8).collect::<Vec<_>>();
+        let client = ClientAttachment::new("core.dmp", &dump).chunk_size(1000);
+        let config = ClientAttachment::new("config.json", b"{}");
+        let lost = (0..20_u8).collect::<Vec<_>>();
+        let incomplete = ClientAttachment::new("lost.bin", &lost).chunk_size(10);
+
+        let mut assembler = Assembler::default();
+        let records = client
+            .records("app.crash")
+            .into_iter()
+            .chain(config.records("app.config"))
+            .chain(incomplete.records("app").into_iter().take(1));
+        for (i, mut record) in records.enumerate() {
+            assembler.add(&mut record, session.blobs()).unwrap();
+            session.push(&record).unwrap();
+            if i == 0 {
+                session.push(&devlog!(Level::Info, "app", "msg")).unwrap();
+            }
+        }
+        session.flush();
+
+        // 閉じる前はレコードから組み立てる
+        let (attachment, buf) = read(session.dir(), client.id()).unwrap();
+        assert_eq!((attachment.parts, attachment.hash), (3, None));
+        assert_eq!(buf, dump);
+
+        assembler.finish(&session).unwrap();
+        assert!(session.dir().join(ATTACHMENTS_FILENAME).exists());
+        let attachments = list(session.dir()).unwrap();
+        assert_eq!(attachments.len(), 3);
+        let find = |id: &str| attachments.iter().find(|x| x.id == id).unwrap().clone();
+        let joined = find(client.id());
+        assert_eq!(joined.filename, "core.dmp");
+        assert_eq!(joined.mime, "application/x-dmp");
+        assert_eq!(joined.category, "app.crash");
+        assert_eq!((joined.len, joined.parts, joined.received), (2500, 3, 3));
+        assert_eq!(
+            joined.hash.as_deref(),
+            Some(crate::blob::blob_hash(&dump).as_str())
+        );
+        assert_eq!(read(session.dir(), client.id()).unwrap().1, dump);
+        assert_eq!(read(session.dir(), config.id()).unwrap().1, b"{}");
+
+        let lost = find(incomplete.id());
+        assert!(!lost.is_complete());
+        assert_eq!(lost.hash, None);
+        let e = read(session.dir(), incomplete.id()).unwrap_err();
+        assert!(e.to_string().contains("1 of 2 parts"), "{}", e);
+        assert!(read(session.dir(), "missing").is_err());
+        // 部分はblobに書き出している
+        assert!(session.dir().join(BLOB_DIR).exists());
+    }
+}
@@ -0,0 +1,155 @@
+//! 保存先を変更した操作の記録
+//!
+//! 保存先ルートのJSON Linesファイルに追記する。
+//! 複数のプロセスが同時に追記しても行が混ざらないようにファイルロックをかける
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+
+/// 保存先ルートに作るファイル名
+pub const AUDIT_FILENAME: &str = "audit.log";
+
+/// 保持期間や空き容量のために自動で削除した場合の実行者
+pub const INITIATOR_RETENTION: &str = "retention";
+
+/// コマンドラインなど、実行者を指定しなかった場合
+pub const INITIATOR_LOCAL: &str = "local";
+
+/// 変更の種類
+pub const OP_DELETE: &str = "delete";
+pub const OP_PRUNE: &str = "prune";
+pub const OP_TRIM: &str = "trim";
+pub const OP_RESTORE: &str = "restore";
+pub const OP_TRANSFORM: &str = "transform";
+
+/// 1回の変更
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub operation: String,
+    pub session: String,
+    pub at: DateTime<Utc>,
+    /// 削除や書き出しをしたバイト数。失敗した場合はそれまでの分
+    pub bytes: u64,
+    /// GraphQLのクライアントのアドレスか[`INITIATOR_RETENTION`]など
+    pub initiator: String,
+    /// 書き出し先など、操作の相手になったセッション
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// 失敗した場合の理由
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(operation: &str, session: &str, initiator: &str) -> Self {
+        Self {
+            operation: operation.to_string(),
+            session: session.to_string(),
+            at: Utc::now(),
+            bytes: 0,
+            initiator: initiator.to_string(),
+            target: None,
+            error: None,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} bytes by {}",
+            self.at.to_rfc3339(),
+            self.operation,
+            self.session,
+            self.bytes,
+            self.initiator
+        )?;
+        if let Some(x) = self.target.as_ref() {
+            write!(f, " into {}", x)?;
+        }
+        if let Some(x) = self.error.as_ref() {
+            write!(f, " failed: {}", x)?;
+        }
+        Ok(())
+    }
+}
+
+/// 1行追記する
+pub(crate) fn append(root: &Path, entry: &AuditEntry) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(root.join(AUDIT_FILENAME))?;
+    f.lock_exclusive()?;
+    let result = f.write_all(&line).and_then(|_| f.sync_data());
+    f.unlock()?;
+    result
+}
+
+/// 新しい順に最大`limit`件読む。ファイルがなければ空
+///
+/// 書きかけや壊れた行は読み飛ばす
+pub fn read(root: &Path, limit: Option<usize>) -> io::Result<Vec<AuditEntry>> {
+    let f = match File::open(root.join(AUDIT_FILENAME)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    f.lock_shared()?;
+    let lines = BufReader::new(&f).lines().collect::<io::Result<Vec<_>>>();
+    f.unlock()?;
+    let mut entries = lines?
+        .iter()
+        .filter_map(|x| serde_json::from_str::<AuditEntry>(x).ok())
+        .collect::<Vec<_>>();
+    entries.reverse();
+    if let Some(limit) = limit {
+        entries.truncate(limit);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use super::{append, read, AuditEntry, AUDIT_FILENAME};
+
+    #[test]
+    fn test_audit_append_read() {
+        let dir = TempDir::new("audit").unwrap();
+        assert!(read(dir.path(), None).unwrap().is_empty());
+
+        let mut first = AuditEntry::new("delete", "a", "127.0.0.1");
+        first.bytes = 10;
+        append(dir.path(), &first).unwrap();
+        // 途中で止まった書き込みは読み飛ばす
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(AUDIT_FILENAME))
+            .unwrap();
+        writeln!(f, "{{\"operation\":").unwrap();
+        let mut second = AuditEntry::new("prune", "b", "retention");
+        second.error = Some("busy".to_string());
+        append(dir.path(), &second).unwrap();
+
+        assert_eq!(read(dir.path(), None).unwrap(), [second.clone(), first]);
+        assert_eq!(read(dir.path(), Some(1)).unwrap(), [second]);
+    }
+}
@@ -3,58 +3,52 @@ use std::{
     time::{Duration, Instant},
 };
 
-use actix::prelude::*;
-
-use actix_cors::Cors;
 use actix_http::http::header;
-use actix_web::{
-    guard,
-    web::{self, Data},
-    App, Error, HttpRequest, HttpResponse, HttpServer,
-};
-use actix_web_actors::ws;
-use async_graphql::{EmptyMutation, EmptySubscription, Schema};
-use env_logger::Env;
-use log::{debug, error, info};
+use env_logger::{Env, Target};
+use log::{debug, error, info, warn};
 use serde_cbor::{to_vec, Deserializer};
 use structopt::StructOpt;
-use uplog::{Record, WS_PATH};
+use uplog::{ElapsedStyle, KvStyle, Record, RecordFormatter, INGEST_PATH};
 use uplog_tools::{
-    actor::StorageActor,
-    webapi::{self, Query},
-    Storage,
+    anonymize::{AnonymizeRules, Anonymizer},
+    cat::{self, CatFormat, CatOptions},
+    config::ServerConfig,
+    filter::Filter,
+    format::{pretty, PrettyOptions},
+    lifecycle::is_server_record,
+    logfile::{self, RotatingFile},
+    reader,
+    replay::ReplaySpeed,
+    resolve_data_dir,
+    scan::ScanOptions,
+    server::{run_server, ServerHandle, ServerOptions},
+    Deadline, HealthFlag, OnError, RecordIter, SessionQuery, SessionSortKey, SortOrder, Storage,
 };
-use uuid::Uuid;
-
-// Handle http request
-async fn ws_index(
-    req: HttpRequest,
-    stream: web::Payload,
-    srv: web::Data<Addr<StorageActor>>,
-) -> Result<HttpResponse, Error> {
-    let ip_addr: String = req
-        .connection_info()
-        .realip_remote_addr()
-        .map(|x| String::from(x))
-        .unwrap_or_else(|| String::from("unknown"));
-    let actor =
-        uplog_tools::actor::WsConn::new(Uuid::new_v4(), ip_addr, srv.get_ref().clone().recipient());
-    let mut res = ws::handshake(&req)?;
-    // デフォルトでは64KBのペイロードのため拡張する
-    let codec = actix_http::ws::Codec::new().max_size(uplog::DEFAULT_BUFFER_SIZE);
-    let out_stream = ws::WebsocketContext::with_codec(actor, stream, codec);
-    let res = res.streaming(out_stream);
-    Ok(res)
-}
 
 #[derive(Debug, PartialEq, StructOpt)]
 struct Opt {
     #[structopt(long, short)]
     debug: bool,
+    /// write the log of this tool to a file instead of stdout
+    #[structopt(long, global = true, parse(from_os_str), name = "LOG_FILE")]
+    log_file: Option<PathBuf>,
+    /// rename the log file to LOG_FILE.1 before it grows past this size
+    #[structopt(long, global = true, default_value = "10", name = "LOG_MB")]
+    log_rotate_mb: u64,
+    /// number of renamed log files to keep
+    #[structopt(long, global = true, default_value = "5", name = "LOG_FILES")]
+    log_keep: usize,
+    /// encrypt new sessions of the server with the key in this file, and decrypt them when
+    /// reading. The file holds 32 bytes or 64 hex digits
+    #[cfg(feature = "encryption")]
+    #[structopt(long, global = true, parse(from_os_str), name = "KEY_FILE")]
+    encrypt_key_file: Option<PathBuf>,
     #[structopt(subcommand)]
     sub: Subcommands,
 }
 
+// 起動時に1度解析するだけなのでBoxにしない
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, PartialEq, StructOpt)]
 enum Subcommands {
     /// log receive server
@@ -63,42 +57,213 @@ enum Subcommands {
     Dev(DevOpt),
     /// read data dir and file
     Read(ReadOpt),
+    /// stream the records of a session to stdout, e.g. to pipe into grep or jq
+    Cat(CatOpt),
+    /// pack a session into a single portable file
+    Archive(ArchiveOpt),
+    /// restore a session from an archive file
+    Unarchive(UnarchiveOpt),
+    /// resend a stored session to another server
+    Replay(ReplayOpt),
+    /// copy a time range of a session into a new session
+    Trim(TrimOpt),
+    /// keep a session from being removed to free space, or release it with --unpin
+    Pin(PinOpt),
+    /// copy a session into a new session, scrubbing values by a rules file
+    Anonymize(AnonymizeOpt),
+    /// check the files of sessions after an unclean shutdown
+    Verify(VerifyOpt),
+    /// compare record counts per category of sessions
+    Stats(StatsOpt),
+    /// print the CBOR items of a data file in diagnostic notation
+    Inspect(InspectOpt),
+    /// print the log of deleted, pruned, trimmed and restored sessions, newest first
+    Audit(AuditOpt),
+    /// list the clients connected to a running server, or disconnect one of them
+    ServerStatus(ServerStatusOpt),
+    /// browse sessions and records in the terminal
+    #[cfg(all(unix, feature = "tui"))]
+    Tui(TuiOpt),
 }
 
 #[derive(Debug, PartialEq, StructOpt)]
 struct ServerOpt {
+    /// toml file of the options below, e.g. `port = 8040`. UPLOG_SERVER_* environment
+    /// variables, e.g. UPLOG_SERVER_PORT, override it and the flags override both
+    #[structopt(long, parse(from_os_str), name = "CONFIG_FILE")]
+    config: Option<PathBuf>,
+    /// print the options merged from the defaults, the config file, the environment and the flags,
+    /// and exit. Run it without other options to see the defaults
+    #[structopt(long)]
+    print_config: bool,
     /// listen port
-    #[structopt(long, short, default_value = "8040")]
-    port: u16,
+    #[structopt(long, short)]
+    port: Option<u16>,
     /// uplog database directory
-    #[structopt(long, short, default_value = "~/uplog", name = "DATA_DIR")]
-    data_dir: String,
+    #[structopt(long, short, name = "DATA_DIR")]
+    data_dir: Option<String>,
     /// webview static file directory
-    #[structopt(long, default_value = "./view", name = "VIEW_DIR")]
-    view_dir: String,
+    #[structopt(long, name = "VIEW_DIR")]
+    view_dir: Option<String>,
+    /// websocket path receiving records, optionally labeling its sessions, e.g. `/staging=staging`.
+    /// Repeat to listen on several paths. `/logger` is also accepted until the next release
+    #[structopt(long = "ws-path", name = "PATH[=LABEL]")]
+    ws_paths: Vec<String>,
+    /// close the connection after this many consecutive undecodable messages
+    #[structopt(long)]
+    max_decode_failures: Option<u64>,
+    /// reject records (and byte/text values) larger than this many bytes without decoding them
+    #[structopt(long, name = "MAX_BYTES")]
+    max_record_bytes: Option<usize>,
+    /// stamp the server receive time on every record as `_ingest.received_at`
+    #[structopt(long)]
+    ingest_receive_time: bool,
+    /// stamp the client ip on every record as `_ingest.client_ip`
+    #[structopt(long)]
+    ingest_client_ip: bool,
+    /// stamp the connection uuid on every record as `_ingest.connection_id`
+    #[structopt(long)]
+    ingest_connection_id: bool,
+    /// keep at most this many kv entries of a record and count the rest as `_kv_truncated`
+    #[structopt(long, name = "ENTRIES")]
+    max_kv_entries: Option<usize>,
+    /// store bytes values larger than this many bytes in `blobs/` of the session
+    #[structopt(long, name = "BYTES")]
+    blob_threshold: Option<usize>,
+    /// start a new session (`<uuid>-<n>`) on each `uplog.boundary` record
+    #[structopt(long)]
+    split_on_boundary: bool,
+    /// when a client reconnects while its old connection looks alive:
+    /// reject the new one, take over the old session, or write a parallel session
+    #[structopt(long, possible_values = &["reject", "takeover", "parallel"])]
+    duplicate_policy: Option<String>,
+    /// times to retry writing a record after the storage failed, before dropping it
+    #[structopt(long, name = "RETRIES")]
+    write_retries: Option<u32>,
+    /// records waiting for a retry over this many bytes are spilled to a file in the session
+    #[structopt(long, name = "QUEUE_BYTES")]
+    retry_queue_bytes: Option<usize>,
+    /// memory for caching repeated graphql reads of the same records, 0 to disable
+    #[structopt(long, name = "MB")]
+    query_cache_mb: Option<usize>,
+    /// also accept clients on the same host through this unix domain socket
+    #[structopt(long, parse(from_os_str), name = "SOCKET")]
+    uds_path: Option<PathBuf>,
+    /// permissions of the socket file in octal, e.g. 660
+    #[structopt(long, name = "MODE", parse(try_from_str = parse_mode))]
+    uds_mode: Option<u32>,
+    /// close connections that send nothing for this many seconds
+    #[structopt(long, name = "SECONDS", parse(try_from_str = parse_seconds))]
+    idle_timeout: Option<Duration>,
+    /// close connections that send more than this many bytes in total
+    #[structopt(long, name = "CONNECTION_BYTES")]
+    max_connection_bytes: Option<u64>,
+    /// stop accepting new sessions when the storage volume has fewer free bytes than this
+    #[structopt(long, name = "MIN_FREE")]
+    min_free_bytes: Option<u64>,
+    /// accept new sessions again at this many free bytes, default 10% above --min-free-bytes
+    #[structopt(long, name = "RESUME_FREE")]
+    resume_free_bytes: Option<u64>,
+    /// how often to check the free space
+    #[structopt(long, name = "CHECK_SECONDS", parse(try_from_str = parse_seconds))]
+    disk_check_interval: Option<Duration>,
+    /// bytes each open connection may still send after the storage became read-only
+    #[structopt(long, name = "RESERVED")]
+    reserved_bytes: Option<u64>,
+    /// remove the oldest closed sessions when the free space is below --min-free-bytes
+    #[structopt(long)]
+    prune_when_full: bool,
+    /// refuse to pin a session when the pinned sessions would take more bytes than this
+    #[structopt(long, name = "PINNED_BYTES")]
+    max_pinned_bytes: Option<u64>,
+    /// close connections that send no record within this many seconds, without creating a session
+    #[structopt(long, name = "GRACE_SECONDS", parse(try_from_str = parse_seconds))]
+    handshake_grace: Option<Duration>,
+    /// close connections whose first messages are all undecodable, without creating a session
+    #[structopt(long)]
+    max_handshake_failures: Option<u64>,
+    /// check and repair the most recent session before listening
+    #[structopt(long)]
+    verify_on_start: bool,
+    /// reject graphql queries nested deeper than this
+    #[structopt(long)]
+    max_query_depth: Option<usize>,
+    /// reject graphql queries selecting more fields than this, counting list fields once per item
+    #[structopt(long)]
+    max_query_complexity: Option<usize>,
+    /// most records a graphql query reads at once
+    #[structopt(long)]
+    max_read_length: Option<usize>,
+    /// abort graphql reads scanning a session file for longer than this, 0 to disable
+    #[structopt(long, name = "QUERY_SECONDS", parse(try_from_str = parse_seconds))]
+    query_timeout: Option<Duration>,
+    /// sessions read at the same time by graphql searches and stats over several sessions
+    #[structopt(long, name = "THREADS")]
+    scan_threads: Option<usize>,
+    /// toml file of bearer tokens and the sessions each may read, tail or mutate, and of the
+    /// tenants whose sessions are kept in their own directory.
+    /// Every request may access every session without it
+    #[structopt(long, parse(from_os_str), name = "ACL_FILE")]
+    acl_file: Option<PathBuf>,
+}
+
+fn parse_mode(src: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(src, 8)
 }
 
 impl ServerOpt {
-    fn get_data_dir(&self) -> Option<PathBuf> {
-        if self.data_dir.is_empty() {
-            return None;
-        }
-        if self.data_dir.starts_with("~/") {
-            let data_local_dir = dirs::data_local_dir()?;
-            Some(data_local_dir.join(&self.data_dir[2..]))
-        } else {
-            Some(PathBuf::from(&self.data_dir))
+    /// コマンドラインで指定したものだけを設定にする
+    fn flags(&self) -> ServerConfig {
+        let seconds = |x: Option<Duration>| x.map(|x| x.as_secs_f64());
+        ServerConfig {
+            port: self.port,
+            data_dir: self.data_dir.clone(),
+            view_dir: self.view_dir.clone(),
+            ws_paths: Some(self.ws_paths.clone()).filter(|x| !x.is_empty()),
+            max_decode_failures: self.max_decode_failures,
+            max_record_bytes: self.max_record_bytes,
+            ingest_receive_time: self.ingest_receive_time.then_some(true),
+            ingest_client_ip: self.ingest_client_ip.then_some(true),
+            ingest_connection_id: self.ingest_connection_id.then_some(true),
+            max_kv_entries: self.max_kv_entries,
+            blob_threshold: self.blob_threshold,
+            split_on_boundary: self.split_on_boundary.then_some(true),
+            duplicate_policy: self.duplicate_policy.clone(),
+            write_retries: self.write_retries,
+            retry_queue_bytes: self.retry_queue_bytes,
+            query_cache_mb: self.query_cache_mb,
+            uds_path: self.uds_path.clone(),
+            uds_mode: self.uds_mode.map(|x| format!("{:o}", x)),
+            idle_timeout: seconds(self.idle_timeout),
+            max_connection_bytes: self.max_connection_bytes,
+            min_free_bytes: self.min_free_bytes,
+            resume_free_bytes: self.resume_free_bytes,
+            disk_check_interval: seconds(self.disk_check_interval),
+            reserved_bytes: self.reserved_bytes,
+            prune_when_full: self.prune_when_full.then_some(true),
+            max_pinned_bytes: self.max_pinned_bytes,
+            handshake_grace: seconds(self.handshake_grace),
+            max_handshake_failures: self.max_handshake_failures,
+            verify_on_start: self.verify_on_start.then_some(true),
+            max_query_depth: self.max_query_depth,
+            max_query_complexity: self.max_query_complexity,
+            max_read_length: self.max_read_length,
+            query_timeout: seconds(self.query_timeout),
+            scan_threads: self.scan_threads,
+            acl_file: self.acl_file.clone(),
         }
     }
 
-    fn get_view_dir(&self) -> Option<PathBuf> {
-        let path = PathBuf::from(&self.view_dir);
-        println!("view dir {:?}", &path);
-        if path.exists() {
-            Some(path)
-        } else {
-            None
+    /// 設定ファイル、環境変数、コマンドラインの順に重ねる
+    fn config(&self) -> std::io::Result<ServerConfig> {
+        // 環境変数はUTF-8でないものを読み飛ばす
+        let vars = std::env::vars_os()
+            .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)));
+        let (config, warnings) = ServerConfig::load(self.config.as_deref(), vars, self.flags())?;
+        for warning in warnings {
+            warn!("{}", warning);
         }
+        Ok(config)
     }
 }
 
@@ -126,6 +291,14 @@ fn parse_milliseconds(src: &str) -> Result<Duration, std::num::ParseIntError> {
     Ok(Duration::from_millis(n))
 }
 
+/// 小数を含む秒数
+fn parse_seconds(src: &str) -> Result<Duration, String> {
+    src.parse::<f64>()
+        .ok()
+        .and_then(|x| Duration::try_from_secs_f64(x).ok())
+        .ok_or_else(|| format!("invalid seconds {}", src))
+}
+
 #[derive(Debug, PartialEq, StructOpt)]
 struct ReadOpt {
     #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
@@ -133,6 +306,255 @@ struct ReadOpt {
     /// read file
     #[structopt(name = "FILE")]
     file: Option<String>,
+    /// print only records matching the expression, e.g. `level >= warn && kv.retries > 3`
+    #[structopt(long = "where", name = "EXPR")]
+    where_: Option<String>,
+    /// colored output with kv pairs on separate lines
+    #[structopt(long)]
+    pretty: bool,
+    /// do not use colors with --pretty
+    #[structopt(long)]
+    no_color: bool,
+    /// print the elapsed time as HH:MM:SS.mmm
+    #[structopt(long)]
+    hms: bool,
+    /// do not print the file and line of records
+    #[structopt(long)]
+    no_location: bool,
+    /// cut messages longer than this many characters
+    #[structopt(long, name = "CHARS")]
+    max_message_width: Option<usize>,
+    /// skip this many sessions of the listing
+    #[structopt(long, default_value = "0")]
+    offset: usize,
+    /// list at most this many sessions
+    #[structopt(long)]
+    limit: Option<usize>,
+    /// sort the listing by
+    #[structopt(long, default_value = "created", possible_values = &["created", "updated", "size", "name"])]
+    sort_by: SessionSortKey,
+    #[structopt(long, default_value = "asc", possible_values = &["asc", "desc"])]
+    order: SortOrder,
+    /// list only sessions whose name contains this
+    #[structopt(long, name = "TEXT")]
+    name_contains: Option<String>,
+    /// list only sessions with this tag
+    #[structopt(long)]
+    tag: Option<String>,
+    /// list only sessions whose recorded health has this flag
+    #[structopt(long, name = "FLAG", possible_values = &["has_errors", "had_decode_failures", "ended_uncleanly", "clock_skew_detected", "gaps_detected"])]
+    health: Option<HealthFlag>,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct ArchiveOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+    /// session name
+    #[structopt(long, short)]
+    session: String,
+    /// output file
+    #[structopt(long, short, parse(from_os_str))]
+    out: PathBuf,
+    /// put offloaded blobs back into the records
+    #[structopt(long)]
+    reinline_blobs: bool,
+    /// scrub the records by this rules file as `anonymize` does. Implies --reinline-blobs
+    #[structopt(long, parse(from_os_str), name = "RULES")]
+    rules: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct AnonymizeOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+    /// session name
+    #[structopt(long, short)]
+    session: String,
+    /// name of the new session
+    #[structopt(long, short)]
+    out: String,
+    /// TOML file mapping kv keys (`[keys]`) and regex patterns (`[patterns]`) to `drop`, `hash`
+    /// or `mask`. Hashes are salted by `salt`, or by a random salt for each run
+    #[structopt(long, parse(from_os_str), name = "RULES")]
+    rules: PathBuf,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct ReplayOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+    /// session name
+    #[structopt(long, short)]
+    session: String,
+    /// server url, e.g. ws://localhost:8040
+    #[structopt(long, short)]
+    target: String,
+    /// `asap`, or a factor dividing the recorded intervals such as `10x`
+    #[structopt(long, default_value = "1x")]
+    speed: ReplaySpeed,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct TrimOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+    /// session name
+    #[structopt(long, short)]
+    session: String,
+    /// start of the range in elapsed seconds, inclusive
+    #[structopt(long, name = "FROM", parse(try_from_str = parse_seconds))]
+    from: Duration,
+    /// end of the range in elapsed seconds, exclusive
+    #[structopt(long, name = "TO", parse(try_from_str = parse_seconds))]
+    to: Duration,
+    /// name of the new session
+    #[structopt(long, short)]
+    out: String,
+    /// make elapsed relative to the start of the range
+    #[structopt(long)]
+    rebase: bool,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct PinOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+    /// session name
+    #[structopt(name = "NAME")]
+    name: String,
+    /// release the pin
+    #[structopt(long)]
+    unpin: bool,
+    /// refuse to pin when the pinned sessions would take more bytes than this
+    #[structopt(long, name = "PINNED_BYTES")]
+    max_pinned_bytes: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct VerifyOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+    /// check only this session
+    #[structopt(long, short)]
+    session: Option<String>,
+    /// rebuild wrong index files and truncate a partial record at the end, keeping `.bak` copies
+    #[structopt(long)]
+    repair: bool,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct StatsOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+    /// comma separated session names, one column each
+    #[structopt(
+        long,
+        use_delimiter = true,
+        required_unless = "N",
+        conflicts_with = "N"
+    )]
+    sessions: Vec<String>,
+    /// compare the last N created sessions, oldest first
+    #[structopt(long, name = "N")]
+    latest: Option<usize>,
+    #[structopt(long, default_value = "csv", possible_values = &["csv", "json"])]
+    format: String,
+    /// sessions counted at the same time
+    #[structopt(long, default_value = "4", name = "THREADS")]
+    scan_threads: usize,
+    /// leave out sessions not counted within this many seconds
+    #[structopt(long, name = "SECONDS", parse(try_from_str = parse_seconds))]
+    timeout: Option<Duration>,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct CatOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+    /// session name, or `-` to read a CBOR sequence of records from stdin
+    #[structopt(long, short)]
+    session: String,
+    #[structopt(long, default_value = "jsonl", possible_values = &["jsonl", "csv", "pretty"])]
+    format: CatFormat,
+    /// write only records matching the expression, e.g. `level >= warn && kv.retries > 3`
+    #[structopt(long = "where", name = "EXPR")]
+    where_: Option<String>,
+    /// exit with an error at the first record that cannot be decoded instead of skipping it
+    #[structopt(long)]
+    strict: bool,
+    /// do not use colors with --format pretty
+    #[structopt(long)]
+    no_color: bool,
+    /// print the elapsed time as HH:MM:SS.mmm with --format pretty
+    #[structopt(long)]
+    hms: bool,
+    /// with --format csv, write a typed column per kv key instead of the kv JSON column.
+    /// Values that do not fit the type of their column are left blank
+    #[structopt(long)]
+    kv_columns: bool,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct InspectOpt {
+    /// data file, e.g. DATA_DIR/SESSION/seqdata
+    #[structopt(long, short, parse(from_os_str))]
+    file: PathBuf,
+    /// byte offset of the first item
+    #[structopt(long, conflicts_with = "index")]
+    offset: Option<usize>,
+    /// number of the first item, counted from the start of the file
+    #[structopt(long)]
+    index: Option<usize>,
+    /// number of items to print
+    #[structopt(long, short, default_value = "1")]
+    count: usize,
+    /// also print the bytes as a hex dump
+    #[structopt(long)]
+    raw: bool,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct AuditOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+    /// number of entries to print
+    #[structopt(long, short)]
+    limit: Option<usize>,
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    format: String,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct ServerStatusOpt {
+    /// address of the server
+    #[structopt(long, default_value = "http://localhost:8040")]
+    url: String,
+    /// bearer token when the server restricts sessions by tokens
+    #[structopt(long)]
+    token: Option<String>,
+    /// print the connections as JSON
+    #[structopt(long)]
+    json: bool,
+    /// close this connection instead of listing them
+    #[structopt(long, name = "UUID")]
+    disconnect: Option<String>,
+}
+
+#[cfg(all(unix, feature = "tui"))]
+#[derive(Debug, PartialEq, StructOpt)]
+struct TuiOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+}
+
+#[derive(Debug, PartialEq, StructOpt)]
+struct UnarchiveOpt {
+    #[structopt(long, short, default_value = "tempdb", name = "DATA_DIR")]
+    data_dir: String,
+    /// archive file
+    #[structopt(name = "FILE", parse(from_os_str))]
+    file: PathBuf,
 }
 
 fn main() {
@@ -141,11 +563,66 @@ fn main() {
     if opt.debug {
         std::env::set_var("RUST_LOG", "debug");
     }
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let mut logger = env_logger::Builder::from_env(Env::default().default_filter_or("info"));
+    if let Some(path) = opt.log_file.as_ref() {
+        let file = match RotatingFile::new(path, opt.log_rotate_mb << 20, opt.log_keep) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("failed to open log file {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        };
+        // env_logger 0.8はis_testのときだけPipeに書き、それ以外はstderrに書いてしまう
+        logger.target(Target::Pipe(Box::new(file))).is_test(true);
+    }
+    logger.init();
+    if opt.log_file.is_some() {
+        logfile::log_panics();
+    }
+    #[cfg(feature = "encryption")]
+    let encrypt_key = opt.encrypt_key_file.as_ref().map(|path| {
+        match uplog_tools::crypt::EncryptionKey::from_file(path) {
+            Ok(key) => {
+                // 読み出すコマンドでも復号できるように登録しておく
+                uplog_tools::crypt::register_key(&key);
+                key
+            }
+            Err(e) => {
+                error!("failed to read key file {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    });
 
     match opt.sub {
         Subcommands::Server(subopt) => {
-            server(subopt.into()).unwrap();
+            let config = match subopt.config() {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            if subopt.print_config {
+                print!("{}", config.to_toml());
+                return;
+            }
+            #[allow(unused_mut)]
+            let mut subopt = match ServerOptions::try_from(config) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            #[cfg(feature = "encryption")]
+            {
+                subopt.encrypt_key = encrypt_key;
+            }
+            if let Err(e) = run_server(subopt).and_then(ServerHandle::join) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
         }
         Subcommands::Dev(subopt) => {
             if subopt.use_log_macro {
@@ -157,83 +634,84 @@ fn main() {
         Subcommands::Read(subopt) => {
             read(subopt.into());
         }
-    };
-}
-
-struct ServerOption {
-    port: u16,
-    data_dir: PathBuf,
-    view_dir: PathBuf,
-}
-
-impl From<ServerOpt> for ServerOption {
-    fn from(x: ServerOpt) -> Self {
-        Self {
-            port: x.port,
-            data_dir: x.get_data_dir().expect("not found user local data dir"),
-            view_dir: x.get_view_dir().expect("not found webview file dir"),
+        Subcommands::Cat(subopt) => {
+            if let Err(e) = cat(subopt) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
         }
-    }
-}
-
-fn server(opt: ServerOption) -> std::io::Result<()> {
-    let bind_addr = format!("0.0.0.0:{}", opt.port);
-    let storage = uplog_tools::Storage::new(&opt.data_dir)?;
-    info!("data store in [{}]", opt.data_dir.to_string_lossy());
-    let mut rt = actix_web::rt::System::new("server");
-    let schema = Schema::build(
-        Query::new(storage.clone()),
-        EmptyMutation,
-        EmptySubscription,
-    )
-    .finish();
-
-    rt.block_on(async move {
-        // setup storage dir
-        let storage_actor = uplog_tools::actor::StorageActor::new(storage);
-        let storage_addr = storage_actor.start();
-
-        info!("listen at {}", &bind_addr);
-        HttpServer::new(move || {
-            let cors = Cors::default()
-                .allowed_origin_fn(|_origin, _req_head| true)
-                .allowed_methods(vec!["GET", "POST"])
-                .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
-                .allowed_header(header::CONTENT_TYPE)
-                .supports_credentials()
-                .max_age(3600);
-            App::new()
-                .wrap(cors)
-                // enable logger
-                // .wrap(middleware::Logger::default())
-                .data(storage_addr.clone())
-                // websocket route
-                .service(web::resource(WS_PATH).route(web::get().to(ws_index)))
-                // graphql
-                .app_data(Data::new(schema.clone()))
-                .service(
-                    web::resource("/graphql")
-                        .guard(guard::Post())
-                        .to(webapi::index),
-                )
-                .service(
-                    web::resource("/graphql")
-                        .guard(guard::Get())
-                        .to(webapi::index_playground),
-                )
-                .service(
-                    actix_files::Files::new("/", &opt.view_dir)
-                        .prefer_utf8(true)
-                        .index_file("index.html"),
-                )
-        })
-        .bind(bind_addr)
-        .unwrap()
-        .run()
-        .await
-        .unwrap();
-    });
-    rt.run()
+        Subcommands::Archive(subopt) => {
+            archive(subopt).unwrap();
+        }
+        Subcommands::Unarchive(subopt) => {
+            unarchive(subopt).unwrap();
+        }
+        Subcommands::Replay(subopt) => {
+            if let Err(e) = replay(subopt) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Subcommands::Trim(subopt) => {
+            if let Err(e) = trim(subopt) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Subcommands::Pin(subopt) => {
+            if let Err(e) = pin(subopt) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Subcommands::Anonymize(subopt) => {
+            if let Err(e) = anonymize(subopt) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Subcommands::Verify(subopt) => match verify(subopt) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Subcommands::Stats(subopt) => {
+            if let Err(e) = stats(subopt) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Subcommands::Inspect(subopt) => match inspect(subopt) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Subcommands::Audit(subopt) => {
+            if let Err(e) = audit(subopt) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Subcommands::ServerStatus(subopt) => {
+            if let Err(e) = server_status(subopt) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        #[cfg(all(unix, feature = "tui"))]
+        Subcommands::Tui(subopt) => {
+            if let Err(e) = tui(subopt) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    };
 }
 
 struct DevOption {
@@ -245,7 +723,7 @@ struct DevOption {
 
 impl DevOption {
     fn addr(&self) -> String {
-        format!("ws://{}:{}{}", self.host, self.port, WS_PATH)
+        format!("ws://{}:{}{}", self.host, self.port, INGEST_PATH)
     }
 }
 
@@ -305,25 +783,58 @@ fn client_log_interface(opt: DevOption) {
 }
 
 struct ReadOption {
-    data_dir: String,
+    data_dir: PathBuf,
     file: Option<String>,
+    filter: Option<Filter>,
+    pretty: Option<PrettyOptions>,
+    format: RecordFormatter,
+    listing: SessionQuery,
 }
 
 impl From<ReadOpt> for ReadOption {
     fn from(x: ReadOpt) -> Self {
+        let format = RecordFormatter::new()
+            .elapsed(if x.hms {
+                ElapsedStyle::Hms
+            } else {
+                ElapsedStyle::Seconds
+            })
+            .location(!x.no_location)
+            .max_message_width(x.max_message_width);
         Self {
-            data_dir: x.data_dir,
+            data_dir: resolve_data_dir(&x.data_dir).expect("failed to resolve data dir"),
             file: x.file,
+            filter: x.where_.map(|expr| {
+                Filter::parse(&expr).unwrap_or_else(|e| {
+                    error!("invalid --where expression\n{}", e.highlight(&expr));
+                    std::process::exit(1);
+                })
+            }),
+            pretty: x.pretty.then(|| PrettyOptions {
+                format: format.kv(KvStyle::Multiline),
+                ..PrettyOptions::for_stdout(x.no_color)
+            }),
+            format,
+            listing: SessionQuery {
+                offset: x.offset,
+                limit: x.limit,
+                sort_by: x.sort_by,
+                order: x.order,
+                name_contains: x.name_contains,
+                tag: x.tag,
+                name_globs: None,
+                health: x.health,
+            },
         }
     }
 }
 
 fn read(opt: ReadOption) {
-    let storage = Storage::new(opt.data_dir).unwrap();
-    let mut records = storage.records().unwrap();
+    let storage = Storage::new_shared(opt.data_dir).unwrap();
 
     match opt.file {
         Some(path) => {
+            let records = storage.records().unwrap();
             // TODO implment into library
             debug!("read file {}", path);
             let iter = records
@@ -334,7 +845,13 @@ fn read(opt: ReadOption) {
                 let reader = Deserializer::from_reader(f).into_iter::<Record>();
                 for r in reader {
                     match r {
-                        Ok(r) => println!("{}", r),
+                        Ok(r) if opt.filter.as_ref().is_none_or(|f| f.matches(&r)) => {
+                            match opt.pretty.as_ref() {
+                                Some(x) => println!("{}", pretty(&r, x)),
+                                None => println!("{}", opt.format.format(&r)),
+                            }
+                        }
+                        Ok(_) => {}
                         Err(e) => {
                             error!("failed to read record, {}", e);
                             return;
@@ -344,10 +861,408 @@ fn read(opt: ReadOption) {
             }
         }
         None => {
-            records.sort_by(|a, b| a.created_at().cmp(b.created_at()));
-            for r in records {
+            let page = storage.records_paged(&opt.listing).unwrap();
+            for r in page.sessions.iter() {
                 println!("{}", r);
             }
+            if opt.listing.limit.is_some() || opt.listing.offset > 0 {
+                eprintln!("{}", page);
+            }
         }
     };
 }
+
+/// 読み手が閉じた場合も正常に終える
+fn cat(opt: CatOpt) -> std::io::Result<()> {
+    let filter = opt
+        .where_
+        .as_deref()
+        .map(|expr| {
+            Filter::parse(expr).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid --where expression\n{}", e.highlight(expr)),
+                )
+            })
+        })
+        .transpose()?;
+    let storage = match opt.session.as_str() {
+        "-" => None,
+        _ => Some(Storage::new_shared(resolve_data_dir(&opt.data_dir)?)?),
+    };
+    let kv_columns = match (opt.kv_columns, storage.as_ref()) {
+        (false, _) => None,
+        (true, Some(storage)) if opt.format == CatFormat::Csv => {
+            Some(storage.kv_types(&opt.session)?.columns())
+        }
+        (true, _) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--kv-columns needs --format csv and a stored session",
+            ))
+        }
+    };
+    let mut records = match storage {
+        None => RecordIter::from_reader(std::io::stdin()),
+        Some(storage) => storage.session_records(&opt.session)?,
+    }
+    .on_error(if opt.strict {
+        OnError::Strict
+    } else {
+        OnError::Skip
+    });
+    let format = RecordFormatter::new().elapsed(if opt.hms {
+        ElapsedStyle::Hms
+    } else {
+        ElapsedStyle::Seconds
+    });
+    let opts = CatOptions {
+        format: opt.format,
+        filter,
+        pretty: PrettyOptions {
+            format: format.kv(KvStyle::Multiline),
+            ..PrettyOptions::for_stdout(opt.no_color)
+        },
+        kv_columns,
+    };
+    let summary = cat::cat(&mut records, std::io::stdout().lock(), &opts)?;
+    if !records.skipped().is_empty() {
+        warn!(
+            "skipped {} records that could not be decoded",
+            records.skipped().len()
+        );
+    }
+    if summary.incompatible > 0 {
+        warn!(
+            "left {} kv values blank that do not fit the type of their column",
+            summary.incompatible
+        );
+    }
+    debug!("cat {:?}", summary);
+    Ok(())
+}
+
+fn archive(opt: ArchiveOpt) -> std::io::Result<()> {
+    let storage = Storage::new_shared(resolve_data_dir(&opt.data_dir)?)?;
+    let f = std::io::BufWriter::new(std::fs::File::create(&opt.out)?);
+    let manifest = match (opt.rules.as_ref(), opt.reinline_blobs) {
+        (Some(rules), _) => {
+            let anonymizer = Anonymizer::new(AnonymizeRules::from_file(rules)?);
+            storage.archive_session_transformed(&opt.session, f, &anonymizer)?
+        }
+        (None, true) => storage.archive_session_reinlined(&opt.session, f)?,
+        (None, false) => storage.archive_session(&opt.session, f)?,
+    };
+    info!(
+        "archived {} files into {}",
+        manifest.files.len(),
+        opt.out.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// パスを省略したURLには受信のパスを付ける
+fn replay_url(target: &str) -> String {
+    let (scheme, rest) = target.split_once("://").unwrap_or(("ws", target));
+    match rest.split_once('/') {
+        Some((_, path)) if !path.is_empty() => format!("{}://{}", scheme, rest),
+        _ => format!("{}://{}{}", scheme, rest.trim_end_matches('/'), INGEST_PATH),
+    }
+}
+
+fn replay(opt: ReplayOpt) -> std::io::Result<()> {
+    use std::io::Error;
+    use tungstenite::{connect, Message};
+
+    let storage = Storage::new_shared(resolve_data_dir(&opt.data_dir)?)?;
+    let records = storage
+        .session_records_reinlined(&opt.session)?
+        .map_while(|x| {
+            // 書き込み途中の末尾は読めないので終わりとする
+            x.map_err(|e| error!("failed to read record, {}", e)).ok()
+        })
+        // 送り先のサーバーが書き直す
+        .filter(|x| !is_server_record(x));
+    let url = replay_url(&opt.target);
+    let (mut client, _) = connect(url.as_str()).map_err(Error::other)?;
+    println!("replay {} to {} at {}", opt.session, url, opt.speed);
+
+    let start = Instant::now();
+    let mut last_report = start;
+    let stats = uplog_tools::replay::replay(
+        records,
+        opt.speed,
+        |buf| {
+            client
+                .write_message(Message::binary(buf))
+                .map_err(Error::other)
+        },
+        |stats| {
+            if last_report.elapsed() >= Duration::from_secs(1) {
+                println!("sent {} records", stats.records);
+                last_report = Instant::now();
+            }
+        },
+    )?;
+    client.close(None).map_err(Error::other)?;
+    // 閉じたことをサーバーが受け取るまで読む
+    while client.read_message().is_ok() {}
+    println!(
+        "sent {} records in {} messages ({} bytes) in {:.3}s",
+        stats.records,
+        stats.messages,
+        stats.bytes,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+fn trim(opt: TrimOpt) -> std::io::Result<()> {
+    let storage = Storage::new(resolve_data_dir(&opt.data_dir)?)?;
+    let count = storage.trim_session(&opt.session, opt.from, opt.to, &opt.out, opt.rebase)?;
+    info!("wrote {} records into {}", count, opt.out);
+    Ok(())
+}
+
+/// 動いているサーバーと同時に使えるように共有で開く
+fn pin(opt: PinOpt) -> std::io::Result<()> {
+    let storage = Storage::new_shared(resolve_data_dir(&opt.data_dir)?)?
+        .max_pinned_bytes(opt.max_pinned_bytes);
+    let meta = storage.pin_session(&opt.name, !opt.unpin)?;
+    match meta.pinned {
+        true => info!("pinned {}", opt.name),
+        false => info!("unpinned {}", opt.name),
+    }
+    Ok(())
+}
+
+fn anonymize(opt: AnonymizeOpt) -> std::io::Result<()> {
+    let anonymizer = Anonymizer::new(AnonymizeRules::from_file(&opt.rules)?);
+    let storage = Storage::new(resolve_data_dir(&opt.data_dir)?)?;
+    let count = storage.transform_session(&opt.session, &opt.out, &anonymizer)?;
+    info!("wrote {} anonymized records into {}", count, opt.out);
+    Ok(())
+}
+
+fn unarchive(opt: UnarchiveOpt) -> std::io::Result<()> {
+    let storage = Storage::new(resolve_data_dir(&opt.data_dir)?)?;
+    let f = std::io::BufReader::new(std::fs::File::open(&opt.file)?);
+    let manifest = storage.restore_archive(f)?;
+    info!("restored session {}", manifest.session);
+    Ok(())
+}
+
+/// 全て問題がないか直せた場合はtrueを返す
+fn verify(opt: VerifyOpt) -> std::io::Result<bool> {
+    // 修復する場合はサーバーと同時に書き換えないように排他する
+    let data_dir = resolve_data_dir(&opt.data_dir)?;
+    let storage = match opt.repair {
+        true => Storage::new(data_dir)?,
+        false => Storage::new_shared(data_dir)?,
+    };
+    let reports = match opt.session.as_ref() {
+        Some(name) => vec![storage.verify_session(name, opt.repair)?],
+        None => {
+            let mut names = storage
+                .records()?
+                .iter()
+                .map(|x| x.name())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+                .iter()
+                .map(|x| storage.verify_session(x, opt.repair))
+                .collect::<std::io::Result<Vec<_>>>()?
+        }
+    };
+    for report in reports.iter() {
+        println!("{}", report);
+    }
+    let failed = reports.iter().filter(|x| x.has_unrepaired()).count();
+    println!(
+        "{} sessions, {} with findings, {} not repaired",
+        reports.len(),
+        reports.iter().filter(|x| !x.is_ok()).count(),
+        failed
+    );
+    Ok(failed == 0)
+}
+
+fn stats(opt: StatsOpt) -> std::io::Result<()> {
+    let storage = Storage::new_shared(resolve_data_dir(&opt.data_dir)?)?;
+    let names = match opt.latest {
+        Some(n) => {
+            let mut records = storage.records()?;
+            records.sort_by(|a, b| b.created_at().cmp(a.created_at()));
+            records.truncate(n);
+            records.iter().rev().map(|x| x.name()).collect()
+        }
+        None => opt.sessions,
+    };
+    let options = ScanOptions::default()
+        .threads(opt.scan_threads)
+        .deadline(opt.timeout.map(Deadline::after).unwrap_or_default());
+    let table = storage.sessions_stats_with(&names, options)?;
+    if !table.incomplete.is_empty() {
+        warn!(
+            "left out sessions not counted in time: {}",
+            table.incomplete.join(", ")
+        );
+    }
+    match opt.format.as_str() {
+        "json" => println!(
+            "{}",
+            serde_json::to_string_pretty(&table).map_err(std::io::Error::other)?
+        ),
+        _ => print!("{}", table.to_csv()),
+    }
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "tui"))]
+fn tui(opt: TuiOpt) -> std::io::Result<()> {
+    let storage = Storage::new_shared(resolve_data_dir(&opt.data_dir)?)?;
+    uplog_tools::tui::term::run(uplog_tools::tui::App::new(storage)?)
+}
+
+fn audit(opt: AuditOpt) -> std::io::Result<()> {
+    let storage = Storage::new_shared(resolve_data_dir(&opt.data_dir)?)?;
+    let entries = storage.audit_log(opt.limit)?;
+    match opt.format.as_str() {
+        "json" => println!(
+            "{}",
+            serde_json::to_string_pretty(&entries).map_err(std::io::Error::other)?
+        ),
+        _ => entries.iter().for_each(|x| println!("{}", x)),
+    }
+    Ok(())
+}
+
+const LIVE_CONNECTIONS_QUERY: &str = "{ liveConnections { uuid clientIp session connectedAt \
+    frames bytes lastActivityAt } }";
+
+/// 動いているサーバーのGraphQLに問い合わせる
+fn post_graphql(opt: &ServerStatusOpt, query: &str) -> std::io::Result<serde_json::Value> {
+    use std::io::Error;
+
+    let url = format!("{}/graphql", opt.url.trim_end_matches('/'));
+    let body = serde_json::json!({ "query": query });
+    let token = opt.token.clone();
+    let mut sys = actix_web::rt::System::new("server-status");
+    let res: serde_json::Value = sys.block_on(async move {
+        let mut req = actix_web::client::Client::default().post(url.as_str());
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let mut res = req
+            .send_json(&body)
+            .await
+            .map_err(|e| Error::other(format!("failed to request {}: {}", url, e)))?;
+        res.json().await.map_err(Error::other)
+    })?;
+    if let Some(e) = res["errors"].as_array().and_then(|x| x.first()) {
+        return Err(Error::other(
+            e["message"].as_str().unwrap_or("").to_string(),
+        ));
+    }
+    Ok(res["data"].clone())
+}
+
+fn server_status(opt: ServerStatusOpt) -> std::io::Result<()> {
+    if let Some(uuid) = opt.disconnect.as_ref() {
+        let query = format!(
+            "mutation {{ disconnect(uuid: {}) }}",
+            serde_json::Value::from(uuid.as_str())
+        );
+        post_graphql(&opt, &query)?;
+        println!("disconnected {}", uuid);
+        return Ok(());
+    }
+    let data = post_graphql(&opt, LIVE_CONNECTIONS_QUERY)?;
+    let connections = data["liveConnections"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    if opt.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&connections).map_err(std::io::Error::other)?
+        );
+        return Ok(());
+    }
+    let text = |x: &serde_json::Value| x.as_str().unwrap_or("-").to_string();
+    println!(
+        "{:<36}  {:<15}  {:<25}  {:>8}  {:>10}  {:<25}  SESSION",
+        "UUID", "CLIENT", "CONNECTED", "FRAMES", "BYTES", "LAST ACTIVITY"
+    );
+    for x in connections.iter() {
+        println!(
+            "{:<36}  {:<15}  {:<25}  {:>8}  {:>10}  {:<25}  {}",
+            text(&x["uuid"]),
+            text(&x["clientIp"]),
+            text(&x["connectedAt"]),
+            x["frames"],
+            x["bytes"],
+            text(&x["lastActivityAt"]),
+            text(&x["session"]),
+        );
+    }
+    Ok(())
+}
+
+/// 読めない項目があればfalse
+fn inspect(opt: InspectOpt) -> std::io::Result<bool> {
+    let buf = std::fs::read(&opt.file)?;
+    let offset = match opt.index {
+        Some(index) => reader::item_offset(&buf, index).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("item {} is not in the readable part of the file", index),
+            )
+        })?,
+        None => opt.offset.unwrap_or(0),
+    };
+    let items = reader::inspect(&buf, offset, opt.count);
+    if items.is_empty() {
+        println!("no item at byte {} of {}", offset, buf.len());
+    }
+    let mut ok = true;
+    for item in items {
+        println!("offset {} length {}", item.offset, item.bytes.len());
+        if let Some(diagnostic) = item.diagnostic {
+            println!("{}", diagnostic);
+        }
+        if opt.raw {
+            print!("{}", hex_dump(item.bytes, item.offset));
+        }
+        if let Some(e) = item.error {
+            println!("error: {}", e);
+            ok = false;
+        }
+    }
+    Ok(ok)
+}
+
+/// 1行16バイトの16進表示。行頭はファイルでの位置
+fn hex_dump(bytes: &[u8], base: usize) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex = line
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let text = line
+            .iter()
+            .map(|&x| {
+                if x.is_ascii_graphic() || x == b' ' {
+                    x as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        out.push_str(&format!("{:08x}  {:<47}  {}\n", base + i * 16, hex, text));
+    }
+    out
+}
@@ -0,0 +1,181 @@
+//! 大きなバイト列の分離保存
+//!
+//! 数MBの`Value::Bytes`をseqdataにそのまま書くと、使わない検索でも読み飛ばしに時間がかかる。
+//! 閾値を超えるものはセッションディレクトリの`blobs/{hash}`に書き出し、
+//! レコードには`{_blob: hash, len}`の置き換えだけを残す。
+//! 同じ内容は同じhashになるので1ファイルだけ保存する
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use uplog::{Record, Value};
+
+/// セッションディレクトリ内の保存先
+pub const BLOB_DIR: &str = "blobs";
+/// 置き換えたmapでhashを持つキー
+pub const BLOB_KEY: &str = "_blob";
+/// 置き換えたmapで元の長さを持つキー
+pub const BLOB_LEN_KEY: &str = "len";
+
+/// 内容のsha256 (hex)
+pub fn blob_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// パスに使うので想定した形式か確認する
+pub(crate) fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|x| matches!(x, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// 置き換えたmapであればhashと長さを返す
+pub fn placeholder(value: &Value) -> Option<(&str, u64)> {
+    match value {
+        Value::Map(map) if map.len() == 2 => match (map.get(BLOB_KEY), map.get(BLOB_LEN_KEY)) {
+            (Some(Value::Text(hash)), Some(Value::U64(len))) => Some((hash, *len)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn not_found(e: io::Error, hash: &str) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::NotFound => {
+            io::Error::new(io::ErrorKind::NotFound, format!("blob not found: {}", hash))
+        }
+        _ => e,
+    }
+}
+
+/// 1セッション分のblobの保存先
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new<P: AsRef<Path>>(session_dir: P) -> Self {
+        Self {
+            dir: session_dir.as_ref().join(BLOB_DIR),
+        }
+    }
+
+    /// 保存してhashを返す。同じ内容が既にあれば書き込まない
+    pub fn put(&self, data: &[u8]) -> io::Result<String> {
+        let hash = blob_hash(data);
+        let path = self.dir.join(&hash);
+        if !path.exists() {
+            std::fs::create_dir_all(&self.dir)?;
+            // 書き込み途中のファイルを読まれないように別名で書いてから置き換える
+            let tmp = self.dir.join(format!(".{}.tmp", hash));
+            std::fs::write(&tmp, data)?;
+            std::fs::rename(&tmp, &path)?;
+        }
+        Ok(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(self.path(hash)?).map_err(|e| not_found(e, hash))
+    }
+
+    /// 読み込まずに長さだけ返す
+    pub fn len(&self, hash: &str) -> io::Result<u64> {
+        Ok(std::fs::metadata(self.path(hash)?)
+            .map_err(|e| not_found(e, hash))?
+            .len())
+    }
+
+    fn path(&self, hash: &str) -> io::Result<PathBuf> {
+        if !is_valid_hash(hash) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid blob hash {}", hash),
+            ));
+        }
+        Ok(self.dir.join(hash))
+    }
+
+    /// thresholdバイトより大きいBytesを書き出して置き換える
+    pub fn offload(&self, record: &mut Record, threshold: usize) -> io::Result<()> {
+        if let Some(kv) = record.kv.as_mut() {
+            for value in kv.values_mut() {
+                self.offload_value(value, threshold)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn offload_value(&self, value: &mut Value, threshold: usize) -> io::Result<()> {
+        match value {
+            Value::Bytes(x) if x.len() > threshold => {
+                let len = x.len() as u64;
+                let hash = self.put(x)?;
+                let mut map = BTreeMap::new();
+                map.insert(BLOB_KEY.to_string(), Value::Text(hash));
+                map.insert(BLOB_LEN_KEY.to_string(), Value::U64(len));
+                *value = Value::Map(map);
+            }
+            Value::Array(x) => {
+                for v in x.iter_mut() {
+                    self.offload_value(v, threshold)?;
+                }
+            }
+            Value::Map(x) => {
+                for v in x.values_mut() {
+                    self.offload_value(v, threshold)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// 置き換えたmapを元のBytesに戻す
+    pub fn reinline(&self, record: &mut Record) -> io::Result<()> {
+        if let Some(kv) = record.kv.as_mut() {
+            for value in kv.values_mut() {
+                self.reinline_value(value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// レコードが参照するblobを`dst`にコピーする。hashは変わらないのでレコードはそのまま使える
+    pub fn copy_referenced(&self, record: &Record, dst: &BlobStore) -> io::Result<()> {
+        if let Some(kv) = record.kv.as_ref() {
+            for value in kv.values() {
+                self.copy_value(value, dst)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_value(&self, value: &Value, dst: &BlobStore) -> io::Result<()> {
+        if let Some((hash, _)) = placeholder(value) {
+            if !dst.dir.join(hash).exists() {
+                dst.put(&self.get(hash)?)?;
+            }
+            return Ok(());
+        }
+        match value {
+            Value::Array(x) => x.iter().try_for_each(|v| self.copy_value(v, dst)),
+            Value::Map(x) => x.values().try_for_each(|v| self.copy_value(v, dst)),
+            _ => Ok(()),
+        }
+    }
+
+    fn reinline_value(&self, value: &mut Value) -> io::Result<()> {
+        if let Some((hash, _)) = placeholder(value) {
+            *value = Value::Bytes(self.get(hash)?);
+            return Ok(());
+        }
+        match value {
+            Value::Array(x) => x.iter_mut().try_for_each(|v| self.reinline_value(v)),
+            Value::Map(x) => x.values_mut().try_for_each(|v| self.reinline_value(v)),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! 読み出し結果のキャッシュ
+//!
+//! ダッシュボードは書き込み中のセッションの同じ範囲を周期的に読み直す。
+//! キーにデータファイルの更新時刻と長さを含めるので、追記されたセッションの古い結果は使わない
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+#[cfg(feature = "web")]
+use async_graphql::SimpleObject;
+
+use crate::{
+    reader::{OnError, ReadOptions, ReadPage},
+    writer::CBORSequenceWriter,
+    LogRecord,
+};
+
+/// サーバーの既定のキャッシュの大きさ
+pub const DEFAULT_QUERY_CACHE_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    session: PathBuf,
+    modified: SystemTime,
+    len: u64,
+    start: usize,
+    length: usize,
+    on_error: OnError,
+    lazy_kv: bool,
+}
+
+#[derive(Debug)]
+struct Entry {
+    page: Arc<ReadPage>,
+    size: usize,
+    /// 最後に使った順番
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<CacheKey, Entry>,
+    tick: u64,
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(x) = self.entries.remove(key) {
+            self.bytes -= x.size;
+        }
+    }
+
+    /// 最も長く使っていないものから捨てる
+    fn evict(&mut self, capacity: usize) {
+        while self.bytes > capacity {
+            let oldest = match self.entries.iter().min_by_key(|(_, x)| x.used) {
+                Some((k, _)) => k.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+/// Hit counts and size of a [`QueryCache`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct QueryCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+    /// estimated size of the cached records
+    pub bytes: u64,
+    pub capacity_bytes: u64,
+}
+
+/// LRU cache of record ranges read from sessions.
+#[derive(Debug)]
+pub struct QueryCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl Default for QueryCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUERY_CACHE_BYTES)
+    }
+}
+
+impl QueryCache {
+    /// 0の場合は何も保持しない
+    pub fn new(capacity_bytes: usize) -> Self {
+        Self {
+            capacity: capacity_bytes,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        let inner = self.inner.lock().expect("query cache lock");
+        QueryCacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            entries: inner.entries.len() as u64,
+            bytes: inner.bytes as u64,
+            capacity_bytes: self.capacity as u64,
+        }
+    }
+
+    /// `session_dir`の`start`から`length`件を返す。なければ`load`で読んで保持する
+    ///
+    /// 読めないレコードの扱いで結果が変わるので`on_error`もキーに含める
+    pub fn read_at<F>(
+        &self,
+        session_dir: &Path,
+        start: usize,
+        length: usize,
+        on_error: OnError,
+        load: F,
+    ) -> io::Result<Arc<ReadPage>>
+    where
+        F: FnOnce() -> io::Result<ReadPage>,
+    {
+        let options = ReadOptions::default().on_error(on_error);
+        self.read_at_with(session_dir, start, length, options, load)
+    }
+
+    /// Like `read_at`, keeping pages read with [`ReadOptions::lazy_kv`] apart from the others.
+    pub fn read_at_with<F>(
+        &self,
+        session_dir: &Path,
+        start: usize,
+        length: usize,
+        options: ReadOptions,
+        load: F,
+    ) -> io::Result<Arc<ReadPage>>
+    where
+        F: FnOnce() -> io::Result<ReadPage>,
+    {
+        let metadata = std::fs::metadata(session_dir.join(CBORSequenceWriter::FILENAME))?;
+        let key = CacheKey {
+            session: session_dir.to_owned(),
+            modified: metadata.modified()?,
+            len: metadata.len(),
+            start,
+            length,
+            on_error: options.on_error,
+            lazy_kv: options.lazy_kv,
+        };
+        {
+            let mut inner = self.inner.lock().expect("query cache lock");
+            inner.tick += 1;
+            let tick = inner.tick;
+            if let Some(entry) = inner.entries.get_mut(&key) {
+                entry.used = tick;
+                let page = entry.page.clone();
+                inner.hits += 1;
+                return Ok(page);
+            }
+            inner.misses += 1;
+            // 書き込みで変わったセッションの結果はもう使わない
+            let stale = inner
+                .entries
+                .keys()
+                .filter(|x| {
+                    x.session == key.session && (x.modified, x.len) != (key.modified, key.len)
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            for x in stale.iter() {
+                inner.remove(x);
+            }
+        }
+
+        // 読み込み中はロックを持たない
+        let page = Arc::new(load()?);
+        let size = estimate_size(&page.records);
+        if size <= self.capacity {
+            let mut inner = self.inner.lock().expect("query cache lock");
+            inner.remove(&key);
+            inner.tick += 1;
+            let used = inner.tick;
+            inner.bytes += size;
+            inner.entries.insert(
+                key,
+                Entry {
+                    page: page.clone(),
+                    size,
+                    used,
+                },
+            );
+            inner.evict(self.capacity);
+        }
+        Ok(page)
+    }
+}
+
+/// 保持するレコードの大きさの目安。エンコードした長さで数える
+fn estimate_size(records: &[LogRecord]) -> usize {
+    records
+        .iter()
+        .map(|x| {
+            std::mem::size_of::<LogRecord>()
+                + serde_cbor::to_vec(&x.record).map_or(0, |x| x.len())
+                + x.lazy_kv.as_ref().map_or(0, |x| x.encoded_len())
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io};
+
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level};
+
+    use super::QueryCache;
+    use crate::{
+        reader::{CBORSequenceReader, OnError, StorageReader},
+        writer::RecordWriter,
+        LogRecord, Storage,
+    };
+
+    #[test]
+    fn test_query_cache() {
+        devinit!();
+        let dir = TempDir::new("cache").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let mut session = storage.create_session("cache").unwrap();
+        let path = dir.path().join("cache");
+        let push = |session: &mut crate::Session, i: u32| {
+            session
+                .push(&devlog!(Level::Info, "app", "msg", "i", i))
+                .unwrap();
+            session.flush();
+        };
+        for i in 0..10 {
+            push(&mut session, i);
+        }
+
+        // 開いた回数を数える
+        let opened = Cell::new(0);
+        let load = |start: usize, length: usize| {
+            opened.set(opened.get() + 1);
+            Ok(CBORSequenceReader::new(&path)?
+                .read_at(start, length)?
+                .into())
+        };
+        let cache = QueryCache::new(1024 * 1024);
+        let read = |start, length| -> io::Result<Vec<usize>> {
+            let page =
+                cache.read_at(&path, start, length, OnError::Skip, || load(start, length))?;
+            Ok(page.records.iter().map(|x: &LogRecord| x.id).collect())
+        };
+        assert_eq!(read(5, 10).unwrap(), (5..10).collect::<Vec<_>>());
+        assert_eq!(read(5, 10).unwrap(), (5..10).collect::<Vec<_>>());
+        assert_eq!(opened.get(), 1);
+        // 範囲が違えば別に読む
+        assert_eq!(read(0, 2).unwrap(), vec![0, 1]);
+        assert_eq!(opened.get(), 2);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 2));
+        // 読めないレコードの扱いが違えば別に読む
+        cache
+            .read_at(&path, 0, 2, OnError::Strict, || load(0, 2))
+            .unwrap();
+        assert_eq!(opened.get(), 3);
+        assert_eq!(cache.stats().entries, 3);
+
+        // 追記すると読み直し、そのセッションの古い結果は捨てる
+        push(&mut session, 10);
+        assert_eq!(read(5, 10).unwrap(), (5..11).collect::<Vec<_>>());
+        assert_eq!(opened.get(), 4);
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(read(5, 10).unwrap(), (5..11).collect::<Vec<_>>());
+        assert_eq!(opened.get(), 4);
+    }
+
+    #[test]
+    fn test_query_cache_eviction() {
+        devinit!();
+        let dir = TempDir::new("cache").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let mut session = storage.create_session("cache").unwrap();
+        for i in 0..10_u32 {
+            session
+                .push(&devlog!(Level::Info, "app", "msg", "i", i))
+                .unwrap();
+        }
+        session.flush();
+        let path = dir.path().join("cache");
+        let load = |start| -> io::Result<crate::ReadPage> {
+            Ok(CBORSequenceReader::new(&path)?.read_at(start, 1)?.into())
+        };
+
+        // 1件分だけ入る大きさにする
+        let one = super::estimate_size(&load(0).unwrap().records);
+        let cache = QueryCache::new(one * 2 - 1);
+        for start in [0, 1, 0] {
+            cache
+                .read_at(&path, start, 1, OnError::Skip, || load(start))
+                .unwrap();
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (0, 3, 1));
+        assert!(stats.bytes <= stats.capacity_bytes);
+
+        // 0の場合は保持しない
+        let cache = QueryCache::new(0);
+        for _ in 0..2 {
+            cache
+                .read_at(&path, 0, 1, OnError::Skip, || load(0))
+                .unwrap();
+        }
+        assert_eq!(cache.stats().misses, 2);
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
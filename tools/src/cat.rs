@@ -0,0 +1,442 @@
+//! レコードを1件ずつ書き出す
+//!
+//! 他のツールにパイプでつなぐために1件ごとにflushする。
+//! 読み手が先に閉じた場合(EPIPE)はエラーにせずに終える
+use std::{
+    fmt::{self, Display},
+    io::{self, Write},
+    str::FromStr,
+};
+
+use serde::Serialize;
+use uplog::{Level, Record, KV};
+
+use crate::{
+    filter::Filter,
+    format::{pretty, PrettyOptions},
+    kvtype::ColumnType,
+};
+
+/// Columns of [`CatFormat::Csv`], written as the first line.
+pub const CSV_HEADER: &str = "elapsed,level,category,message,module_path,file,line,kv";
+
+/// Output format of [`cat`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatFormat {
+    /// one JSON object per line
+    Jsonl,
+    /// [`CSV_HEADER`] and one row per record, kv as JSON
+    Csv,
+    /// the colored terminal format of [`pretty`]
+    Pretty,
+}
+
+impl CatFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Jsonl => "jsonl",
+            Self::Csv => "csv",
+            Self::Pretty => "pretty",
+        }
+    }
+}
+
+impl Display for CatFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for CatFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Self::Jsonl),
+            "csv" => Ok(Self::Csv),
+            "pretty" => Ok(Self::Pretty),
+            _ => Err(format!(
+                "unknown format {}, expected jsonl, csv or pretty",
+                s
+            )),
+        }
+    }
+}
+
+/// Options of [`cat`].
+#[derive(Debug, Clone)]
+pub struct CatOptions {
+    pub format: CatFormat,
+    /// write only the records matching this
+    pub filter: Option<Filter>,
+    /// used with [`CatFormat::Pretty`]
+    pub pretty: PrettyOptions,
+    /// with [`CatFormat::Csv`], write a `kv.<key>` column of this type per key instead of the
+    /// `kv` column, see [`crate::kvtype::KvTypeRegistry::columns`]
+    pub kv_columns: Option<Vec<(String, ColumnType)>>,
+}
+
+/// What [`cat`] wrote.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CatSummary {
+    /// records written
+    pub written: u64,
+    /// records left out by the filter
+    pub filtered: u64,
+    /// the reader closed the pipe before all records were written
+    pub closed: bool,
+    /// kv values left blank because they do not fit the type of their column
+    pub incompatible: u64,
+}
+
+/// jsonlの1行。`elapsed`はjqで扱いやすいように秒の小数にする
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    elapsed: f64,
+    level: Level,
+    category: &'a str,
+    message: &'a str,
+    module_path: Option<&'a str>,
+    file: Option<&'a str>,
+    line: Option<u32>,
+    kv: Option<&'a KV>,
+}
+
+impl<'a> From<&'a Record> for JsonRecord<'a> {
+    fn from(x: &'a Record) -> Self {
+        Self {
+            elapsed: x.elapsed.as_secs_f64(),
+            level: x.metadata.level(),
+            category: &x.category,
+            message: &x.message,
+            module_path: x.module_path.as_deref(),
+            file: x.file.as_deref(),
+            line: x.line,
+            kv: x.kv.as_ref(),
+        }
+    }
+}
+
+/// Writes `records` to `out` in `opts.format`, flushing after every record.
+///
+/// Stops at the first record that cannot be read and returns its error.
+/// A closed pipe is not an error: it ends the output with [`CatSummary::closed`] set.
+pub fn cat<I, W>(records: I, mut out: W, opts: &CatOptions) -> io::Result<CatSummary>
+where
+    I: IntoIterator<Item = io::Result<Record>>,
+    W: Write,
+{
+    let mut summary = CatSummary::default();
+    if opts.format == CatFormat::Csv {
+        if let Err(e) = write_line(&mut out, &csv_header(opts.kv_columns.as_deref())) {
+            return closed_or(e, summary);
+        }
+    }
+    for record in records {
+        let record = record?;
+        if !opts.filter.as_ref().is_none_or(|f| f.matches(&record)) {
+            summary.filtered += 1;
+            continue;
+        }
+        let line = match (opts.format, opts.kv_columns.as_deref()) {
+            (CatFormat::Csv, Some(columns)) => {
+                let (line, incompatible) = typed_csv_row(&record, columns);
+                summary.incompatible += incompatible;
+                line
+            }
+            _ => render(&record, opts)?,
+        };
+        if let Err(e) = write_line(&mut out, &line) {
+            return closed_or(e, summary);
+        }
+        summary.written += 1;
+    }
+    Ok(summary)
+}
+
+/// 読み手が閉じた場合はそこまでの結果を返す
+fn closed_or(e: io::Error, summary: CatSummary) -> io::Result<CatSummary> {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        Ok(CatSummary {
+            closed: true,
+            ..summary
+        })
+    } else {
+        Err(e)
+    }
+}
+
+fn write_line<W: Write>(out: &mut W, line: &str) -> io::Result<()> {
+    out.write_all(line.as_bytes())?;
+    out.write_all(b"\n")?;
+    out.flush()
+}
+
+fn render(record: &Record, opts: &CatOptions) -> io::Result<String> {
+    Ok(match opts.format {
+        CatFormat::Jsonl => serde_json::to_string(&JsonRecord::from(record))?,
+        CatFormat::Csv => csv_row(record)?,
+        CatFormat::Pretty => pretty(record, &opts.pretty),
+    })
+}
+
+fn csv_header(kv_columns: Option<&[(String, ColumnType)]>) -> String {
+    let Some(columns) = kv_columns else {
+        return CSV_HEADER.to_string();
+    };
+    let mut header = CSV_HEADER.trim_end_matches(",kv").to_string();
+    for (key, _) in columns {
+        header.push(',');
+        header.push_str(&csv_field(&format!("kv.{}", key)));
+    }
+    header
+}
+
+/// kv以外の列
+fn csv_fields(record: &Record) -> Vec<String> {
+    vec![
+        format!("{:.9}", record.elapsed.as_secs_f64()),
+        format!("{:?}", record.metadata.level()),
+        csv_field(&record.category),
+        csv_field(&record.message),
+        csv_field(record.module_path.as_deref().unwrap_or_default()),
+        csv_field(record.file.as_deref().unwrap_or_default()),
+        record.line.map(|x| x.to_string()).unwrap_or_default(),
+    ]
+}
+
+fn csv_row(record: &Record) -> io::Result<String> {
+    let kv = match record.kv.as_ref() {
+        Some(kv) => serde_json::to_string(kv)?,
+        None => String::new(),
+    };
+    let mut fields = csv_fields(record);
+    fields.push(csv_field(&kv));
+    Ok(fields.join(","))
+}
+
+/// キーごとの列に書いた行と、列の型に合わずに空にした値の数
+fn typed_csv_row(record: &Record, columns: &[(String, ColumnType)]) -> (String, u64) {
+    let mut fields = csv_fields(record);
+    let mut incompatible = 0;
+    for (key, column) in columns {
+        let value = record.kv.as_ref().and_then(|x| x.get(key));
+        let cell = match value.map(|x| column.cell(x)) {
+            Some(Some(x)) => csv_field(&x),
+            Some(None) => {
+                log::debug!("{} at {:?} does not fit {:?}", key, record.elapsed, column);
+                incompatible += 1;
+                String::new()
+            }
+            None => String::new(),
+        };
+        fields.push(cell);
+    }
+    (fields.join(","), incompatible)
+}
+
+/// CSVのフィールド。区切りや引用符を含む場合は囲む
+pub(crate) fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        time::Duration,
+    };
+
+    use uplog::{devlog, Level, Record, Value};
+
+    use super::{cat, CatFormat, CatOptions, CatSummary, CSV_HEADER};
+    use crate::{filter::Filter, format::PrettyOptions, kvtype::KvTypeRegistry, RecordIter};
+
+    fn records() -> Vec<Record> {
+        (0..4_u64)
+            .map(|i| {
+                let level = if i % 2 == 0 { Level::Info } else { Level::Warn };
+                let mut r = devlog!(level, "app.net", "say \"hi\", bye", "retries", i);
+                r.elapsed = Duration::from_millis(1500 * i);
+                r
+            })
+            .collect()
+    }
+
+    fn options(format: CatFormat) -> CatOptions {
+        CatOptions {
+            format,
+            filter: None,
+            pretty: PrettyOptions::for_stdout(true),
+            kv_columns: None,
+        }
+    }
+
+    fn run(records: Vec<Record>, opts: &CatOptions) -> (String, CatSummary) {
+        let mut out = Vec::new();
+        let summary = cat(records.into_iter().map(Ok), &mut out, opts).unwrap();
+        (String::from_utf8(out).unwrap(), summary)
+    }
+
+    #[test]
+    fn test_cat_jsonl() {
+        let opts = CatOptions {
+            filter: Some(Filter::parse("level >= warn").unwrap()),
+            ..options(CatFormat::Jsonl)
+        };
+        let (out, summary) = run(records(), &opts);
+        assert_eq!(
+            summary,
+            CatSummary {
+                written: 2,
+                filtered: 2,
+                closed: false,
+                incompatible: 0,
+            }
+        );
+        let lines = out
+            .lines()
+            .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["elapsed"], serde_json::json!(1.5));
+        assert_eq!(lines[0]["level"], serde_json::json!("Warn"));
+        assert_eq!(lines[0]["message"], serde_json::json!("say \"hi\", bye"));
+        assert_eq!(lines[1]["kv"], serde_json::json!({"retries": 3}));
+    }
+
+    #[test]
+    fn test_cat_csv() {
+        let (out, summary) = run(records(), &options(CatFormat::Csv));
+        assert_eq!(summary.written, 4);
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert!(
+            lines[2].starts_with("1.500000000,Warn,app.net,\"say \"\"hi\"\", bye\","),
+            "{}",
+            lines[2]
+        );
+        assert!(
+            lines[2].ends_with(",\"{\"\"retries\"\":1}\""),
+            "{}",
+            lines[2]
+        );
+    }
+
+    /// 型が変わるキーは多い方の型の列にし、合わない値は空にして数える
+    #[test]
+    fn test_cat_csv_kv_columns() {
+        let values = [
+            Value::U64(1),
+            Value::F64(2.5),
+            Value::Text("n/a".into()),
+            Value::U64(4),
+            Value::Null,
+        ];
+        let records = values
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let mut r = devlog!(Level::Info, "app", "m", "name", format!("n{}", i));
+                r.kv.as_mut().unwrap().insert("value".into(), v);
+                r
+            })
+            .collect::<Vec<_>>();
+        let mut registry = KvTypeRegistry::default();
+        for r in records.iter() {
+            registry.observe(r);
+        }
+        let opts = CatOptions {
+            kv_columns: Some(registry.columns()),
+            ..options(CatFormat::Csv)
+        };
+        let (out, summary) = run(records, &opts);
+        assert_eq!(summary.written, 5);
+        assert_eq!(summary.incompatible, 1);
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "elapsed,level,category,message,module_path,file,line,kv.name,kv.value"
+        );
+        let cells = lines[1..]
+            .iter()
+            .map(|x| x.rsplit(',').next().unwrap())
+            .collect::<Vec<_>>();
+        // 整数はf64の列に合わせ、文字列とnullは空にする
+        assert_eq!(cells, ["1", "2.5", "", "4", ""]);
+        assert!(lines[3].ends_with(",n2,"), "{}", lines[3]);
+    }
+
+    #[test]
+    fn test_cat_pretty() {
+        let (out, _) = run(records(), &options(CatFormat::Pretty));
+        assert!(out.contains("say \"hi\", bye"));
+        assert!(!out.contains('\x1b'));
+    }
+
+    /// 途中で閉じられる出力
+    struct ClosedAfter(usize);
+
+    impl Write for ClosedAfter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0 == 0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.0 -= 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_cat_broken_pipe() {
+        let opts = options(CatFormat::Jsonl);
+        // 1件目と改行を書いたところで閉じる
+        let summary = cat(records().into_iter().map(Ok), ClosedAfter(2), &opts).unwrap();
+        assert_eq!(summary.written, 1);
+        assert!(summary.closed);
+
+        // それ以外の書き込みのエラーは返す
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::WriteZero.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let err = cat(records().into_iter().map(Ok), Full, &opts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn test_cat_stream() {
+        let mut buf = Vec::new();
+        for r in records() {
+            serde_cbor::to_writer(&mut buf, &r).unwrap();
+        }
+        let (out, summary) = {
+            let mut out = Vec::new();
+            let iter = RecordIter::from_reader(io::Cursor::new(buf.clone()));
+            let summary = cat(iter, &mut out, &options(CatFormat::Jsonl)).unwrap();
+            (String::from_utf8(out).unwrap(), summary)
+        };
+        assert_eq!(summary.written, 4);
+        assert_eq!(out.lines().count(), 4);
+
+        // 途中で切れたデータはエラーにする
+        buf.truncate(buf.len() - 3);
+        let mut out = Vec::new();
+        let iter = RecordIter::from_reader(io::Cursor::new(buf));
+        assert!(cat(iter, &mut out, &options(CatFormat::Jsonl)).is_err());
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 3);
+    }
+}
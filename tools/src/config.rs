@@ -0,0 +1,441 @@
+//! `server`の設定ファイル
+//!
+//! `--config`で指定したTOMLのファイル、`UPLOG_SERVER_*`の環境変数、コマンドラインの順に
+//! 後のものが前のものを上書きする。キーはオプション名の`-`を`_`にしたもので、
+//! 環境変数ではそれを大文字にして[`ENV_PREFIX`]を付ける。秒数のオプションは小数も受け付ける。
+//! 知らないキーは起動を止めずに警告する
+//!
+//! ```toml
+//! port = 8040
+//! data_dir = "/var/lib/uplog"
+//! ws_paths = ["/ingest", "/staging=staging"]
+//! idle_timeout = 30
+//! min_free_bytes = 1073741824
+//! acl_file = "/etc/uplog/acl.toml"
+//! ```
+//!
+//! 環境変数の`ws_paths`は`,`で区切る
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Prefix of the environment variables read by [`ServerConfig::from_env`].
+pub const ENV_PREFIX: &str = "UPLOG_SERVER_";
+
+/// Options of the `server` command, each unset unless given.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub port: Option<u16>,
+    pub data_dir: Option<String>,
+    pub view_dir: Option<String>,
+    /// `PATH[=LABEL]` of each ingest endpoint
+    pub ws_paths: Option<Vec<String>>,
+    pub max_decode_failures: Option<u64>,
+    pub max_record_bytes: Option<usize>,
+    pub ingest_receive_time: Option<bool>,
+    pub ingest_client_ip: Option<bool>,
+    pub ingest_connection_id: Option<bool>,
+    pub max_kv_entries: Option<usize>,
+    pub blob_threshold: Option<usize>,
+    pub split_on_boundary: Option<bool>,
+    /// `reject`, `takeover` or `parallel`
+    pub duplicate_policy: Option<String>,
+    pub write_retries: Option<u32>,
+    pub retry_queue_bytes: Option<usize>,
+    pub query_cache_mb: Option<usize>,
+    pub uds_path: Option<PathBuf>,
+    /// permissions of the socket file in octal digits, e.g. `"660"`
+    pub uds_mode: Option<String>,
+    /// seconds
+    pub idle_timeout: Option<f64>,
+    pub max_connection_bytes: Option<u64>,
+    pub min_free_bytes: Option<u64>,
+    pub resume_free_bytes: Option<u64>,
+    /// seconds
+    pub disk_check_interval: Option<f64>,
+    pub reserved_bytes: Option<u64>,
+    pub prune_when_full: Option<bool>,
+    pub max_pinned_bytes: Option<u64>,
+    /// seconds
+    pub handshake_grace: Option<f64>,
+    pub max_handshake_failures: Option<u64>,
+    pub verify_on_start: Option<bool>,
+    pub max_query_depth: Option<usize>,
+    pub max_query_complexity: Option<usize>,
+    pub max_read_length: Option<usize>,
+    /// seconds
+    pub query_timeout: Option<f64>,
+    pub scan_threads: Option<usize>,
+    pub acl_file: Option<PathBuf>,
+}
+
+fn invalid_config<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// 全てのキーを持つJSONのオブジェクト。設定していないものはnull
+fn to_json(config: &ServerConfig) -> Value {
+    serde_json::to_value(config).expect("the config serializes to json")
+}
+
+/// 環境変数の値を`key`の型で読めるJSONにする
+///
+/// 数や真偽値として読み、読めなければ文字列、`,`で区切った文字列の配列の順に試す
+fn env_to_json(key: &str, value: &str) -> Result<Value, serde_json::Error> {
+    let candidates = [
+        serde_json::from_str::<Value>(value)
+            .ok()
+            .filter(|x| x.is_number() || x.is_boolean()),
+        Some(Value::from(value)),
+        Some(Value::from(value.split(',').collect::<Vec<_>>())),
+    ];
+    let mut error = None;
+    for candidate in candidates.into_iter().flatten() {
+        let mut object = serde_json::Map::new();
+        object.insert(key.to_string(), candidate.clone());
+        match serde_json::from_value::<ServerConfig>(Value::Object(object)) {
+            Ok(_) => return Ok(candidate),
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+    Err(error.expect("a string is always tried"))
+}
+
+/// TOMLの値をJSONにしてserdeで読めるようにする
+fn toml_to_json(value: &toml_edit::Value) -> io::Result<Value> {
+    use toml_edit::Value as Toml;
+    Ok(match value {
+        Toml::String(x) => Value::from(x.value().as_str()),
+        Toml::Integer(x) => Value::from(*x.value()),
+        Toml::Float(x) => Value::from(*x.value()),
+        Toml::Boolean(x) => Value::from(*x.value()),
+        Toml::Datetime(x) => Value::from(x.value().to_string()),
+        Toml::Array(x) => Value::Array(x.iter().map(toml_to_json).collect::<io::Result<_>>()?),
+        Toml::InlineTable(x) => Value::Object(
+            x.iter()
+                .map(|(k, v)| Ok((k.to_string(), toml_to_json(v)?)))
+                .collect::<io::Result<_>>()?,
+        ),
+    })
+}
+
+fn item_to_json(item: &toml_edit::Item) -> io::Result<Value> {
+    Ok(match item {
+        toml_edit::Item::None => Value::Null,
+        toml_edit::Item::Value(x) => toml_to_json(x)?,
+        toml_edit::Item::Table(x) => Value::Object(
+            x.iter()
+                .map(|(k, v)| Ok((k.to_string(), item_to_json(v)?)))
+                .collect::<io::Result<_>>()?,
+        ),
+        toml_edit::Item::ArrayOfTables(x) => Value::Array(
+            x.iter()
+                .map(|t| item_to_json(&toml_edit::Item::Table(t.clone())))
+                .collect::<io::Result<_>>()?,
+        ),
+    })
+}
+
+fn json_to_toml(value: &Value) -> Option<toml_edit::Value> {
+    Some(match value {
+        Value::Null | Value::Object(_) => return None,
+        Value::Bool(x) => (*x).into(),
+        Value::Number(x) => match x.as_i64() {
+            Some(x) => x.into(),
+            None => x.as_f64()?.into(),
+        },
+        Value::String(x) => x.as_str().into(),
+        Value::Array(x) => x
+            .iter()
+            .filter_map(json_to_toml)
+            .collect::<toml_edit::Array>()
+            .into(),
+    })
+}
+
+impl ServerConfig {
+    /// The values used for the options given nowhere, as printed by `server --print-config`.
+    pub fn defaults() -> Self {
+        Self {
+            port: Some(8040),
+            data_dir: Some("~/uplog".to_string()),
+            view_dir: Some("./view".to_string()),
+            ws_paths: Some(vec![uplog::INGEST_PATH.to_string()]),
+            max_decode_failures: Some(10),
+            ingest_receive_time: Some(false),
+            ingest_client_ip: Some(false),
+            ingest_connection_id: Some(false),
+            split_on_boundary: Some(false),
+            duplicate_policy: Some("parallel".to_string()),
+            write_retries: Some(5),
+            retry_queue_bytes: Some(1024 * 1024),
+            query_cache_mb: Some(16),
+            disk_check_interval: Some(10.0),
+            reserved_bytes: Some(1024 * 1024),
+            prune_when_full: Some(false),
+            handshake_grace: Some(10.0),
+            max_handshake_failures: Some(3),
+            verify_on_start: Some(false),
+            max_query_depth: Some(16),
+            max_query_complexity: Some(200_000),
+            max_read_length: Some(10_000),
+            query_timeout: Some(10.0),
+            scan_threads: Some(4),
+            ..Default::default()
+        }
+    }
+
+    /// Each option of `over` that is set, else the one of `self`.
+    pub fn merge(self, over: Self) -> Self {
+        let mut merged = to_json(&self);
+        if let (Some(merged), Value::Object(over)) = (merged.as_object_mut(), to_json(&over)) {
+            merged.extend(over.into_iter().filter(|(_, v)| !v.is_null()));
+        }
+        serde_json::from_value(merged).expect("merged from valid configs")
+    }
+
+    /// Reads a TOML config, returning it and its unknown keys.
+    pub fn from_toml(s: &str) -> io::Result<(Self, Vec<String>)> {
+        let doc = s.parse::<toml_edit::Document>().map_err(invalid_config)?;
+        let json = item_to_json(doc.as_item())?;
+        let known = to_json(&Self::default());
+        let unknown = json
+            .as_object()
+            .into_iter()
+            .flat_map(|x| x.keys())
+            .filter(|x| known.get(x.as_str()).is_none())
+            .cloned()
+            .collect();
+        let config = serde_json::from_value::<Self>(json).map_err(invalid_config)?;
+        Ok((config, unknown))
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<(Self, Vec<String>)> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// Reads the variables starting with [`ENV_PREFIX`], returning the config and the unknown
+    /// variables.
+    pub fn from_env<I: IntoIterator<Item = (String, String)>>(
+        vars: I,
+    ) -> io::Result<(Self, Vec<String>)> {
+        let known = to_json(&Self::default());
+        let mut config = serde_json::Map::new();
+        let mut unknown = Vec::new();
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let key = key.to_ascii_lowercase();
+            if known.get(&key).is_none() {
+                unknown.push(name);
+                continue;
+            }
+            let value = env_to_json(&key, &value)
+                .map_err(|e| invalid_config(format!("{}: {}", name, e)))?;
+            config.insert(key, value);
+        }
+        let config = serde_json::from_value(Value::Object(config)).map_err(invalid_config)?;
+        Ok((config, unknown))
+    }
+
+    /// Merges [`ServerConfig::defaults`], the file at `path`, the environment `vars` and `flags`
+    /// in this order, returning the result and a warning for each unknown key.
+    pub fn load<I: IntoIterator<Item = (String, String)>>(
+        path: Option<&Path>,
+        vars: I,
+        flags: Self,
+    ) -> io::Result<(Self, Vec<String>)> {
+        let mut config = Self::defaults();
+        let mut warnings = Vec::new();
+        if let Some(path) = path {
+            let (file, unknown) = Self::from_file(path).map_err(|e| {
+                io::Error::new(e.kind(), format!("config {}: {}", path.display(), e))
+            })?;
+            warnings.extend(
+                unknown
+                    .into_iter()
+                    .map(|x| format!("unknown key {} in {}", x, path.display())),
+            );
+            config = config.merge(file);
+        }
+        let (env, unknown) = Self::from_env(vars)?;
+        warnings.extend(
+            unknown
+                .into_iter()
+                .map(|x| format!("unknown environment variable {}", x)),
+        );
+        Ok((config.merge(env).merge(flags), warnings))
+    }
+
+    /// The options that are set, as a TOML config.
+    pub fn to_toml(&self) -> String {
+        let mut doc = toml_edit::Document::new();
+        if let Ok(Value::Object(map)) = serde_json::to_value(self) {
+            for (key, value) in map.iter() {
+                if let Some(x) = json_to_toml(value) {
+                    doc[key.as_str()] = toml_edit::value(x);
+                }
+            }
+        }
+        doc.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tempdir::TempDir;
+
+    use super::ServerConfig;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let (config, unknown) = ServerConfig::from_toml(
+            r#"
+port = 9000
+ws_paths = ["/ingest", "/staging=staging"]
+idle_timeout = 30
+query_timeout = 2.5
+verify_on_start = true
+acl_file = "/etc/uplog/acl.toml"
+retention_days = 7
+
+[tls]
+cert = "cert.pem"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.port, Some(9000));
+        assert_eq!(
+            config.ws_paths.as_deref(),
+            Some(&["/ingest".to_string(), "/staging=staging".to_string()][..])
+        );
+        assert_eq!(config.idle_timeout, Some(30.0));
+        assert_eq!(config.query_timeout, Some(2.5));
+        assert_eq!(config.verify_on_start, Some(true));
+        assert_eq!(
+            config.acl_file.as_deref(),
+            Some(Path::new("/etc/uplog/acl.toml"))
+        );
+        assert_eq!(config.data_dir, None);
+        // 知らないキーはエラーにしない
+        assert_eq!(unknown, ["retention_days", "tls"]);
+
+        assert!(ServerConfig::from_toml("port = \"high\"").is_err());
+        assert!(ServerConfig::from_toml("port = ").is_err());
+    }
+
+    #[test]
+    fn test_from_env() {
+        let (config, unknown) = ServerConfig::from_env(vars(&[
+            ("UPLOG_SERVER_PORT", "9100"),
+            ("UPLOG_SERVER_WS_PATHS", "/a,/b=b"),
+            ("UPLOG_SERVER_PRUNE_WHEN_FULL", "true"),
+            ("UPLOG_SERVER_HANDSHAKE_GRACE", "0.5"),
+            ("UPLOG_SERVER_MAX_PINNED_BYTES", "1048576"),
+            ("UPLOG_SERVER_RETENTION", "7"),
+            ("HOME", "/root"),
+        ]))
+        .unwrap();
+        assert_eq!(config.port, Some(9100));
+        assert_eq!(config.ws_paths, Some(vec!["/a".into(), "/b=b".into()]));
+        assert_eq!(config.prune_when_full, Some(true));
+        assert_eq!(config.handshake_grace, Some(0.5));
+        assert_eq!(config.max_pinned_bytes, Some(1048576));
+        assert_eq!(unknown, ["UPLOG_SERVER_RETENTION"]);
+
+        // 値はキーの型で読む
+        let (config, _) = ServerConfig::from_env(vars(&[
+            ("UPLOG_SERVER_UDS_MODE", "660"),
+            ("UPLOG_SERVER_DATA_DIR", "true"),
+            ("UPLOG_SERVER_WS_PATHS", "/a"),
+        ]))
+        .unwrap();
+        assert_eq!(config.uds_mode.as_deref(), Some("660"));
+        assert_eq!(config.data_dir.as_deref(), Some("true"));
+        assert_eq!(config.ws_paths, Some(vec!["/a".into()]));
+
+        let e = ServerConfig::from_env(vars(&[("UPLOG_SERVER_PORT", "x")])).unwrap_err();
+        assert!(e.to_string().contains("UPLOG_SERVER_PORT"), "{}", e);
+    }
+
+    #[test]
+    fn test_load_precedence() {
+        let dir = TempDir::new("config").unwrap();
+        let path = dir.path().join("server.toml");
+        std::fs::write(
+            &path,
+            "port = 9000\ndata_dir = \"/file\"\nview_dir = \"/file/view\"\nmax_read_length = 5\nunknown = 1\n",
+        )
+        .unwrap();
+        let env = vars(&[
+            ("UPLOG_SERVER_PORT", "9100"),
+            ("UPLOG_SERVER_DATA_DIR", "/env"),
+        ]);
+        let flags = ServerConfig {
+            port: Some(9200),
+            ..Default::default()
+        };
+
+        // 既定値 < ファイル < 環境変数 < コマンドライン
+        let (config, warnings) = ServerConfig::load(Some(&path), env.clone(), flags).unwrap();
+        assert_eq!(config.port, Some(9200));
+        assert_eq!(config.data_dir.as_deref(), Some("/env"));
+        assert_eq!(config.view_dir.as_deref(), Some("/file/view"));
+        assert_eq!(config.max_read_length, Some(5));
+        assert_eq!(config.max_query_depth, Some(16));
+        assert_eq!(config.max_record_bytes, None);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("unknown"), "{:?}", warnings);
+
+        let (config, _) = ServerConfig::load(Some(&path), env, ServerConfig::default()).unwrap();
+        assert_eq!(config.port, Some(9100));
+        let (config, _) =
+            ServerConfig::load(Some(&path), Vec::new(), ServerConfig::default()).unwrap();
+        assert_eq!(config.port, Some(9000));
+        assert_eq!(config.data_dir.as_deref(), Some("/file"));
+        let (config, warnings) =
+            ServerConfig::load(None, Vec::new(), ServerConfig::default()).unwrap();
+        assert_eq!(config, ServerConfig::defaults());
+        assert!(warnings.is_empty());
+
+        assert!(ServerConfig::load(
+            Some(&dir.path().join("missing.toml")),
+            Vec::new(),
+            ServerConfig::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_to_toml() {
+        let config = ServerConfig {
+            uds_mode: Some("660".into()),
+            idle_timeout: Some(1.5),
+            ..ServerConfig::defaults()
+        };
+        let text = config.to_toml();
+        assert!(text.contains("port = 8040\n"), "{}", text);
+        assert!(!text.contains("max_record_bytes"), "{}", text);
+        // 書き出したものをそのまま読める
+        let (parsed, unknown) = ServerConfig::from_toml(&text).unwrap();
+        assert_eq!(parsed, config);
+        assert!(unknown.is_empty());
+    }
+}
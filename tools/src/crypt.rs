@@ -0,0 +1,545 @@
+//! データファイルの暗号化
+//!
+//! ファイルの先頭にnonceの前半[`NONCE_PREFIX_LEN`]バイトを置き、続けて平文を最大[`FRAME_SIZE`]の
+//! フレームに分けて`[長さ u32][暗号文][タグ 16]`の順に書く。
+//! 鍵はセッションごとのsaltからHKDF-SHA256で導出し、XChaCha20-Poly1305で暗号化する。
+//! nonceはファイルごとの乱数にフレーム番号をつなげるので、同じセッションの別のファイルや
+//! 書き直したファイルでも重ならない。
+//! flushのたびに途中までのフレームを閉じるので、読み出し側は閉じたフレームだけを読む
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::RwLock,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+use crate::meta::EncryptionInfo;
+
+/// Most plaintext bytes in one frame of an encrypted data file.
+pub const FRAME_SIZE: usize = 64 * 1024;
+
+/// Name of the scheme recorded in [`EncryptionInfo::scheme`].
+pub const SCHEME: &str = "xchacha20poly1305";
+
+/// Random bytes at the start of each encrypted file, followed by the frame number in the nonce.
+pub const NONCE_PREFIX_LEN: usize = 16;
+
+const HEADER_LEN: u64 = 4;
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+
+/// Key that encrypts the data files of new sessions, see [`crate::Storage::encrypt_with`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncryptionKey({})", self.id())
+    }
+}
+
+impl EncryptionKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// Reads a key file holding 32 raw bytes or 64 hex digits.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let buf = std::fs::read(path.as_ref())?;
+        let text = std::str::from_utf8(&buf).map(str::trim).unwrap_or("");
+        if let Some(key) = decode_hex(text) {
+            return Ok(Self(key));
+        }
+        match <[u8; 32]>::try_from(buf.as_slice()) {
+            Ok(key) => Ok(Self(key)),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "key file {} must hold 32 bytes or 64 hex digits",
+                    path.as_ref().display()
+                ),
+            )),
+        }
+    }
+
+    /// Short public name of the key, stored in the session to find the key for reading.
+    pub fn id(&self) -> String {
+        hex(&Sha256::new()
+            .chain_update(b"uplog key id")
+            .chain_update(self.0)
+            .finalize()[..8])
+    }
+}
+
+/// 読み出しに使う鍵。idで選ぶ
+static KEYS: RwLock<Vec<EncryptionKey>> = RwLock::new(Vec::new());
+
+/// Makes `key` available to the readers of encrypted sessions in this process.
+pub fn register_key(key: &EncryptionKey) {
+    let mut keys = KEYS.write().expect("encryption keys lock");
+    if !keys.contains(key) {
+        keys.push(key.clone());
+    }
+}
+
+fn find_key(id: &str) -> Option<EncryptionKey> {
+    KEYS.read()
+        .expect("encryption keys lock")
+        .iter()
+        .find(|x| x.id() == id)
+        .cloned()
+}
+
+/// セッションごとの鍵
+#[derive(Clone)]
+struct SessionKeys {
+    cipher: XChaCha20Poly1305,
+}
+
+impl SessionKeys {
+    fn derive(key: &EncryptionKey, salt: &[u8]) -> Self {
+        let mut okm = [0_u8; 32];
+        Hkdf::<Sha256>::new(Some(salt), &key.0)
+            .expand(b"uplog data key", &mut okm)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self {
+            cipher: XChaCha20Poly1305::new(&okm.into()),
+        }
+    }
+
+    /// 長さも認証する
+    fn seal(&self, prefix: &[u8; NONCE_PREFIX_LEN], frame: u64, plain: &[u8]) -> Vec<u8> {
+        let aad = (plain.len() as u32).to_le_bytes();
+        self.cipher
+            .encrypt(
+                &nonce(prefix, frame),
+                Payload {
+                    msg: plain,
+                    aad: &aad,
+                },
+            )
+            .expect("a frame is shorter than the XChaCha20-Poly1305 limit")
+    }
+
+    fn open(
+        &self,
+        prefix: &[u8; NONCE_PREFIX_LEN],
+        frame: u64,
+        sealed: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let aad = ((sealed.len() - TAG_LEN) as u32).to_le_bytes();
+        self.cipher
+            .decrypt(
+                &nonce(prefix, frame),
+                Payload {
+                    msg: sealed,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "frame {} failed authentication: wrong key or corrupted data",
+                        frame
+                    ),
+                )
+            })
+    }
+}
+
+/// ファイルごとの乱数とフレーム番号
+fn nonce(prefix: &[u8; NONCE_PREFIX_LEN], frame: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..].copy_from_slice(&frame.to_le_bytes());
+    nonce
+}
+
+fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut buf = [0_u8; N];
+    getrandom::getrandom(&mut buf).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(buf)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<[u8; 32]> {
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+    let mut key = [0_u8; 32];
+    for (i, x) in key.iter_mut().enumerate() {
+        *x = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+/// 新しいセッションの暗号化の情報。saltは毎回作る
+pub(crate) fn new_session(key: &EncryptionKey) -> io::Result<EncryptionInfo> {
+    let salt = random_bytes::<SALT_LEN>()?;
+    Ok(EncryptionInfo {
+        scheme: SCHEME.to_string(),
+        key_id: key.id(),
+        salt: hex(&salt),
+    })
+}
+
+fn session_keys(info: &EncryptionInfo, key: Option<&EncryptionKey>) -> io::Result<SessionKeys> {
+    if info.scheme != SCHEME {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unknown encryption scheme {}", info.scheme),
+        ));
+    }
+    let key = match key {
+        Some(x) if x.id() == info.key_id => x.clone(),
+        Some(x) => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "session is encrypted with key {}, not with key {}",
+                    info.key_id,
+                    x.id()
+                ),
+            ))
+        }
+        None => find_key(&info.key_id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "session is encrypted with key {}, which is not loaded (--encrypt-key-file)",
+                    info.key_id
+                ),
+            )
+        })?,
+    };
+    let salt = (0..info.salt.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(info.salt.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid encryption salt"))?;
+    Ok(SessionKeys::derive(&key, &salt))
+}
+
+/// 平文をフレームに分けて書く。flushとdropで途中のフレームも閉じる
+pub(crate) struct EncryptingWriter<W: Write> {
+    inner: W,
+    keys: SessionKeys,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    frame: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// 空のファイルに書き始める
+    pub(crate) fn new(
+        mut inner: W,
+        info: &EncryptionInfo,
+        key: &EncryptionKey,
+    ) -> io::Result<Self> {
+        let keys = session_keys(info, Some(key))?;
+        let prefix = random_bytes::<NONCE_PREFIX_LEN>()?;
+        inner.write_all(&prefix)?;
+        Ok(Self {
+            inner,
+            keys,
+            prefix,
+            frame: 0,
+            buf: Vec::with_capacity(FRAME_SIZE),
+        })
+    }
+
+    fn seal(&mut self, len: usize) -> io::Result<()> {
+        let sealed = self.keys.seal(&self.prefix, self.frame, &self.buf[..len]);
+        self.buf.drain(..len);
+        self.inner.write_all(&(len as u32).to_le_bytes())?;
+        self.inner.write_all(&sealed)?;
+        self.frame += 1;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        while self.buf.len() >= FRAME_SIZE {
+            self.seal(FRAME_SIZE)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.seal(self.buf.len())?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for EncryptingWriter<W> {
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
+
+/// 閉じたフレーム
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    /// 平文での開始位置
+    start: u64,
+    /// ファイル上の暗号文の位置
+    offset: u64,
+    len: usize,
+}
+
+/// 平文の位置で読む。書き込み中のファイルは閉じたフレームまでを読む
+pub struct DecryptingReader {
+    file: File,
+    keys: SessionKeys,
+    /// ファイルの先頭のnonceの前半。まだ書かれていなければ空のファイルとして扱う
+    prefix: Option<[u8; NONCE_PREFIX_LEN]>,
+    frames: Vec<Frame>,
+    /// 次に調べるファイル上の位置
+    scanned: u64,
+    pos: u64,
+    /// 最後に復号したフレームの番号と平文
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl DecryptingReader {
+    /// `key`がなければ登録した鍵から探す
+    pub(crate) fn new(
+        file: File,
+        info: &EncryptionInfo,
+        key: Option<&EncryptionKey>,
+    ) -> io::Result<Self> {
+        let mut reader = Self {
+            file,
+            keys: session_keys(info, key)?,
+            prefix: None,
+            frames: Vec::new(),
+            scanned: NONCE_PREFIX_LEN as u64,
+            pos: 0,
+            cache: None,
+        };
+        reader.refresh()?;
+        Ok(reader)
+    }
+
+    /// 追記されたフレームを探す
+    fn refresh(&mut self) -> io::Result<()> {
+        let file_len = self.file.metadata()?.len();
+        if self.prefix.is_none() {
+            if file_len < NONCE_PREFIX_LEN as u64 {
+                return Ok(());
+            }
+            let mut prefix = [0_u8; NONCE_PREFIX_LEN];
+            self.file.seek(SeekFrom::Start(0))?;
+            self.file.read_exact(&mut prefix)?;
+            self.prefix = Some(prefix);
+        }
+        while self.scanned + HEADER_LEN <= file_len {
+            let mut header = [0_u8; HEADER_LEN as usize];
+            self.file.seek(SeekFrom::Start(self.scanned))?;
+            self.file.read_exact(&mut header)?;
+            let len = u32::from_le_bytes(header) as usize;
+            let end = self.scanned + HEADER_LEN + len as u64 + TAG_LEN as u64;
+            if len > FRAME_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid frame length {} at {}", len, self.scanned),
+                ));
+            }
+            if end > file_len {
+                break;
+            }
+            let start = self.frames.last().map_or(0, |x| x.start + x.len as u64);
+            self.frames.push(Frame {
+                start,
+                offset: self.scanned + HEADER_LEN,
+                len,
+            });
+            self.scanned = end;
+        }
+        Ok(())
+    }
+
+    /// Plaintext length of the frames written completely.
+    pub fn len(&mut self) -> io::Result<u64> {
+        self.refresh()?;
+        Ok(self.frames.last().map_or(0, |x| x.start + x.len as u64))
+    }
+
+    pub fn is_empty(&mut self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn frame_data(&mut self, index: usize) -> io::Result<&[u8]> {
+        if self.cache.as_ref().is_none_or(|(i, _)| *i != index) {
+            let frame = self.frames[index];
+            let mut sealed = vec![0_u8; frame.len + TAG_LEN];
+            self.file.seek(SeekFrom::Start(frame.offset))?;
+            self.file.read_exact(&mut sealed)?;
+            // フレームがあればnonceの前半は読んである
+            let prefix = self.prefix.expect("read before the frames");
+            let data = self.keys.open(&prefix, index as u64, &sealed)?;
+            self.cache = Some((index, data));
+        }
+        Ok(&self.cache.as_ref().expect("decrypted above").1)
+    }
+}
+
+impl Read for DecryptingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pos = self.pos;
+        let index = match self
+            .frames
+            .partition_point(|x| x.start + x.len as u64 <= pos)
+        {
+            i if i < self.frames.len() => i,
+            _ => {
+                // 読み切ったら追記されたフレームを探す
+                self.refresh()?;
+                match self
+                    .frames
+                    .partition_point(|x| x.start + x.len as u64 <= pos)
+                {
+                    i if i < self.frames.len() => i,
+                    _ => return Ok(0),
+                }
+            }
+        };
+        let start = self.frames[index].start;
+        let data = self.frame_data(index)?;
+        let skip = (pos - start) as usize;
+        let n = buf.len().min(data.len() - skip);
+        buf[..n].copy_from_slice(&data[skip..skip + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for DecryptingReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+            SeekFrom::End(x) => self.len()?.checked_add_signed(x),
+        };
+        self.pos = target
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use tempdir::TempDir;
+
+    use super::{
+        new_session, DecryptingReader, EncryptingWriter, EncryptionKey, FRAME_SIZE,
+        NONCE_PREFIX_LEN,
+    };
+
+    #[test]
+    fn test_encrypted_frames() {
+        let dir = TempDir::new("crypt").unwrap();
+        let path = dir.path().join("data");
+        let key = EncryptionKey::new([7; 32]);
+        let info = new_session(&key).unwrap();
+        let plain = (0..FRAME_SIZE * 2 + 100)
+            .map(|x| (x % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let mut writer =
+            EncryptingWriter::new(std::fs::File::create(&path).unwrap(), &info, &key).unwrap();
+        writer.write_all(&plain[..10]).unwrap();
+        // 途中のフレームはflushで閉じる
+        writer.flush().unwrap();
+        let mut reader =
+            DecryptingReader::new(std::fs::File::open(&path).unwrap(), &info, Some(&key)).unwrap();
+        assert_eq!(reader.len().unwrap(), 10);
+        writer.write_all(&plain[10..]).unwrap();
+        assert_eq!(reader.len().unwrap(), FRAME_SIZE as u64 * 2 + 10);
+        drop(writer);
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, plain);
+        // フレームをまたいで読む
+        reader.seek(SeekFrom::Start(FRAME_SIZE as u64 - 5)).unwrap();
+        let mut buf = [0_u8; 20];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..], plain[FRAME_SIZE - 5..FRAME_SIZE + 15]);
+
+        // 暗号文に平文は現れない
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(64).any(|x| x == &plain[1000..1064]));
+        // 同じ鍵で同じ平文を書いてもファイルごとにnonceが変わる
+        let other_path = dir.path().join("other");
+        let mut writer =
+            EncryptingWriter::new(std::fs::File::create(&other_path).unwrap(), &info, &key)
+                .unwrap();
+        writer.write_all(&plain).unwrap();
+        drop(writer);
+        let other_raw = std::fs::read(&other_path).unwrap();
+        assert_ne!(raw[..NONCE_PREFIX_LEN], other_raw[..NONCE_PREFIX_LEN]);
+        assert_ne!(
+            raw[NONCE_PREFIX_LEN + 4..NONCE_PREFIX_LEN + 64],
+            other_raw[NONCE_PREFIX_LEN + 4..NONCE_PREFIX_LEN + 64]
+        );
+
+        // 違う鍵や改ざんは読めない
+        let other = EncryptionKey::new([8; 32]);
+        let e = DecryptingReader::new(std::fs::File::open(&path).unwrap(), &info, Some(&other))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        // 登録していない鍵は探せない
+        let e = DecryptingReader::new(std::fs::File::open(&path).unwrap(), &info, None)
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("not loaded"), "{}", e);
+        let mut raw = raw;
+        raw[100] ^= 1;
+        std::fs::write(&path, raw).unwrap();
+        let mut reader =
+            DecryptingReader::new(std::fs::File::open(&path).unwrap(), &info, Some(&key)).unwrap();
+        let e = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_key_file() {
+        let dir = TempDir::new("crypt").unwrap();
+        let path = dir.path().join("key");
+        std::fs::write(&path, format!("{}\n", "ab".repeat(32))).unwrap();
+        assert_eq!(
+            EncryptionKey::from_file(&path).unwrap(),
+            EncryptionKey::new([0xab; 32])
+        );
+        std::fs::write(&path, [3_u8; 32]).unwrap();
+        assert_eq!(
+            EncryptionKey::from_file(&path).unwrap(),
+            EncryptionKey::new([3; 32])
+        );
+        std::fs::write(&path, b"short").unwrap();
+        assert!(EncryptionKey::from_file(&path).is_err());
+        assert_ne!(
+            EncryptionKey::new([3; 32]).id(),
+            EncryptionKey::new([4; 32]).id()
+        );
+    }
+}
@@ -0,0 +1,567 @@
+//! 信用できないクライアントから受け取ったデータのデコード
+//!
+//! CBORのヘッダーは任意の長さを宣言できるため、そのままデコードすると
+//! 2^32バイトのバイト列でも確保しようとする。
+//! 先にヘッダーだけを辿って値ごとの上限とレコードごとの上限を確認し、
+//! デコード自体も上限を設けた読み込み元から行う
+use std::{fmt::Display, io};
+
+use serde::{de::Error as _, Deserialize};
+use uplog::{
+    precision::{self, Precision},
+    wire::{WireDecoder, WireItem},
+    Record,
+};
+
+// serde_cborの再帰の上限と揃える
+const MAX_DEPTH: usize = 128;
+const BREAK: u8 = 0xff;
+
+/// Size limits applied while decoding records from a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// largest byte string or text string in a record
+    pub max_value_bytes: usize,
+    /// largest encoded record
+    pub max_record_bytes: usize,
+}
+
+impl DecodeLimits {
+    /// Uses the same limit for a single value and a whole record.
+    pub fn new(max_record_bytes: usize) -> Self {
+        Self {
+            max_value_bytes: max_record_bytes,
+            max_record_bytes,
+        }
+    }
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self::new(uplog::DEFAULT_BUFFER_SIZE)
+    }
+}
+
+/// 上限を超えたもの
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Oversize {
+    Value { declared: u64, limit: usize },
+    Record { len: usize, limit: usize },
+}
+
+impl Display for Oversize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Oversize::Value { declared, limit } => write!(
+                f,
+                "value declares {} bytes, limit is {} bytes",
+                declared, limit
+            ),
+            Oversize::Record { len, limit } => {
+                write!(f, "record has {} bytes, limit is {} bytes", len, limit)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// CBORまたはRecordとして解釈できない。以降の区切りは信用できない
+    Cbor(serde_cbor::Error),
+    /// 上限を超えたためデコードせずに捨てた
+    Oversize(Oversize),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Cbor(e) => e.fmt(f),
+            DecodeError::Oversize(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// 読み込めるバイト数を制限する
+///
+/// serde_cborのio読み込みは宣言された長さではなく実際に読めた分だけ確保するので、
+/// 読み込み元を制限すれば確保する量も制限される
+#[derive(Debug)]
+pub struct LimitedReader<R> {
+    inner: R,
+    limit: usize,
+    consumed: usize,
+}
+
+impl<R: io::Read> LimitedReader<R> {
+    pub fn new(inner: R, limit: usize) -> Self {
+        Self {
+            inner,
+            limit,
+            consumed: 0,
+        }
+    }
+
+    /// 読み込んだバイト数
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+}
+
+impl<R: io::Read> io::Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let remaining = self.limit - self.consumed;
+        if remaining == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("read limit {} bytes exceeded", self.limit),
+            ));
+        }
+        let len = buf.len().min(remaining);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+/// ヘッダーを辿った結果
+#[derive(Debug, PartialEq, Eq)]
+enum Scan {
+    /// 上限内に収まっている。レコードの終わりの位置
+    Fits(usize),
+    /// 上限を超えた。レコードの終わりがわかれば読み飛ばせる
+    Oversize(Oversize, Option<usize>),
+    /// 形式が不正。宣言された要素数で確保しないようにデコードせずにエラーにする
+    Malformed,
+}
+
+/// 値を確保せずにヘッダーだけを辿る
+struct Scanner<'a> {
+    buf: &'a [u8],
+    limits: DecodeLimits,
+    /// 最初に見つかった上限超過
+    oversize: Option<Oversize>,
+}
+
+/// 形式が不正か、読み飛ばせない上限超過
+struct Stop;
+
+impl<'a> Scanner<'a> {
+    fn scan(buf: &'a [u8], limits: DecodeLimits) -> Scan {
+        let mut scanner = Self {
+            buf,
+            limits,
+            oversize: None,
+        };
+        match (scanner.item(0, 0), scanner.oversize) {
+            (Ok(end), None) if end > limits.max_record_bytes => Scan::Oversize(
+                Oversize::Record {
+                    len: end,
+                    limit: limits.max_record_bytes,
+                },
+                Some(end),
+            ),
+            (Ok(end), None) => Scan::Fits(end),
+            (Ok(end), Some(x)) => Scan::Oversize(x, Some(end)),
+            (Err(Stop), Some(x)) => Scan::Oversize(x, None),
+            (Err(Stop), None) => Scan::Malformed,
+        }
+    }
+
+    /// 先頭のバイトとその引数、次の位置
+    fn header(&self, pos: usize) -> Result<(u8, u8, u64, usize), Stop> {
+        let first = *self.buf.get(pos).ok_or(Stop)?;
+        let (major, info) = (first >> 5, first & 0x1f);
+        let size = match info {
+            0..=23 => return Ok((major, info, info as u64, pos + 1)),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            31 => return Ok((major, info, 0, pos + 1)),
+            _ => return Err(Stop),
+        };
+        let bytes = self.buf.get(pos + 1..pos + 1 + size).ok_or(Stop)?;
+        let arg = bytes.iter().fold(0_u64, |acc, x| acc << 8 | *x as u64);
+        Ok((major, info, arg, pos + 1 + size))
+    }
+
+    /// 値の上限を超えていれば記録する
+    fn check_value(&mut self, declared: u64) {
+        if declared > self.limits.max_value_bytes as u64 {
+            self.oversize.get_or_insert(Oversize::Value {
+                declared,
+                limit: self.limits.max_value_bytes,
+            });
+        }
+    }
+
+    /// 宣言された長さを確認して値の終わりを返す
+    fn string(&mut self, declared: u64, pos: usize) -> Result<usize, Stop> {
+        self.check_value(declared);
+        if declared > (self.buf.len() - pos) as u64 {
+            return Err(Stop);
+        }
+        Ok(pos + declared as usize)
+    }
+
+    fn item(&mut self, pos: usize, depth: usize) -> Result<usize, Stop> {
+        if depth > MAX_DEPTH {
+            return Err(Stop);
+        }
+        let (major, info, arg, mut pos) = self.header(pos)?;
+        let indefinite = info == 31;
+        match major {
+            0 | 1 if !indefinite => Ok(pos),
+            2 | 3 if !indefinite => self.string(arg, pos),
+            2 | 3 => {
+                // 分割された文字列は連結されるので合計で確認する
+                let mut total = 0_u64;
+                loop {
+                    if self.buf.get(pos) == Some(&BREAK) {
+                        return Ok(pos + 1);
+                    }
+                    let (chunk_major, chunk_info, len, next) = self.header(pos)?;
+                    if chunk_major != major || chunk_info == 31 {
+                        return Err(Stop);
+                    }
+                    total = total.saturating_add(len);
+                    self.check_value(total);
+                    pos = self.string(len, next)?;
+                }
+            }
+            4 | 5 => {
+                let per_entry = if major == 4 { 1 } else { 2 };
+                if indefinite {
+                    loop {
+                        if self.buf.get(pos) == Some(&BREAK) {
+                            return Ok(pos + 1);
+                        }
+                        for _ in 0..per_entry {
+                            pos = self.item(pos, depth + 1)?;
+                        }
+                    }
+                }
+                // 要素は最低1バイトなので残りより多い宣言は不正
+                let items = arg.checked_mul(per_entry).ok_or(Stop)?;
+                if items > (self.buf.len() - pos) as u64 {
+                    return Err(Stop);
+                }
+                for _ in 0..items {
+                    pos = self.item(pos, depth + 1)?;
+                }
+                Ok(pos)
+            }
+            6 if !indefinite => self.item(pos, depth + 1),
+            7 if !indefinite => Ok(pos),
+            _ => Err(Stop),
+        }
+    }
+}
+
+/// 1メッセージに含まれるレコードを上限を確認しながら順にデコードする
+///
+/// 上限を超えたレコードは終わりがわかれば読み飛ばして続ける。
+/// 解釈できないデータがあればそれ以降は返さない
+pub struct FrameDecoder<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    limits: DecodeLimits,
+    done: bool,
+    /// 辞書を交渉した接続の表
+    wire: Option<&'a mut WireDecoder>,
+    /// 整数の`elapsed`の単位
+    time_precision: Option<Precision>,
+}
+
+impl<'a> FrameDecoder<'a> {
+    pub fn new(buf: &'a [u8], limits: DecodeLimits) -> Self {
+        Self {
+            buf,
+            offset: 0,
+            limits,
+            done: false,
+            wire: None,
+            time_precision: None,
+        }
+    }
+
+    /// `elapsed`が整数のレコードをこの単位で読む
+    pub fn time_precision(mut self, precision: Option<Precision>) -> Self {
+        self.time_precision = precision;
+        self
+    }
+
+    /// [`uplog::wire`]の形式として読み、表を使ってレコードに戻す
+    pub fn with_wire(mut self, wire: &'a mut WireDecoder) -> Self {
+        self.wire = Some(wire);
+        self
+    }
+
+    /// 次に読む位置。エラーの場合はそのレコードの先頭
+    pub fn byte_offset(&self) -> usize {
+        self.offset
+    }
+
+    /// 表への割り当てを読んだ場合はNoneを返す
+    fn decode(&mut self) -> Result<Option<Record>, DecodeError> {
+        let rest = &self.buf[self.offset..];
+        let mut reader = LimitedReader::new(rest, self.limits.max_record_bytes);
+        let mut de = serde_cbor::Deserializer::from_reader(&mut reader);
+        let record = match self.wire.as_mut() {
+            Some(wire) => {
+                let item = WireItem::deserialize(&mut de).map_err(DecodeError::Cbor)?;
+                wire.apply(item)
+                    .map_err(|e| DecodeError::Cbor(serde_cbor::Error::custom(e)))?
+            }
+            None => {
+                let record =
+                    precision::decode_with(self.time_precision, || Record::deserialize(&mut de));
+                Some(record.map_err(DecodeError::Cbor)?)
+            }
+        };
+        self.offset += reader.consumed();
+        Ok(record)
+    }
+}
+
+impl<'a> Iterator for FrameDecoder<'a> {
+    type Item = Result<Record, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done || self.offset >= self.buf.len() {
+                return None;
+            }
+            let result = match Scanner::scan(&self.buf[self.offset..], self.limits) {
+                Scan::Fits(_) => self.decode().transpose(),
+                Scan::Malformed => Some(Err(DecodeError::Cbor(serde_cbor::Error::custom(
+                    "malformed CBOR",
+                )))),
+                Scan::Oversize(e, Some(end)) => {
+                    self.offset += end;
+                    Some(Err(DecodeError::Oversize(e)))
+                }
+                Scan::Oversize(e, None) => {
+                    self.done = true;
+                    Some(Err(DecodeError::Oversize(e)))
+                }
+            };
+            if matches!(result, Some(Err(DecodeError::Cbor(_)))) {
+                self.done = true;
+            }
+            if result.is_some() {
+                return result;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+    };
+
+    use uplog::{devinit, devlog, Level, Record, Value};
+
+    use super::{DecodeError, DecodeLimits, FrameDecoder, Oversize};
+
+    /// このスレッドで要求された最大の確保サイズを記録する
+    struct PeakAlloc;
+
+    thread_local! {
+        static TRACKING: Cell<bool> = const { Cell::new(false) };
+        static PEAK: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn track(size: usize) {
+        if TRACKING.with(|x| x.get()) {
+            PEAK.with(|x| x.set(x.get().max(size)));
+        }
+    }
+
+    unsafe impl GlobalAlloc for PeakAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            track(layout.size());
+            System.alloc(layout)
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            track(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: PeakAlloc = PeakAlloc;
+
+    /// デコード中に要求された最大の確保サイズとデコード結果
+    fn decode_all(buf: &[u8], limits: DecodeLimits) -> (usize, Vec<Result<Record, DecodeError>>) {
+        let mut results = Vec::with_capacity(16);
+        PEAK.with(|x| x.set(0));
+        TRACKING.with(|x| x.set(true));
+        for r in FrameDecoder::new(buf, limits) {
+            results.push(r);
+        }
+        TRACKING.with(|x| x.set(false));
+        (PEAK.with(|x| x.get()), results)
+    }
+
+    fn encode(records: &[Record]) -> Vec<u8> {
+        records
+            .iter()
+            .flat_map(|x| serde_cbor::to_vec(x).unwrap())
+            .collect()
+    }
+
+    /// `data`の4バイトのバイト列を書き換える
+    fn patch_data(buf: &[u8], data: &[u8]) -> Vec<u8> {
+        let target = [0x44, 0xaa, 0xbb, 0xcc, 0xdd];
+        let pos = buf.windows(target.len()).position(|x| x == target).unwrap();
+        let mut result = buf[..pos].to_vec();
+        result.extend_from_slice(data);
+        result.extend_from_slice(&buf[pos + target.len()..]);
+        result
+    }
+
+    #[test]
+    fn test_oversized_header() {
+        devinit!();
+        let limits = DecodeLimits::new(4096);
+        let record = devlog!(
+            Level::Info,
+            "cat",
+            "msg",
+            "data",
+            vec![0xaa_u8, 0xbb, 0xcc, 0xdd]
+        );
+        let valid = encode(std::slice::from_ref(&record));
+        let (_, results) = decode_all(&valid, limits);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].as_ref().unwrap(), &record);
+
+        // バイト列、文字列、配列、mapで大きな長さを宣言する
+        let headers: &[&[u8]] = &[
+            &[0x5a, 0xff, 0xff, 0xff, 0xff],
+            &[0x5b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            &[0x7a, 0xff, 0xff, 0xff, 0xff],
+            &[0x5f, 0x5a, 0x7f, 0xff, 0xff, 0xff],
+            &[0x9a, 0xff, 0xff, 0xff, 0xff],
+            &[0xbb, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            &[0x59, 0x10, 0x00],
+        ];
+        for header in headers {
+            let mut data = header.to_vec();
+            data.extend_from_slice(&[0xaa, 0xbb, 0xcc, 0xdd]);
+            let crafted = patch_data(&valid, &data);
+            let (peak, results) = decode_all(&crafted, limits);
+            assert!(peak <= limits.max_record_bytes, "{:x?} {}", header, peak);
+            assert_eq!(results.len(), 1, "{:x?}", header);
+            assert!(results[0].is_err(), "{:x?}", header);
+        }
+
+        // 分割されたバイト列は連結して読む
+        let chunked = patch_data(&valid, &[0x5f, 0x42, 0xaa, 0xbb, 0x42, 0xcc, 0xdd, 0xff]);
+        let (_, results) = decode_all(&chunked, DecodeLimits::new(4096));
+        assert_eq!(results[0].as_ref().unwrap(), &record);
+        // 分割したそれぞれは上限以下でも合計で確認する
+        let mut data = vec![0x5f];
+        for _ in 0..2 {
+            data.extend_from_slice(&[0x58, 100]);
+            data.extend_from_slice(&[0; 100]);
+        }
+        data.push(0xff);
+        let chunked = patch_data(&valid, &data);
+        let small_value = DecodeLimits {
+            max_value_bytes: 150,
+            max_record_bytes: 4096,
+        };
+        let (_, results) = decode_all(&chunked, small_value);
+        assert!(matches!(
+            results[0],
+            Err(DecodeError::Oversize(Oversize::Value {
+                declared: 200,
+                limit: 150
+            }))
+        ));
+
+        // 上限を超えるが中身まであるレコードは読み飛ばして次を読む
+        let large = devlog!(Level::Info, "cat", "large", "data", vec![0_u8; 8192]);
+        let buf = encode(&[record.clone(), large, record.clone()]);
+        let (peak, results) = decode_all(&buf, limits);
+        assert!(peak <= limits.max_record_bytes, "{}", peak);
+        assert_eq!(results.len(), 3);
+        assert!(matches!(
+            results[1],
+            Err(DecodeError::Oversize(Oversize::Value {
+                declared: 8192,
+                limit: 4096
+            }))
+        ));
+        assert_eq!(results[2].as_ref().unwrap(), &record);
+
+        // 個々の値は小さくてもレコード全体で超える
+        let limits = DecodeLimits {
+            max_value_bytes: 4096,
+            max_record_bytes: 1024,
+        };
+        let many = devlog!(
+            Level::Info,
+            "cat",
+            "many",
+            "data",
+            Value::Array(vec![Value::Bytes(vec![0; 512]); 4])
+        );
+        let buf = encode(&[many, record.clone()]);
+        let (_, results) = decode_all(&buf, limits);
+        assert!(matches!(
+            results[0],
+            Err(DecodeError::Oversize(Oversize::Record { limit: 1024, .. }))
+        ));
+        assert_eq!(results[1].as_ref().unwrap(), &record);
+    }
+
+    /// 有効なデータを1バイトずつ壊しても上限を超えて確保しない
+    #[test]
+    fn test_mutated_frames() {
+        devinit!();
+        let limits = DecodeLimits::new(4096);
+        let record = devlog!(
+            Level::Info,
+            "cat",
+            "msg",
+            "data",
+            vec![0xaa_u8, 0xbb, 0xcc, 0xdd],
+            "list",
+            vec![1_u32, 2, 3]
+        );
+        let valid = encode(&[record.clone(), record]);
+        let mut seed = 0x2545_f491_u32;
+        for pos in 0..valid.len() {
+            for _ in 0..8 {
+                // xorshift
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                let mut buf = valid.clone();
+                buf[pos] = seed as u8;
+                let (peak, _) = decode_all(&buf, limits);
+                assert!(
+                    peak <= limits.max_record_bytes,
+                    "{} {:x} {}",
+                    pos,
+                    seed,
+                    peak
+                );
+            }
+        }
+    }
+}
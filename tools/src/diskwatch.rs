@@ -0,0 +1,443 @@
+//! ストレージの空き容量の監視
+//!
+//! 空きが下限を下回ったら新しいセッションを受け付けない読み取り専用にする。
+//! 既存の接続は予約した量までは書き込める。空きが回復の閾値を超えたら受け付けを再開する
+use std::{
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use actix::prelude::*;
+use actix_web::{web, HttpResponse};
+use async_graphql::SimpleObject;
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::Storage;
+
+/// 受け付けられる状態かを返すパス
+pub const READYZ_PATH: &str = "/readyz";
+
+/// Free space of the storage volume.
+pub trait FreeSpace: Send + Sync {
+    fn free_bytes(&self) -> io::Result<u64>;
+}
+
+/// Free space available to this process on the volume of a directory.
+#[derive(Debug, Clone)]
+pub struct MountFreeSpace(pub PathBuf);
+
+impl FreeSpace for MountFreeSpace {
+    fn free_bytes(&self) -> io::Result<u64> {
+        fs2::available_space(&self.0)
+    }
+}
+
+/// When the server stops and resumes accepting sessions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskPolicy {
+    /// the storage becomes read-only below this many free bytes
+    pub min_free_bytes: u64,
+    /// ingest resumes at this many free bytes, above `min_free_bytes` not to flap
+    pub resume_free_bytes: u64,
+    pub interval: Duration,
+    /// bytes each open connection may still send while read-only
+    pub reserved_bytes: u64,
+    /// remove the oldest closed sessions when below `min_free_bytes`
+    pub prune: bool,
+}
+
+impl DiskPolicy {
+    /// 回復の閾値は下限の1割増し
+    pub fn new(min_free_bytes: u64) -> Self {
+        Self {
+            min_free_bytes,
+            resume_free_bytes: min_free_bytes + min_free_bytes / 10,
+            interval: Duration::from_secs(10),
+            reserved_bytes: 1024 * 1024,
+            prune: false,
+        }
+    }
+}
+
+/// State of the storage volume.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, SimpleObject)]
+pub struct DiskStatus {
+    /// new sessions are rejected
+    pub read_only: bool,
+    /// free bytes at the last check, none before the first check
+    pub free_bytes: Option<u64>,
+    pub min_free_bytes: u64,
+    pub resume_free_bytes: u64,
+    /// sessions removed to free space since the server started
+    pub pruned_sessions: u64,
+}
+
+#[derive(Debug, Default)]
+struct DiskState {
+    policy: Option<DiskPolicy>,
+    read_only: AtomicBool,
+    free_bytes: AtomicU64,
+    /// 1度でも空き容量を確かめたか
+    checked: AtomicBool,
+    pruned_sessions: AtomicU64,
+}
+
+/// Read-only state shared between the watchdog and the connections.
+///
+/// The default guard has no policy and never becomes read-only.
+#[derive(Debug, Clone, Default)]
+pub struct DiskGuard(Arc<DiskState>);
+
+impl DiskGuard {
+    pub fn new(policy: DiskPolicy) -> Self {
+        Self(Arc::new(DiskState {
+            policy: Some(policy),
+            ..Default::default()
+        }))
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.0.read_only.load(Ordering::Acquire)
+    }
+
+    /// 読み取り専用の間に既存の接続が送れるバイト数
+    pub fn reserved_bytes(&self) -> u64 {
+        self.0.policy.map_or(0, |x| x.reserved_bytes)
+    }
+
+    pub fn status(&self) -> DiskStatus {
+        let policy = self.0.policy;
+        DiskStatus {
+            read_only: self.is_read_only(),
+            free_bytes: self
+                .0
+                .checked
+                .load(Ordering::Acquire)
+                .then(|| self.0.free_bytes.load(Ordering::Acquire)),
+            min_free_bytes: policy.map_or(0, |x| x.min_free_bytes),
+            resume_free_bytes: policy.map_or(0, |x| x.resume_free_bytes),
+            pruned_sessions: self.0.pruned_sessions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 空き容量を確かめて状態を更新し、読み取り専用かを返す
+    ///
+    /// 削除が有効なら下限を下回ったときに回復の閾値まで古いセッションを削除する
+    pub fn check(&self, space: &dyn FreeSpace, storage: Option<&Storage>) -> io::Result<bool> {
+        let policy = match self.0.policy {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+        let mut free = space.free_bytes()?;
+        if free < policy.min_free_bytes && policy.prune {
+            if let Some(storage) = storage {
+                let removed = prune_oldest(storage, policy.resume_free_bytes.saturating_sub(free))?;
+                if !removed.is_empty() {
+                    self.0
+                        .pruned_sessions
+                        .fetch_add(removed.len() as u64, Ordering::Relaxed);
+                    free = space.free_bytes()?;
+                }
+            }
+        }
+        self.0.free_bytes.store(free, Ordering::Release);
+        self.0.checked.store(true, Ordering::Release);
+        let was_read_only = self.is_read_only();
+        let read_only = if was_read_only {
+            free < policy.resume_free_bytes
+        } else {
+            free < policy.min_free_bytes
+        };
+        if read_only != was_read_only {
+            if read_only {
+                warn!(
+                    "storage is read-only, {} bytes free is below {}",
+                    free, policy.min_free_bytes
+                );
+            } else {
+                info!("storage accepts sessions again, {} bytes free", free);
+            }
+            self.0.read_only.store(read_only, Ordering::Release);
+        }
+        Ok(read_only)
+    }
+}
+
+/// 書き込み中でない古いセッションから、合計`bytes`以上になるまで削除する。削除したセッション名を返す
+///
+/// 固定したセッションは削除しない
+pub fn prune_oldest(storage: &Storage, bytes: u64) -> io::Result<Vec<String>> {
+    let storage = storage.as_initiator(crate::audit::INITIATOR_RETENTION);
+    let mut sessions = storage.records()?;
+    sessions.retain(|x| !x.is_live() && !x.meta().pinned);
+    sessions.sort_by_key(|x| *x.created_at());
+    let mut removed = Vec::new();
+    let mut freed = 0;
+    for info in sessions {
+        if freed >= bytes {
+            break;
+        }
+        let name = info.name();
+        match storage.prune_session(&name) {
+            Ok(size) => {
+                warn!("removed session {} to free {} bytes", name, size);
+                freed += size;
+                removed.push(name);
+            }
+            // 一覧の後に書き込みが始まった
+            Err(e) => error!("failed to remove session {}: {}", name, e),
+        }
+    }
+    Ok(removed)
+}
+
+/// 一定間隔で空き容量を確かめる
+pub struct DiskWatchActor {
+    guard: DiskGuard,
+    space: Box<dyn FreeSpace>,
+    storage: Option<Storage>,
+    interval: Duration,
+}
+
+impl DiskWatchActor {
+    pub fn new(guard: DiskGuard, space: Box<dyn FreeSpace>) -> Self {
+        let interval = guard
+            .0
+            .policy
+            .map_or(Duration::from_secs(10), |x| x.interval);
+        Self {
+            guard,
+            space,
+            storage: None,
+            interval,
+        }
+    }
+
+    /// 削除が有効な場合に古いセッションを削除するストレージ
+    pub fn storage(mut self, storage: Storage) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    fn check(&mut self) {
+        if let Err(e) = self.guard.check(self.space.as_ref(), self.storage.as_ref()) {
+            error!("failed to check free space: {}", e);
+        }
+    }
+}
+
+impl Actor for DiskWatchActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.check();
+        ctx.run_interval(self.interval, |act, _| act.check());
+    }
+}
+
+/// 読み取り専用の間は503を返す
+pub async fn readyz(guard: Option<web::Data<DiskGuard>>) -> HttpResponse {
+    let status = guard.map(|x| x.status()).unwrap_or_default();
+    if status.read_only {
+        HttpResponse::ServiceUnavailable().json(status)
+    } else {
+        HttpResponse::Ok().json(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    };
+
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level};
+
+    use super::{prune_oldest, DiskGuard, DiskPolicy, FreeSpace};
+    use crate::{writer::RecordWriter, Storage};
+
+    /// テストで空き容量を差し替える
+    #[derive(Default, Clone)]
+    struct FakeSpace(Arc<AtomicU64>);
+
+    impl FakeSpace {
+        fn set(&self, free: u64) {
+            self.0.store(free, Ordering::SeqCst);
+        }
+    }
+
+    impl FreeSpace for FakeSpace {
+        fn free_bytes(&self) -> io::Result<u64> {
+            Ok(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    #[test]
+    fn test_read_only_hysteresis() {
+        let guard = DiskGuard::new(DiskPolicy {
+            resume_free_bytes: 150,
+            ..DiskPolicy::new(100)
+        });
+        let space = FakeSpace::default();
+        assert_eq!(guard.status().free_bytes, None);
+        for (free, read_only) in [
+            (200, false),
+            (90, true),
+            (120, true),
+            (160, false),
+            (120, false),
+        ] {
+            space.set(free);
+            assert_eq!(guard.check(&space, None).unwrap(), read_only, "{}", free);
+            assert_eq!(guard.is_read_only(), read_only);
+        }
+        let status = guard.status();
+        assert_eq!(status.free_bytes, Some(120));
+        assert_eq!(
+            (status.min_free_bytes, status.resume_free_bytes),
+            (100, 150)
+        );
+
+        // 監視しない場合は読み取り専用にならない
+        space.set(0);
+        assert!(!DiskGuard::default().check(&space, None).unwrap());
+    }
+
+    #[test]
+    fn test_prune_oldest() {
+        devinit!();
+        let dir = TempDir::new("prune").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        for name in ["a", "b", "c"] {
+            let mut session = storage.create_session(name).unwrap();
+            session.push(&devlog!(Level::Info, "app", "msg")).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // 書き込み中のセッションは削除しない
+        let _live = storage.create_session("d").unwrap();
+        assert_eq!(prune_oldest(&storage, 1).unwrap(), ["a"]);
+        assert_eq!(prune_oldest(&storage, u64::MAX).unwrap(), ["b", "c"]);
+        let names = storage
+            .records()
+            .unwrap()
+            .iter()
+            .map(|x| x.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["d"]);
+        assert!(storage.remove_session("d").is_err());
+
+        // 削除は自動で行ったものとして記録し、拒否した削除も残す
+        let entries = storage.audit_log(None).unwrap();
+        let summary = entries
+            .iter()
+            .rev()
+            .map(|x| {
+                (
+                    x.operation.as_str(),
+                    x.session.as_str(),
+                    x.initiator.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("prune", "a", "retention"),
+                ("prune", "b", "retention"),
+                ("prune", "c", "retention"),
+                ("delete", "d", "local"),
+            ]
+        );
+        assert!(entries[1..].iter().all(|x| x.is_ok() && x.bytes > 0));
+        assert!(entries[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("being written"));
+
+        // 下限を下回ったら削除して数える
+        let mut session = storage.create_session("e").unwrap();
+        session.push(&devlog!(Level::Info, "app", "msg")).unwrap();
+        drop(session);
+        let guard = DiskGuard::new(DiskPolicy {
+            prune: true,
+            ..DiskPolicy::new(100)
+        });
+        let space = FakeSpace::default();
+        space.set(10);
+        assert!(guard.check(&space, Some(&storage)).unwrap());
+        assert_eq!(guard.status().pruned_sessions, 1);
+    }
+
+    #[test]
+    fn test_prune_keeps_pinned() {
+        devinit!();
+        let dir = TempDir::new("prune_pinned").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        for name in ["a", "b", "c", "d"] {
+            let mut session = storage.create_session(name).unwrap();
+            session.push(&devlog!(Level::Info, "app", "msg")).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        storage.pin_session("a", true).unwrap();
+        storage.pin_session("c", true).unwrap();
+        // 固定した最も古いセッションを飛ばして削除する
+        assert_eq!(prune_oldest(&storage, 1).unwrap(), ["b"]);
+        assert_eq!(prune_oldest(&storage, u64::MAX).unwrap(), ["d"]);
+        assert!(prune_oldest(&storage, u64::MAX).unwrap().is_empty());
+
+        // 固定を外せば削除できる
+        storage.pin_session("c", false).unwrap();
+        assert_eq!(prune_oldest(&storage, u64::MAX).unwrap(), ["c"]);
+        let names = storage
+            .records()
+            .unwrap()
+            .iter()
+            .map(|x| x.name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a"]);
+    }
+
+    #[test]
+    fn test_readyz() {
+        use actix_web::{test, web, App};
+
+        let guard = DiskGuard::new(DiskPolicy::new(100));
+        let space = FakeSpace::default();
+        let mut sys = actix_web::rt::System::new("readyz");
+        sys.block_on(async move {
+            let mut app =
+                test::init_service(App::new().app_data(web::Data::new(guard.clone())).service(
+                    web::resource(super::READYZ_PATH).route(web::get().to(super::readyz)),
+                ))
+                .await;
+            let request = || {
+                test::TestRequest::get()
+                    .uri(super::READYZ_PATH)
+                    .to_request()
+            };
+            space.set(10);
+            guard.check(&space, None).unwrap();
+            let res = test::call_service(&mut app, request()).await;
+            assert_eq!(res.status().as_u16(), 503);
+            let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+            assert!(body.contains(r#""read_only":true"#), "{}", body);
+
+            space.set(1000);
+            guard.check(&space, None).unwrap();
+            let res = test::call_service(&mut app, request()).await;
+            assert_eq!(res.status().as_u16(), 200);
+        });
+    }
+}
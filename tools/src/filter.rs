@@ -0,0 +1,583 @@
+//! レコードを絞り込む式
+//!
+//! ```text
+//! level >= warn && category ~ "net.*" && kv.retries > 3
+//! ```
+//!
+//! - 比較: `==`, `!=`, `<`, `<=`, `>`, `>=`。左辺がフィールド、右辺がリテラル
+//! - カテゴリと同じ`.`区切りのglob: `~`
+//! - 存在確認: `has(kv.retries)`
+//! - 論理演算: `&&`, `||`, `!`, `( )`
+//!
+//! フィールドは`level`, `category`, `message`, `target`, `module_path`, `file`, `line`,
+//! `elapsed` (秒)と`kv.<key>`。`.`以外の記号を含むキーは`kv["key"]`と書く。
+//! 存在しないフィールドとの比較は常に偽になる
+use std::{cmp::Ordering, fmt::Display, str::FromStr};
+
+use uplog::{CategoryPattern, Level, Record, Value};
+
+/// 式の解析エラー。positionは式の先頭からのバイト位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new<S: Into<String>>(position: usize, message: S) -> Self {
+        Self {
+            position,
+            message: message.into(),
+        }
+    }
+
+    /// 式の下に位置を示す行を付けて返す
+    pub fn highlight(&self, source: &str) -> String {
+        let column = source[..self.position.min(source.len())].chars().count();
+        format!("{}\n{}^ {}", source, " ".repeat(column), self.message)
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64, Option<i128>),
+    Op(&'static str),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+const OPERATORS: &[&str] = &["&&", "||", "==", "!=", "<=", ">=", "<", ">", "~", "!"];
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')
+}
+
+fn tokenize(src: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = src.char_indices().peekable();
+    while let Some(&(pos, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let rest = &src[pos..];
+        let token = match c {
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => s.push(c),
+                            None => return Err(ParseError::new(pos, "unterminated string")),
+                        },
+                        Some((_, c)) => s.push(c),
+                        None => return Err(ParseError::new(pos, "unterminated string")),
+                    }
+                }
+                tokens.push((pos, Token::Str(s)));
+                continue;
+            }
+            c if c.is_ascii_digit()
+                || (c == '-' && rest[1..].starts_with(|x: char| x.is_ascii_digit())) =>
+            {
+                let len = rest[1..]
+                    .find(|x: char| !(x.is_ascii_digit() || x == '.'))
+                    .map_or(rest.len(), |x| x + 1);
+                let text = &rest[..len];
+                let num = text
+                    .parse::<f64>()
+                    .map_err(|_| ParseError::new(pos, format!("invalid number {}", text)))?;
+                for _ in 0..text.chars().count() {
+                    chars.next();
+                }
+                tokens.push((pos, Token::Num(num, text.parse::<i128>().ok())));
+                continue;
+            }
+            c if is_ident_start(c) => {
+                let len = rest.find(|x| !is_ident_char(x)).unwrap_or(rest.len());
+                for _ in 0..rest[..len].chars().count() {
+                    chars.next();
+                }
+                tokens.push((pos, Token::Ident(rest[..len].to_string())));
+                continue;
+            }
+            _ => match OPERATORS.iter().find(|x| rest.starts_with(*x)) {
+                Some(op) => {
+                    chars.next();
+                    if op.len() == 2 {
+                        chars.next();
+                    }
+                    tokens.push((pos, Token::Op(op)));
+                    continue;
+                }
+                None => {
+                    return Err(ParseError::new(
+                        pos,
+                        format!("unexpected character '{}'", c),
+                    ))
+                }
+            },
+        };
+        chars.next();
+        tokens.push((pos, token));
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    Level,
+    Category,
+    Message,
+    Target,
+    ModulePath,
+    File,
+    Line,
+    Elapsed,
+    Kv(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Null,
+    Bool(bool),
+    Num(f64, Option<i128>),
+    Str(String),
+    Level(Level),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Has(Field),
+    Cmp(Field, CmpOp, Literal),
+    Match(Field, CategoryPattern),
+}
+
+struct Parser<'a> {
+    tokens: &'a [(usize, Token)],
+    index: usize,
+    end: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.index).map(|x| &x.1)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.index).map_or(self.end, |x| x.0)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let t = self.peek();
+        self.index += 1;
+        t
+    }
+
+    fn expect(&mut self, token: Token, name: &str) -> Result<(), ParseError> {
+        let pos = self.position();
+        match self.next() {
+            Some(t) if *t == token => Ok(()),
+            _ => Err(ParseError::new(pos, format!("expected {}", name))),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.parse_and()?;
+        while self.peek() == Some(&Token::Op("||")) {
+            self.next();
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.parse_unary()?;
+        while self.peek() == Some(&Token::Op("&&")) {
+            self.next();
+            lhs = Expr::And(Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek() {
+            Some(Token::Op("!")) => {
+                self.next();
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some(Token::LParen) => {
+                self.next();
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen, "')'")?;
+                Ok(expr)
+            }
+            Some(Token::Ident(x)) if x == "has" => {
+                self.next();
+                self.expect(Token::LParen, "'(' after has")?;
+                let field = self.parse_field()?;
+                self.expect(Token::RParen, "')'")?;
+                Ok(Expr::Has(field))
+            }
+            _ => self.parse_comparison(),
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, ParseError> {
+        let field = self.parse_field()?;
+        let pos = self.position();
+        let op = match self.next() {
+            Some(Token::Op("~")) => {
+                let pos = self.position();
+                return match self.next() {
+                    Some(Token::Str(x)) => CategoryPattern::new(x)
+                        .map(|p| Expr::Match(field, p))
+                        .map_err(|e| ParseError::new(pos, e.to_string())),
+                    _ => Err(ParseError::new(pos, "expected a pattern string after '~'")),
+                };
+            }
+            Some(Token::Op("==")) => CmpOp::Eq,
+            Some(Token::Op("!=")) => CmpOp::Ne,
+            Some(Token::Op("<")) => CmpOp::Lt,
+            Some(Token::Op("<=")) => CmpOp::Le,
+            Some(Token::Op(">")) => CmpOp::Gt,
+            Some(Token::Op(">=")) => CmpOp::Ge,
+            _ => return Err(ParseError::new(pos, "expected a comparison operator")),
+        };
+        let literal = self.parse_literal()?;
+        Ok(Expr::Cmp(field, op, literal))
+    }
+
+    fn parse_field(&mut self) -> Result<Field, ParseError> {
+        let pos = self.position();
+        let name = match self.next() {
+            Some(Token::Ident(x)) => x,
+            _ => return Err(ParseError::new(pos, "expected a field")),
+        };
+        let field = match name.as_str() {
+            "level" => Field::Level,
+            "category" => Field::Category,
+            "message" => Field::Message,
+            "target" => Field::Target,
+            "module_path" => Field::ModulePath,
+            "file" => Field::File,
+            "line" => Field::Line,
+            "elapsed" => Field::Elapsed,
+            "kv" => {
+                self.expect(Token::LBracket, "'.' or '[' after kv")?;
+                let pos = self.position();
+                let key = match self.next() {
+                    Some(Token::Str(x)) => x.clone(),
+                    _ => return Err(ParseError::new(pos, "expected a key string")),
+                };
+                self.expect(Token::RBracket, "']'")?;
+                Field::Kv(key)
+            }
+            x => match x.strip_prefix("kv.") {
+                Some(key) if !key.is_empty() => Field::Kv(key.to_string()),
+                _ => return Err(ParseError::new(pos, format!("unknown field {}", x))),
+            },
+        };
+        Ok(field)
+    }
+
+    fn parse_literal(&mut self) -> Result<Literal, ParseError> {
+        let pos = self.position();
+        let literal = match self.next() {
+            Some(Token::Str(x)) => Literal::Str(x.clone()),
+            Some(Token::Num(f, i)) => Literal::Num(*f, *i),
+            Some(Token::Ident(x)) => match x.as_str() {
+                "true" => Literal::Bool(true),
+                "false" => Literal::Bool(false),
+                "null" => Literal::Null,
+                x => match parse_level(x) {
+                    Some(l) => Literal::Level(l),
+                    None => {
+                        return Err(ParseError::new(
+                            pos,
+                            format!("unknown identifier {}, strings must be quoted", x),
+                        ))
+                    }
+                },
+            },
+            _ => return Err(ParseError::new(pos, "expected a value")),
+        };
+        Ok(literal)
+    }
+}
+
+pub(crate) fn parse_level(s: &str) -> Option<Level> {
+    match s.to_ascii_lowercase().as_str() {
+        "trace" => Some(Level::Trace),
+        "debug" => Some(Level::Debug),
+        "info" => Some(Level::Info),
+        "warn" => Some(Level::Warn),
+        "error" => Some(Level::Error),
+        _ => None,
+    }
+}
+
+/// 評価時のフィールドの値
+enum Val<'a> {
+    Null,
+    Bool(bool),
+    Num(f64, Option<i128>),
+    Str(&'a str),
+    Level(Level),
+    /// バイト列と配列は存在確認だけできる
+    Other,
+}
+
+impl<'a> Val<'a> {
+    fn from_value(v: &'a Value) -> Self {
+        // 整数は桁を落とさずに比べられるように残す
+        let int = v
+            .as_i64()
+            .map(i128::from)
+            .or_else(|| v.as_u64().map(i128::from));
+        if let Some(x) = int {
+            Val::Num(x as f64, Some(x))
+        } else if let Some(x) = v.as_f64() {
+            Val::Num(x, None)
+        } else if let Some(x) = v.as_str() {
+            Val::Str(x)
+        } else if let Some(x) = v.as_bool() {
+            Val::Bool(x)
+        } else if v.is_null() {
+            Val::Null
+        } else {
+            Val::Other
+        }
+    }
+}
+
+impl Field {
+    fn resolve<'a>(&self, r: &'a Record) -> Option<Val<'a>> {
+        match self {
+            Field::Level => Some(Val::Level(r.level())),
+            Field::Category => Some(Val::Str(&r.category)),
+            Field::Message => Some(Val::Str(&r.message)),
+            Field::Target => Some(Val::Str(r.target())),
+            Field::ModulePath => r.module_path.as_deref().map(Val::Str),
+            Field::File => r.file.as_deref().map(Val::Str),
+            Field::Line => r.line.map(|x| Val::Num(x as f64, Some(x as i128))),
+            Field::Elapsed => Some(Val::Num(r.elapsed.as_secs_f64(), None)),
+            Field::Kv(key) => r.kv.as_ref()?.get(key).map(Val::from_value),
+        }
+    }
+}
+
+/// 型が合わない場合はNone
+fn compare(val: &Val, literal: &Literal) -> Option<Ordering> {
+    match (val, literal) {
+        (Val::Null, Literal::Null) => Some(Ordering::Equal),
+        (Val::Bool(a), Literal::Bool(b)) => Some(a.cmp(b)),
+        (Val::Num(_, Some(a)), Literal::Num(_, Some(b))) => Some(a.cmp(b)),
+        (Val::Num(a, _), Literal::Num(b, _)) => a.partial_cmp(b),
+        (Val::Str(a), Literal::Str(b)) => Some((*a).cmp(b.as_str())),
+        (Val::Level(a), Literal::Level(b)) => Some(a.cmp(b)),
+        (Val::Level(a), Literal::Str(b)) => parse_level(b).map(|b| a.cmp(&b)),
+        (Val::Str(a), Literal::Level(b)) => parse_level(a).map(|a| a.cmp(b)),
+        _ => None,
+    }
+}
+
+impl Expr {
+    fn eval(&self, r: &Record) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(r) && b.eval(r),
+            Expr::Or(a, b) => a.eval(r) || b.eval(r),
+            Expr::Not(a) => !a.eval(r),
+            Expr::Has(f) => f.resolve(r).is_some(),
+            Expr::Match(f, p) => match f.resolve(r) {
+                Some(Val::Str(x)) => p.matches(x),
+                _ => false,
+            },
+            Expr::Cmp(f, op, literal) => {
+                let val = match f.resolve(r) {
+                    Some(x) => x,
+                    None => return false,
+                };
+                match compare(&val, literal) {
+                    Some(o) => match op {
+                        CmpOp::Eq => o == Ordering::Equal,
+                        CmpOp::Ne => o != Ordering::Equal,
+                        CmpOp::Lt => o == Ordering::Less,
+                        CmpOp::Le => o != Ordering::Greater,
+                        CmpOp::Gt => o == Ordering::Greater,
+                        CmpOp::Ge => o != Ordering::Less,
+                    },
+                    None => *op == CmpOp::Ne,
+                }
+            }
+        }
+    }
+}
+
+/// 解析済みの絞り込み条件
+#[derive(Debug, Clone)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+impl Filter {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let tokens = tokenize(source)?;
+        if tokens.is_empty() {
+            return Err(ParseError::new(0, "empty expression"));
+        }
+        let mut parser = Parser {
+            tokens: &tokens,
+            index: 0,
+            end: source.len(),
+        };
+        let expr = parser.parse_or()?;
+        if parser.peek().is_some() {
+            return Err(ParseError::new(parser.position(), "unexpected token"));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            expr,
+        })
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        self.expr.eval(record)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl FromStr for Filter {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use uplog::{devinit, devlog, Level, Record, Value, KV};
+
+    use super::Filter;
+
+    fn fixtures() -> Vec<Record> {
+        devinit!();
+        let mut records = vec![
+            devlog!(Level::Info, "net.eth0.rx", "packet", "retries", 0_u8),
+            devlog!(Level::Warn, "net.eth0.tx", "retry", "retries", 5_u8),
+            devlog!(Level::Error, "net.wlan0.rx", "lost", "retries", -1_i64),
+            devlog!(Level::Debug, "app.ui", "click", "button", "ok"),
+            devlog!(Level::Warn, "app.db", "slow query", "ms", 12.5_f64),
+        ];
+        let mut kv = KV::new();
+        kv.insert(
+            "_ingest.client_ip".to_string(),
+            Value::Text("10.0.0.1".into()),
+        );
+        kv.insert("weird key".to_string(), Value::Bool(true));
+        kv.insert("nothing".to_string(), Value::Null);
+        records[3].kv.as_mut().unwrap().extend(kv);
+        records[4].elapsed = Duration::from_secs(10);
+        records
+    }
+
+    #[test]
+    fn test_filter_table() {
+        let records = fixtures();
+        let cases: &[(&str, &[usize])] = &[
+            ("level >= warn", &[1, 2, 4]),
+            ("level == \"debug\"", &[3]),
+            (r#"category ~ "net.*.rx""#, &[0, 2]),
+            (r#"category ~ "net.**" && kv.retries > 3"#, &[1]),
+            ("level >= warn && category ~ \"net.*\"", &[]),
+            ("kv.retries < 0 || kv.ms >= 12.5", &[2, 4]),
+            ("has(kv.retries)", &[0, 1, 2]),
+            ("!has(kv.retries)", &[3, 4]),
+            // 存在しないフィールドは`!=`でも偽
+            ("kv.retries != 0", &[1, 2]),
+            (r#"kv._ingest.client_ip == "10.0.0.1""#, &[3]),
+            (r#"kv["weird key"] == true && kv.nothing == null"#, &[3]),
+            (r#"message == "slow query" && elapsed > 9.5"#, &[4]),
+            ("!(level == info || level == debug) && line > 0", &[1, 2, 4]),
+            (r#"kv.button == "ok" && kv.button != 1"#, &[3]),
+        ];
+        for (expr, expected) in cases {
+            let filter = Filter::parse(expr).unwrap();
+            let actual = records
+                .iter()
+                .enumerate()
+                .filter(|(_, r)| filter.matches(r))
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            assert_eq!(&actual, expected, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let cases = [
+            ("level >= ", 9),
+            ("level >= warm", 9),
+            ("levl >= warn", 0),
+            ("level >= warn &&", 16),
+            ("(level >= warn", 14),
+            ("category ~ net", 11),
+            (r#"category ~ "a.**b""#, 11),
+            ("message == \"open", 11),
+            ("level >= warn $", 14),
+            ("kv.x == 1 kv.y", 10),
+            ("", 0),
+        ];
+        for (expr, position) in cases {
+            let err = Filter::parse(expr).unwrap_err();
+            assert_eq!(err.position, position, "{}: {}", expr, err);
+        }
+        let err = Filter::parse("levl >= warn").unwrap_err();
+        assert_eq!(
+            err.highlight("levl >= warn"),
+            "levl >= warn\n^ unknown field levl"
+        );
+    }
+}
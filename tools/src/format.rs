@@ -0,0 +1,251 @@
+//! 端末向けのレコードの整形
+//!
+//! readコマンドの`--pretty`で使う。他の表示でも同じ見た目にするため文字列を返す
+use std::{
+    fmt::{Display, Write},
+    io::IsTerminal,
+};
+
+use uplog::{KvStyle, Level, Record, RecordFormatter};
+
+pub(crate) const RESET: &str = "\x1b[0m";
+pub(crate) const BOLD: &str = "\x1b[1m";
+pub(crate) const DIM: &str = "\x1b[2m";
+pub(crate) const CYAN: &str = "\x1b[36m";
+
+/// kvの行の字下げ
+const KV_INDENT: &str = "    ";
+
+/// Options for [`pretty`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrettyOptions {
+    /// use ANSI color codes
+    pub color: bool,
+    /// width of the right-aligned elapsed time
+    pub elapsed_width: usize,
+    /// elapsed style, location, kv layout and message width
+    pub format: RecordFormatter,
+}
+
+impl Default for PrettyOptions {
+    fn default() -> Self {
+        Self {
+            color: true,
+            elapsed_width: 10,
+            format: RecordFormatter::new().kv(KvStyle::Multiline),
+        }
+    }
+}
+
+impl PrettyOptions {
+    /// Colors only when stdout is a terminal and `no_color` is false.
+    pub fn for_stdout(no_color: bool) -> Self {
+        Self {
+            color: !no_color && std::io::stdout().is_terminal(),
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) fn level_style(level: Level) -> &'static str {
+    match level {
+        Level::Trace => "\x1b[90m",
+        Level::Debug => "\x1b[34m",
+        Level::Info => "\x1b[32m",
+        Level::Warn => "\x1b[33m",
+        Level::Error => "\x1b[1;31m",
+    }
+}
+
+pub(crate) fn level_label(level: Level) -> &'static str {
+    match level {
+        Level::Trace => "TRACE",
+        Level::Debug => "DEBUG",
+        Level::Info => "INFO",
+        Level::Warn => "WARN",
+        Level::Error => "ERROR",
+    }
+}
+
+/// 色を使う場合だけ囲む
+fn paint<T: Display>(out: &mut String, color: bool, style: &str, text: T) {
+    if color {
+        write!(out, "{}{}{}", style, text, RESET)
+    } else {
+        write!(out, "{}", text)
+    }
+    .expect("write to string");
+}
+
+/// Renders a record for reading in a terminal.
+///
+/// ```text
+///     1.2340 ERROR [net.eth0] link down  src/net.rs:42
+///     reason  = "timeout"
+///     retries = 3
+/// ```
+pub fn pretty(record: &Record, opts: &PrettyOptions) -> String {
+    let color = opts.color;
+    let format = &opts.format;
+    let mut out = String::new();
+    paint(
+        &mut out,
+        color,
+        DIM,
+        format_args!(
+            "{:>width$}",
+            format.format_elapsed(record.elapsed),
+            width = opts.elapsed_width
+        ),
+    );
+    out.push(' ');
+    paint(
+        &mut out,
+        color,
+        level_style(record.level()),
+        format_args!("{:<5}", level_label(record.level())),
+    );
+    out.push_str(" [");
+    paint(&mut out, color, CYAN, &record.category);
+    out.push_str("] ");
+    let message = format.format_message(&record.message);
+    match record.level() {
+        Level::Error => paint(&mut out, color, BOLD, message),
+        _ => write!(out, "{}", message).expect("write to string"),
+    }
+    if let Some(file) = record.file().filter(|_| format.shows_location()) {
+        out.push_str("  ");
+        match record.line() {
+            Some(line) => paint(&mut out, color, DIM, format_args!("{}:{}", file, line)),
+            None => paint(&mut out, color, DIM, file),
+        }
+    }
+    if let Some(kv) = record.key_values() {
+        match format.kv_style() {
+            KvStyle::Multiline => {
+                let width = kv.keys().map(|x| x.chars().count()).max().unwrap_or(0);
+                for (k, v) in kv.iter() {
+                    out.push('\n');
+                    out.push_str(KV_INDENT);
+                    paint(
+                        &mut out,
+                        color,
+                        CYAN,
+                        format_args!("{:<width$}", k, width = width),
+                    );
+                    write!(out, " = {}", v).expect("write to string");
+                }
+            }
+            KvStyle::Inline => {
+                out.push_str(" {");
+                for (k, v) in kv.iter() {
+                    paint(&mut out, color, CYAN, k);
+                    write!(out, " = {}, ", v).expect("write to string");
+                }
+                out.push('}');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use uplog::{devinit, devlog, ElapsedStyle, Level, RecordFormatter};
+
+    use super::{pretty, PrettyOptions};
+
+    /// 色のエスケープシーケンスを取り除く
+    fn strip_ansi(s: &str) -> String {
+        let mut out = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|x| *x == 'm');
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_pretty() {
+        devinit!();
+        let mut record = devlog!(
+            Level::Error,
+            "net.eth0",
+            "link down",
+            "retries",
+            3_u32,
+            "reason",
+            "timeout"
+        );
+        record.elapsed = Duration::from_millis(1234);
+        record.file = Some("src/net.rs".to_string());
+        record.line = Some(42);
+
+        let plain = pretty(
+            &record,
+            &PrettyOptions {
+                color: false,
+                ..Default::default()
+            },
+        );
+        let expect = [
+            "    1.2340 ERROR [net.eth0] link down  src/net.rs:42",
+            "    reason  = \"timeout\"",
+            "    retries = 3",
+        ]
+        .join("\n");
+        assert_eq!(plain, expect);
+
+        let colored = pretty(&record, &PrettyOptions::default());
+        let expect = [
+            "\x1b[2m    1.2340\x1b[0m \x1b[1;31mERROR\x1b[0m [\x1b[36mnet.eth0\x1b[0m] \x1b[1mlink down\x1b[0m  \x1b[2msrc/net.rs:42\x1b[0m",
+            "    \x1b[36mreason \x1b[0m = \"timeout\"",
+            "    \x1b[36mretries\x1b[0m = 3",
+        ]
+        .join("\n");
+        assert_eq!(colored, expect);
+        assert_eq!(strip_ansi(&colored), plain);
+
+        // kvと位置がない場合は1行
+        let mut record = devlog!(Level::Info, "app", "started");
+        record.elapsed = Duration::from_secs(75);
+        record.file = None;
+        let plain = pretty(
+            &record,
+            &PrettyOptions {
+                color: false,
+                elapsed_width: 8,
+                ..Default::default()
+            },
+        );
+        assert_eq!(plain, " 75.0000 INFO  [app] started");
+    }
+
+    #[test]
+    fn test_pretty_format() {
+        devinit!();
+        let mut record = devlog!(Level::Warn, "net", "link down on eth0", "retries", 3_u32);
+        record.elapsed = Duration::from_millis(4_834_567);
+        record.file = Some("src/net.rs".to_string());
+        record.line = Some(42);
+
+        let opts = PrettyOptions {
+            color: false,
+            elapsed_width: 14,
+            format: RecordFormatter::new()
+                .elapsed(ElapsedStyle::Hms)
+                .location(false)
+                .max_message_width(Some(10)),
+        };
+        assert_eq!(
+            pretty(&record, &opts),
+            "  01:20:34.567 WARN  [net] link down… {retries = 3, }"
+        );
+    }
+}
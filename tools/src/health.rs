@@ -0,0 +1,298 @@
+//! セッションの異常の印と点数
+//!
+//! 一覧で問題のあるセッションを見分けるため、閉じるときに印を付加情報に残す。
+//! レコードは通し番号を持たないので、欠落はクライアントが書いた破棄と切断のレコードと
+//! サーバーが書き直せずに失ったレコード数から判断する
+use std::{io, path::Path};
+
+#[cfg(feature = "web")]
+use async_graphql::{Enum, SimpleObject};
+use serde::{Deserialize, Serialize};
+use uplog::{Level, Record, CLIENT_CATEGORY};
+
+use crate::{lifecycle::is_server_record, RecordIter, SessionMeta};
+
+/// Clock offset beyond which [`SessionHealth::clock_skew_detected`] is set, in milliseconds.
+pub const CLOCK_SKEW_THRESHOLD_MS: i64 = 1000;
+
+/// 印ごとに点数から引く値
+const ERROR_PENALTY: u8 = 10;
+const DECODE_FAILURE_PENALTY: u8 = 20;
+const UNCLEAN_END_PENALTY: u8 = 25;
+const CLOCK_SKEW_PENALTY: u8 = 15;
+const GAP_PENALTY: u8 = 30;
+
+/// Anomaly flags of a session and a score summarizing them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct SessionHealth {
+    /// the client logged a record at the Error level
+    pub has_errors: bool,
+    /// the server failed to decode a message of the client, or the data file has a record
+    /// that cannot be read
+    pub had_decode_failures: bool,
+    /// the session was not closed by the client
+    pub ended_uncleanly: bool,
+    /// the client clock differed by more than [`CLOCK_SKEW_THRESHOLD_MS`]
+    pub clock_skew_detected: bool,
+    /// records are missing: the client dropped records or lost the connection,
+    /// or the server failed to write records
+    pub gaps_detected: bool,
+    /// 100 when no flag is set, lower the more serious the flags are
+    pub score: u8,
+}
+
+impl SessionHealth {
+    pub fn has(&self, flag: HealthFlag) -> bool {
+        match flag {
+            HealthFlag::HasErrors => self.has_errors,
+            HealthFlag::HadDecodeFailures => self.had_decode_failures,
+            HealthFlag::EndedUncleanly => self.ended_uncleanly,
+            HealthFlag::ClockSkewDetected => self.clock_skew_detected,
+            HealthFlag::GapsDetected => self.gaps_detected,
+        }
+    }
+
+    fn penalized_score(&self) -> u8 {
+        [
+            (self.has_errors, ERROR_PENALTY),
+            (self.had_decode_failures, DECODE_FAILURE_PENALTY),
+            (self.ended_uncleanly, UNCLEAN_END_PENALTY),
+            (self.clock_skew_detected, CLOCK_SKEW_PENALTY),
+            (self.gaps_detected, GAP_PENALTY),
+        ]
+        .into_iter()
+        .filter(|(set, _)| *set)
+        .fold(100_u8, |score, (_, penalty)| score.saturating_sub(penalty))
+    }
+}
+
+/// A flag of [`SessionHealth`] to filter sessions by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "web", derive(Enum))]
+pub enum HealthFlag {
+    HasErrors,
+    HadDecodeFailures,
+    EndedUncleanly,
+    ClockSkewDetected,
+    GapsDetected,
+}
+
+impl HealthFlag {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::HasErrors => "has_errors",
+            Self::HadDecodeFailures => "had_decode_failures",
+            Self::EndedUncleanly => "ended_uncleanly",
+            Self::ClockSkewDetected => "clock_skew_detected",
+            Self::GapsDetected => "gaps_detected",
+        }
+    }
+}
+
+impl std::str::FromStr for HealthFlag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Self::HasErrors,
+            Self::HadDecodeFailures,
+            Self::EndedUncleanly,
+            Self::ClockSkewDetected,
+            Self::GapsDetected,
+        ]
+        .into_iter()
+        .find(|x| x.as_str() == s)
+        .ok_or_else(|| format!("unknown health flag {}", s))
+    }
+}
+
+/// レコードを1件ずつ見て印を集める。書き込み中のセッションでも読み直さずに済む
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HealthScan {
+    errors: bool,
+    gaps: bool,
+    unreadable: bool,
+}
+
+impl HealthScan {
+    pub(crate) fn observe(&mut self, record: &Record) {
+        if is_server_record(record) {
+            return;
+        }
+        if record.category == CLIENT_CATEGORY {
+            // 破棄したレコード数と切断はクライアントが送信データの先頭に挟む
+            let dropped = record
+                .kv
+                .as_ref()
+                .and_then(|x| x.get("dropped"))
+                .and_then(|x| x.as_u64())
+                .is_some_and(|x| x > 0);
+            self.gaps |= dropped || record.message == "disconnected";
+            return;
+        }
+        self.errors |= record.level() == Level::Error;
+    }
+
+    /// 付加情報と合わせて印を決める。`live`は書き込み中で終了の理由がまだないこと
+    pub(crate) fn finish(&self, meta: &SessionMeta, live: bool) -> SessionHealth {
+        let ended_uncleanly = match meta.end_reason.as_deref() {
+            Some(reason) => !reason.starts_with("client"),
+            None => !live,
+        };
+        let mut health = SessionHealth {
+            has_errors: self.errors,
+            had_decode_failures: self.unreadable || meta.decode_failures > 0,
+            ended_uncleanly,
+            clock_skew_detected: meta
+                .clock_offset_ms
+                .is_some_and(|x| x.abs() > CLOCK_SKEW_THRESHOLD_MS),
+            gaps_detected: self.gaps || meta.lost_records > 0,
+            score: 0,
+        };
+        health.score = health.penalized_score();
+        health
+    }
+}
+
+/// セッションのレコードを全て読んで印を集める。読めないレコードがあればそこで止める
+pub(crate) fn scan<P: AsRef<Path>>(session_dir: P) -> io::Result<HealthScan> {
+    let mut scan = HealthScan::default();
+    for record in RecordIter::new(session_dir)? {
+        match record {
+            Ok(record) => scan.observe(&record),
+            Err(_) => {
+                scan.unreadable = true;
+                break;
+            }
+        }
+    }
+    Ok(scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use uplog::{devlog, Level, Record};
+
+    use super::{HealthFlag, HealthScan, SessionHealth, CLOCK_SKEW_THRESHOLD_MS};
+    use crate::SessionMeta;
+
+    fn closed_by_client() -> SessionMeta {
+        SessionMeta {
+            end_reason: Some("client".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn dropped(n: u64) -> Record {
+        devlog!(Level::Warn, uplog::CLIENT_CATEGORY, "dropped", "dropped", n)
+    }
+
+    fn assess(records: &[Record], meta: &SessionMeta) -> SessionHealth {
+        let mut scan = HealthScan::default();
+        for r in records {
+            scan.observe(r);
+        }
+        scan.finish(meta, false)
+    }
+
+    #[test]
+    fn test_healthy() {
+        let records = [devlog!(Level::Info, "app", "ok"), dropped(0)];
+        let health = assess(&records, &closed_by_client());
+        assert_eq!(
+            health,
+            SessionHealth {
+                has_errors: false,
+                had_decode_failures: false,
+                ended_uncleanly: false,
+                clock_skew_detected: false,
+                gaps_detected: false,
+                score: 100,
+            }
+        );
+        // 書き込み中は終了の理由がなくても異常にしない
+        let health = HealthScan::default().finish(&SessionMeta::default(), true);
+        assert!(!health.ended_uncleanly);
+    }
+
+    #[test]
+    fn test_flags() {
+        let meta = closed_by_client();
+        let health = assess(&[devlog!(Level::Error, "app", "failed")], &meta);
+        assert!(health.has(HealthFlag::HasErrors));
+        assert_eq!(health.score, 90);
+
+        // クライアントのカテゴリのWarn以上は数えない
+        let health = assess(&[dropped(3)], &meta);
+        assert!(!health.has_errors);
+        assert!(health.has(HealthFlag::GapsDetected));
+        let health = assess(
+            &[devlog!(Level::Warn, uplog::CLIENT_CATEGORY, "disconnected")],
+            &meta,
+        );
+        assert!(health.gaps_detected);
+        let health = assess(
+            &[],
+            &SessionMeta {
+                lost_records: 2,
+                ..closed_by_client()
+            },
+        );
+        assert!(health.gaps_detected);
+
+        let health = assess(
+            &[],
+            &SessionMeta {
+                decode_failures: 1,
+                ..closed_by_client()
+            },
+        );
+        assert!(health.has(HealthFlag::HadDecodeFailures));
+
+        for reason in [None, Some("idle_timeout"), Some("shutdown")] {
+            let meta = SessionMeta {
+                end_reason: reason.map(String::from),
+                ..Default::default()
+            };
+            assert!(
+                assess(&[], &meta).has(HealthFlag::EndedUncleanly),
+                "{:?}",
+                reason
+            );
+        }
+        let meta = SessionMeta {
+            end_reason: Some("client:flush".to_string()),
+            ..Default::default()
+        };
+        assert!(!assess(&[], &meta).ended_uncleanly);
+
+        for (offset, skewed) in [
+            (CLOCK_SKEW_THRESHOLD_MS, false),
+            (-CLOCK_SKEW_THRESHOLD_MS - 1, true),
+        ] {
+            let meta = SessionMeta {
+                clock_offset_ms: Some(offset),
+                ..closed_by_client()
+            };
+            assert_eq!(
+                assess(&[], &meta).has(HealthFlag::ClockSkewDetected),
+                skewed
+            );
+        }
+    }
+
+    #[test]
+    fn test_score() {
+        let meta = SessionMeta {
+            clock_offset_ms: Some(60_000),
+            decode_failures: 4,
+            lost_records: 1,
+            ..Default::default()
+        };
+        let health = assess(&[devlog!(Level::Error, "app", "failed")], &meta);
+        assert_eq!(health.score, 0);
+        assert_eq!("gaps_detected".parse(), Ok(HealthFlag::GapsDetected));
+        assert!("gaps".parse::<HealthFlag>().is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! 受信したレコードを保存する前の加工
+//!
+//! 古いクライアントのデータでも後から受信時の状況がわかるように
+//! サーバー側の情報をkvに書き込む
+use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use uplog::{Record, Value, KV_TRUNCATED_KEY};
+use uuid::Uuid;
+
+/// サーバーが書き込むkvのキーのprefix
+pub const INGEST_PREFIX: &str = "_ingest.";
+
+/// Information about the connection a record arrived on.
+#[derive(Debug, Clone)]
+pub struct IngestContext<'a> {
+    pub connection_id: Uuid,
+    /// `ip:port` or `ip` of the client
+    pub remote_addr: &'a str,
+    pub received_at: DateTime<Utc>,
+}
+
+/// Modifies a decoded record before it is persisted.
+///
+/// Implement this to register your own transform to an [`IngestPipeline`].
+pub trait RecordTransform: Send + Sync + Debug {
+    fn transform(&self, record: &mut Record, ctx: &IngestContext);
+}
+
+/// `_ingest.`を付けてkvに書き込む
+pub fn set_ingest_value(record: &mut Record, key: &str, value: Value) {
+    record
+        .kv
+        .get_or_insert_with(Default::default)
+        .insert(format!("{}{}", INGEST_PREFIX, key), value);
+}
+
+/// サーバーで受信した時刻 (RFC3339)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AddReceiveTime;
+
+impl RecordTransform for AddReceiveTime {
+    fn transform(&self, record: &mut Record, ctx: &IngestContext) {
+        let time = ctx.received_at.to_rfc3339_opts(SecondsFormat::Micros, true);
+        set_ingest_value(record, "received_at", Value::Text(time));
+    }
+}
+
+/// クライアントのIPアドレス
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AddClientIp;
+
+impl RecordTransform for AddClientIp {
+    fn transform(&self, record: &mut Record, ctx: &IngestContext) {
+        let ip = ctx
+            .remote_addr
+            .parse::<SocketAddr>()
+            .map(|x| x.ip().to_string())
+            .unwrap_or_else(|_| ctx.remote_addr.to_string());
+        set_ingest_value(record, "client_ip", Value::Text(ip));
+    }
+}
+
+/// 接続ごとのuuid。セッション名と同じ
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AddConnectionId;
+
+impl RecordTransform for AddConnectionId {
+    fn transform(&self, record: &mut Record, ctx: &IngestContext) {
+        set_ingest_value(
+            record,
+            "connection_id",
+            Value::Text(ctx.connection_id.to_string()),
+        );
+    }
+}
+
+/// kvをキーの順に最大の数だけ残し、除いた数を`_kv_truncated`に書き込む
+///
+/// クライアントが既に除いていればその数に足す。`_kv_truncated`は数に含めない
+#[derive(Debug, Clone, Copy)]
+pub struct LimitKvEntries(pub usize);
+
+impl RecordTransform for LimitKvEntries {
+    fn transform(&self, record: &mut Record, _ctx: &IngestContext) {
+        let kv = match record.kv.as_mut() {
+            Some(x) => x,
+            None => return,
+        };
+        let len = kv.len() - usize::from(kv.contains_key(KV_TRUNCATED_KEY));
+        if len <= self.0 {
+            return;
+        }
+        let prev = kv
+            .remove(KV_TRUNCATED_KEY)
+            .and_then(|x| x.as_u64())
+            .unwrap_or(0);
+        let mut i = 0;
+        kv.retain(|_, _| {
+            i += 1;
+            i <= self.0
+        });
+        let dropped = prev + (len - self.0) as u64;
+        kv.insert(KV_TRUNCATED_KEY.to_string(), Value::U64(dropped));
+    }
+}
+
+/// 登録順に適用する加工の一覧
+#[derive(Debug, Clone, Default)]
+pub struct IngestPipeline {
+    transforms: Vec<Arc<dyn RecordTransform>>,
+}
+
+impl IngestPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 末尾に追加する
+    pub fn with<T: RecordTransform + 'static>(mut self, transform: T) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    pub fn apply(&self, record: &mut Record, ctx: &IngestContext) {
+        for t in self.transforms.iter() {
+            t.transform(record, ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use uplog::{devinit, devlog, Level, Record, Value};
+    use uuid::Uuid;
+
+    use super::{
+        set_ingest_value, AddClientIp, AddConnectionId, AddReceiveTime, IngestContext,
+        IngestPipeline, LimitKvEntries, RecordTransform,
+    };
+
+    /// 前の加工の結果を読んで上書きする
+    #[derive(Debug)]
+    struct Append(&'static str);
+
+    impl RecordTransform for Append {
+        fn transform(&self, record: &mut Record, _ctx: &IngestContext) {
+            let prev = match record.kv.as_ref().and_then(|x| x.get("_ingest.order")) {
+                Some(Value::Text(x)) => x.clone(),
+                _ => String::new(),
+            };
+            set_ingest_value(record, "order", Value::Text(prev + self.0));
+        }
+    }
+
+    #[test]
+    fn test_ingest_pipeline() {
+        devinit!();
+        let id = Uuid::new_v4();
+        let ctx = IngestContext {
+            connection_id: id,
+            remote_addr: "192.168.0.10:54321",
+            received_at: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+        };
+        let pipeline = IngestPipeline::new()
+            .with(AddReceiveTime)
+            .with(AddClientIp)
+            .with(AddConnectionId)
+            .with(Append("a"))
+            .with(Append("b"));
+
+        let mut record = devlog!(Level::Info, "cat", "msg", "n", 1_u8);
+        pipeline.apply(&mut record, &ctx);
+        let kv = record.kv.unwrap();
+        assert_eq!(
+            kv["_ingest.received_at"],
+            Value::Text("2020-09-13T12:26:40.000000Z".to_string())
+        );
+        assert_eq!(
+            kv["_ingest.client_ip"],
+            Value::Text("192.168.0.10".to_string())
+        );
+        assert_eq!(kv["_ingest.connection_id"], Value::Text(id.to_string()));
+        assert_eq!(kv["_ingest.order"], Value::Text("ab".to_string()));
+        // 元のkvは残す
+        assert_eq!(kv["n"], Value::U64(1));
+
+        // kvのないレコードにも書き込む
+        let mut record = devlog!(Level::Info, "cat", "msg");
+        IngestPipeline::new().with(AddClientIp).apply(
+            &mut record,
+            &IngestContext {
+                remote_addr: "unknown",
+                ..ctx
+            },
+        );
+        assert_eq!(
+            record.kv.unwrap()["_ingest.client_ip"],
+            Value::Text("unknown".to_string())
+        );
+    }
+
+    #[test]
+    fn test_limit_kv_entries() {
+        devinit!();
+        let ctx = IngestContext {
+            connection_id: Uuid::new_v4(),
+            remote_addr: "127.0.0.1",
+            received_at: Utc::now(),
+        };
+        let pipeline = IngestPipeline::new().with(LimitKvEntries(2));
+
+        let mut record = devlog!(Level::Info, "cat", "msg", "c", 3_u8, "a", 1_u8, "b", 2_u8);
+        pipeline.apply(&mut record, &ctx);
+        let kv = record.kv.unwrap();
+        assert_eq!(
+            kv.keys().map(String::as_str).collect::<Vec<_>>(),
+            ["_kv_truncated", "a", "b"]
+        );
+        assert_eq!(kv["_kv_truncated"], Value::U64(1));
+
+        // クライアントが除いた数に足す
+        let mut record = devlog!(
+            Level::Info,
+            "cat",
+            "msg",
+            "_kv_truncated",
+            5_u64,
+            "a",
+            1_u8,
+            "b",
+            2_u8,
+            "c",
+            3_u8
+        );
+        pipeline.apply(&mut record, &ctx);
+        let kv = record.kv.unwrap();
+        assert_eq!(kv.len(), 3);
+        assert_eq!(kv["_kv_truncated"], Value::U64(6));
+
+        // 上限以内なら変えない
+        let mut record = devlog!(Level::Info, "cat", "msg", "_kv_truncated", 5_u64, "a", 1_u8);
+        pipeline.apply(&mut record, &ctx);
+        assert_eq!(record.kv.unwrap()["_kv_truncated"], Value::U64(5));
+    }
+}
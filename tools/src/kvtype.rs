@@ -0,0 +1,277 @@
+//! kvのキーごとの値の型
+//!
+//! kvの値はレコードごとに型を持つので、同じキーでも型が変わることがある。
+//! エクスポートで列の型を決めるため、キーごとに見た型とその数を数える。
+//! 集計と一緒に数え、閉じたセッションは付加情報に残す
+use std::collections::BTreeMap;
+
+#[cfg(feature = "web")]
+use async_graphql::{Enum, SimpleObject};
+use serde::{Deserialize, Serialize};
+use uplog::{Record, Value};
+
+/// Type of a kv value, one per variant of [`uplog::Value`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "web", derive(Enum))]
+#[serde(rename_all = "snake_case")]
+pub enum KvType {
+    Null,
+    Bool,
+    I64,
+    U64,
+    F32,
+    F64,
+    Text,
+    Bytes,
+    Array,
+    Map,
+}
+
+impl KvType {
+    pub fn of(value: &Value) -> Self {
+        match value {
+            Value::Null => Self::Null,
+            Value::Bool(_) => Self::Bool,
+            Value::I64(_) => Self::I64,
+            Value::U64(_) => Self::U64,
+            Value::F32(_) => Self::F32,
+            Value::F64(_) => Self::F64,
+            Value::Text(_) => Self::Text,
+            Value::Bytes(_) => Self::Bytes,
+            Value::Array(_) => Self::Array,
+            Value::Map(_) => Self::Map,
+        }
+    }
+
+    /// 同じ列に入れられる型のまとまり
+    fn group(self) -> Option<ColumnGroup> {
+        Some(match self {
+            Self::Null => return None,
+            Self::Bool => ColumnGroup::Bool,
+            Self::I64 | Self::U64 | Self::F32 | Self::F64 => ColumnGroup::Number,
+            Self::Text => ColumnGroup::Text,
+            Self::Bytes | Self::Array | Self::Map => ColumnGroup::Json,
+        })
+    }
+}
+
+/// 同じ数のときは前にあるものを選ぶ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ColumnGroup {
+    Number,
+    Text,
+    Bool,
+    Json,
+}
+
+/// Type of an exported kv column, chosen by [`KvTypeRegistry::column`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "web", derive(Enum))]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnType {
+    U64,
+    I64,
+    /// also holds the integer values of the key
+    F64,
+    Bool,
+    Text,
+    /// bytes, arrays and maps as JSON
+    Json,
+}
+
+impl ColumnType {
+    /// The cell of `value` in this column: empty for null, `None` when the value does not fit.
+    pub fn cell(self, value: &Value) -> Option<String> {
+        Some(match (self, value) {
+            (_, Value::Null) => String::new(),
+            (Self::U64, Value::U64(x)) => x.to_string(),
+            (Self::I64, Value::I64(x)) => x.to_string(),
+            (Self::I64, Value::U64(x)) => i64::try_from(*x).ok()?.to_string(),
+            (Self::F64, Value::F64(x)) => x.to_string(),
+            (Self::F64, Value::F32(x)) => (*x as f64).to_string(),
+            (Self::F64, Value::U64(x)) => (*x as f64).to_string(),
+            (Self::F64, Value::I64(x)) => (*x as f64).to_string(),
+            (Self::Bool, Value::Bool(x)) => x.to_string(),
+            (Self::Text, Value::Text(x)) => x.clone(),
+            (Self::Json, Value::Bytes(_) | Value::Array(_) | Value::Map(_)) => {
+                serde_json::to_string(value).ok()?
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// Count of the values of a type seen for a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct KvTypeCount {
+    pub kind: KvType,
+    pub count: u64,
+}
+
+/// Types seen for a kv key and the column type chosen for them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct KvKey {
+    pub key: String,
+    pub types: Vec<KvTypeCount>,
+    pub column: ColumnType,
+}
+
+/// Types of the values of each kv key of a session, with the number of values of each type.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct KvTypeRegistry(BTreeMap<String, BTreeMap<KvType, u64>>);
+
+impl KvTypeRegistry {
+    pub fn observe(&mut self, record: &Record) {
+        for (key, value) in record.kv.iter().flatten() {
+            *self
+                .0
+                .entry(key.clone())
+                .or_default()
+                .entry(KvType::of(value))
+                .or_default() += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The column type of `key`.
+    ///
+    /// Numbers share a column, as f64 when any of them is a float. When a key has values of
+    /// several kinds, e.g. numbers and text, the kind with the most values is chosen and the
+    /// others do not fit. A key with only null values is text.
+    pub fn column(&self, key: &str) -> ColumnType {
+        let Some(types) = self.0.get(key) else {
+            return ColumnType::Text;
+        };
+        let mut groups = BTreeMap::<ColumnGroup, u64>::new();
+        for (kind, count) in types.iter() {
+            if let Some(group) = kind.group() {
+                *groups.entry(group).or_default() += count;
+            }
+        }
+        let group = groups
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(group, _)| *group);
+        let has = |kind| types.contains_key(&kind);
+        match group {
+            Some(ColumnGroup::Number) if has(KvType::F32) || has(KvType::F64) => ColumnType::F64,
+            Some(ColumnGroup::Number) if has(KvType::I64) => ColumnType::I64,
+            Some(ColumnGroup::Number) => ColumnType::U64,
+            Some(ColumnGroup::Bool) => ColumnType::Bool,
+            Some(ColumnGroup::Json) => ColumnType::Json,
+            Some(ColumnGroup::Text) | None => ColumnType::Text,
+        }
+    }
+
+    /// Every key in order, with its column type.
+    pub fn columns(&self) -> Vec<(String, ColumnType)> {
+        self.0
+            .keys()
+            .map(|key| (key.clone(), self.column(key)))
+            .collect()
+    }
+
+    pub fn keys(&self) -> Vec<KvKey> {
+        self.0
+            .iter()
+            .map(|(key, types)| KvKey {
+                key: key.clone(),
+                types: types
+                    .iter()
+                    .map(|(kind, count)| KvTypeCount {
+                        kind: *kind,
+                        count: *count,
+                    })
+                    .collect(),
+                column: self.column(key),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uplog::{devlog, Level, Record, Value};
+
+    use super::{ColumnType, KvType, KvTypeRegistry};
+
+    fn registry(values: &[(&str, Value)]) -> KvTypeRegistry {
+        let mut registry = KvTypeRegistry::default();
+        for (key, value) in values {
+            let mut r: Record = devlog!(Level::Info, "app", "m");
+            r.kv = Some([(key.to_string(), value.clone())].into_iter().collect());
+            registry.observe(&r);
+        }
+        registry
+    }
+
+    #[test]
+    fn test_column_type() {
+        let r = registry(&[
+            ("count", Value::U64(1)),
+            ("count", Value::U64(2)),
+            ("ratio", Value::U64(1)),
+            ("ratio", Value::F64(0.5)),
+            ("delta", Value::I64(-1)),
+            ("delta", Value::U64(3)),
+            ("mixed", Value::U64(1)),
+            ("mixed", Value::Text("a".into())),
+            ("mixed", Value::Text("b".into())),
+            ("tie", Value::U64(1)),
+            ("tie", Value::Text("a".into())),
+            ("flag", Value::Bool(true)),
+            ("flag", Value::Null),
+            ("list", Value::Array(vec![Value::U64(1)])),
+            ("empty", Value::Null),
+        ]);
+        assert_eq!(r.column("count"), ColumnType::U64);
+        assert_eq!(r.column("ratio"), ColumnType::F64);
+        assert_eq!(r.column("delta"), ColumnType::I64);
+        assert_eq!(r.column("mixed"), ColumnType::Text);
+        assert_eq!(r.column("tie"), ColumnType::U64);
+        assert_eq!(r.column("flag"), ColumnType::Bool);
+        assert_eq!(r.column("list"), ColumnType::Json);
+        assert_eq!(r.column("empty"), ColumnType::Text);
+        assert_eq!(r.column("missing"), ColumnType::Text);
+
+        let keys = r.keys();
+        let mixed = keys.iter().find(|x| x.key == "mixed").unwrap();
+        assert_eq!(mixed.types.len(), 2);
+        assert_eq!(mixed.types[1].kind, KvType::Text);
+        assert_eq!(mixed.types[1].count, 2);
+    }
+
+    #[test]
+    fn test_cell() {
+        assert_eq!(ColumnType::F64.cell(&Value::U64(3)).as_deref(), Some("3"));
+        assert_eq!(
+            ColumnType::F64.cell(&Value::F64(0.5)).as_deref(),
+            Some("0.5")
+        );
+        assert_eq!(ColumnType::I64.cell(&Value::U64(7)).as_deref(), Some("7"));
+        assert_eq!(ColumnType::I64.cell(&Value::U64(u64::MAX)), None);
+        assert_eq!(ColumnType::U64.cell(&Value::Text("x".into())), None);
+        assert_eq!(ColumnType::U64.cell(&Value::Null).as_deref(), Some(""));
+        assert_eq!(
+            ColumnType::Json
+                .cell(&Value::Array(vec![Value::U64(1)]))
+                .as_deref(),
+            Some("[1]")
+        );
+        assert_eq!(ColumnType::Text.cell(&Value::U64(1)), None);
+    }
+
+    #[test]
+    fn test_registry_serde() {
+        let r = registry(&[("a", Value::U64(1)), ("a", Value::Text("x".into()))]);
+        let json = serde_json::to_string(&r).unwrap();
+        assert_eq!(json, r#"{"a":{"u64":1,"text":1}}"#);
+        assert_eq!(serde_json::from_str::<KvTypeRegistry>(&json).unwrap(), r);
+    }
+}
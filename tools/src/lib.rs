@@ -1,90 +1,317 @@
+//! Storage and server of uplog.
+//!
+//! The bundled binary serves [`webapi`] and [`actor`] with actix-web, but the storage layer can be
+//! embedded in another service without either of them:
+//!
+//! - [`Storage`] lists sessions as [`SessionInfo`] and creates a [`Session`] to write to
+//! - [`open_reader`] returns a [`StorageReader`] that reads a page of [`LogRecord`]
+//! - [`Storage::session_records`] iterates all records of a session
+//! - [`Filter`] and [`QueryCache`] are the search and cache used by the GraphQL API
+//!
+//! The server, the GraphQL API and the command line tool are behind the default `web` feature.
+//! [`server::run_server`] starts the same server as the binary inside another process.
+//! Build with `default-features = false` to read and analyse data files through [`analysis`]
+//! without actix and async-graphql.
+//!
+//! ```
+//! use uplog_tools::{open_reader, Storage, StorageReader};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! # let dir = tempdir::TempDir::new("doc")?;
+//! let storage = Storage::new_shared(dir.path())?;
+//! for info in storage.records()? {
+//!     let page = open_reader(&info)?.read_at(0, 100)?;
+//!     println!("{} {} records", info.name(), page.len());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+#[cfg(feature = "web")]
+pub mod acl;
+#[cfg(feature = "web")]
 pub mod actor;
-mod reader;
+pub mod analysis;
+pub mod anonymize;
+pub mod archive;
+pub mod attachment;
+pub mod audit;
+pub mod blob;
+pub mod cache;
+pub mod cat;
+#[cfg(feature = "web")]
+pub mod config;
+#[cfg(feature = "encryption")]
+pub mod crypt;
+pub mod decode;
+#[cfg(feature = "web")]
+pub mod diskwatch;
+pub mod filter;
+pub mod format;
+pub mod health;
+#[cfg(feature = "web")]
+pub mod ingest;
+pub mod kvtype;
+pub mod lifecycle;
+pub mod listing;
+mod lock;
+pub mod logfile;
+pub mod meta;
+pub mod overview;
+mod path;
+pub mod reader;
+pub mod registry;
+#[cfg(feature = "web")]
+pub mod replay;
+#[cfg(feature = "web")]
+pub mod retry;
+pub mod scan;
+#[cfg(feature = "web")]
+pub mod server;
+pub mod stats;
+pub mod tenant;
+pub mod testing;
+#[cfg(feature = "tui")]
+pub mod tui;
+#[cfg(all(unix, feature = "web"))]
+pub mod uds;
+pub mod verify;
+pub mod view;
+#[cfg(feature = "web")]
 pub mod webapi;
-mod writer;
+pub mod writer;
 
 use std::{
+    collections::HashMap,
     fmt::Display,
-    fs::{File, OpenOptions},
+    fs::File,
     io,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
+#[cfg(feature = "web")]
 use async_graphql::{scalar, Enum, Object};
 use chrono::{DateTime, Utc};
-use log::debug;
-use serde::{Deserialize, Serialize};
-use uplog::{Level, Record, KV};
+use log::{debug, error, warn};
+#[cfg(feature = "web")]
+use serde::Deserialize;
+use serde::Serialize;
+#[cfg(feature = "web")]
+use uplog::Level;
+use uplog::Record;
+use uplog::KV;
 
-#[derive(Debug, Serialize)]
+pub use audit::AuditEntry;
+pub use blob::BlobStore;
+pub use cache::{QueryCache, QueryCacheStats};
+pub use filter::Filter;
+pub use health::{HealthFlag, SessionHealth};
+pub use listing::{SessionPage, SessionQuery, SessionSortKey, SortOrder};
+pub use lock::LOCK_FILENAME;
+pub use meta::{BuildInfo, EncryptionInfo, SessionMeta};
+pub use overview::{Histogram, Overview};
+pub use path::resolve_data_dir;
+pub use reader::{
+    open_reader, CBORSequenceReader, Cursor, Deadline, LazyKv, OnError, PageRecord, PartialRecord,
+    ReadOptions, ReadPage, RecordError, RecordIter, ScanTimeout, StorageReader,
+};
+pub use registry::{RegistryGauges, SessionRegistry};
+#[cfg(feature = "web")]
+pub use retry::{RetryPolicy, RetryStats};
+pub use view::{RecordTime, RecordView};
+pub use writer::RecordWriter;
+
+/// Version of the session directory layout written by [`Storage`].
+pub const STORAGE_FORMAT_VERSION: u32 = 1;
+
+/// A record with its position in the session.
+#[derive(Debug, Clone)]
 pub struct LogRecord {
+    /// データファイルの先頭からの番号。絞り込みに関わらず同じレコードは同じ値
     id: usize,
+    /// 1回の読み出しの結果の中での位置
+    matched_index: usize,
     record: Record,
+    /// セッションの開始時刻がわかる場合の時刻
+    time: Option<RecordTime>,
+    /// 読み飛ばしたkv
+    lazy_kv: Option<LazyKv>,
+}
+
+/// 読み飛ばしたkvも含めて書き出す
+impl Serialize for LogRecord {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Ser<'a> {
+            id: usize,
+            matched_index: usize,
+            record: std::borrow::Cow<'a, Record>,
+            #[serde(flatten, skip_serializing_if = "Option::is_none")]
+            time: Option<RecordTime>,
+        }
+        let record = match self.lazy_kv {
+            Some(ref lazy) => std::borrow::Cow::Owned(Record {
+                kv: lazy.get().cloned(),
+                ..self.record.clone()
+            }),
+            None => std::borrow::Cow::Borrowed(&self.record),
+        };
+        Ser {
+            id: self.id,
+            matched_index: self.matched_index,
+            record,
+            time: self.time,
+        }
+        .serialize(serializer)
+    }
 }
 
 impl LogRecord {
     pub fn new(id: usize, record: Record) -> Self {
-        Self { id, record }
+        Self {
+            id,
+            matched_index: 0,
+            record,
+            time: None,
+            lazy_kv: None,
+        }
+    }
+
+    /// セッションの開始時刻からレコードの時刻を求める
+    pub fn with_session_start(mut self, session_start: DateTime<Utc>) -> Self {
+        self.time = Some(RecordView::new(&self.record, session_start).time());
+        self
+    }
+
+    /// Wall-clock time, known when read through [`open_reader`].
+    pub fn time(&self) -> Option<RecordTime> {
+        self.time
+    }
+
+    /// 絞り込んだ結果の中での位置を設定する
+    pub fn with_matched_index(mut self, matched_index: usize) -> Self {
+        self.matched_index = matched_index;
+        self
+    }
+
+    pub fn matched_index(&self) -> usize {
+        self.matched_index
+    }
+
+    /// セッションの先頭からの番号
+    pub fn index(&self) -> usize {
+        self.id
+    }
+
+    /// The record. When read with [`ReadOptions::lazy_kv`] its key-values are only the ones
+    /// whose keys start with `_`, see [`kv`](Self::kv) for all of them.
+    pub fn as_record(&self) -> &Record {
+        &self.record
+    }
+
+    /// The record with its key-values, decoding them if they were skipped.
+    pub fn into_record(mut self) -> Record {
+        if let Some(lazy) = self.lazy_kv.take() {
+            self.record.kv = lazy.get().cloned();
+        }
+        self.record
+    }
+
+    /// Key-values of the record, decoded on first use when they were skipped.
+    pub fn kv(&self) -> Option<&KV> {
+        match self.lazy_kv {
+            Some(ref lazy) => lazy.get(),
+            None => self.record.kv.as_ref(),
+        }
+    }
+
+    /// 読み飛ばしたkvを持たせる
+    pub(crate) fn with_lazy_kv(mut self, lazy_kv: Option<LazyKv>) -> Self {
+        self.lazy_kv = lazy_kv;
+        self
     }
 }
 
+#[cfg(feature = "web")]
 #[Object]
 impl LogRecord {
+    /// same as `absoluteId`
     async fn id(&self) -> usize {
         self.id
     }
+    /// index of the record in the session file, stable across filters and pages
+    async fn absolute_id(&self) -> usize {
+        self.id
+    }
+    /// index of the record in the result of this query
+    #[graphql(name = "matchedIndex")]
+    async fn graphql_matched_index(&self) -> usize {
+        self.matched_index
+    }
     async fn record<'a>(&'a self) -> RecordObject<'a> {
-        RecordObject(&self.record)
+        RecordObject(self)
+    }
+    /// wall-clock time of the record
+    async fn timestamp(&self) -> Option<webapi::DateTimeScalar> {
+        self.time.map(|x| webapi::DateTimeScalar(x.timestamp))
+    }
+    /// true when `timestamp` was computed from the session start and `elapsed`,
+    /// for records stored without a receive time
+    async fn timestamp_synthetic(&self) -> Option<bool> {
+        self.time.map(|x| x.timestamp_synthetic)
     }
 }
 
-struct RecordObject<'record>(&'record Record);
+/// 読み飛ばしたkvを`kv`で復号するのでレコードの位置ごと持つ
+#[cfg(feature = "web")]
+struct RecordObject<'record>(&'record LogRecord);
 
+#[cfg(feature = "web")]
 #[Object]
 impl<'record> RecordObject<'record> {
     async fn level(&self) -> LogLevel {
-        self.0.metadata.level().into()
+        self.0.record.metadata.level().into()
     }
     async fn elapsed(&self) -> DurationScalar {
-        DurationScalar(self.0.elapsed.as_secs_f64())
+        DurationScalar(self.0.record.elapsed.as_secs_f64())
     }
     async fn category(&self) -> &str {
-        &self.0.category
+        &self.0.record.category
     }
     async fn message(&self) -> &str {
-        &self.0.message
+        &self.0.record.message
     }
     async fn module_path(&self) -> Option<&str> {
-        if let Some(ref x) = self.0.module_path {
+        if let Some(ref x) = self.0.record.module_path {
             Some(x)
         } else {
             None
         }
     }
     async fn file(&self) -> Option<&str> {
-        if let Some(ref x) = self.0.file {
+        if let Some(ref x) = self.0.record.file {
             Some(x)
         } else {
             None
         }
     }
     async fn line(&self) -> Option<&u32> {
-        if let Some(ref x) = self.0.line {
+        if let Some(ref x) = self.0.record.line {
             Some(x)
         } else {
             None
         }
     }
     async fn kv(&self) -> Option<KeyValue<'record>> {
-        if let Some(ref kv) = self.0.kv {
-            return Some(KeyValue(kv));
-        }
-        None
+        self.0.kv().map(KeyValue)
     }
 }
 
-#[derive(Enum, Copy, Clone, Eq, PartialEq)]
-enum LogLevel {
+/// GraphQLのレベル。順序は[`Level`]に従う
+#[cfg(feature = "web")]
+#[derive(Enum, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub(crate) enum LogLevel {
     Trace,
     Debug,
     Info,
@@ -92,6 +319,7 @@ enum LogLevel {
     Error,
 }
 
+#[cfg(feature = "web")]
 impl From<Level> for LogLevel {
     fn from(x: Level) -> Self {
         match x {
@@ -104,8 +332,37 @@ impl From<Level> for LogLevel {
     }
 }
 
+#[cfg(feature = "web")]
+impl From<LogLevel> for Level {
+    fn from(x: LogLevel) -> Self {
+        match x {
+            LogLevel::Trace => Self::Trace,
+            LogLevel::Debug => Self::Debug,
+            LogLevel::Info => Self::Info,
+            LogLevel::Warn => Self::Warn,
+            LogLevel::Error => Self::Error,
+        }
+    }
+}
+
+#[cfg(feature = "web")]
+impl Ord for LogLevel {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        Level::from(*self).cmp(&Level::from(*other))
+    }
+}
+
+#[cfg(feature = "web")]
+impl PartialOrd for LogLevel {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(feature = "web")]
 struct KeyValue<'record>(&'record KV);
 
+#[cfg(feature = "web")]
 #[Object]
 impl<'record> KeyValue<'record> {
     async fn json(&self) -> Result<String, serde_json::Error> {
@@ -114,8 +371,10 @@ impl<'record> KeyValue<'record> {
 }
 
 // 自力で実装しなくてもserdeをかぶせたらいい感じにしてくれる
+#[cfg(feature = "web")]
 #[derive(Debug, Serialize, Deserialize)]
 struct DurationScalar(f64);
+#[cfg(feature = "web")]
 scalar!(DurationScalar, "Duration");
 
 /// ログファイルの配置を管理する
@@ -123,43 +382,824 @@ scalar!(DurationScalar, "Duration");
 pub struct Storage {
     /// 保存先ルート
     dir: PathBuf,
+    /// 全てのcloneが破棄されたら解放する
+    lock: Option<Arc<lock::DirLock>>,
+    /// cloneの間で共有する
+    stats: Arc<stats::StatsCache>,
+    registry: Arc<SessionRegistry>,
+    /// テナントごとの保存先。cloneの間で共有する
+    tenants: Arc<Mutex<HashMap<String, Storage>>>,
+    /// 変更を記録するときの実行者
+    initiator: String,
+    /// 新しいセッションを暗号化する鍵
+    #[cfg(feature = "encryption")]
+    encryption: Option<Arc<crypt::EncryptionKey>>,
+    /// 固定したセッションの合計バイト数の上限
+    max_pinned_bytes: Option<u64>,
 }
 
 impl Storage {
+    /// 保存先を排他して開く。他のプロセスが使用中の場合はエラー
     pub fn new<A: AsRef<Path>>(root_dir: A) -> io::Result<Self> {
+        std::fs::create_dir_all(&root_dir)?;
+        let lock = lock::DirLock::acquire(root_dir.as_ref())?;
+        Ok(Self {
+            dir: root_dir.as_ref().to_owned(),
+            lock: Some(Arc::new(lock)),
+            stats: Arc::default(),
+            registry: Arc::default(),
+            tenants: Arc::default(),
+            initiator: audit::INITIATOR_LOCAL.to_string(),
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            max_pinned_bytes: None,
+        })
+    }
+
+    /// 排他せずに開く。サーバーの動作中に読み出すツール向け
+    pub fn new_shared<A: AsRef<Path>>(root_dir: A) -> io::Result<Self> {
         std::fs::create_dir_all(&root_dir)?;
         Ok(Self {
             dir: root_dir.as_ref().to_owned(),
+            lock: None,
+            stats: Arc::default(),
+            registry: Arc::default(),
+            tenants: Arc::default(),
+            initiator: audit::INITIATOR_LOCAL.to_string(),
+            #[cfg(feature = "encryption")]
+            encryption: None,
+            max_pinned_bytes: None,
         })
     }
 
+    /// 他のcloneが全て破棄されるまで、`timeout`を上限に待つ。排他していなければすぐに戻る
+    pub(crate) fn wait_unshared(&self, timeout: Duration) -> bool {
+        let Some(lock) = self.lock.as_ref() else {
+            return true;
+        };
+        let deadline = std::time::Instant::now() + timeout;
+        while Arc::strong_count(lock) > 1 {
+            if std::time::Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// Encrypts the data and index files of the sessions created from now on with `key`.
+    ///
+    /// The key is also registered for reading, see [`crypt::register_key`]. Sessions written
+    /// before stay as they are, and blobs are kept inline so that they are encrypted too.
+    /// Records waiting for a write retry are not spilled to a file, so those over
+    /// [`RetryPolicy::max_queue_bytes`] are lost.
+    #[cfg(feature = "encryption")]
+    pub fn encrypt_with(mut self, key: crypt::EncryptionKey) -> Self {
+        crypt::register_key(&key);
+        self.encryption = Some(Arc::new(key));
+        self
+    }
+
+    /// A clone that records `initiator`, e.g. the address of a GraphQL client, in the audit log.
+    pub fn as_initiator(&self, initiator: &str) -> Self {
+        Self {
+            initiator: initiator.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// Limits the total size of the pinned sessions, see [`Storage::pin_session`].
+    pub fn max_pinned_bytes(mut self, bytes: Option<u64>) -> Self {
+        self.max_pinned_bytes = bytes;
+        self
+    }
+
+    /// Entries of the audit log of the sessions changed through any storage on this directory,
+    /// newest first.
+    pub fn audit_log(&self, limit: Option<usize>) -> io::Result<Vec<AuditEntry>> {
+        audit::read(&self.dir, limit)
+    }
+
+    /// 変更を記録する。記録できなくても変更は取り消さない
+    fn audit<T>(&self, mut entry: AuditEntry, result: &io::Result<T>) {
+        if let Err(e) = result {
+            entry.error = Some(e.to_string());
+        }
+        if let Err(e) = audit::append(&self.dir, &entry) {
+            error!("failed to write audit log {:?}: {}", entry, e);
+        }
+    }
+
+    /// 排他して開いているか
+    pub fn is_exclusive(&self) -> bool {
+        self.lock.is_some()
+    }
+
     pub fn create_session(&self, name: &str) -> io::Result<Session> {
         let dirpath = self.dir.join(name);
         std::fs::create_dir_all(&dirpath).expect("failed to create storage dir");
-        Session::new(dirpath)
+        #[cfg(feature = "encryption")]
+        if let Some(key) = self.encryption.as_ref() {
+            return Ok(Session::encrypted(dirpath, key)?.watch(self.registry.clone(), name));
+        }
+        Ok(Session::new(dirpath)?.watch(self.registry.clone(), name))
+    }
+
+    /// 付加情報を1回で書いてからセッションを作る
+    ///
+    /// 新しいセッションは隠したディレクトリで付加情報を書いてから名前を変えるので、
+    /// 一覧には書き終わった付加情報と一緒に現れる
+    pub fn create_session_with<F: Fn(&mut SessionMeta)>(
+        &self,
+        name: &str,
+        f: F,
+    ) -> io::Result<Session> {
+        let dirpath = self.dir.join(name);
+        if !dirpath.is_dir() {
+            let staging = self.dir.join(format!(".{}.creating", name));
+            std::fs::create_dir_all(&staging)?;
+            SessionMeta::update(&staging, &f)?;
+            if std::fs::rename(&staging, &dirpath).is_ok() {
+                return self.create_session(name);
+            }
+            // 同じ名前のセッションが先に作られた
+            std::fs::remove_dir_all(&staging)?;
+        }
+        SessionMeta::update(&dirpath, f)?;
+        self.create_session(name)
+    }
+
+    /// Notifications of the sessions written through this storage and its clones.
+    pub fn registry(&self) -> &Arc<SessionRegistry> {
+        &self.registry
+    }
+
+    /// Reads at most `limit` records from `cursor` and returns the cursor after them.
+    pub fn read_after(
+        &self,
+        name: &str,
+        cursor: Cursor,
+        limit: usize,
+    ) -> io::Result<(Vec<LogRecord>, Cursor)> {
+        let dirpath = self.session_dir(name)?;
+        let start = session_start_at(&dirpath)?;
+        CBORSequenceReader::new(dirpath)?
+            .with_session_start(start)
+            .read_after(cursor, limit)
+    }
+
+    /// 区切りで分割した続きのセッションを作る。メモとタグは引き継ぐ
+    pub fn split_session(&self, parent: &str, name: &str) -> io::Result<Session> {
+        let meta = self.session_meta(parent)?;
+        let session = self.create_session(name)?;
+        SessionMeta::update(&self.dir.join(name), |x| {
+            *x = SessionMeta {
+                parent: Some(parent.to_string()),
+                ..meta
+            };
+        })?;
+        Ok(session)
+    }
+
+    /// 既存のセッションのディレクトリを返す
+    fn session_dir(&self, name: &str) -> io::Result<PathBuf> {
+        let dirpath = self.dir.join(name);
+        if name.is_empty()
+            || name.starts_with('.')
+            || name.contains(['/', '\\'])
+            || !dirpath.is_dir()
+            || tenant::is_tenant_root(&dirpath)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("session not found: {}", name),
+            ));
+        }
+        Ok(dirpath)
+    }
+
+    /// セッションを1ファイルにまとめて書き出す
+    pub fn archive_session<W: io::Write>(
+        &self,
+        name: &str,
+        writer: W,
+    ) -> io::Result<archive::Manifest> {
+        let dirpath = self.session_dir(name)?;
+        archive::write_archive(&dirpath, name, writer, false, None)
+    }
+
+    /// 分離したblobをレコードに戻してセッションを書き出す
+    pub fn archive_session_reinlined<W: io::Write>(
+        &self,
+        name: &str,
+        writer: W,
+    ) -> io::Result<archive::Manifest> {
+        let dirpath = self.session_dir(name)?;
+        archive::write_archive(&dirpath, name, writer, true, None)
+    }
+
+    /// blobを戻したレコードを`transform`で書き換えてセッションを書き出す
+    pub fn archive_session_transformed<W: io::Write>(
+        &self,
+        name: &str,
+        writer: W,
+        transform: &dyn anonymize::ExportTransform,
+    ) -> io::Result<archive::Manifest> {
+        let dirpath = self.session_dir(name)?;
+        archive::write_archive(&dirpath, name, writer, true, Some(transform))
+    }
+
+    /// セッションのレコードを先頭から読む。blobは置き換えたまま返す
+    pub fn session_records(&self, name: &str) -> io::Result<RecordIter> {
+        RecordIter::new(self.session_dir(name)?)
+    }
+
+    /// セッションのレコードを先頭から読み、分離したblobをレコードに戻して返す
+    pub fn session_records_reinlined(&self, name: &str) -> io::Result<RecordIter> {
+        let dirpath = self.session_dir(name)?;
+        Ok(RecordIter::new(&dirpath)?.reinline_blobs(BlobStore::new(dirpath)))
+    }
+
+    /// セッションに分離して保存したblobを読む
+    pub fn read_blob(&self, name: &str, hash: &str) -> io::Result<Vec<u8>> {
+        BlobStore::new(self.session_dir(name)?).get(hash)
+    }
+
+    /// セッションに分離して保存したblobの長さを返す
+    pub fn blob_len(&self, name: &str, hash: &str) -> io::Result<u64> {
+        BlobStore::new(self.session_dir(name)?).len(hash)
+    }
+
+    /// セッションの添付ファイルの一覧
+    pub fn attachments(&self, name: &str) -> io::Result<Vec<attachment::Attachment>> {
+        attachment::list(&self.session_dir(name)?)
+    }
+
+    /// 添付ファイルの内容を読む
+    pub fn read_attachment(
+        &self,
+        name: &str,
+        id: &str,
+    ) -> io::Result<(attachment::Attachment, Vec<u8>)> {
+        attachment::read(&self.session_dir(name)?, id)
+    }
+
+    /// セッションのファイルを確認する。`repair`の場合は直せるものを直す
+    pub fn verify_session(&self, name: &str, repair: bool) -> io::Result<verify::SessionReport> {
+        verify::verify_session(self.session_dir(name)?, repair)
+    }
+
+    /// 最後に更新されたセッションを確認する。セッションがなければNone
+    pub fn verify_latest(&self, repair: bool) -> io::Result<Option<verify::SessionReport>> {
+        let latest = self.records()?.into_iter().max_by_key(|x| x.updated_at);
+        latest
+            .map(|x| verify::verify_session(x.path(), repair))
+            .transpose()
+    }
+
+    /// セッションのカテゴリごとのレコード数。変わっていなければ前回の集計を返す
+    pub fn session_stats(&self, name: &str) -> io::Result<Arc<stats::SessionStats>> {
+        self.stats.get(&self.session_dir(name)?)
+    }
+
+    /// kvのキーごとの値の型。閉じたときに残したものがなければ集計と一緒に数える
+    pub fn kv_types(&self, name: &str) -> io::Result<kvtype::KvTypeRegistry> {
+        let dir = self.session_dir(name)?;
+        if let Some(x) = SessionMeta::load(&dir)?.kv_types {
+            return Ok(x);
+        }
+        Ok(self.stats.get(&dir)?.kv_types.clone())
+    }
+
+    /// elapsedの`bucket_seconds`秒ごとのレベル別のレコード数
+    pub fn session_histogram(&self, name: &str, bucket_seconds: u64) -> io::Result<Histogram> {
+        Histogram::collect(self.session_dir(name)?, bucket_seconds)
+    }
+
+    /// `names`の順に列を並べたセッションごとのレコード数
+    pub fn sessions_stats(&self, names: &[String]) -> io::Result<stats::StatsTable> {
+        self.sessions_stats_with(names, scan::ScanOptions::default())
+    }
+
+    /// `sessions_stats`と同じ。セッションを並列に数え、期限までに数え終わらなかったものは
+    /// 列に含めずに[`stats::StatsTable::incomplete`]に入れる
+    pub fn sessions_stats_with(
+        &self,
+        names: &[String],
+        options: scan::ScanOptions,
+    ) -> io::Result<stats::StatsTable> {
+        let dirs = names
+            .iter()
+            .map(|x| self.session_dir(x))
+            .collect::<io::Result<Vec<_>>>()?;
+        let results = scan::scan_parallel(&dirs, options, |dir, deadline| {
+            self.stats.get_until(dir, deadline)
+        });
+        let mut sessions = Vec::with_capacity(names.len());
+        let mut incomplete = Vec::new();
+        for (name, result) in names.iter().zip(results) {
+            match result {
+                Some(Ok(x)) => sessions.push((name.clone(), x)),
+                Some(Err(e)) if ScanTimeout::from_io(&e).is_none() => return Err(e),
+                _ => incomplete.push(name.clone()),
+            }
+        }
+        Ok(stats::StatsTable {
+            incomplete,
+            ..stats::StatsTable::new(&sessions)
+        })
+    }
+
+    /// セッションのメモとタグを返す
+    pub fn session_meta(&self, name: &str) -> io::Result<SessionMeta> {
+        SessionMeta::load(&self.session_dir(name)?)
+    }
+
+    /// セッションのメモを上書きする。空文字列の場合は削除する
+    pub fn set_session_note(&self, name: &str, note: &str) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.session_dir(name)?, |meta| {
+            meta.note = (!note.is_empty()).then(|| note.to_string());
+        })
+    }
+
+    /// クライアントが送った`elapsed`の単位を記録する
+    pub fn set_session_time_precision(
+        &self,
+        name: &str,
+        precision: &str,
+    ) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.session_dir(name)?, |meta| {
+            meta.time_precision = Some(precision.to_string());
+        })
+    }
+
+    /// 接続時に求めたクライアントの時計のずれを記録する
+    pub fn set_session_clock_offset(&self, name: &str, offset_ms: i64) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.session_dir(name)?, |meta| {
+            meta.clock_offset_ms = Some(offset_ms);
+        })
+    }
+
+    /// クライアントのセッションの開始時刻を記録する
+    pub fn set_session_start(
+        &self,
+        name: &str,
+        start_at: DateTime<Utc>,
+    ) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.session_dir(name)?, |meta| {
+            meta.start_at = Some(start_at);
+        })
+    }
+
+    /// クライアントが接続した受信パスのラベルを記録する
+    pub fn set_session_label(&self, name: &str, label: &str) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.session_dir(name)?, |meta| {
+            meta.label = Some(label.to_string());
+        })
+    }
+
+    /// クライアントのビルド情報を付加情報に書く
+    pub fn set_session_build_info(
+        &self,
+        name: &str,
+        build_info: &BuildInfo,
+    ) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.session_dir(name)?, |meta| {
+            meta.build_info = Some(build_info.clone());
+        })
+    }
+
+    /// セッションのレコードを読み直して異常の印を付加情報に残す
+    pub fn assess_session(&self, name: &str) -> io::Result<SessionMeta> {
+        let dir = self.session_dir(name)?;
+        let scan = health::scan(&dir)?;
+        let live = self.registry.is_open(name);
+        SessionMeta::update(&dir, |meta| meta.health = Some(scan.finish(meta, live)))
+    }
+
+    /// セッションにタグを追加する。既にある場合は何もしない
+    pub fn add_session_tag(&self, name: &str, tag: &str) -> io::Result<SessionMeta> {
+        if tag.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tag must not be empty",
+            ));
+        }
+        SessionMeta::update(&self.session_dir(name)?, |meta| {
+            if !meta.has_tag(tag) {
+                meta.tags.push(tag.to_string());
+            }
+        })
+    }
+
+    /// セッションからタグを削除する
+    pub fn remove_session_tag(&self, name: &str, tag: &str) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.session_dir(name)?, |meta| {
+            meta.tags.retain(|x| x != tag);
+        })
+    }
+
+    /// セッションを固定する、または固定を外す
+    ///
+    /// 固定したセッションは空き容量のために削除せず、[`Storage::force_remove_session`]でしか
+    /// 削除できない。固定したセッションの合計が上限を超える場合は固定しない
+    pub fn pin_session(&self, name: &str, pinned: bool) -> io::Result<SessionMeta> {
+        let dir = self.session_dir(name)?;
+        if let Some(max) = self.max_pinned_bytes.filter(|_| pinned) {
+            let mut total = dir_size(&dir)?;
+            for info in self.records()? {
+                if info.meta().pinned && info.name() != name {
+                    total += dir_size(info.path())?;
+                }
+            }
+            if total > max {
+                return Err(io::Error::new(
+                    io::ErrorKind::QuotaExceeded,
+                    format!(
+                        "pinned sessions would take {} bytes, over the limit of {}",
+                        total, max
+                    ),
+                ));
+            }
+        }
+        SessionMeta::update(&dir, |meta| meta.pinned = pinned)
+    }
+
+    /// 新しく作るセッションのディレクトリを返す。同名のセッションがある場合はエラー
+    fn new_session_dir(&self, name: &str, kind: io::ErrorKind) -> io::Result<PathBuf> {
+        let dirpath = self.dir.join(name);
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(io::Error::new(
+                kind,
+                format!("invalid session name {}", name),
+            ));
+        }
+        if dirpath.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("session already exists: {}", name),
+            ));
+        }
+        Ok(dirpath)
+    }
+
+    /// アーカイブからセッションを復元する。同名のセッションがある場合はエラー
+    pub fn restore_archive<R: io::Read>(&self, reader: R) -> io::Result<archive::Manifest> {
+        let (manifest, contents) = archive::read_archive(reader)?;
+        let mut entry = AuditEntry::new(audit::OP_RESTORE, &manifest.session, &self.initiator);
+        let result = (|| {
+            let dirpath = self.new_session_dir(&manifest.session, io::ErrorKind::InvalidData)?;
+            std::fs::create_dir_all(&dirpath)?;
+            for (file, buf) in manifest.files.iter().zip(contents) {
+                let path = dirpath.join(&file.name);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(path, &buf)?;
+                entry.bytes += buf.len() as u64;
+            }
+            Ok(())
+        })();
+        self.audit(entry, &result);
+        result.map(|_| manifest)
+    }
+
+    /// elapsedが`from`以上`to`未満のレコードを新しいセッション`new_name`に書き出す
+    ///
+    /// 元のセッションは変更しない。`rebase`の場合は`from`を0とする。
+    /// 範囲にレコードがない場合はセッションを作らずにエラーを返す。書き出したレコード数を返す
+    pub fn trim_session(
+        &self,
+        name: &str,
+        from: Duration,
+        to: Duration,
+        new_name: &str,
+        rebase: bool,
+    ) -> io::Result<usize> {
+        let mut entry = AuditEntry::new(audit::OP_TRIM, name, &self.initiator);
+        entry.target = Some(new_name.to_string());
+        let result = self.trim_into(name, from, to, new_name, rebase);
+        if result.is_ok() {
+            entry.bytes = dir_size(&self.dir.join(new_name)).unwrap_or(0);
+        }
+        self.audit(entry, &result);
+        result
+    }
+
+    fn trim_into(
+        &self,
+        name: &str,
+        from: Duration,
+        to: Duration,
+        new_name: &str,
+        rebase: bool,
+    ) -> io::Result<usize> {
+        if from >= to {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("empty range {:?}..{:?}", from, to),
+            ));
+        }
+        let src_dir = self.session_dir(name)?;
+        let records = self.session_records(name)?;
+        let dst_dir = self.new_session_dir(new_name, io::ErrorKind::InvalidInput)?;
+        let src_blobs = BlobStore::new(&src_dir);
+        let result = (|| {
+            let mut session = self.create_session(new_name)?;
+            let mut count = 0;
+            for record in records {
+                let mut record = record?;
+                if record.elapsed < from || record.elapsed >= to {
+                    continue;
+                }
+                src_blobs.copy_referenced(&record, session.blobs())?;
+                if rebase {
+                    record.elapsed -= from;
+                }
+                session.push(&record)?;
+                count += 1;
+            }
+            if count == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no records of {} in {:?}..{:?}", name, from, to),
+                ));
+            }
+            // 経過時間を詰めた分だけ開始時刻を進める
+            let mut start_at = session_start_at(&src_dir)?;
+            if rebase {
+                start_at += chrono::Duration::from_std(from).map_err(io::Error::other)?;
+            }
+            SessionMeta::update(&dst_dir, |x| {
+                x.parent = Some(name.to_string());
+                x.start_at = Some(start_at);
+            })?;
+            Ok(count)
+        })();
+        if result.is_err() {
+            std::fs::remove_dir_all(&dst_dir).ok();
+        }
+        result
+    }
+
+    /// 全てのレコードを`transform`で書き換えて新しいセッション`new_name`に書き出す
+    ///
+    /// 元のセッションは変更しない。書き換えた後も参照しているblobだけを写す。書き出したレコード数を返す
+    pub fn transform_session(
+        &self,
+        name: &str,
+        new_name: &str,
+        transform: &dyn anonymize::ExportTransform,
+    ) -> io::Result<usize> {
+        let mut entry = AuditEntry::new(audit::OP_TRANSFORM, name, &self.initiator);
+        entry.target = Some(new_name.to_string());
+        let result = self.transform_into(name, new_name, transform);
+        if result.is_ok() {
+            entry.bytes = dir_size(&self.dir.join(new_name)).unwrap_or(0);
+        }
+        self.audit(entry, &result);
+        result
+    }
+
+    fn transform_into(
+        &self,
+        name: &str,
+        new_name: &str,
+        transform: &dyn anonymize::ExportTransform,
+    ) -> io::Result<usize> {
+        let src_dir = self.session_dir(name)?;
+        let records = self.session_records(name)?;
+        let dst_dir = self.new_session_dir(new_name, io::ErrorKind::InvalidInput)?;
+        let src_blobs = BlobStore::new(&src_dir);
+        let result = (|| {
+            let mut session = self.create_session(new_name)?;
+            let mut count = 0;
+            for record in records {
+                let mut record = record?;
+                transform.transform(&mut record);
+                src_blobs.copy_referenced(&record, session.blobs())?;
+                session.push(&record)?;
+                count += 1;
+            }
+            let start_at = session_start_at(&src_dir)?;
+            SessionMeta::update(&dst_dir, |x| {
+                x.parent = Some(name.to_string());
+                x.start_at = Some(start_at);
+            })?;
+            Ok(count)
+        })();
+        if result.is_err() {
+            std::fs::remove_dir_all(&dst_dir).ok();
+        }
+        result
+    }
+
+    /// セッションを削除して、削除したファイルの合計バイト数を返す。
+    /// 書き込み中のセッションと固定したセッションは削除しない
+    pub fn remove_session(&self, name: &str) -> io::Result<u64> {
+        self.remove_session_as(name, audit::OP_DELETE, false)
+    }
+
+    /// 固定したセッションも削除する。書き込み中のセッションは削除しない
+    pub fn force_remove_session(&self, name: &str) -> io::Result<u64> {
+        self.remove_session_as(name, audit::OP_DELETE, true)
+    }
+
+    /// 空き容量や保持期間のために削除する。記録の種類だけが[`Storage::remove_session`]と異なる
+    pub fn prune_session(&self, name: &str) -> io::Result<u64> {
+        self.remove_session_as(name, audit::OP_PRUNE, false)
+    }
+
+    fn remove_session_as(&self, name: &str, operation: &str, force: bool) -> io::Result<u64> {
+        let mut entry = AuditEntry::new(operation, name, &self.initiator);
+        let result = (|| {
+            let dir = self.session_dir(name)?;
+            if self.registry.is_open(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("session is being written: {}", name),
+                ));
+            }
+            if !force && SessionMeta::load(&dir)?.pinned {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("session is pinned: {}", name),
+                ));
+            }
+            let size = dir_size(&dir)?;
+            let removed = std::fs::remove_dir_all(&dir);
+            // 途中で失敗した場合は消せた分を残す
+            entry.bytes = match removed {
+                Ok(()) => size,
+                Err(_) => size.saturating_sub(dir_size(&dir).unwrap_or(size)),
+            };
+            removed.map(|_| size)
+        })();
+        self.audit(entry, &result);
+        result
     }
 
+    /// セッションの一覧。順番は決めない
     pub fn records(&self) -> io::Result<Vec<SessionInfo>> {
+        Ok(self
+            .session_entries()?
+            .into_iter()
+            .map(SessionEntry::load)
+            .collect())
+    }
+
+    /// メタデータを読まずにセッションのディレクトリを並べる
+    fn session_entries(&self) -> io::Result<Vec<SessionEntry>> {
         let rd = std::fs::read_dir(&self.dir)?;
         let vec = rd.fold(vec![], |mut a, v| {
             if let Ok(d) = v {
+                // ロックファイルなどとテナントの保存先、作成中のセッションはセッションではない
+                if !d.file_type().map(|t| t.is_dir()).unwrap_or(false)
+                    || d.file_name().to_string_lossy().starts_with('.')
+                    || tenant::is_tenant_root(&d.path())
+                {
+                    return a;
+                }
                 let metadata = std::fs::metadata(d.path()).unwrap();
-                let i = SessionInfo {
+                let size = std::fs::metadata(d.path().join(SessionInfo::FILENAME))
+                    .map(|x| x.len())
+                    .unwrap_or(0);
+                let name = d.file_name().to_string_lossy().to_string();
+                a.push(SessionEntry {
+                    live: self.registry.is_open(&name),
+                    name,
                     created_at: metadata.created().unwrap().into(),
                     updated_at: metadata.modified().unwrap().into(),
                     path: d.path(),
-                };
-                a.push(i);
+                    size,
+                    meta: None,
+                });
             };
             a
         });
         Ok(vec)
     }
+
+    /// Sessions matching `query`, sorted and cut to a page.
+    ///
+    /// Sorting uses only the directory entries, and the metadata is read just for the page
+    /// unless `query.tag` needs it for every session.
+    pub fn records_paged(&self, query: &SessionQuery) -> io::Result<SessionPage> {
+        let mut entries = self.session_entries()?;
+        if let Some(x) = query.name_contains.as_deref() {
+            entries.retain(|e| e.name.contains(x));
+        }
+        if let Some(globs) = query.name_globs.as_ref() {
+            entries.retain(|e| globs.iter().any(|x| listing::glob_matches(x, &e.name)));
+        }
+        if let Some(tag) = query.tag.as_deref() {
+            for e in entries.iter_mut() {
+                e.load_meta();
+            }
+            entries.retain(|e| e.meta.as_ref().is_some_and(|x| x.has_tag(tag)));
+        }
+        if let Some(flag) = query.health {
+            for e in entries.iter_mut() {
+                e.load_meta();
+            }
+            entries.retain(|e| {
+                e.meta
+                    .as_ref()
+                    .and_then(|x| x.health)
+                    .is_some_and(|x| x.has(flag))
+            });
+        }
+        entries.sort_by(|a, b| {
+            let ord = match query.sort_by {
+                SessionSortKey::Created => a.created_at.cmp(&b.created_at),
+                SessionSortKey::Updated => a.updated_at.cmp(&b.updated_at),
+                SessionSortKey::Size => a.size.cmp(&b.size),
+                SessionSortKey::Name => std::cmp::Ordering::Equal,
+            }
+            // 同じ値の場合も順番が変わらないように名前で並べる
+            .then_with(|| a.name.cmp(&b.name));
+            match query.order {
+                SortOrder::Asc => ord,
+                SortOrder::Desc => ord.reverse(),
+            }
+        });
+        Ok(SessionPage {
+            total: entries.len(),
+            sessions: entries
+                .into_iter()
+                .skip(query.offset)
+                .take(query.limit.unwrap_or(usize::MAX))
+                .map(SessionEntry::load)
+                .collect(),
+        })
+    }
+}
+
+/// 一覧の並べ替えに使うセッションの情報
+struct SessionEntry {
+    name: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    path: PathBuf,
+    size: u64,
+    meta: Option<SessionMeta>,
+    live: bool,
+}
+
+impl SessionEntry {
+    fn load_meta(&mut self) {
+        if self.meta.is_none() {
+            self.meta = Some(SessionMeta::load(&self.path).unwrap_or_else(|e| {
+                warn!("failed to read meta of {}: {}", self.path.display(), e);
+                SessionMeta::default()
+            }));
+        }
+    }
+
+    fn load(mut self) -> SessionInfo {
+        self.load_meta();
+        SessionInfo {
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            path: self.path,
+            size: self.size,
+            meta: self.meta.unwrap_or_default(),
+            live: self.live,
+        }
+    }
+}
+
+/// クライアントのセッションの開始時刻。記録していなければ一覧と同じくディレクトリの作成時刻を使う
+fn session_start_at(dirpath: &Path) -> io::Result<DateTime<Utc>> {
+    match SessionMeta::load(dirpath)?.start_at {
+        Some(x) => Ok(x),
+        None => Ok(dirpath.metadata()?.created()?.into()),
+    }
+}
+
+/// ディレクトリ以下のファイルの合計バイト数
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
 }
 
 /// ある一連のログの書き込みを管理する
 pub struct Session {
     writer: Box<dyn writer::RecordWriter>,
+    blobs: BlobStore,
+    dir: PathBuf,
+    /// 書き出したことを知らせる先とセッション名
+    watch: Option<(Arc<SessionRegistry>, String)>,
+    encrypted: bool,
 }
 
 impl Session {
@@ -167,27 +1207,126 @@ impl Session {
         let writer = writer::CBORSequenceWriter::new(dirpath.as_ref())?;
         Ok(Self {
             writer: Box::new(writer),
+            blobs: BlobStore::new(dirpath.as_ref()),
+            dir: dirpath.as_ref().to_path_buf(),
+            watch: None,
+            encrypted: false,
+        })
+    }
+
+    #[cfg(feature = "encryption")]
+    fn encrypted<A: AsRef<Path>>(dirpath: A, key: &crypt::EncryptionKey) -> io::Result<Self> {
+        let writer = writer::CBORSequenceWriter::new_encrypted(dirpath.as_ref(), key)?;
+        Ok(Self {
+            writer: Box::new(writer),
+            blobs: BlobStore::new(dirpath.as_ref()),
+            dir: dirpath.as_ref().to_path_buf(),
+            watch: None,
+            encrypted: true,
+        })
+    }
+
+    /// Whether the data file is encrypted. Blobs are not offloaded from encrypted sessions.
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// 閉じるまで書き込み中として登録する
+    fn watch(mut self, registry: Arc<SessionRegistry>, name: &str) -> Self {
+        registry.open(name);
+        self.watch = Some((registry, name.to_string()));
+        self
+    }
+
+    /// Directory of the session.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// 書き直せずに失ったレコード数を付加情報に足す
+    #[cfg(feature = "web")]
+    pub(crate) fn add_lost_records(&self, count: u64) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.dir, |meta| meta.lost_records += count)
+    }
+
+    /// 閉じた理由を付加情報に残す
+    #[cfg(feature = "web")]
+    pub(crate) fn set_end_reason(&self, reason: &str) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.dir, |meta| meta.end_reason = Some(reason.to_string()))
+    }
+
+    /// 閉じるときにデコードの失敗数と異常の印を付加情報に残す
+    #[cfg(feature = "web")]
+    pub(crate) fn record_health(
+        &self,
+        decode_failures: u64,
+        scan: &health::HealthScan,
+    ) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.dir, |meta| {
+            meta.decode_failures += decode_failures;
+            meta.health = Some(scan.finish(meta, false));
         })
     }
+
+    /// 閉じるときにkvの値の型を付加情報に残す
+    #[cfg(feature = "web")]
+    pub(crate) fn record_kv_types(
+        &self,
+        kv_types: &kvtype::KvTypeRegistry,
+    ) -> io::Result<SessionMeta> {
+        SessionMeta::update(&self.dir, |meta| meta.kv_types = Some(kv_types.clone()))
+    }
+
+    /// Flushes only when a reader is waiting for new records of this session.
+    pub fn flush_if_watched(&mut self) {
+        if self
+            .watch
+            .as_ref()
+            .is_some_and(|(registry, name)| registry.has_waiters(name))
+        {
+            self.flush();
+        }
+    }
+
+    /// 分離して保存するblobの保存先
+    pub fn blobs(&self) -> &BlobStore {
+        &self.blobs
+    }
 }
 
-impl writer::RecordWriter for Session {
+impl RecordWriter for Session {
     fn push(&mut self, record: &uplog::Record) -> Result<(), std::io::Error> {
         self.writer.push(record)
     }
+
+    fn flush(&mut self) {
+        self.writer.flush();
+        if let Some((registry, name)) = self.watch.as_ref() {
+            registry.notify(name);
+        }
+    }
 }
 
 impl Drop for Session {
     fn drop(&mut self) {
-        self.writer.flush()
+        self.flush();
+        if let Some((registry, name)) = self.watch.as_ref() {
+            registry.close(name);
+        }
     }
 }
 
-#[derive(Debug)]
+/// A session found in [`Storage::records`].
+#[derive(Debug, Clone)]
 pub struct SessionInfo {
     pub(crate) created_at: DateTime<Utc>,
     pub(crate) updated_at: DateTime<Utc>,
     pub(crate) path: PathBuf,
+    /// size of the data file when listed
+    pub(crate) size: u64,
+    pub(crate) meta: SessionMeta,
+    /// whether a writer of this server held the session open when listed
+    pub(crate) live: bool,
 }
 
 impl Display for SessionInfo {
@@ -205,19 +1344,46 @@ impl Display for SessionInfo {
 impl SessionInfo {
     #[allow(dead_code)]
     const FILENAME: &'static str = "seqdata";
+    /// 書き込み中でも開けるように共有を許して開く。末尾は書き込み途中のレコードかもしれない
     pub fn open(&self) -> io::Result<File> {
         debug!("SessionInfo open: {}", self.filepath().to_str().unwrap());
-        OpenOptions::new().read(true).open(self.filepath())
+        writer::open_shared_read(self.filepath())
     }
 
+    /// セッションのディレクトリ
     pub fn path(&self) -> &Path {
         self.path.as_ref()
     }
 
+    /// [`Storage`]の各メソッドに渡す名前
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
     pub fn created_at(&self) -> &DateTime<Utc> {
         &self.created_at
     }
 
+    pub fn updated_at(&self) -> &DateTime<Utc> {
+        &self.updated_at
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn meta(&self) -> &SessionMeta {
+        &self.meta
+    }
+
+    /// Whether the session was still being written when listed.
+    pub fn is_live(&self) -> bool {
+        self.live
+    }
+
     fn filepath(&self) -> PathBuf {
         self.path.join(Self::FILENAME)
     }
@@ -230,7 +1396,242 @@ mod tests {
     use crate::{writer::RecordWriter, *};
     use serde_cbor::Deserializer;
     use tempdir::TempDir;
-    use uplog::{devinit, devlog, Level, Record};
+    use uplog::{devinit, devlog, KvExt, Level, Record};
+
+    /// `s00`から順にi件のレコードを書いたセッションを作る
+    fn paged_fixture(dir: &TempDir) -> Storage {
+        devinit!();
+        let storage = Storage::new(dir.path()).unwrap();
+        for i in 0..30_u64 {
+            let name = format!("s{:02}", i);
+            let mut session = storage.create_session(&name).unwrap();
+            for j in 0..i {
+                session
+                    .push(&devlog!(Level::Info, "cat", "msg", "number", j))
+                    .unwrap();
+            }
+            if i % 10 == 3 {
+                storage.add_session_tag(&name, "release").unwrap();
+            }
+        }
+        storage
+    }
+
+    fn names(page: &SessionPage) -> Vec<String> {
+        page.sessions.iter().map(|x| x.name()).collect()
+    }
+
+    #[test]
+    fn test_remove_session_audit() -> std::io::Result<()> {
+        devinit!();
+        let dir = TempDir::new("audit").unwrap();
+        let storage = Storage::new(dir.path())?;
+        let mut session = storage.create_session("a")?;
+        session.push(&devlog!(Level::Info, "cat", "msg"))?;
+        drop(session);
+        let size = storage.remove_session("a")?;
+        assert!(storage
+            .as_initiator("10.0.0.1")
+            .remove_session("x")
+            .is_err());
+
+        let entries = storage.audit_log(None)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            (entries[0].operation.as_str(), entries[0].session.as_str()),
+            ("delete", "x")
+        );
+        assert_eq!(entries[0].initiator, "10.0.0.1");
+        assert!(entries[0].error.as_deref().unwrap().contains("not found"));
+        assert_eq!(entries[1].session, "a");
+        assert_eq!(entries[1].bytes, size);
+        assert_eq!(entries[1].initiator, "local");
+        assert!(entries[1].is_ok());
+        // 記録はセッションとして扱わない
+        assert!(storage.records()?.is_empty());
+        assert_eq!(storage.audit_log(Some(1))?, entries[..1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_session() -> std::io::Result<()> {
+        devinit!();
+        let dir = TempDir::new("pin").unwrap();
+        let storage = Storage::new(dir.path())?;
+        for name in ["a", "b"] {
+            let mut session = storage.create_session(name)?;
+            session.push(&devlog!(Level::Info, "cat", "msg"))?;
+        }
+        assert!(storage.pin_session("a", true)?.pinned);
+        assert!(storage.session_meta("a")?.pinned);
+        let e = storage.remove_session("a").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(storage.force_remove_session("a")? > 0);
+        assert!(storage.pin_session("a", true).is_err());
+
+        // 固定したセッションの合計が上限を超える場合は固定しない
+        let size = dir_size(&dir.path().join("b"))?;
+        let e = storage
+            .clone()
+            .max_pinned_bytes(Some(size - 1))
+            .pin_session("b", true)
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::QuotaExceeded);
+        assert!(!storage.session_meta("b")?.pinned);
+        let limited = storage.max_pinned_bytes(Some(size));
+        limited.pin_session("b", true)?;
+        // 外すのは上限に関わらずできる
+        assert!(!limited.pin_session("b", false)?.pinned);
+        Ok(())
+    }
+
+    #[test]
+    fn test_records_paged() {
+        let dir = TempDir::new("paged").unwrap();
+        let storage = paged_fixture(&dir);
+        let query = |q: SessionQuery| storage.records_paged(&q).unwrap();
+        let by_name = SessionQuery {
+            sort_by: SessionSortKey::Name,
+            order: SortOrder::Asc,
+            ..Default::default()
+        };
+
+        // ページの境界
+        let page = query(SessionQuery {
+            offset: 0,
+            limit: Some(7),
+            ..by_name.clone()
+        });
+        assert_eq!(page.total, 30);
+        assert_eq!(
+            names(&page),
+            ["s00", "s01", "s02", "s03", "s04", "s05", "s06"]
+        );
+        let page = query(SessionQuery {
+            offset: 28,
+            limit: Some(7),
+            ..by_name.clone()
+        });
+        assert_eq!(
+            (page.total, names(&page)),
+            (30, vec!["s28".into(), "s29".into()])
+        );
+        let page = query(SessionQuery {
+            offset: 30,
+            ..by_name.clone()
+        });
+        assert_eq!((page.total, page.sessions.len()), (30, 0));
+
+        // 並べ替え
+        let page = query(SessionQuery {
+            limit: Some(3),
+            order: SortOrder::Desc,
+            ..by_name.clone()
+        });
+        assert_eq!(names(&page), ["s29", "s28", "s27"]);
+        let page = query(SessionQuery {
+            limit: Some(3),
+            sort_by: SessionSortKey::Size,
+            order: SortOrder::Desc,
+            ..Default::default()
+        });
+        assert_eq!(names(&page), ["s29", "s28", "s27"]);
+        assert!(page.sessions[0].size() > page.sessions[1].size());
+        let page = query(SessionQuery {
+            sort_by: SessionSortKey::Size,
+            order: SortOrder::Asc,
+            ..Default::default()
+        });
+        assert_eq!(page.sessions[0].size(), 0);
+        assert!(page.sessions.windows(2).all(|x| x[0].size() <= x[1].size()));
+        for (sort_by, key) in [
+            (
+                SessionSortKey::Created,
+                SessionInfo::created_at as fn(&SessionInfo) -> &DateTime<Utc>,
+            ),
+            (SessionSortKey::Updated, SessionInfo::updated_at),
+        ] {
+            let page = query(SessionQuery {
+                sort_by,
+                ..Default::default()
+            });
+            assert_eq!(page.sessions.len(), 30);
+            assert!(page.sessions.windows(2).all(|x| key(&x[0]) >= key(&x[1])));
+        }
+
+        // 絞り込んだ数を返す
+        let page = query(SessionQuery {
+            limit: Some(4),
+            name_contains: Some("s1".to_string()),
+            ..by_name.clone()
+        });
+        assert_eq!(page.total, 10);
+        assert_eq!(names(&page), ["s10", "s11", "s12", "s13"]);
+        let page = query(SessionQuery {
+            limit: Some(2),
+            name_globs: Some(vec!["s2?".to_string(), "s05".to_string()]),
+            ..by_name.clone()
+        });
+        assert_eq!(page.total, 11);
+        assert_eq!(names(&page), ["s05", "s20"]);
+        let page = query(SessionQuery {
+            name_globs: Some(Vec::new()),
+            ..by_name.clone()
+        });
+        assert_eq!(page.total, 0);
+        let page = query(SessionQuery {
+            tag: Some("release".to_string()),
+            ..by_name
+        });
+        assert_eq!(page.total, 3);
+        assert_eq!(names(&page), ["s03", "s13", "s23"]);
+        assert!(page.sessions[0].meta().has_tag("release"));
+    }
+
+    #[test]
+    fn test_assess_session() -> std::io::Result<()> {
+        devinit!();
+        let dir = TempDir::new("assess").unwrap();
+        let storage = Storage::new(dir.path())?;
+        let mut session = storage.create_session("a")?;
+        session.push(&devlog!(Level::Info, "cat", "msg"))?;
+        session.push(&devlog!(Level::Error, "cat", "failed"))?;
+        session.flush();
+        let flagged = |flag| {
+            let query = SessionQuery {
+                health: Some(flag),
+                ..Default::default()
+            };
+            storage.records_paged(&query).unwrap().total
+        };
+        // 記録する前は印で絞り込めない
+        assert_eq!(flagged(HealthFlag::HasErrors), 0);
+
+        // 書き込み中は閉じた理由がなくても異常にしない
+        let health = storage.assess_session("a")?.health.unwrap();
+        assert!(health.has_errors);
+        assert!(!health.ended_uncleanly);
+        assert!(!health.had_decode_failures);
+        assert_eq!(flagged(HealthFlag::HasErrors), 1);
+
+        // 閉じた理由がなく、途中で切れたレコードがある
+        drop(session);
+        let data = dir.path().join("a").join(SessionInfo::FILENAME);
+        let len = std::fs::metadata(&data)?.len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&data)?
+            .set_len(len - 2)?;
+        let health = storage.assess_session("a")?.health.unwrap();
+        assert!(health.ended_uncleanly);
+        assert!(health.had_decode_failures);
+        // 切れたのはエラーのレコード
+        assert!(!health.has_errors);
+        assert_eq!(health.score, 55);
+        assert_eq!(flagged(HealthFlag::EndedUncleanly), 1);
+        assert!(storage.assess_session("missing").is_err());
+        Ok(())
+    }
 
     #[test]
     fn test_storage_session() -> std::io::Result<()> {
@@ -262,4 +1663,266 @@ mod tests {
         assert_eq!(counter, 2);
         Ok(())
     }
+
+    #[test]
+    fn test_archive_round_trip() -> std::io::Result<()> {
+        devinit!();
+        let path = TempDir::new("storage").expect("create temp dir of storage");
+        let src = Storage::new(path.path().join("src"))?;
+        let dst = Storage::new(path.path().join("dst"))?;
+        let name = "archived";
+
+        let records = (0..200_u32)
+            .map(|i| devlog!(Level::Info, "cat", "msg", "number", i))
+            .collect::<Vec<_>>();
+        {
+            let mut session = src.create_session(name)?;
+            for r in records.iter() {
+                session.push(r)?;
+            }
+        }
+
+        let archive_path = path.path().join("session.uplog");
+        let manifest = src.archive_session(name, File::create(&archive_path)?)?;
+        assert!(manifest.files.iter().any(|x| x.name == "seqdata"));
+        assert!(src.archive_session("not_found", Vec::new()).is_err());
+
+        let restored = dst.restore_archive(File::open(&archive_path)?)?;
+        assert_eq!(restored, manifest);
+        // 同名のセッションは上書きしない
+        let err = dst.restore_archive(File::open(&archive_path)?).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+        let f = File::open(path.path().join("dst").join(name).join("seqdata"))?;
+        let actual = Deserializer::from_reader(f)
+            .into_iter::<Record>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(actual, records);
+        Ok(())
+    }
+
+    /// 書き込み中のセッションを別のスレッドから読めることを確認する
+    #[test]
+    fn test_read_live_session() -> std::io::Result<()> {
+        devinit!();
+        let path = TempDir::new("storage").expect("create temp dir of storage");
+        let storage = Storage::new(path.path())?;
+        let name = "live";
+        let total = 2000_u64;
+        let info = |storage: &Storage| {
+            storage
+                .records()
+                .unwrap()
+                .into_iter()
+                .find(|x| x.name() == name)
+                .unwrap()
+        };
+
+        let (opened, wait_open) = std::sync::mpsc::channel();
+        let (release, wait_release) = std::sync::mpsc::channel::<()>();
+        let writer = {
+            let storage = storage.clone();
+            std::thread::spawn(move || {
+                let mut session = storage.create_session(name).unwrap();
+                opened.send(()).unwrap();
+                for i in 0..total {
+                    session
+                        .push(&devlog!(Level::Info, "cat", "msg", "number", i))
+                        .unwrap();
+                    if i % 16 == 0 {
+                        session.flush();
+                    }
+                }
+                session.flush();
+                // 閉じる前に書き込み中であることを確認させる
+                wait_release.recv().ok();
+            })
+        };
+        wait_open.recv().unwrap();
+        assert!(info(&storage).is_live());
+
+        // 書き込みの途中でも読めたところまでは番号が連続している
+        let mut read = 0;
+        while read < total as usize {
+            let mut reader = open_reader(&info(&storage))?;
+            let data = reader.read_at(0, total as usize)?;
+            for (i, x) in data.iter().enumerate() {
+                assert_eq!(x.id, i);
+                let kv = x.record.key_values().unwrap();
+                assert_eq!(kv.get_u64("number"), Some(i as u64));
+            }
+            if let Some(partial) = reader.trailing_partial() {
+                assert_eq!(partial.index, data.len());
+            }
+            read = data.len();
+        }
+        assert!(info(&storage).is_live());
+
+        release.send(()).unwrap();
+        writer.join().unwrap();
+        assert!(!info(&storage).is_live());
+        Ok(())
+    }
+
+    /// 同時に更新しても壊れずに全て反映されることを確認する
+    #[test]
+    fn test_session_meta_concurrent_update() -> std::io::Result<()> {
+        let path = TempDir::new("storage").expect("create temp dir of storage");
+        let storage = Storage::new(path.path())?;
+        storage.create_session("s")?;
+        assert_eq!(storage.session_meta("s")?, SessionMeta::default());
+
+        let handles = (0..8)
+            .map(|i| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    for j in 0..10 {
+                        storage
+                            .add_session_tag("s", &format!("{}-{}", i, j))
+                            .unwrap();
+                        storage.set_session_note("s", &format!("{}", i)).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            h.join().unwrap();
+        }
+        let meta = storage.session_meta("s")?;
+        assert_eq!(meta.tags.len(), 80);
+        assert!(meta.note.is_some());
+
+        storage.set_session_note("s", "")?;
+        assert_eq!(storage.session_meta("s")?.note, None);
+        assert!(storage.session_meta("../s").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_trim_session() -> std::io::Result<()> {
+        use std::time::Duration;
+        devinit!();
+        let path = TempDir::new("trim").expect("create temp dir of storage");
+        let storage = Storage::new(path.path())?;
+        {
+            let mut session = storage.create_session("long")?;
+            for i in 0..100_u32 {
+                let mut r = devlog!(Level::Info, "cat", "msg", "number", i);
+                r.elapsed = Duration::from_millis(10 * i as u64);
+                if i == 30 {
+                    r.kv.as_mut()
+                        .unwrap()
+                        .insert("blob".to_string(), uplog::Value::Bytes(vec![1; 1000]));
+                    session.blobs().offload(&mut r, 100)?;
+                }
+                session.push(&r)?;
+            }
+        }
+        let ms = Duration::from_millis;
+
+        // 開始は含み、終了は含まない
+        assert_eq!(
+            storage.trim_session("long", ms(200), ms(500), "window", false)?,
+            30
+        );
+        let trimmed = storage
+            .session_records_reinlined("window")?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(trimmed.len(), 30);
+        assert_eq!(trimmed.first().unwrap().elapsed, ms(200));
+        assert_eq!(trimmed.last().unwrap().elapsed, ms(490));
+        assert_eq!(
+            trimmed[10].key_values().unwrap().get("blob"),
+            Some(&uplog::Value::Bytes(vec![1; 1000]))
+        );
+        assert_eq!(
+            storage.session_meta("window")?.parent.as_deref(),
+            Some("long")
+        );
+        // 元のセッションは変わらない
+        assert_eq!(storage.session_records("long")?.count(), 100);
+
+        // elapsedを範囲の開始からにする
+        storage.trim_session("long", ms(200), ms(500), "rebased", true)?;
+        let rebased = storage
+            .session_records("rebased")?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(rebased.first().unwrap().elapsed, ms(0));
+        assert_eq!(rebased.last().unwrap().elapsed, ms(290));
+
+        // 範囲にレコードがなければセッションを作らない
+        let err = storage
+            .trim_session("long", ms(5000), ms(6000), "empty", false)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(!path.path().join("empty").exists());
+        let err = storage
+            .trim_session("long", ms(500), ms(500), "empty", false)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        let err = storage
+            .trim_session("long", ms(0), ms(500), "window", false)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        let err = storage
+            .trim_session("missing", ms(0), ms(500), "other", false)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        Ok(())
+    }
+
+    /// 既存のディレクトリを開いても中のセッションを消さない
+    #[test]
+    fn test_storage_new_existing_dir() -> std::io::Result<()> {
+        let path = TempDir::new("existing").expect("create temp dir of storage");
+        {
+            let storage = Storage::new(path.path())?;
+            storage.create_session("first")?;
+        }
+        let storage = Storage::new(path.path())?;
+        let records = storage.records()?;
+        assert_eq!(records.len(), 1);
+        assert!(records[0].path().ends_with("first"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_lock() -> std::io::Result<()> {
+        let path = TempDir::new("storage").expect("create temp dir of storage");
+        let storage = Storage::new(path.path())?;
+        let lockfile = path.path().join(LOCK_FILENAME);
+        assert_eq!(
+            std::fs::read_to_string(&lockfile)?,
+            std::process::id().to_string()
+        );
+        // ロックファイルはセッションとして扱わない
+        assert!(storage.records()?.is_empty());
+
+        // 2つ目のサーバーは開けない
+        let err = Storage::new(path.path()).unwrap_err();
+        assert!(err.to_string().contains("locked"), "{}", err);
+        // 読み出し用は開ける
+        let shared = Storage::new_shared(path.path())?;
+        assert!(!shared.is_exclusive());
+
+        // cloneが残っている間は解放しない
+        let cloned = storage.clone();
+        drop(storage);
+        assert!(Storage::new(path.path()).is_err());
+        drop(cloned);
+        // ファイルは残してpidだけ消す
+        assert_eq!(std::fs::read_to_string(&lockfile)?, "");
+        drop(Storage::new(path.path())?);
+
+        // 異常終了したプロセスのロックファイルは上書きする
+        std::fs::write(&lockfile, "4194305")?;
+        let storage = Storage::new(path.path())?;
+        assert_eq!(
+            std::fs::read_to_string(&lockfile)?,
+            std::process::id().to_string()
+        );
+        drop(storage);
+        Ok(())
+    }
 }
@@ -0,0 +1,237 @@
+//! サーバーが書き込むセッションの開始と終了のレコード
+//!
+//! 後から読んだときに、正常に閉じたのか接続が切れたのかわかるようにする。
+//! クライアントのレコードと区別できるように`_origin = "server"`を付ける
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use chrono::{SecondsFormat, Utc};
+use uplog::{protocol, Level, Record, Value, KV};
+
+/// category of the records written by the server
+pub const SESSION_CATEGORY: &str = "uplog.session";
+/// key of the kv marking records written by the server
+pub const ORIGIN_KEY: &str = "_origin";
+pub const ORIGIN_SERVER: &str = "server";
+
+/// Why a session was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// the client closed the connection, with the reason it sent if any
+    Client(Option<protocol::CloseReason>),
+    /// the connection was lost without closing
+    ConnectionLost,
+    /// closed by consecutive decode failures
+    DecodeError,
+    /// nothing was received within the idle timeout
+    IdleTimeout,
+    /// the connection sent more than its quota
+    Quota,
+    /// the storage became almost full
+    StorageFull,
+    /// the server stopped while the session was open
+    Shutdown,
+    /// an operator closed the connection
+    Disconnected,
+}
+
+impl CloseReason {
+    /// 数える単位。クライアントが送った理由は区別しない
+    pub const ALL: [CloseReason; 8] = [
+        Self::Client(None),
+        Self::ConnectionLost,
+        Self::DecodeError,
+        Self::IdleTimeout,
+        Self::Quota,
+        Self::StorageFull,
+        Self::Shutdown,
+        Self::Disconnected,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Client(_) => "client",
+            Self::ConnectionLost => "connection_lost",
+            Self::DecodeError => "decode_error",
+            Self::IdleTimeout => "idle_timeout",
+            Self::Quota => "quota",
+            Self::StorageFull => "storage_full",
+            Self::Shutdown => "shutdown",
+            Self::Disconnected => "disconnected",
+        }
+    }
+
+    /// 閉じるフレームで送る、または受け取った理由
+    pub fn wire(&self) -> Option<protocol::CloseReason> {
+        match self {
+            Self::Client(x) => *x,
+            Self::ConnectionLost => None,
+            Self::DecodeError => Some(protocol::CloseReason::DecodeError),
+            Self::IdleTimeout => Some(protocol::CloseReason::IdleTimeout),
+            Self::Quota => Some(protocol::CloseReason::Quota),
+            Self::StorageFull => Some(protocol::CloseReason::StorageFull),
+            Self::Shutdown => Some(protocol::CloseReason::Shutdown),
+            Self::Disconnected => Some(protocol::CloseReason::Disconnected),
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Self::Client(_) => 0,
+            Self::ConnectionLost => 1,
+            Self::DecodeError => 2,
+            Self::IdleTimeout => 3,
+            Self::Quota => 4,
+            Self::Shutdown => 5,
+            Self::StorageFull => 6,
+            Self::Disconnected => 7,
+        }
+    }
+}
+
+/// クライアントが理由を送った場合は`client:flush`のように続ける
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Client(Some(x)) => write!(f, "{}:{}", self.as_str(), x),
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+static CLOSED_SESSIONS: [AtomicU64; 8] = [const { AtomicU64::new(0) }; 8];
+
+/// 閉じたセッションを理由ごとに数える
+#[cfg(feature = "web")]
+pub(crate) fn count_close(reason: CloseReason) {
+    CLOSED_SESSIONS[reason.index()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of sessions closed for each reason since the server started.
+pub fn close_counts() -> Vec<(CloseReason, u64)> {
+    CloseReason::ALL
+        .into_iter()
+        .map(|x| (x, CLOSED_SESSIONS[x.index()].load(Ordering::Relaxed)))
+        .collect()
+}
+
+/// サーバーが書き込んだレコードか
+pub fn is_server_record(record: &Record) -> bool {
+    record
+        .kv
+        .as_ref()
+        .and_then(|x| x.get(ORIGIN_KEY))
+        .is_some_and(|x| *x == Value::Text(ORIGIN_SERVER.to_string()))
+}
+
+fn server_record(message: &str, elapsed: Duration, kv: KV) -> Record {
+    kv.into_iter()
+        .fold(Record::builder(), |builder, (key, value)| {
+            builder.kv(key, value)
+        })
+        .level(Level::Info)
+        .target(module_path!())
+        .category(SESSION_CATEGORY)
+        .module_path(module_path!())
+        .message(message)
+        .elapsed(elapsed)
+        .kv(ORIGIN_KEY, ORIGIN_SERVER)
+        .kv(
+            "server_time",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+        )
+        .build()
+}
+
+/// セッションの先頭に書くレコード
+pub fn opened_record(remote_addr: &str, codec: &str) -> Record {
+    let mut kv = KV::new();
+    kv.insert(
+        "client_addr".to_string(),
+        Value::Text(remote_addr.to_string()),
+    );
+    kv.insert(
+        "server_version".to_string(),
+        Value::Text(env!("CARGO_PKG_VERSION").to_string()),
+    );
+    kv.insert("codec".to_string(), Value::Text(codec.to_string()));
+    server_record("opened", Duration::ZERO, kv)
+}
+
+/// セッションの最後に書くレコード。elapsedは最後に受け取ったレコードに合わせる
+pub fn closed_record(
+    reason: CloseReason,
+    elapsed: Duration,
+    records: u64,
+    connected: Duration,
+) -> Record {
+    let mut kv = KV::new();
+    kv.insert(
+        "reason".to_string(),
+        Value::Text(reason.as_str().to_string()),
+    );
+    if let Some(x) = reason.wire() {
+        kv.insert("close_code".to_string(), Value::U64(x.code().into()));
+    }
+    if let CloseReason::Client(Some(x)) = reason {
+        kv.insert("client_reason".to_string(), Value::Text(x.to_string()));
+    }
+    kv.insert("records".to_string(), Value::U64(records));
+    kv.insert(
+        "connected_secs".to_string(),
+        Value::F64(connected.as_secs_f64()),
+    );
+    server_record("closed", elapsed, kv)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use uplog::{devinit, devlog, protocol, Level, Value};
+
+    use super::{closed_record, is_server_record, opened_record, CloseReason};
+
+    #[test]
+    fn test_server_records() {
+        devinit!();
+        let opened = opened_record("127.0.0.1:1234", "uplog.cbor");
+        assert!(is_server_record(&opened));
+        assert_eq!(opened.message, "opened");
+        assert_eq!(
+            opened.kv.as_ref().unwrap()["codec"],
+            Value::Text("uplog.cbor".to_string())
+        );
+        let closed = closed_record(
+            CloseReason::IdleTimeout,
+            Duration::from_secs(3),
+            10,
+            Duration::from_secs(4),
+        );
+        assert!(is_server_record(&closed));
+        assert_eq!(closed.elapsed, Duration::from_secs(3));
+        assert_eq!(
+            closed.kv.as_ref().unwrap()["reason"],
+            Value::Text("idle_timeout".to_string())
+        );
+        assert_eq!(closed.kv.as_ref().unwrap()["close_code"], Value::U64(4100));
+
+        // 新しいクライアントの知らない理由も番号のまま残す
+        let reason = CloseReason::Client(Some(protocol::CloseReason::from_code(4999)));
+        assert_eq!(reason.to_string(), "client:unknown(4999)");
+        let closed = closed_record(reason, Duration::ZERO, 0, Duration::ZERO);
+        let kv = closed.kv.unwrap();
+        assert_eq!(kv["reason"], Value::Text("client".to_string()));
+        assert_eq!(kv["close_code"], Value::U64(4999));
+        assert_eq!(CloseReason::Client(None).to_string(), "client");
+
+        // クライアントが送ったレコードは区別する
+        let mut record = devlog!(Level::Info, "app", "msg", "_origin", "client");
+        assert!(!is_server_record(&record));
+        record.kv = None;
+        assert!(!is_server_record(&record));
+    }
+}
@@ -0,0 +1,150 @@
+//! セッション一覧の並べ替えと分割
+//!
+//! セッションが多い場合に一覧の全てを返さずに済むよう、[`crate::Storage::records_paged`]は
+//! ディレクトリの情報だけで並べ替えて切り出し、返す分のメタデータだけを読む
+use std::{fmt, str::FromStr};
+
+#[cfg(feature = "web")]
+use async_graphql::Enum;
+
+use crate::{HealthFlag, SessionInfo};
+
+/// Key to sort sessions by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "web", derive(Enum))]
+pub enum SessionSortKey {
+    #[default]
+    Created,
+    Updated,
+    /// size of the data file
+    Size,
+    Name,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "web", derive(Enum))]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// 名前を小文字で書いた時の一覧。CLIの引数で使う
+fn parse_lowercase<T: Copy>(s: &str, names: &[(&str, T)]) -> Result<T, String> {
+    names
+        .iter()
+        .find(|(name, _)| *name == s)
+        .map(|(_, x)| *x)
+        .ok_or_else(|| {
+            let names = names.iter().map(|(x, _)| *x).collect::<Vec<_>>();
+            format!("expected one of {}, got {}", names.join(", "), s)
+        })
+}
+
+impl FromStr for SessionSortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use SessionSortKey::*;
+        parse_lowercase(
+            s,
+            &[
+                ("created", Created),
+                ("updated", Updated),
+                ("size", Size),
+                ("name", Name),
+            ],
+        )
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_lowercase(s, &[("asc", SortOrder::Asc), ("desc", SortOrder::Desc)])
+    }
+}
+
+/// Conditions of [`crate::Storage::records_paged`]. The default is every session, newest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionQuery {
+    pub offset: usize,
+    /// all sessions after `offset` when `None`
+    pub limit: Option<usize>,
+    pub sort_by: SessionSortKey,
+    pub order: SortOrder,
+    /// only sessions whose name contains this
+    pub name_contains: Option<String>,
+    /// only sessions with this tag. Reads the metadata of every session to check it.
+    pub tag: Option<String>,
+    /// only sessions whose name matches one of these [`glob_matches`] patterns. No session when
+    /// empty, every session when `None`.
+    pub name_globs: Option<Vec<String>>,
+    /// only sessions whose stored [`crate::SessionHealth`] has this flag. Sessions closed before
+    /// the health was recorded match only after [`crate::Storage::assess_session`].
+    pub health: Option<HealthFlag>,
+}
+
+/// A page of sessions and the number of sessions matching the query.
+#[derive(Debug)]
+pub struct SessionPage {
+    pub total: usize,
+    pub sessions: Vec<SessionInfo>,
+}
+
+impl fmt::Display for SessionPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} sessions", self.sessions.len(), self.total)
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` is any characters and `?` is one character.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    // 最後の`*`の位置と、そこから読み直す名前の位置
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_matches;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("team-a-*", "team-a-01"));
+        assert!(glob_matches("team-a-*", "team-a-"));
+        assert!(!glob_matches("team-a-*", "team-b-01"));
+        assert!(glob_matches("*-prod", "api-prod"));
+        assert!(glob_matches("s?", "s1"));
+        assert!(!glob_matches("s?", "s10"));
+        assert!(glob_matches("*a*b*", "xxaxxbxx"));
+        assert!(!glob_matches("*a*b", "xxaxxbxc"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("exact", "exact"));
+        assert!(!glob_matches("exact", "exactly"));
+    }
+}
@@ -0,0 +1,71 @@
+//! 保存先ディレクトリの排他
+//!
+//! 同じディレクトリに複数のサーバーが書き込まないように、
+//! ロックファイルにadvisory lockをかけて保持者のpidを書き込む
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use fs2::FileExt;
+use log::warn;
+
+/// 保存先ルートに作るロックファイル名
+pub const LOCK_FILENAME: &str = ".uplog.lock";
+
+/// 保持している間ディレクトリを排他する
+///
+/// ロックファイルは消さない。消すと古いinodeをロックしたプロセスと
+/// 新しく作ったファイルをロックしたプロセスの両方が保持者になりうる
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    pub(crate) fn acquire(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(LOCK_FILENAME);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let holder = read_pid(&mut file);
+        if let Err(e) = file.try_lock_exclusive() {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "data dir {} is locked by another process (pid {})",
+                    dir.display(),
+                    holder.map_or_else(|| "unknown".to_string(), |x| x.to_string())
+                ),
+            ));
+        }
+        // 正常に終了していればpidは空にしてある。ロックは終了時に解放されるので
+        // 取得できた時点で残っているpidは異常終了したプロセスのもの
+        if let Some(pid) = holder {
+            warn!("break stale lock of {} left by pid {}", dir.display(), pid);
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_data()?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // 解放前に空にして、他のプロセスが古いpidを読まないようにする
+        self.file.set_len(0).ok();
+        self.file.unlock().ok();
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut buf = String::new();
+    file.read_to_string(&mut buf).ok()?;
+    buf.trim().parse().ok()
+}
@@ -0,0 +1,227 @@
+//! ツール自身の動作ログを書き出すファイル
+//!
+//! 標準出力を読まないサービスとして動かすときに使う。
+//! 上限を超える前に`.1`, `.2`...へ名前を変えて新しいファイルに書く
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    panic,
+    path::{Path, PathBuf},
+};
+
+use log::error;
+
+/// Log file that is renamed to `PATH.1` before it grows past `max_bytes`.
+///
+/// Older files shift to `PATH.2`, `PATH.3` and so on, and the one past `keep` is removed.
+/// With `keep` 0 the file is truncated instead. A single write larger than `max_bytes` goes
+/// into its own file, it is never split.
+///
+/// ```
+/// use std::io::Write;
+/// use uplog_tools::logfile::RotatingFile;
+///
+/// # fn main() -> std::io::Result<()> {
+/// # let dir = tempdir::TempDir::new("doc")?;
+/// let path = dir.path().join("server.log");
+/// let mut f = RotatingFile::new(&path, 16, 2)?;
+/// f.write_all(b"first line\n")?;
+/// f.write_all(b"second line\n")?;
+/// assert!(dir.path().join("server.log.1").exists());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    /// 今のファイルの大きさ
+    written: u64,
+}
+
+impl RotatingFile {
+    /// Opens `path` to append to it, creating it if missing.
+    pub fn new(path: impl AsRef<Path>, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = open_append(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            keep,
+            file,
+            written,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path of the `index`th older file, e.g. `server.log.1`.
+    pub fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+            self.written = 0;
+            return Ok(());
+        }
+        // 一番古いものを消してから順に名前をずらす
+        match fs::remove_file(self.rotated_path(self.keep)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for index in (1..self.keep).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = open_append(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        // 1行を2つのファイルに分けない
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Logs panics as errors through the `log` crate before the previous hook runs.
+///
+/// Without it a panic of a service with its log in a [`RotatingFile`] only reaches stderr.
+pub fn log_panics() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        let thread = std::thread::current();
+        match info.location() {
+            Some(location) => error!(
+                "thread '{}' panicked at {}: {}",
+                thread.name().unwrap_or("<unnamed>"),
+                location,
+                message
+            ),
+            None => error!(
+                "thread '{}' panicked: {}",
+                thread.name().unwrap_or("<unnamed>"),
+                message
+            ),
+        }
+        log::logger().flush();
+        previous(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use tempdir::TempDir;
+
+    use super::RotatingFile;
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = TempDir::new("logfile").unwrap();
+        let path = dir.path().join("logs").join("server.log");
+        let mut f = RotatingFile::new(&path, 10, 2).unwrap();
+        let read = |index: usize| {
+            let path = match index {
+                0 => path.clone(),
+                x => rotated(&path, x),
+            };
+            fs::read_to_string(path).ok()
+        };
+
+        f.write_all(b"aaaa\n").unwrap();
+        f.write_all(b"bbbb\n").unwrap();
+        assert_eq!(read(0).as_deref(), Some("aaaa\nbbbb\n"));
+        assert_eq!(read(1), None);
+
+        // 上限を超える前に名前を変える
+        f.write_all(b"cccc\n").unwrap();
+        assert_eq!(read(0).as_deref(), Some("cccc\n"));
+        assert_eq!(read(1).as_deref(), Some("aaaa\nbbbb\n"));
+
+        // 上限より大きい書き込みも分けない
+        f.write_all(b"dddddddddddd\n").unwrap();
+        f.write_all(b"eeee\n").unwrap();
+        assert_eq!(read(0).as_deref(), Some("eeee\n"));
+        assert_eq!(read(1).as_deref(), Some("dddddddddddd\n"));
+        assert_eq!(read(2).as_deref(), Some("cccc\n"));
+        // keepより古いものは消す
+        assert_eq!(read(3), None);
+        let names = fs::read_dir(path.parent().unwrap()).unwrap().count();
+        assert_eq!(names, 3);
+
+        // 開き直すと続きに追記する
+        drop(f);
+        let mut f = RotatingFile::new(&path, 10, 2).unwrap();
+        f.write_all(b"ff\n").unwrap();
+        assert_eq!(read(0).as_deref(), Some("eeee\nff\n"));
+        f.write_all(b"gg\n").unwrap();
+        assert_eq!(read(0).as_deref(), Some("gg\n"));
+        assert_eq!(read(1).as_deref(), Some("eeee\nff\n"));
+        assert_eq!(read(2).as_deref(), Some("dddddddddddd\n"));
+    }
+
+    #[test]
+    fn test_rotating_file_keep_none() {
+        let dir = TempDir::new("logfile").unwrap();
+        let path = dir.path().join("server.log");
+        let mut f = RotatingFile::new(&path, 8, 0).unwrap();
+        f.write_all(b"aaaa\n").unwrap();
+        f.write_all(b"bbbb\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "bbbb\n");
+        assert!(!f.rotated_path(1).exists());
+    }
+
+    fn rotated(path: &std::path::Path, index: usize) -> std::path::PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", index));
+        name.into()
+    }
+}
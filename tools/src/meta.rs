@@ -0,0 +1,149 @@
+//! セッションごとの付加情報
+//!
+//! セッションディレクトリ内のJSONファイルに保存する。
+//! 複数の更新が同時に来ても壊れないようにファイルロックをかけて読み書きする
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+#[cfg(feature = "web")]
+use async_graphql::SimpleObject;
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{health::SessionHealth, kvtype::KvTypeRegistry};
+
+/// セッションディレクトリ内のファイル名
+pub const META_FILENAME: &str = "meta.json";
+
+/// 解析結果のメモとタグ
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionMeta {
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// 区切りで分割した場合の前のセッション名
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// クライアントが`elapsed`を整数で送った場合の単位。保存したレコードは`Duration`に戻してある
+    #[serde(default)]
+    pub time_precision: Option<String>,
+    /// 接続時に求めたクライアントの時計のずれ(ミリ秒)。サーバーの時刻からクライアントの時刻を引いた値
+    #[serde(default)]
+    pub clock_offset_ms: Option<i64>,
+    /// クライアントが接続した受信パスのラベル
+    #[serde(default)]
+    pub label: Option<String>,
+    /// 最初のレコードの受信時刻から経過時間を引いた、クライアントのセッションの開始時刻
+    #[serde(default)]
+    pub start_at: Option<DateTime<Utc>>,
+    /// 書き直せずに失ったレコード数。失っていなければ0
+    #[serde(default)]
+    pub lost_records: u64,
+    /// サーバーが終了のレコードに書いた閉じた理由。開いているセッションにはない
+    #[serde(default)]
+    pub end_reason: Option<String>,
+    /// データファイルを暗号化している場合の方式と鍵
+    #[serde(default)]
+    pub encryption: Option<EncryptionInfo>,
+    /// クライアントがハンドシェイクで送ったビルド情報
+    #[serde(default)]
+    pub build_info: Option<BuildInfo>,
+    /// サーバーがデコードできなかったクライアントのメッセージ数
+    #[serde(default)]
+    pub decode_failures: u64,
+    /// 閉じたとき、または求められたときに判断した異常の印
+    #[serde(default)]
+    pub health: Option<SessionHealth>,
+    /// 閉じたときに数えたkvのキーごとの値の型
+    #[serde(default)]
+    pub kv_types: Option<KvTypeRegistry>,
+    /// 空き容量のために削除せず、強制しなければ削除できない
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+/// Build of the client binary, sent with `uplog::Builder::with_build_info`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct BuildInfo {
+    /// package name
+    #[serde(default)]
+    pub name: Option<String>,
+    /// package version
+    #[serde(default)]
+    pub version: Option<String>,
+    /// commit the binary was built from, when the build script set `GIT_HASH`
+    #[serde(default)]
+    pub git_hash: Option<String>,
+    /// cargo profile, when the build script set `PROFILE`
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// target triple
+    #[serde(default)]
+    pub target: Option<String>,
+}
+
+/// How the data file of a session is encrypted. The key itself is not stored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionInfo {
+    pub scheme: String,
+    /// id of the key, see `EncryptionKey::id`
+    pub key_id: String,
+    /// セッションごとの鍵を導出するsalt (hex)
+    pub salt: String,
+}
+
+impl SessionMeta {
+    /// ファイルがなければ空の情報を返す
+    pub(crate) fn load(session_dir: &Path) -> io::Result<Self> {
+        let mut f = match File::open(session_dir.join(META_FILENAME)) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        f.lock_shared()?;
+        let meta = read_meta(&mut f);
+        f.unlock()?;
+        meta
+    }
+
+    /// 読み込みから書き込みまでを排他して更新する
+    pub(crate) fn update<F: FnOnce(&mut Self)>(session_dir: &Path, f: F) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(session_dir.join(META_FILENAME))?;
+        file.lock_exclusive()?;
+        let result = read_meta(&mut file).and_then(|mut meta| {
+            f(&mut meta);
+            let buf = serde_json::to_vec_pretty(&meta)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.set_len(0)?;
+            file.write_all(&buf)?;
+            file.sync_data()?;
+            Ok(meta)
+        });
+        file.unlock()?;
+        result
+    }
+
+    pub(crate) fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|x| x == tag)
+    }
+}
+
+fn read_meta(f: &mut File) -> io::Result<SessionMeta> {
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    if buf.is_empty() {
+        return Ok(SessionMeta::default());
+    }
+    serde_json::from_slice(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
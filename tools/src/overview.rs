@@ -0,0 +1,438 @@
+//! セッションの概要
+//!
+//! データファイルと並べて、一定のレコード数かelapsedの1秒ごとに区切った範囲の
+//! レベルごとのレコード数を`overview.cbor`に書く。セッション全体のヒストグラムはこれを足し合わせ、
+//! 最後の区切りより後だけをデータファイルから読む。
+//! 概要のない古いセッションはデータファイルを全て読む。`verify --repair`で作り直せる
+use std::{
+    collections::BTreeMap,
+    io::{self, BufReader},
+    path::Path,
+    time::Duration,
+};
+
+#[cfg(feature = "web")]
+use async_graphql::SimpleObject;
+use serde::{Deserialize, Serialize};
+use uplog::{Level, Record};
+
+use crate::{lifecycle::is_server_record, RecordIter};
+
+/// Name of the overview file in a session directory.
+pub const OVERVIEW_FILENAME: &str = "overview.cbor";
+/// Most records summarized by one [`OverviewEntry`].
+pub const OVERVIEW_RECORDS: usize = 4096;
+
+/// Record counts per level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct LevelCounts {
+    pub trace: u64,
+    pub debug: u64,
+    pub info: u64,
+    pub warn: u64,
+    pub error: u64,
+}
+
+impl LevelCounts {
+    pub fn add(&mut self, level: Level) {
+        match level {
+            Level::Trace => self.trace += 1,
+            Level::Debug => self.debug += 1,
+            Level::Info => self.info += 1,
+            Level::Warn => self.warn += 1,
+            Level::Error => self.error += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.trace += other.trace;
+        self.debug += other.debug;
+        self.info += other.info;
+        self.warn += other.warn;
+        self.error += other.error;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.trace + self.debug + self.info + self.warn + self.error
+    }
+}
+
+/// Summary of consecutive records of a session.
+///
+/// A range ends after [`OVERVIEW_RECORDS`] records or before a record of the client in another
+/// second of elapsed, so all the records of the client in a range are in the same second.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverviewEntry {
+    /// index of the first record
+    pub first: usize,
+    /// records in the range, including the ones written by the server
+    pub records: usize,
+    /// offset of the first record in the data file
+    pub offset: u64,
+    /// bytes of the records in the data file
+    pub bytes: u64,
+    /// whole seconds of elapsed of the records written by the client
+    pub second: u64,
+    /// records per level, without the ones written by the server
+    pub levels: LevelCounts,
+}
+
+/// 書き込むレコードを範囲に分けて数える
+#[derive(Debug, Default)]
+pub(crate) struct OverviewBuilder {
+    current: Option<OverviewEntry>,
+    /// 次のレコードの番号と位置
+    next: usize,
+    offset: u64,
+}
+
+impl OverviewBuilder {
+    /// `len`バイトの`record`を加える。その前で範囲が閉じればそれを返す
+    pub(crate) fn push(&mut self, record: &Record, len: u64) -> Option<OverviewEntry> {
+        let client = !is_server_record(record);
+        let second = record.elapsed.as_secs();
+        let cut = self.current.as_ref().is_some_and(|x| {
+            x.records >= OVERVIEW_RECORDS || (client && x.levels.total() > 0 && x.second != second)
+        });
+        let closed = if cut { self.current.take() } else { None };
+        let entry = self.current.get_or_insert(OverviewEntry {
+            first: self.next,
+            records: 0,
+            offset: self.offset,
+            bytes: 0,
+            second,
+            levels: LevelCounts::default(),
+        });
+        if client {
+            if entry.levels.total() == 0 {
+                entry.second = second;
+            }
+            entry.levels.add(record.level());
+        }
+        entry.records += 1;
+        entry.bytes += len;
+        self.next += 1;
+        self.offset += len;
+        closed
+    }
+}
+
+/// Closed ranges of a session read from [`OVERVIEW_FILENAME`].
+///
+/// The records after the last range are not summarized yet and are read from the data file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overview {
+    entries: Vec<OverviewEntry>,
+}
+
+impl Overview {
+    /// 概要がなければ`None`。書きかけの末尾の項目は除く
+    pub fn load<P: AsRef<Path>>(session_dir: P) -> io::Result<Option<Self>> {
+        let file = match std::fs::File::open(session_dir.as_ref().join(OVERVIEW_FILENAME)) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::<OverviewEntry>::new();
+        for entry in serde_cbor::Deserializer::from_reader(BufReader::new(file)).into_iter() {
+            let entry: OverviewEntry = match entry {
+                Ok(x) => x,
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            let (first, offset) = entries
+                .last()
+                .map_or((0, 0), |x| (x.first + x.records, x.offset + x.bytes));
+            if entry.first != first || entry.offset != offset {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "overview entry {} starts at record {} offset {}, expected {} and {}",
+                        entries.len(),
+                        entry.first,
+                        entry.offset,
+                        first,
+                        offset
+                    ),
+                ));
+            }
+            entries.push(entry);
+        }
+        Ok(Some(Self { entries }))
+    }
+
+    pub fn entries(&self) -> &[OverviewEntry] {
+        &self.entries
+    }
+
+    /// Index and offset of the first record after the summarized ranges.
+    pub fn end(&self) -> (usize, u64) {
+        self.entries
+            .last()
+            .map_or((0, 0), |x| (x.first + x.records, x.offset + x.bytes))
+    }
+}
+
+/// Records of a range of elapsed in a [`Histogram`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct HistogramBucket {
+    /// elapsed seconds at the start of the bucket
+    pub start: u64,
+    pub levels: LevelCounts,
+}
+
+/// Records of a session per level in buckets of elapsed.
+///
+/// Records written by the server are not counted, and empty buckets are left out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct Histogram {
+    pub bucket_seconds: u64,
+    pub buckets: Vec<HistogramBucket>,
+    /// whether the closed ranges were counted from the overview instead of the data file
+    pub from_overview: bool,
+}
+
+impl Histogram {
+    /// Counts the records from the overview and the records after it, or from all the
+    /// records when the session has no overview.
+    pub fn collect<P: AsRef<Path>>(session_dir: P, bucket_seconds: u64) -> io::Result<Self> {
+        check_bucket(bucket_seconds)?;
+        let overview = match Overview::load(session_dir.as_ref()) {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!(
+                    "ignore the overview of {}: {}",
+                    session_dir.as_ref().display(),
+                    e
+                );
+                None
+            }
+        };
+        let Some(overview) = overview else {
+            return Self::scan(session_dir, bucket_seconds);
+        };
+        let mut buckets = BTreeMap::<u64, LevelCounts>::new();
+        for entry in overview.entries.iter().filter(|x| x.levels.total() > 0) {
+            buckets
+                .entry(entry.second / bucket_seconds * bucket_seconds)
+                .or_default()
+                .merge(&entry.levels);
+        }
+        let (index, offset) = overview.end();
+        count_records(
+            RecordIter::from_offset(session_dir, index, offset)?,
+            bucket_seconds,
+            &mut buckets,
+        )?;
+        Ok(Self::new(bucket_seconds, buckets, true))
+    }
+
+    /// Counts all the records of the data file.
+    pub fn scan<P: AsRef<Path>>(session_dir: P, bucket_seconds: u64) -> io::Result<Self> {
+        check_bucket(bucket_seconds)?;
+        let mut buckets = BTreeMap::new();
+        count_records(RecordIter::new(session_dir)?, bucket_seconds, &mut buckets)?;
+        Ok(Self::new(bucket_seconds, buckets, false))
+    }
+
+    fn new(bucket_seconds: u64, buckets: BTreeMap<u64, LevelCounts>, from_overview: bool) -> Self {
+        Self {
+            bucket_seconds,
+            buckets: buckets
+                .into_iter()
+                .map(|(start, levels)| HistogramBucket { start, levels })
+                .collect(),
+            from_overview,
+        }
+    }
+}
+
+fn check_bucket(bucket_seconds: u64) -> io::Result<()> {
+    match bucket_seconds {
+        0 => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bucket must be at least 1 second",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn count_records(
+    records: RecordIter,
+    bucket_seconds: u64,
+    buckets: &mut BTreeMap<u64, LevelCounts>,
+) -> io::Result<()> {
+    for record in records {
+        let record = record?;
+        if is_server_record(&record) {
+            continue;
+        }
+        buckets
+            .entry(record.elapsed.as_secs() / bucket_seconds * bucket_seconds)
+            .or_default()
+            .add(record.level());
+    }
+    Ok(())
+}
+
+/// Index of the first record written by the client at or after `elapsed`.
+///
+/// The ranges of the overview whose records are all before `elapsed` are skipped without
+/// reading the data file.
+pub fn seek_elapsed<P: AsRef<Path>>(
+    session_dir: P,
+    elapsed: Duration,
+) -> io::Result<Option<usize>> {
+    let (index, offset) = match Overview::load(session_dir.as_ref()).ok().flatten() {
+        Some(overview) => overview
+            .entries
+            .iter()
+            .find(|x| x.levels.total() > 0 && x.second >= elapsed.as_secs())
+            .map_or_else(|| overview.end(), |x| (x.first, x.offset)),
+        None => (0, 0),
+    };
+    for (i, record) in RecordIter::from_offset(session_dir, index, offset)?.enumerate() {
+        let record = record?;
+        if !is_server_record(&record) && record.elapsed >= elapsed {
+            return Ok(Some(index + i));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level, Record};
+
+    use super::{seek_elapsed, Histogram, Overview, OVERVIEW_FILENAME, OVERVIEW_RECORDS};
+    use crate::{
+        lifecycle::{closed_record, opened_record, CloseReason},
+        writer::RecordWriter,
+        Storage,
+    };
+
+    /// elapsedが`elapsed(i)`のレコードを`count`件書く
+    fn fixture(storage: &Storage, name: &str, count: usize, elapsed: impl Fn(usize) -> Duration) {
+        devinit!();
+        let levels = [
+            Level::Info,
+            Level::Warn,
+            Level::Debug,
+            Level::Error,
+            Level::Info,
+        ];
+        let mut session = storage.create_session(name).unwrap();
+        session
+            .push(&opened_record("127.0.0.1", "uplog.cbor.v1"))
+            .unwrap();
+        for i in 0..count {
+            let mut r: Record = devlog!(levels[i % levels.len()], "cat", "msg", "i", i as u64);
+            r.elapsed = elapsed(i);
+            session.push(&r).unwrap();
+        }
+        session
+            .push(&closed_record(
+                CloseReason::Client(None),
+                Duration::from_secs(1),
+                1,
+                Duration::ZERO,
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_histogram_overview_matches_scan() {
+        let dir = TempDir::new("overview").unwrap();
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        // 1秒に約30件で、途中に長い空白と前後するelapsedがある
+        fixture(&storage, "s", 10_000, |i| match i {
+            5000..=5009 => Duration::from_millis(2_000),
+            _ if i > 6000 => Duration::from_millis(1_000_000 + i as u64 * 33),
+            _ => Duration::from_millis(i as u64 * 33),
+        });
+        let path = dir.path().join("s");
+        let overview = Overview::load(&path).unwrap().unwrap();
+        assert!(overview.entries().len() > 100);
+        assert!(overview.end().0 < 10_002);
+        for bucket in [1, 5, 60, 3600] {
+            let fast = Histogram::collect(&path, bucket).unwrap();
+            let full = Histogram::scan(&path, bucket).unwrap();
+            assert!(fast.from_overview && !full.from_overview);
+            assert_eq!(fast.buckets, full.buckets, "bucket {}", bucket);
+            assert_eq!(
+                fast.buckets.iter().map(|x| x.levels.total()).sum::<u64>(),
+                10_000
+            );
+        }
+    }
+
+    #[test]
+    fn test_histogram_record_limit() {
+        let dir = TempDir::new("overview").unwrap();
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        // 全て同じ秒でもレコード数で区切る
+        fixture(&storage, "s", OVERVIEW_RECORDS * 2 + 10, |_| Duration::ZERO);
+        let path = dir.path().join("s");
+        let overview = Overview::load(&path).unwrap().unwrap();
+        assert_eq!(overview.entries().len(), 2);
+        assert_eq!(overview.entries()[0].records, OVERVIEW_RECORDS);
+        // サーバーが書いたレコードは範囲に入るが数えない
+        assert_eq!(
+            overview.entries()[0].levels.total(),
+            OVERVIEW_RECORDS as u64 - 1
+        );
+        assert_eq!(
+            Histogram::collect(&path, 1).unwrap().buckets,
+            Histogram::scan(&path, 1).unwrap().buckets
+        );
+    }
+
+    #[test]
+    fn test_histogram_without_overview() {
+        let dir = TempDir::new("overview").unwrap();
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        fixture(&storage, "s", 100, |i| {
+            Duration::from_millis(i as u64 * 100)
+        });
+        let path = dir.path().join("s");
+        std::fs::remove_file(path.join(OVERVIEW_FILENAME)).unwrap();
+        let histogram = Histogram::collect(&path, 2).unwrap();
+        assert!(!histogram.from_overview);
+        assert_eq!(histogram.buckets.len(), 5);
+        assert_eq!(histogram.buckets[1].start, 2);
+        assert_eq!(histogram.buckets[1].levels.total(), 20);
+        assert_eq!(
+            Histogram::collect(&path, 0).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn test_seek_elapsed() {
+        let dir = TempDir::new("overview").unwrap();
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        fixture(&storage, "s", 1000, |i| {
+            Duration::from_millis(i as u64 * 10)
+        });
+        let path = dir.path().join("s");
+        // 0番目はサーバーが書いたレコード
+        assert_eq!(seek_elapsed(&path, Duration::ZERO).unwrap(), Some(1));
+        assert_eq!(
+            seek_elapsed(&path, Duration::from_millis(5_005)).unwrap(),
+            Some(502)
+        );
+        assert_eq!(seek_elapsed(&path, Duration::from_secs(10)).unwrap(), None);
+        // 概要がなくても同じ
+        std::fs::remove_file(path.join(OVERVIEW_FILENAME)).unwrap();
+        assert_eq!(
+            seek_elapsed(&path, Duration::from_millis(5_005)).unwrap(),
+            Some(502)
+        );
+    }
+}
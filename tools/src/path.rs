@@ -0,0 +1,189 @@
+use std::{
+    io,
+    path::{Component, PathBuf},
+};
+
+/// データ保存先の指定を実際のパスに解決する
+///
+/// - 先頭の`~`はホームディレクトリ
+/// - `$VAR`, `${VAR}`, `%VAR%`は環境変数
+///
+/// ディレクトリがなければ作成し、正規化したパスを返す
+pub fn resolve_data_dir(src: &str) -> io::Result<PathBuf> {
+    resolve_data_dir_with(src, dirs::home_dir(), |k| std::env::var(k).ok())
+}
+
+fn resolve_data_dir_with<F>(src: &str, home: Option<PathBuf>, env: F) -> io::Result<PathBuf>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let path = expand_path(src, home, env)?;
+    std::fs::create_dir_all(&path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to create data dir {}: {}", path.display(), e),
+        )
+    })?;
+    path.canonicalize().map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to canonicalize data dir {}: {}", path.display(), e),
+        )
+    })
+}
+
+fn expand_path<F>(src: &str, home: Option<PathBuf>, env: F) -> io::Result<PathBuf>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if src.trim().is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "data dir is empty",
+        ));
+    }
+    let expanded = expand_env(src, &env)?;
+    let path = PathBuf::from(&expanded);
+    let mut components = path.components();
+    match components.next() {
+        Some(Component::Normal(x)) if x == "~" => {
+            let home = home.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "home directory is not found")
+            })?;
+            Ok(home.join(components.as_path()))
+        }
+        _ => Ok(path),
+    }
+}
+
+/// 環境変数を展開する。未定義の変数はエラー
+fn expand_env<F>(src: &str, env: &F) -> io::Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let lookup = |name: &str| {
+        env(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("environment variable {} is not defined", name),
+            )
+        })
+    };
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let mut result = String::with_capacity(src.len());
+    let mut rest = src;
+    while let Some(pos) = rest.find(['$', '%']) {
+        result.push_str(&rest[..pos]);
+        let marker = &rest[pos..];
+        if let Some(braced) = marker.strip_prefix("${") {
+            let end = braced.find('}').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unclosed ${{ in {}", src),
+                )
+            })?;
+            result.push_str(&lookup(&braced[..end])?);
+            rest = &braced[end + 1..];
+        } else if let Some(name) = marker.strip_prefix('$') {
+            let end = name.find(|c| !is_name(c)).unwrap_or(name.len());
+            if end == 0 {
+                result.push('$');
+            } else {
+                result.push_str(&lookup(&name[..end])?);
+            }
+            rest = &name[end..];
+        } else {
+            let name = &marker[1..];
+            match name.find('%') {
+                Some(end) if end > 0 && name[..end].chars().all(is_name) => {
+                    result.push_str(&lookup(&name[..end])?);
+                    rest = &name[end + 1..];
+                }
+                _ => {
+                    result.push('%');
+                    rest = name;
+                }
+            }
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use tempdir::TempDir;
+
+    use super::{expand_path, resolve_data_dir_with};
+
+    /// 区切り文字の違いを吸収して比較する
+    fn normalize(p: &Path) -> PathBuf {
+        p.components().collect()
+    }
+
+    fn env(k: &str) -> Option<String> {
+        match k {
+            "XDG_DATA_HOME" => Some("/data/xdg".to_string()),
+            "APPDATA" => Some("/data/appdata".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = Some(PathBuf::from("/home/nkmm"));
+        let p = expand_path("~/uplog", home.clone(), env).unwrap();
+        assert_eq!(normalize(&p), PathBuf::from("/home/nkmm/uplog"));
+        let p = expand_path("~", home.clone(), env).unwrap();
+        assert_eq!(normalize(&p), PathBuf::from("/home/nkmm"));
+        // 途中の~は展開しない
+        let p = expand_path("data/~/uplog", home, env).unwrap();
+        assert_eq!(normalize(&p), PathBuf::from("data/~/uplog"));
+        // ホームがない
+        assert!(expand_path("~/uplog", None, env).is_err());
+    }
+
+    #[test]
+    fn test_expand_env() {
+        let p = expand_path("$XDG_DATA_HOME/uplog", None, env).unwrap();
+        assert_eq!(normalize(&p), PathBuf::from("/data/xdg/uplog"));
+        let p = expand_path("${XDG_DATA_HOME}/uplog", None, env).unwrap();
+        assert_eq!(normalize(&p), PathBuf::from("/data/xdg/uplog"));
+        let p = expand_path("%APPDATA%/uplog", None, env).unwrap();
+        assert_eq!(normalize(&p), PathBuf::from("/data/appdata/uplog"));
+        // 変数でない記号はそのまま
+        let p = expand_path("data/100%/$", None, env).unwrap();
+        assert_eq!(normalize(&p), PathBuf::from("data/100%/$"));
+
+        let err = expand_path("$NOT_DEFINED/uplog", None, env).unwrap_err();
+        assert!(err.to_string().contains("NOT_DEFINED"));
+        assert!(expand_path("${XDG_DATA_HOME/uplog", None, env).is_err());
+        assert!(expand_path("", None, env).is_err());
+    }
+
+    #[test]
+    fn test_resolve_data_dir() {
+        let dir = TempDir::new("resolve").unwrap();
+        let base = dir.path().to_string_lossy().to_string();
+        let tmp_env = |k: &str| match k {
+            "UPLOG_TEST_BASE" => Some(base.clone()),
+            _ => None,
+        };
+
+        // 作成と正規化
+        let p = resolve_data_dir_with("$UPLOG_TEST_BASE/a/../b/uplog", None, tmp_env).unwrap();
+        assert!(p.is_dir());
+        assert!(p.is_absolute());
+        assert_eq!(
+            p,
+            dir.path().canonicalize().unwrap().join("b").join("uplog")
+        );
+
+        // ホーム
+        let p = resolve_data_dir_with("~/uplog", Some(dir.path().to_owned()), tmp_env).unwrap();
+        assert_eq!(p, dir.path().canonicalize().unwrap().join("uplog"));
+    }
+}
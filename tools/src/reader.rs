@@ -1,72 +1,983 @@
 use std::{
+    cell::RefCell,
+    fmt,
     fs::File,
-    io::{Seek, SeekFrom},
+    io::{self, BufReader, Read, Seek, SeekFrom},
     path::Path,
+    rc::Rc,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
 };
 
-use uplog::Record;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde_cbor::{de::IoRead, StreamDeserializer};
+use uplog::{Record, RecordHead, KV};
 
-use crate::{writer::CBORSequenceWriter, LogRecord};
+use crate::{
+    view::session_start,
+    writer::{open_shared_read, CBORSequenceWriter},
+    BlobStore, LogRecord, SessionInfo, SessionMeta,
+};
+
+/// セッションのデータファイル。暗号化していれば平文の位置で読む
+pub enum DataFile {
+    Plain(File),
+    #[cfg(feature = "encryption")]
+    Encrypted(Box<crate::crypt::DecryptingReader>),
+    /// 標準入力などの読み進めるだけのストリーム。長さは分からず、シークできない
+    Stream(Box<dyn Read + Send>),
+}
+
+impl DataFile {
+    /// 付加情報が暗号化を示していれば登録した鍵で復号する
+    pub fn open<P: AsRef<Path>>(dirpath: P) -> io::Result<Self> {
+        Self::open_in(dirpath.as_ref(), CBORSequenceWriter::FILENAME)
+    }
+
+    /// セッションの中のファイルを開く。索引もデータと同じ鍵で暗号化している
+    pub(crate) fn open_in(dirpath: &Path, filename: &str) -> io::Result<Self> {
+        let file = open_shared_read(dirpath.join(filename))?;
+        match SessionMeta::load(dirpath)?.encryption {
+            None => Ok(Self::Plain(file)),
+            #[cfg(feature = "encryption")]
+            Some(info) => Ok(Self::Encrypted(Box::new(
+                crate::crypt::DecryptingReader::new(file, &info, None)?,
+            ))),
+            #[cfg(not(feature = "encryption"))]
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "session {} is encrypted, build with the encryption feature to read it",
+                    dirpath.display()
+                ),
+            )),
+        }
+    }
+
+    /// 読める長さ。暗号化している場合は閉じたフレームまでの平文の長さ
+    pub fn len(&mut self) -> io::Result<u64> {
+        match self {
+            Self::Plain(x) => Ok(x.metadata()?.len()),
+            #[cfg(feature = "encryption")]
+            Self::Encrypted(x) => x.len(),
+            Self::Stream(_) => Err(not_seekable()),
+        }
+    }
+
+    pub fn is_empty(&mut self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+impl Read for DataFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(x) => x.read(buf),
+            #[cfg(feature = "encryption")]
+            Self::Encrypted(x) => x.read(buf),
+            Self::Stream(x) => x.read(buf),
+        }
+    }
+}
+
+impl Seek for DataFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::Plain(x) => x.seek(pos),
+            #[cfg(feature = "encryption")]
+            Self::Encrypted(x) => x.seek(pos),
+            Self::Stream(_) => Err(not_seekable()),
+        }
+    }
+}
+
+fn not_seekable() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "stream is not seekable")
+}
 
+/// Reads a range of records of a session. [`open_reader`] returns the default implementation.
+///
 /// 最低限満たすべき性質
 pub trait StorageReader {
     /// メモリに確保する形式。省メモリにするためにWriterを渡すインターフェースにするのが望ましい
     fn read_at(&mut self, index: usize, len: usize) -> Result<Vec<LogRecord>, std::io::Error>;
+
+    /// Like `read_at`, but gives up with a [`ScanTimeout`] error once `deadline` has passed.
+    /// The default implementation does not check the deadline.
+    fn read_at_until(
+        &mut self,
+        index: usize,
+        len: usize,
+        deadline: Deadline,
+    ) -> Result<Vec<LogRecord>, std::io::Error> {
+        let _ = deadline;
+        self.read_at(index, len)
+    }
+
+    /// Like `read_at_until`, handling records that cannot be decoded as `options.on_error` says.
+    /// The default implementation reads with `read_at_until` and reports no skipped records.
+    fn read_page(
+        &mut self,
+        index: usize,
+        len: usize,
+        options: ReadOptions,
+    ) -> Result<ReadPage, std::io::Error> {
+        Ok(self.read_at_until(index, len, options.deadline)?.into())
+    }
+
+    /// Record left incomplete at the end by the last read, which the writer may still be flushing.
+    /// Reading it again later can succeed. The default implementation does not detect it.
+    fn trailing_partial(&self) -> Option<PartialRecord> {
+        None
+    }
+}
+
+/// A record at the end of a session that could not be read because it was not completely written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialRecord {
+    /// number of the record
+    pub index: usize,
+    /// offset of the record in the data file
+    pub offset: u64,
+}
+
+/// 読み出しの途中で期限を確認するレコードの間隔
+pub const DEADLINE_CHECK_INTERVAL: usize = 256;
+
+/// Time after which a scan gives up. The default never expires.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now().checked_add(timeout))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.0.is_some_and(|x| Instant::now() >= x)
+    }
+}
+
+/// What a read does with a record that cannot be decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "web", derive(async_graphql::Enum))]
+pub enum OnError {
+    /// fail with a [`RecordError`]
+    Strict,
+    /// leave the record out and report it in [`ReadPage::skipped`]
+    #[default]
+    Skip,
+    /// end the page before the record and report it in [`ReadPage::stopped_at`]
+    StopAt,
+}
+
+/// Options of [`StorageReader::read_page`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadOptions {
+    pub on_error: OnError,
+    pub deadline: Deadline,
+    /// skip decoding the key-values until [`LogRecord::kv`] asks for them.
+    /// Readers that can not skip them ignore it
+    pub lazy_kv: bool,
+}
+
+impl ReadOptions {
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn lazy_kv(mut self, lazy_kv: bool) -> Self {
+        self.lazy_kv = lazy_kv;
+        self
+    }
+}
+
+/// Key-values of a record read with [`ReadOptions::lazy_kv`], decoded on first use.
+///
+/// Clones share the decoded key-values.
+#[derive(Debug, Clone)]
+pub struct LazyKv {
+    /// レコード全体のCBOR
+    encoded: Arc<[u8]>,
+    decoded: Arc<OnceLock<Option<KV>>>,
+}
+
+impl LazyKv {
+    fn new(encoded: &[u8]) -> Self {
+        Self {
+            encoded: encoded.into(),
+            decoded: Arc::default(),
+        }
+    }
+
+    /// `None` if the record has no key-values or they can not be decoded.
+    pub fn get(&self) -> Option<&KV> {
+        self.decoded
+            .get_or_init(|| {
+                // 読み飛ばさなかった場合と同じ結果になるようにレコードとして読む
+                serde_cbor::from_slice::<Record>(&self.encoded)
+                    .ok()
+                    .and_then(|x| x.kv)
+            })
+            .as_ref()
+    }
+
+    /// Length of the encoded record kept for decoding.
+    pub fn encoded_len(&self) -> usize {
+        self.encoded.len()
+    }
+}
+
+/// Record collected by [`scan_range`].
+pub trait PageRecord {
+    fn into_log_record(self, id: usize) -> LogRecord;
+}
+
+impl PageRecord for Record {
+    fn into_log_record(self, id: usize) -> LogRecord {
+        LogRecord::new(id, self)
+    }
+}
+
+/// kvを読み飛ばしたレコード
+struct HeadRecord {
+    record: Record,
+    kv: Option<LazyKv>,
+}
+
+impl PageRecord for HeadRecord {
+    fn into_log_record(self, id: usize) -> LogRecord {
+        LogRecord::new(id, self.record).with_lazy_kv(self.kv)
+    }
+}
+
+/// 読んだバイト列を残すReader。読み飛ばしたkvを後で復号するためにレコードのバイト列を取り出す
+struct Recording<R> {
+    inner: R,
+    read: Rc<RefCell<Vec<u8>>>,
+}
+
+impl<R: Read> Read for Recording<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.borrow_mut().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// A record that could not be decoded. The source of the `InvalidData` error of [`OnError::Strict`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordError {
+    /// number of the record
+    pub index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to read record {}: {}", self.index, self.message)
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+impl RecordError {
+    /// `InvalidData`のエラーならその中身を返す
+    pub fn from_io(e: &std::io::Error) -> Option<Self> {
+        (e.kind() == std::io::ErrorKind::InvalidData)
+            .then(|| e.get_ref()?.downcast_ref::<Self>().cloned())
+            .flatten()
+    }
+
+    fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, self)
+    }
+}
+
+/// Records read by [`StorageReader::read_page`].
+#[derive(Debug, Clone, Default)]
+pub struct ReadPage {
+    pub records: Vec<LogRecord>,
+    /// records left out with [`OnError::Skip`]
+    pub skipped: Vec<RecordError>,
+    /// record that ended the page with [`OnError::StopAt`]
+    pub stopped_at: Option<RecordError>,
+}
+
+impl From<Vec<LogRecord>> for ReadPage {
+    fn from(records: Vec<LogRecord>) -> Self {
+        Self {
+            records,
+            ..Default::default()
+        }
+    }
+}
+
+/// Source of the `TimedOut` error returned when a scan passes its [`Deadline`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanTimeout {
+    /// records read before giving up, including the ones skipped to reach the start
+    pub scanned: usize,
+}
+
+impl fmt::Display for ScanTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "scan timed out after {} records", self.scanned)
+    }
+}
+
+impl std::error::Error for ScanTimeout {}
+
+impl ScanTimeout {
+    /// `TimedOut`のエラーならその中身を返す
+    pub fn from_io(e: &std::io::Error) -> Option<Self> {
+        (e.kind() == std::io::ErrorKind::TimedOut)
+            .then(|| e.get_ref()?.downcast_ref::<Self>().copied())
+            .flatten()
+    }
+}
+
+/// `start`番目から並ぶレコードのうち`index`番目から`len`件を集める。
+/// [`DEADLINE_CHECK_INTERVAL`]件ごとに期限を確認する。読めないレコードも1件と数える
+pub fn scan_range<I, R, E>(
+    iter: I,
+    start: usize,
+    index: usize,
+    len: usize,
+    options: ReadOptions,
+) -> Result<ReadPage, std::io::Error>
+where
+    I: Iterator<Item = Result<R, E>>,
+    R: PageRecord,
+    E: fmt::Display,
+{
+    let mut count: usize = 0;
+    let mut page = ReadPage {
+        records: Vec::with_capacity(len.min(DEADLINE_CHECK_INTERVAL)),
+        ..Default::default()
+    };
+    for (scanned, (i, v)) in iter.enumerate().map(|(i, v)| (i + start, v)).enumerate() {
+        if scanned % DEADLINE_CHECK_INTERVAL == DEADLINE_CHECK_INTERVAL - 1
+            && options.deadline.is_expired()
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                ScanTimeout {
+                    scanned: scanned + 1,
+                },
+            ));
+        }
+        if i >= index {
+            match v {
+                Ok(v) => {
+                    let matched = page.records.len();
+                    page.records
+                        .push(v.into_log_record(i).with_matched_index(matched))
+                }
+                Err(e) => {
+                    let e = RecordError {
+                        index: i,
+                        message: e.to_string(),
+                    };
+                    match options.on_error {
+                        OnError::Strict => return Err(e.into_io()),
+                        OnError::Skip => page.skipped.push(e),
+                        OnError::StopAt => {
+                            page.stopped_at = Some(e);
+                            break;
+                        }
+                    }
+                }
+            }
+            count += 1;
+            if count >= len {
+                break;
+            }
+        }
+    }
+    Ok(page)
 }
 
 /// 単純なCBORSequenceFile
-/// index情報など含まれていないので先頭から読むしかない
+/// index fileがあれば目的のレコードの近くから読み、なければ先頭から読む
 pub struct CBORSequenceReader {
-    file: File,
+    file: DataFile,
+    /// (レコード番号, オフセット) 昇順
+    index: Vec<(usize, u64)>,
+    /// 最後の読み出しで末尾にあった書き込み途中のレコード
+    partial: Option<PartialRecord>,
+    /// 読んだレコードの時刻を求めるセッションの開始時刻
+    session_start: Option<DateTime<Utc>>,
 }
 
 impl CBORSequenceReader {
-    #[allow(dead_code)]
+    /// セッションのディレクトリを開く
     pub fn new<P: AsRef<Path>>(dirpath: P) -> Result<Self, std::io::Error> {
-        let file = std::fs::File::open(dirpath.as_ref().join(CBORSequenceWriter::FILENAME))?;
-        Ok(Self { file })
+        let mut file = DataFile::open(dirpath.as_ref())?;
+        let index = match DataFile::open_in(dirpath.as_ref(), CBORSequenceWriter::INDEX_FILENAME) {
+            Ok(f) => read_index(f, file.len()?)?,
+            Err(_) => Vec::new(),
+        };
+        Ok(Self {
+            file,
+            index,
+            partial: None,
+            session_start: None,
+        })
+    }
+
+    /// 読んだレコードにセッションの開始時刻から求めた時刻を付ける
+    pub fn with_session_start(mut self, session_start: DateTime<Utc>) -> Self {
+        self.session_start = Some(session_start);
+        self
+    }
+
+    /// Number of the last record in the index file, 0 without one.
+    ///
+    /// Reading from here finds the end of a large session without reading it from the beginning.
+    pub fn last_indexed(&self) -> usize {
+        self.index.last().map_or(0, |(i, _)| *i)
+    }
+
+    fn with_time(&self, record: LogRecord) -> LogRecord {
+        with_time(self.session_start, record)
+    }
+
+    /// indexから読み始める位置を探す
+    fn seek_position(&self, index: usize) -> (usize, u64) {
+        match self.index.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(pos) => self.index[pos],
+            Err(0) => (0, 0),
+            Err(pos) => self.index[pos - 1],
+        }
+    }
+}
+
+fn with_time(session_start: Option<DateTime<Utc>>, record: LogRecord) -> LogRecord {
+    match session_start {
+        Some(start) => record.with_session_start(start),
+        None => record,
     }
 }
 
+/// Opens a reader of the session.
+///
+/// Records read carry their wall-clock time, see [`crate::RecordView`].
+pub fn open_reader(info: &SessionInfo) -> std::io::Result<CBORSequenceReader> {
+    Ok(CBORSequenceReader::new(info.path())?.with_session_start(session_start(info)))
+}
+
+/// 書き込み途中でデータ本体よりも先に進んでいるindexは無視する
+fn read_index(mut f: DataFile, data_len: u64) -> Result<Vec<(usize, u64)>, std::io::Error> {
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    let index = buf
+        .chunks_exact(16)
+        .map(|x| {
+            let mut id = [0_u8; 8];
+            let mut offset = [0_u8; 8];
+            id.copy_from_slice(&x[..8]);
+            offset.copy_from_slice(&x[8..]);
+            (u64::from_le_bytes(id) as usize, u64::from_le_bytes(offset))
+        })
+        .take_while(|(_, offset)| *offset <= data_len)
+        .collect();
+    Ok(index)
+}
+
 impl From<File> for CBORSequenceReader {
     fn from(file: File) -> Self {
-        Self { file }
+        Self {
+            file: DataFile::Plain(file),
+            index: Vec::new(),
+            partial: None,
+            session_start: None,
+        }
+    }
+}
+
+/// Position after the last record read by [`CBORSequenceReader::read_after`].
+///
+/// Formatted as an opaque string for clients. The default is the head of a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cursor {
+    /// number of the next record
+    pub index: usize,
+    /// offset of the next record in the data file
+    pub offset: u64,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:x}-{:x}", self.index, self.offset)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid cursor {}", s),
+            )
+        };
+        let (index, offset) = s.split_once('-').ok_or_else(invalid)?;
+        Ok(Self {
+            index: usize::from_str_radix(index, 16).map_err(|_| invalid())?,
+            offset: u64::from_str_radix(offset, 16).map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl CBORSequenceReader {
+    /// `cursor`から最大`limit`件を読み、読んだレコードの次の位置を返す。
+    /// 書き込み途中のレコードは読まずに、次はその先頭から読む
+    pub fn read_after(
+        &mut self,
+        cursor: Cursor,
+        limit: usize,
+    ) -> Result<(Vec<LogRecord>, Cursor), std::io::Error> {
+        if cursor.offset > self.file.len()? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("cursor {} is beyond the end of the session", cursor),
+            ));
+        }
+        self.file.seek(SeekFrom::Start(cursor.offset))?;
+        let session_start = self.session_start;
+        let mut iter = serde_cbor::Deserializer::from_reader(BufReader::new(&mut self.file))
+            .into_iter::<Record>();
+        let mut result = Vec::with_capacity(limit.min(DEADLINE_CHECK_INTERVAL));
+        let mut next = cursor;
+        while result.len() < limit {
+            match iter.next() {
+                Some(Ok(record)) => {
+                    let matched = result.len();
+                    result.push(
+                        with_time(session_start, LogRecord::new(next.index, record))
+                            .with_matched_index(matched),
+                    );
+                    next = Cursor {
+                        index: next.index + 1,
+                        offset: cursor.offset + iter.byte_offset() as u64,
+                    };
+                }
+                Some(Err(e)) if e.is_eof() => break,
+                Some(Err(e)) => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                }
+                None => break,
+            }
+        }
+        Ok((result, next))
     }
 }
 
 impl StorageReader for CBORSequenceReader {
     fn read_at(&mut self, index: usize, len: usize) -> Result<Vec<LogRecord>, std::io::Error> {
-        // 先頭から読んで特定のindexから特定の長さのデータを読み出して返す
+        self.read_at_until(index, len, Deadline::default())
+    }
+
+    /// 読めないレコードは飛ばし、警告を残す
+    fn read_at_until(
+        &mut self,
+        index: usize,
+        len: usize,
+        deadline: Deadline,
+    ) -> Result<Vec<LogRecord>, std::io::Error> {
+        let page = self.read_page(index, len, ReadOptions::default().deadline(deadline))?;
+        for e in page.skipped.iter() {
+            warn!("{}", e);
+        }
+        Ok(page.records)
+    }
+
+    fn read_page(
+        &mut self,
+        index: usize,
+        len: usize,
+        options: ReadOptions,
+    ) -> Result<ReadPage, std::io::Error> {
+        // indexで近い位置から読んで特定のindexから特定の長さのデータを読み出して返す
         debug_assert!(len > 0);
-        let mut count: usize = 0;
-        let mut result = Vec::with_capacity(len);
-        self.file.seek(SeekFrom::Start(0))?;
-        let iter = serde_cbor::Deserializer::from_reader(&self.file).into_iter::<Record>();
-        for (i, v) in iter.enumerate() {
-            if i >= index {
-                if let Ok(v) = v {
-                    result.push(LogRecord::new(i, v))
-                } else {
-                    println!("failed to read");
+        let (start, offset) = self.seek_position(index);
+        self.file.seek(SeekFrom::Start(offset))?;
+        if options.lazy_kv {
+            return self.read_page_lazy(start, offset, index, len, options);
+        }
+        let mut iter = serde_cbor::Deserializer::from_reader(&mut self.file).into_iter::<Record>();
+        // 途中で切れているレコードは読み終わりとして扱い、位置を覚えておく
+        let mut partial = None;
+        let mut next_index = start;
+        let records = std::iter::from_fn(|| {
+            let before = iter.byte_offset() as u64;
+            match iter.next()? {
+                Err(e) if e.is_eof() => {
+                    partial = Some(PartialRecord {
+                        index: next_index,
+                        offset: offset + before,
+                    });
+                    None
+                }
+                v => {
+                    next_index += 1;
+                    Some(v)
+                }
+            }
+        });
+        let result = scan_range(records, start, index, len, options);
+        self.partial = partial;
+        let mut page = result?;
+        page.records = std::mem::take(&mut page.records)
+            .into_iter()
+            .map(|x| self.with_time(x))
+            .collect();
+        Ok(page)
+    }
+
+    fn trailing_partial(&self) -> Option<PartialRecord> {
+        self.partial
+    }
+}
+
+impl CBORSequenceReader {
+    /// kvを読み飛ばして`offset`から読む。kvのあるレコードはバイト列を残す
+    fn read_page_lazy(
+        &mut self,
+        start: usize,
+        offset: u64,
+        index: usize,
+        len: usize,
+        options: ReadOptions,
+    ) -> Result<ReadPage, std::io::Error> {
+        let read = Rc::new(RefCell::new(Vec::new()));
+        let reader = Recording {
+            inner: BufReader::new(&mut self.file),
+            read: read.clone(),
+        };
+        let mut iter = serde_cbor::Deserializer::from_reader(reader).into_iter::<RecordHead>();
+        let mut partial = None;
+        let mut next_index = start;
+        // readの先頭のオフセット
+        let mut base = 0;
+        let records = std::iter::from_fn(|| {
+            let before = iter.byte_offset();
+            let v = iter.next()?;
+            let after = iter.byte_offset();
+            let mut read = read.borrow_mut();
+            let end = (after - base).min(read.len());
+            let v = match v {
+                Err(e) if e.is_eof() => {
+                    partial = Some(PartialRecord {
+                        index: next_index,
+                        offset: offset + before as u64,
+                    });
+                    return None;
+                }
+                Ok(head) => Ok(HeadRecord {
+                    kv: head
+                        .kv_skipped
+                        .then(|| LazyKv::new(&read[before - base..end])),
+                    record: head.record,
+                }),
+                Err(e) => Err(e),
+            };
+            // 次のレコードの先読みは残す
+            read.drain(..end);
+            base += end;
+            next_index += 1;
+            Some(v)
+        });
+        let result = scan_range(records, start, index, len, options);
+        self.partial = partial;
+        let mut page = result?;
+        page.records = std::mem::take(&mut page.records)
+            .into_iter()
+            .map(|x| self.with_time(x))
+            .collect();
+        Ok(page)
+    }
+}
+
+/// Reads all records of a session from the beginning.
+///
+/// A record that cannot be decoded is an error with a [`RecordError`] source unless
+/// [`RecordIter::on_error`] says otherwise.
+pub struct RecordIter {
+    inner: StreamDeserializer<'static, IoRead<BufReader<DataFile>>, Record>,
+    blobs: Option<BlobStore>,
+    on_error: OnError,
+    /// 次のレコードの番号
+    index: usize,
+    skipped: Vec<RecordError>,
+    stopped_at: Option<RecordError>,
+}
+
+impl RecordIter {
+    pub(crate) fn new<P: AsRef<Path>>(dirpath: P) -> std::io::Result<Self> {
+        Ok(Self::from_data(DataFile::open(dirpath)?))
+    }
+
+    /// セッションのディレクトリの外にあるデータファイルを読む。暗号化していないものに限る
+    pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::from_data(DataFile::Plain(open_shared_read(path)?)))
+    }
+
+    /// Reads a CBOR sequence of records from `reader`, e.g. the raw data of a client on stdin.
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Self {
+        Self::from_data(DataFile::Stream(Box::new(reader)))
+    }
+
+    /// `offset`から始まる`index`番目のレコードから読む
+    pub(crate) fn from_offset<P: AsRef<Path>>(
+        dirpath: P,
+        index: usize,
+        offset: u64,
+    ) -> std::io::Result<Self> {
+        let mut file = DataFile::open(dirpath)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(Self {
+            index,
+            ..Self::from_data(file)
+        })
+    }
+
+    fn from_data(file: DataFile) -> Self {
+        Self {
+            inner: serde_cbor::Deserializer::from_reader(BufReader::new(file)).into_iter(),
+            blobs: None,
+            on_error: OnError::Strict,
+            index: 0,
+            skipped: Vec::new(),
+            stopped_at: None,
+        }
+    }
+
+    /// What to do with a record that cannot be decoded. Defaults to [`OnError::Strict`].
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    /// Records left out so far with [`OnError::Skip`].
+    pub fn skipped(&self) -> &[RecordError] {
+        &self.skipped
+    }
+
+    /// Record that ended the iteration with [`OnError::StopAt`].
+    pub fn stopped_at(&self) -> Option<&RecordError> {
+        self.stopped_at.as_ref()
+    }
+
+    /// 分離して保存したblobをレコードに戻して返す
+    pub fn reinline_blobs(mut self, blobs: BlobStore) -> Self {
+        self.blobs = Some(blobs);
+        self
+    }
+}
+
+impl Iterator for RecordIter {
+    type Item = std::io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = loop {
+            if self.stopped_at.is_some() {
+                return None;
+            }
+            let index = self.index;
+            let next = self.inner.next()?;
+            self.index += 1;
+            match next {
+                Ok(x) => break x,
+                Err(e) => {
+                    let e = RecordError {
+                        index,
+                        message: e.to_string(),
+                    };
+                    match self.on_error {
+                        OnError::Strict => return Some(Err(e.into_io())),
+                        OnError::Skip => self.skipped.push(e),
+                        OnError::StopAt => self.stopped_at = Some(e),
+                    }
                 }
-                count += 1;
-                if count >= len {
-                    break;
+            }
+        };
+        if let Some(blobs) = self.blobs.as_ref() {
+            if let Err(e) = blobs.reinline(&mut record) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(record))
+    }
+}
+
+/// CBORの項目を先頭から順に診断表記で1行ずつ書く。デコードできない位置で止め、その位置と理由を書く
+pub fn render_diagnostic(buf: &[u8]) -> String {
+    let mut out = String::new();
+    let mut offset = 0;
+    while offset < buf.len() {
+        match decode_item(&buf[offset..]) {
+            Ok((value, len)) => {
+                write_diagnostic(&value, &mut out);
+                out.push('\n');
+                offset += len;
+            }
+            Err(e) => {
+                out.push_str(&decode_error(offset, &e));
+                out.push('\n');
+                break;
+            }
+        }
+    }
+    out
+}
+
+/// 先頭の項目とその長さ
+fn decode_item(buf: &[u8]) -> Result<(serde_cbor::Value, usize), serde_cbor::Error> {
+    use serde::Deserialize;
+    let mut de = serde_cbor::Deserializer::from_slice(buf);
+    let value = serde_cbor::Value::deserialize(&mut de)?;
+    Ok((value, de.byte_offset()))
+}
+
+/// `offset`は`e`の位置の基準になる項目の先頭
+fn decode_error(offset: usize, e: &serde_cbor::Error) -> String {
+    format!(
+        "decode failed at byte {}: {}",
+        offset as u64 + e.offset(),
+        e
+    )
+}
+
+/// RFC 8949の診断表記
+fn write_diagnostic(value: &serde_cbor::Value, out: &mut String) {
+    use serde_cbor::Value;
+    use std::fmt::Write;
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(x) => write!(out, "{}", x).unwrap(),
+        Value::Integer(x) => write!(out, "{}", x).unwrap(),
+        Value::Float(x) if x.is_nan() => out.push_str("NaN"),
+        Value::Float(x) if x.is_infinite() => {
+            out.push_str(if *x > 0.0 { "Infinity" } else { "-Infinity" })
+        }
+        Value::Float(x) => write!(out, "{:?}", x).unwrap(),
+        Value::Bytes(x) => {
+            out.push_str("h'");
+            x.iter().for_each(|b| write!(out, "{:02x}", b).unwrap());
+            out.push('\'');
+        }
+        Value::Text(x) => write!(out, "{:?}", x).unwrap(),
+        Value::Array(x) => {
+            out.push('[');
+            for (i, v) in x.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
                 }
+                write_diagnostic(v, out);
+            }
+            out.push(']');
+        }
+        Value::Map(x) => {
+            out.push('{');
+            for (i, (k, v)) in x.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_diagnostic(k, out);
+                out.push_str(": ");
+                write_diagnostic(v, out);
+            }
+            out.push('}');
+        }
+        Value::Tag(tag, v) => {
+            write!(out, "{}(", tag).unwrap();
+            write_diagnostic(v, out);
+            out.push(')');
+        }
+        // 非公開の列挙子がある
+        _ => write!(out, "{:?}", value).unwrap(),
+    }
+}
+
+/// A CBOR item of a data file, read by [`inspect`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InspectedItem<'a> {
+    /// offset of the item in the buffer
+    pub offset: usize,
+    /// bytes of the item, or the rest of the buffer when it could not be decoded
+    pub bytes: &'a [u8],
+    /// diagnostic notation of the item
+    pub diagnostic: Option<String>,
+    /// why the item could not be decoded, or why it is not a record
+    pub error: Option<String>,
+}
+
+/// `offset`から`count`件の項目を読む。デコードできない項目で止める
+pub fn inspect(buf: &[u8], offset: usize, count: usize) -> Vec<InspectedItem<'_>> {
+    let mut items = Vec::new();
+    let mut offset = offset.min(buf.len());
+    while items.len() < count && offset < buf.len() {
+        match decode_item(&buf[offset..]) {
+            Ok((value, len)) => {
+                let mut diagnostic = String::new();
+                write_diagnostic(&value, &mut diagnostic);
+                // 途中から読むと別の型の項目として読めてしまうことがある
+                let error = serde_cbor::value::from_value::<Record>(value)
+                    .err()
+                    .map(|e| format!("not a record: {}", e));
+                items.push(InspectedItem {
+                    offset,
+                    bytes: &buf[offset..offset + len],
+                    diagnostic: Some(diagnostic),
+                    error,
+                });
+                offset += len;
+            }
+            Err(e) => {
+                items.push(InspectedItem {
+                    offset,
+                    bytes: &buf[offset..],
+                    diagnostic: None,
+                    error: Some(decode_error(offset, &e)),
+                });
+                break;
             }
         }
-        Ok(result)
     }
+    items
+}
+
+/// `index`番目の項目の位置。そこまで読めなければNone
+pub fn item_offset(buf: &[u8], index: usize) -> Option<usize> {
+    let mut offset = 0;
+    for _ in 0..index {
+        offset += decode_item(buf.get(offset..)?).ok()?.1;
+    }
+    (offset < buf.len()).then_some(offset)
 }
 
 #[cfg(test)]
 mod tests {
     use tempdir::TempDir;
-    use uplog::{devlog, Level, Value};
+    use uplog::{devlog, KvExt, Level};
 
     use crate::writer::{CBORSequenceWriter, RecordWriter};
 
-    use super::{CBORSequenceReader, StorageReader};
+    use super::{
+        inspect, item_offset, render_diagnostic, CBORSequenceReader, OnError, PartialRecord,
+        ReadOptions, RecordError, RecordIter, StorageReader,
+    };
     #[test]
     fn test_cbor_seq_read() -> std::io::Result<()> {
         uplog::session_init();
@@ -74,22 +985,21 @@ mod tests {
         let file_path = dir.path();
 
         // make testdata
-        let mut writer = CBORSequenceWriter::new(&file_path).unwrap();
+        let mut writer = CBORSequenceWriter::new(file_path).unwrap();
         for i in 0..10_u8 {
             let r = devlog!(Level::Info, "cat", &format!("nyan {}", i), "number", i);
             writer.push(&r)?;
         }
         drop(writer);
 
-        let mut reader = CBORSequenceReader::new(&file_path)?;
+        let mut reader = CBORSequenceReader::new(file_path)?;
 
         // check index
         for start in 0..10 {
             let data = reader.read_at(start, 10)?;
             assert_eq!(10 - start, data.len());
-            if let Some(Value::U64(ref v)) = data[0].record.key_values().unwrap().get("number") {
-                assert_eq!(start as u64, *v);
-            }
+            let kv = data[0].record.key_values().unwrap();
+            assert_eq!(kv.get_u64("number"), Some(start as u64));
         }
         // check len
         for len in 1..10 {
@@ -99,4 +1009,298 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_cbor_seq_read_with_index() -> std::io::Result<()> {
+        uplog::session_init();
+        let dir = TempDir::new("testdata")?;
+        let file_path = dir.path();
+        let total = CBORSequenceWriter::INDEX_INTERVAL * 3 + 5;
+
+        let mut writer = CBORSequenceWriter::new(file_path).unwrap();
+        for i in 0..total {
+            let r = devlog!(Level::Info, "cat", "nyan", "number", i as u64);
+            writer.push(&r)?;
+        }
+        drop(writer);
+
+        let mut reader = CBORSequenceReader::new(file_path)?;
+        assert_eq!(reader.index.len(), 4);
+        assert_eq!(
+            reader.last_indexed(),
+            CBORSequenceWriter::INDEX_INTERVAL * 3
+        );
+        for start in [0, 1, 63, 64, 65, 130, total - 1] {
+            let data = reader.read_at(start, 3)?;
+            assert_eq!(data[0].id, start);
+            assert_eq!(data[0].matched_index(), 0);
+            let kv = data[0].record.key_values().unwrap();
+            assert_eq!(kv.get_u64("number"), Some(start as u64));
+        }
+        assert!(reader.read_at(total, 3)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cbor_seq_read_trailing_partial() -> std::io::Result<()> {
+        use std::io::Write;
+        uplog::session_init();
+        let dir = TempDir::new("testdata")?;
+        let file_path = dir.path();
+
+        let mut writer = CBORSequenceWriter::new(file_path).unwrap();
+        for i in 0..3_u64 {
+            writer.push(&devlog!(Level::Info, "cat", "nyan", "number", i))?;
+        }
+        drop(writer);
+        let data_path = file_path.join(CBORSequenceWriter::FILENAME);
+        let complete = std::fs::metadata(&data_path)?.len();
+
+        // 4件目の前半だけ書き出された状態
+        let buf = serde_cbor::to_vec(&devlog!(Level::Info, "cat", "nyan", "number", 3_u64))
+            .map_err(std::io::Error::other)?;
+        let (head, tail) = buf.split_at(buf.len() / 2);
+        let mut f = std::fs::OpenOptions::new().append(true).open(&data_path)?;
+        f.write_all(head)?;
+
+        let mut reader = CBORSequenceReader::new(file_path)?;
+        let data = reader.read_at(0, 10)?;
+        assert_eq!(data.len(), 3);
+        assert_eq!(
+            reader.trailing_partial(),
+            Some(PartialRecord {
+                index: 3,
+                offset: complete
+            })
+        );
+
+        // 残りが書き出されれば読める
+        f.write_all(tail)?;
+        let data = reader.read_at(0, 10)?;
+        assert_eq!(data.len(), 4);
+        assert_eq!(
+            data[3].record.key_values().unwrap().get_u64("number"),
+            Some(3)
+        );
+        assert_eq!(reader.trailing_partial(), None);
+        Ok(())
+    }
+
+    /// 3番目の項目がレコードではない6件のデータ
+    fn corrupted_fixture(file_path: &std::path::Path) -> std::io::Result<()> {
+        let mut writer = CBORSequenceWriter::new(file_path).unwrap();
+        for i in 0..3_u64 {
+            writer.push(&devlog!(Level::Info, "cat", "nyan", "number", i))?;
+        }
+        drop(writer);
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(file_path.join(CBORSequenceWriter::FILENAME))?;
+        serde_cbor::to_writer(&mut f, &"broken").map_err(std::io::Error::other)?;
+        for i in 3..5_u64 {
+            serde_cbor::to_writer(&mut f, &devlog!(Level::Info, "cat", "nyan", "number", i))
+                .map_err(std::io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cbor_seq_read_on_error() -> std::io::Result<()> {
+        uplog::session_init();
+        let dir = TempDir::new("testdata")?;
+        corrupted_fixture(dir.path())?;
+        let mut reader = CBORSequenceReader::new(dir.path())?;
+        let ids = |records: &[crate::LogRecord]| records.iter().map(|x| x.id).collect::<Vec<_>>();
+        let options = |on_error| ReadOptions::default().on_error(on_error);
+
+        // Skip: 読めないレコードを飛ばして知らせる
+        let page = reader.read_page(0, 10, options(OnError::Skip))?;
+        assert_eq!(ids(&page.records), vec![0, 1, 2, 4, 5]);
+        assert_eq!(page.records[3].matched_index(), 3);
+        assert_eq!(
+            page.skipped.iter().map(|x| x.index).collect::<Vec<_>>(),
+            vec![3]
+        );
+        assert_eq!(page.stopped_at, None);
+        // 読めないレコードも1件と数える
+        let page = reader.read_page(2, 3, options(OnError::Skip))?;
+        assert_eq!(ids(&page.records), vec![2, 4]);
+        assert_eq!(ids(&reader.read_at(0, 10)?), vec![0, 1, 2, 4, 5]);
+
+        // Strict: レコードの番号を持つエラー
+        let e = reader
+            .read_page(1, 10, options(OnError::Strict))
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        let source = RecordError::from_io(&e).unwrap();
+        assert_eq!(source.index, 3);
+        assert!(
+            e.to_string().starts_with("failed to read record 3:"),
+            "{}",
+            e
+        );
+        // 範囲の外にあれば読める
+        let page = reader.read_page(4, 10, options(OnError::Strict))?;
+        assert_eq!(ids(&page.records), vec![4, 5]);
+
+        // StopAt: 読めないレコードの手前で終わる
+        let page = reader.read_page(0, 10, options(OnError::StopAt))?;
+        assert_eq!(ids(&page.records), vec![0, 1, 2]);
+        assert!(page.skipped.is_empty());
+        assert_eq!(page.stopped_at.map(|x| x.index), Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cbor_seq_read_lazy_kv() -> std::io::Result<()> {
+        use std::io::Write;
+        uplog::session_init();
+        let dir = TempDir::new("testdata")?;
+        let file_path = dir.path();
+        let total = CBORSequenceWriter::INDEX_INTERVAL * 2 + 3;
+
+        let mut writer = CBORSequenceWriter::new(file_path).unwrap();
+        for i in 0..total {
+            let r = devlog!(Level::Info, "cat", "nyan", "number", i as u64, "text", "x");
+            writer.push(&r)?;
+        }
+        drop(writer);
+        let data_path = file_path.join(CBORSequenceWriter::FILENAME);
+        let buf = serde_cbor::to_vec(&devlog!(Level::Info, "cat", "nyan", "number", 0_u64))
+            .map_err(std::io::Error::other)?;
+        let mut f = std::fs::OpenOptions::new().append(true).open(&data_path)?;
+        f.write_all(&buf[..buf.len() / 2])?;
+
+        let mut reader = CBORSequenceReader::new(file_path)?;
+        let lazy = ReadOptions::default().lazy_kv(true);
+        for (start, len) in [(0, 5), (63, 4), (CBORSequenceWriter::INDEX_INTERVAL, 100)] {
+            let eager = reader.read_page(start, len, ReadOptions::default())?;
+            let page = reader.read_page(start, len, lazy)?;
+            assert_eq!(page.records.len(), eager.records.len());
+            for (l, e) in page.records.into_iter().zip(eager.records) {
+                assert_eq!(l.id, e.id);
+                // `_`で始まらないkvは読まずに残す
+                assert!(l.as_record().key_values().unwrap().is_empty());
+                assert_eq!(l.kv(), e.kv());
+                assert_eq!(
+                    serde_json::to_string(&l).unwrap(),
+                    serde_json::to_string(&e).unwrap()
+                );
+                assert_eq!(
+                    serde_json::to_string(&l.into_record()).unwrap(),
+                    serde_json::to_string(&e.into_record()).unwrap()
+                );
+            }
+        }
+        assert_eq!(reader.trailing_partial().map(|x| x.index), Some(total));
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_iter_on_error() -> std::io::Result<()> {
+        uplog::session_init();
+        let dir = TempDir::new("testdata")?;
+        corrupted_fixture(dir.path())?;
+        let numbers = |records: Vec<uplog::Record>| {
+            records
+                .iter()
+                .map(|x| x.key_values().unwrap().get_u64("number").unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let results = RecordIter::new(dir.path())?.collect::<Vec<_>>();
+        assert_eq!(results.len(), 6);
+        let e = results[3].as_ref().unwrap_err();
+        assert_eq!(RecordError::from_io(e).map(|x| x.index), Some(3));
+        assert_eq!(results.iter().filter(|x| x.is_err()).count(), 1);
+
+        let mut iter = RecordIter::new(dir.path())?.on_error(OnError::Skip);
+        let records = iter.by_ref().collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(numbers(records), vec![0, 1, 2, 3, 4]);
+        assert_eq!(iter.skipped().len(), 1);
+        assert_eq!(iter.skipped()[0].index, 3);
+
+        let mut iter = RecordIter::new(dir.path())?.on_error(OnError::StopAt);
+        let records = iter.by_ref().collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(numbers(records), vec![0, 1, 2]);
+        assert_eq!(iter.stopped_at().map(|x| x.index), Some(3));
+        assert!(iter.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_diagnostic() {
+        let value = serde_cbor::Value::Map(
+            [
+                (
+                    serde_cbor::Value::Text("a".to_string()),
+                    serde_cbor::Value::Array(vec![
+                        serde_cbor::Value::Integer(-1),
+                        serde_cbor::Value::Float(1.5),
+                        serde_cbor::Value::Null,
+                    ]),
+                ),
+                (
+                    serde_cbor::Value::Text("b".to_string()),
+                    serde_cbor::Value::Bytes(vec![0xde, 0xad]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let mut buf = serde_cbor::to_vec(&value).unwrap();
+        buf.extend(serde_cbor::to_vec(&true).unwrap());
+        assert_eq!(
+            render_diagnostic(&buf),
+            "{\"a\": [-1, 1.5, null], \"b\": h'dead'}\ntrue\n"
+        );
+        // 文字列の途中で切れている
+        let text = serde_cbor::to_vec(&"hello").unwrap();
+        assert_eq!(
+            render_diagnostic(&text[..3]),
+            "decode failed at byte 3: EOF while parsing a value at offset 3\n"
+        );
+    }
+
+    #[test]
+    fn test_inspect() {
+        uplog::session_init();
+        let mut buf = Vec::new();
+        for i in 0..3_u32 {
+            serde_cbor::to_writer(&mut buf, &devlog!(Level::Info, "cat", "msg", "n", i)).unwrap();
+        }
+        let second = item_offset(&buf, 1).unwrap();
+        let third = item_offset(&buf, 2).unwrap();
+        assert_eq!(item_offset(&buf, 3), None);
+
+        // 正しいレコード
+        let items = inspect(&buf, second, 5);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].offset, second);
+        assert_eq!(items[0].bytes, &buf[second..third]);
+        assert_eq!(items[0].error, None);
+        assert!(items[0]
+            .diagnostic
+            .as_ref()
+            .unwrap()
+            .contains("\"category\": \"cat\""));
+        assert_eq!(inspect(&buf, 0, 1).len(), 1);
+
+        // 最後のレコードが途中で切れている
+        let truncated = &buf[..buf.len() - 2];
+        let items = inspect(truncated, third, 1);
+        assert_eq!(items[0].bytes, &truncated[third..]);
+        assert_eq!(items[0].diagnostic, None);
+        let error = items[0].error.as_ref().unwrap();
+        assert!(
+            error.starts_with(&format!("decode failed at byte {}:", truncated.len())),
+            "{}",
+            error
+        );
+
+        // レコードの途中から読むと、読めてもレコードではないか、読めない
+        let items = inspect(&buf, second + 1, 1);
+        assert_eq!(items[0].offset, second + 1);
+        assert!(items[0].error.is_some(), "{:?}", items[0]);
+    }
 }
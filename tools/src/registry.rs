@@ -0,0 +1,167 @@
+//! 書き込み中のセッションの登録と更新の通知
+//!
+//! 新しいレコードを待つ読み出しはセッション名で登録し、書き込み側はファイルに書き出した後に起こす。
+//! 書き込み側は待っている読み出しがある場合だけ書き出すので、待つ側がいなければ書き込みは変わらない。
+//! 書き込み側は開いている間登録しておき、一覧ではまだ書き込み中かを返す
+use std::{collections::HashMap, sync::Mutex};
+
+#[cfg(feature = "web")]
+use async_graphql::SimpleObject;
+use futures::channel::oneshot;
+
+/// Readers and writers registered now.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct RegistryGauges {
+    /// sessions with a waiting reader
+    pub waiting_sessions: u64,
+    /// readers waiting for new records
+    pub waiters: u64,
+    /// sessions held open by a writer
+    pub open_sessions: u64,
+}
+
+/// Wakes readers waiting for new records of a session.
+#[derive(Debug, Default)]
+pub struct SessionRegistry {
+    waiters: Mutex<HashMap<String, Vec<oneshot::Sender<()>>>>,
+    /// 開いている書き込み側の数
+    writers: Mutex<HashMap<String, usize>>,
+}
+
+impl SessionRegistry {
+    /// 書き込み側がセッションを開いた
+    pub(crate) fn open(&self, name: &str) {
+        *self
+            .writers
+            .lock()
+            .expect("session registry lock")
+            .entry(name.to_string())
+            .or_default() += 1;
+    }
+
+    /// 書き込み側がセッションを閉じた
+    pub(crate) fn close(&self, name: &str) {
+        let mut writers = self.writers.lock().expect("session registry lock");
+        if let Some(count) = writers.get_mut(name) {
+            *count -= 1;
+            if *count == 0 {
+                writers.remove(name);
+            }
+        }
+    }
+
+    /// Whether a writer still holds the session open.
+    pub fn is_open(&self, name: &str) -> bool {
+        self.writers
+            .lock()
+            .expect("session registry lock")
+            .contains_key(name)
+    }
+
+    /// Returns a receiver completed by the next [`SessionRegistry::notify`] of `name`.
+    ///
+    /// Subscribe before reading, so that records written in between are not missed.
+    pub fn subscribe(&self, name: &str) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let mut waiters = self.waiters.lock().expect("session registry lock");
+        // 待つのをやめた読み出しと、書き込まれないまま誰も待たなくなったセッションを除く
+        waiters.retain(|_, list| {
+            list.retain(|x| !x.is_canceled());
+            !list.is_empty()
+        });
+        waiters.entry(name.to_string()).or_default().push(tx);
+        rx
+    }
+
+    /// 待っている読み出しがあるか
+    pub fn has_waiters(&self, name: &str) -> bool {
+        self.waiters
+            .lock()
+            .expect("session registry lock")
+            .get(name)
+            .is_some_and(|x| x.iter().any(|x| !x.is_canceled()))
+    }
+
+    pub fn gauges(&self) -> RegistryGauges {
+        let waiters = self.waiters.lock().expect("session registry lock");
+        RegistryGauges {
+            waiting_sessions: waiters.len() as u64,
+            waiters: waiters.values().map(|x| x.len() as u64).sum(),
+            open_sessions: self.writers.lock().expect("session registry lock").len() as u64,
+        }
+    }
+
+    /// Wakes all readers waiting for `name`.
+    pub fn notify(&self, name: &str) {
+        let waiters = self
+            .waiters
+            .lock()
+            .expect("session registry lock")
+            .remove(name);
+        for tx in waiters.into_iter().flatten() {
+            tx.send(()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::{RegistryGauges, SessionRegistry};
+
+    #[test]
+    fn test_notify() {
+        let registry = SessionRegistry::default();
+        assert!(!registry.has_waiters("a"));
+        let a = registry.subscribe("a");
+        let b = registry.subscribe("b");
+        assert!(registry.has_waiters("a"));
+
+        registry.notify("a");
+        assert!(block_on(a).is_ok());
+        assert!(!registry.has_waiters("a"));
+        assert!(registry.has_waiters("b"));
+
+        // 待つのをやめたら書き込み側は書き出さなくてよい
+        drop(b);
+        assert!(!registry.has_waiters("b"));
+    }
+
+    #[test]
+    fn test_prune_waiters() {
+        let registry = SessionRegistry::default();
+        for i in 0..100 {
+            drop(registry.subscribe(&format!("gone-{}", i)));
+        }
+        let _a = registry.subscribe("a");
+        // 書き込まれなかったセッションの登録は次に待つときに除く
+        assert_eq!(
+            registry.gauges(),
+            RegistryGauges {
+                waiting_sessions: 1,
+                waiters: 1,
+                open_sessions: 0,
+            }
+        );
+        registry.open("a");
+        assert_eq!(registry.gauges().open_sessions, 1);
+        registry.close("a");
+        assert_eq!(registry.gauges().open_sessions, 0);
+    }
+
+    #[test]
+    fn test_open_writers() {
+        let registry = SessionRegistry::default();
+        registry.open("a");
+        registry.open("a");
+        registry.close("a");
+        assert!(registry.is_open("a"));
+        registry.close("a");
+        assert!(!registry.is_open("a"));
+        // 開いていないセッションを閉じても何もしない
+        registry.close("b");
+        assert!(!registry.is_open("b"));
+    }
+}
@@ -0,0 +1,253 @@
+//! 保存したセッションを別のサーバーへ送り直す
+//!
+//! データの移行やサーバーの不具合の再現に使う。
+//! レコードの`elapsed`の間隔を保って送るか、待たずにまとめて送る
+use std::{
+    fmt::Display,
+    io,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use uplog::Record;
+
+/// How fast records are replayed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// keep the recorded intervals divided by this factor
+    Scaled(f64),
+    /// send without waiting
+    Asap,
+}
+
+impl Default for ReplaySpeed {
+    fn default() -> Self {
+        Self::Scaled(1.0)
+    }
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = String;
+
+    /// `asap`、`10x`または`0.5`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "asap" {
+            return Ok(Self::Asap);
+        }
+        match s.strip_suffix('x').unwrap_or(s).parse::<f64>() {
+            Ok(x) if x.is_finite() && x > 0.0 => Ok(Self::Scaled(x)),
+            _ => Err(format!(
+                "invalid speed {}, expected asap or a positive factor such as 10x",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for ReplaySpeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Scaled(x) => write!(f, "{}x", x),
+            Self::Asap => write!(f, "asap"),
+        }
+    }
+}
+
+/// Counts of a replay.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub records: u64,
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// 送り直す。`send`には1メッセージ分のCBOR Sequenceを渡す
+///
+/// 送るたびに`progress`を呼ぶ
+pub fn replay<I, S, P>(
+    records: I,
+    speed: ReplaySpeed,
+    mut send: S,
+    mut progress: P,
+) -> io::Result<ReplayStats>
+where
+    I: IntoIterator<Item = Record>,
+    S: FnMut(&[u8]) -> io::Result<()>,
+    P: FnMut(&ReplayStats),
+{
+    // クライアントの送信単位を超えないようにまとめる
+    let max_message_bytes = uplog::DEFAULT_BUFFER_SIZE;
+    let mut stats = ReplayStats::default();
+    let mut buf = Vec::new();
+    let mut flush = |buf: &mut Vec<u8>, stats: &mut ReplayStats| -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        send(buf)?;
+        stats.messages += 1;
+        stats.bytes += buf.len() as u64;
+        buf.clear();
+        progress(stats);
+        Ok(())
+    };
+    // 最初のレコードを送った時刻とそのelapsed
+    let mut origin: Option<(Instant, Duration)> = None;
+    for record in records {
+        if let ReplaySpeed::Scaled(factor) = speed {
+            let (start, first) = *origin.get_or_insert((Instant::now(), record.elapsed()));
+            let due = start + record.elapsed().saturating_sub(first).div_f64(factor);
+            let now = Instant::now();
+            if due > now {
+                // 待つ前にそれまでの分を送る
+                flush(&mut buf, &mut stats)?;
+                thread::sleep(due - now);
+            }
+        }
+        let encoded = serde_cbor::to_vec(&record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if !buf.is_empty() && buf.len() + encoded.len() > max_message_bytes {
+            flush(&mut buf, &mut stats)?;
+        }
+        buf.extend_from_slice(&encoded);
+        stats.records += 1;
+    }
+    flush(&mut buf, &mut stats)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tempdir::TempDir;
+    use tungstenite::{connect, Message};
+    use uplog::Record;
+
+    use super::{replay, ReplaySpeed};
+    use crate::{
+        lifecycle::is_server_record,
+        server::{run_server, ServerOptions},
+        testing::wait_for,
+        writer::RecordWriter,
+        Storage,
+    };
+
+    #[test]
+    fn test_replay_speed() {
+        assert_eq!("asap".parse(), Ok(ReplaySpeed::Asap));
+        assert_eq!("10x".parse(), Ok(ReplaySpeed::Scaled(10.0)));
+        assert_eq!("0.5".parse(), Ok(ReplaySpeed::Scaled(0.5)));
+        for x in ["0x", "-1x", "fast", "infx", ""] {
+            assert!(x.parse::<ReplaySpeed>().is_err(), "{}", x);
+        }
+        assert_eq!(ReplaySpeed::Scaled(10.0).to_string(), "10x");
+    }
+
+    fn records(count: u64, interval: Duration) -> Vec<Record> {
+        (0..count)
+            .map(|i| {
+                Record::builder()
+                    .category("replay")
+                    .message("msg")
+                    .elapsed(Duration::from_secs(1) + interval * i as u32)
+                    .kv("i", i)
+                    .build()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_replay_timing() {
+        let records = records(5, Duration::from_millis(100));
+        let mut sent = Vec::new();
+        let start = Instant::now();
+        let stats = replay(
+            records.clone(),
+            ReplaySpeed::Scaled(10.0),
+            |buf| {
+                sent.push((start.elapsed(), buf.to_vec()));
+                Ok(())
+            },
+            |_| {},
+        )
+        .unwrap();
+        // 間隔が10msになるので1レコードずつ送る
+        assert_eq!(stats.records, 5);
+        assert_eq!(stats.messages, 5);
+        assert!(sent[4].0 >= Duration::from_millis(40), "{:?}", sent[4].0);
+
+        // 待たない場合は1メッセージにまとめる
+        let mut progress = Vec::new();
+        let stats = replay(
+            records.clone(),
+            ReplaySpeed::Asap,
+            |_| Ok(()),
+            |x| progress.push(*x),
+        )
+        .unwrap();
+        assert_eq!(stats.messages, 1);
+        assert_eq!(progress, vec![stats]);
+        let expect = records
+            .iter()
+            .flat_map(|x| serde_cbor::to_vec(x).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(stats.bytes, expect.len() as u64);
+    }
+
+    /// 保存したセッションを受信サーバーに送り直し、同じレコードが保存されることを確認する
+    #[test]
+    fn test_replay_to_server() {
+        let src_dir = TempDir::new("replay_src").unwrap();
+        let src = Storage::new(src_dir.path()).unwrap();
+        {
+            let mut session = src.create_session("source").unwrap();
+            for r in records(50, Duration::from_millis(1)) {
+                session.push(&r).unwrap();
+            }
+        }
+
+        let dst_dir = TempDir::new("replay_dst").unwrap();
+        let server = run_server(ServerOptions::new(dst_dir.path())).unwrap();
+        let dst = server.storage().clone();
+
+        let expect = src
+            .session_records("source")
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let (mut client, _) = connect(format!(
+            "ws://127.0.0.1:{}{}",
+            server.port(),
+            uplog::WS_PATH
+        ))
+        .unwrap();
+        let stats = replay(
+            expect.clone(),
+            ReplaySpeed::Scaled(20.0),
+            |buf| {
+                client
+                    .write_message(Message::binary(buf))
+                    .map_err(std::io::Error::other)
+            },
+            |_| {},
+        )
+        .unwrap();
+        client.close(None).unwrap();
+        assert_eq!(stats.records, 50);
+
+        let received = wait_for(|| {
+            let names = dst.records().ok()?;
+            // データファイルはセッションのディレクトリより後に作られる
+            let received = dst
+                .session_records(&names.first()?.path().file_name()?.to_string_lossy())
+                .ok()?
+                .filter_map(Result::ok)
+                .filter(|x| !is_server_record(x))
+                .collect::<Vec<_>>();
+            (received.len() == expect.len()).then_some(received)
+        });
+        assert_eq!(received, expect);
+        server.stop(false).unwrap();
+    }
+}
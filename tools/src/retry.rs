@@ -0,0 +1,464 @@
+//! 書き込みに失敗したレコードの再試行
+//!
+//! ファイルシステムの一時的な失敗でレコードを失わないよう、失敗したレコードを順番を保って溜めておき、間隔をあけて書き直す。
+//! メモリに溜める量を超えた分はセッションディレクトリの一時ファイルに書き出し、書き込めるようになったら読み戻す。
+//! 暗号化したセッションは平文を残さないように書き出さず、溢れた分は失う
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+#[cfg(feature = "web")]
+use async_graphql::SimpleObject;
+use log::{error, warn};
+use uplog::Record;
+
+use crate::writer::RecordWriter;
+
+/// 溢れたレコードを書き出すファイル名。書き直し終えたら消す
+pub const SPILL_FILENAME: &str = "retry.spill";
+
+/// How a session retries records it failed to write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// retries of a record before it is dropped
+    pub max_retries: u32,
+    /// wait before the first retry, doubled on each failure
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// records over this many bytes waiting for a retry go to a file in the session directory
+    pub max_queue_bytes: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            max_queue_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// `failures`回失敗した後に待つ時間
+    pub(crate) fn backoff(&self, failures: u32) -> Duration {
+        let factor = 1_u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static SPILLED: AtomicU64 = AtomicU64::new(0);
+static LOST: AtomicU64 = AtomicU64::new(0);
+
+/// Counts of records the server failed to write, over all sessions since it started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct RetryStats {
+    /// writes retried after a failure
+    pub retries: u64,
+    /// records written to the spill file while waiting for a retry
+    pub spilled: u64,
+    /// records dropped after all retries failed
+    pub lost: u64,
+}
+
+pub fn retry_stats() -> RetryStats {
+    RetryStats {
+        retries: RETRIES.load(Ordering::Relaxed),
+        spilled: SPILLED.load(Ordering::Relaxed),
+        lost: LOST.load(Ordering::Relaxed),
+    }
+}
+
+/// 溢れたレコードのファイル。末尾に追記し、先頭から読み戻す
+struct Spill {
+    path: PathBuf,
+    file: File,
+    /// 次に読み戻すレコードの位置
+    read_offset: u64,
+    count: usize,
+}
+
+impl Spill {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            read_offset: 0,
+            count: 0,
+        })
+    }
+
+    fn push(&mut self, record: &Record) -> io::Result<()> {
+        serde_cbor::to_writer(&mut self.file, record).map_err(io::Error::other)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// 先頭のレコードと、その次の位置
+    fn peek(&self) -> io::Result<(Record, u64)> {
+        let mut f = File::open(&self.path)?;
+        f.seek(SeekFrom::Start(self.read_offset))?;
+        let mut iter =
+            serde_cbor::Deserializer::from_reader(BufReader::new(f)).into_iter::<Record>();
+        match iter.next() {
+            Some(Ok(record)) => Ok((record, self.read_offset + iter.byte_offset() as u64)),
+            Some(Err(e)) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "spill file is shorter than expected",
+            )),
+        }
+    }
+
+    fn remove(self) {
+        drop(self.file);
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("failed to remove {}: {}", self.path.to_string_lossy(), e);
+        }
+    }
+}
+
+/// 書き込みに失敗したレコードを順番に溜めて書き直す
+pub(crate) struct RetryQueue {
+    policy: RetryPolicy,
+    /// 溢れた分を書き出すファイル。なければ失う
+    spill_path: Option<PathBuf>,
+    queue: VecDeque<(Record, usize)>,
+    queued_bytes: usize,
+    /// メモリに溜めたレコードより後に受け取ったレコード
+    spill: Option<Spill>,
+    /// 先頭のレコードの失敗した回数
+    failures: u32,
+    /// 付加情報にまだ記録していない失ったレコード数
+    lost: u64,
+}
+
+impl RetryQueue {
+    pub(crate) fn new(policy: RetryPolicy, session_dir: &Path) -> Self {
+        Self {
+            policy,
+            spill_path: Some(session_dir.join(SPILL_FILENAME)),
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+            spill: None,
+            failures: 0,
+            lost: 0,
+        }
+    }
+
+    /// 溢れた分をファイルに書き出さずに失う
+    pub(crate) fn without_spill(mut self) -> Self {
+        self.spill_path = None;
+        self
+    }
+
+    pub(crate) fn policy(&self) -> RetryPolicy {
+        self.policy
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queue.is_empty() && self.spill.is_none()
+    }
+
+    /// 書き直しを待っているレコードがあればその後ろに並べる
+    pub(crate) fn write<W: RecordWriter + ?Sized>(&mut self, writer: &mut W, record: Record) {
+        if self.is_empty() {
+            match writer.push(&record) {
+                Ok(()) => return,
+                Err(e) => {
+                    warn!("failed to write, retry later: {}", e);
+                    self.failures = 1;
+                }
+            }
+        }
+        self.enqueue(record);
+    }
+
+    fn enqueue(&mut self, record: Record) {
+        // 失敗した時だけなので書き出す大きさをそのまま数える
+        let size = serde_cbor::to_vec(&record).map_or(0, |x| x.len());
+        if self.spill.is_none() && self.queued_bytes + size <= self.policy.max_queue_bytes {
+            self.queued_bytes += size;
+            self.queue.push_back((record, size));
+            return;
+        }
+        let spill = match (self.spill.as_mut(), self.spill_path.as_ref()) {
+            (Some(x), _) => Ok(x),
+            (None, Some(path)) => Spill::create(path.clone()).map(|x| self.spill.insert(x)),
+            (None, None) => {
+                error!("retry queue is full, drop a record");
+                self.lose(1);
+                return;
+            }
+        };
+        match spill.and_then(|x| x.push(&record)) {
+            Ok(()) => {
+                SPILLED.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                error!("failed to spill a record: {}", e);
+                self.lose(1);
+            }
+        }
+    }
+
+    fn lose(&mut self, count: u64) {
+        LOST.fetch_add(count, Ordering::Relaxed);
+        self.lost += count;
+    }
+
+    /// 次に書き直すまで待つ時間
+    pub(crate) fn backoff(&self) -> Duration {
+        self.policy.backoff(self.failures)
+    }
+
+    /// 溜めたレコードを順に書き直す。失敗した場合は次に書き直すまで待つ時間を返す
+    pub(crate) fn retry<W: RecordWriter + ?Sized>(&mut self, writer: &mut W) -> Option<Duration> {
+        loop {
+            let result = if let Some((record, _)) = self.queue.front() {
+                RETRIES.fetch_add(1, Ordering::Relaxed);
+                writer.push(record).map(|_| {
+                    let (_, size) = self.queue.pop_front().expect("front");
+                    self.queued_bytes -= size;
+                })
+            } else if let Some(spill) = self.spill.as_mut() {
+                RETRIES.fetch_add(1, Ordering::Relaxed);
+                spill.peek().and_then(|(record, next)| {
+                    writer.push(&record)?;
+                    spill.read_offset = next;
+                    spill.count -= 1;
+                    Ok(())
+                })
+            } else {
+                return None;
+            };
+            match result {
+                Ok(()) => self.failures = 0,
+                Err(e) if self.failures < self.policy.max_retries => {
+                    warn!("failed to write, retry later: {}", e);
+                    self.failures += 1;
+                    return Some(self.backoff());
+                }
+                Err(e) => {
+                    error!("drop a record after {} retries: {}", self.failures, e);
+                    self.drop_front();
+                    self.failures = 0;
+                }
+            }
+            if self.spill.as_ref().is_some_and(|x| x.count == 0) {
+                self.spill.take().expect("spill").remove();
+            }
+        }
+    }
+
+    /// 先頭のレコードを諦める
+    fn drop_front(&mut self) {
+        if let Some((_, size)) = self.queue.pop_front() {
+            self.queued_bytes -= size;
+        } else if let Some(spill) = self.spill.as_mut() {
+            // 読めない場合は残り全てを失う
+            match spill.peek() {
+                Ok((_, next)) => {
+                    spill.read_offset = next;
+                    spill.count -= 1;
+                }
+                Err(_) => {
+                    let count = spill.count as u64;
+                    self.spill.take().expect("spill").remove();
+                    self.lose(count);
+                    return;
+                }
+            }
+        }
+        self.lose(1);
+    }
+
+    /// 書き直さずに残りを全て諦める
+    pub(crate) fn abandon(&mut self) {
+        let count = self.queue.len() as u64 + self.spill.as_ref().map_or(0, |x| x.count as u64);
+        self.queue.clear();
+        self.queued_bytes = 0;
+        if let Some(spill) = self.spill.take() {
+            spill.remove();
+        }
+        if count > 0 {
+            error!("drop {} records waiting for a retry", count);
+            self.lose(count);
+        }
+    }
+
+    /// 前回から新たに失ったレコード数
+    pub(crate) fn take_lost(&mut self) -> u64 {
+        std::mem::take(&mut self.lost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time::Duration};
+
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, KvExt, Level, Record};
+
+    use super::{retry_stats, RetryPolicy, RetryQueue, SPILL_FILENAME};
+    use crate::writer::RecordWriter;
+
+    /// 最初のN回は失敗する
+    #[derive(Default)]
+    struct FlakyWriter {
+        failures: usize,
+        records: Vec<Record>,
+    }
+
+    impl RecordWriter for FlakyWriter {
+        fn push(&mut self, record: &Record) -> Result<(), io::Error> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::other("injected"));
+            }
+            self.records.push(record.clone());
+            Ok(())
+        }
+    }
+
+    fn numbers(records: &[Record]) -> Vec<u64> {
+        records
+            .iter()
+            .map(|x| x.key_values().unwrap().get_u64("number").unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(4), Duration::from_millis(800));
+        assert_eq!(policy.backoff(5), Duration::from_secs(1));
+        assert_eq!(policy.backoff(100), Duration::from_secs(1));
+    }
+
+    /// 溢れた分をファイルに書き出しても順番を保って全て書き直す
+    #[test]
+    fn test_retry_queue_spill() {
+        devinit!();
+        let dir = TempDir::new("retry").unwrap();
+        let policy = RetryPolicy {
+            max_retries: 3,
+            max_queue_bytes: 256,
+            ..Default::default()
+        };
+        let mut queue = RetryQueue::new(policy, dir.path());
+        let mut writer = FlakyWriter {
+            failures: 3,
+            ..Default::default()
+        };
+        let stats = retry_stats();
+
+        for i in 0..20_u64 {
+            queue.write(&mut writer, devlog!(Level::Info, "cat", "msg", "number", i));
+        }
+        // 最初の失敗から後は全て溜める
+        assert!(writer.records.is_empty());
+        assert!(dir.path().join(SPILL_FILENAME).exists());
+
+        let mut waits = 0;
+        while let Some(backoff) = queue.retry(&mut writer) {
+            assert!(backoff >= policy.initial_backoff);
+            waits += 1;
+        }
+        assert_eq!(waits, 2);
+        assert!(queue.is_empty());
+        assert_eq!(numbers(&writer.records), (0..20).collect::<Vec<_>>());
+        assert!(!dir.path().join(SPILL_FILENAME).exists());
+        assert_eq!(queue.take_lost(), 0);
+
+        let after = retry_stats();
+        assert!(after.spilled > stats.spilled);
+        assert!(after.retries >= stats.retries + 20);
+
+        // 書き直し終えたら直接書く
+        queue.write(
+            &mut writer,
+            devlog!(Level::Info, "cat", "msg", "number", 20_u64),
+        );
+        assert_eq!(writer.records.len(), 21);
+    }
+
+    /// 書き出さない場合は溜められる分だけ書き直し、溢れた分は失う
+    #[test]
+    fn test_retry_queue_without_spill() {
+        devinit!();
+        let dir = TempDir::new("retry").unwrap();
+        let policy = RetryPolicy {
+            max_retries: 3,
+            max_queue_bytes: 256,
+            ..Default::default()
+        };
+        let mut queue = RetryQueue::new(policy, dir.path()).without_spill();
+        let mut writer = FlakyWriter {
+            failures: 1,
+            ..Default::default()
+        };
+        for i in 0..20_u64 {
+            queue.write(&mut writer, devlog!(Level::Info, "cat", "msg", "number", i));
+        }
+        assert!(!dir.path().join(SPILL_FILENAME).exists());
+        while queue.retry(&mut writer).is_some() {}
+        let written = numbers(&writer.records);
+        assert!(!written.is_empty() && written.len() < 20, "{:?}", written);
+        assert_eq!(written, (0..written.len() as u64).collect::<Vec<_>>());
+        assert_eq!(queue.take_lost(), 20 - written.len() as u64);
+    }
+
+    /// 書き直しの回数を超えたレコードは捨てて次に進む
+    #[test]
+    fn test_retry_queue_loss() {
+        devinit!();
+        let dir = TempDir::new("retry").unwrap();
+        let policy = RetryPolicy {
+            max_retries: 2,
+            ..Default::default()
+        };
+        let mut queue = RetryQueue::new(policy, dir.path());
+        let mut writer = FlakyWriter {
+            failures: 3,
+            ..Default::default()
+        };
+        for i in 0..3_u64 {
+            queue.write(&mut writer, devlog!(Level::Info, "cat", "msg", "number", i));
+        }
+        while queue.retry(&mut writer).is_some() {}
+        assert_eq!(numbers(&writer.records), vec![1, 2]);
+        assert_eq!(queue.take_lost(), 1);
+        assert_eq!(queue.take_lost(), 0);
+
+        // 閉じるときに残っている分は失う
+        writer.failures = 1;
+        queue.write(
+            &mut writer,
+            devlog!(Level::Info, "cat", "msg", "number", 3_u64),
+        );
+        queue.abandon();
+        assert!(queue.is_empty());
+        assert_eq!(queue.take_lost(), 1);
+    }
+}
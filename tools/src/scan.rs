@@ -0,0 +1,295 @@
+//! 複数のセッションの並列の読み出し
+//!
+//! セッションごとに独立して読み、決めた数のスレッドで分け合う。
+//! 全体で1つの期限を持ち、過ぎたら読み始めていないセッションは読まずに結果を返す
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use uplog::Record;
+
+use crate::{
+    reader::{Deadline, OnError, ReadOptions, ScanTimeout, StorageReader},
+    LogRecord, SessionInfo,
+};
+
+/// 既定の並列数
+pub const DEFAULT_SCAN_THREADS: usize = 4;
+
+/// 検索で1回に読むレコード数
+const SEARCH_PAGE_LENGTH: usize = 1024;
+
+/// Parallelism and deadline of [`scan_parallel`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// sessions read at the same time
+    pub threads: usize,
+    /// deadline of the whole scan
+    pub deadline: Deadline,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            threads: DEFAULT_SCAN_THREADS,
+            deadline: Deadline::default(),
+        }
+    }
+}
+
+impl ScanOptions {
+    /// 0は1として扱う
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = deadline;
+        self
+    }
+}
+
+/// Calls `scan` for each of `items` on up to `options.threads` threads.
+///
+/// Results are in the order of `items`. `None` is an item not started before the deadline.
+/// `scan` gets the deadline to give up in the middle of an item.
+pub fn scan_parallel<I, T, F>(
+    items: &[I],
+    options: ScanOptions,
+    scan: F,
+) -> Vec<Option<io::Result<T>>>
+where
+    I: Sync,
+    T: Send,
+    F: Fn(&I, Deadline) -> io::Result<T> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..items.len()).map(|_| None).collect::<Vec<_>>());
+    let worker = || loop {
+        if options.deadline.is_expired() {
+            break;
+        }
+        let i = next.fetch_add(1, Ordering::Relaxed);
+        let item = match items.get(i) {
+            Some(x) => x,
+            None => break,
+        };
+        let result = scan(item, options.deadline);
+        results.lock().expect("scan results lock")[i] = Some(result);
+    };
+    let threads = options.threads.clamp(1, items.len().max(1));
+    if threads == 1 {
+        worker();
+    } else {
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(worker);
+            }
+        });
+    }
+    results.into_inner().expect("scan results lock")
+}
+
+/// A record found by [`search_sessions`].
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub session: String,
+    pub record: LogRecord,
+}
+
+/// Records found in several sessions, merged by time.
+#[derive(Debug, Clone, Default)]
+pub struct SearchResult {
+    pub hits: Vec<SearchHit>,
+    /// sessions searched to the end
+    pub scanned: usize,
+    /// sessions not searched to the end before the deadline
+    pub incomplete: Vec<String>,
+}
+
+impl SearchResult {
+    /// true when some sessions were not searched to the end
+    pub fn is_partial(&self) -> bool {
+        !self.incomplete.is_empty()
+    }
+}
+
+/// 1つのセッションから最大`limit`件を探す。期限を過ぎたらそこまでの結果とfalseを返す
+fn search_session(
+    reader: &mut dyn StorageReader,
+    matches: &dyn Fn(&Record) -> bool,
+    limit: usize,
+    deadline: Deadline,
+) -> io::Result<(Vec<LogRecord>, bool)> {
+    let options = ReadOptions::default()
+        .on_error(OnError::Skip)
+        .deadline(deadline);
+    let mut found = Vec::new();
+    let mut index = 0;
+    loop {
+        let page = match reader.read_page(index, SEARCH_PAGE_LENGTH, options) {
+            Ok(x) => x,
+            Err(e) if ScanTimeout::from_io(&e).is_some() => return Ok((found, false)),
+            Err(e) => return Err(e),
+        };
+        let read = page.records.len() + page.skipped.len();
+        for record in page.records {
+            if matches(record.as_record()) {
+                found.push(record);
+                if found.len() >= limit {
+                    return Ok((found, true));
+                }
+            }
+        }
+        if read < SEARCH_PAGE_LENGTH {
+            return Ok((found, true));
+        }
+        if deadline.is_expired() {
+            return Ok((found, false));
+        }
+        index += read;
+    }
+}
+
+/// Searches `sessions` in parallel for up to `limit` records for which `matches` is true.
+///
+/// Each session is read by a reader of `open`. The hits of all sessions are sorted by time and
+/// the first `limit` are kept. Sessions not read to the end before the deadline are listed in
+/// [`SearchResult::incomplete`] with the hits found so far.
+pub fn search_sessions<M, F>(
+    sessions: &[SessionInfo],
+    matches: M,
+    limit: usize,
+    options: ScanOptions,
+    open: F,
+) -> io::Result<SearchResult>
+where
+    M: Fn(&Record) -> bool + Sync,
+    F: Fn(&SessionInfo) -> io::Result<Box<dyn StorageReader>> + Sync,
+{
+    let results = scan_parallel(sessions, options, |info, deadline| {
+        search_session(open(info)?.as_mut(), &matches, limit, deadline)
+    });
+    let mut result = SearchResult::default();
+    for (info, found) in sessions.iter().zip(results) {
+        match found.transpose()? {
+            Some((records, complete)) => {
+                if complete {
+                    result.scanned += 1;
+                } else {
+                    result.incomplete.push(info.name());
+                }
+                result
+                    .hits
+                    .extend(records.into_iter().map(|record| SearchHit {
+                        session: info.name(),
+                        record,
+                    }));
+            }
+            None => result.incomplete.push(info.name()),
+        }
+    }
+    // 時刻がなければセッションの順のまま
+    result
+        .hits
+        .sort_by_key(|x| x.record.time().map(|t| t.timestamp));
+    result.hits.truncate(limit);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level};
+
+    use super::{scan_parallel, search_sessions, ScanOptions};
+    use crate::{filter::Filter, open_reader, writer::RecordWriter, Deadline, Storage};
+
+    #[test]
+    fn test_scan_parallel() {
+        let items = (0..20).collect::<Vec<u64>>();
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let results = scan_parallel(&items, ScanOptions::default().threads(3), |x, _| {
+            let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(n, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(2));
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(x * 2)
+        });
+        let values = results
+            .into_iter()
+            .map(|x| x.unwrap().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(values, (0..20).map(|x| x * 2).collect::<Vec<_>>());
+        assert!(most.load(Ordering::SeqCst) <= 3);
+
+        // 期限を過ぎていれば読み始めない
+        let options = ScanOptions::default().deadline(Deadline::after(Duration::ZERO));
+        let results = scan_parallel(&items, options, |x, _| Ok(*x));
+        assert!(results.iter().all(|x| x.is_none()));
+    }
+
+    #[test]
+    fn test_search_sessions() {
+        devinit!();
+        let dir = TempDir::new("scan").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        for name in ["a", "b", "c"] {
+            let mut session = storage.create_session(name).unwrap();
+            for i in 0..50_u64 {
+                let level = if i % 10 == 0 {
+                    Level::Error
+                } else {
+                    Level::Info
+                };
+                session.push(&devlog!(level, "app", name, "i", i)).unwrap();
+            }
+        }
+        let mut sessions = storage.records().unwrap();
+        sessions.sort_by_key(|x| x.name());
+        let filter = Filter::parse("level >= error").unwrap();
+        let matches = |x: &uplog::Record| filter.matches(x);
+        let open = |x: &crate::SessionInfo| -> std::io::Result<Box<dyn crate::StorageReader>> {
+            Ok(Box::new(open_reader(x)?))
+        };
+
+        let result = search_sessions(
+            &sessions,
+            matches,
+            100,
+            ScanOptions::default().threads(2),
+            open,
+        )
+        .unwrap();
+        assert_eq!(result.scanned, 3);
+        assert!(!result.is_partial());
+        assert_eq!(result.hits.len(), 15);
+        for name in ["a", "b", "c"] {
+            let ids = result
+                .hits
+                .iter()
+                .filter(|x| x.session == name)
+                .map(|x| x.record.id)
+                .collect::<Vec<_>>();
+            assert_eq!(ids, vec![0, 10, 20, 30, 40]);
+        }
+        assert!(result.hits.windows(2).all(
+            |x| x[0].record.time().unwrap().timestamp <= x[1].record.time().unwrap().timestamp
+        ));
+
+        // 全体で件数を制限する
+        let result = search_sessions(&sessions, matches, 4, ScanOptions::default(), open).unwrap();
+        assert_eq!(result.hits.len(), 4);
+    }
+}
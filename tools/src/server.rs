@@ -0,0 +1,505 @@
+//! 受信サーバーとGraphQLのAPIを起動する
+//!
+//! コマンドラインの`server`のほか、同じプロセスでサーバーを動かす組み込みや試験で使う。
+//! サーバーは専用のスレッドで動き、[`ServerHandle`]で止める
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use actix::prelude::*;
+use actix_cors::Cors;
+use actix_http::http::header;
+use actix_web::{
+    guard,
+    web::{self, Data},
+    App, HttpServer,
+};
+use log::{info, warn};
+
+use crate::{
+    acl::{Acl, Authorize},
+    actor::{
+        ByteQuota, DecodePolicy, DuplicatePolicy, HandshakePolicy, IdleTimeout, IngestEndpoint,
+        StorageActor,
+    },
+    cache::QueryCache,
+    config::ServerConfig,
+    decode::DecodeLimits,
+    diskwatch::{self, DiskGuard, DiskPolicy, DiskWatchActor, MountFreeSpace},
+    ingest::{AddClientIp, AddConnectionId, AddReceiveTime, IngestPipeline, LimitKvEntries},
+    resolve_data_dir,
+    retry::RetryPolicy,
+    webapi::{self, Mutation, Query, QueryLimits},
+    Storage,
+};
+
+/// 止まったサーバーのワーカーが保存先を破棄するのを待つ上限
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Options of [`run_server`], resolved from a [`ServerConfig`] or built by [`ServerOptions::new`].
+pub struct ServerOptions {
+    /// 0 listens on a free port, see [`ServerHandle::port`]
+    pub port: u16,
+    pub data_dir: PathBuf,
+    /// static files of the web view, not served when none
+    pub view_dir: Option<PathBuf>,
+    pub ingest_endpoints: Vec<IngestEndpoint>,
+    pub decode_policy: DecodePolicy,
+    pub decode_limits: DecodeLimits,
+    pub ingest: IngestPipeline,
+    pub blob_threshold: Option<usize>,
+    pub split_on_boundary: bool,
+    pub duplicate_policy: DuplicatePolicy,
+    pub write_retry: RetryPolicy,
+    pub query_cache_bytes: usize,
+    pub uds_path: Option<PathBuf>,
+    pub uds_mode: Option<u32>,
+    pub idle_timeout: Option<Duration>,
+    pub max_connection_bytes: Option<u64>,
+    pub disk: Option<DiskPolicy>,
+    /// total size of pinned sessions, no limit when none
+    pub max_pinned_bytes: Option<u64>,
+    pub handshake: HandshakePolicy,
+    pub verify_on_start: bool,
+    pub query_limits: QueryLimits,
+    pub acl_file: Option<PathBuf>,
+    #[cfg(feature = "encryption")]
+    pub encrypt_key: Option<crate::crypt::EncryptionKey>,
+}
+
+fn invalid_option<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+/// 設定の秒数を読む
+fn config_seconds(key: &str, secs: Option<f64>) -> io::Result<Option<Duration>> {
+    secs.map(|x| {
+        Duration::try_from_secs_f64(x).map_err(|_| invalid_option(format!("invalid {} {}", key, x)))
+    })
+    .transpose()
+}
+
+impl ServerOptions {
+    /// The defaults of [`ServerConfig::defaults`] storing into `data_dir`, listening on a free
+    /// port without the web view.
+    pub fn new<P: Into<PathBuf>>(data_dir: P) -> Self {
+        let x = Self::resolve(ServerConfig::defaults(), data_dir.into())
+            .expect("the default server config is valid");
+        Self {
+            port: 0,
+            view_dir: None,
+            ..x
+        }
+    }
+
+    /// 既定値で埋めた設定を読む。保存先は解決したものを使う
+    fn resolve(x: ServerConfig, data_dir: PathBuf) -> io::Result<Self> {
+        let mut ingest = IngestPipeline::new();
+        // サーバーが付けるkvは数えない
+        if let Some(max) = x.max_kv_entries {
+            ingest = ingest.with(LimitKvEntries(max));
+        }
+        if x.ingest_receive_time == Some(true) {
+            ingest = ingest.with(AddReceiveTime);
+        }
+        if x.ingest_client_ip == Some(true) {
+            ingest = ingest.with(AddClientIp);
+        }
+        if x.ingest_connection_id == Some(true) {
+            ingest = ingest.with(AddConnectionId);
+        }
+        let ws_paths = x
+            .ws_paths
+            .unwrap_or_default()
+            .iter()
+            .map(|x| x.parse::<IngestEndpoint>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_option)?;
+        let query_timeout = config_seconds("query_timeout", x.query_timeout)?.unwrap_or_default();
+        let disk_check_interval =
+            config_seconds("disk_check_interval", x.disk_check_interval)?.unwrap_or_default();
+        let reserved_bytes = x.reserved_bytes.unwrap_or_default();
+        let prune = x.prune_when_full == Some(true);
+        Ok(Self {
+            port: x.port.unwrap_or_default(),
+            data_dir,
+            view_dir: x.view_dir.map(PathBuf::from),
+            ingest_endpoints: IngestEndpoint::with_legacy(ws_paths),
+            decode_policy: DecodePolicy {
+                max_consecutive_failures: x.max_decode_failures.unwrap_or_default(),
+                ..Default::default()
+            },
+            decode_limits: x
+                .max_record_bytes
+                .map(DecodeLimits::new)
+                .unwrap_or_default(),
+            ingest,
+            blob_threshold: x.blob_threshold,
+            split_on_boundary: x.split_on_boundary == Some(true),
+            duplicate_policy: x
+                .duplicate_policy
+                .unwrap_or_default()
+                .parse()
+                .map_err(invalid_option)?,
+            write_retry: RetryPolicy {
+                max_retries: x.write_retries.unwrap_or_default(),
+                max_queue_bytes: x.retry_queue_bytes.unwrap_or_default(),
+                ..Default::default()
+            },
+            query_cache_bytes: x.query_cache_mb.unwrap_or_default() * 1024 * 1024,
+            uds_path: x.uds_path,
+            uds_mode: x
+                .uds_mode
+                .as_deref()
+                .map(|x| u32::from_str_radix(x, 8))
+                .transpose()
+                .map_err(|e| invalid_option(format!("invalid uds_mode: {}", e)))?,
+            idle_timeout: config_seconds("idle_timeout", x.idle_timeout)?,
+            max_connection_bytes: x.max_connection_bytes,
+            disk: x.min_free_bytes.map(|min| {
+                let policy = DiskPolicy::new(min);
+                DiskPolicy {
+                    resume_free_bytes: x
+                        .resume_free_bytes
+                        .unwrap_or(policy.resume_free_bytes)
+                        .max(min),
+                    interval: disk_check_interval,
+                    reserved_bytes,
+                    prune,
+                    ..policy
+                }
+            }),
+            max_pinned_bytes: x.max_pinned_bytes,
+            handshake: HandshakePolicy {
+                grace: config_seconds("handshake_grace", x.handshake_grace)?.unwrap_or_default(),
+                max_invalid_frames: x.max_handshake_failures.unwrap_or_default(),
+            },
+            verify_on_start: x.verify_on_start == Some(true),
+            query_limits: QueryLimits {
+                max_depth: x.max_query_depth.unwrap_or_default(),
+                max_complexity: x.max_query_complexity.unwrap_or_default(),
+                max_read_length: x.max_read_length.unwrap_or_default(),
+                scan_timeout: Some(query_timeout).filter(|x| !x.is_zero()),
+                scan_threads: x.scan_threads.unwrap_or_default().max(1),
+            },
+            acl_file: x.acl_file,
+            #[cfg(feature = "encryption")]
+            encrypt_key: None,
+        })
+    }
+}
+
+impl TryFrom<ServerConfig> for ServerOptions {
+    type Error = io::Error;
+
+    /// Fills the options not set with [`ServerConfig::defaults`] and creates the data dir.
+    /// The view dir must exist.
+    fn try_from(x: ServerConfig) -> io::Result<Self> {
+        // 指定しなかったものは既定値で埋める
+        let x = ServerConfig::defaults().merge(x);
+        let data_dir = resolve_data_dir(x.data_dir.as_deref().unwrap_or_default())?;
+        let options = Self::resolve(x, data_dir)?;
+        if let Some(view_dir) = options.view_dir.as_ref() {
+            info!("view dir {:?}", view_dir);
+            if !view_dir.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("not found webview file dir {}", view_dir.display()),
+                ));
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// A server started by [`run_server`].
+///
+/// The server runs until [`ServerHandle::stop`] or a termination signal.
+pub struct ServerHandle {
+    addrs: Vec<SocketAddr>,
+    server: actix_web::dev::Server,
+    storage_actor: Addr<StorageActor>,
+    storage: Storage,
+    thread: thread::JoinHandle<io::Result<()>>,
+}
+
+impl ServerHandle {
+    /// The addresses listened on.
+    pub fn addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// The port listened on, useful when [`ServerOptions::port`] is 0.
+    pub fn port(&self) -> u16 {
+        self.addrs[0].port()
+    }
+
+    /// The actor writing the received records, e.g. to send [`crate::actor::RouteControl`].
+    pub fn storage_actor(&self) -> &Addr<StorageActor> {
+        &self.storage_actor
+    }
+
+    /// The storage the server writes to, sharing its registry.
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Stops the server and waits for it.
+    ///
+    /// A graceful stop waits for the open connections to close, up to 30 seconds.
+    pub fn stop(self, graceful: bool) -> io::Result<()> {
+        futures::executor::block_on(self.server.stop(graceful));
+        self.join()
+    }
+
+    /// Waits until the server stops, e.g. by a termination signal.
+    ///
+    /// The data dir can be opened again once this returns.
+    pub fn join(self) -> io::Result<()> {
+        let result = self
+            .thread
+            .join()
+            .map_err(|_| io::Error::other("the server thread panicked"))?;
+        // 待ち受けのワーカーは止まった後に別のスレッドで保存先を破棄する
+        if !self.storage.wait_unshared(RELEASE_TIMEOUT) {
+            warn!("the data dir is still in use after the server stopped");
+        }
+        result
+    }
+}
+
+/// Starts the server in its own thread and returns once it listens.
+///
+/// ```no_run
+/// use uplog_tools::server::{run_server, ServerOptions};
+///
+/// let server = run_server(ServerOptions::new("/tmp/uplog"))?;
+/// println!("listen at {}", server.port());
+/// server.stop(true)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn run_server(mut opt: ServerOptions) -> io::Result<ServerHandle> {
+    let storage = Storage::new(&opt.data_dir)?.max_pinned_bytes(opt.max_pinned_bytes);
+    #[cfg(feature = "encryption")]
+    let storage = match opt.encrypt_key.clone() {
+        Some(key) => {
+            info!("encrypt new sessions with key {}", key.id());
+            storage.encrypt_with(key)
+        }
+        None => storage,
+    };
+    info!("data store in [{}]", opt.data_dir.to_string_lossy());
+    let acl = opt
+        .acl_file
+        .as_ref()
+        .map(Acl::from_file)
+        .transpose()?
+        .unwrap_or_default();
+    if !acl.is_empty() {
+        info!("restrict sessions by bearer tokens");
+    }
+    for endpoint in opt.ingest_endpoints.iter_mut() {
+        endpoint.tenant = endpoint
+            .label
+            .as_deref()
+            .and_then(|x| acl.tenant_of_label(x))
+            .map(String::from);
+        if let Some(tenant) = endpoint.tenant.as_deref() {
+            info!("store sessions of {} in tenant {}", endpoint.path, tenant);
+        }
+    }
+    if opt.verify_on_start {
+        match storage.verify_latest(true)? {
+            Some(report) if report.is_ok() => info!("verified {}", report),
+            Some(report) => warn!("verified {}", report),
+            None => {}
+        }
+    }
+
+    let handle_storage = storage.clone();
+    let (sender, receiver) = mpsc::channel();
+    let thread = thread::Builder::new()
+        .name("uplog-server".to_string())
+        .spawn(move || {
+            let mut sys = actix_web::rt::System::new("server");
+            sys.block_on(async move {
+                match start(opt, storage, acl) {
+                    Ok((server, addrs, storage_actor)) => {
+                        let _ = sender.send(Ok((server.clone(), addrs, storage_actor)));
+                        server.await
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        Ok(())
+                    }
+                }
+            })
+        })?;
+    match receiver.recv() {
+        Ok(Ok((server, addrs, storage_actor))) => Ok(ServerHandle {
+            addrs,
+            server,
+            storage_actor,
+            storage: handle_storage,
+            thread,
+        }),
+        Ok(Err(e)) => {
+            let _ = thread.join();
+            Err(e)
+        }
+        Err(_) => Err(io::Error::other("the server thread panicked")),
+    }
+}
+
+/// actixのシステムの中でアクターと待ち受けを始める
+fn start(
+    opt: ServerOptions,
+    storage: Storage,
+    acl: Acl,
+) -> io::Result<(actix_web::dev::Server, Vec<SocketAddr>, Addr<StorageActor>)> {
+    let bind_addr = format!("0.0.0.0:{}", opt.port);
+    let storage_actor = StorageActor::new(storage.clone())
+        .blob_threshold(opt.blob_threshold)
+        .split_on_boundary(opt.split_on_boundary)
+        .duplicate_policy(opt.duplicate_policy)
+        .write_retry(opt.write_retry);
+    let storage_addr = storage_actor.start();
+    let disk = opt.disk.map(DiskGuard::new).unwrap_or_default();
+    if opt.disk.is_some() {
+        DiskWatchActor::new(disk.clone(), Box::new(MountFreeSpace(opt.data_dir.clone())))
+            .storage(storage.clone())
+            .start();
+    }
+    if let Some(path) = opt.uds_path.as_ref() {
+        start_uds_listener(path, &opt, storage_addr.clone())?;
+    }
+    let schema = webapi::build_schema(
+        Query::new(storage.clone())
+            .query_cache(Arc::new(QueryCache::new(opt.query_cache_bytes)))
+            .limits(opt.query_limits)
+            .disk_guard(disk.clone())
+            .connections(storage_addr.clone().recipient()),
+        Mutation::new(storage.clone())
+            .control(storage_addr.clone().recipient())
+            .connections(storage_addr.clone().recipient()),
+    );
+
+    let data = storage_addr.clone();
+    let server = HttpServer::new(move || {
+        let cors = Cors::default()
+            .allowed_origin_fn(|_origin, _req_head| true)
+            .allowed_methods(vec!["GET", "POST"])
+            .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
+            .allowed_header(header::CONTENT_TYPE)
+            .supports_credentials()
+            .max_age(3600);
+        App::new()
+            .wrap(Authorize::new(acl.clone()))
+            .wrap(cors)
+            // enable logger
+            // .wrap(middleware::Logger::default())
+            .data(data.clone())
+            .app_data(Data::new(storage.clone()))
+            .app_data(Data::new(opt.decode_policy))
+            .app_data(Data::new(opt.handshake))
+            .app_data(Data::new(opt.decode_limits))
+            .app_data(Data::new(opt.ingest.clone()))
+            .app_data(Data::new(disk.clone()))
+            .configure(|cfg| {
+                if let Some(timeout) = opt.idle_timeout {
+                    cfg.app_data(Data::new(IdleTimeout(timeout)));
+                }
+                if let Some(quota) = opt.max_connection_bytes {
+                    cfg.app_data(Data::new(ByteQuota(quota)));
+                }
+            })
+            // websocket routes
+            .configure(|cfg| {
+                for endpoint in opt.ingest_endpoints.iter() {
+                    cfg.service(endpoint.resource());
+                }
+            })
+            // archive download
+            .service(
+                web::resource(format!("{}/{{name}}", webapi::ARCHIVE_PATH))
+                    .route(web::get().to(webapi::download_archive)),
+            )
+            // blob download
+            .service(
+                web::resource(format!("{}/{{name}}/{{hash}}", webapi::BLOB_PATH))
+                    .route(web::get().to(webapi::download_blob)),
+            )
+            // attachment download
+            .service(
+                web::resource(format!("{}/{{name}}/{{id}}", webapi::ATTACHMENT_PATH))
+                    .route(web::get().to(webapi::download_attachment)),
+            )
+            // records after a cursor
+            .service(
+                web::resource(format!("{}/{{name}}/records", webapi::SESSIONS_PATH))
+                    .route(web::get().to(webapi::records_after)),
+            )
+            // readiness
+            .service(web::resource(diskwatch::READYZ_PATH).route(web::get().to(diskwatch::readyz)))
+            // version
+            .service(web::resource(webapi::VERSION_PATH).route(web::get().to(webapi::version)))
+            // graphql
+            .app_data(Data::new(schema.clone()))
+            .service(web::resource(webapi::SCHEMA_PATH).route(web::get().to(webapi::schema_sdl)))
+            .service(
+                web::resource("/graphql")
+                    .guard(guard::Post())
+                    .to(webapi::index),
+            )
+            .service(
+                web::resource("/graphql")
+                    .guard(guard::Get())
+                    .to(webapi::index_playground),
+            )
+            .configure(|cfg| {
+                if let Some(view_dir) = opt.view_dir.as_ref() {
+                    cfg.service(
+                        actix_files::Files::new("/", view_dir)
+                            .prefer_utf8(true)
+                            .index_file("index.html"),
+                    );
+                }
+            })
+    })
+    .bind(&bind_addr)?;
+    let addrs = server.addrs();
+    info!("listen at {:?}", addrs);
+    Ok((server.run(), addrs, storage_addr))
+}
+
+#[cfg(unix)]
+fn start_uds_listener(
+    path: &Path,
+    opt: &ServerOptions,
+    storage_addr: Addr<StorageActor>,
+) -> io::Result<()> {
+    crate::uds::UdsListener::new(path, storage_addr.recipient())
+        .mode(opt.uds_mode)
+        .decode_policy(opt.decode_policy)
+        .decode_limits(opt.decode_limits)
+        .ingest(opt.ingest.clone())
+        .idle_timeout(opt.idle_timeout)
+        .start()
+}
+
+#[cfg(not(unix))]
+fn start_uds_listener(
+    _path: &Path,
+    _opt: &ServerOptions,
+    _storage_addr: Addr<StorageActor>,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix domain socket is only available on unix",
+    ))
+}
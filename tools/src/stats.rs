@@ -0,0 +1,593 @@
+//! セッションのカテゴリごとのレコード数と記録の間隔
+//!
+//! リリースの確認で複数のセッションのエラー数とカテゴリごとの量を並べて比べる。
+//! 集計はデータファイルの更新時刻と長さをキーに保持し、変わっていなければ読み直さない
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+#[cfg(feature = "web")]
+use async_graphql::{Object, SimpleObject};
+use serde::Serialize;
+use uplog::{Level, DELTA_KEY};
+
+#[cfg(feature = "web")]
+use crate::LogLevel;
+use crate::{
+    cat::csv_field,
+    kvtype::KvTypeRegistry,
+    lifecycle::is_server_record,
+    reader::{Deadline, ScanTimeout, DEADLINE_CHECK_INTERVAL},
+    writer::CBORSequenceWriter,
+    RecordIter,
+};
+
+/// Record counts of a session. Records written by the server are not counted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SessionStats {
+    pub records: u64,
+    /// records at the Error level
+    pub errors: u64,
+    /// records per category
+    pub categories: BTreeMap<String, u64>,
+    /// highest level per category
+    pub max_levels: BTreeMap<String, Level>,
+    /// `categories` split at the dots
+    pub tree: Vec<CategoryNode>,
+    /// gaps between records of the categories that carry them, sorted by category
+    pub deltas: Vec<DeltaStats>,
+    /// types of the kv values of each key
+    pub kv_types: KvTypeRegistry,
+}
+
+impl SessionStats {
+    /// セッションのレコードを全て読んで数える
+    pub fn collect<P: AsRef<Path>>(session_dir: P) -> io::Result<Self> {
+        Self::collect_until(session_dir, Deadline::default())
+    }
+
+    /// `collect`と同じ。期限を過ぎたら[`ScanTimeout`]のエラーを返す
+    pub fn collect_until<P: AsRef<Path>>(session_dir: P, deadline: Deadline) -> io::Result<Self> {
+        let mut stats = Self::default();
+        let mut deltas = BTreeMap::<String, Vec<u64>>::new();
+        for (scanned, record) in RecordIter::new(session_dir)?.enumerate() {
+            if scanned % DEADLINE_CHECK_INTERVAL == DEADLINE_CHECK_INTERVAL - 1
+                && deadline.is_expired()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    ScanTimeout {
+                        scanned: scanned + 1,
+                    },
+                ));
+            }
+            let record = record?;
+            if is_server_record(&record) {
+                continue;
+            }
+            stats.records += 1;
+            stats.kv_types.observe(&record);
+            if record.level() == Level::Error {
+                stats.errors += 1;
+            }
+            let level = stats
+                .max_levels
+                .entry(record.category.clone())
+                .or_insert(record.level());
+            *level = (*level).max(record.level());
+            if let Some(delta) = record
+                .kv
+                .as_ref()
+                .and_then(|x| x.get(DELTA_KEY))
+                .and_then(|x| x.as_u64())
+            {
+                deltas
+                    .entry(record.category.clone())
+                    .or_default()
+                    .push(delta);
+            }
+            *stats.categories.entry(record.category).or_default() += 1;
+        }
+        stats.tree = CategoryNode::tree(&stats.categories, &stats.max_levels);
+        stats.deltas = deltas
+            .into_iter()
+            .map(|(category, x)| DeltaStats::new(category, x))
+            .collect();
+        Ok(stats)
+    }
+}
+
+/// Gaps between consecutive records of a category in microseconds.
+///
+/// Taken from [`uplog::DELTA_KEY`] that clients add with `Builder::emit_deltas`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct DeltaStats {
+    pub category: String,
+    /// records carrying a delta
+    pub count: u64,
+    pub min_us: u64,
+    /// rounded down
+    pub mean_us: u64,
+    /// 99th percentile by the nearest rank
+    pub p99_us: u64,
+}
+
+impl DeltaStats {
+    /// `deltas`は空でないこと
+    fn new(category: String, mut deltas: Vec<u64>) -> Self {
+        deltas.sort_unstable();
+        let count = deltas.len();
+        let sum = deltas.iter().map(|x| *x as u128).sum::<u128>();
+        // 順位は切り上げ
+        let rank = (count * 99).div_ceil(100).max(1);
+        Self {
+            category,
+            count: count as u64,
+            min_us: deltas[0],
+            mean_us: (sum / count as u128) as u64,
+            p99_us: deltas[rank - 1],
+        }
+    }
+}
+
+/// A segment of the dotted category names, with the counts of the categories under it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CategoryNode {
+    /// part of the name between the dots
+    pub segment: String,
+    /// name up to and including this segment
+    pub full_path: String,
+    /// records in this category and the categories under it
+    pub count: u64,
+    /// records in exactly this category, 0 for a node that only groups others
+    pub own_count: u64,
+    /// highest level in this category and the categories under it
+    pub max_level: Level,
+    /// sorted by segment
+    pub children: Vec<CategoryNode>,
+}
+
+impl CategoryNode {
+    /// カテゴリ名を`.`で区切って木にする。根は最初の区切りごとに並ぶ
+    pub fn tree(
+        categories: &BTreeMap<String, u64>,
+        max_levels: &BTreeMap<String, Level>,
+    ) -> Vec<Self> {
+        let mut roots: Vec<Self> = Vec::new();
+        for (category, count) in categories {
+            let level = max_levels.get(category).copied().unwrap_or(Level::Trace);
+            let mut nodes = &mut roots;
+            let mut end = 0;
+            let segments = category.split('.').collect::<Vec<_>>();
+            for (i, segment) in segments.iter().enumerate() {
+                end += segment.len() + usize::from(i > 0);
+                let index = match nodes.iter().position(|x| x.segment == *segment) {
+                    Some(x) => x,
+                    None => {
+                        nodes.push(Self {
+                            segment: segment.to_string(),
+                            full_path: category[..end].to_string(),
+                            count: 0,
+                            own_count: 0,
+                            max_level: level,
+                            children: Vec::new(),
+                        });
+                        nodes.len() - 1
+                    }
+                };
+                let node = &mut nodes[index];
+                node.count += count;
+                node.max_level = node.max_level.max(level);
+                if i + 1 == segments.len() {
+                    node.own_count += count;
+                }
+                nodes = &mut node.children;
+            }
+        }
+        // 名前順でも"net-a"が"net.rx"より前に来るので区切りで並べ直す
+        fn sort(nodes: &mut [CategoryNode]) {
+            nodes.sort_by(|a, b| a.segment.cmp(&b.segment));
+            nodes.iter_mut().for_each(|x| sort(&mut x.children));
+        }
+        sort(&mut roots);
+        roots
+    }
+}
+
+#[cfg(feature = "web")]
+#[Object]
+impl CategoryNode {
+    async fn segment(&self) -> &str {
+        &self.segment
+    }
+    async fn full_path(&self) -> &str {
+        &self.full_path
+    }
+    /// records in this category and the categories under it
+    async fn count(&self) -> u64 {
+        self.count
+    }
+    /// records in exactly this category
+    async fn own_count(&self) -> u64 {
+        self.own_count
+    }
+    async fn max_level(&self) -> LogLevel {
+        self.max_level.into()
+    }
+    async fn children(&self) -> &[CategoryNode] {
+        &self.children
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    modified: SystemTime,
+    len: u64,
+    stats: Arc<SessionStats>,
+}
+
+/// [`SessionStats`] of sessions, kept until the data file changes.
+#[derive(Debug, Default)]
+pub struct StatsCache {
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+}
+
+impl StatsCache {
+    /// 保持していて変わっていなければそれを返し、なければ数えて保持する
+    pub fn get(&self, session_dir: &Path) -> io::Result<Arc<SessionStats>> {
+        self.get_until(session_dir, Deadline::default())
+    }
+
+    /// `get`と同じ。数えている途中で期限を過ぎたら[`ScanTimeout`]のエラーを返す
+    pub fn get_until(
+        &self,
+        session_dir: &Path,
+        deadline: Deadline,
+    ) -> io::Result<Arc<SessionStats>> {
+        let metadata = std::fs::metadata(session_dir.join(CBORSequenceWriter::FILENAME))?;
+        let (modified, len) = (metadata.modified()?, metadata.len());
+        if let Some(x) = self
+            .entries
+            .lock()
+            .expect("stats cache lock")
+            .get(session_dir)
+            .filter(|x| x.modified == modified && x.len == len)
+        {
+            return Ok(x.stats.clone());
+        }
+        let stats = Arc::new(SessionStats::collect_until(session_dir, deadline)?);
+        self.entries.lock().expect("stats cache lock").insert(
+            session_dir.to_owned(),
+            Entry {
+                modified,
+                len,
+                stats: stats.clone(),
+            },
+        );
+        Ok(stats)
+    }
+}
+
+/// Counts of a category, one per session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct StatsRow {
+    pub category: String,
+    pub counts: Vec<u64>,
+    /// sum of `counts`
+    pub total: u64,
+}
+
+/// Record counts of sessions side by side.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "web", derive(SimpleObject))]
+pub struct StatsTable {
+    /// column names
+    pub sessions: Vec<String>,
+    /// one row per category in any of the sessions, sorted by name
+    pub rows: Vec<StatsRow>,
+    /// all records of each session
+    pub totals: StatsRow,
+    /// Error records of each session
+    pub errors: StatsRow,
+    /// sessions left out because they were not counted before the deadline
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub incomplete: Vec<String>,
+}
+
+/// 合計とエラー数の行の名前。カテゴリ名と区別するため括弧で囲む
+const TOTAL_LABEL: &str = "(total)";
+const ERRORS_LABEL: &str = "(errors)";
+
+fn row(category: String, counts: Vec<u64>) -> StatsRow {
+    StatsRow {
+        category,
+        total: counts.iter().sum(),
+        counts,
+    }
+}
+
+impl StatsTable {
+    /// `sessions`の順に列を並べる
+    pub fn new(sessions: &[(String, Arc<SessionStats>)]) -> Self {
+        let categories = sessions
+            .iter()
+            .flat_map(|(_, x)| x.categories.keys())
+            .collect::<BTreeSet<_>>();
+        let rows = categories
+            .into_iter()
+            .map(|category| {
+                let counts = sessions
+                    .iter()
+                    .map(|(_, x)| x.categories.get(category).copied().unwrap_or(0))
+                    .collect();
+                row(category.clone(), counts)
+            })
+            .collect();
+        Self {
+            sessions: sessions.iter().map(|(name, _)| name.clone()).collect(),
+            rows,
+            totals: row(
+                TOTAL_LABEL.to_string(),
+                sessions.iter().map(|(_, x)| x.records).collect(),
+            ),
+            errors: row(
+                ERRORS_LABEL.to_string(),
+                sessions.iter().map(|(_, x)| x.errors).collect(),
+            ),
+            incomplete: Vec::new(),
+        }
+    }
+
+    /// Keeps the category rows for which `f` is true. The total and error rows are left as they are.
+    pub fn retain_categories<F: FnMut(&str) -> bool>(&mut self, mut f: F) {
+        self.rows.retain(|x| f(&x.category));
+    }
+
+    /// Header, category rows, then the total and error rows. The last column is the sum.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::new();
+        let header = std::iter::once("category")
+            .chain(self.sessions.iter().map(String::as_str))
+            .chain(std::iter::once("total"))
+            .map(csv_field)
+            .collect::<Vec<_>>();
+        writeln!(out, "{}", header.join(",")).unwrap();
+        for row in self.rows.iter().chain([&self.totals, &self.errors]) {
+            let fields = std::iter::once(csv_field(&row.category))
+                .chain(row.counts.iter().map(u64::to_string))
+                .chain(std::iter::once(row.total.to_string()))
+                .collect::<Vec<_>>();
+            writeln!(out, "{}", fields.join(",")).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level, DELTA_KEY};
+
+    use super::{csv_field, CategoryNode, DeltaStats, StatsTable};
+    use crate::{scan::ScanOptions, writer::RecordWriter, Deadline, Storage};
+
+    /// カテゴリとレベルの組のレコードを書いたセッションを作る
+    fn fixture(storage: &Storage, name: &str, records: &[(&str, Level)]) {
+        devinit!();
+        let mut session = storage.create_session(name).unwrap();
+        for (category, level) in records {
+            session.push(&devlog!(*level, category, "msg")).unwrap();
+        }
+    }
+
+    fn fixtures(dir: &TempDir) -> Storage {
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        fixture(
+            &storage,
+            "run1",
+            &[
+                ("app.net", Level::Info),
+                ("app.net", Level::Error),
+                ("app.camera", Level::Info),
+                ("app.camera", Level::Warn),
+                ("app.camera", Level::Info),
+            ],
+        );
+        fixture(
+            &storage,
+            "run2",
+            &[
+                ("app.net", Level::Error),
+                ("app.net", Level::Error),
+                ("app,misc", Level::Debug),
+            ],
+        );
+        storage
+    }
+
+    #[test]
+    fn test_stats_csv() {
+        let dir = TempDir::new("stats").unwrap();
+        let storage = fixtures(&dir);
+        let table = storage
+            .sessions_stats(&["run1".to_string(), "run2".to_string()])
+            .unwrap();
+        assert_eq!(
+            table.to_csv(),
+            "category,run1,run2,total\n\
+             \"app,misc\",0,1,1\n\
+             app.camera,3,0,3\n\
+             app.net,2,2,4\n\
+             (total),5,3,8\n\
+             (errors),1,2,3\n"
+        );
+        // 列は指定した順に並べる
+        let table = storage
+            .sessions_stats(&["run2".to_string(), "run1".to_string()])
+            .unwrap();
+        assert_eq!(table.sessions, ["run2", "run1"]);
+        assert_eq!(table.rows[2].counts, [2, 2]);
+        assert!(storage.sessions_stats(&["missing".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_stats_cache() {
+        let dir = TempDir::new("stats").unwrap();
+        let storage = fixtures(&dir);
+        let first = storage.session_stats("run1").unwrap();
+        assert!(std::sync::Arc::ptr_eq(
+            &first,
+            &storage.session_stats("run1").unwrap()
+        ));
+        // 追記されたら数え直す
+        let mut data = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("run1").join("seqdata"))
+            .unwrap();
+        serde_cbor::to_writer(&mut data, &devlog!(Level::Error, "app.net", "msg")).unwrap();
+        let updated = storage.session_stats("run1").unwrap();
+        assert_eq!((updated.records, updated.errors), (6, 2));
+        assert_eq!(
+            StatsTable::new(&[("run1".to_string(), updated)])
+                .errors
+                .total,
+            2
+        );
+    }
+
+    #[test]
+    fn test_sessions_stats_parallel() {
+        let dir = TempDir::new("stats").unwrap();
+        let storage = fixtures(&dir);
+        for i in 3..8 {
+            fixture(&storage, &format!("run{}", i), &[("app", Level::Info)]);
+        }
+        let names = (1..8).map(|x| format!("run{}", x)).collect::<Vec<_>>();
+        let sequential = storage
+            .sessions_stats_with(&names, ScanOptions::default().threads(1))
+            .unwrap();
+        let parallel = storage
+            .sessions_stats_with(&names, ScanOptions::default().threads(3))
+            .unwrap();
+        assert_eq!(parallel, sequential);
+        assert_eq!(parallel.sessions, names);
+        assert_eq!(parallel.totals.counts, vec![5, 3, 1, 1, 1, 1, 1]);
+        assert!(parallel.incomplete.is_empty());
+
+        // 期限までに数えられなかったセッションは列に含めない
+        let expired = ScanOptions::default().deadline(Deadline::after(Duration::ZERO));
+        let table = storage.sessions_stats_with(&names, expired).unwrap();
+        assert!(table.sessions.is_empty());
+        assert_eq!(table.incomplete, names);
+        let json = serde_json::to_value(&table).unwrap();
+        assert_eq!(json["incomplete"].as_array().unwrap().len(), 7);
+        assert!(serde_json::to_value(&parallel)
+            .unwrap()
+            .get("incomplete")
+            .is_none());
+    }
+
+    /// 先頭の区切りが同じカテゴリをまとめ、葉でも内側でもあるカテゴリは両方の数を持つ
+    #[test]
+    fn test_category_tree() {
+        let categories = [
+            ("app", 1),
+            ("app-x", 2),
+            ("app.net", 3),
+            ("app.net.rx", 4),
+            ("db", 5),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        let max_levels = [
+            ("app", Level::Warn),
+            ("app-x", Level::Info),
+            ("app.net", Level::Debug),
+            ("app.net.rx", Level::Error),
+            ("db", Level::Trace),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        let tree = CategoryNode::tree(&categories, &max_levels);
+
+        let shape = |nodes: &[CategoryNode]| {
+            nodes
+                .iter()
+                .map(|x| (x.full_path.clone(), x.count, x.own_count, x.max_level))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            shape(&tree),
+            [
+                ("app".to_string(), 8, 1, Level::Error),
+                ("app-x".to_string(), 2, 2, Level::Info),
+                ("db".to_string(), 5, 5, Level::Trace),
+            ]
+        );
+        assert_eq!(
+            shape(&tree[0].children),
+            [("app.net".to_string(), 7, 3, Level::Error)]
+        );
+        let rx = &tree[0].children[0].children;
+        assert_eq!(shape(rx), [("app.net.rx".to_string(), 4, 4, Level::Error)]);
+        assert_eq!(rx[0].segment, "rx");
+        assert!(rx[0].children.is_empty());
+    }
+
+    /// クライアントが付けた間隔をカテゴリごとにまとめる
+    #[test]
+    fn test_delta_stats() {
+        devinit!();
+        let dir = TempDir::new("stats").unwrap();
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        let mut session = storage.create_session("run").unwrap();
+        for i in 1..=100_u64 {
+            session
+                .push(&devlog!(Level::Info, "net", "msg", DELTA_KEY, i * 1000))
+                .unwrap();
+        }
+        session.push(&devlog!(Level::Info, "app", "msg")).unwrap();
+        session
+            .push(&devlog!(Level::Info, "app", "msg", DELTA_KEY, 7_u64))
+            .unwrap();
+        session.push(&devlog!(Level::Info, "db", "msg")).unwrap();
+        drop(session);
+
+        let stats = storage.session_stats("run").unwrap();
+        let expected = |category: &str, count, min_us, mean_us, p99_us| DeltaStats {
+            category: category.to_string(),
+            count,
+            min_us,
+            mean_us,
+            p99_us,
+        };
+        assert_eq!(
+            stats.deltas,
+            [
+                expected("app", 1, 7, 7, 7),
+                expected("net", 100, 1000, 50_500, 99_000),
+            ]
+        );
+        assert_eq!(
+            DeltaStats::new("x".to_string(), vec![5, 1, 3]),
+            expected("x", 3, 1, 3, 5)
+        );
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("a.b"), "a.b");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
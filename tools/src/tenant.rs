@@ -0,0 +1,123 @@
+//! テナントごとの保存先
+//!
+//! 1つのサーバーで顧客ごとにデータを分けるため、テナントのセッションは`{data_dir}/{tenant}`に置く。
+//! テナントの保存先は初めて使うときに作り、元の[`Storage`]の全てのcloneで共有する。
+//! 受信とGraphQLが同じ保存先を使うので、書き込み中のセッションの通知も届く。
+//! テナントのディレクトリには[`TENANT_MARKER`]を置き、元の保存先の一覧には出さない
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, PoisonError},
+};
+
+use crate::Storage;
+
+/// File that marks a directory of the data dir as the root of a tenant.
+pub const TENANT_MARKER: &str = ".uplog-tenant";
+
+/// Whether `id` can name a tenant: not empty, no path separators and not hidden.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && !id.starts_with('.') && !id.contains(['/', '\\'])
+}
+
+/// テナントの保存先のディレクトリか
+pub(crate) fn is_tenant_root(dir: &Path) -> bool {
+    dir.join(TENANT_MARKER).is_file()
+}
+
+impl Storage {
+    /// The storage of `tenant` under this data dir, created on first use.
+    ///
+    /// Every clone of this storage returns the same instance for the same tenant, so a session
+    /// written by the ingest server is seen live by the GraphQL API.
+    pub fn tenant(&self, tenant: &str) -> io::Result<Storage> {
+        if !is_valid_id(tenant) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid tenant id: {}", tenant),
+            ));
+        }
+        let mut tenants = self.tenants.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(x) = tenants.get(tenant) {
+            return Ok(x.clone());
+        }
+        let dir = self.dir.join(tenant);
+        // 同じ名前のセッションがあればテナントにしない
+        if dir.is_dir() && !is_tenant_root(&dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is a session, not a tenant", tenant),
+            ));
+        }
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(TENANT_MARKER), b"")?;
+        let storage = Storage {
+            dir,
+            // 元の保存先のロックで排他する
+            lock: self.lock.clone(),
+            stats: Arc::default(),
+            registry: Arc::default(),
+            initiator: self.initiator.clone(),
+            #[cfg(feature = "encryption")]
+            encryption: self.encryption.clone(),
+            max_pinned_bytes: self.max_pinned_bytes,
+            tenants: Arc::default(),
+        };
+        tenants.insert(tenant.to_string(), storage.clone());
+        Ok(storage)
+    }
+
+    /// The storage of `tenant`, or this storage when there is none.
+    pub fn scoped(&self, tenant: Option<&str>) -> io::Result<Storage> {
+        match tenant {
+            Some(x) => self.tenant(x),
+            None => Ok(self.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::{is_valid_id, TENANT_MARKER};
+    use crate::Storage;
+
+    #[test]
+    fn test_tenant_storage() {
+        let dir = TempDir::new("tenant").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        storage.create_session("base").unwrap();
+        let acme = storage.tenant("acme").unwrap();
+        acme.create_session("a1").unwrap();
+        assert!(dir.path().join("acme").join(TENANT_MARKER).is_file());
+        assert!(dir.path().join("acme").join("a1").is_dir());
+
+        // cloneからも同じ保存先を返す
+        let again = storage.clone().tenant("acme").unwrap();
+        assert!(std::sync::Arc::ptr_eq(again.registry(), acme.registry()));
+
+        // テナントは元の一覧に出さず、セッションとして開けない
+        let names = |s: &Storage| {
+            let mut x = s
+                .records()
+                .unwrap()
+                .into_iter()
+                .map(|x| x.name())
+                .collect::<Vec<_>>();
+            x.sort();
+            x
+        };
+        assert_eq!(names(&storage), ["base"]);
+        assert_eq!(names(&acme), ["a1"]);
+        assert!(storage.session_meta("acme").is_err());
+        assert!(storage.scoped(None).unwrap().session_meta("base").is_ok());
+
+        for id in ["", ".hidden", "a/b", "a\\b"] {
+            assert!(!is_valid_id(id));
+            assert!(storage.tenant(id).is_err(), "{}", id);
+        }
+        // 既存のセッションと同じ名前は使えない
+        assert!(storage.tenant("base").is_err());
+    }
+}
@@ -0,0 +1,31 @@
+//! 試験で使う補助
+//!
+//! サーバーは別スレッドで受信して書き込むので、結果は条件を満たすまで待って確かめる
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long [`wait_for`] polls before giving up.
+pub const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Polls `f` until it returns some value, panics after [`WAIT_TIMEOUT`].
+///
+/// ```
+/// use uplog_tools::testing::wait_for;
+///
+/// let mut count = 0;
+/// assert_eq!(wait_for(|| { count += 1; Some(count).filter(|x| *x > 2) }), 3);
+/// ```
+pub fn wait_for<T, F: FnMut() -> Option<T>>(mut f: F) -> T {
+    let deadline = Instant::now() + WAIT_TIMEOUT;
+    loop {
+        if let Some(x) = f() {
+            return x;
+        }
+        if Instant::now() > deadline {
+            panic!("timed out after {:?}", WAIT_TIMEOUT);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,1088 @@
+//! 端末でセッションとレコードを読むための画面
+//!
+//! `tui`コマンドの状態。キーの入力で状態を変え、描画は行の一覧を返すだけにして端末なしで試せるようにする。
+//! レコードはページ単位で読んで一部だけを持つので、大きなセッションでも全てを読まない
+use std::io;
+
+use uplog::{Record, Value};
+
+use crate::{
+    format::{level_label, level_style, CYAN, DIM},
+    open_reader, CBORSequenceReader, Filter, LogRecord, OnError, ReadOptions, SessionInfo,
+    SessionQuery, SessionSortKey, SortOrder, Storage, StorageReader,
+};
+
+#[cfg(unix)]
+pub mod term;
+
+/// 1回に読むレコード数
+const PAGE_LENGTH: usize = 256;
+/// 持っておくレコードの最大数。超えたら遠い側から捨てる
+const MAX_WINDOW: usize = 4 * PAGE_LENGTH;
+/// 検索で1回に読み進める最大のレコード数。見つからなければ元の位置に戻る
+const SEARCH_LIMIT: usize = 64 * PAGE_LENGTH;
+
+const REVERSE: &str = "\x1b[7m";
+const BOLD_REVERSE: &str = "\x1b[1;7m";
+
+/// A key pressed, decoded from the terminal input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Esc,
+    Tab,
+    Backspace,
+    Char(char),
+    Ctrl(char),
+}
+
+/// Pane with the focus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pane {
+    Sessions,
+    Records,
+    Detail,
+}
+
+/// Text being typed in the status line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prompt {
+    /// part of the session names to list
+    SessionFilter,
+    /// text of the message or category to jump to, searched while typing
+    Search,
+    /// [`Filter`] expression of the records to list
+    RecordFilter,
+}
+
+/// Text drawn in an ANSI style. The style is empty for the default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub style: &'static str,
+    pub text: String,
+}
+
+impl Span {
+    fn new<S: Into<String>>(style: &'static str, text: S) -> Self {
+        Self {
+            style,
+            text: text.into(),
+        }
+    }
+}
+
+/// A row of the screen.
+pub type Line = Vec<Span>;
+
+/// 幅に合わせて切り詰め、足りなければ空白で埋める。幅は文字数で数える
+fn fit(line: Line, width: usize) -> Line {
+    let mut rest = width;
+    let mut out = Vec::with_capacity(line.len() + 1);
+    for span in line {
+        if rest == 0 {
+            break;
+        }
+        let text = span.text.chars().take(rest).collect::<String>();
+        rest -= text.chars().count();
+        out.push(Span::new(span.style, text));
+    }
+    if rest > 0 {
+        match out.last_mut() {
+            // 選択中の行は末尾まで同じ見た目にする
+            Some(last) if last.style == REVERSE || last.style == BOLD_REVERSE => {
+                last.text.push_str(&" ".repeat(rest))
+            }
+            _ => out.push(Span::new("", " ".repeat(rest))),
+        }
+    }
+    out
+}
+
+/// 選択中の行は反転する
+fn select(line: Line, selected: bool) -> Line {
+    if selected {
+        let text = line.into_iter().map(|x| x.text).collect::<String>();
+        vec![Span::new(REVERSE, text)]
+    } else {
+        line
+    }
+}
+
+/// 開いたセッションのレコードのうち、読んだ範囲
+struct RecordList {
+    info: SessionInfo,
+    reader: CBORSequenceReader,
+    filter: Option<Filter>,
+    /// 絞り込みに合うレコード
+    window: Vec<LogRecord>,
+    /// windowを読んだデータファイルの範囲
+    start: usize,
+    end: usize,
+    /// endがデータファイルの末尾
+    at_end: bool,
+    cursor: usize,
+    scroll: usize,
+}
+
+impl RecordList {
+    fn open(info: SessionInfo, filter: Option<Filter>) -> io::Result<Self> {
+        let reader = open_reader(&info)?;
+        let mut list = Self {
+            info,
+            reader,
+            filter,
+            window: Vec::new(),
+            start: 0,
+            end: 0,
+            at_end: false,
+            cursor: 0,
+            scroll: 0,
+        };
+        list.seek(0)?;
+        Ok(list)
+    }
+
+    /// 読めないレコードは飛ばし、絞り込みに合うものと読んだ数を返す
+    fn read(&mut self, index: usize, len: usize) -> io::Result<(Vec<LogRecord>, usize)> {
+        let page =
+            self.reader
+                .read_page(index, len, ReadOptions::default().on_error(OnError::Skip))?;
+        let read = page.records.len() + page.skipped.len();
+        let records = match self.filter.as_ref() {
+            Some(f) => page
+                .records
+                .into_iter()
+                .filter(|x| f.matches(x.as_record()))
+                .collect(),
+            None => page.records,
+        };
+        Ok((records, read))
+    }
+
+    /// 末尾に1ページ読み足す
+    fn load_forward(&mut self) -> io::Result<()> {
+        let (records, read) = self.read(self.end, PAGE_LENGTH)?;
+        self.end += read;
+        self.at_end = read < PAGE_LENGTH;
+        self.window.extend(records);
+        if self.window.len() > MAX_WINDOW {
+            let n = self.window.len() - MAX_WINDOW;
+            self.window.drain(..n);
+            self.start = self.window[0].index();
+            self.cursor = self.cursor.saturating_sub(n);
+            self.scroll = self.scroll.saturating_sub(n);
+        }
+        Ok(())
+    }
+
+    /// 先頭に1ページ読み足す
+    fn load_backward(&mut self) -> io::Result<()> {
+        let from = self.start.saturating_sub(PAGE_LENGTH);
+        if from == self.start {
+            return Ok(());
+        }
+        let (records, _) = self.read(from, self.start - from)?;
+        let n = records.len();
+        self.start = from;
+        self.window.splice(..0, records);
+        self.cursor += n;
+        self.scroll += n;
+        if self.window.len() > MAX_WINDOW {
+            self.end = self.window[MAX_WINDOW].index();
+            self.at_end = false;
+            self.window.truncate(MAX_WINDOW);
+        }
+        Ok(())
+    }
+
+    /// データファイルの`index`番目から読み直す
+    fn seek(&mut self, index: usize) -> io::Result<()> {
+        self.window.clear();
+        self.start = index;
+        self.end = index;
+        self.at_end = false;
+        self.cursor = 0;
+        self.scroll = 0;
+        self.load_forward()
+    }
+
+    fn next(&mut self) -> io::Result<bool> {
+        while self.cursor + 1 >= self.window.len() {
+            if self.at_end {
+                return Ok(false);
+            }
+            self.load_forward()?;
+        }
+        self.cursor += 1;
+        Ok(true)
+    }
+
+    fn prev(&mut self) -> io::Result<bool> {
+        while self.cursor == 0 || self.window.is_empty() {
+            if self.start == 0 {
+                return Ok(false);
+            }
+            self.load_backward()?;
+        }
+        self.cursor -= 1;
+        Ok(true)
+    }
+
+    fn move_by(&mut self, delta: isize) -> io::Result<()> {
+        for _ in 0..delta.unsigned_abs() {
+            let moved = if delta > 0 {
+                self.next()?
+            } else {
+                self.prev()?
+            };
+            if !moved {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn home(&mut self) -> io::Result<()> {
+        self.seek(0)
+    }
+
+    /// indexの最後の位置から末尾まで読み、最後のレコードを選ぶ
+    fn end(&mut self) -> io::Result<()> {
+        self.seek(self.reader.last_indexed())?;
+        while !self.at_end {
+            self.load_forward()?;
+        }
+        while self.window.is_empty() && self.start > 0 {
+            self.load_backward()?;
+        }
+        self.cursor = self.window.len().saturating_sub(1);
+        Ok(())
+    }
+
+    /// 書き足されたレコードを読む
+    fn refresh(&mut self) -> io::Result<()> {
+        if self.at_end {
+            self.at_end = false;
+            while !self.at_end {
+                self.load_forward()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn selected(&self) -> Option<&LogRecord> {
+        self.window.get(self.cursor)
+    }
+
+    /// 選んだ行が`rows`行に収まるように表示する位置を動かす
+    fn scroll_into_view(&mut self, rows: usize) {
+        let rows = rows.max(1);
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        } else if self.cursor >= self.scroll + rows {
+            self.scroll = self.cursor + 1 - rows;
+        }
+    }
+
+    /// 選んだレコードから`text`を含むレコードを探す。見つからなければ元の位置に戻る
+    fn find(&mut self, text: &str, forward: bool, skip_current: bool) -> io::Result<bool> {
+        let origin = (self.selected().map(LogRecord::index), self.cursor);
+        let mut moved = if skip_current {
+            self.step(forward)?
+        } else {
+            !self.window.is_empty()
+        };
+        let mut steps = 0;
+        while moved && steps < SEARCH_LIMIT {
+            if self
+                .selected()
+                .is_some_and(|x| contains(x.as_record(), text))
+            {
+                return Ok(true);
+            }
+            moved = self.step(forward)?;
+            steps += 1;
+        }
+        match origin {
+            // 読み直していなければ同じ位置に戻せる
+            (Some(index), cursor)
+                if self.window.get(cursor).map(LogRecord::index) == Some(index) =>
+            {
+                self.cursor = cursor
+            }
+            (Some(index), _) => match self.window.iter().position(|x| x.index() == index) {
+                Some(x) => self.cursor = x,
+                None => self.seek(index)?,
+            },
+            (None, _) => {}
+        }
+        Ok(false)
+    }
+
+    fn step(&mut self, forward: bool) -> io::Result<bool> {
+        if forward {
+            self.next()
+        } else {
+            self.prev()
+        }
+    }
+}
+
+/// 大文字と小文字を区別せずにメッセージかカテゴリに含む
+fn contains(record: &Record, text: &str) -> bool {
+    let text = text.to_lowercase();
+    record.message.to_lowercase().contains(&text) || record.category.to_lowercase().contains(&text)
+}
+
+/// kvの値を字下げした木として並べる
+fn push_value(lines: &mut Vec<Line>, depth: usize, key: &str, value: &Value) {
+    let indent = "  ".repeat(depth);
+    match value {
+        Value::Map(map) => {
+            lines.push(vec![Span::new(CYAN, format!("{}{}", indent, key))]);
+            for (k, v) in map {
+                push_value(lines, depth + 1, k, v);
+            }
+        }
+        Value::Array(values) => {
+            lines.push(vec![Span::new(CYAN, format!("{}{}", indent, key))]);
+            for (i, v) in values.iter().enumerate() {
+                push_value(lines, depth + 1, &format!("[{}]", i), v);
+            }
+        }
+        _ => lines.push(vec![
+            Span::new(CYAN, format!("{}{}", indent, key)),
+            Span::new("", format!(" = {}", value)),
+        ]),
+    }
+}
+
+/// State of the `tui` command: the session list, the records of the open session and the detail
+/// of the selected record.
+///
+/// Drive it with [`App::handle`] and [`App::tick`], and draw [`App::render`] to a terminal of
+/// [`App::resize`].
+pub struct App {
+    storage: Storage,
+    sort_by: SessionSortKey,
+    order: SortOrder,
+    session_filter: String,
+    sessions: Vec<SessionInfo>,
+    session_cursor: usize,
+    session_scroll: usize,
+    records: Option<RecordList>,
+    filter: Option<Filter>,
+    search: String,
+    detail_scroll: usize,
+    focus: Pane,
+    prompt: Option<(Prompt, String)>,
+    follow: bool,
+    status: String,
+    size: (usize, usize),
+    quit: bool,
+}
+
+impl App {
+    /// Lists the sessions of `storage`, newest first.
+    pub fn new(storage: Storage) -> io::Result<Self> {
+        let mut app = Self {
+            storage,
+            sort_by: SessionSortKey::Created,
+            order: SortOrder::Desc,
+            session_filter: String::new(),
+            sessions: Vec::new(),
+            session_cursor: 0,
+            session_scroll: 0,
+            records: None,
+            filter: None,
+            search: String::new(),
+            detail_scroll: 0,
+            focus: Pane::Sessions,
+            prompt: None,
+            follow: false,
+            status: String::new(),
+            size: (80, 24),
+            quit: false,
+        };
+        app.reload_sessions()?;
+        Ok(app)
+    }
+
+    /// Sets the size of the terminal in columns and rows.
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.size = (width, height);
+        self.scroll_into_view();
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn focus(&self) -> Pane {
+        self.focus
+    }
+
+    pub fn is_following(&self) -> bool {
+        self.follow
+    }
+
+    /// Message shown in the status line, such as an error of the last key.
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+
+    /// Sessions listed, in the order shown.
+    pub fn sessions(&self) -> &[SessionInfo] {
+        &self.sessions
+    }
+
+    /// Session under the cursor of the session list.
+    pub fn selected_session(&self) -> Option<&SessionInfo> {
+        self.sessions.get(self.session_cursor)
+    }
+
+    /// Session whose records are listed.
+    pub fn open_session(&self) -> Option<&SessionInfo> {
+        self.records.as_ref().map(|x| &x.info)
+    }
+
+    /// Records read around the cursor, which are a part of the open session.
+    pub fn loaded_records(&self) -> &[LogRecord] {
+        self.records.as_ref().map_or(&[], |x| &x.window)
+    }
+
+    /// Record under the cursor of the record list.
+    pub fn selected_record(&self) -> Option<&LogRecord> {
+        self.records.as_ref().and_then(RecordList::selected)
+    }
+
+    /// 画面の大きさから求めた、各領域の行数 (セッション, レコード, 詳細)。見出しの行は除く
+    fn layout(&self) -> (usize, usize, usize) {
+        let body = self.size.1.saturating_sub(1);
+        let detail = body / 3;
+        let records = body - detail;
+        (
+            body.saturating_sub(1),
+            records.saturating_sub(1),
+            detail.saturating_sub(1),
+        )
+    }
+
+    fn scroll_into_view(&mut self) {
+        let (sessions, records, _) = self.layout();
+        let rows = sessions.max(1);
+        if self.session_cursor < self.session_scroll {
+            self.session_scroll = self.session_cursor;
+        } else if self.session_cursor >= self.session_scroll + rows {
+            self.session_scroll = self.session_cursor + 1 - rows;
+        }
+        if let Some(list) = self.records.as_mut() {
+            list.scroll_into_view(records);
+        }
+    }
+
+    fn reload_sessions(&mut self) -> io::Result<()> {
+        let selected = self.selected_session().map(SessionInfo::name);
+        let query = SessionQuery {
+            sort_by: self.sort_by,
+            order: self.order,
+            name_contains: Some(self.session_filter.clone()).filter(|x| !x.is_empty()),
+            ..Default::default()
+        };
+        self.sessions = self.storage.records_paged(&query)?.sessions;
+        self.session_cursor = selected
+            .and_then(|name| self.sessions.iter().position(|x| x.name() == name))
+            .unwrap_or(0);
+        Ok(())
+    }
+
+    fn open_selected(&mut self) -> io::Result<()> {
+        if let Some(info) = self.selected_session().cloned() {
+            self.records = Some(RecordList::open(info, self.filter.clone())?);
+            self.detail_scroll = 0;
+            self.focus = Pane::Records;
+        }
+        Ok(())
+    }
+
+    /// Reads the records written to the open session since the last read when following it.
+    pub fn tick(&mut self) {
+        if !self.follow {
+            return;
+        }
+        if let Some(list) = self.records.as_mut() {
+            let result = list.refresh().map(|_| {
+                list.cursor = list.window.len().saturating_sub(1);
+            });
+            self.report(result);
+        }
+    }
+
+    /// Applies a key.
+    pub fn handle(&mut self, key: Key) {
+        let result = match self.prompt.take() {
+            Some((prompt, text)) => self.handle_prompt(prompt, text, key),
+            None => self.handle_key(key),
+        };
+        self.report(result);
+    }
+
+    /// エラーは状態行に出して続ける
+    fn report(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            self.status = e.to_string();
+        }
+        self.scroll_into_view();
+    }
+
+    fn handle_prompt(&mut self, prompt: Prompt, mut text: String, key: Key) -> io::Result<()> {
+        match key {
+            Key::Esc => return Ok(()),
+            Key::Enter => return self.submit(prompt, text),
+            Key::Backspace => {
+                text.pop();
+            }
+            Key::Ctrl('u') => text.clear(),
+            Key::Char(c) => text.push(c),
+            _ => {}
+        }
+        // 入力しながら探す
+        if prompt == Prompt::Search && !text.is_empty() {
+            if let Some(list) = self.records.as_mut() {
+                self.status = if list.find(&text, true, false)? {
+                    String::new()
+                } else {
+                    format!("not found: {}", text)
+                };
+            }
+        }
+        self.prompt = Some((prompt, text));
+        Ok(())
+    }
+
+    fn submit(&mut self, prompt: Prompt, text: String) -> io::Result<()> {
+        match prompt {
+            Prompt::SessionFilter => {
+                self.session_filter = text;
+                self.reload_sessions()?;
+            }
+            Prompt::Search => self.search = text,
+            Prompt::RecordFilter => {
+                self.filter = if text.trim().is_empty() {
+                    None
+                } else {
+                    match Filter::parse(&text) {
+                        Ok(x) => Some(x),
+                        Err(e) => {
+                            self.status = e.to_string();
+                            return Ok(());
+                        }
+                    }
+                };
+                if let Some(list) = self.records.as_mut() {
+                    list.filter = self.filter.clone();
+                    list.home()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: Key) -> io::Result<()> {
+        self.status.clear();
+        match key {
+            Key::Char('q') | Key::Ctrl('c') => self.quit = true,
+            Key::Tab => {
+                self.focus = match self.focus {
+                    Pane::Sessions if self.records.is_some() => Pane::Records,
+                    Pane::Records => Pane::Detail,
+                    _ => Pane::Sessions,
+                }
+            }
+            Key::Char('F') => self.follow = !self.follow,
+            _ => match self.focus {
+                Pane::Sessions => self.handle_sessions(key)?,
+                Pane::Records => self.handle_records(key)?,
+                Pane::Detail => self.handle_detail(key),
+            },
+        }
+        Ok(())
+    }
+
+    fn handle_sessions(&mut self, key: Key) -> io::Result<()> {
+        let last = self.sessions.len().saturating_sub(1);
+        let page = self.layout().0.max(1);
+        match key {
+            Key::Up | Key::Char('k') => self.session_cursor = self.session_cursor.saturating_sub(1),
+            Key::Down | Key::Char('j') => self.session_cursor = (self.session_cursor + 1).min(last),
+            Key::PageUp => self.session_cursor = self.session_cursor.saturating_sub(page),
+            Key::PageDown => self.session_cursor = (self.session_cursor + page).min(last),
+            Key::Home | Key::Char('g') => self.session_cursor = 0,
+            Key::End | Key::Char('G') => self.session_cursor = last,
+            Key::Enter => self.open_selected()?,
+            Key::Char('/') => {
+                self.prompt = Some((Prompt::SessionFilter, self.session_filter.clone()))
+            }
+            Key::Char('s') => {
+                self.sort_by = match self.sort_by {
+                    SessionSortKey::Created => SessionSortKey::Updated,
+                    SessionSortKey::Updated => SessionSortKey::Size,
+                    SessionSortKey::Size => SessionSortKey::Name,
+                    SessionSortKey::Name => SessionSortKey::Created,
+                };
+                self.reload_sessions()?;
+            }
+            Key::Char('r') => {
+                self.order = match self.order {
+                    SortOrder::Asc => SortOrder::Desc,
+                    SortOrder::Desc => SortOrder::Asc,
+                };
+                self.reload_sessions()?;
+            }
+            Key::Char('R') => self.reload_sessions()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_records(&mut self, key: Key) -> io::Result<()> {
+        let page = self.layout().1.max(1) as isize;
+        let list = match self.records.as_mut() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let before = list.selected().map(LogRecord::index);
+        match key {
+            Key::Up | Key::Char('k') => list.move_by(-1)?,
+            Key::Down | Key::Char('j') => list.move_by(1)?,
+            Key::PageUp => list.move_by(-page)?,
+            Key::PageDown => list.move_by(page)?,
+            Key::Home | Key::Char('g') => list.home()?,
+            Key::End | Key::Char('G') => list.end()?,
+            Key::Enter => self.focus = Pane::Detail,
+            Key::Esc => self.focus = Pane::Sessions,
+            Key::Char('/') => self.prompt = Some((Prompt::Search, String::new())),
+            Key::Char(c @ ('n' | 'N'))
+                if !self.search.is_empty() && !list.find(&self.search, c == 'n', true)? =>
+            {
+                self.status = format!("not found: {}", self.search);
+            }
+            Key::Char('f') => {
+                let text = self
+                    .filter
+                    .as_ref()
+                    .map_or(String::new(), |x| x.as_str().to_string());
+                self.prompt = Some((Prompt::RecordFilter, text));
+            }
+            _ => {}
+        }
+        if self.selected_record().map(LogRecord::index) != before {
+            self.detail_scroll = 0;
+        }
+        Ok(())
+    }
+
+    fn handle_detail(&mut self, key: Key) {
+        match key {
+            Key::Up | Key::Char('k') => self.detail_scroll = self.detail_scroll.saturating_sub(1),
+            Key::Down | Key::Char('j') => self.detail_scroll += 1,
+            Key::Home | Key::Char('g') => self.detail_scroll = 0,
+            Key::Esc => self.focus = Pane::Records,
+            _ => {}
+        }
+    }
+
+    fn title(&self, pane: Pane, text: String) -> Line {
+        let style = if self.focus == pane {
+            BOLD_REVERSE
+        } else {
+            REVERSE
+        };
+        vec![Span::new(style, text)]
+    }
+
+    fn session_lines(&self, rows: usize) -> Vec<Line> {
+        let mut title = format!(" sessions {:?} {:?}", self.sort_by, self.order).to_lowercase();
+        if !self.session_filter.is_empty() {
+            title.push_str(&format!(" /{}", self.session_filter));
+        }
+        let mut lines = vec![self.title(Pane::Sessions, title)];
+        for (i, info) in self
+            .sessions
+            .iter()
+            .enumerate()
+            .skip(self.session_scroll)
+            .take(rows)
+        {
+            let marker = if info.is_live() { "* " } else { "  " };
+            let line = vec![Span::new(DIM, marker), Span::new("", info.name())];
+            lines.push(select(line, i == self.session_cursor));
+        }
+        lines
+    }
+
+    fn record_lines(&self, rows: usize) -> Vec<Line> {
+        let list = match self.records.as_ref() {
+            Some(x) => x,
+            None => return vec![self.title(Pane::Records, " records".to_string())],
+        };
+        let mut title = format!(" {}", list.info.name());
+        if let Some(f) = list.filter.as_ref() {
+            title.push_str(&format!(" [{}]", f.as_str()));
+        }
+        if self.follow {
+            title.push_str(" FOLLOW");
+        }
+        let mut lines = vec![self.title(Pane::Records, title)];
+        for (i, x) in list.window.iter().enumerate().skip(list.scroll).take(rows) {
+            let record = x.as_record();
+            let time = match x.time() {
+                Some(t) => t.timestamp.format("%H:%M:%S%.3f").to_string(),
+                None => format!("{:.3}", record.elapsed.as_secs_f64()),
+            };
+            let line = vec![
+                Span::new(DIM, format!("{} ", time)),
+                Span::new(
+                    level_style(record.level()),
+                    format!("{:<5} ", level_label(record.level())),
+                ),
+                Span::new(CYAN, format!("[{}] ", record.category)),
+                Span::new("", record.message.replace('\n', " ")),
+            ];
+            lines.push(select(line, i == list.cursor));
+        }
+        lines
+    }
+
+    /// 選んだレコードの項目とkvの木
+    pub fn detail_lines(&self) -> Vec<Line> {
+        let x = match self.selected_record() {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+        let record = x.as_record();
+        let field = |name: &str, value: String| {
+            vec![Span::new(DIM, format!("{:<9}", name)), Span::new("", value)]
+        };
+        let mut lines = vec![field("index", x.index().to_string())];
+        if let Some(t) = x.time() {
+            let mark = if t.timestamp_synthetic { " ~" } else { "" };
+            lines.push(field("time", format!("{}{}", t.timestamp, mark)));
+        }
+        lines.push(field("elapsed", format!("{:?}", record.elapsed)));
+        lines.push(vec![
+            Span::new(DIM, format!("{:<9}", "level")),
+            Span::new(level_style(record.level()), level_label(record.level())),
+        ]);
+        lines.push(field("category", record.category.clone()));
+        if let Some(file) = record.file() {
+            let line = record.line().map_or(String::new(), |x| format!(":{}", x));
+            lines.push(field("location", format!("{}{}", file, line)));
+        }
+        for (i, text) in record.message.lines().enumerate() {
+            lines.push(field(if i == 0 { "message" } else { "" }, text.to_string()));
+        }
+        for (k, v) in record.key_values().into_iter().flatten() {
+            push_value(&mut lines, 0, k, v);
+        }
+        lines
+    }
+
+    fn status_line(&self) -> Line {
+        match self.prompt.as_ref() {
+            Some((prompt, text)) => {
+                let label = match prompt {
+                    Prompt::SessionFilter => "session name: ",
+                    Prompt::Search => "search: ",
+                    Prompt::RecordFilter => "filter: ",
+                };
+                vec![Span::new(BOLD_REVERSE, label), Span::new("", text.clone())]
+            }
+            None if !self.status.is_empty() => vec![Span::new(BOLD_REVERSE, self.status.clone())],
+            None => vec![Span::new(
+                DIM,
+                "q quit  tab pane  enter open  / search  n/N next  f filter  F follow  s/r sort",
+            )],
+        }
+    }
+
+    /// Draws the screen as [`App::resize`] rows of its width.
+    pub fn render(&self) -> Vec<Line> {
+        let (width, height) = self.size;
+        let (session_rows, record_rows, detail_rows) = self.layout();
+        let left = (width / 4).clamp(12.min(width / 2), 40);
+        let right = width.saturating_sub(left + 1);
+
+        let mut right_lines = self.record_lines(record_rows);
+        right_lines.resize(record_rows + 1, Vec::new());
+        right_lines.push(self.title(Pane::Detail, " detail".to_string()));
+        let detail = self.detail_lines();
+        right_lines.extend(
+            detail
+                .into_iter()
+                .skip(self.detail_scroll)
+                .take(detail_rows),
+        );
+        let mut left_lines = self.session_lines(session_rows);
+
+        let body = height.saturating_sub(1);
+        left_lines.resize(body, Vec::new());
+        right_lines.resize(body, Vec::new());
+        let mut lines = left_lines
+            .into_iter()
+            .zip(right_lines)
+            .map(|(l, r)| {
+                let mut line = fit(l, left);
+                line.push(Span::new(DIM, "│"));
+                line.extend(fit(r, right));
+                fit(line, width)
+            })
+            .collect::<Vec<_>>();
+        if height > 0 {
+            lines.push(fit(self.status_line(), width));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level, Value};
+
+    use super::{App, Key, Pane, MAX_WINDOW};
+    use crate::{writer::RecordWriter, Storage};
+
+    fn text(app: &App) -> Vec<String> {
+        app.render()
+            .into_iter()
+            .map(|x| x.into_iter().map(|x| x.text).collect())
+            .collect()
+    }
+
+    fn keys(app: &mut App, keys: &[Key]) {
+        for key in keys {
+            app.handle(*key);
+        }
+    }
+
+    fn typing(app: &mut App, s: &str) {
+        for c in s.chars() {
+            app.handle(Key::Char(c));
+        }
+    }
+
+    /// 名前の順に並べて先頭を選ぶ。並べ替えても選んだセッションは変わらない
+    fn by_name(app: &mut App) {
+        keys(app, &[Key::Char('s'), Key::Char('s'), Key::Char('s')]);
+        keys(app, &[Key::Char('r'), Key::Home]);
+    }
+
+    fn fixture(dir: &TempDir, large: usize) -> Storage {
+        devinit!();
+        let storage = Storage::new(dir.path()).unwrap();
+        let mut session = storage.create_session("alpha").unwrap();
+        for i in 0..30_u64 {
+            let level = if i % 10 == 9 {
+                Level::Error
+            } else {
+                Level::Info
+            };
+            let message = if i == 17 { "needle here" } else { "message" };
+            session
+                .push(&devlog!(level, "app.net", message, "i", i))
+                .unwrap();
+        }
+        let mut kv = uplog::KV::new();
+        kv.insert(
+            "nested".to_string(),
+            Value::Map([("inner".to_string(), Value::U64(7))].into_iter().collect()),
+        );
+        let mut record = devlog!(Level::Warn, "app.kv", "with kv");
+        record.kv = Some(kv);
+        session.push(&record).unwrap();
+        drop(session);
+
+        let mut session = storage.create_session("beta").unwrap();
+        for i in 0..large as u64 {
+            session
+                .push(&devlog!(Level::Debug, "bulk", "line", "i", i))
+                .unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn test_navigation() {
+        let dir = TempDir::new("tui").unwrap();
+        let mut app = App::new(fixture(&dir, 10)).unwrap();
+        app.resize(100, 40);
+        by_name(&mut app);
+        let names = app.sessions().iter().map(|x| x.name()).collect::<Vec<_>>();
+        assert_eq!(names, ["alpha", "beta"]);
+
+        keys(&mut app, &[Key::Enter]);
+        assert_eq!(app.focus(), Pane::Records);
+        assert_eq!(app.open_session().unwrap().name(), "alpha");
+        keys(&mut app, &[Key::Down, Key::Down, Key::Up]);
+        assert_eq!(app.selected_record().unwrap().index(), 1);
+        keys(&mut app, &[Key::End]);
+        assert_eq!(app.selected_record().unwrap().index(), 30);
+        keys(&mut app, &[Key::PageUp]);
+        assert!(app.selected_record().unwrap().index() < 30);
+        keys(&mut app, &[Key::Home]);
+        assert_eq!(app.selected_record().unwrap().index(), 0);
+
+        // 詳細にkvの木を出す
+        keys(&mut app, &[Key::End, Key::Enter]);
+        assert_eq!(app.focus(), Pane::Detail);
+        let detail = text(&app).join("\n");
+        assert!(detail.contains("nested"), "{}", detail);
+        assert!(detail.contains("  inner = 7"), "{}", detail);
+
+        // 画面の大きさに合わせる
+        let screen = text(&app);
+        assert_eq!(screen.len(), 40);
+        assert!(screen.iter().all(|x| x.chars().count() == 100));
+        app.resize(40, 8);
+        let screen = text(&app);
+        assert_eq!(screen.len(), 8);
+        assert!(screen.iter().all(|x| x.chars().count() == 40));
+
+        keys(&mut app, &[Key::Esc, Key::Esc, Key::Down, Key::Enter]);
+        assert_eq!(app.open_session().unwrap().name(), "beta");
+        keys(&mut app, &[Key::Char('q')]);
+        assert!(app.should_quit());
+    }
+
+    #[test]
+    fn test_filter_and_search() {
+        let dir = TempDir::new("tui").unwrap();
+        let mut app = App::new(fixture(&dir, 10)).unwrap();
+        by_name(&mut app);
+
+        // セッション名で絞り込む
+        keys(&mut app, &[Key::Char('/')]);
+        typing(&mut app, "bet");
+        keys(&mut app, &[Key::Enter]);
+        assert_eq!(app.sessions().len(), 1);
+        keys(&mut app, &[Key::Char('/'), Key::Backspace, Key::Backspace]);
+        keys(
+            &mut app,
+            &[Key::Backspace, Key::Enter, Key::Home, Key::Enter],
+        );
+        assert_eq!(app.sessions().len(), 2);
+        assert_eq!(app.open_session().unwrap().name(), "alpha");
+
+        // 入力しながら探し、nで次を探す
+        keys(&mut app, &[Key::Char('/')]);
+        typing(&mut app, "NEEDLE");
+        assert_eq!(app.selected_record().unwrap().index(), 17);
+        keys(&mut app, &[Key::Enter, Key::Char('n')]);
+        assert_eq!(app.selected_record().unwrap().index(), 17);
+        assert!(app.status().contains("not found"));
+        keys(&mut app, &[Key::Char('/')]);
+        typing(&mut app, "app.kv");
+        keys(&mut app, &[Key::Enter]);
+        assert_eq!(app.selected_record().unwrap().index(), 30);
+        keys(&mut app, &[Key::Char('/')]);
+        typing(&mut app, "needle");
+        keys(&mut app, &[Key::Enter, Key::Char('N')]);
+        assert_eq!(app.selected_record().unwrap().index(), 17);
+
+        // 式でレコードを絞り込む
+        keys(&mut app, &[Key::Char('f')]);
+        typing(&mut app, "level >= error");
+        keys(&mut app, &[Key::Enter]);
+        let ids = app
+            .loaded_records()
+            .iter()
+            .map(|x| x.index())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [9, 19, 29]);
+
+        // 解析できない式は状態行に出して絞り込みを変えない
+        keys(&mut app, &[Key::Char('f')]);
+        typing(&mut app, " and (");
+        keys(&mut app, &[Key::Enter]);
+        assert!(!app.status().is_empty());
+        assert_eq!(app.loaded_records().len(), 3);
+
+        // 空にすると全て出す
+        keys(&mut app, &[Key::Char('f'), Key::Ctrl('u'), Key::Enter]);
+        assert_eq!(app.loaded_records().len(), 31);
+    }
+
+    #[test]
+    fn test_large_session() {
+        let dir = TempDir::new("tui").unwrap();
+        let total = MAX_WINDOW * 3 + 10;
+        let mut app = App::new(fixture(&dir, total)).unwrap();
+        by_name(&mut app);
+        keys(&mut app, &[Key::Down, Key::Enter]);
+        assert_eq!(app.open_session().unwrap().name(), "beta");
+        assert!(app.loaded_records().len() < total);
+
+        keys(&mut app, &[Key::End]);
+        assert_eq!(app.selected_record().unwrap().index(), total - 1);
+        assert!(app.loaded_records().len() <= MAX_WINDOW);
+
+        // 持っている範囲の外へ戻る
+        for _ in 0..(MAX_WINDOW * 2 / 16) {
+            keys(&mut app, &[Key::PageUp]);
+        }
+        let index = app.selected_record().unwrap().index();
+        assert!(index < total - MAX_WINDOW, "{}", index);
+        assert!(app.loaded_records().len() <= MAX_WINDOW);
+        let loaded = app.loaded_records();
+        assert!(loaded.windows(2).all(|x| x[0].index() + 1 == x[1].index()));
+
+        keys(&mut app, &[Key::Home]);
+        assert_eq!(app.selected_record().unwrap().index(), 0);
+        for _ in 0..(MAX_WINDOW * 2 / 16) {
+            keys(&mut app, &[Key::PageDown]);
+        }
+        assert!(app.selected_record().unwrap().index() > MAX_WINDOW);
+        assert!(app.loaded_records().len() <= MAX_WINDOW);
+    }
+
+    #[test]
+    fn test_follow() {
+        let dir = TempDir::new("tui").unwrap();
+        let storage = fixture(&dir, 0);
+        let mut session = storage.create_session("gamma").unwrap();
+        session
+            .push(&devlog!(Level::Info, "live", "first"))
+            .unwrap();
+        session.flush();
+
+        let mut app = App::new(storage).unwrap();
+        by_name(&mut app);
+        keys(&mut app, &[Key::End, Key::Enter, Key::Char('F')]);
+        assert!(app.is_following());
+        assert_eq!(app.loaded_records().len(), 1);
+
+        for i in 0..5_u64 {
+            session
+                .push(&devlog!(Level::Info, "live", "next", "i", i))
+                .unwrap();
+        }
+        session.flush();
+        app.tick();
+        assert_eq!(app.loaded_records().len(), 6);
+        assert_eq!(app.selected_record().unwrap().index(), 5);
+
+        // 止めている間は読まない
+        keys(&mut app, &[Key::Char('F')]);
+        session.push(&devlog!(Level::Info, "live", "last")).unwrap();
+        session.flush();
+        app.tick();
+        assert_eq!(app.loaded_records().len(), 6);
+    }
+}
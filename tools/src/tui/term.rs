@@ -0,0 +1,200 @@
+//! 端末の入出力
+//!
+//! termiosで端末をrawモードにし、ANSIのエスケープシーケンスで描く。
+//! 大きさは入力を待つ間隔ごとに確かめるので、SIGWINCHを受けなくても変わった大きさで描き直す
+use std::{
+    io::{self, Write},
+    os::unix::io::AsRawFd,
+    time::Duration,
+};
+
+use super::{App, Key};
+use crate::format::RESET;
+
+/// 入力を待つ間隔。この間隔で大きさの変化と追従するセッションを確かめる
+const TICK: Duration = Duration::from_millis(250);
+
+/// 抜けるときに端末を元に戻す
+struct RawMode {
+    fd: i32,
+    saved: libc::termios,
+}
+
+impl RawMode {
+    fn enable(fd: i32) -> io::Result<Self> {
+        // SAFETY: tcgetattrが埋めるまで使わない
+        let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = saved;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd, saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.saved) };
+    }
+}
+
+/// 端末の(列, 行)
+fn terminal_size(fd: i32) -> Option<(usize, usize)> {
+    let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } != 0 || size.ws_col == 0 {
+        return None;
+    }
+    Some((size.ws_col as usize, size.ws_row as usize))
+}
+
+/// 入力があるか`timeout`まで待つ
+fn wait_input(fd: i32, timeout: Duration) -> io::Result<bool> {
+    let mut poll = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as i32) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(e),
+            }
+        }
+        n => Ok(n > 0),
+    }
+}
+
+/// 1回に読んだ入力をキーに分ける。知らないエスケープシーケンスは捨てる
+pub fn decode_keys(buf: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let text = String::from_utf8_lossy(buf);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\x1b' if chars.peek() == Some(&'[') || chars.peek() == Some(&'O') => {
+                chars.next();
+                let mut seq = String::new();
+                for c in chars.by_ref() {
+                    seq.push(c);
+                    if c.is_ascii_alphabetic() || c == '~' {
+                        break;
+                    }
+                }
+                match seq.as_str() {
+                    "A" => Key::Up,
+                    "B" => Key::Down,
+                    "H" | "1~" | "7~" => Key::Home,
+                    "F" | "4~" | "8~" => Key::End,
+                    "5~" => Key::PageUp,
+                    "6~" => Key::PageDown,
+                    _ => continue,
+                }
+            }
+            '\x1b' => Key::Esc,
+            '\r' | '\n' => Key::Enter,
+            '\t' => Key::Tab,
+            '\x7f' | '\x08' => Key::Backspace,
+            c @ '\x01'..='\x1a' => Key::Ctrl((c as u8 - 1 + b'a') as char),
+            c if c.is_control() => continue,
+            c => Key::Char(c),
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+fn draw<W: Write>(out: &mut W, app: &App) -> io::Result<()> {
+    let mut buf = String::new();
+    for (row, line) in app.render().into_iter().enumerate() {
+        buf.push_str(&format!("\x1b[{};1H", row + 1));
+        for span in line {
+            if span.style.is_empty() {
+                buf.push_str(&span.text);
+            } else {
+                buf.push_str(span.style);
+                buf.push_str(&span.text);
+                buf.push_str(RESET);
+            }
+        }
+    }
+    out.write_all(buf.as_bytes())?;
+    out.flush()
+}
+
+/// Runs `app` in the terminal of stdin and stdout until it quits.
+pub fn run(mut app: App) -> io::Result<()> {
+    let fd = io::stdin().as_raw_fd();
+    let out_fd = io::stdout().as_raw_fd();
+    let raw = RawMode::enable(fd)?;
+    let mut out = io::stdout().lock();
+    // 別の画面に描き、抜けたら元の画面に戻す
+    out.write_all(b"\x1b[?1049h\x1b[?25l\x1b[2J")?;
+    let result = (|| {
+        let mut size = None;
+        let mut buf = [0_u8; 64];
+        loop {
+            let current = terminal_size(out_fd).unwrap_or((80, 24));
+            if size != Some(current) {
+                size = Some(current);
+                app.resize(current.0, current.1);
+                out.write_all(b"\x1b[2J")?;
+            }
+            draw(&mut out, &app)?;
+            if app.should_quit() {
+                return Ok(());
+            }
+            if wait_input(fd, TICK)? {
+                // 標準入力のバッファを通すと読み残しをpollで待ってしまうので直接読む
+                let n = match unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) } {
+                    -1 => return Err(io::Error::last_os_error()),
+                    n => n as usize,
+                };
+                if n == 0 {
+                    return Ok(());
+                }
+                for key in decode_keys(&buf[..n]) {
+                    app.handle(key);
+                }
+            } else {
+                app.tick();
+            }
+        }
+    })();
+    out.write_all(b"\x1b[?25h\x1b[?1049l")?;
+    out.flush()?;
+    drop(raw);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_keys;
+    use crate::tui::Key;
+
+    #[test]
+    fn test_decode_keys() {
+        assert_eq!(
+            decode_keys(b"j\x1b[A\x1b[6~\x1bOH\r\x7f\x15/\xe3\x81\x82"),
+            [
+                Key::Char('j'),
+                Key::Up,
+                Key::PageDown,
+                Key::Home,
+                Key::Enter,
+                Key::Backspace,
+                Key::Ctrl('u'),
+                Key::Char('/'),
+                Key::Char('あ'),
+            ]
+        );
+        // 単独のESCと知らないシーケンス
+        assert_eq!(decode_keys(b"\x1b"), [Key::Esc]);
+        assert_eq!(decode_keys(b"\x1b[200~q"), [Key::Char('q')]);
+    }
+}
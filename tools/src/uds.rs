@@ -0,0 +1,314 @@
+//! 同じホストのクライアントからUnix domain socketで受信する
+//!
+//! 長さのヘッダーを付けたメッセージを受け取り、WsConnと同じ経路でセッションに書き込む。
+//! 形式は[`uplog::protocol::FRAME_HEADER_LEN`]を参照
+use std::{
+    fs::Permissions,
+    io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use actix::prelude::*;
+use futures::{channel::mpsc, stream::Stream, StreamExt};
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+use uplog::protocol::{frame_header, frame_len, Codec, ServerMessage, FRAME_HEADER_LEN};
+use uuid::Uuid;
+
+use crate::{
+    actor::{
+        ClientControl, DecodeFailure, DecodePolicy, Inbound, StorageRequest, StorageResponse,
+        Takeover,
+    },
+    decode::DecodeLimits,
+    ingest::IngestPipeline,
+    lifecycle::CloseReason,
+};
+
+/// Accepts clients built with `uplog::Builder::uds_path`.
+pub struct UdsListener {
+    path: PathBuf,
+    mode: Option<u32>,
+    storage_addr: Recipient<StorageRequest>,
+    decode_policy: DecodePolicy,
+    decode_limits: DecodeLimits,
+    ingest: IngestPipeline,
+    idle_timeout: Option<Duration>,
+}
+
+impl UdsListener {
+    pub fn new<P: AsRef<Path>>(path: P, storage_addr: Recipient<StorageRequest>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            mode: None,
+            storage_addr,
+            decode_policy: DecodePolicy::default(),
+            decode_limits: DecodeLimits::default(),
+            ingest: IngestPipeline::default(),
+            idle_timeout: None,
+        }
+    }
+
+    /// ソケットファイルのパーミッション。指定しなければumaskに従う
+    pub fn mode(mut self, mode: Option<u32>) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn decode_policy(mut self, policy: DecodePolicy) -> Self {
+        self.decode_policy = policy;
+        self
+    }
+
+    pub fn decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.decode_limits = limits;
+        self
+    }
+
+    /// 保存前にレコードに適用する加工
+    pub fn ingest(mut self, pipeline: IngestPipeline) -> Self {
+        self.ingest = pipeline;
+        self
+    }
+
+    /// 無通信の時間を超えた接続を閉じる
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// ソケットを作って受け付けを始める。actixのSystemの中で呼ぶ
+    pub fn start(self) -> io::Result<()> {
+        // 前回の終了時に残ったソケットは置き換える。ソケット以外は消さない
+        if let Ok(meta) = std::fs::symlink_metadata(&self.path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", self.path.display()),
+                ));
+            }
+            std::fs::remove_file(&self.path)?;
+        }
+        let mut listener = UnixListener::bind(&self.path)?;
+        if let Some(mode) = self.mode {
+            std::fs::set_permissions(&self.path, Permissions::from_mode(mode))?;
+        }
+        info!("listen at {}", self.path.display());
+        actix::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().await {
+                match stream {
+                    Ok(stream) => self.accept(stream),
+                    Err(e) => error!("failed to accept uds connection {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn accept(&self, stream: UnixStream) {
+        // デフォルトでは64KBのペイロードのため拡張する
+        let max_size = uplog::DEFAULT_BUFFER_SIZE.max(self.decode_limits.max_record_bytes);
+        let (reader, mut writer) = tokio::io::split(stream);
+        let (sender, mut receiver) = mpsc::unbounded::<Vec<u8>>();
+        actix::spawn(async move {
+            while let Some(buf) = receiver.next().await {
+                let header = match frame_header(buf.len()) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!("failed to send to uds client {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = writer.write_all(&header).await {
+                    warn!("failed to send to uds client {}", e);
+                    break;
+                }
+                if let Err(e) = writer.write_all(&buf).await {
+                    warn!("failed to send to uds client {}", e);
+                    break;
+                }
+            }
+        });
+        let mut inbound = Inbound::new(Uuid::new_v4(), self.path.display().to_string());
+        inbound.decode_policy = self.decode_policy;
+        inbound.decode_limits = self.decode_limits;
+        inbound.ingest = self.ingest.clone();
+        inbound.idle_timeout = self.idle_timeout;
+        let storage_addr = self.storage_addr.clone();
+        UdsConn::create(move |ctx| {
+            ctx.add_stream(frames(reader, max_size));
+            UdsConn {
+                inbound,
+                storage_addr,
+                sender,
+            }
+        });
+    }
+}
+
+/// 長さのヘッダーで区切ったメッセージを読む。上限を超える長さは読まずに失敗する
+fn frames<R: AsyncRead + Unpin + 'static>(
+    reader: R,
+    max_size: usize,
+) -> impl Stream<Item = io::Result<Vec<u8>>> {
+    futures::stream::try_unfold(reader, move |mut reader| async move {
+        let mut header = [0_u8; FRAME_HEADER_LEN];
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = frame_len(header);
+        if len > max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes exceeds {}", len, max_size),
+            ));
+        }
+        let mut buf = vec![0_u8; len];
+        reader.read_exact(&mut buf).await?;
+        Ok(Some((buf, reader)))
+    })
+}
+
+/// Unix domain socketの1接続
+pub struct UdsConn {
+    inbound: Inbound,
+    storage_addr: Recipient<StorageRequest>,
+    /// クライアントに送るメッセージ
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl UdsConn {
+    fn on_decode_failure(&mut self, failure: DecodeFailure, ctx: &mut <Self as Actor>::Context) {
+        if let Some(buf) = failure.report {
+            self.sender.unbounded_send(buf).ok();
+        }
+        if let Some(reason) = failure.close {
+            info!("close connection [{}] {}", self.inbound.id, reason);
+            self.inbound.close_reason = CloseReason::DecodeError;
+            ctx.stop();
+        }
+    }
+}
+
+impl Actor for UdsConn {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.storage_addr
+            .send(StorageRequest {
+                addr: ctx.address().recipient(),
+                control: ctx.address().recipient(),
+                takeover: ctx.address().recipient(),
+                self_id: self.inbound.id,
+                // 接続時に送る手段がないので同じクライアントの再接続は区別しない
+                client_session: None,
+                remote_addr: self.inbound.remote_addr.clone(),
+                codec: Codec::Cbor,
+                // クライアントはDurationの形式で送る
+                time_precision: None,
+                clock_offset_ms: None,
+                label: None,
+                // 受信パスのラベルもトークンもないのでデータディレクトリに作る
+                tenant: None,
+                // レコードより先にセッションを作るので開始時刻はわからない
+                start_at: None,
+                // ビルド情報は最初のレコードにだけある
+                build_info: None,
+            })
+            .into_actor(self)
+            .then(|res, _, ctx| {
+                if res.is_err() {
+                    ctx.stop();
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+        if let Some(timeout) = self.inbound.idle_timeout {
+            ctx.run_interval(timeout / 2, |act, ctx| {
+                if act.inbound.is_idle() {
+                    info!("close idle connection [{}]", act.inbound.id);
+                    act.inbound.close_reason = CloseReason::IdleTimeout;
+                    ctx.stop();
+                }
+            });
+        }
+    }
+
+    fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        self.inbound.close_session();
+        Running::Stop
+    }
+}
+
+impl Handler<StorageResponse> for UdsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: StorageResponse, ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            StorageResponse::Accept(a) => self.inbound.attach(a),
+            StorageResponse::Reject(reason) => {
+                info!("reject connection [{}] {}", self.inbound.id, reason);
+                ctx.stop();
+            }
+            StorageResponse::Error(e) => {
+                error!("failed to create session {}", e);
+                ctx.stop();
+            }
+        };
+    }
+}
+
+impl Handler<Takeover> for UdsConn {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Takeover, ctx: &mut Self::Context) -> Self::Result {
+        // セッションは新しい接続が使い続けるので閉じない
+        self.inbound.session_addr = None;
+        ctx.stop();
+    }
+}
+
+impl Handler<ClientControl> for UdsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientControl, _ctx: &mut Self::Context) -> Self::Result {
+        info!("send command [{}] {:?}", self.inbound.id, msg.0);
+        match serde_cbor::to_vec(&ServerMessage::Control(msg.0)) {
+            Ok(buf) => {
+                self.sender.unbounded_send(buf).ok();
+            }
+            Err(e) => error!("failed to encode command [{}] {}", self.inbound.id, e),
+        }
+    }
+}
+
+impl StreamHandler<io::Result<Vec<u8>>> for UdsConn {
+    fn handle(&mut self, item: io::Result<Vec<u8>>, ctx: &mut Self::Context) {
+        self.inbound.touch();
+        match item {
+            Ok(bin) => {
+                if let Err(failure) = self.inbound.feed(&bin) {
+                    self.on_decode_failure(failure, ctx);
+                }
+            }
+            Err(e) => {
+                warn!("connection error [{}] {:?}", self.inbound.id, e);
+                ctx.stop()
+            }
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        info!("close by client [{}]", self.inbound.id);
+        self.inbound.close_reason = CloseReason::Client(None);
+        ctx.stop();
+    }
+}
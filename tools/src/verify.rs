@@ -0,0 +1,689 @@
+//! 異常終了の後にセッションのファイルを確認して直す
+//!
+//! データファイルはCBOR Sequenceで、レコードごとのCRCや通し番号は持たない。
+//! そのため区切りを辿れるか、indexの番号とオフセットがデータと一致するかを確認する。
+//! 修復はindexと概要の作り直しと、末尾の書きかけのレコードの切り詰めだけを行い、
+//! 変更するファイルは先に`.bak`に残す
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use uplog::Record;
+
+use crate::{
+    overview::{Overview, OverviewBuilder, OverviewEntry, OVERVIEW_FILENAME},
+    writer::CBORSequenceWriter,
+};
+
+/// indexの1項目の大きさ
+const INDEX_ENTRY_BYTES: u64 = 16;
+
+/// A problem found in a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// the data file does not exist
+    MissingData,
+    /// the data file is encrypted and was not checked
+    Encrypted,
+    /// the data file ends in the middle of a record
+    TrailingPartialRecord { offset: u64, len: u64 },
+    /// a record that is not valid CBOR or not a record, the rest of the file is not checked
+    CorruptRecord {
+        index: usize,
+        offset: u64,
+        message: String,
+    },
+    /// the index file does not exist
+    MissingIndex,
+    /// the index file is not a multiple of the entry size
+    MalformedIndex { len: u64 },
+    /// the number of index entries does not match the records
+    IndexLength { expected: usize, actual: usize },
+    /// an index entry points to another record number than its position requires
+    IndexRecordGap {
+        entry: usize,
+        expected: usize,
+        actual: usize,
+    },
+    /// an index entry points to another offset than the record starts at
+    IndexOffsetMismatch {
+        entry: usize,
+        expected: u64,
+        actual: u64,
+    },
+    /// the overview file does not exist, as in sessions written by older versions
+    MissingOverview,
+    /// the overview file cannot be read or does not summarize the records
+    OverviewMismatch { message: String },
+}
+
+impl Finding {
+    /// `--repair`で直せるか
+    pub fn is_repairable(&self) -> bool {
+        !matches!(
+            self,
+            Self::MissingData | Self::Encrypted | Self::CorruptRecord { .. }
+        )
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingData => write!(f, "data file is missing"),
+            Self::Encrypted => write!(f, "data file is encrypted, not checked"),
+            Self::TrailingPartialRecord { offset, len } => write!(
+                f,
+                "partial record of {} bytes at the end, offset {}",
+                len, offset
+            ),
+            Self::CorruptRecord {
+                index,
+                offset,
+                message,
+            } => write!(
+                f,
+                "corrupt record {} at offset {}: {}",
+                index, offset, message
+            ),
+            Self::MissingIndex => write!(f, "index file is missing"),
+            Self::MalformedIndex { len } => {
+                write!(f, "index file has {} bytes, not a multiple of 16", len)
+            }
+            Self::IndexLength { expected, actual } => {
+                write!(f, "index has {} entries, expected {}", actual, expected)
+            }
+            Self::IndexRecordGap {
+                entry,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "index entry {} points to record {}, expected {}",
+                entry, actual, expected
+            ),
+            Self::IndexOffsetMismatch {
+                entry,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "index entry {} points to offset {}, expected {}",
+                entry, actual, expected
+            ),
+            Self::MissingOverview => write!(f, "overview file is missing"),
+            Self::OverviewMismatch { message } => write!(f, "overview is wrong: {}", message),
+        }
+    }
+}
+
+/// A change made by the repair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Repair {
+    /// wrote a new index file with this many entries
+    IndexRebuilt { entries: usize },
+    /// wrote a new overview file with this many entries
+    OverviewRebuilt { entries: usize },
+    /// truncated the data file
+    Truncated { from: u64, to: u64 },
+}
+
+impl Display for Repair {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IndexRebuilt { entries } => write!(f, "rebuilt index with {} entries", entries),
+            Self::OverviewRebuilt { entries } => {
+                write!(f, "rebuilt overview with {} entries", entries)
+            }
+            Self::Truncated { from, to } => {
+                write!(f, "truncated data from {} to {} bytes", from, to)
+            }
+        }
+    }
+}
+
+/// Result of [`verify_session`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionReport {
+    pub name: String,
+    /// readable records
+    pub records: usize,
+    /// bytes of the readable records
+    pub valid_bytes: u64,
+    pub findings: Vec<Finding>,
+    pub repairs: Vec<Repair>,
+}
+
+impl SessionReport {
+    pub fn is_ok(&self) -> bool {
+        self.findings.is_empty()
+    }
+
+    /// 修復した後も残っている問題があるか
+    pub fn has_unrepaired(&self) -> bool {
+        self.findings
+            .iter()
+            .any(|x| self.repairs.is_empty() || !x.is_repairable())
+    }
+}
+
+impl Display for SessionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} records, {} bytes, ",
+            self.name, self.records, self.valid_bytes
+        )?;
+        match self.findings.len() {
+            0 => write!(f, "ok")?,
+            n => write!(f, "{} findings", n)?,
+        }
+        for x in self.findings.iter() {
+            write!(f, "\n  - {}", x)?;
+        }
+        for x in self.repairs.iter() {
+            write!(f, "\n  * {}", x)?;
+        }
+        Ok(())
+    }
+}
+
+/// データファイルを辿った結果
+struct DataScan {
+    /// indexに書くべき項目
+    index: Vec<(usize, u64)>,
+    /// 概要に書くべき項目
+    overview: Vec<OverviewEntry>,
+    records: usize,
+    valid_bytes: u64,
+    data_bytes: u64,
+    finding: Option<Finding>,
+}
+
+fn scan_data(path: &Path) -> io::Result<DataScan> {
+    let file = File::open(path)?;
+    let data_bytes = file.metadata()?.len();
+    let mut iter =
+        serde_cbor::Deserializer::from_reader(BufReader::new(file)).into_iter::<Record>();
+    let mut overview = OverviewBuilder::default();
+    let mut scan = DataScan {
+        index: Vec::new(),
+        overview: Vec::new(),
+        records: 0,
+        valid_bytes: 0,
+        data_bytes,
+        finding: None,
+    };
+    loop {
+        let offset = iter.byte_offset() as u64;
+        if offset >= data_bytes {
+            break;
+        }
+        match iter.next() {
+            None => break,
+            Some(Ok(record)) => {
+                let len = iter.byte_offset() as u64 - offset;
+                scan.overview.extend(overview.push(&record, len));
+                if scan
+                    .records
+                    .is_multiple_of(CBORSequenceWriter::INDEX_INTERVAL)
+                {
+                    scan.index.push((scan.records, offset));
+                }
+                scan.records += 1;
+                scan.valid_bytes = iter.byte_offset() as u64;
+            }
+            Some(Err(e)) if e.is_eof() => {
+                scan.finding = Some(Finding::TrailingPartialRecord {
+                    offset,
+                    len: data_bytes - offset,
+                });
+                break;
+            }
+            Some(Err(e)) => {
+                scan.finding = Some(Finding::CorruptRecord {
+                    index: scan.records,
+                    offset,
+                    message: e.to_string(),
+                });
+                break;
+            }
+        }
+    }
+    Ok(scan)
+}
+
+/// indexの項目を読む。項目の大きさで割り切れない場合は最後の半端を除く
+fn read_index(path: &Path) -> io::Result<(Vec<(usize, u64)>, u64)> {
+    let buf = std::fs::read(path)?;
+    let entries = buf
+        .chunks_exact(INDEX_ENTRY_BYTES as usize)
+        .map(|x| {
+            let id = u64::from_le_bytes(x[..8].try_into().unwrap());
+            let offset = u64::from_le_bytes(x[8..].try_into().unwrap());
+            (id as usize, offset)
+        })
+        .collect();
+    Ok((entries, buf.len() as u64))
+}
+
+fn check_index(expected: &[(usize, u64)], actual: &[(usize, u64)], findings: &mut Vec<Finding>) {
+    if expected.len() != actual.len() {
+        findings.push(Finding::IndexLength {
+            expected: expected.len(),
+            actual: actual.len(),
+        });
+    }
+    for (entry, (e, a)) in expected.iter().zip(actual.iter()).enumerate() {
+        if e.0 != a.0 {
+            findings.push(Finding::IndexRecordGap {
+                entry,
+                expected: e.0,
+                actual: a.0,
+            });
+        } else if e.1 != a.1 {
+            findings.push(Finding::IndexOffsetMismatch {
+                entry,
+                expected: e.1,
+                actual: a.1,
+            });
+        }
+    }
+}
+
+/// 書かれている概要が読める範囲の記録と同じか。壊れたレコードより後の項目は確かめない
+fn check_overview(dirpath: &Path, expected: &[OverviewEntry], corrupt: bool) -> Option<Finding> {
+    let actual = match Overview::load(dirpath) {
+        Ok(Some(x)) => x,
+        Ok(None) => return Some(Finding::MissingOverview),
+        Err(e) => {
+            return Some(Finding::OverviewMismatch {
+                message: e.to_string(),
+            })
+        }
+    };
+    let actual = match corrupt {
+        true => &actual.entries()[..actual.entries().len().min(expected.len())],
+        false => actual.entries(),
+    };
+    if actual.len() != expected.len() {
+        return Some(Finding::OverviewMismatch {
+            message: format!("{} entries, expected {}", actual.len(), expected.len()),
+        });
+    }
+    let entry = expected.iter().zip(actual).position(|(e, a)| e != a)?;
+    Some(Finding::OverviewMismatch {
+        message: format!("entry {} does not match the records", entry),
+    })
+}
+
+fn write_overview(path: &Path, entries: &[OverviewEntry]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut f = File::create(&tmp)?;
+        for entry in entries {
+            serde_cbor::to_writer(&mut f, entry).map_err(io::Error::other)?;
+        }
+        f.sync_all()?;
+    }
+    std::fs::rename(tmp, path)
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    path.with_file_name(name)
+}
+
+fn write_index(path: &Path, index: &[(usize, u64)]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut f = File::create(&tmp)?;
+        for (id, offset) in index {
+            f.write_all(&(*id as u64).to_le_bytes())?;
+            f.write_all(&offset.to_le_bytes())?;
+        }
+        f.sync_all()?;
+    }
+    std::fs::rename(tmp, path)
+}
+
+/// Checks the data and index files of a session directory.
+///
+/// With `repair`, a partial record at the end of the data file is truncated and a missing or
+/// wrong index or overview file is rebuilt. The files are copied to `<name>.bak` before they are changed.
+/// A corrupt record in the middle of the data is reported but not repaired.
+pub fn verify_session<P: AsRef<Path>>(dirpath: P, repair: bool) -> io::Result<SessionReport> {
+    let dirpath = dirpath.as_ref();
+    let mut report = SessionReport {
+        name: dirpath
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        records: 0,
+        valid_bytes: 0,
+        findings: Vec::new(),
+        repairs: Vec::new(),
+    };
+    let data_path = dirpath.join(CBORSequenceWriter::FILENAME);
+    if !data_path.exists() {
+        report.findings.push(Finding::MissingData);
+        return Ok(report);
+    }
+    // 暗号文を切り詰めたりindexを作り直したりしない
+    if crate::SessionMeta::load(dirpath)?.encryption.is_some() {
+        report.findings.push(Finding::Encrypted);
+        return Ok(report);
+    }
+    let scan = scan_data(&data_path)?;
+    report.records = scan.records;
+    report.valid_bytes = scan.valid_bytes;
+    let truncate = matches!(scan.finding, Some(Finding::TrailingPartialRecord { .. }));
+    let corrupt = matches!(scan.finding, Some(Finding::CorruptRecord { .. }));
+    report.findings.extend(scan.finding);
+
+    let index_path = dirpath.join(CBORSequenceWriter::INDEX_FILENAME);
+    let index_ok = if index_path.exists() {
+        let (actual, len) = read_index(&index_path)?;
+        let before = report.findings.len();
+        if len % INDEX_ENTRY_BYTES != 0 {
+            report.findings.push(Finding::MalformedIndex { len });
+        }
+        // 壊れたレコードより後の項目は確かめられない
+        let actual = match corrupt {
+            true => &actual[..actual.len().min(scan.index.len())],
+            false => &actual[..],
+        };
+        check_index(&scan.index, actual, &mut report.findings);
+        report.findings.len() == before
+    } else {
+        report.findings.push(Finding::MissingIndex);
+        false
+    };
+
+    let overview_finding = check_overview(dirpath, &scan.overview, corrupt);
+    let overview_ok = overview_finding.is_none();
+    report.findings.extend(overview_finding);
+
+    if !repair {
+        return Ok(report);
+    }
+    if truncate {
+        std::fs::copy(&data_path, backup_path(&data_path))?;
+        let f = OpenOptions::new().write(true).open(&data_path)?;
+        f.set_len(scan.valid_bytes)?;
+        f.sync_all()?;
+        report.repairs.push(Repair::Truncated {
+            from: scan.data_bytes,
+            to: scan.valid_bytes,
+        });
+    }
+    if !index_ok {
+        if index_path.exists() {
+            std::fs::copy(&index_path, backup_path(&index_path))?;
+        }
+        write_index(&index_path, &scan.index)?;
+        report.repairs.push(Repair::IndexRebuilt {
+            entries: scan.index.len(),
+        });
+    }
+    if !overview_ok {
+        let overview_path = dirpath.join(OVERVIEW_FILENAME);
+        if overview_path.exists() {
+            std::fs::copy(&overview_path, backup_path(&overview_path))?;
+        }
+        write_overview(&overview_path, &scan.overview)?;
+        report.repairs.push(Repair::OverviewRebuilt {
+            entries: scan.overview.len(),
+        });
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        path::{Path, PathBuf},
+    };
+
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level};
+
+    use super::{verify_session, Finding, Repair, OVERVIEW_FILENAME};
+    use crate::{reader::StorageReader, writer::RecordWriter, CBORSequenceReader, Storage};
+
+    /// 200レコードのセッションを作ってディレクトリを返す
+    fn fixture(dir: &TempDir, name: &str) -> PathBuf {
+        devinit!();
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        let mut session = storage.create_session(name).unwrap();
+        for i in 0..200_u32 {
+            session
+                .push(&devlog!(Level::Info, "verify", "msg", "i", i))
+                .unwrap();
+        }
+        dir.path().join(name)
+    }
+
+    fn data_len(dir: &Path) -> u64 {
+        std::fs::metadata(dir.join("seqdata")).unwrap().len()
+    }
+
+    fn patch(path: &Path, pos: u64, bytes: &[u8]) {
+        let mut buf = std::fs::read(path).unwrap();
+        buf[pos as usize..pos as usize + bytes.len()].copy_from_slice(bytes);
+        std::fs::write(path, buf).unwrap();
+    }
+
+    #[test]
+    fn test_verify_clean() {
+        let dir = TempDir::new("verify").unwrap();
+        let path = fixture(&dir, "clean");
+        let report = verify_session(&path, true).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.records, 200);
+        assert_eq!(report.valid_bytes, data_len(&path));
+        // 問題がなければ何も変えない
+        assert!(report.repairs.is_empty());
+        assert!(!path.join("seqdata.bak").exists());
+        assert!(!path.join("index.bak").exists());
+
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        let latest = storage.verify_latest(false).unwrap().unwrap();
+        assert_eq!(latest.name, "clean");
+        assert_eq!(storage.verify_session("clean", false).unwrap(), latest);
+        assert!(storage.verify_session("missing", false).is_err());
+        let empty = TempDir::new("verify").unwrap();
+        let storage = Storage::new_shared(empty.path()).unwrap();
+        assert_eq!(storage.verify_latest(false).unwrap(), None);
+    }
+
+    #[test]
+    fn test_verify_partial_record() {
+        let dir = TempDir::new("verify").unwrap();
+        let path = fixture(&dir, "partial");
+        let len = data_len(&path);
+        OpenOptions::new()
+            .write(true)
+            .open(path.join("seqdata"))
+            .unwrap()
+            .set_len(len - 5)
+            .unwrap();
+
+        let report = verify_session(&path, false).unwrap();
+        assert_eq!(report.records, 199);
+        let valid = report.valid_bytes;
+        assert_eq!(
+            report.findings,
+            vec![Finding::TrailingPartialRecord {
+                offset: valid,
+                len: len - 5 - valid
+            }]
+        );
+        assert!(report.has_unrepaired());
+
+        let report = verify_session(&path, true).unwrap();
+        assert_eq!(
+            report.repairs,
+            vec![Repair::Truncated {
+                from: len - 5,
+                to: valid
+            }]
+        );
+        assert!(!report.has_unrepaired());
+        assert_eq!(data_len(&path), valid);
+        assert_eq!(
+            std::fs::metadata(path.join("seqdata.bak")).unwrap().len(),
+            len - 5
+        );
+        let report = verify_session(&path, false).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(
+            Storage::new_shared(dir.path())
+                .unwrap()
+                .session_records("partial")
+                .unwrap()
+                .count(),
+            199
+        );
+    }
+
+    #[test]
+    fn test_verify_index() {
+        let dir = TempDir::new("verify").unwrap();
+        let path = fixture(&dir, "index");
+        let index = path.join("index");
+        let original = std::fs::read(&index).unwrap();
+        // 200レコードなので0, 64, 128, 192の4項目
+        assert_eq!(original.len(), 64);
+
+        // オフセットが違う
+        patch(&index, 16 + 8, &1_u64.to_le_bytes());
+        let report = verify_session(&path, false).unwrap();
+        let expected = u64::from_le_bytes(original[24..32].try_into().unwrap());
+        assert_eq!(
+            report.findings,
+            vec![Finding::IndexOffsetMismatch {
+                entry: 1,
+                expected,
+                actual: 1
+            }]
+        );
+
+        // 番号が続いていない
+        std::fs::write(&index, &original).unwrap();
+        patch(&index, 32, &100_u64.to_le_bytes());
+        let report = verify_session(&path, false).unwrap();
+        assert_eq!(
+            report.findings,
+            vec![Finding::IndexRecordGap {
+                entry: 2,
+                expected: 128,
+                actual: 100
+            }]
+        );
+
+        // 書きかけの項目と足りない項目
+        std::fs::write(&index, &original[..40]).unwrap();
+        let report = verify_session(&path, false).unwrap();
+        assert_eq!(
+            report.findings,
+            vec![
+                Finding::MalformedIndex { len: 40 },
+                Finding::IndexLength {
+                    expected: 4,
+                    actual: 2
+                }
+            ]
+        );
+
+        // 作り直すと元と同じになる
+        let report = verify_session(&path, true).unwrap();
+        assert_eq!(report.repairs, vec![Repair::IndexRebuilt { entries: 4 }]);
+        assert_eq!(std::fs::read(&index).unwrap(), original);
+        assert_eq!(std::fs::read(path.join("index.bak")).unwrap().len(), 40);
+
+        // indexがない
+        std::fs::remove_file(&index).unwrap();
+        let report = verify_session(&path, true).unwrap();
+        assert_eq!(report.findings, vec![Finding::MissingIndex]);
+        assert_eq!(std::fs::read(&index).unwrap(), original);
+        let page = CBORSequenceReader::new(&path)
+            .unwrap()
+            .read_at(150, 1)
+            .unwrap();
+        assert_eq!(page[0].index(), 150);
+    }
+
+    #[test]
+    fn test_verify_overview() {
+        devinit!();
+        let dir = TempDir::new("verify").unwrap();
+        let storage = Storage::new_shared(dir.path()).unwrap();
+        let mut session = storage.create_session("overview").unwrap();
+        for i in 0..20_u64 {
+            let mut r = devlog!(Level::Info, "verify", "msg");
+            r.elapsed = std::time::Duration::from_secs(i / 2);
+            session.push(&r).unwrap();
+        }
+        drop(session);
+        let path = dir.path().join("overview");
+        let overview = path.join(OVERVIEW_FILENAME);
+        let original = std::fs::read(&overview).unwrap();
+        assert!(verify_session(&path, false).unwrap().is_ok());
+
+        // 古いセッションには概要がない
+        std::fs::remove_file(&overview).unwrap();
+        let report = verify_session(&path, false).unwrap();
+        assert_eq!(report.findings, vec![Finding::MissingOverview]);
+        assert!(!overview.exists());
+        let report = verify_session(&path, true).unwrap();
+        // 最後の秒の範囲はまだ閉じていない
+        assert_eq!(report.repairs, vec![Repair::OverviewRebuilt { entries: 9 }]);
+        assert_eq!(std::fs::read(&overview).unwrap(), original);
+
+        // 項目が欠けている
+        std::fs::write(&overview, &original[..original.len() / 2]).unwrap();
+        let report = verify_session(&path, true).unwrap();
+        assert!(matches!(
+            report.findings[..],
+            [Finding::OverviewMismatch { .. }]
+        ));
+        assert_eq!(std::fs::read(&overview).unwrap(), original);
+        assert!(path.join("overview.cbor.bak").exists());
+    }
+
+    #[test]
+    fn test_verify_corrupt_record() {
+        let dir = TempDir::new("verify").unwrap();
+        let path = fixture(&dir, "corrupt");
+        let len = data_len(&path);
+        let offset = u64::from_le_bytes(
+            std::fs::read(path.join("index")).unwrap()[24..32]
+                .try_into()
+                .unwrap(),
+        );
+        // 64番目のレコードの先頭を配列にする
+        patch(&path.join("seqdata"), offset, &[0x80]);
+
+        let report = verify_session(&path, true).unwrap();
+        assert_eq!(report.records, 64);
+        assert!(matches!(
+            report.findings[0],
+            Finding::CorruptRecord { index: 64, offset: x, .. } if x == offset
+        ));
+        // 壊れたレコード以降は消さない
+        assert!(report.has_unrepaired());
+        assert_eq!(data_len(&path), len);
+        assert!(!path.join("seqdata.bak").exists());
+
+        std::fs::remove_file(path.join("seqdata")).unwrap();
+        let report = verify_session(&path, true).unwrap();
+        assert_eq!(report.findings, vec![Finding::MissingData]);
+        assert!(report.has_unrepaired());
+    }
+}
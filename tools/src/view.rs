@@ -0,0 +1,179 @@
+//! レコードの時刻
+//!
+//! レコードは接続元のセッション開始からの経過時間しか持たないので、時刻が必要な表示や出力はここを通す。
+//! サーバーが受信時刻を付けたレコードはその時刻を、付けていないレコードはセッションの開始時刻に経過時間を足した値を使う
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use uplog::{Record, Value};
+
+use crate::SessionInfo;
+
+/// サーバーが付けた受信時刻のキー
+pub const RECEIVED_AT_KEY: &str = "_ingest.received_at";
+
+/// Wall-clock time of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct RecordTime {
+    pub timestamp: DateTime<Utc>,
+    /// true when computed from the session start and the elapsed time of the record
+    pub timestamp_synthetic: bool,
+}
+
+/// A record with its wall-clock time.
+#[derive(Debug, Clone, Copy)]
+pub struct RecordView<'a> {
+    record: &'a Record,
+    time: RecordTime,
+}
+
+impl<'a> RecordView<'a> {
+    /// `session_start`は[`session_start`]で求めたセッションの開始時刻
+    pub fn new(record: &'a Record, session_start: DateTime<Utc>) -> Self {
+        let time = match received_at(record) {
+            Some(timestamp) => RecordTime {
+                timestamp,
+                timestamp_synthetic: false,
+            },
+            None => RecordTime {
+                timestamp: chrono::Duration::from_std(record.elapsed)
+                    .ok()
+                    .and_then(|x| session_start.checked_add_signed(x))
+                    .unwrap_or(session_start),
+                timestamp_synthetic: true,
+            },
+        };
+        Self { record, time }
+    }
+
+    pub fn record(&self) -> &'a Record {
+        self.record
+    }
+
+    pub fn time(&self) -> RecordTime {
+        self.time
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.time.timestamp
+    }
+
+    /// Whether the timestamp was computed instead of recorded.
+    pub fn is_synthetic(&self) -> bool {
+        self.time.timestamp_synthetic
+    }
+}
+
+/// レコードに付いている受信時刻
+fn received_at(record: &Record) -> Option<DateTime<Utc>> {
+    match record.key_values()?.get(RECEIVED_AT_KEY)? {
+        Value::Text(x) => DateTime::parse_from_rfc3339(x)
+            .ok()
+            .map(|x| x.with_timezone(&Utc)),
+        _ => None,
+    }
+}
+
+/// Start of the client session of `info`, from its metadata or else the creation of its directory.
+pub fn session_start(info: &SessionInfo) -> DateTime<Utc> {
+    info.meta().start_at.unwrap_or(*info.created_at())
+}
+
+/// 出力に使う時刻の形式
+pub fn format_timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level, Value};
+
+    use super::{format_timestamp, session_start, RecordView, RECEIVED_AT_KEY};
+    use crate::{writer::RecordWriter, Storage};
+
+    #[test]
+    fn test_record_view() {
+        devinit!();
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let mut record = devlog!(Level::Info, "app", "old");
+        record.elapsed = Duration::from_millis(1500);
+        let view = RecordView::new(&record, start);
+        assert!(view.is_synthetic());
+        assert_eq!(
+            format_timestamp(&view.timestamp()),
+            "2020-09-13T12:26:41.500000Z"
+        );
+
+        let received = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        record.kv.get_or_insert_with(Default::default).insert(
+            RECEIVED_AT_KEY.to_string(),
+            Value::Text(format_timestamp(&received)),
+        );
+        let view = RecordView::new(&record, start);
+        assert!(!view.is_synthetic());
+        assert_eq!(view.timestamp(), received);
+    }
+
+    /// 受信時刻のない古いセッションと新しいセッションのレコードを時刻で並べる
+    #[test]
+    fn test_record_view_order() {
+        devinit!();
+        let dir = TempDir::new("view").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let start = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+
+        // 古い形式: 受信時刻がなく、開始時刻は付加情報から
+        {
+            let mut session = storage.create_session("old").unwrap();
+            for i in [0_u64, 2, 4] {
+                let mut r = devlog!(Level::Info, "app", "old", "order", i);
+                r.elapsed = Duration::from_secs(i);
+                session.push(&r).unwrap();
+            }
+        }
+        storage.set_session_start("old", start).unwrap();
+        // 新しい形式: サーバーが受信時刻を付けている
+        {
+            let mut session = storage.create_session("new").unwrap();
+            for i in [1_u64, 3, 5] {
+                let mut r = devlog!(Level::Info, "app", "new", "order", i);
+                r.elapsed = Duration::from_secs(100);
+                let at = start + chrono::Duration::seconds(i as i64);
+                r.kv.get_or_insert_with(Default::default).insert(
+                    RECEIVED_AT_KEY.to_string(),
+                    Value::Text(format_timestamp(&at)),
+                );
+                session.push(&r).unwrap();
+            }
+        }
+
+        let mut records = Vec::new();
+        for info in storage.records().unwrap() {
+            let base = session_start(&info);
+            for r in storage.session_records(&info.name()).unwrap() {
+                records.push((r.unwrap(), base));
+            }
+        }
+        let mut views = records
+            .iter()
+            .map(|(r, base)| RecordView::new(r, *base))
+            .collect::<Vec<_>>();
+        views.sort_by_key(|x| x.time());
+        let order = views
+            .iter()
+            .map(|x| {
+                let kv = x.record().key_values().unwrap();
+                (kv["order"].clone(), x.is_synthetic())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            (0..6_u64)
+                .map(|i| (Value::U64(i), i % 2 == 0))
+                .collect::<Vec<_>>()
+        );
+    }
+}
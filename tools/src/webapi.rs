@@ -1,27 +1,417 @@
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
 use crate::{
-    reader::{CBORSequenceReader, StorageReader},
-    LogRecord, SessionInfo, Storage,
+    acl::{AccessDenied, Permission, Scope},
+    actor::{LiveConnection, LiveConnections, RouteControl},
+    audit::AuditEntry,
+    cache::{QueryCache, QueryCacheStats},
+    diskwatch::{DiskGuard, DiskStatus},
+    filter::{parse_level, Filter},
+    kvtype::KvKey,
+    lifecycle::is_server_record,
+    overview::{seek_elapsed, Histogram},
+    reader::{
+        open_reader, Cursor, Deadline, OnError, ReadOptions, ReadPage, RecordError, ScanTimeout,
+        StorageReader,
+    },
+    scan::{search_sessions, ScanOptions, DEFAULT_SCAN_THREADS},
+    stats::{CategoryNode, DeltaStats, StatsTable},
+    BuildInfo, HealthFlag, LogLevel, LogRecord, SessionHealth, SessionInfo, SessionQuery,
+    SessionSortKey, SortOrder, Storage,
 };
+use actix::Recipient;
 use actix_web::HttpRequest;
 use actix_web::{web, HttpResponse, Result};
 use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
 use async_graphql::{
-    scalar, EmptyMutation, EmptySubscription, InputObject, Object, Schema, SimpleObject,
+    scalar, Context, EmptySubscription, ErrorExtensions, InputObject, Object, Schema, SimpleObject,
 };
 use async_graphql_actix_web::{Request, Response};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use uplog::{
+    protocol::{Codec, ControlCommand},
+    CategoryPattern, Level,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
-struct DateTimeScalar(DateTime<Utc>);
+pub(crate) struct DateTimeScalar(pub(crate) DateTime<Utc>);
 scalar!(DateTimeScalar, "DateTime");
 
 /// GraphQL Schema
-pub type ApiSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+pub type ApiSchema = Schema<Query, Mutation, EmptySubscription>;
+
+/// Builds the schema with the depth and complexity limits of the [`QueryLimits`] of `query`.
+pub fn build_schema(query: Query, mutation: Mutation) -> ApiSchema {
+    let limits = query.limits;
+    Schema::build(query, mutation, EmptySubscription)
+        .limit_depth(limits.max_depth)
+        .limit_complexity(limits.max_complexity)
+        .finish()
+}
+
+/// Executes a request. Errors of the depth and complexity limits get a `code` extension.
+pub async fn execute(
+    schema: &ApiSchema,
+    request: impl Into<async_graphql::Request>,
+) -> async_graphql::Response {
+    let mut res = schema.execute(request).await;
+    // 制限のエラーはasync-graphqlが拡張なしで返すのでメッセージで見分ける
+    for e in res.errors.iter_mut() {
+        let code = match e.message.as_str() {
+            "Query is nested too deep." => "QUERY_TOO_DEEP",
+            "Query is too complex." => "QUERY_TOO_COMPLEX",
+            _ => continue,
+        };
+        e.extensions
+            .get_or_insert_with(Default::default)
+            .set("code", code);
+    }
+    res
+}
+
+/// 変更の記録に残すGraphQLのクライアントのアドレス
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientAddr(pub String);
 
 /// GraphQL Endpoint
-pub async fn index(schema: web::Data<ApiSchema>, req: Request) -> Response {
-    schema.execute(req.into_inner()).await.into()
+pub async fn index(
+    schema: web::Data<ApiSchema>,
+    http: HttpRequest,
+    scope: Scope,
+    req: Request,
+) -> Response {
+    let mut req = req.into_inner().data(scope);
+    if let Some(addr) = http.peer_addr() {
+        req = req.data(ClientAddr(addr.ip().to_string()));
+    }
+    execute(&schema, req).await.into()
+}
+
+/// 扱えないセッションへのリクエストに返す応答
+fn denied_response(e: AccessDenied) -> HttpResponse {
+    let mut res = match e {
+        AccessDenied::Unauthenticated => {
+            let mut res = HttpResponse::Unauthorized();
+            res.header(actix_web::http::header::WWW_AUTHENTICATE, "Bearer");
+            res
+        }
+        AccessDenied::Forbidden { .. } => HttpResponse::Forbidden(),
+    };
+    res.json(serde_json::json!({ "code": e.code(), "message": e.to_string() }))
+}
+
+/// リクエストのトークンのテナントの保存先
+fn tenant_storage(storage: &Storage, scope: &Scope) -> std::result::Result<Storage, HttpResponse> {
+    storage
+        .scoped(scope.tenant())
+        .map_err(|e| HttpResponse::InternalServerError().body(e.to_string()))
+}
+
+/// アーカイブのダウンロード
+pub const ARCHIVE_PATH: &str = "/archive";
+
+/// Session archive download
+pub async fn download_archive(
+    storage: web::Data<Storage>,
+    name: web::Path<String>,
+    scope: Scope,
+) -> Result<HttpResponse> {
+    if let Err(e) = scope.check(&name, Permission::Read) {
+        return Ok(denied_response(e));
+    }
+    let storage = match tenant_storage(&storage, &scope) {
+        Ok(x) => x,
+        Err(res) => return Ok(res),
+    };
+    let mut buf = Vec::new();
+    match storage.archive_session(&name, &mut buf) {
+        Ok(_) => Ok(HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .header(
+                "Content-Disposition",
+                format!(
+                    "attachment; filename=\"{}.{}\"",
+                    name,
+                    crate::archive::ARCHIVE_EXTENSION
+                ),
+            )
+            .body(buf)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(HttpResponse::NotFound().body(e.to_string()))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+/// 分離して保存したblobのダウンロード
+pub const BLOB_PATH: &str = "/blob";
+
+/// Offloaded blob download
+pub async fn download_blob(
+    storage: web::Data<Storage>,
+    path: web::Path<(String, String)>,
+    scope: Scope,
+) -> Result<HttpResponse> {
+    let (name, hash) = path.into_inner();
+    if let Err(e) = scope.check(&name, Permission::Read) {
+        return Ok(denied_response(e));
+    }
+    let storage = match tenant_storage(&storage, &scope) {
+        Ok(x) => x,
+        Err(res) => return Ok(res),
+    };
+    match storage.read_blob(&name, &hash) {
+        Ok(buf) => Ok(HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(buf)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(HttpResponse::NotFound().body(e.to_string()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            Ok(HttpResponse::BadRequest().body(e.to_string()))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+/// 添付ファイルのダウンロード
+pub const ATTACHMENT_PATH: &str = "/attachment";
+
+/// Attachment download with the filename and MIME type the client sent
+pub async fn download_attachment(
+    storage: web::Data<Storage>,
+    path: web::Path<(String, String)>,
+    scope: Scope,
+) -> Result<HttpResponse> {
+    let (name, id) = path.into_inner();
+    if let Err(e) = scope.check(&name, Permission::Read) {
+        return Ok(denied_response(e));
+    }
+    let storage = match tenant_storage(&storage, &scope) {
+        Ok(x) => x,
+        Err(res) => return Ok(res),
+    };
+    match storage.read_attachment(&name, &id) {
+        Ok((attachment, buf)) => {
+            // ヘッダーに入れられない文字は置き換える
+            let filename = attachment
+                .filename
+                .chars()
+                .map(|x| match x {
+                    '"' | '\\' => '_',
+                    x if x.is_ascii_graphic() || x == ' ' => x,
+                    _ => '_',
+                })
+                .collect::<String>();
+            Ok(HttpResponse::Ok()
+                .content_type(attachment.mime.as_str())
+                .header(
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{}\"", filename),
+                )
+                .body(buf))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(HttpResponse::NotFound().body(e.to_string()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            Ok(HttpResponse::BadRequest().body(e.to_string()))
+        }
+        Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+/// URLのパスの1区切りとして使えるようにする
+fn path_segment(s: &str) -> String {
+    s.bytes()
+        .map(|x| match x {
+            b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'-' | b'_' | b'.' | b'~' => {
+                (x as char).to_string()
+            }
+            x => format!("%{:02X}", x),
+        })
+        .collect()
+}
+
+/// セッションのレコードを前回の続きから取得するパス
+pub const SESSIONS_PATH: &str = "/sessions";
+/// Response header with the cursor to pass as `after` for the next page.
+pub const NEXT_CURSOR_HEADER: &str = "X-Next-Cursor";
+const DEFAULT_SYNC_LIMIT: usize = 1000;
+/// 長く待たせても1分まで
+const MAX_WAIT_MS: u64 = 60_000;
+
+/// Query of [`records_after`].
+#[derive(Debug, Deserialize)]
+pub struct RecordsQuery {
+    /// `X-Next-Cursor` of the previous page. The head of the session when omitted.
+    after: Option<String>,
+    /// records per page, 1000 by default
+    limit: Option<usize>,
+    /// wait this long for new records when there are none after the cursor
+    #[serde(default)]
+    wait_ms: u64,
+    /// only records at this level or above, such as `warn`
+    min_level: Option<String>,
+    /// only records at one of these comma separated levels, such as `warn,error`
+    level_in: Option<String>,
+}
+
+impl RecordsQuery {
+    fn levels(&self) -> std::result::Result<LevelFilter, String> {
+        let parse = |s: &str| {
+            parse_level(s.trim())
+                .map(LogLevel::from)
+                .ok_or_else(|| format!("unknown level: {}", s))
+        };
+        Ok(LevelFilter {
+            min: self.min_level.as_deref().map(parse).transpose()?,
+            any_of: self
+                .level_in
+                .as_deref()
+                .map(|x| x.split(',').map(parse).collect())
+                .transpose()?,
+        })
+    }
+}
+
+/// JSONで返すレコード
+#[derive(Serialize)]
+struct SyncRecord<'a> {
+    id: usize,
+    record: &'a uplog::Record,
+}
+
+/// Records of a session after a cursor, with the cursor of the next page in `X-Next-Cursor`.
+///
+/// The body is a CBOR sequence of records, or a JSON array of `{id, record}` when the request
+/// accepts `application/json`. An empty page keeps the cursor, so polling can reuse it.
+/// `min_level` and `level_in` drop records from the page after it is read, so the cursor still
+/// moves past them.
+pub async fn records_after(
+    storage: web::Data<Storage>,
+    name: web::Path<String>,
+    query: web::Query<RecordsQuery>,
+    req: HttpRequest,
+    scope: Scope,
+) -> Result<HttpResponse> {
+    if let Err(e) = scope.check(&name, Permission::Tail) {
+        return Ok(denied_response(e));
+    }
+    let storage = match tenant_storage(&storage, &scope) {
+        Ok(x) => x,
+        Err(res) => return Ok(res),
+    };
+    let cursor = match query.after.as_deref().map(str::parse::<Cursor>).transpose() {
+        Ok(x) => x.unwrap_or_default(),
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e.to_string())),
+    };
+    let levels = match query.levels() {
+        Ok(x) => x,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_SYNC_LIMIT);
+    if limit == 0 || limit > MAX_READ_LENGTH {
+        return Ok(HttpResponse::BadRequest().body(format!(
+            "limit must be between 1 and {}, got {}",
+            MAX_READ_LENGTH, limit
+        )));
+    }
+    let wait = Duration::from_millis(query.wait_ms.min(MAX_WAIT_MS));
+    // 読む前に登録して、読んでから待つまでの間の書き込みを取りこぼさない
+    let notified = (!wait.is_zero()).then(|| storage.registry().subscribe(&name));
+    let mut page = storage.read_after(&name, cursor, limit);
+    if let (Ok((records, _)), Some(notified)) = (&page, notified) {
+        if records.is_empty() {
+            let timeout = actix_web::rt::time::delay_for(wait);
+            futures::future::select(notified, timeout).await;
+            page = storage.read_after(&name, cursor, limit);
+        }
+    }
+    let (records, next) = match page {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(HttpResponse::NotFound().body(e.to_string()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            return Ok(HttpResponse::BadRequest().body(e.to_string()))
+        }
+        Err(e) => return Ok(HttpResponse::InternalServerError().body(e.to_string())),
+    };
+    let records = records
+        .iter()
+        .filter(|x| levels.matches(x.record.level()))
+        .collect::<Vec<_>>();
+    let json = req
+        .headers()
+        .get(actix_web::http::header::ACCEPT)
+        .and_then(|x| x.to_str().ok())
+        .is_some_and(|x| x.contains("application/json"));
+    let mut res = HttpResponse::Ok();
+    res.header(NEXT_CURSOR_HEADER, next.to_string());
+    if json {
+        let records = records
+            .iter()
+            .map(|x| SyncRecord {
+                id: x.id,
+                record: &x.record,
+            })
+            .collect::<Vec<_>>();
+        Ok(res.json(records))
+    } else {
+        let mut buf = Vec::new();
+        for x in records.iter() {
+            serde_cbor::to_writer(&mut buf, &x.record)
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+        }
+        Ok(res.content_type("application/cbor-seq").body(buf))
+    }
+}
+
+/// GraphQLのスキーマを取得するパス
+pub const SCHEMA_PATH: &str = "/graphql/schema";
+
+/// GraphQL schema in SDL
+pub async fn schema_sdl(schema: web::Data<ApiSchema>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(schema.sdl())
+}
+
+/// サーバーのバージョンを取得するパス
+pub const VERSION_PATH: &str = "/version";
+
+/// Versions of the running server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: String,
+    /// `UPLOG_GIT_HASH` at build time
+    pub git_hash: Option<String>,
+    pub storage_format_version: u32,
+    pub archive_format_version: u32,
+    /// websocket subprotocols in the preferred order
+    pub subprotocols: Vec<String>,
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: option_env!("UPLOG_GIT_HASH").map(ToString::to_string),
+            storage_format_version: crate::STORAGE_FORMAT_VERSION,
+            archive_format_version: crate::archive::ARCHIVE_FORMAT_VERSION,
+            subprotocols: Codec::ALL
+                .iter()
+                .map(|x| x.subprotocol().to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Version endpoint
+pub async fn version() -> HttpResponse {
+    HttpResponse::Ok().json(VersionInfo::current())
 }
 
 /// GraphQL PlayGround
@@ -34,11 +424,128 @@ pub async fn index_playground(req: HttpRequest) -> Result<HttpResponse> {
         .body(source))
 }
 
+/// 保存先を変更した操作
+#[derive(SimpleObject)]
+struct AuditEntryView {
+    /// `delete`, `prune`, `trim` or `restore`
+    operation: String,
+    session: String,
+    at: DateTimeScalar,
+    /// bytes removed or written, up to the failure if the operation failed
+    bytes: u64,
+    /// address of the GraphQL client, `retention` or `local`
+    initiator: String,
+    /// session written by the operation, e.g. the output of `trim`
+    target: Option<String>,
+    /// why the operation failed; none when it succeeded
+    error: Option<String>,
+}
+
+impl From<AuditEntry> for AuditEntryView {
+    fn from(x: AuditEntry) -> Self {
+        Self {
+            operation: x.operation,
+            session: x.session,
+            at: DateTimeScalar(x.at),
+            bytes: x.bytes,
+            initiator: x.initiator,
+            target: x.target,
+            error: x.error,
+        }
+    }
+}
+
+/// 接続中のクライアント
+#[derive(SimpleObject)]
+struct LiveConnectionView {
+    /// id of the connection, the argument of `disconnect`
+    uuid: String,
+    client_ip: String,
+    /// id the client sends on every connection, when it sent one
+    client_session: Option<String>,
+    /// session being written; none until the first record
+    session: Option<String>,
+    connected_at: DateTimeScalar,
+    /// messages received
+    frames: u64,
+    /// bytes of the messages received
+    bytes: u64,
+    /// when the last message arrived; none before the first one
+    last_activity_at: Option<DateTimeScalar>,
+}
+
+impl From<LiveConnection> for LiveConnectionView {
+    fn from(x: LiveConnection) -> Self {
+        Self {
+            uuid: x.uuid.to_string(),
+            client_ip: x.client_ip,
+            client_session: x.client_session.map(|x| x.to_string()),
+            session: x.session,
+            connected_at: DateTimeScalar(x.connected_at),
+            frames: x.frames,
+            bytes: x.bytes,
+            last_activity_at: x.last_activity_at.map(DateTimeScalar),
+        }
+    }
+}
+
+/// リクエストのトークンで見られる接続か
+fn connection_visible(scope: &Scope, x: &LiveConnection) -> bool {
+    let name = x.session.clone().unwrap_or_else(|| x.uuid.to_string());
+    scope.allows(&name, Permission::Read)
+        && scope
+            .tenant()
+            .is_none_or(|tenant| x.tenant.as_deref() == Some(tenant))
+}
+
+/// 接続の一覧を受信サーバーに問い合わせる
+async fn live_connections(
+    connections: Option<&Recipient<LiveConnections>>,
+    msg: LiveConnections,
+) -> async_graphql::Result<Vec<LiveConnection>> {
+    let connections = connections.ok_or_else(|| {
+        async_graphql::Error::new("live connections are not available")
+            .extend_with(|_, e| e.set("code", "NOT_AVAILABLE"))
+    })?;
+    connections.send(msg).await?.map_err(|e| {
+        async_graphql::Error::new(e).extend_with(|_, e| {
+            e.set("code", "CONNECTION_NOT_FOUND");
+            e.set("field", "uuid");
+        })
+    })
+}
+
 #[derive(SimpleObject)]
 struct SessionViewInfo {
     created_at: DateTimeScalar,
     updated_at: DateTimeScalar,
     name: String,
+    note: Option<String>,
+    tags: Vec<String>,
+    /// session this one was split from by a boundary record
+    parent: Option<String>,
+    /// unit of elapsed sent by the client when it was sent as an integer
+    time_precision: Option<String>,
+    /// server clock minus client clock in milliseconds, measured when the client connected
+    clock_offset_ms: Option<i64>,
+    /// label of the ingest path the client connected to
+    label: Option<String>,
+    /// size of the data file in bytes
+    size: u64,
+    /// whether a client is still writing to the session
+    live: bool,
+    /// records the server failed to write, 0 when none were lost
+    lost_records: u64,
+    /// why the server closed the session, e.g. `client:flush` or `idle_timeout`; none while open
+    end_reason: Option<String>,
+    /// build of the client binary, when the client sent it
+    build_info: Option<BuildInfo>,
+    /// messages of the client the server failed to decode
+    decode_failures: u64,
+    /// anomaly flags recorded when the session closed or by `assessSession`
+    health: Option<SessionHealth>,
+    /// kept when the server frees disk space, and deleted only with `force`
+    pinned: bool,
 }
 
 impl From<SessionInfo> for SessionViewInfo {
@@ -46,50 +553,3058 @@ impl From<SessionInfo> for SessionViewInfo {
         Self {
             created_at: DateTimeScalar(x.created_at),
             updated_at: DateTimeScalar(x.updated_at),
-            name: x.path().file_name().unwrap().to_string_lossy().to_string(),
+            name: x.name(),
+            note: x.meta.note,
+            tags: x.meta.tags,
+            parent: x.meta.parent,
+            time_precision: x.meta.time_precision,
+            clock_offset_ms: x.meta.clock_offset_ms,
+            label: x.meta.label,
+            size: x.size,
+            live: x.live,
+            lost_records: x.meta.lost_records,
+            end_reason: x.meta.end_reason,
+            build_info: x.meta.build_info,
+            decode_failures: x.meta.decode_failures,
+            health: x.meta.health,
+            pinned: x.meta.pinned,
+        }
+    }
+}
+
+/// Limits on the queries the server accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryLimits {
+    /// deepest nesting of fields
+    pub max_depth: usize,
+    /// sum of the selected fields, where a list of records counts its fields once per record
+    pub max_complexity: usize,
+    /// most records read at once by `storageReadAt` and on each side by `context`
+    pub max_read_length: usize,
+    /// time a resolver may spend scanning a session file, or several sessions for `searchAll`
+    pub scan_timeout: Option<Duration>,
+    /// sessions read at the same time by `searchAll` and `multiSessionStats`
+    pub scan_threads: usize,
+}
+
+pub const DEFAULT_MAX_QUERY_DEPTH: usize = 16;
+/// 最大件数の読み出しで20程度のフィールドを選べる
+pub const DEFAULT_MAX_QUERY_COMPLEXITY: usize = 200_000;
+pub const DEFAULT_SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_QUERY_DEPTH,
+            max_complexity: DEFAULT_MAX_QUERY_COMPLEXITY,
+            max_read_length: MAX_READ_LENGTH,
+            scan_timeout: Some(DEFAULT_SCAN_TIMEOUT),
+            scan_threads: DEFAULT_SCAN_THREADS,
         }
     }
 }
 
+/// セッションの読み出しを開く関数。テストでは遅い読み出しに差し替える
+type OpenReader = fn(&SessionInfo) -> std::io::Result<Box<dyn StorageReader>>;
+
+fn open_boxed_reader(session: &SessionInfo) -> std::io::Result<Box<dyn StorageReader>> {
+    Ok(Box::new(open_reader(session)?))
+}
+
 #[derive(Debug)]
 pub struct Query {
     storage: Storage,
+    cache: Arc<QueryCache>,
+    limits: QueryLimits,
+    open: OpenReader,
+    disk: DiskGuard,
+    /// 受信サーバーの接続の一覧の問い合わせ先
+    connections: Option<Recipient<LiveConnections>>,
 }
 
 impl Query {
     pub fn new(storage: Storage) -> Self {
-        Self { storage }
+        Self {
+            storage,
+            cache: Arc::default(),
+            limits: QueryLimits::default(),
+            open: open_boxed_reader,
+            disk: DiskGuard::default(),
+            connections: None,
+        }
+    }
+
+    /// 受信サーバーと同じプロセスで動く場合に設定する
+    pub fn connections(mut self, connections: Recipient<LiveConnections>) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    /// 空き容量の監視と共有する状態
+    pub fn disk_guard(mut self, guard: DiskGuard) -> Self {
+        self.disk = guard;
+        self
     }
+
+    /// 既定は[`QueryLimits::default`]。深さと複雑さは[`build_schema`]で設定する
+    pub fn limits(mut self, limits: QueryLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    #[cfg(test)]
+    fn open_reader(mut self, open: OpenReader) -> Self {
+        self.open = open;
+        self
+    }
+
+    /// 読み出し結果のキャッシュ。既定は[`crate::cache::DEFAULT_QUERY_CACHE_BYTES`]
+    pub fn query_cache(mut self, cache: Arc<QueryCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    fn deadline(&self) -> Deadline {
+        self.limits
+            .scan_timeout
+            .map(Deadline::after)
+            .unwrap_or_default()
+    }
+
+    /// 複数のセッションを読む並列数と全体の期限
+    fn scan_options(&self) -> ScanOptions {
+        ScanOptions::default()
+            .threads(self.limits.scan_threads)
+            .deadline(self.deadline())
+    }
+
+    /// 同じ範囲の読み出しはファイルが変わるまでキャッシュから返す
+    ///
+    /// `lazy_kv`ならkvは参照されるまで復号しない
+    fn read_at(
+        &self,
+        session: &SessionInfo,
+        start: usize,
+        length: usize,
+        on_error: OnError,
+        lazy_kv: bool,
+    ) -> async_graphql::Result<Arc<ReadPage>> {
+        let options = ReadOptions::default()
+            .on_error(on_error)
+            .deadline(self.deadline())
+            .lazy_kv(lazy_kv);
+        self.cache
+            .read_at_with(session.path(), start, length, options, || {
+                (self.open)(session)?.read_page(start, length, options)
+            })
+            .map_err(|e| {
+                if let Some(x) = ScanTimeout::from_io(&e) {
+                    return async_graphql::Error::new(format!(
+                        "query timed out after scanning {} records",
+                        x.scanned
+                    ))
+                    .extend_with(|_, e| {
+                        e.set("code", "QUERY_TIMEOUT");
+                        e.set("partial", true);
+                        e.set("scanned", x.scanned as u64);
+                    });
+                }
+                match RecordError::from_io(&e) {
+                    Some(x) => async_graphql::Error::new(x.to_string()).extend_with(|_, e| {
+                        e.set("code", "RECORD_DECODE_FAILED");
+                        e.set("index", x.index as u64);
+                    }),
+                    None => e.into(),
+                }
+            })
+    }
+
+    /// リクエストのトークンのテナントの保存先
+    fn tenant_storage(&self, ctx: &Context<'_>) -> async_graphql::Result<Storage> {
+        Ok(self.storage.scoped(request_scope(ctx).tenant())?)
+    }
+
+    /// 検索するセッションを古い順に返す
+    fn search_targets(
+        &self,
+        ctx: &Context<'_>,
+        filter: SessionFilter,
+    ) -> async_graphql::Result<Vec<SessionInfo>> {
+        let query = SessionQuery {
+            name_globs: readable_globs(ctx)?,
+            limit: filter
+                .latest
+                .map(|x| validate_count("latest", Some(x), 1, MAX_PAGE_SIZE))
+                .transpose()?,
+            ..session_query(
+                filter.tag,
+                None,
+                SessionSortKey::Created,
+                SortOrder::Desc,
+                filter.name_contains,
+            )?
+        };
+        let mut sessions = self.tenant_storage(ctx)?.records_paged(&query)?.sessions;
+        if let Some(since) = filter.since {
+            sessions.retain(|x| *x.created_at() >= since.0);
+        }
+        sessions.reverse();
+        Ok(sessions)
+    }
+
+    /// 名前を含むセッションのうち、読めるものを返す
+    fn find_session(&self, ctx: &Context<'_>, name: &str) -> async_graphql::Result<SessionInfo> {
+        let name = validate_name("name", name)?;
+        let scope = request_scope(ctx);
+        scope.authenticate().map_err(access_denied)?;
+        let mut denied = None;
+        for x in self
+            .tenant_storage(ctx)?
+            .records()?
+            .into_iter()
+            .filter(|x| {
+                x.path()
+                    .file_name()
+                    .map(|x| x.to_string_lossy().contains(name))
+                    .unwrap_or(false)
+            })
+        {
+            match scope.check(&x.name(), Permission::Read) {
+                Ok(()) => return Ok(x),
+                Err(e) => denied = denied.or(Some(e)),
+            }
+        }
+        Err(denied.map_or_else(|| session_not_found(name), access_denied))
+    }
+}
+
+/// 1回に読み出せる最大レコード数の既定値
+pub const MAX_READ_LENGTH: usize = 10_000;
+const DEFAULT_READ_LENGTH: usize = 100;
+const MAX_NAME_LENGTH: usize = 128;
+const MAX_TAG_LENGTH: usize = 64;
+const MAX_NOTE_LENGTH: usize = 4096;
+/// セッション一覧の1ページの既定と最大の件数
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 1000;
+/// 1回に比べられる最大セッション数
+const MAX_STATS_SESSIONS: usize = 32;
+/// 複数のセッションの検索で返す件数の既定値
+const DEFAULT_SEARCH_LENGTH: usize = 100;
+/// 変更の記録を読む件数の既定と最大
+const DEFAULT_AUDIT_LENGTH: usize = 100;
+const MAX_AUDIT_LENGTH: usize = 10_000;
+
+/// リクエストのトークンで扱えるもの。[`crate::acl::Authorize`]を通していなければ全て
+fn request_scope(ctx: &Context<'_>) -> Scope {
+    ctx.data_opt::<Scope>().cloned().unwrap_or_default()
+}
+
+fn access_denied(e: AccessDenied) -> async_graphql::Error {
+    async_graphql::Error::new(e.to_string()).extend_with(|_, ext| {
+        ext.set("code", e.code());
+        if let AccessDenied::Forbidden {
+            session,
+            permission,
+        } = &e
+        {
+            ext.set("session", session.as_str());
+            ext.set("permission", permission.as_str());
+        }
+    })
+}
+
+/// 既知のトークンを持つか、制限していないか確かめる
+fn authenticate(ctx: &Context<'_>) -> async_graphql::Result<()> {
+    request_scope(ctx).authenticate().map_err(access_denied)
+}
+
+fn authorize(ctx: &Context<'_>, name: &str, permission: Permission) -> async_graphql::Result<()> {
+    request_scope(ctx)
+        .check(name, permission)
+        .map_err(access_denied)
+}
+
+/// 一覧に出せるセッション名のパターン
+fn readable_globs(ctx: &Context<'_>) -> async_graphql::Result<Option<Vec<String>>> {
+    let scope = request_scope(ctx);
+    scope.authenticate().map_err(access_denied)?;
+    Ok(scope.session_globs(Permission::Read))
+}
+
+fn invalid_input(field: &'static str, message: String) -> async_graphql::Error {
+    async_graphql::Error::new(message).extend_with(|_, e| {
+        e.set("code", "INVALID_INPUT");
+        e.set("field", field);
+    })
+}
+
+/// セッション名として使える文字だけか確認する
+fn validate_name<'a>(field: &'static str, name: &'a str) -> async_graphql::Result<&'a str> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(invalid_input(
+            field,
+            format!("{} must be 1 to {} characters", field, MAX_NAME_LENGTH),
+        ));
+    }
+    if name.starts_with('.')
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(invalid_input(
+            field,
+            format!("{} contains invalid characters", field),
+        ));
+    }
+    Ok(name)
+}
+
+/// 空でなく制御文字を含まない文字列か確認する
+fn validate_text<'a>(
+    field: &'static str,
+    text: &'a str,
+    allow_empty: bool,
+    max: usize,
+) -> async_graphql::Result<&'a str> {
+    if (!allow_empty && text.is_empty()) || text.len() > max {
+        return Err(invalid_input(
+            field,
+            format!("{} must be at most {} bytes", field, max),
+        ));
+    }
+    if text.chars().any(|c| c.is_control() && c != '\n') {
+        return Err(invalid_input(
+            field,
+            format!("{} contains control characters", field),
+        ));
+    }
+    Ok(text)
+}
+
+/// レコードのリストの複雑さ。選んだフィールドを件数分数える
+fn list_complexity(length: i64, child_complexity: usize) -> usize {
+    (length.max(0) as usize).saturating_mul(child_complexity)
+}
+
+/// 0以上max以下の数か確認する
+fn validate_count(
+    field: &'static str,
+    value: Option<i64>,
+    default: usize,
+    max: usize,
+) -> async_graphql::Result<usize> {
+    match value {
+        None => Ok(default),
+        Some(x) if x >= 0 && x as u64 <= max as u64 => Ok(x as usize),
+        Some(x) => Err(invalid_input(
+            field,
+            format!("{} must be between 0 and {}, got {}", field, max, x),
+        )),
+    }
+}
+
+/// セッション一覧の引数を確認する。件数は呼び出し側で決める
+fn session_query(
+    tag: Option<String>,
+    offset: Option<i64>,
+    sort_by: SessionSortKey,
+    order: SortOrder,
+    name_contains: Option<String>,
+) -> async_graphql::Result<SessionQuery> {
+    if let Some(tag) = tag.as_deref() {
+        validate_text("tag", tag, false, MAX_TAG_LENGTH)?;
+    }
+    if let Some(x) = name_contains.as_deref() {
+        validate_text("nameContains", x, true, MAX_NAME_LENGTH)?;
+    }
+    Ok(SessionQuery {
+        offset: validate_count("offset", offset, 0, usize::MAX)?,
+        limit: None,
+        sort_by,
+        order,
+        name_contains,
+        tag,
+        name_globs: None,
+        health: None,
+    })
+}
+
+/// セッション一覧の1ページと条件に合うセッションの数
+#[derive(SimpleObject)]
+struct SessionPageView {
+    total: u64,
+    sessions: Vec<SessionViewInfo>,
 }
 
 #[Object]
 impl Query {
-    async fn storages(&self) -> Result<Vec<SessionViewInfo>, std::io::Error> {
-        let mut records: Vec<SessionInfo> = self.storage.records()?;
-        records.sort_by(|a, b| b.created_at().cmp(a.created_at()));
+    /// タグを指定した場合はそのタグを持つセッションに絞る。既定は全てのセッションを新しい順に返す
+    #[allow(clippy::too_many_arguments)]
+    async fn storages(
+        &self,
+        ctx: &Context<'_>,
+        tag: Option<String>,
+        offset: Option<i64>,
+        limit: Option<i64>,
+        #[graphql(default)] sort_by: SessionSortKey,
+        #[graphql(default)] order: SortOrder,
+        name_contains: Option<String>,
+        health: Option<HealthFlag>,
+    ) -> async_graphql::Result<Vec<SessionViewInfo>> {
+        let query = SessionQuery {
+            limit: limit
+                .map(|x| validate_count("limit", Some(x), 0, usize::MAX))
+                .transpose()?,
+            name_globs: readable_globs(ctx)?,
+            health,
+            ..session_query(tag, offset, sort_by, order, name_contains)?
+        };
+        let page = self.tenant_storage(ctx)?.records_paged(&query)?;
+        Ok(page
+            .sessions
+            .into_iter()
+            .map(SessionViewInfo::from)
+            .collect())
+    }
+
+    /// `storages`の1ページと条件に合うセッションの数。`limit`の既定は50件
+    #[allow(clippy::too_many_arguments)]
+    async fn storages_page(
+        &self,
+        ctx: &Context<'_>,
+        tag: Option<String>,
+        offset: Option<i64>,
+        limit: Option<i64>,
+        #[graphql(default)] sort_by: SessionSortKey,
+        #[graphql(default)] order: SortOrder,
+        name_contains: Option<String>,
+        health: Option<HealthFlag>,
+    ) -> async_graphql::Result<SessionPageView> {
+        let query = SessionQuery {
+            limit: Some(validate_count(
+                "limit",
+                limit,
+                DEFAULT_PAGE_SIZE,
+                MAX_PAGE_SIZE,
+            )?),
+            name_globs: readable_globs(ctx)?,
+            health,
+            ..session_query(tag, offset, sort_by, order, name_contains)?
+        };
+        let page = self.tenant_storage(ctx)?.records_paged(&query)?;
+        Ok(SessionPageView {
+            total: page.total as u64,
+            sessions: page
+                .sessions
+                .into_iter()
+                .map(SessionViewInfo::from)
+                .collect(),
+        })
+    }
+
+    /// category, whereを指定した場合は読み込んだ範囲のうち一致するレコードだけを返す
+    #[graphql(
+        complexity = "list_complexity(vars.length.unwrap_or(DEFAULT_READ_LENGTH as i64), child_complexity)"
+    )]
+    async fn storage_read_at(
+        &self,
+        ctx: &Context<'_>,
+        vars: ReadAtVars,
+    ) -> async_graphql::Result<Vec<LogRecord>> {
+        let start = validate_count("start", vars.start, 0, usize::MAX)?;
+        let length = validate_count(
+            "length",
+            vars.length,
+            DEFAULT_READ_LENGTH,
+            self.limits.max_read_length,
+        )?;
+        let pattern = vars
+            .category
+            .as_deref()
+            .map(|x| {
+                CategoryPattern::new(x).map_err(|e| {
+                    async_graphql::Error::new(e.to_string()).extend_with(|_, e| {
+                        e.set("code", "INVALID_PATTERN");
+                        e.set("field", "category");
+                    })
+                })
+            })
+            .transpose()?;
+        let filter = vars
+            .where_
+            .as_deref()
+            .map(|x| {
+                Filter::parse(x).map_err(|e| {
+                    invalid_input("where", e.to_string()).extend_with(|_, ext| {
+                        ext.set("position", e.position as u64);
+                    })
+                })
+            })
+            .transpose()?;
+        let levels = LevelFilter::new(vars.min_level, vars.level_in)?;
+        let session = self.find_session(ctx, &vars.name)?;
+        let start = match vars.start_elapsed {
+            None => start,
+            Some(_) if vars.start.is_some() => {
+                return Err(invalid_input(
+                    "startElapsed",
+                    "startElapsed can not be used with start".to_string(),
+                ))
+            }
+            Some(x) if x.is_finite() && x >= 0.0 => {
+                // 概要で前の範囲を飛ばして探す
+                match seek_elapsed(session.path(), Duration::from_secs_f64(x))? {
+                    Some(x) => x,
+                    None => return Ok(Vec::new()),
+                }
+            }
+            Some(x) => {
+                return Err(invalid_input(
+                    "startElapsed",
+                    format!("startElapsed must be 0 or more seconds, got {}", x),
+                ))
+            }
+        };
+        // kvを返さず絞り込みにも使わなければ復号しない
+        let lazy_kv = filter.is_none() && !ctx.look_ahead().field("record").field("kv").exists();
+        let page = self.read_at(&session, start, length, vars.on_error, lazy_kv)?;
+        if !page.skipped.is_empty() {
+            // 読めたレコードは返し、飛ばしたものをerrorsで知らせる
+            let indices = page
+                .skipped
+                .iter()
+                .map(|x| async_graphql::Value::from(x.index as u64))
+                .collect::<Vec<_>>();
+            let e = async_graphql::Error::new(format!(
+                "skipped {} records that could not be read",
+                page.skipped.len()
+            ))
+            .extend_with(|_, e| {
+                e.set("code", "RECORDS_SKIPPED");
+                e.set("skipped", page.skipped.len() as u64);
+                e.set("indices", async_graphql::Value::List(indices));
+            });
+            ctx.add_error(ctx.set_error_path(e.into_server_error(ctx.item.pos)));
+        }
+        Ok(page
+            .records
+            .iter()
+            .filter(|x| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|p| p.matches(&x.record.category))
+            })
+            .filter(|x| levels.matches(x.record.level()))
+            .filter(|x| filter.as_ref().is_none_or(|f| f.matches(&x.record)))
+            .filter(|x| !(vars.exclude_server_records && is_server_record(&x.record)))
+            .enumerate()
+            .map(|(i, x)| x.clone().with_matched_index(i))
+            .collect())
+    }
+
+    /// セッションのアーカイブをダウンロードするURLを返す
+    async fn archive_session(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+    ) -> async_graphql::Result<String> {
+        let session = self.find_session(ctx, &name)?;
+        let name = session.path().file_name().unwrap().to_string_lossy();
+        Ok(format!("{}/{}", ARCHIVE_PATH, name))
+    }
 
-        let record = records.into_iter().map(SessionViewInfo::from).collect();
-        Ok(record)
+    /// レコードから分離して保存したblobの長さとダウンロードするURLを返す
+    async fn blob(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        hash: String,
+    ) -> async_graphql::Result<BlobInfo> {
+        validate_name("name", &name)?;
+        if !crate::blob::is_valid_hash(&hash) {
+            return Err(invalid_input(
+                "hash",
+                "hash must be 64 lowercase hex characters".to_string(),
+            ));
+        }
+        let session = self.find_session(ctx, &name)?;
+        let name = session.path().file_name().unwrap().to_string_lossy();
+        let len = self
+            .tenant_storage(ctx)?
+            .blob_len(&name, &hash)
+            .map_err(|e| {
+                async_graphql::Error::new(e.to_string()).extend_with(|_, ext| {
+                    ext.set("code", "BLOB_NOT_FOUND");
+                    ext.set("field", "hash");
+                })
+            })?;
+        Ok(BlobInfo {
+            len,
+            url: format!("{}/{}/{}", BLOB_PATH, name, hash),
+            hash,
+        })
     }
 
-    async fn storage_read_at(&self, vars: ReadAtVars) -> Result<Vec<LogRecord>, std::io::Error> {
-        let records = self.storage.records().unwrap();
-        let target: Vec<SessionInfo> = records
+    /// セッションの添付ファイルとダウンロードするURLを返す
+    async fn attachments(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+    ) -> async_graphql::Result<Vec<AttachmentInfo>> {
+        let session = self.find_session(ctx, &name)?;
+        let name = session.path().file_name().unwrap().to_string_lossy();
+        Ok(self
+            .tenant_storage(ctx)?
+            .attachments(&name)?
             .into_iter()
-            .filter(|x| x.path().to_str().unwrap().contains(&vars.name))
-            .collect();
-        if target.is_empty() {
-            return Ok(Vec::new());
+            .map(|x| AttachmentInfo {
+                url: format!(
+                    "{}/{}/{}",
+                    ATTACHMENT_PATH,
+                    path_segment(&name),
+                    path_segment(&x.id)
+                ),
+                complete: x.is_complete(),
+                id: x.id,
+                filename: x.filename,
+                mime: x.mime,
+                category: x.category,
+                len: x.len,
+                parts: x.parts,
+                received: x.received,
+            })
+            .collect())
+    }
+
+    /// 指定したレコードの前後を返す
+    #[graphql(
+        complexity = "list_complexity(before.saturating_add(after).saturating_add(1), child_complexity)"
+    )]
+    async fn context(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        id: i64,
+        #[graphql(default = 20)] before: i64,
+        #[graphql(default = 20)] after: i64,
+    ) -> async_graphql::Result<Vec<ContextRecord>> {
+        let id = validate_count("id", Some(id), 0, usize::MAX)?;
+        let max = self.limits.max_read_length;
+        let before = validate_count("before", Some(before), 0, max)?;
+        let after = validate_count("after", Some(after), 0, max)?;
+        let session = self.find_session(ctx, &name)?;
+        let start = id.saturating_sub(before);
+        let lazy_kv = !ctx
+            .look_ahead()
+            .field("record")
+            .field("record")
+            .field("kv")
+            .exists();
+        let records = self
+            .read_at(
+                &session,
+                start,
+                id - start + after + 1,
+                OnError::Skip,
+                lazy_kv,
+            )?
+            .records
+            .clone();
+        if !records.iter().any(|x| x.id == id) {
+            return Err(
+                async_graphql::Error::new(format!("record id {} is out of range", id)).extend_with(
+                    |_, e| {
+                        e.set("code", "OUT_OF_RANGE");
+                        e.set("id", id);
+                    },
+                ),
+            );
         }
-        let session = &target[0];
-        let mut reader: CBORSequenceReader = session.open().unwrap().into();
-        reader.read_at(vars.start.unwrap_or(0), vars.length.unwrap_or(100))
+        Ok(records
+            .iter()
+            .map(|record| ContextRecord {
+                target: record.id == id,
+                record: record.clone(),
+            })
+            .collect())
+    }
+
+    /// 読み出し結果のキャッシュの利用状況
+    async fn query_cache_stats(&self, ctx: &Context<'_>) -> async_graphql::Result<QueryCacheStats> {
+        authenticate(ctx)?;
+        Ok(self.cache.stats())
+    }
+
+    /// レコードを送らずにセッションを作らないまま閉じた接続の数
+    async fn rejected_handshakes(&self, ctx: &Context<'_>) -> async_graphql::Result<u64> {
+        authenticate(ctx)?;
+        Ok(crate::actor::rejected_handshakes())
+    }
+
+    /// 接続やセッション、キャッシュのために今持っているものの数。増え続けていないかを見る
+    async fn resource_gauges(&self, ctx: &Context<'_>) -> async_graphql::Result<ResourceGauges> {
+        authenticate(ctx)?;
+        Ok(ResourceGauges {
+            actors: crate::actor::actor_gauges(),
+            registry: self.storage.registry().gauges(),
+            query_cache: self.cache.stats(),
+        })
+    }
+
+    /// 書き込みに失敗したレコードの書き直しと損失の数
+    async fn write_retry_stats(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<crate::RetryStats> {
+        authenticate(ctx)?;
+        Ok(crate::retry::retry_stats())
+    }
+
+    /// ストレージの空き容量と読み取り専用か
+    async fn disk_status(&self, ctx: &Context<'_>) -> async_graphql::Result<DiskStatus> {
+        authenticate(ctx)?;
+        Ok(self.disk.status())
+    }
+
+    /// 閉じた理由ごとのセッション数
+    async fn close_reason_counts(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<Vec<CloseReasonCount>> {
+        authenticate(ctx)?;
+        Ok(crate::lifecycle::close_counts()
+            .into_iter()
+            .map(|(reason, count)| CloseReasonCount {
+                reason: reason.as_str().to_string(),
+                count,
+            })
+            .collect())
+    }
+
+    /// セッションごとのカテゴリ別のレコード数を`names`の順に並べる。
+    /// レベルを指定したら、いずれかのセッションでの最も高いレベルが合うカテゴリの行だけ返す
+    async fn multi_session_stats(
+        &self,
+        ctx: &Context<'_>,
+        names: Vec<String>,
+        min_level: Option<LogLevel>,
+        level_in: Option<Vec<LogLevel>>,
+    ) -> async_graphql::Result<StatsTable> {
+        let levels = LevelFilter::new(min_level, level_in)?;
+        if names.is_empty() || names.len() > MAX_STATS_SESSIONS {
+            return Err(invalid_input(
+                "names",
+                format!(
+                    "names must have 1 to {} sessions, got {}",
+                    MAX_STATS_SESSIONS,
+                    names.len()
+                ),
+            ));
+        }
+        let names = names
+            .iter()
+            .map(|x| Ok(self.find_session(ctx, x)?.name()))
+            .collect::<async_graphql::Result<Vec<_>>>()?;
+        let mut table = self
+            .tenant_storage(ctx)?
+            .sessions_stats_with(&names, self.scan_options())?;
+        if !levels.is_empty() {
+            let mut max_levels = BTreeMap::<String, Level>::new();
+            for name in table.sessions.iter() {
+                for (category, level) in self
+                    .tenant_storage(ctx)?
+                    .session_stats(name)?
+                    .max_levels
+                    .iter()
+                {
+                    let max = max_levels.entry(category.clone()).or_insert(*level);
+                    *max = (*max).max(*level);
+                }
+            }
+            table.retain_categories(|x| max_levels.get(x).is_some_and(|l| levels.matches(*l)));
+        }
+        Ok(table)
+    }
+
+    /// 条件に合うセッションを並列に検索し、見つけたレコードを時刻順に最大`limit`件返す。
+    /// 期限までに読み終わらなかったセッションがあれば`partial`にする
+    #[graphql(
+        complexity = "list_complexity(limit.unwrap_or(DEFAULT_SEARCH_LENGTH as i64), child_complexity)"
+    )]
+    async fn search_all(
+        &self,
+        ctx: &Context<'_>,
+        query: String,
+        session_filter: Option<SessionFilter>,
+        limit: Option<i64>,
+        min_level: Option<LogLevel>,
+        level_in: Option<Vec<LogLevel>>,
+    ) -> async_graphql::Result<SearchAllResult> {
+        let levels = LevelFilter::new(min_level, level_in)?;
+        let limit = validate_count(
+            "limit",
+            limit,
+            DEFAULT_SEARCH_LENGTH,
+            self.limits.max_read_length,
+        )?;
+        let filter = Filter::parse(&query).map_err(|e| {
+            invalid_input("query", e.to_string()).extend_with(|_, ext| {
+                ext.set("position", e.position as u64);
+            })
+        })?;
+        let sessions = self.search_targets(ctx, session_filter.unwrap_or_default())?;
+        let result = search_sessions(
+            &sessions,
+            |x| levels.matches(x.level()) && filter.matches(x),
+            limit,
+            self.scan_options(),
+            self.open,
+        )?;
+        Ok(SearchAllResult {
+            partial: result.is_partial(),
+            scanned: result.scanned as u64,
+            incomplete: result.incomplete,
+            hits: result
+                .hits
+                .into_iter()
+                .map(|x| SearchHitView {
+                    session: x.session,
+                    record: x.record,
+                })
+                .collect(),
+        })
+    }
+
+    /// elapsedの`bucketSeconds`秒ごとのレベル別のレコード数。閉じた範囲は概要から数える
+    async fn session_histogram(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        #[graphql(default = 60)] bucket_seconds: i64,
+    ) -> async_graphql::Result<Histogram> {
+        if bucket_seconds < 1 {
+            return Err(invalid_input(
+                "bucketSeconds",
+                format!("bucketSeconds must be 1 or more, got {}", bucket_seconds),
+            ));
+        }
+        let name = self.find_session(ctx, &name)?.name();
+        Ok(self
+            .tenant_storage(ctx)?
+            .session_histogram(&name, bucket_seconds as u64)?)
+    }
+
+    /// セッションのカテゴリを`.`で区切った木。集計と一緒に保持したものを返す
+    async fn categories(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+    ) -> async_graphql::Result<Vec<CategoryNode>> {
+        let name = self.find_session(ctx, &name)?.name();
+        Ok(self.tenant_storage(ctx)?.session_stats(&name)?.tree.clone())
+    }
+
+    /// セッションのkvのキーごとに見た値の型と、エクスポートする列の型
+    async fn kv_keys(&self, ctx: &Context<'_>, name: String) -> async_graphql::Result<Vec<KvKey>> {
+        let name = self.find_session(ctx, &name)?.name();
+        Ok(self.tenant_storage(ctx)?.kv_types(&name)?.keys())
+    }
+
+    /// セッションのカテゴリごとの記録の間隔。クライアントが間隔を付けたカテゴリだけ返す
+    async fn category_deltas(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+    ) -> async_graphql::Result<Vec<DeltaStats>> {
+        let name = self.find_session(ctx, &name)?.name();
+        Ok(self
+            .tenant_storage(ctx)?
+            .session_stats(&name)?
+            .deltas
+            .clone())
+    }
+
+    /// 保存先を変更した操作を新しい順に返す。トークンで制限している場合は読めるセッションの操作だけ
+    async fn audit(
+        &self,
+        ctx: &Context<'_>,
+        limit: Option<i64>,
+    ) -> async_graphql::Result<Vec<AuditEntryView>> {
+        let limit = validate_count("limit", limit, DEFAULT_AUDIT_LENGTH, MAX_AUDIT_LENGTH)?;
+        let scope = request_scope(ctx);
+        scope.authenticate().map_err(access_denied)?;
+        let entries = match scope {
+            Scope::Open => self.tenant_storage(ctx)?.audit_log(Some(limit))?,
+            _ => self.tenant_storage(ctx)?.audit_log(None)?,
+        };
+        Ok(entries
+            .into_iter()
+            .filter(|x| scope.allows(&x.session, Permission::Read))
+            .take(limit)
+            .map(AuditEntryView::from)
+            .collect())
+    }
+
+    /// 受信サーバーに接続中のクライアントを接続した順に返す。トークンで制限している場合は読めるセッションだけ
+    async fn live_connections(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<Vec<LiveConnectionView>> {
+        authenticate(ctx)?;
+        let scope = request_scope(ctx);
+        let list = live_connections(self.connections.as_ref(), LiveConnections::List).await?;
+        Ok(list
+            .into_iter()
+            .filter(|x| connection_visible(&scope, x))
+            .map(LiveConnectionView::from)
+            .collect())
     }
 }
 
+pub struct Mutation {
+    storage: Storage,
+    /// 接続中のクライアントへの設定変更の送り先
+    control: Option<Recipient<RouteControl>>,
+    /// 受信サーバーの接続を切る問い合わせ先
+    connections: Option<Recipient<LiveConnections>>,
+}
+
+impl Mutation {
+    pub fn new(storage: Storage) -> Self {
+        Self {
+            storage,
+            control: None,
+            connections: None,
+        }
+    }
+
+    /// 受信サーバーと同じプロセスで動く場合に設定する
+    pub fn connections(mut self, connections: Recipient<LiveConnections>) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    /// 受信サーバーと同じプロセスで動く場合に設定する
+    pub fn control(mut self, control: Recipient<RouteControl>) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// 変更の記録にクライアントのアドレスを残すストレージ
+    fn storage_for(&self, ctx: &Context<'_>) -> async_graphql::Result<Storage> {
+        let storage = self.tenant_storage(ctx)?;
+        Ok(match ctx.data_opt::<ClientAddr>() {
+            Some(addr) => storage.as_initiator(&addr.0),
+            None => storage,
+        })
+    }
+
+    /// リクエストのトークンのテナントの保存先
+    fn tenant_storage(&self, ctx: &Context<'_>) -> async_graphql::Result<Storage> {
+        Ok(self.storage.scoped(request_scope(ctx).tenant())?)
+    }
+
+    fn session_view(
+        &self,
+        ctx: &Context<'_>,
+        name: &str,
+    ) -> async_graphql::Result<SessionViewInfo> {
+        self.tenant_storage(ctx)?
+            .records()?
+            .into_iter()
+            .find(|x| x.path().file_name().map(|x| x == name).unwrap_or(false))
+            .map(SessionViewInfo::from)
+            .ok_or_else(|| session_not_found(name))
+    }
+}
+
+fn session_not_found(name: &str) -> async_graphql::Error {
+    async_graphql::Error::new(format!("session not found: {}", name)).extend_with(|_, e| {
+        e.set("code", "SESSION_NOT_FOUND");
+        e.set("field", "name");
+    })
+}
+
+/// ストレージのエラーをGraphQLのエラーに変換する
+fn storage_error(name: &str, e: std::io::Error) -> async_graphql::Error {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => session_not_found(name),
+        std::io::ErrorKind::InvalidInput => invalid_input("tag", e.to_string()),
+        _ => e.into(),
+    }
+}
+
+#[Object]
+impl Mutation {
+    /// セッションにメモを残す。空文字列で削除する
+    async fn set_session_note(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        note: String,
+    ) -> async_graphql::Result<SessionViewInfo> {
+        validate_name("name", &name)?;
+        authorize(ctx, &name, Permission::Mutate)?;
+        validate_text("note", &note, true, MAX_NOTE_LENGTH)?;
+        self.tenant_storage(ctx)?
+            .set_session_note(&name, &note)
+            .map_err(|e| storage_error(&name, e))?;
+        self.session_view(ctx, &name)
+    }
+
+    async fn add_session_tag(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        tag: String,
+    ) -> async_graphql::Result<SessionViewInfo> {
+        validate_name("name", &name)?;
+        authorize(ctx, &name, Permission::Mutate)?;
+        validate_text("tag", &tag, false, MAX_TAG_LENGTH)?;
+        self.tenant_storage(ctx)?
+            .add_session_tag(&name, &tag)
+            .map_err(|e| storage_error(&name, e))?;
+        self.session_view(ctx, &name)
+    }
+
+    /// セッションのレコードを読み直して異常の印を記録する
+    async fn assess_session(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+    ) -> async_graphql::Result<SessionViewInfo> {
+        validate_name("name", &name)?;
+        authorize(ctx, &name, Permission::Mutate)?;
+        self.tenant_storage(ctx)?
+            .assess_session(&name)
+            .map_err(|e| storage_error(&name, e))?;
+        self.session_view(ctx, &name)
+    }
+
+    async fn remove_session_tag(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        tag: String,
+    ) -> async_graphql::Result<SessionViewInfo> {
+        validate_name("name", &name)?;
+        authorize(ctx, &name, Permission::Mutate)?;
+        self.tenant_storage(ctx)?
+            .remove_session_tag(&name, &tag)
+            .map_err(|e| storage_error(&name, e))?;
+        self.session_view(ctx, &name)
+    }
+
+    /// 固定したセッションは空き容量のために削除せず、`deleteSession`も`force`が必要になる
+    async fn pin_session(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        #[graphql(default = true)] pinned: bool,
+    ) -> async_graphql::Result<SessionViewInfo> {
+        validate_name("name", &name)?;
+        authorize(ctx, &name, Permission::Mutate)?;
+        self.tenant_storage(ctx)?
+            .pin_session(&name, pinned)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::QuotaExceeded => async_graphql::Error::new(e.to_string())
+                    .extend_with(|_, e| e.set("code", "PIN_LIMIT_EXCEEDED")),
+                _ => storage_error(&name, e),
+            })?;
+        self.session_view(ctx, &name)
+    }
+
+    /// セッションを削除して、削除したバイト数を返す。固定したセッションは`force`がなければ削除しない
+    async fn delete_session(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        #[graphql(default)] force: bool,
+    ) -> async_graphql::Result<u64> {
+        validate_name("name", &name)?;
+        authorize(ctx, &name, Permission::Mutate)?;
+        let storage = self.storage_for(ctx)?;
+        let info = self.session_view(ctx, &name)?;
+        if info.live {
+            return Err(
+                async_graphql::Error::new(format!("session is being written: {}", name))
+                    .extend_with(|_, e| {
+                        e.set("code", "SESSION_LIVE");
+                        e.set("field", "name");
+                    }),
+            );
+        }
+        if info.pinned && !force {
+            return Err(async_graphql::Error::new(format!(
+                "session is pinned, delete it with force: {}",
+                name
+            ))
+            .extend_with(|_, e| {
+                e.set("code", "SESSION_PINNED");
+                e.set("field", "name");
+            }));
+        }
+        let removed = match force {
+            true => storage.force_remove_session(&name),
+            false => storage.remove_session(&name),
+        };
+        removed.map_err(|e| storage_error(&name, e))
+    }
+
+    /// elapsedが`from`秒以上`to`秒未満のレコードを新しいセッションにコピーする。
+    /// `rebase`の場合はelapsedを`from`からの時間にする
+    async fn trim_session(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        from: f64,
+        to: f64,
+        new_name: String,
+        #[graphql(default)] rebase: bool,
+    ) -> async_graphql::Result<SessionViewInfo> {
+        validate_name("name", &name)?;
+        validate_name("newName", &new_name)?;
+        // 元のセッションを読んで新しいセッションを作る
+        authorize(ctx, &name, Permission::Mutate)?;
+        authorize(ctx, &new_name, Permission::Mutate)?;
+        let seconds = |field, x: f64| {
+            Duration::try_from_secs_f64(x).map_err(|_| {
+                invalid_input(field, format!("{} must be non-negative seconds", field))
+            })
+        };
+        let (from, to) = (seconds("from", from)?, seconds("to", to)?);
+        self.storage_for(ctx)?
+            .trim_session(&name, from, to, &new_name, rebase)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => session_not_found(&name),
+                std::io::ErrorKind::AlreadyExists => invalid_input("newName", e.to_string()),
+                std::io::ErrorKind::InvalidInput => async_graphql::Error::new(e.to_string())
+                    .extend_with(|_, e| e.set("code", "EMPTY_RANGE")),
+                _ => e.into(),
+            })?;
+        self.session_view(ctx, &new_name)
+    }
+
+    /// 接続中のクライアントの出力レベルを変更する。categoryが空の場合は全体
+    async fn set_client_level(
+        &self,
+        ctx: &Context<'_>,
+        session: String,
+        level: LogLevel,
+        category: String,
+    ) -> async_graphql::Result<bool> {
+        validate_name("session", &session)?;
+        authorize(ctx, &session, Permission::Mutate)?;
+        if !category.is_empty() {
+            CategoryPattern::new(&category).map_err(|e| {
+                async_graphql::Error::new(e.to_string()).extend_with(|_, e| {
+                    e.set("code", "INVALID_PATTERN");
+                    e.set("field", "category");
+                })
+            })?;
+        }
+        let command =
+            ControlCommand::set_level(level.into(), (!category.is_empty()).then_some(category));
+        self.route_control(ctx, session, command).await
+    }
+
+    /// 接続中のクライアントに端末に残した履歴を別のセッションとして送らせる
+    ///
+    /// 範囲はクライアントのセッション開始からの秒数で、ない場合は最も古いものから最新まで
+    async fn request_client_history(
+        &self,
+        ctx: &Context<'_>,
+        session: String,
+        from_elapsed: Option<f64>,
+        to_elapsed: Option<f64>,
+    ) -> async_graphql::Result<bool> {
+        validate_name("session", &session)?;
+        authorize(ctx, &session, Permission::Mutate)?;
+        for (field, value) in [("fromElapsed", from_elapsed), ("toElapsed", to_elapsed)] {
+            if value.is_some_and(|x| !x.is_finite() || x < 0.0) {
+                return Err(async_graphql::Error::new(format!(
+                    "{} must be a positive number of seconds",
+                    field
+                ))
+                .extend_with(|_, e| {
+                    e.set("code", "INVALID_RANGE");
+                    e.set("field", field);
+                }));
+            }
+        }
+        let command = ControlCommand::upload_history(from_elapsed, to_elapsed);
+        self.route_control(ctx, session, command).await
+    }
+
+    /// 接続を閉じる。クライアントは切断された理由を受け取って再接続する
+    async fn disconnect(&self, ctx: &Context<'_>, uuid: String) -> async_graphql::Result<bool> {
+        let id = uuid::Uuid::parse_str(&uuid)
+            .map_err(|e| invalid_input("uuid", format!("invalid uuid {}: {}", uuid, e)))?;
+        let scope = request_scope(ctx);
+        let connections = self.connections.as_ref();
+        let not_found = || {
+            async_graphql::Error::new(format!("connection {} is not open", id)).extend_with(
+                |_, e| {
+                    e.set("code", "CONNECTION_NOT_FOUND");
+                    e.set("field", "uuid");
+                },
+            )
+        };
+        let target = live_connections(connections, LiveConnections::List)
+            .await?
+            .into_iter()
+            .find(|x| x.uuid == id && connection_visible(&scope, x))
+            .ok_or_else(not_found)?;
+        authorize(
+            ctx,
+            target.session.as_deref().unwrap_or(&uuid),
+            Permission::Mutate,
+        )?;
+        live_connections(connections, LiveConnections::Disconnect(id)).await?;
+        Ok(true)
+    }
+}
+
+impl Mutation {
+    /// 接続中のクライアントにコマンドを送る
+    async fn route_control(
+        &self,
+        ctx: &Context<'_>,
+        session: String,
+        command: ControlCommand,
+    ) -> async_graphql::Result<bool> {
+        let control = self.control.as_ref().ok_or_else(|| {
+            async_graphql::Error::new("client control is not available")
+                .extend_with(|_, e| e.set("code", "NOT_AVAILABLE"))
+        })?;
+        control
+            .send(RouteControl {
+                session,
+                tenant: request_scope(ctx).tenant().map(String::from),
+                command,
+            })
+            .await?
+            .map_err(|e| {
+                async_graphql::Error::new(e).extend_with(|_, e| {
+                    e.set("code", "SESSION_NOT_CONNECTED");
+                    e.set("field", "session");
+                })
+            })?;
+        Ok(true)
+    }
+}
+
+/// 前後のレコードのうち指定したレコードに印をつける
+#[derive(SimpleObject)]
+struct ContextRecord {
+    target: bool,
+    record: LogRecord,
+}
+
+/// 閉じた理由とその数
+#[derive(SimpleObject)]
+struct CloseReasonCount {
+    reason: String,
+    count: u64,
+}
+
+/// レコードから分離して保存したバイト列
+#[derive(SimpleObject)]
+struct BlobInfo {
+    hash: String,
+    len: u64,
+    /// 内容をダウンロードするパス
+    url: String,
+}
+
+/// クライアントが送った添付ファイル
+#[derive(SimpleObject)]
+struct AttachmentInfo {
+    id: String,
+    filename: String,
+    mime: String,
+    category: String,
+    len: u64,
+    parts: u32,
+    /// セッションにある部分の数
+    received: u32,
+    /// 全ての部分が揃っているか
+    complete: bool,
+    /// 内容をダウンロードするパス。揃っていなければ404を返す
+    url: String,
+}
+
+#[derive(SimpleObject)]
+struct ResourceGauges {
+    actors: crate::actor::ActorGauges,
+    registry: crate::RegistryGauges,
+    query_cache: QueryCacheStats,
+}
+
+/// `searchAll`で検索するセッション。既定は全て
+#[derive(InputObject, Default)]
+struct SessionFilter {
+    tag: Option<String>,
+    name_contains: Option<String>,
+    /// sessions created at or after this time
+    since: Option<DateTimeScalar>,
+    /// only the last N created sessions
+    latest: Option<i64>,
+}
+
+/// `searchAll`で見つけたレコード
+#[derive(SimpleObject)]
+struct SearchHitView {
+    session: String,
+    record: LogRecord,
+}
+
+#[derive(SimpleObject)]
+struct SearchAllResult {
+    hits: Vec<SearchHitView>,
+    /// sessions searched to the end
+    scanned: u64,
+    /// sessions not searched to the end before the deadline
+    incomplete: Vec<String>,
+    /// true when `incomplete` is not empty, so more records may match
+    partial: bool,
+}
+
 #[derive(InputObject)]
 struct ReadAtVars {
     name: String,
-    start: Option<usize>,
-    length: Option<usize>,
+    start: Option<i64>,
+    /// start at the first record of the client at or after this elapsed second instead of `start`
+    start_elapsed: Option<f64>,
+    length: Option<i64>,
+    /// `net.*.rx`のようなカテゴリのパターン
+    category: Option<String>,
+    /// `level >= warn && kv.retries > 3`のような絞り込み式
+    #[graphql(name = "where")]
+    where_: Option<String>,
+    /// skip the `uplog.session` records written by the server
+    #[graphql(default)]
+    exclude_server_records: bool,
+    /// what to do with a record that cannot be read. `SKIP` reports the skipped records in `errors`
+    #[graphql(default)]
+    on_error: OnError,
+    /// only records at this level or above
+    min_level: Option<LogLevel>,
+    /// only records at one of these levels
+    level_in: Option<Vec<LogLevel>>,
+}
+
+/// `minLevel`と`levelIn`による絞り込み。両方指定したら両方に合うものだけ
+#[derive(Default)]
+struct LevelFilter {
+    min: Option<LogLevel>,
+    any_of: Option<Vec<LogLevel>>,
+}
+
+impl LevelFilter {
+    fn new(
+        min_level: Option<LogLevel>,
+        level_in: Option<Vec<LogLevel>>,
+    ) -> async_graphql::Result<Self> {
+        if level_in.as_ref().is_some_and(Vec::is_empty) {
+            return Err(invalid_input(
+                "levelIn",
+                "levelIn must have at least one level".to_string(),
+            ));
+        }
+        Ok(Self {
+            min: min_level,
+            any_of: level_in,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.min.is_none() && self.any_of.is_none()
+    }
+
+    fn matches(&self, level: Level) -> bool {
+        let level = LogLevel::from(level);
+        self.min.is_none_or(|x| level >= x)
+            && self.any_of.as_ref().is_none_or(|x| x.contains(&level))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptySubscription, Schema};
+    use futures::executor::block_on;
+    use tempdir::TempDir;
+    use uplog::{devinit, devlog, Level};
+
+    use super::{build_schema, execute, Mutation, Query, QueryLimits};
+    use crate::{
+        writer::{CBORSequenceWriter, RecordWriter},
+        Storage,
+    };
+
+    fn setup(dir: &TempDir, count: u64) -> Storage {
+        devinit!();
+        let storage = Storage::new(dir.path()).unwrap();
+        let mut session = storage.create_session("ctx").unwrap();
+        for i in 0..count {
+            let r = devlog!(Level::Info, "cat", "msg", "number", i);
+            session.push(&r).unwrap();
+        }
+        storage
+    }
+
+    fn query(storage: Storage, q: &str) -> async_graphql::Response {
+        query_with(Query::new(storage.clone()), storage, q)
+    }
+
+    fn query_with(query: Query, storage: Storage, q: &str) -> async_graphql::Response {
+        let schema = build_schema(query, Mutation::new(storage));
+        block_on(execute(&schema, q))
+    }
+
+    #[test]
+    fn test_context() {
+        let dir = TempDir::new("context").unwrap();
+        let storage = setup(&dir, 300);
+
+        // mid-file
+        let res = query(
+            storage.clone(),
+            r#"{ context(name: "ctx", id: 150, before: 20, after: 20) { target record { id } } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let data = res.data.into_json().unwrap();
+        let records = data["context"].as_array().unwrap();
+        assert_eq!(records.len(), 41);
+        assert_eq!(records[0]["record"]["id"], 130);
+        assert_eq!(records[20]["record"]["id"], 150);
+        assert_eq!(records[20]["target"], true);
+        assert_eq!(records.iter().filter(|x| x["target"] == true).count(), 1);
+
+        // head
+        let res = query(
+            storage.clone(),
+            r#"{ context(name: "ctx", id: 3, before: 20, after: 2) { target record { id } } }"#,
+        );
+        let data = res.data.into_json().unwrap();
+        let records = data["context"].as_array().unwrap();
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[0]["record"]["id"], 0);
+        assert_eq!(records[3]["target"], true);
+
+        // out of range
+        let res = query(
+            storage,
+            r#"{ context(name: "ctx", id: 300, before: 20, after: 20) { target } }"#,
+        );
+        assert_eq!(res.errors.len(), 1);
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "OUT_OF_RANGE");
+    }
+
+    #[test]
+    fn test_session_note_and_tags() {
+        let dir = TempDir::new("meta").unwrap();
+        let storage = setup(&dir, 1);
+        storage.create_session("other").unwrap();
+
+        let res = query(
+            storage.clone(),
+            r#"mutation { setSessionNote(name: "ctx", note: "reproduced bug #123") { note } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let res = query(
+            storage.clone(),
+            r#"mutation { setSessionNote(name: "ctx", note: "fixed") { note } }"#,
+        );
+        assert_eq!(
+            res.data.into_json().unwrap()["setSessionNote"]["note"],
+            "fixed"
+        );
+        for tag in ["crash", "fw-2.3", "crash"] {
+            let q = format!(
+                r#"mutation {{ addSessionTag(name: "ctx", tag: "{}") {{ tags }} }}"#,
+                tag
+            );
+            assert!(query(storage.clone(), &q).errors.is_empty());
+        }
+
+        // メタデータのないセッションも一覧に含まれる
+        let res = query(storage.clone(), r#"{ storages { name note tags } }"#);
+        let data = res.data.into_json().unwrap();
+        let sessions = data["storages"].as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        let other = sessions.iter().find(|x| x["name"] == "other").unwrap();
+        assert!(other["note"].is_null());
+        assert_eq!(other["tags"].as_array().unwrap().len(), 0);
+
+        let res = query(
+            storage.clone(),
+            r#"{ storages(tag: "crash") { name tags } }"#,
+        );
+        let data = res.data.into_json().unwrap();
+        let sessions = data["storages"].as_array().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0]["name"], "ctx");
+        assert_eq!(sessions[0]["tags"], serde_json::json!(["crash", "fw-2.3"]));
+
+        let res = query(
+            storage.clone(),
+            r#"mutation { removeSessionTag(name: "ctx", tag: "crash") { tags } }"#,
+        );
+        assert_eq!(
+            res.data.into_json().unwrap()["removeSessionTag"]["tags"],
+            serde_json::json!(["fw-2.3"])
+        );
+        let res = query(storage.clone(), r#"{ storages(tag: "crash") { name } }"#);
+        assert_eq!(
+            res.data.into_json().unwrap()["storages"],
+            serde_json::json!([])
+        );
+
+        let res = query(
+            storage,
+            r#"mutation { addSessionTag(name: "not_found", tag: "x") { tags } }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "SESSION_NOT_FOUND");
+    }
+
+    #[test]
+    fn test_pin_and_delete_session() {
+        let dir = TempDir::new("pin").unwrap();
+        let storage = setup(&dir, 1);
+        let mut live = storage.create_session("other").unwrap();
+        live.push(&devlog!(Level::Info, "cat", "msg")).unwrap();
+
+        let res = query(
+            storage.clone(),
+            r#"mutation { pinSession(name: "ctx") { name pinned } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(res.data.into_json().unwrap()["pinSession"]["pinned"], true);
+        let res = query(storage.clone(), r#"{ storages { name pinned } }"#);
+        let data = res.data.into_json().unwrap();
+        let pinned = data["storages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|x| x["pinned"] == true)
+            .map(|x| x["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(pinned, ["ctx"]);
+
+        let res = query(
+            storage.clone(),
+            r#"mutation { deleteSession(name: "ctx") }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "SESSION_PINNED");
+        assert!(storage.session_dir("ctx").is_ok());
+        let res = query(
+            storage.clone(),
+            r#"mutation { deleteSession(name: "ctx", force: true) }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert!(
+            res.data.into_json().unwrap()["deleteSession"]
+                .as_u64()
+                .unwrap()
+                > 0
+        );
+
+        // 書き込み中のセッションは削除しない
+        let res = query(
+            storage.clone(),
+            r#"mutation { deleteSession(name: "other") }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "SESSION_LIVE");
+        drop(live);
+
+        let res = query(
+            storage.clone().max_pinned_bytes(Some(0)),
+            r#"mutation { pinSession(name: "other") { pinned } }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "PIN_LIMIT_EXCEEDED");
+        let res = query(
+            storage,
+            r#"mutation { pinSession(name: "not_found") { pinned } }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "SESSION_NOT_FOUND");
+    }
+
+    #[test]
+    fn test_trim_session() {
+        let dir = TempDir::new("trim").unwrap();
+        let storage = setup(&dir, 1);
+        {
+            let mut session = storage.create_session("long").unwrap();
+            for i in 0..20_u64 {
+                let mut r = devlog!(Level::Info, "cat", "msg", "number", i);
+                r.elapsed = std::time::Duration::from_secs(i);
+                session.push(&r).unwrap();
+            }
+        }
+
+        let schema = build_schema(Query::new(storage.clone()), Mutation::new(storage.clone()));
+        let request = async_graphql::Request::new(
+            r#"mutation { trimSession(name: "long", from: 5, to: 8.5, newName: "short", rebase: true) { name parent } }"#,
+        )
+        .data(super::ClientAddr("192.0.2.1".to_string()));
+        let res = block_on(execute(&schema, request));
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["trimSession"],
+            serde_json::json!({"name": "short", "parent": "long"})
+        );
+        let res = query(
+            storage.clone(),
+            r#"{ storageReadAt(vars: {name: "short", length: 100}) { record { elapsed } } }"#,
+        );
+        let data = res.data.into_json().unwrap();
+        let elapsed = data["storageReadAt"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["record"]["elapsed"].as_f64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(elapsed, vec![0.0, 1.0, 2.0, 3.0]);
+
+        // クライアントのアドレスと一緒に記録する
+        let res = query(
+            storage.clone(),
+            r#"{ audit(limit: 10) { operation session target initiator bytes error } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let audit = &res.data.into_json().unwrap()["audit"];
+        assert_eq!(audit.as_array().unwrap().len(), 1);
+        assert_eq!(audit[0]["operation"], "trim");
+        assert_eq!(audit[0]["session"], "long");
+        assert_eq!(audit[0]["target"], "short");
+        assert_eq!(audit[0]["initiator"], "192.0.2.1");
+        assert!(audit[0]["bytes"].as_u64().unwrap() > 0);
+        assert!(audit[0]["error"].is_null());
+
+        // 詰めた経過時間に合わせて開始時刻を進めるので時刻は変わらない
+        let start = storage.session_meta("long").unwrap().start_at;
+        assert_eq!(start, None);
+        let timestamps = |name: &str| {
+            let q = format!(
+                r#"{{ storageReadAt(vars: {{name: "{}", length: 100}}) {{ timestamp timestampSynthetic }} }}"#,
+                name
+            );
+            let data = query(storage.clone(), &q).data.into_json().unwrap();
+            data["storageReadAt"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| {
+                    assert_eq!(x["timestampSynthetic"], true);
+                    x["timestamp"].as_str().unwrap().to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timestamps("short"), timestamps("long")[5..9].to_vec());
+
+        for (q, code) in [
+            (
+                r#"mutation { trimSession(name: "long", from: 100, to: 200, newName: "none") { name } }"#,
+                "EMPTY_RANGE",
+            ),
+            (
+                r#"mutation { trimSession(name: "long", from: 0, to: 1, newName: "short") { name } }"#,
+                "INVALID_INPUT",
+            ),
+            (
+                r#"mutation { trimSession(name: "long", from: -1, to: 1, newName: "x") { name } }"#,
+                "INVALID_INPUT",
+            ),
+            (
+                r#"mutation { trimSession(name: "none", from: 0, to: 1, newName: "x") { name } }"#,
+                "SESSION_NOT_FOUND",
+            ),
+        ] {
+            let res = query(storage.clone(), q);
+            let err = serde_json::to_value(&res.errors[0]).unwrap();
+            assert_eq!(err["extensions"]["code"], code, "{}", q);
+        }
+    }
+
+    #[test]
+    fn test_storage_read_at_category() {
+        devinit!();
+        let dir = TempDir::new("category").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        {
+            let mut session = storage.create_session("cat").unwrap();
+            for c in ["net.eth0.rx", "net.eth0.tx", "net.wlan0.rx", "app"] {
+                session.push(&devlog!(Level::Info, c, "msg")).unwrap();
+            }
+        }
+
+        let res = query(
+            storage.clone(),
+            r#"{ storageReadAt(vars: { name: "cat", category: "net.*.rx" }) { id } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["storageReadAt"],
+            serde_json::json!([{ "id": 0 }, { "id": 2 }])
+        );
+
+        let res = query(
+            storage,
+            r#"{ storageReadAt(vars: { name: "cat", category: "net.**x" }) { id } }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "INVALID_PATTERN");
+    }
+
+    #[test]
+    fn test_storage_read_at_lazy_kv() {
+        devinit!();
+        let dir = TempDir::new("lazy").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        {
+            let mut session = storage.create_session("lazy").unwrap();
+            for i in 0..5_u64 {
+                session
+                    .push(&devlog!(Level::Info, "cat", "msg", "number", i))
+                    .unwrap();
+            }
+        }
+
+        // kvを選ばない問い合わせの後でも、選んだ問い合わせはkvを返す
+        let schema = build_schema(Query::new(storage.clone()), Mutation::new(storage));
+        let res = block_on(execute(
+            &schema,
+            r#"{ storageReadAt(vars: { name: "lazy" }) { id record { message } } }"#,
+        ));
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let res = block_on(execute(
+            &schema,
+            r#"{ storageReadAt(vars: { name: "lazy" }) { id record { kv { json } } } }"#,
+        ));
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let data = res.data.into_json().unwrap();
+        let kv = data["storageReadAt"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["record"]["kv"]["json"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(kv.len(), 5);
+        for (i, json) in kv.iter().enumerate() {
+            let value: serde_json::Value = serde_json::from_str(json).unwrap();
+            assert_eq!(value["number"], i, "{}", json);
+        }
+    }
+
+    #[test]
+    fn test_session_histogram() {
+        devinit!();
+        let dir = TempDir::new("histogram").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        {
+            let mut session = storage.create_session("h").unwrap();
+            for i in 0..30_u64 {
+                let level = if i % 10 == 0 {
+                    Level::Error
+                } else {
+                    Level::Info
+                };
+                let mut r = devlog!(level, "cat", "msg");
+                r.elapsed = std::time::Duration::from_secs(i);
+                session.push(&r).unwrap();
+            }
+        }
+
+        let res = query(
+            storage.clone(),
+            r#"{ sessionHistogram(name: "h", bucketSeconds: 20) {
+                bucketSeconds fromOverview buckets { start levels { info error } }
+            } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["sessionHistogram"],
+            serde_json::json!({
+                "bucketSeconds": 20,
+                "fromOverview": true,
+                "buckets": [
+                    { "start": 0, "levels": { "info": 18, "error": 2 } },
+                    { "start": 20, "levels": { "info": 9, "error": 1 } },
+                ]
+            })
+        );
+        let res = query(
+            storage.clone(),
+            r#"{ sessionHistogram(name: "h", bucketSeconds: 0) { bucketSeconds } }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "INVALID_INPUT");
+
+        let read = |vars: &str| {
+            let q = format!(
+                r#"{{ storageReadAt(vars: {{ name: "h", length: 2, {} }}) {{ id }} }}"#,
+                vars
+            );
+            query(storage.clone(), &q)
+        };
+        let res = read("startElapsed: 12.5");
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["storageReadAt"],
+            serde_json::json!([{ "id": 13 }, { "id": 14 }])
+        );
+        let res = read("startElapsed: 100");
+        assert_eq!(
+            res.data.into_json().unwrap()["storageReadAt"],
+            serde_json::json!([])
+        );
+        for vars in ["startElapsed: -1", "startElapsed: 1, start: 0"] {
+            let err = serde_json::to_value(&read(vars).errors[0]).unwrap();
+            assert_eq!(err["extensions"]["code"], "INVALID_INPUT", "{}", vars);
+        }
+    }
+
+    #[test]
+    fn test_multi_session_stats() {
+        let dir = TempDir::new("stats").unwrap();
+        let storage = setup(&dir, 3);
+        let mut session = storage.create_session("other").unwrap();
+        session.push(&devlog!(Level::Error, "net", "msg")).unwrap();
+        session.flush();
+
+        let res = query(
+            storage.clone(),
+            r#"{ multiSessionStats(names: ["ctx", "other"]) {
+                sessions rows { category counts total } errors { counts }
+            } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["multiSessionStats"],
+            serde_json::json!({
+                "sessions": ["ctx", "other"],
+                "rows": [
+                    { "category": "cat", "counts": [3, 0], "total": 3 },
+                    { "category": "net", "counts": [0, 1], "total": 1 },
+                ],
+                "errors": { "counts": [0, 1] },
+            })
+        );
+
+        for q in [
+            r#"{ multiSessionStats(names: []) { sessions } }"#,
+            r#"{ multiSessionStats(names: ["missing"]) { sessions } }"#,
+        ] {
+            assert!(!query(storage.clone(), q).errors.is_empty(), "{}", q);
+        }
+    }
+
+    #[test]
+    fn test_categories() {
+        let dir = TempDir::new("stats").unwrap();
+        let storage = setup(&dir, 1);
+        let mut session = storage.create_session("tree").unwrap();
+        for (c, level) in [
+            ("net", Level::Info),
+            ("net.rx", Level::Error),
+            ("net.tx", Level::Debug),
+        ] {
+            session.push(&devlog!(level, c, "msg")).unwrap();
+        }
+        session.flush();
+
+        let res = query(
+            storage.clone(),
+            r#"{ categories(name: "tree") {
+                segment fullPath count ownCount maxLevel
+                children { segment fullPath count ownCount maxLevel children { segment } }
+            } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let leaf = |segment: &str, level: &str| {
+            serde_json::json!({
+                "segment": segment,
+                "fullPath": format!("net.{}", segment),
+                "count": 1,
+                "ownCount": 1,
+                "maxLevel": level,
+                "children": [],
+            })
+        };
+        assert_eq!(
+            res.data.into_json().unwrap()["categories"],
+            serde_json::json!([{
+                "segment": "net",
+                "fullPath": "net",
+                "count": 3,
+                "ownCount": 1,
+                "maxLevel": "ERROR",
+                "children": [leaf("rx", "ERROR"), leaf("tx", "DEBUG")],
+            }])
+        );
+        assert!(
+            !query(storage, r#"{ categories(name: "missing") { segment } }"#)
+                .errors
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_attachments() {
+        let dir = TempDir::new("attachment").unwrap();
+        let storage = setup(&dir, 1);
+        let mut session = storage.create_session("files").unwrap();
+        let bytes = [7_u8; 10];
+        let attachment = uplog::Attachment::new("a b.json", &bytes).chunk_size(4);
+        // 最後の部分が届いていない
+        for r in attachment.records("app").into_iter().take(2) {
+            session.push(&r).unwrap();
+        }
+        session.flush();
+
+        let res = query(
+            storage,
+            r#"{ attachments(name: "files") {
+                filename mime category len parts received complete url
+            } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["attachments"],
+            serde_json::json!([{
+                "filename": "a b.json",
+                "mime": "application/json",
+                "category": "app",
+                "len": 10,
+                "parts": 3,
+                "received": 2,
+                "complete": false,
+                "url": format!("{}/files/{}", super::ATTACHMENT_PATH, attachment.id()),
+            }])
+        );
+        assert_eq!(super::path_segment("a b/c"), "a%20b%2Fc");
+    }
+
+    #[test]
+    fn test_category_deltas() {
+        let dir = TempDir::new("stats").unwrap();
+        let storage = setup(&dir, 1);
+        let mut session = storage.create_session("deltas").unwrap();
+        for delta in [100_u64, 300] {
+            session
+                .push(&devlog!(Level::Info, "net", "msg", uplog::DELTA_KEY, delta))
+                .unwrap();
+        }
+        session.push(&devlog!(Level::Info, "app", "msg")).unwrap();
+        session.flush();
+
+        let res = query(
+            storage,
+            r#"{ categoryDeltas(name: "deltas") { category count minUs meanUs p99Us } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["categoryDeltas"],
+            serde_json::json!([{
+                "category": "net",
+                "count": 2,
+                "minUs": 100,
+                "meanUs": 200,
+                "p99Us": 300,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_kv_keys() {
+        let dir = TempDir::new("kvkeys").unwrap();
+        let storage = setup(&dir, 1);
+        let mut session = storage.create_session("kv").unwrap();
+        session
+            .push(&devlog!(Level::Info, "app", "msg", "n", 1_u64))
+            .unwrap();
+        session
+            .push(&devlog!(Level::Info, "app", "msg", "n", "one"))
+            .unwrap();
+        session
+            .push(&devlog!(Level::Info, "app", "msg", "n", 2.5_f64))
+            .unwrap();
+        session.flush();
+
+        let res = query(
+            storage,
+            r#"{ kvKeys(name: "kv") { key column types { kind count } } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["kvKeys"],
+            serde_json::json!([{
+                "key": "n",
+                "column": "F64",
+                "types": [
+                    {"kind": "U64", "count": 1},
+                    // CBORでは精度を落とさない小さい型で送る
+                    {"kind": "F32", "count": 1},
+                    {"kind": "TEXT", "count": 1},
+                ],
+            }])
+        );
+    }
+
+    /// 同じ読み出しはキャッシュから返し、追記されたら読み直す
+    #[test]
+    fn test_query_cache() {
+        devinit!();
+        let dir = TempDir::new("cache").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let mut session = storage.create_session("cache").unwrap();
+        for c in ["net", "app"] {
+            session.push(&devlog!(Level::Info, c, "msg")).unwrap();
+        }
+        session.flush();
+        let schema = Schema::build(
+            Query::new(storage.clone()),
+            Mutation::new(storage),
+            EmptySubscription,
+        )
+        .finish();
+        let ids = |q: &str| {
+            let res = block_on(schema.execute(q));
+            assert!(res.errors.is_empty(), "{:?}", res.errors);
+            res.data.into_json().unwrap()["storageReadAt"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x["id"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let stats = || {
+            let res = block_on(schema.execute("{ queryCacheStats { hits misses entries } }"));
+            res.data.into_json().unwrap()["queryCacheStats"].clone()
+        };
+
+        let all = r#"{ storageReadAt(vars: { name: "cache" }) { id } }"#;
+        assert_eq!(ids(all), vec![0, 1]);
+        assert_eq!(ids(all), vec![0, 1]);
+        // 絞り込みは読み出した範囲に適用するので同じ結果を使う
+        assert_eq!(
+            ids(r#"{ storageReadAt(vars: { name: "cache", category: "app" }) { id } }"#),
+            vec![1]
+        );
+        assert_eq!(
+            stats(),
+            serde_json::json!({ "hits": 2, "misses": 1, "entries": 1 })
+        );
+
+        session.push(&devlog!(Level::Info, "app", "msg")).unwrap();
+        session.flush();
+        assert_eq!(ids(all), vec![0, 1, 2]);
+        assert_eq!(
+            stats(),
+            serde_json::json!({ "hits": 2, "misses": 2, "entries": 1 })
+        );
+    }
+
+    #[test]
+    fn test_exclude_server_records() {
+        use crate::lifecycle::{closed_record, opened_record, CloseReason};
+        use std::time::Duration;
+
+        let dir = TempDir::new("server_records").unwrap();
+        let storage = setup(&dir, 0);
+        {
+            let mut session = storage.create_session("ctx").unwrap();
+            session
+                .push(&opened_record("127.0.0.1", "uplog.cbor.v1"))
+                .unwrap();
+            session.push(&devlog!(Level::Info, "cat", "msg")).unwrap();
+            session
+                .push(&closed_record(
+                    CloseReason::Client(None),
+                    Duration::ZERO,
+                    1,
+                    Duration::ZERO,
+                ))
+                .unwrap();
+        }
+        let read = |vars: &str| {
+            let q = format!(
+                r#"{{ storageReadAt(vars: {{ name: "ctx", {} }}) {{ id }} }}"#,
+                vars
+            );
+            let res = query(storage.clone(), &q);
+            assert!(res.errors.is_empty(), "{:?}", res.errors);
+            res.data.into_json().unwrap()["storageReadAt"].clone()
+        };
+        assert_eq!(
+            read("start: 0"),
+            serde_json::json!([{ "id": 0 }, { "id": 1 }, { "id": 2 }])
+        );
+        assert_eq!(
+            read("excludeServerRecords: true"),
+            serde_json::json!([{ "id": 1 }])
+        );
+    }
+
+    #[test]
+    fn test_storage_read_at_where() {
+        let dir = TempDir::new("where").unwrap();
+        let storage = setup(&dir, 10);
+
+        let res = query(
+            storage.clone(),
+            r#"{ storageReadAt(vars: { name: "ctx", where: "kv.number >= 7 || kv.number == 2" }) { id } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["storageReadAt"],
+            serde_json::json!([{ "id": 2 }, { "id": 7 }, { "id": 8 }, { "id": 9 }])
+        );
+
+        let res = query(
+            storage,
+            r#"{ storageReadAt(vars: { name: "ctx", where: "kv.number >= " }) { id } }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "INVALID_INPUT");
+        assert_eq!(err["extensions"]["field"], "where");
+        assert_eq!(err["extensions"]["position"], 13);
+    }
+
+    #[test]
+    fn test_level_inputs() {
+        devinit!();
+        let dir = TempDir::new("levels").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let mut session = storage.create_session("mixed").unwrap();
+        let levels = [
+            ("app", Level::Trace),
+            ("app", Level::Debug),
+            ("net", Level::Info),
+            ("net", Level::Warn),
+            ("db", Level::Error),
+            ("app", Level::Warn),
+        ];
+        for (category, level) in levels {
+            session.push(&devlog!(level, category, "msg")).unwrap();
+        }
+        session.flush();
+        let ids = |args: &str| {
+            let res = query(
+                storage.clone(),
+                &format!(
+                    r#"{{ storageReadAt(vars: {{ name: "mixed", {} }}) {{ id }} }}"#,
+                    args
+                ),
+            );
+            assert!(res.errors.is_empty(), "{:?}", res.errors);
+            res.data.into_json().unwrap()["storageReadAt"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x["id"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // 指定したレベル以上を返す
+        assert_eq!(ids("minLevel: WARN"), [3, 4, 5]);
+        assert_eq!(ids("minLevel: TRACE"), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(ids("levelIn: [DEBUG, ERROR]"), [1, 4]);
+        assert_eq!(ids("minLevel: INFO, levelIn: [DEBUG, WARN]"), [3, 5]);
+
+        let res = query(
+            storage.clone(),
+            r#"{ searchAll(query: "category ~ \"app\"", minLevel: DEBUG) { hits { record { id } } } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["searchAll"]["hits"],
+            serde_json::json!([{ "record": { "id": 1 } }, { "record": { "id": 5 } }])
+        );
+
+        // カテゴリの最も高いレベルで行を選ぶ
+        let res = query(
+            storage.clone(),
+            r#"{ multiSessionStats(names: ["mixed"], minLevel: WARN) { rows { category } totals { total } } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["multiSessionStats"],
+            serde_json::json!({
+                "rows": [{ "category": "app" }, { "category": "db" }, { "category": "net" }],
+                "totals": { "total": 6 },
+            })
+        );
+        let res = query(
+            storage.clone(),
+            r#"{ multiSessionStats(names: ["mixed"], levelIn: [ERROR]) { rows { category } } }"#,
+        );
+        assert_eq!(
+            res.data.into_json().unwrap()["multiSessionStats"]["rows"],
+            serde_json::json!([{ "category": "db" }])
+        );
+
+        let res = query(
+            storage,
+            r#"{ storageReadAt(vars: { name: "mixed", levelIn: [] }) { id } }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "INVALID_INPUT");
+        assert_eq!(err["extensions"]["field"], "levelIn");
+    }
+
+    #[test]
+    fn test_storage_read_at_on_error() {
+        let dir = TempDir::new("on_error").unwrap();
+        let storage = setup(&dir, 3);
+        // 3番目の次にレコードではない項目を書き足す
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("ctx").join(CBORSequenceWriter::FILENAME))
+            .unwrap();
+        serde_cbor::to_writer(&mut f, &"broken").unwrap();
+        serde_cbor::to_writer(&mut f, &devlog!(Level::Info, "cat", "msg", "number", 3)).unwrap();
+        drop(f);
+        let read = |mode: &str| {
+            let on_error = match mode {
+                "" => String::new(),
+                x => format!(", onError: {}", x),
+            };
+            query(
+                storage.clone(),
+                &format!(
+                    r#"{{ storageReadAt(vars: {{ name: "ctx"{} }}) {{ id }} }}"#,
+                    on_error
+                ),
+            )
+        };
+
+        // 既定はSKIPで、読めたレコードと飛ばしたものを返す
+        for mode in ["", "SKIP"] {
+            let res = read(mode);
+            assert_eq!(res.errors.len(), 1, "{:?}", res.errors);
+            let err = serde_json::to_value(&res.errors[0]).unwrap();
+            assert_eq!(err["extensions"]["code"], "RECORDS_SKIPPED");
+            assert_eq!(err["extensions"]["skipped"], 1);
+            assert_eq!(err["extensions"]["indices"], serde_json::json!([3]));
+            assert_eq!(err["path"], serde_json::json!(["storageReadAt"]));
+            assert_eq!(
+                res.data.into_json().unwrap()["storageReadAt"],
+                serde_json::json!([{ "id": 0 }, { "id": 1 }, { "id": 2 }, { "id": 4 }])
+            );
+        }
+
+        let res = read("STRICT");
+        assert_eq!(res.errors.len(), 1);
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "RECORD_DECODE_FAILED");
+        assert_eq!(err["extensions"]["index"], 3);
+
+        let res = read("STOP_AT");
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["storageReadAt"],
+            serde_json::json!([{ "id": 0 }, { "id": 1 }, { "id": 2 }])
+        );
+    }
+
+    /// 絞り込みと読み出し位置によらず同じレコードは同じidになる
+    #[test]
+    fn test_stable_record_ids() {
+        let dir = TempDir::new("ids").unwrap();
+        let storage = setup(&dir, 200);
+
+        let read = |vars: &str| {
+            let q = format!(
+                r#"{{ storageReadAt(vars: {{ name: "ctx", {} }}) {{ id absoluteId matchedIndex record {{ kv {{ json }} }} }} }}"#,
+                vars
+            );
+            let res = query(storage.clone(), &q);
+            assert!(res.errors.is_empty(), "{:?}", res.errors);
+            res.data.into_json().unwrap()["storageReadAt"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| {
+                    assert_eq!(x["id"], x["absoluteId"]);
+                    let kv = x["record"]["kv"]["json"].as_str().unwrap().to_string();
+                    (
+                        x["absoluteId"].as_u64().unwrap(),
+                        x["matchedIndex"].as_u64().unwrap(),
+                        kv,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let all = read("start: 100, length: 50");
+        assert_eq!(all.len(), 50);
+        for (i, (id, matched, kv)) in all.iter().enumerate() {
+            assert_eq!(*id, 100 + i as u64);
+            assert_eq!(*matched, i as u64);
+            assert!(kv.contains(&id.to_string()), "{}", kv);
+        }
+        let filtered = read(
+            r#"start: 100, length: 50, where: "kv.number == 103 || kv.number == 121 || kv.number == 149""#,
+        );
+        assert_eq!(
+            filtered.iter().map(|x| x.0).collect::<Vec<_>>(),
+            vec![103, 121, 149]
+        );
+        assert_eq!(
+            filtered.iter().map(|x| x.1).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        for (id, _, kv) in filtered.iter() {
+            assert_eq!(all[*id as usize - 100].2, *kv);
+        }
+    }
+
+    #[test]
+    fn test_invalid_inputs() {
+        let dir = TempDir::new("validation").unwrap();
+        let storage = setup(&dir, 10);
+
+        let cases = [
+            (
+                r#"{ storageReadAt(vars: { name: "ctx", length: -1 }) { id } }"#,
+                "INVALID_INPUT",
+                "length",
+            ),
+            (
+                r#"{ storageReadAt(vars: { name: "ctx", length: 10001 }) { id } }"#,
+                "INVALID_INPUT",
+                "length",
+            ),
+            (
+                r#"{ storageReadAt(vars: { name: "ctx", start: -5 }) { id } }"#,
+                "INVALID_INPUT",
+                "start",
+            ),
+            (
+                r#"{ storageReadAt(vars: { name: "" }) { id } }"#,
+                "INVALID_INPUT",
+                "name",
+            ),
+            (
+                r#"{ storageReadAt(vars: { name: "../../etc" }) { id } }"#,
+                "INVALID_INPUT",
+                "name",
+            ),
+            (
+                r#"{ storageReadAt(vars: { name: "not_found" }) { id } }"#,
+                "SESSION_NOT_FOUND",
+                "name",
+            ),
+            (
+                r#"{ context(name: "ctx", id: 5, after: 10001) { target } }"#,
+                "INVALID_INPUT",
+                "after",
+            ),
+            (
+                r#"{ context(name: "ctx", id: -1) { target } }"#,
+                "INVALID_INPUT",
+                "id",
+            ),
+            (
+                r#"{ archiveSession(name: "ctx/../..") }"#,
+                "INVALID_INPUT",
+                "name",
+            ),
+            (
+                r#"mutation { addSessionTag(name: "ctx", tag: "") { tags } }"#,
+                "INVALID_INPUT",
+                "tag",
+            ),
+            (
+                r#"mutation { setSessionNote(name: "..", note: "x") { note } }"#,
+                "INVALID_INPUT",
+                "name",
+            ),
+        ];
+        for (q, code, field) in cases {
+            let res = query(storage.clone(), q);
+            assert_eq!(res.errors.len(), 1, "{}", q);
+            let err = serde_json::to_value(&res.errors[0]).unwrap();
+            assert_eq!(err["extensions"]["code"], code, "{}", q);
+            assert_eq!(err["extensions"]["field"], field, "{}", q);
+        }
+
+        // 上限内なら読める
+        let res = query(
+            storage,
+            r#"{ storageReadAt(vars: { name: "ctx", start: 8, length: 10000 }) { id } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["storageReadAt"],
+            serde_json::json!([{ "id": 8 }, { "id": 9 }])
+        );
+    }
+
+    #[test]
+    fn test_storages_page() {
+        let dir = TempDir::new("storages").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        for i in 0..30 {
+            storage.create_session(&format!("s{:02}", i)).unwrap();
+        }
+
+        let res = query(
+            storage.clone(),
+            r#"{ storagesPage(offset: 25, limit: 10, sortBy: NAME, order: ASC) { total sessions { name size } } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let data = res.data.into_json().unwrap();
+        assert_eq!(data["storagesPage"]["total"], 30);
+        let names = data["storagesPage"]["sessions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["s25", "s26", "s27", "s28", "s29"]);
+
+        // 既定は50件
+        let res = query(
+            storage.clone(),
+            r#"{ storagesPage { total sessions { name } } }"#,
+        );
+        let data = res.data.into_json().unwrap();
+        assert_eq!(
+            data["storagesPage"]["sessions"].as_array().unwrap().len(),
+            30
+        );
+
+        // storagesは引数がなければ全てを返す
+        let res = query(
+            storage.clone(),
+            r#"{ storages(nameContains: "s2", sortBy: NAME, order: DESC, limit: 2) { name } }"#,
+        );
+        assert_eq!(
+            res.data.into_json().unwrap()["storages"],
+            serde_json::json!([{ "name": "s29" }, { "name": "s28" }])
+        );
+        let res = query(storage.clone(), r#"{ storages { name } }"#);
+        assert_eq!(
+            res.data.into_json().unwrap()["storages"]
+                .as_array()
+                .unwrap()
+                .len(),
+            30
+        );
+
+        let res = query(storage, r#"{ storagesPage(limit: 1001) { total } }"#);
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["field"], "limit");
+    }
+
+    #[test]
+    fn test_acl() {
+        use crate::acl::{Acl, Grant, Permission};
+
+        let dir = TempDir::new("acl").unwrap();
+        let storage = setup(&dir, 3);
+        for name in ["team-a-1", "team-a-2", "team-b-1"] {
+            storage.create_session(name).unwrap();
+        }
+        let acl = Acl::default()
+            .grant(
+                "a",
+                Grant {
+                    sessions: vec!["team-a-*".to_string()],
+                    permissions: vec![Permission::Read],
+                    tenant: None,
+                },
+            )
+            .grant(
+                "admin",
+                Grant {
+                    sessions: vec!["*".to_string()],
+                    permissions: vec![Permission::Read, Permission::Mutate],
+                    tenant: None,
+                },
+            );
+        let schema = build_schema(Query::new(storage.clone()), Mutation::new(storage));
+        let run = |token: Option<&str>, q: &str| {
+            let req = async_graphql::Request::new(q).data(acl.scope(token));
+            block_on(execute(&schema, req))
+        };
+        let code = |res: &async_graphql::Response| {
+            let err = serde_json::to_value(&res.errors[0]).unwrap();
+            err["extensions"]["code"].as_str().unwrap().to_string()
+        };
+
+        // 一覧は読めるセッションだけ
+        let res = run(
+            Some("a"),
+            r#"{ storagesPage(sortBy: NAME, order: ASC) { total sessions { name } } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["storagesPage"],
+            serde_json::json!({
+                "total": 2,
+                "sessions": [{ "name": "team-a-1" }, { "name": "team-a-2" }],
+            })
+        );
+        let res = run(Some("admin"), r#"{ storages { name } }"#);
+        assert_eq!(
+            res.data.into_json().unwrap()["storages"]
+                .as_array()
+                .unwrap()
+                .len(),
+            4
+        );
+
+        // 範囲外の読み出し
+        let res = run(Some("a"), r#"{ attachments(name: "team-b-1") { id } }"#);
+        assert_eq!(code(&res), "FORBIDDEN");
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["session"], "team-b-1");
+        assert_eq!(err["extensions"]["permission"], "read");
+        let res = run(Some("a"), r#"{ attachments(name: "team-a-2") { id } }"#);
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        // 名前の一部が読めるセッションに一致すればそれを返す
+        let res = run(Some("a"), r#"{ categories(name: "team-") { segment } }"#);
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+
+        // トークンがないか知らないトークン
+        for token in [None, Some("unknown")] {
+            let res = run(token, r#"{ storages { name } }"#);
+            assert_eq!(code(&res), "UNAUTHENTICATED");
+            let res = run(token, r#"{ diskStatus { readOnly } }"#);
+            assert_eq!(code(&res), "UNAUTHENTICATED");
+        }
+
+        // 変更
+        let q = r#"mutation { setSessionNote(name: "team-a-1", note: "x") { note } }"#;
+        let res = run(Some("a"), q);
+        assert_eq!(code(&res), "FORBIDDEN");
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["permission"], "mutate");
+        let res = run(Some("admin"), q);
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+
+        // ACLが空なら今まで通り
+        let res = block_on(execute(
+            &schema,
+            async_graphql::Request::new(r#"{ storages { name } }"#)
+                .data(Acl::default().scope(None)),
+        ));
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+    }
+
+    #[test]
+    fn test_acl_http() {
+        use crate::acl::{Acl, Authorize, Grant, Permission};
+        use actix_web::{http::StatusCode, test, web, App};
+
+        let dir = TempDir::new("acl_http").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        for name in ["team-a-1", "team-b-1"] {
+            storage.create_session(name).unwrap().flush();
+        }
+        let acl = Acl::default().grant(
+            "a",
+            Grant {
+                sessions: vec!["team-a-*".to_string()],
+                permissions: vec![Permission::Read, Permission::Tail],
+                tenant: None,
+            },
+        );
+
+        let mut sys = actix_web::rt::System::new("acl_http");
+        sys.block_on(async move {
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(Authorize::new(acl))
+                    .app_data(web::Data::new(storage.clone()))
+                    .service(
+                        web::resource(format!("{}/{{name}}/records", super::SESSIONS_PATH))
+                            .route(web::get().to(super::records_after)),
+                    ),
+            )
+            .await;
+            let get = |name: &str, token: Option<&str>| {
+                let mut req = test::TestRequest::get().uri(&format!(
+                    "{}/{}/records",
+                    super::SESSIONS_PATH,
+                    name
+                ));
+                if let Some(token) = token {
+                    req = req.header("Authorization", format!("Bearer {}", token));
+                }
+                req.to_request()
+            };
+
+            let res = test::call_service(&mut app, get("team-a-1", Some("a"))).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = test::call_service(&mut app, get("team-a-1", None)).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            let res = test::call_service(&mut app, get("team-b-1", Some("a"))).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let body: serde_json::Value =
+                serde_json::from_slice(&test::read_body(res).await).unwrap();
+            assert_eq!(body["code"], "FORBIDDEN");
+        });
+    }
+
+    #[test]
+    fn test_query_limits() {
+        let dir = TempDir::new("limits").unwrap();
+        let storage = setup(&dir, 10);
+        let limits = QueryLimits {
+            max_depth: 3,
+            max_read_length: 5,
+            ..Default::default()
+        };
+        let cases = [
+            (
+                r#"{ context(name: "ctx", id: 5) { record { record { message } } } }"#,
+                "QUERY_TOO_DEEP",
+            ),
+            // 件数の上限より先に複雑さで断る
+            (
+                r#"{ storageReadAt(vars: { name: "ctx", length: 100000000 }) { id } }"#,
+                "QUERY_TOO_COMPLEX",
+            ),
+            (
+                r#"{ context(name: "ctx", id: 5, after: 9223372036854775807) { target } }"#,
+                "QUERY_TOO_COMPLEX",
+            ),
+            (
+                r#"{ storageReadAt(vars: { name: "ctx", length: 6 }) { id } }"#,
+                "INVALID_INPUT",
+            ),
+        ];
+        for (q, code) in cases {
+            let res = query_with(
+                Query::new(storage.clone()).limits(limits),
+                storage.clone(),
+                q,
+            );
+            assert_eq!(res.errors.len(), 1, "{}", q);
+            let err = serde_json::to_value(&res.errors[0]).unwrap();
+            assert_eq!(err["extensions"]["code"], code, "{}", q);
+        }
+
+        let res = query_with(
+            Query::new(storage.clone()).limits(limits),
+            storage,
+            r#"{ storageReadAt(vars: { name: "ctx", length: 5 }) { id record { message } } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+    }
+
+    /// 1件ごとに待つ読み出し
+    struct SlowReader;
+
+    impl crate::StorageReader for SlowReader {
+        fn read_at(&mut self, index: usize, len: usize) -> std::io::Result<Vec<crate::LogRecord>> {
+            self.read_at_until(index, len, crate::Deadline::default())
+        }
+
+        fn read_at_until(
+            &mut self,
+            index: usize,
+            len: usize,
+            deadline: crate::Deadline,
+        ) -> std::io::Result<Vec<crate::LogRecord>> {
+            let options = crate::ReadOptions::default().deadline(deadline);
+            Ok(self.read_page(index, len, options)?.records)
+        }
+
+        fn read_page(
+            &mut self,
+            index: usize,
+            len: usize,
+            options: crate::ReadOptions,
+        ) -> std::io::Result<crate::ReadPage> {
+            let iter = (0..).map(|i: u64| {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                Ok::<_, std::io::Error>(devlog!(Level::Info, "cat", "slow", "number", i))
+            });
+            crate::reader::scan_range(iter, 0, index, len, options)
+        }
+    }
+
+    fn open_slow(_: &crate::SessionInfo) -> std::io::Result<Box<dyn crate::StorageReader>> {
+        Ok(Box::new(SlowReader))
+    }
+
+    #[test]
+    fn test_scan_timeout() {
+        use crate::reader::DEADLINE_CHECK_INTERVAL;
+
+        let dir = TempDir::new("timeout").unwrap();
+        let storage = setup(&dir, 1);
+        let limits = QueryLimits {
+            scan_timeout: Some(std::time::Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let slow = || {
+            Query::new(storage.clone())
+                .limits(limits)
+                .open_reader(open_slow)
+        };
+        let res = query_with(
+            slow(),
+            storage.clone(),
+            r#"{ storageReadAt(vars: { name: "ctx", start: 10, length: 1000 }) { id } }"#,
+        );
+        assert_eq!(res.errors.len(), 1);
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "QUERY_TIMEOUT");
+        assert_eq!(err["extensions"]["partial"], true);
+        assert_eq!(err["extensions"]["scanned"], DEADLINE_CHECK_INTERVAL as u64);
+        assert_eq!(err["path"], serde_json::json!(["storageReadAt"]));
+
+        // 打ち切った結果はキャッシュしない
+        let res = query_with(
+            slow(),
+            storage.clone(),
+            r#"{ context(name: "ctx", id: 500) { target } }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "QUERY_TIMEOUT");
+
+        // 期限内に読み終われば返す
+        let res = query_with(
+            slow(),
+            storage,
+            r#"{ storageReadAt(vars: { name: "ctx", start: 2, length: 3 }) { id } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["storageReadAt"],
+            serde_json::json!([{ "id": 2 }, { "id": 3 }, { "id": 4 }])
+        );
+    }
+
+    #[test]
+    fn test_resource_gauges() {
+        let dir = TempDir::new("gauges").unwrap();
+        let storage = setup(&dir, 3);
+        let _waiting = storage.registry().subscribe("ctx");
+        let res = query(
+            storage,
+            r#"{ resourceGauges { registry { waitingSessions waiters openSessions } queryCache { entries } } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        assert_eq!(
+            res.data.into_json().unwrap()["resourceGauges"],
+            serde_json::json!({
+                "registry": { "waitingSessions": 1, "waiters": 1, "openSessions": 0 },
+                "queryCache": { "entries": 0 },
+            })
+        );
+    }
+
+    #[test]
+    fn test_search_all() {
+        let dir = TempDir::new("search").unwrap();
+        let storage = setup(&dir, 10);
+        for name in ["run-1", "run-2"] {
+            let mut session = storage.create_session(name).unwrap();
+            for i in 0..10_u64 {
+                session
+                    .push(&devlog!(Level::Info, "cat", "msg", "number", i))
+                    .unwrap();
+            }
+            session.flush();
+        }
+        let search = |args: &str| {
+            let res = query(
+                storage.clone(),
+                &format!(
+                    "{{ searchAll({}) {{ hits {{ session record {{ id }} }} scanned partial incomplete }} }}",
+                    args
+                ),
+            );
+            assert!(res.errors.is_empty(), "{:?}", res.errors);
+            res.data.into_json().unwrap()["searchAll"].clone()
+        };
+        let hits = |result: &serde_json::Value| {
+            let mut hits = result["hits"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| {
+                    (
+                        x["session"].as_str().unwrap().to_string(),
+                        x["record"]["id"].as_u64().unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            hits.sort();
+            hits
+        };
+
+        let result = search(r#"query: "kv.number >= 8""#);
+        assert_eq!(result["scanned"], 3);
+        assert_eq!(result["partial"], false);
+        assert_eq!(result["incomplete"], serde_json::json!([]));
+        let expected = ["ctx", "run-1", "run-2"]
+            .into_iter()
+            .flat_map(|x| [(x.to_string(), 8), (x.to_string(), 9)])
+            .collect::<Vec<_>>();
+        assert_eq!(hits(&result), expected);
+
+        // 全体の件数を制限する
+        let result = search(r#"query: "kv.number >= 8", limit: 4"#);
+        assert_eq!(hits(&result).len(), 4);
+
+        let result = search(r#"query: "kv.number == 3", sessionFilter: { nameContains: "run" }"#);
+        assert_eq!(result["scanned"], 2);
+        assert_eq!(
+            hits(&result),
+            vec![("run-1".to_string(), 3), ("run-2".to_string(), 3)]
+        );
+        let result =
+            search(r#"query: "kv.number == 3", sessionFilter: { since: "2999-01-01T00:00:00Z" }"#);
+        assert_eq!(result["scanned"], 0);
+
+        let res = query(
+            storage,
+            r#"{ searchAll(query: "kv.number >=") { scanned } }"#,
+        );
+        let err = serde_json::to_value(&res.errors[0]).unwrap();
+        assert_eq!(err["extensions"]["code"], "INVALID_INPUT");
+        assert_eq!(err["extensions"]["field"], "query");
+    }
+
+    #[test]
+    fn test_search_all_timeout() {
+        let dir = TempDir::new("search").unwrap();
+        let storage = setup(&dir, 1);
+        for name in ["run-1", "run-2"] {
+            storage.create_session(name).unwrap().flush();
+        }
+        let limits = QueryLimits {
+            scan_timeout: Some(std::time::Duration::from_millis(20)),
+            scan_threads: 2,
+            ..Default::default()
+        };
+        let res = query_with(
+            Query::new(storage.clone())
+                .limits(limits)
+                .open_reader(open_slow),
+            storage,
+            r#"{ searchAll(query: "kv.number < 3") { hits { session } scanned partial incomplete } }"#,
+        );
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let result = res.data.into_json().unwrap()["searchAll"].clone();
+        // 期限で打ち切ったセッションと読み始めなかったセッションを返す
+        assert_eq!(result["partial"], true);
+        assert_eq!(result["scanned"], 0);
+        let mut incomplete = result["incomplete"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x.as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        incomplete.sort();
+        assert_eq!(incomplete, vec!["ctx", "run-1", "run-2"]);
+    }
+
+    #[test]
+    fn test_records_after() {
+        use actix_web::{test, web, App};
+        use std::time::{Duration, Instant};
+        use uplog::Record;
+
+        devinit!();
+        let dir = TempDir::new("sync").unwrap();
+        let storage = Storage::new(dir.path()).unwrap();
+        let mut session = storage.create_session("sync").unwrap();
+        for i in 0..5_u64 {
+            session
+                .push(&devlog!(Level::Info, "cat", "msg", "number", i))
+                .unwrap();
+        }
+        session.flush();
+
+        let mut sys = actix_web::rt::System::new("sync");
+        sys.block_on(async move {
+            let mut app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(storage.clone()))
+                    .service(
+                        web::resource(format!("{}/{{name}}/records", super::SESSIONS_PATH))
+                            .route(web::get().to(super::records_after)),
+                    ),
+            )
+            .await;
+            let uri = |query: &str| format!("{}/sync/records?{}", super::SESSIONS_PATH, query);
+            let cursor = |res: &actix_web::dev::ServiceResponse| {
+                res.headers()
+                    .get(super::NEXT_CURSOR_HEADER)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            };
+            let numbers = |body: &[u8]| {
+                serde_cbor::Deserializer::from_slice(body)
+                    .into_iter::<Record>()
+                    .map(|x| x.unwrap().kv.unwrap()["number"].as_u64().unwrap())
+                    .collect::<Vec<_>>()
+            };
+
+            // 続きから読む
+            let req = test::TestRequest::get().uri(&uri("limit=3")).to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+            let next = cursor(&res);
+            assert_eq!(numbers(&test::read_body(res).await), [0, 1, 2]);
+
+            let req = test::TestRequest::get()
+                .uri(&uri(&format!("after={}&limit=3", next)))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            let next = cursor(&res);
+            assert_eq!(numbers(&test::read_body(res).await), [3, 4]);
+
+            // レベルで絞っても読んだところまでカーソルを進める
+            let req = test::TestRequest::get()
+                .uri(&uri("limit=5&min_level=warn"))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(cursor(&res), next);
+            assert!(test::read_body(res).await.is_empty());
+            let req = test::TestRequest::get()
+                .uri(&uri("level_in=info,fatal"))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+            // 新しいレコードがなければ同じカーソルを返す
+            let req = test::TestRequest::get()
+                .uri(&uri(&format!("after={}", next)))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(cursor(&res), next);
+            assert!(test::read_body(res).await.is_empty());
+
+            // 追記された分だけ読む
+            for i in 5..7_u64 {
+                session
+                    .push(&devlog!(Level::Info, "cat", "msg", "number", i))
+                    .unwrap();
+            }
+            session.flush();
+            let req = test::TestRequest::get()
+                .uri(&uri(&format!("after={}", next)))
+                .header("Accept", "application/json")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            let next = cursor(&res);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            let ids = body
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x["id"].as_u64().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(ids, [5, 6]);
+
+            // 待っている間に書かれたら起きる
+            let req = test::TestRequest::get()
+                .uri(&uri(&format!("after={}&wait_ms=5000", next)))
+                .to_request();
+            let start = Instant::now();
+            let (res, _) = futures::join!(test::call_service(&mut app, req), async {
+                actix_web::rt::time::delay_for(Duration::from_millis(100)).await;
+                session
+                    .push(&devlog!(Level::Info, "cat", "msg", "number", 7_u64))
+                    .unwrap();
+                session.flush_if_watched();
+            });
+            assert!(start.elapsed() < Duration::from_secs(5));
+            let next = cursor(&res);
+            assert_eq!(numbers(&test::read_body(res).await), [7]);
+
+            // 書かれなければ待ち時間で空を返す
+            let req = test::TestRequest::get()
+                .uri(&uri(&format!("after={}&wait_ms=50", next)))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(cursor(&res), next);
+            assert!(test::read_body(res).await.is_empty());
+
+            for (query, status) in [("after=zz", 400), ("limit=0", 400), ("after=0-ffffff", 400)] {
+                let req = test::TestRequest::get().uri(&uri(query)).to_request();
+                let res = test::call_service(&mut app, req).await;
+                assert_eq!(res.status().as_u16(), status, "{}", query);
+            }
+            let req = test::TestRequest::get()
+                .uri(&format!("{}/missing/records", super::SESSIONS_PATH))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status().as_u16(), 404);
+        });
+    }
+
+    #[test]
+    fn test_schema_and_version() {
+        use actix_web::{test, web, App};
+
+        let dir = TempDir::new("version").unwrap();
+        let storage = setup(&dir, 1);
+        let schema = Schema::build(
+            Query::new(storage.clone()),
+            Mutation::new(storage),
+            EmptySubscription,
+        )
+        .finish();
+        let mut sys = actix_web::rt::System::new("version");
+        sys.block_on(async move {
+            let mut app = test::init_service(
+                App::new()
+                    .data(schema)
+                    .service(
+                        web::resource(super::VERSION_PATH).route(web::get().to(super::version)),
+                    )
+                    .service(
+                        web::resource(super::SCHEMA_PATH).route(web::get().to(super::schema_sdl)),
+                    ),
+            )
+            .await;
+
+            let req = test::TestRequest::get()
+                .uri(super::SCHEMA_PATH)
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert!(res.status().is_success());
+            let sdl = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+            assert!(sdl.contains("storageReadAt"), "{}", sdl);
+
+            let req = test::TestRequest::get()
+                .uri(super::VERSION_PATH)
+                .to_request();
+            let version: super::VersionInfo = test::read_response_json(&mut app, req).await;
+            assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+            assert_eq!(
+                version.storage_format_version,
+                crate::STORAGE_FORMAT_VERSION
+            );
+            assert_eq!(
+                version.subprotocols,
+                vec![
+                    uplog::protocol::SUBPROTOCOL_CBOR_DEFLATE,
+                    uplog::protocol::SUBPROTOCOL_CBOR_DICT,
+                    uplog::protocol::SUBPROTOCOL_CBOR
+                ]
+            );
+        });
+    }
 }
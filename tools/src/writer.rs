@@ -1,36 +1,137 @@
-use std::{fs::OpenOptions, io::BufWriter, path::Path};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+};
 
 use uplog::Record;
 
-pub(crate) trait RecordWriter {
+use crate::overview::{OverviewBuilder, OVERVIEW_FILENAME};
+
+/// Destination of received records, implemented by [`crate::Session`].
+pub trait RecordWriter {
     fn push(&mut self, record: &Record) -> Result<(), std::io::Error>;
+    /// バッファしている分を書き出す
     fn flush(&mut self) {}
 }
 
+/// 読み出し側が書き込み中のファイルを開けるように共有を許す
+///
+/// Windowsの標準の共有モードも読み書きと削除を許しているが、前提にしているので明示しておく
+pub(crate) fn share_options(options: &mut OpenOptions) -> &mut OpenOptions {
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE
+        options.share_mode(0x1 | 0x2 | 0x4);
+    }
+    options
+}
+
+/// 書き込み中かもしれないファイルを読み出し用に開く
+pub(crate) fn open_shared_read<P: AsRef<Path>>(path: P) -> Result<File, std::io::Error> {
+    share_options(OpenOptions::new().read(true)).open(path)
+}
+
+/// 書き込み用に開く。既にあれば切り詰める
+fn create_shared<P: AsRef<Path>>(path: P) -> Result<File, std::io::Error> {
+    share_options(OpenOptions::new().create(true).write(true).truncate(true)).open(path)
+}
+
 /// CBORシーケンスライターはデータをただ直接に書き出す
 pub(crate) struct CBORSequenceWriter {
     writer: Box<dyn std::io::Write>,
+    /// 暗号化したセッションではデータと同じ鍵で暗号化する
+    index: Box<dyn std::io::Write>,
+    /// 書き込み済みのレコード数
+    count: usize,
+    /// 書き込み済みのバイト数
+    offset: u64,
+    /// 概要の書き込み先。暗号化したセッションではレベルごとの数を平文で残さないように書かない
+    overview: Option<(File, OverviewBuilder)>,
 }
 
 impl CBORSequenceWriter {
     #[allow(dead_code)]
     pub(crate) const FILENAME: &'static str = "seqdata";
+    /// レコード番号とファイル上のオフセットの対応を保存する
+    pub(crate) const INDEX_FILENAME: &'static str = "index";
+    /// indexを書き込むレコード間隔
+    pub(crate) const INDEX_INTERVAL: usize = 64;
 
     #[allow(dead_code)]
     pub(crate) fn new<P: AsRef<Path>>(dirpath: P) -> Result<Self, std::io::Error> {
-        let f = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(dirpath.as_ref().join(Self::FILENAME))?;
+        let f = create_shared(dirpath.as_ref().join(Self::FILENAME))?;
+        let index = create_shared(dirpath.as_ref().join(Self::INDEX_FILENAME))?;
+        let overview = create_shared(dirpath.as_ref().join(OVERVIEW_FILENAME))?;
         let writer = Box::new(BufWriter::new(f));
-        Ok(Self { writer })
+        Ok(Self {
+            writer,
+            index: Box::new(index),
+            count: 0,
+            offset: 0,
+            overview: Some((overview, OverviewBuilder::default())),
+        })
+    }
+
+    /// 暗号化して書く。セッションごとのsaltは付加情報に残す
+    #[cfg(feature = "encryption")]
+    pub(crate) fn new_encrypted<P: AsRef<Path>>(
+        dirpath: P,
+        key: &crate::crypt::EncryptionKey,
+    ) -> Result<Self, std::io::Error> {
+        let info = crate::crypt::new_session(key)?;
+        crate::SessionMeta::update(dirpath.as_ref(), |meta| {
+            meta.encryption = Some(info.clone())
+        })?;
+        let f = create_shared(dirpath.as_ref().join(Self::FILENAME))?;
+        let index = create_shared(dirpath.as_ref().join(Self::INDEX_FILENAME))?;
+        let writer = Box::new(crate::crypt::EncryptingWriter::new(
+            BufWriter::new(f),
+            &info,
+            key,
+        )?);
+        let index = Box::new(crate::crypt::EncryptingWriter::new(index, &info, key)?);
+        Ok(Self {
+            writer,
+            index,
+            count: 0,
+            offset: 0,
+            overview: None,
+        })
+    }
+
+    fn push_index(&mut self) -> Result<(), std::io::Error> {
+        let mut entry = [0_u8; 16];
+        entry[..8].copy_from_slice(&(self.count as u64).to_le_bytes());
+        entry[8..].copy_from_slice(&self.offset.to_le_bytes());
+        self.index.write_all(&entry)
     }
 }
 
 impl RecordWriter for CBORSequenceWriter {
     fn push(&mut self, record: &Record) -> Result<(), std::io::Error> {
         use std::io::{Error, ErrorKind};
-        serde_cbor::to_writer(&mut self.writer, record)
-            .map_err(|e| Error::new(ErrorKind::BrokenPipe, format!("write error {}", e)))
+        if self.count.is_multiple_of(Self::INDEX_INTERVAL) {
+            self.push_index()?;
+        }
+        let buf = serde_cbor::to_vec(record)
+            .map_err(|e| Error::new(ErrorKind::BrokenPipe, format!("write error {}", e)))?;
+        if let Some((file, builder)) = self.overview.as_mut() {
+            if let Some(entry) = builder.push(record, buf.len() as u64) {
+                serde_cbor::to_writer(file, &entry)
+                    .map_err(|e| Error::new(ErrorKind::BrokenPipe, format!("write error {}", e)))?;
+            }
+        }
+        self.writer.write_all(&buf)?;
+        self.count += 1;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        self.writer.flush().ok();
+        // 暗号化した索引はflushでフレームを閉じるまで読めない
+        self.index.flush().ok();
     }
 }
@@ -0,0 +1,40 @@
+//! `web`機能を外しても解析用のAPIだけでデータファイルを読んで集計できることを確認する
+//!
+//! `cargo test -p uplog-tools --no-default-features`でも動かす
+use tempdir::TempDir;
+use uplog::{devinit, devlog, Level};
+use uplog_tools::{
+    analysis::{self, filter::Filter, stats::SessionStats},
+    RecordWriter, Storage,
+};
+
+#[test]
+fn test_analysis_api() -> std::io::Result<()> {
+    devinit!();
+    let dir = TempDir::new("analysis")?;
+    let storage = Storage::new_shared(dir.path())?;
+    let mut session = storage.create_session("run")?;
+    for (category, level) in [
+        ("net.rx", Level::Info),
+        ("net.rx", Level::Error),
+        ("app", Level::Warn),
+    ] {
+        session.push(&devlog!(level, category, "msg"))?;
+    }
+    drop(session);
+
+    let session_dir = dir.path().join("run");
+    let filter: Filter = "level >= warn".parse().unwrap();
+    let matched = analysis::open(&session_dir)?
+        .filter(|x| x.as_ref().map_or(true, |x| filter.matches(x)))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(matched.len(), 2);
+
+    let stats = SessionStats::collect(&session_dir)?;
+    assert_eq!((stats.records, stats.errors), (3, 1));
+    assert_eq!(
+        stats.tree.iter().map(|x| x.count).collect::<Vec<_>>(),
+        [1, 2]
+    );
+    Ok(())
+}
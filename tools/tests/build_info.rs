@@ -0,0 +1,72 @@
+//! クライアントのビルド情報がセッションの付加情報に残り、GraphQLで読めることを確認する
+#![cfg(feature = "web")]
+use std::time::Duration;
+
+use futures::executor::block_on;
+use tempdir::TempDir;
+use uplog::Value;
+use uplog_tools::{
+    lifecycle::is_server_record,
+    server::{run_server, ServerOptions},
+    testing::wait_for,
+    webapi::{build_schema, execute, Mutation, Query},
+};
+
+#[test]
+fn test_build_info() {
+    let dir = TempDir::new("build_info").unwrap();
+    let server = run_server(ServerOptions::new(dir.path())).unwrap();
+    let storage = server.storage().clone();
+
+    let info = uplog::build_info!();
+    uplog::Builder::default()
+        .host("127.0.0.1")
+        .port(server.port())
+        .duration(Duration::from_millis(20))
+        .with_build_info(info.clone())
+        .try_init()
+        .unwrap();
+    uplog::info!("build_info.test", "send");
+    uplog::flush();
+
+    let (name, records) = wait_for(|| {
+        let name = storage.records().ok()?.first()?.name();
+        let records = storage
+            .session_records(&name)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter(|x| !is_server_record(x) && x.category != uplog::CLIENT_CATEGORY)
+            .collect::<Vec<_>>();
+        Some((name, records)).filter(|(_, x)| x.len() == 2)
+    });
+    // 最初のレコードにも同じ値を書いている
+    assert_eq!(records[0].category, uplog::BUILD_CATEGORY);
+    assert_eq!(records[0].kv.as_ref(), Some(&info));
+    assert_eq!(records[1].category, "build_info.test");
+
+    let schema = build_schema(Query::new(storage.clone()), Mutation::new(storage));
+    let res = block_on(execute(
+        &schema,
+        "{ storages { name buildInfo { name version gitHash profile target } } }",
+    ));
+    assert!(res.errors.is_empty(), "{:?}", res.errors);
+    let data = res.data.into_json().unwrap();
+    let session = &data["storages"][0];
+    assert_eq!(session["name"], serde_json::json!(name));
+    let text = |key: &str| match info.get(key) {
+        Some(Value::Text(x)) => serde_json::json!(x),
+        _ => serde_json::Value::Null,
+    };
+    assert_eq!(
+        session["buildInfo"],
+        serde_json::json!({
+            "name": "uplog-tools",
+            "version": env!("CARGO_PKG_VERSION"),
+            "gitHash": text("git_hash"),
+            "profile": text("profile"),
+            "target": text("target"),
+        })
+    );
+    assert!(session["buildInfo"]["target"].is_string());
+    server.stop(false).unwrap();
+}
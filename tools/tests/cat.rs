@@ -0,0 +1,95 @@
+//! `cat`サブコマンドをパイプにつないで動かす
+#![cfg(feature = "web")]
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use tempdir::TempDir;
+use uplog::{devlog, Level, Record};
+use uplog_tools::{RecordWriter, Storage};
+
+fn records(n: u64) -> Vec<Record> {
+    (0..n)
+        .map(|i| {
+            let mut r = devlog!(Level::Info, "cat.test", "message", "i", i);
+            r.elapsed = Duration::from_millis(i);
+            r
+        })
+        .collect()
+}
+
+fn command() -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_main"));
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    cmd
+}
+
+/// 読み手が途中で閉じても異常終了しない
+#[test]
+fn test_cat_closed_reader() {
+    let dir = TempDir::new("cat").unwrap();
+    let storage = Storage::new(dir.path()).unwrap();
+    {
+        let mut session = storage.create_session("big").unwrap();
+        for r in records(50_000) {
+            session.push(&r).unwrap();
+        }
+    }
+
+    let mut child = command()
+        .args(["cat", "--session", "big", "--data-dir"])
+        .arg(dir.path())
+        .spawn()
+        .unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    let first = serde_json::from_str::<serde_json::Value>(&line).unwrap();
+    assert_eq!(first["kv"]["i"], serde_json::json!(0));
+    drop(stdout);
+
+    let status = child.wait().unwrap();
+    let mut stderr = String::new();
+    child.stderr.unwrap().read_to_string(&mut stderr).unwrap();
+    assert!(status.success(), "{:?} {}", status, stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+/// `--session -`で標準入力のCBOR Sequenceを読む
+#[test]
+fn test_cat_stdin() {
+    let mut buf = Vec::new();
+    for r in records(5) {
+        serde_cbor::to_writer(&mut buf, &r).unwrap();
+    }
+    let run = |input: Vec<u8>, args: &[&str]| {
+        let mut child = command()
+            .args(["cat", "--session", "-", "--format", "csv"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&input).unwrap();
+        let out = child.wait_with_output().unwrap();
+        (out.status, String::from_utf8(out.stdout).unwrap())
+    };
+
+    let (status, out) = run(buf.clone(), &["--where", "kv.i >= 3"]);
+    assert!(status.success());
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], uplog_tools::cat::CSV_HEADER);
+    assert!(lines[1].starts_with("0.003000000,Info,cat.test,message,"));
+    assert!(lines[2].ends_with(",\"{\"\"i\"\":4}\""), "{}", lines[2]);
+
+    // 途中で切れたデータは厳密に読む場合だけ失敗にする
+    buf.truncate(buf.len() - 3);
+    let (status, out) = run(buf.clone(), &["--strict"]);
+    assert!(!status.success());
+    assert_eq!(out.lines().count(), 1 + 4);
+    let (status, out) = run(buf, &[]);
+    assert!(status.success());
+    assert_eq!(out.lines().count(), 1 + 4);
+}
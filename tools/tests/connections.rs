@@ -0,0 +1,108 @@
+//! 接続中のクライアントの一覧と、GraphQLから接続を閉じられることを確認する
+#![cfg(feature = "web")]
+use futures::executor::block_on;
+use tempdir::TempDir;
+use tungstenite::{
+    client::AutoStream, connect, protocol::frame::coding::CloseCode, Message, WebSocket,
+};
+use uplog::{devlog, Level};
+use uplog_tools::{
+    server::{run_server, ServerOptions},
+    testing::wait_for,
+    webapi::{build_schema, execute, ApiSchema, Mutation, Query},
+};
+
+type Socket = WebSocket<AutoStream>;
+
+/// 接続して1件送る
+fn open(port: u16, message: &str) -> Socket {
+    let (mut socket, _) =
+        connect(format!("ws://127.0.0.1:{}{}", port, uplog::INGEST_PATH)).unwrap();
+    let record = devlog!(Level::Info, "connections.test", message);
+    socket
+        .write_message(Message::binary(serde_cbor::to_vec(&record).unwrap()))
+        .unwrap();
+    socket
+}
+
+fn live_connections(schema: &ApiSchema) -> serde_json::Value {
+    let res = block_on(execute(
+        schema,
+        "{ liveConnections { uuid clientIp session frames bytes lastActivityAt } }",
+    ));
+    assert!(res.errors.is_empty(), "{:?}", res.errors);
+    serde_json::to_value(&res.data).unwrap()["liveConnections"].clone()
+}
+
+/// 一覧が条件を満たすまで待つ
+fn wait_list<F: Fn(&[serde_json::Value]) -> bool>(
+    schema: &ApiSchema,
+    f: F,
+) -> Vec<serde_json::Value> {
+    wait_for(|| {
+        let list = live_connections(schema).as_array().cloned().unwrap();
+        f(&list).then_some(list)
+    })
+}
+
+#[test]
+fn test_live_connections() {
+    let dir = TempDir::new("connections").unwrap();
+    let server = run_server(ServerOptions::new(dir.path())).unwrap();
+    let storage = server.storage().clone();
+    let storage_addr = server.storage_actor().clone();
+    let schema = build_schema(
+        Query::new(storage.clone()).connections(storage_addr.clone().recipient()),
+        Mutation::new(storage).connections(storage_addr.recipient()),
+    );
+
+    let mut first = open(server.port(), "first");
+    let second = open(server.port(), "second");
+    let list = wait_list(&schema, |list| {
+        list.len() == 2
+            && list
+                .iter()
+                .all(|x| x["frames"] == 1 && x["session"].is_string())
+    });
+    assert!(list
+        .iter()
+        .all(|x| x["clientIp"].as_str().unwrap().starts_with("127.0.0.1")));
+    assert!(list
+        .iter()
+        .all(|x| x["bytes"].as_u64().unwrap() > 0 && x["lastActivityAt"].is_string()));
+    assert_ne!(list[0]["session"], list[1]["session"]);
+
+    // 先に接続した方を閉じる
+    let uuid = list[0]["uuid"].as_str().unwrap().to_string();
+    let res = block_on(execute(
+        &schema,
+        &format!(r#"mutation {{ disconnect(uuid: "{}") }}"#, uuid),
+    ));
+    assert!(res.errors.is_empty(), "{:?}", res.errors);
+    let code = loop {
+        match first.read_message() {
+            Ok(Message::Close(frame)) => break frame.map(|x| x.code),
+            Ok(_) => continue,
+            Err(e) => panic!("{}", e),
+        }
+    };
+    assert_eq!(code, Some(CloseCode::Library(4107)));
+    let list = wait_list(&schema, |list| list.len() == 1);
+    assert_ne!(list[0]["uuid"], uuid.as_str());
+
+    // 閉じた接続はもう見つからない
+    let res = block_on(execute(
+        &schema,
+        &format!(r#"mutation {{ disconnect(uuid: "{}") }}"#, uuid),
+    ));
+    let err = serde_json::to_value(&res.errors[0]).unwrap();
+    assert_eq!(err["extensions"]["code"], "CONNECTION_NOT_FOUND");
+    let res = block_on(execute(&schema, r#"mutation { disconnect(uuid: "x") }"#));
+    let err = serde_json::to_value(&res.errors[0]).unwrap();
+    assert_eq!(err["extensions"]["code"], "INVALID_INPUT");
+
+    // 閉じる手順なしに切れた接続も一覧から消える
+    drop(second);
+    wait_list(&schema, |list| list.is_empty());
+    server.stop(false).unwrap();
+}
@@ -0,0 +1,171 @@
+//! 本物のクライアントとサーバーを1つのプロセスで動かし、送ったレコードがそのまま保存されることを確認する
+#![cfg(feature = "web")]
+use std::time::Duration;
+
+use futures::executor::block_on;
+use tempdir::TempDir;
+use uplog::{Growth, KVBorrow, Level, Value, ValueBorrow, KV};
+use uplog_tools::{
+    server::{run_server, ServerOptions},
+    webapi::{build_schema, execute, Mutation, Query},
+    Storage,
+};
+
+const BUFFER_SIZE: usize = 16 * 1024;
+const BURST: u64 = 2000;
+
+/// 送るレコード
+struct Step {
+    level: Level,
+    category: &'static str,
+    message: String,
+    kv: Option<KV>,
+}
+
+impl Step {
+    fn new(level: Level, category: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            level,
+            category,
+            message: message.into(),
+            kv: None,
+        }
+    }
+
+    fn kv(mut self, entries: Vec<(&str, Value)>) -> Self {
+        self.kv = Some(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        );
+        self
+    }
+
+    fn log(&self) {
+        let kv = self.kv.as_ref().map(|kv| {
+            kv.iter()
+                .map(|(k, v)| (k.as_str(), ValueBorrow::from(v)))
+                .collect::<KVBorrow>()
+        });
+        let outcome = uplog::log!(self.level, self.category, &self.message, kv);
+        assert!(outcome.is_accepted(), "{:?}", outcome);
+    }
+}
+
+fn script() -> Vec<Step> {
+    let mut steps = vec![
+        Step::new(Level::Trace, "e2e.level", "trace"),
+        Step::new(Level::Debug, "e2e.level", "debug"),
+        Step::new(Level::Info, "e2e.level", "info"),
+        Step::new(Level::Warn, "e2e.level", "warn"),
+        Step::new(Level::Error, "e2e.level", "error"),
+        Step::new(Level::Info, "e2e.kv", "types").kv(vec![
+            ("u64", Value::U64(u64::MAX)),
+            ("i64", Value::I64(-3)),
+            ("f32", Value::F32(0.5)),
+            // f32で表せない値
+            ("f64", Value::F64(0.1)),
+            ("bool", Value::Bool(true)),
+            ("text", Value::Text("ねこ".to_string())),
+            ("null", Value::Null),
+            (
+                "array",
+                Value::Array(vec![Value::U64(1), Value::Text("a".into())]),
+            ),
+            (
+                "map",
+                Value::Map(
+                    [("k".to_string(), Value::Bool(false))]
+                        .into_iter()
+                        .collect(),
+                ),
+            ),
+        ]),
+        // 送信バッファより大きい値
+        Step::new(Level::Info, "e2e.bytes", "large").kv(vec![(
+            "bytes",
+            Value::Bytes((0..BUFFER_SIZE * 20).map(|x| x as u8).collect()),
+        )]),
+    ];
+    // 1回の入れ替えに収まらない量を続けて書く
+    steps.extend(
+        (0..BURST)
+            .map(|i| Step::new(Level::Info, "e2e.burst", "burst").kv(vec![("i", Value::U64(i))])),
+    );
+    steps.push(Step::new(Level::Info, "e2e.level", "last"));
+    steps
+}
+
+#[test]
+fn test_client_and_server() {
+    let dir = TempDir::new("e2e").unwrap();
+    let server = run_server(ServerOptions::new(dir.path())).unwrap();
+
+    uplog::Builder::default()
+        .host("127.0.0.1")
+        .port(server.port())
+        .duration(Duration::from_millis(20))
+        .buffer_size(BUFFER_SIZE)
+        .buffer_growth(Growth::Doubling {
+            max: 4 * 1024 * 1024,
+        })
+        .try_init()
+        .unwrap();
+    let script = script();
+    for step in script.iter() {
+        step.log();
+    }
+    let report = uplog::flush().unwrap();
+    assert!(report.transport_ok, "{}", report);
+    server.stop(true).unwrap();
+
+    // 止めたサーバーの保存先を開き直す
+    let storage = Storage::new(dir.path()).unwrap();
+    let sessions = storage.records().unwrap();
+    assert_eq!(sessions.len(), 1);
+    let name = sessions[0].name();
+    assert!(sessions[0].path().join("seqdata").metadata().unwrap().len() > 0);
+    let records = storage
+        .session_records(&name)
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap()
+        .into_iter()
+        .filter(|x| x.category.starts_with("e2e."))
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), script.len());
+    for (record, step) in records.iter().zip(script.iter()) {
+        assert_eq!(record.level(), step.level);
+        assert_eq!(record.category, step.category);
+        assert_eq!(record.message, step.message);
+        assert_eq!(record.kv, step.kv, "{}", step.message);
+        assert_eq!(record.target(), module_path!());
+    }
+    assert!(records.windows(2).all(|x| x[0].elapsed <= x[1].elapsed));
+
+    let schema = build_schema(Query::new(storage.clone()), Mutation::new(storage));
+    let query = format!(
+        r#"{{ storageReadAt(vars: {{ name: "{}", category: "e2e.**", length: {} }}) {{
+            record {{ level category message kv {{ json }} }} }} }}"#,
+        name,
+        // 長さはサーバーとクライアントが書くレコードも数える
+        script.len() + 100
+    );
+    let res = block_on(execute(&schema, &query));
+    assert!(res.errors.is_empty(), "{:?}", res.errors);
+    let data = serde_json::to_value(&res.data).unwrap();
+    let read = data["storageReadAt"].as_array().unwrap();
+    assert_eq!(read.len(), script.len());
+    for (x, step) in read.iter().zip(script.iter()) {
+        let record = &x["record"];
+        assert_eq!(
+            record["level"],
+            format!("{:?}", step.level).to_uppercase().as_str()
+        );
+        assert_eq!(record["category"], step.category);
+        assert_eq!(record["message"], step.message.as_str());
+        let kv = step.kv.as_ref().map(|x| serde_json::to_string(x).unwrap());
+        assert_eq!(record["kv"]["json"].as_str(), kv.as_deref());
+    }
+}
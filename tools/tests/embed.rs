@@ -0,0 +1,60 @@
+//! actixとGraphQLを使わずに公開APIだけで保存と読み出しができることを確認する
+use tempdir::TempDir;
+use uplog::{devinit, devlog, Level};
+use uplog_tools::{
+    open_reader, Filter, LogRecord, OnError, QueryCache, ReadOptions, RecordWriter, SessionInfo,
+    Storage, StorageReader,
+};
+
+#[test]
+fn test_embedding_api() -> std::io::Result<()> {
+    devinit!();
+    let dir = TempDir::new("embed")?;
+    let storage = Storage::new(dir.path())?;
+    for name in ["first", "second"] {
+        let mut session = storage.create_session(name)?;
+        for i in 0..30_u32 {
+            session.push(&devlog!(Level::Info, name, "msg", "i", i))?;
+        }
+    }
+
+    let mut sessions: Vec<SessionInfo> = storage.records()?;
+    sessions.sort_by_key(|x| x.name());
+    assert_eq!(
+        sessions.iter().map(|x| x.name()).collect::<Vec<_>>(),
+        vec!["first", "second"]
+    );
+    let info = &sessions[1];
+    assert!(info.updated_at() >= info.created_at());
+    assert!(info.meta().tags.is_empty());
+
+    // 1ページ分を読む
+    let page: Vec<LogRecord> = open_reader(info)?.read_at(10, 5)?;
+    assert_eq!(
+        page.iter().map(|x| x.index()).collect::<Vec<_>>(),
+        (10..15).collect::<Vec<_>>()
+    );
+    assert_eq!(page[0].as_record().category, "second");
+
+    // 検索とキャッシュ
+    let filter = Filter::parse("kv.i >= 25").unwrap();
+    let cache = QueryCache::new(1024 * 1024);
+    for _ in 0..2 {
+        let page = cache.read_at(info.path(), 0, 30, OnError::Skip, || {
+            open_reader(info)?.read_page(0, 30, ReadOptions::default())
+        })?;
+        let found = page
+            .records
+            .iter()
+            .filter(|x| filter.matches(x.as_record()))
+            .count();
+        assert_eq!(found, 5);
+    }
+    assert_eq!(cache.stats().hits, 1);
+
+    let all = storage
+        .session_records(&info.name())?
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(all.len(), 30);
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! クライアントの端末に残した履歴の範囲を、GraphQLから頼んで別のセッションとして受け取れることを確認する
+#![cfg(feature = "web")]
+use std::{thread, time::Duration};
+
+use futures::executor::block_on;
+use tempdir::TempDir;
+use uplog::{Record, RingFileSink, Value};
+use uplog_tools::{
+    server::{run_server, ServerOptions},
+    testing::wait_for,
+    webapi::{build_schema, execute, Mutation, Query},
+    Storage,
+};
+
+/// セッションの試験のレコード
+fn test_records(storage: &Storage, name: &str) -> Vec<Record> {
+    storage
+        .session_records(name)
+        .map(|x| {
+            x.filter_map(Result::ok)
+                .filter(|x| x.category == "history.test")
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_upload_history() {
+    let dir = TempDir::new("history").unwrap();
+    let server = run_server(ServerOptions::new(dir.path().join("storage"))).unwrap();
+    let storage = server.storage().clone();
+
+    let history = RingFileSink::open(dir.path().join("history.ring"), 64 * 1024).unwrap();
+    uplog::Builder::default()
+        .host("127.0.0.1")
+        .port(server.port())
+        .duration(Duration::from_millis(20))
+        .history(history)
+        .try_init()
+        .unwrap();
+    // 範囲の境目の前後は間をあける
+    let elapsed = || {
+        thread::sleep(Duration::from_millis(50));
+        let elapsed = (chrono::Utc::now() - uplog::start_at()).to_std().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        elapsed.as_secs_f64()
+    };
+    let mut window = Vec::new();
+    for i in 0..10_u32 {
+        if i == 5 || i == 9 {
+            window.push(elapsed());
+        }
+        uplog::info!("history.test", "record", "i", i);
+    }
+    let name = wait_for(|| Some(storage.records().ok()?.first()?.name()));
+
+    let schema = build_schema(
+        Query::new(storage.clone()),
+        Mutation::new(storage.clone()).control(server.storage_actor().clone().recipient()),
+    );
+    let query = format!(
+        r#"mutation {{ requestClientHistory(session: "{}", fromElapsed: {}, toElapsed: {}) }}"#,
+        name, window[0], window[1]
+    );
+    let res = block_on(execute(&schema, &query));
+    assert!(res.errors.is_empty(), "{:?}", res.errors);
+
+    // 元のセッションとは別のセッションになる
+    let uploaded = wait_for(|| {
+        let other = storage
+            .records()
+            .ok()?
+            .into_iter()
+            .map(|x| x.name())
+            .find(|x| *x != name)?;
+        let records = test_records(&storage, &other);
+        (records.len() == 4).then_some(records)
+    });
+    let indexes = uploaded
+        .iter()
+        .map(|x| x.kv.as_ref().unwrap()["i"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(indexes, (5..9).map(Value::U64).collect::<Vec<_>>());
+
+    // 範囲が正しくなければ送らない
+    let query = format!(
+        r#"mutation {{ requestClientHistory(session: "{}", fromElapsed: -1.0) }}"#,
+        name
+    );
+    let res = block_on(execute(&schema, &query));
+    let err = serde_json::to_value(&res.errors[0]).unwrap();
+    assert_eq!(err["extensions"]["code"], "INVALID_RANGE");
+    uplog::flush();
+    server.stop(false).unwrap();
+}
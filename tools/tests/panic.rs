@@ -0,0 +1,56 @@
+//! panicがバックトレース付きのErrorのレコードとしてサーバーに届くことを確認する
+#![cfg(feature = "web")]
+use std::{thread, time::Duration};
+
+use tempdir::TempDir;
+use uplog_tools::{
+    lifecycle::is_server_record,
+    server::{run_server, ServerOptions},
+    testing::wait_for,
+};
+
+#[test]
+fn test_capture_panics() {
+    let dir = TempDir::new("panic").unwrap();
+    let server = run_server(ServerOptions::new(dir.path())).unwrap();
+    let storage = server.storage().clone();
+
+    uplog::Builder::default()
+        .host("127.0.0.1")
+        .port(server.port())
+        .duration(Duration::from_millis(20))
+        .category_filter(uplog::CategoryPattern::new("app.*").unwrap())
+        .capture_panics(true)
+        .try_init()
+        .unwrap();
+    std::env::set_var("RUST_BACKTRACE", "1");
+    let line = line!() + 3;
+    let result = thread::Builder::new()
+        .name("worker".to_string())
+        .spawn(|| panic!("broken {}", 42))
+        .unwrap()
+        .join();
+    assert!(result.is_err());
+
+    let records = wait_for(|| {
+        let name = storage.records().ok()?.first()?.name();
+        let records = storage
+            .session_records(&name)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter(|x| !is_server_record(x) && x.category == uplog::PANIC_CATEGORY)
+            .collect::<Vec<_>>();
+        Some(records).filter(|x| !x.is_empty())
+    });
+    // カテゴリの設定に関わらず送る
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record.level(), uplog::Level::Error);
+    assert_eq!(record.message, "broken 42");
+    assert_eq!(record.file.as_deref(), Some(file!()));
+    assert_eq!(record.line, Some(line));
+    let kv = record.kv.as_ref().unwrap();
+    assert_eq!(kv["thread"], uplog::Value::Text("worker".to_string()));
+    assert!(matches!(&kv["backtrace"], uplog::Value::Text(x) if !x.is_empty()));
+    server.stop(false).unwrap();
+}
@@ -0,0 +1,55 @@
+//! `elapsed`を整数で送るクライアントのレコードがDurationに戻して保存されることを確認する
+#![cfg(feature = "web")]
+use std::time::Duration;
+
+use tempdir::TempDir;
+use uplog::precision::Precision;
+use uplog_tools::{
+    lifecycle::is_server_record,
+    server::{run_server, ServerOptions},
+    testing::wait_for,
+};
+
+#[test]
+fn test_time_precision() {
+    let dir = TempDir::new("precision").unwrap();
+    let server = run_server(ServerOptions::new(dir.path())).unwrap();
+    let storage = server.storage().clone();
+
+    uplog::Builder::default()
+        .host("127.0.0.1")
+        .port(server.port())
+        .duration(Duration::from_millis(20))
+        .time_precision(Precision::Millis)
+        .try_init()
+        .unwrap();
+    for i in 0..3_u32 {
+        uplog::info!("precision.test", "send", "i", i);
+    }
+    uplog::flush();
+
+    let (name, records) = wait_for(|| {
+        let name = storage.records().ok()?.first()?.name();
+        let records = storage
+            .session_records(&name)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter(|x| !is_server_record(x) && x.category == "precision.test")
+            .collect::<Vec<_>>();
+        Some((name, records)).filter(|(_, x)| x.len() == 3)
+    });
+    assert_eq!(records.len(), 3);
+    // ミリ秒未満は切り捨てて送る
+    for r in records.iter() {
+        assert_eq!(r.elapsed.subsec_nanos() % 1_000_000, 0, "{:?}", r.elapsed);
+    }
+    assert_eq!(
+        storage
+            .session_meta(&name)
+            .unwrap()
+            .time_precision
+            .as_deref(),
+        Some("ms")
+    );
+    server.stop(false).unwrap();
+}
@@ -0,0 +1,193 @@
+//! 長時間の接続と切断でサーバーの持つものが増え続けないことを確かめる
+//!
+//! 時間がかかるので既定では実行しない。`cargo test --ignored soak`で実行し、
+//! `SOAK_CLIENTS`、`SOAK_SECS`、`SOAK_RECORDS`、`SOAK_SAMPLE_MS`で規模を変える
+#![cfg(all(target_os = "linux", feature = "web"))]
+
+use std::{
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use tempdir::TempDir;
+use tungstenite::{connect, Message};
+use uplog::{devinit, devlog, protocol::SESSION_QUERY, Level};
+use uplog_tools::{
+    actor::{actor_gauges, ActorGauges},
+    server::{run_server, ServerOptions},
+    RegistryGauges,
+};
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(default)
+}
+
+/// プロセスの常駐メモリ(KiB)と開いているファイル記述子の数
+fn sample() -> (u64, usize) {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    let rss = status
+        .lines()
+        .find_map(|x| x.strip_prefix("VmRSS:"))
+        .and_then(|x| x.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap();
+    let fds = std::fs::read_dir("/proc/self/fd").unwrap().count();
+    (rss, fds)
+}
+
+/// 接続して送り、閉じるのを期限まで繰り返す
+fn run_client(addr: SocketAddr, index: usize, records: usize, until: Instant) -> usize {
+    let mut connections = 0;
+    while Instant::now() < until {
+        // 半分のクライアントは再接続で引き継げるセッションIDを送る
+        let url = if index.is_multiple_of(2) {
+            format!(
+                "ws://{}{}?{}={}",
+                addr,
+                uplog::INGEST_PATH,
+                SESSION_QUERY,
+                uuid::Uuid::new_v4()
+            )
+        } else {
+            format!("ws://{}{}", addr, uplog::INGEST_PATH)
+        };
+        let (mut client, _) = connect(url.as_str()).unwrap();
+        for chunk in (0..records as u64).collect::<Vec<_>>().chunks(50) {
+            let buf = chunk
+                .iter()
+                .flat_map(|i| {
+                    serde_cbor::to_vec(&devlog!(Level::Info, "soak", "msg", "i", *i)).unwrap()
+                })
+                .collect::<Vec<_>>();
+            client.write_message(Message::binary(buf)).unwrap();
+        }
+        client.close(None).unwrap();
+        // サーバーが閉じるまで読む
+        while client.read_message().is_ok() {}
+        connections += 1;
+    }
+    connections
+}
+
+/// 条件を満たすまで待ち、満たさなければ最後の値を返す
+fn settle<T: PartialEq, F: FnMut() -> T>(expected: T, mut f: F) -> T {
+    let mut last = f();
+    for _ in 0..500 {
+        if last == expected {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+        last = f();
+    }
+    last
+}
+
+#[test]
+#[ignore]
+fn soak_connections() {
+    devinit!();
+    let clients = env_or("SOAK_CLIENTS", 8_usize);
+    let duration = Duration::from_secs(env_or("SOAK_SECS", 60));
+    let records = env_or("SOAK_RECORDS", 200_usize);
+    let interval = Duration::from_millis(env_or("SOAK_SAMPLE_MS", 1000));
+
+    let dir = TempDir::new("soak").unwrap();
+    let server = run_server(ServerOptions::new(dir.path())).unwrap();
+    let storage = server.storage().clone();
+    let addr = SocketAddr::from(([127, 0, 0, 1], server.port()));
+    // サーバーのワーカーが立ち上がってから数える
+    run_client(addr, 1, records, Instant::now() + Duration::from_millis(1));
+    settle(ActorGauges::default(), actor_gauges);
+    let (_, idle_fds) = sample();
+
+    let until = Instant::now() + duration;
+    let handles = (0..clients)
+        .map(|i| thread::spawn(move || run_client(addr, i, records, until)))
+        .collect::<Vec<_>>();
+    let mut samples = Vec::new();
+    while Instant::now() < until {
+        thread::sleep(interval);
+        let (rss, fds) = sample();
+        eprintln!(
+            "rss {} KiB, fds {}, {:?}, {:?}",
+            rss,
+            fds,
+            actor_gauges(),
+            storage.registry().gauges()
+        );
+        samples.push((rss, fds));
+    }
+    let connections = handles
+        .into_iter()
+        .map(|x| x.join().unwrap())
+        .sum::<usize>();
+    eprintln!("{} connections", connections);
+    assert!(samples.len() >= 3, "run longer than 3 samples");
+
+    // 最初の1/3で立ち上がった後は増え続けない
+    let warm = samples.len() / 3;
+    let warm_rss = samples[..warm.max(1)].iter().map(|x| x.0).max().unwrap();
+    let warm_fds = samples[..warm.max(1)].iter().map(|x| x.1).max().unwrap();
+    let late_rss = samples[samples.len() - warm.max(1)..]
+        .iter()
+        .map(|x| x.0)
+        .max()
+        .unwrap();
+    let late_fds = samples[samples.len() - warm.max(1)..]
+        .iter()
+        .map(|x| x.1)
+        .max()
+        .unwrap();
+    assert!(
+        late_rss <= warm_rss + warm_rss / 2 + 16 * 1024,
+        "rss grew from {} KiB to {} KiB",
+        warm_rss,
+        late_rss
+    );
+    assert!(
+        late_fds <= warm_fds + clients,
+        "fds grew from {} to {}",
+        warm_fds,
+        late_fds
+    );
+
+    // 全て切断したら接続とセッションのためのものは残らない
+    assert_eq!(
+        settle(ActorGauges::default(), actor_gauges),
+        ActorGauges::default()
+    );
+    assert_eq!(
+        settle(RegistryGauges::default(), || storage.registry().gauges()),
+        RegistryGauges::default()
+    );
+    let fds = settle(idle_fds, || sample().1);
+    assert!(
+        fds <= idle_fds,
+        "{} fds left open, {} when idle",
+        fds,
+        idle_fds
+    );
+
+    // 切断の直前に送ったレコードも書き、クライアントの理由で閉じる
+    let sessions = storage.records().unwrap();
+    assert_eq!(sessions.len(), connections + 1);
+    for info in sessions {
+        let count = serde_cbor::Deserializer::from_reader(info.open().unwrap())
+            .into_iter::<uplog::Record>()
+            .filter_map(Result::ok)
+            .filter(|x| x.category == "soak")
+            .count();
+        let meta = storage.session_meta(&info.name()).unwrap();
+        assert_eq!(count, records, "{}", info.name());
+        assert_eq!(
+            meta.end_reason.as_deref(),
+            Some("client"),
+            "{}",
+            info.name()
+        );
+    }
+    server.stop(false).unwrap();
+}
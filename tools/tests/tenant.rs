@@ -0,0 +1,186 @@
+//! 2つのテナントのトークンで1つのサーバーに送り、保存先とGraphQLの一覧が分かれることを確認する
+#![cfg(feature = "web")]
+use futures::executor::block_on;
+use tempdir::TempDir;
+use tungstenite::{client::IntoClientRequest, connect, Message};
+use uplog::{devlog, Level};
+use uplog_tools::{
+    acl::Acl,
+    actor::IngestEndpoint,
+    server::{run_server, ServerOptions},
+    tenant::TENANT_MARKER,
+    testing::wait_for,
+    webapi::{build_schema, execute, Mutation, Query},
+    Storage,
+};
+
+const ACL: &str = r#"
+[[token]]
+token = "acme-secret"
+sessions = ["*"]
+permissions = ["read", "mutate"]
+tenant = "acme"
+
+[[token]]
+token = "globex-secret"
+sessions = ["*"]
+permissions = ["read", "mutate"]
+tenant = "globex"
+
+[[token]]
+token = "admin"
+sessions = ["*"]
+permissions = ["read"]
+
+[[tenant]]
+id = "acme"
+labels = ["acme"]
+"#;
+
+/// トークンを付けて接続し、1件送って閉じる
+fn send(port: u16, path: &str, token: Option<&str>, message: &str) {
+    let mut req = format!("ws://127.0.0.1:{}{}", port, path)
+        .into_client_request()
+        .unwrap();
+    if let Some(token) = token {
+        req.headers_mut().insert(
+            "Authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+    }
+    let (mut socket, _) = connect(req).unwrap();
+    let record = devlog!(Level::Info, "tenant.test", message);
+    socket
+        .write_message(Message::binary(serde_cbor::to_vec(&record).unwrap()))
+        .unwrap();
+    socket.close(None).unwrap();
+    // 閉じたことを受け取るまで読む
+    while socket.read_message().is_ok() {}
+}
+
+fn names(storage: &Storage) -> Vec<String> {
+    let mut names = storage
+        .records()
+        .unwrap()
+        .into_iter()
+        .map(|x| x.name())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_tenant_isolation() {
+    let dir = TempDir::new("tenant").unwrap();
+    let acl_file = dir.path().join("acl.toml");
+    std::fs::write(&acl_file, ACL).unwrap();
+    let acl = ACL.parse::<Acl>().unwrap();
+    let data_dir = dir.path().join("data");
+    let server = run_server(ServerOptions {
+        ingest_endpoints: vec![
+            IngestEndpoint::default(),
+            IngestEndpoint::new("/acme").label("acme"),
+        ],
+        acl_file: Some(acl_file),
+        ..ServerOptions::new(&data_dir)
+    })
+    .unwrap();
+    let storage = server.storage().clone();
+    let port = server.port();
+
+    send(
+        port,
+        uplog::INGEST_PATH,
+        Some("acme-secret"),
+        "acme by token",
+    );
+    send(port, "/acme", None, "acme by label");
+    send(
+        port,
+        uplog::INGEST_PATH,
+        Some("globex-secret"),
+        "globex by token",
+    );
+    send(port, uplog::INGEST_PATH, Some("admin"), "no tenant");
+
+    let acme = storage.tenant("acme").unwrap();
+    let globex = storage.tenant("globex").unwrap();
+    let messages = |storage: &Storage| {
+        let mut messages = names(storage)
+            .iter()
+            .flat_map(|name| storage.session_records(name).unwrap())
+            .filter_map(Result::ok)
+            .filter(|x| x.category == "tenant.test")
+            .map(|x| x.message)
+            .collect::<Vec<_>>();
+        messages.sort();
+        messages
+    };
+    // 閉じたセッションが書き出されるまで待つ
+    wait_for(|| {
+        (messages(&acme).len() == 2
+            && messages(&globex).len() == 1
+            && messages(&storage).len() == 1)
+            .then_some(())
+    });
+
+    // 保存先のディレクトリが分かれる
+    assert!(data_dir.join("acme").join(TENANT_MARKER).is_file());
+    assert!(data_dir.join("globex").join(TENANT_MARKER).is_file());
+    assert_eq!(messages(&acme), ["acme by label", "acme by token"]);
+    assert_eq!(messages(&globex), ["globex by token"]);
+    assert_eq!(messages(&storage), ["no tenant"]);
+    for name in names(&acme) {
+        assert!(data_dir.join("acme").join(&name).is_dir());
+        assert!(!data_dir.join("globex").join(&name).exists());
+    }
+
+    // GraphQLはトークンのテナントのセッションだけを扱う
+    let schema = build_schema(Query::new(storage.clone()), Mutation::new(storage.clone()));
+    let run = |token: &str, q: &str| {
+        let req = async_graphql::Request::new(q).data(acl.scope(Some(token)));
+        block_on(execute(&schema, req))
+    };
+    let listed = |token: &str| {
+        let res = run(token, "{ storages { name } }");
+        assert!(res.errors.is_empty(), "{:?}", res.errors);
+        let mut names = res.data.into_json().unwrap()["storages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["name"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    assert_eq!(listed("acme-secret"), names(&acme));
+    assert_eq!(listed("globex-secret"), names(&globex));
+    assert_eq!(listed("admin"), names(&storage));
+
+    // 他のテナントのセッションは名前を指定しても見つからない
+    let other = &names(&acme)[0];
+    let res = run(
+        "globex-secret",
+        &format!(r#"{{ categories(name: "{}") {{ segment }} }}"#, other),
+    );
+    assert_eq!(res.errors.len(), 1);
+    let res = run(
+        "globex-secret",
+        &format!(
+            r#"mutation {{ setSessionNote(name: "{}", note: "x") {{ note }} }}"#,
+            other
+        ),
+    );
+    assert_eq!(res.errors.len(), 1);
+    assert_eq!(acme.session_meta(other).unwrap().note, None);
+    let res = run(
+        "acme-secret",
+        &format!(
+            r#"mutation {{ setSessionNote(name: "{}", note: "x") {{ note }} }}"#,
+            other
+        ),
+    );
+    assert!(res.errors.is_empty(), "{:?}", res.errors);
+    assert_eq!(acme.session_meta(other).unwrap().note.as_deref(), Some("x"));
+    server.stop(false).unwrap();
+}
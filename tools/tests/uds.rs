@@ -0,0 +1,58 @@
+//! Unix domain socketでクライアントからサーバーに送って保存されることを確認する
+#![cfg(all(unix, feature = "web"))]
+
+use std::{os::unix::fs::PermissionsExt, time::Duration};
+
+use tempdir::TempDir;
+use uplog_tools::{
+    server::{run_server, ServerOptions},
+    testing::wait_for,
+};
+
+#[test]
+fn test_uds_client_server() {
+    let dir = TempDir::new("uds").unwrap();
+    let path = dir.path().join("uplog.sock");
+    let server = run_server(ServerOptions {
+        uds_path: Some(path.clone()),
+        uds_mode: Some(0o600),
+        ..ServerOptions::new(dir.path().join("data"))
+    })
+    .unwrap();
+    let storage = server.storage().clone();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    uplog::Builder::default()
+        .uds_path(&path)
+        .duration(Duration::from_millis(20))
+        .try_init()
+        .unwrap();
+    for i in 0..3_u32 {
+        uplog::info!("uds.test", "send", "i", i);
+    }
+    // 送信スレッドが終わると接続を閉じ、セッションが書き込まれる
+    uplog::flush();
+
+    let messages = wait_for(|| {
+        let messages = storage
+            .records()
+            .ok()?
+            .iter()
+            .flat_map(|x| {
+                serde_cbor::Deserializer::from_reader(x.open().unwrap())
+                    .into_iter::<uplog::Record>()
+                    .filter_map(Result::ok)
+                    .filter(|x| x.category == "uds.test")
+                    .map(|x| x.kv.unwrap()["i"].clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        Some(messages).filter(|x| x.len() == 3)
+    });
+    assert_eq!(
+        messages,
+        (0..3_u64).map(uplog::Value::U64).collect::<Vec<_>>()
+    );
+    server.stop(false).unwrap();
+}
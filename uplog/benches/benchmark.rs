@@ -1,6 +1,8 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use fake::{Dummy, Fake, Faker};
-use uplog::{devlog, devlog_encode, session_init};
+use uplog::{devlog, devlog_encode, session_init, Builder, MockTransport};
 
 #[derive(Debug, Dummy)]
 pub struct DummeData {
@@ -12,7 +14,7 @@ pub struct DummeData {
 
 fn criterion_benchmark(c: &mut Criterion) {
     session_init();
-    let testdata: Vec<DummeData> = (0..1000).into_iter().map(|_| Faker.fake()).collect();
+    let testdata: Vec<DummeData> = (0..1000).map(|_| Faker.fake()).collect();
     let largedata = vec![64_u8; 1024 * 1024 * 500];
 
     c.bench_function("generate log short data 1000", |b| {
@@ -92,5 +94,239 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, criterion_benchmark);
+/// 計測中だけ送信先をMockTransportにする
+fn with_mock_client<F: FnOnce()>(name: &str, buffer_size: usize, single_producer: bool, f: F) {
+    let transport = MockTransport::new();
+    Builder::default()
+        .buffer_size(buffer_size)
+        .single_producer(single_producer)
+        .duration(Duration::from_millis(10))
+        .try_init_with_transport(transport.clone())
+        .unwrap();
+    let dropped = uplog::health().dropped_records;
+    f();
+    // flush後は再初期化できる
+    uplog::flush();
+    // バッファーに収まらなかった分は破棄されるので合わせて出力する
+    println!(
+        "end_to_end/{}/{}: sent {} bytes in {} messages, dropped {} records",
+        name,
+        buffer_size,
+        transport.sent_bytes(),
+        transport.messages(),
+        uplog::health().dropped_records - dropped
+    );
+}
+
+/// マクロからLogClient、バッファーを経て送信スレッドまでを計測する
+///
+/// 書き込みロック内でシリアライズしていた時との比較 (thrpt, 1 core)
+///
+/// | case            | lock内でシリアライズ | thread localでシリアライズ |
+/// |-----------------|----------------------|----------------------------|
+/// | small/16MB      | 2.05 Melem/s         | 2.77 Melem/s               |
+/// | small/2MB       | 2.98 Melem/s         | 2.84 Melem/s               |
+/// | small/256KB     | 4.33 Melem/s         | 2.29 Melem/s               |
+/// | 64KB/16MB       | 3.57 Melem/s         | 0.40 Melem/s               |
+///
+/// 破棄が起きないsmall/16MBで改善している。
+/// 破棄が多いケースは以前はバッファーが溢れた時点で途中までのレコードを残して
+/// 打ち切っていたため速く見えるが、送信データが壊れていた
+///
+/// `swap`は`Builder::single_producer(false)`、`ring`は`true`の場合 (thrpt, 1 core)
+///
+/// | case            | swap         | ring         |
+/// |-----------------|--------------|--------------|
+/// | small/256KB     | 2.07 Melem/s | 2.88 Melem/s |
+/// | small/2MB       | 2.22 Melem/s | 2.70 Melem/s |
+/// | small/16MB      | 2.54 Melem/s | 2.47 Melem/s |
+///
+/// リングバッファーは送信スレッドが折り返していない範囲をコピーせずに読むので、
+/// 送信が追いつかないsmall/256KBとsmall/2MBで速い。
+/// 1 coreでは書き込み側のロックが競合しないため、破棄が起きないsmall/16MBでは差が出ない
+fn end_to_end_benchmark(c: &mut Criterion) {
+    const BATCH: usize = 1000;
+    let testdata: Vec<DummeData> = (0..BATCH).map(|_| Faker.fake()).collect();
+    let blob = vec![64_u8; 64 * 1024];
+
+    let mut group = c.benchmark_group("end_to_end");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    for (mode, single_producer) in [("swap", false), ("ring", true)] {
+        for buffer_size in [256 * 1024, uplog::DEFAULT_BUFFER_SIZE, 16 * 1024 * 1024] {
+            let name = format!("small/{}", mode);
+            with_mock_client(&name, buffer_size, single_producer, || {
+                group.bench_with_input(
+                    BenchmarkId::new(&name, buffer_size),
+                    &testdata,
+                    |b, data| {
+                        b.iter(|| {
+                            for v in data {
+                                uplog::info!(
+                                    "uplog::benches",
+                                    "short log",
+                                    "order_id",
+                                    v.order_id,
+                                    "customer",
+                                    v.customer.as_str(),
+                                    "paid",
+                                    v.paid
+                                );
+                            }
+                        })
+                    },
+                );
+            });
+            let name = format!("64KB/{}", mode);
+            with_mock_client(&name, buffer_size, single_producer, || {
+                group.bench_with_input(BenchmarkId::new(&name, buffer_size), &blob, |b, blob| {
+                    b.iter(|| {
+                        for _ in 0..BATCH {
+                            uplog::info!("uplog::benches", "large data", "data", &blob[..]);
+                        }
+                    })
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+/// 辞書を使った送信形式への変換と、2回目以降のメッセージの大きさ
+///
+/// 1000レコードで213397 bytesが83397 bytesになる。
+/// 変換は615 Kelem/s (1 core) で、送信スレッドの負荷になる
+fn wire_benchmark(c: &mut Criterion) {
+    const BATCH: usize = 1000;
+    let testdata: Vec<DummeData> = (0..BATCH).map(|_| Faker.fake()).collect();
+    let buf = testdata
+        .iter()
+        .flat_map(|v| {
+            let r = devlog!(
+                uplog::Level::Info,
+                "uplog::benches",
+                "short log",
+                "order_id",
+                v.order_id,
+                "customer",
+                v.customer.as_str()
+            );
+            serde_cbor::to_vec(&r).unwrap()
+        })
+        .collect::<Vec<_>>();
+    let mut encoder = uplog::wire::WireEncoder::new();
+    let first = encoder.encode(&buf).unwrap();
+    let second = encoder.encode(&buf).unwrap();
+    println!(
+        "wire: plain {} bytes, first message {} bytes, following messages {} bytes",
+        buf.len(),
+        first.len(),
+        second.len()
+    );
+
+    let mut group = c.benchmark_group("wire");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    group.bench_function("encode", |b| b.iter(|| encoder.encode(&buf).unwrap()));
+    group.finish();
+}
+
+/// `elapsed`を整数で書いた場合の送信量と書き込みの速さ
+///
+/// 1000レコードで177891 bytesが`us`で164878 bytes、`ms`で162876 bytesになる。
+/// 書き込みの速さはほとんど変わらない
+fn precision_benchmark(c: &mut Criterion) {
+    use uplog::precision::Precision;
+    const BATCH: usize = 1000;
+    let init = |precision: Option<Precision>| {
+        let transport = MockTransport::new();
+        let mut builder = Builder::default().duration(Duration::from_millis(10));
+        if let Some(precision) = precision {
+            builder = builder.time_precision(precision);
+        }
+        builder.try_init_with_transport(transport.clone()).unwrap();
+        transport
+    };
+    let log = || {
+        for i in 0..BATCH as u64 {
+            uplog::info!("uplog::benches", "short log", "i", i);
+        }
+    };
+
+    let mut group = c.benchmark_group("precision");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    let mut sizes = Vec::new();
+    for (name, precision) in [
+        ("duration", None),
+        ("us", Some(Precision::Micros)),
+        ("ms", Some(Precision::Millis)),
+    ] {
+        // 同じレコード数で送信量を比べる
+        let transport = init(precision);
+        log();
+        uplog::flush();
+        let bytes = transport.sent_bytes();
+        println!(
+            "precision/{}: sent {} bytes for {} records",
+            name, bytes, BATCH
+        );
+        sizes.push(bytes);
+
+        init(precision);
+        group.bench_function(name, |b| b.iter(log));
+        uplog::flush();
+    }
+    group.finish();
+    // 開始直後はsecsが0なので1レコードあたり少なくともnanosの分は減る
+    assert!(sizes[1] + BATCH as u64 * 2 <= sizes[0], "{:?}", sizes);
+    assert!(sizes[2] <= sizes[1], "{:?}", sizes);
+}
+
+/// レコードを文字列にする速さ
+///
+/// 書き込み先の文字列は使い回すので、書式の中で確保しなければ確保は起きない。
+/// 確保しないことは`tests/format.rs`で数えて確認している
+fn format_benchmark(c: &mut Criterion) {
+    use std::fmt::Write;
+    use uplog::{ElapsedStyle, KvStyle, RecordFormatter};
+
+    let mut record = devlog!(
+        uplog::Level::Info,
+        "uplog::benches",
+        "short log",
+        "order_id",
+        1234_u64,
+        "paid",
+        true
+    );
+    record.file = None;
+    let mut group = c.benchmark_group("format");
+    for (name, formatter) in [
+        ("default", RecordFormatter::new()),
+        (
+            "hms_multiline",
+            RecordFormatter::new()
+                .elapsed(ElapsedStyle::Hms)
+                .kv(KvStyle::Multiline)
+                .max_message_width(Some(8)),
+        ),
+    ] {
+        let mut buf = String::with_capacity(1024);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                buf.clear();
+                write!(buf, "{}", formatter.format(&record)).unwrap();
+                assert!(!buf.is_empty());
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    format_benchmark,
+    criterion_benchmark,
+    end_to_end_benchmark,
+    wire_benchmark,
+    precision_benchmark
+);
 criterion_main!(benches);
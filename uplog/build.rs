@@ -0,0 +1,6 @@
+// `build_info!`で使うターゲットのトリプル
+fn main() {
+    let target = std::env::var("TARGET").unwrap_or_default();
+    println!("cargo:rustc-env=UPLOG_TARGET={}", target);
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,269 @@
+//! ログと一緒に送る小さなファイル
+//!
+//! 設定のスナップショットやminidumpを`_attachment`のmapを持つレコードとして送る。
+//! レコードの上限より大きいものは番号を付けた部分に分け、共通のIDでまとめる
+use std::{
+    collections::BTreeMap,
+    panic::Location,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{KVBorrow, Level, LogOutcome, Record, RecordBorrow, Value, ValueBorrow, KV};
+
+/// Key of the map describing an attachment part, see [`attach`].
+pub const ATTACHMENT_KEY: &str = "_attachment";
+
+/// Bytes of one part when no maximum record size is set.
+pub const ATTACHMENT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// 上限から部分の大きさを決めるときに、`__log_api`が付け足すキーの分として残す余裕
+const CHUNK_MARGIN: usize = 128;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A file shipped to the server as one or more records.
+///
+/// Each record carries [`ATTACHMENT_KEY`] with a map of `id`, `filename`, `mime`, `part`
+/// (from 1), `parts`, `len` of the whole file and the `bytes` of the part. The server joins
+/// the parts by `id`.
+///
+/// ```
+/// use uplog::{Attachment, ATTACHMENT_KEY};
+///
+/// let dump = vec![0_u8; 10];
+/// let attachment = Attachment::new("app.dmp", &dump).chunk_size(4);
+/// let records = attachment.records("app.crash");
+/// assert_eq!(records.len(), 3);
+/// assert_eq!(records[2].kv.as_ref().unwrap()[ATTACHMENT_KEY].as_map().unwrap()["part"], 3_u32.into());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Attachment<'a> {
+    id: String,
+    filename: &'a str,
+    mime: &'a str,
+    bytes: &'a [u8],
+    chunk_size: Option<usize>,
+}
+
+impl<'a> Attachment<'a> {
+    /// The MIME type is guessed from the extension of `filename`.
+    pub fn new(filename: &'a str, bytes: &'a [u8]) -> Self {
+        let n = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        Self {
+            id: format!("{}-{}", crate::session_id(), n),
+            filename,
+            mime: guess_mime(filename),
+            bytes,
+            chunk_size: None,
+        }
+    }
+
+    pub fn mime(mut self, mime: &'a str) -> Self {
+        self.mime = mime;
+        self
+    }
+
+    /// Splits into parts of at most `size` bytes. Defaults to what fits in
+    /// `Builder::max_record_bytes`, or [`ATTACHMENT_CHUNK_SIZE`] without a maximum.
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = Some(size.max(1));
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Number of records [`Attachment::send`] writes.
+    pub fn parts(&self) -> usize {
+        self.chunks().len().max(1)
+    }
+
+    fn chunks(&self) -> Vec<&'a [u8]> {
+        let size = self.chunk_size.unwrap_or_else(|| self.fitting_chunk_size());
+        self.bytes.chunks(size).collect()
+    }
+
+    /// 上限があれば空の部分のレコードを見積もって残りを使う
+    fn fitting_chunk_size(&self) -> usize {
+        let max = match crate::oversize::max_record_bytes() {
+            Some(x) => x,
+            None => return ATTACHMENT_CHUNK_SIZE,
+        };
+        let empty = part_kv(self.part_map(u32::MAX, u32::MAX, &[]));
+        let record = RecordBorrow {
+            metadata: crate::MetadataBorrow::new(Level::Info, module_path!()),
+            elapsed: crate::session::elapsed(),
+            category: "",
+            module_path: Some(module_path!()),
+            file: Some(file!()),
+            line: Some(u32::MAX),
+            message: self.filename,
+            kv: Some(empty),
+        };
+        // バイト列の長さの先頭は最大で9バイト
+        let overhead = crate::estimate_record_size(&record) + 9 + CHUNK_MARGIN;
+        max.saturating_sub(overhead).max(1)
+    }
+
+    fn part_map(&self, part: u32, parts: u32, bytes: &'a [u8]) -> BTreeMap<&str, ValueBorrow<'_>> {
+        let mut map = BTreeMap::new();
+        map.insert("id", ValueBorrow::Text(&self.id));
+        map.insert("filename", ValueBorrow::Text(self.filename));
+        map.insert("mime", ValueBorrow::Text(self.mime));
+        map.insert("part", ValueBorrow::U32(part));
+        map.insert("parts", ValueBorrow::U32(parts));
+        map.insert("len", ValueBorrow::U64(self.bytes.len() as u64));
+        map.insert("bytes", ValueBorrow::Bytes(bytes));
+        map
+    }
+
+    fn each_part<F: FnMut(BTreeMap<&str, ValueBorrow<'_>>) -> bool>(&self, mut f: F) {
+        let chunks = self.chunks();
+        let parts = chunks.len().max(1) as u32;
+        if chunks.is_empty() {
+            f(self.part_map(1, 1, &[]));
+            return;
+        }
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            if !f(self.part_map(i as u32 + 1, parts, chunk)) {
+                return;
+            }
+        }
+    }
+
+    /// Logs the parts at the Info level with the filename as the message.
+    ///
+    /// Stops at the first part that is not accepted and returns its outcome.
+    #[track_caller]
+    pub fn send(&self, category: &str) -> LogOutcome {
+        let location = Location::caller();
+        let mut outcome = LogOutcome::Accepted;
+        self.each_part(|map| {
+            outcome = crate::__log_api(
+                Level::Info,
+                category,
+                category,
+                self.filename,
+                module_path!(),
+                location.file(),
+                location.line(),
+                Some(part_kv(map)),
+            );
+            outcome.is_accepted()
+        });
+        outcome
+    }
+
+    /// The records [`Attachment::send`] would log, e.g. for importers and fixtures.
+    pub fn records(&self, category: &str) -> Vec<Record> {
+        let mut records = Vec::new();
+        self.each_part(|map| {
+            let map = map
+                .iter()
+                .map(|(k, v)| (k.to_string(), Value::from(v)))
+                .collect::<KV>();
+            records.push(
+                Record::builder()
+                    .category(category)
+                    .message(self.filename)
+                    .kv(ATTACHMENT_KEY, map)
+                    .build(),
+            );
+            true
+        });
+        records
+    }
+}
+
+fn part_kv<'a>(map: BTreeMap<&'a str, ValueBorrow<'a>>) -> KVBorrow<'a> {
+    let mut kv = KVBorrow::new();
+    kv.insert(ATTACHMENT_KEY, ValueBorrow::Map(map));
+    kv
+}
+
+/// Ships a small file, e.g. a config snapshot or a minidump, alongside the logs.
+///
+/// Files larger than the maximum record size are split into numbered parts that the server
+/// joins again, see [`Attachment`].
+///
+/// ```
+/// # uplog::session_init();
+/// let config = br#"{"port": 8040}"#;
+/// uplog::attach("app.config", "config.json", config);
+/// ```
+#[track_caller]
+pub fn attach(category: &str, filename: &str, bytes: &[u8]) -> LogOutcome {
+    Attachment::new(filename, bytes).send(category)
+}
+
+/// 拡張子からよく使うものだけ判定する
+fn guess_mime(filename: &str) -> &'static str {
+    let ext = filename
+        .rsplit_once('.')
+        .map(|(_, x)| x.to_ascii_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "txt" | "log" => "text/plain",
+        "json" => "application/json",
+        "toml" => "application/toml",
+        "yaml" | "yml" => "application/yaml",
+        "csv" => "text/csv",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gz" => "application/gzip",
+        "zip" => "application/zip",
+        "dmp" | "mdmp" => "application/x-dmp",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{guess_mime, Attachment, ATTACHMENT_KEY};
+    use crate::Value;
+
+    #[test]
+    fn test_attachment_records() {
+        let bytes = (0..10_u8).collect::<Vec<_>>();
+        let attachment = Attachment::new("core.dmp", &bytes).chunk_size(4);
+        assert_eq!(attachment.parts(), 3);
+        let records = attachment.records("app");
+        let maps = records
+            .iter()
+            .map(|x| x.kv.as_ref().unwrap()[ATTACHMENT_KEY].as_map().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(maps.len(), 3);
+        let mut joined = Vec::new();
+        for (i, map) in maps.iter().enumerate() {
+            assert_eq!(map["id"], Value::from(attachment.id()));
+            assert_eq!(map["filename"], Value::from("core.dmp"));
+            assert_eq!(map["mime"], Value::from("application/x-dmp"));
+            assert_eq!(map["part"], Value::U64(i as u64 + 1));
+            assert_eq!(map["parts"], Value::U64(3));
+            assert_eq!(map["len"], Value::U64(10));
+            joined.extend_from_slice(map["bytes"].as_bytes().unwrap());
+        }
+        assert_eq!(joined, bytes);
+        assert_eq!(records[0].message, "core.dmp");
+
+        // 空のファイルも1つの部分として送る
+        let empty = Attachment::new("empty.txt", &[]);
+        assert_eq!(empty.parts(), 1);
+        assert_ne!(empty.id(), attachment.id());
+        let records = empty.records("app");
+        assert_eq!(records.len(), 1);
+        let map = records[0].kv.as_ref().unwrap()[ATTACHMENT_KEY]
+            .as_map()
+            .unwrap()
+            .clone();
+        assert_eq!(map["bytes"], Value::Bytes(Vec::new()));
+    }
+
+    #[test]
+    fn test_guess_mime() {
+        assert_eq!(guess_mime("config.JSON"), "application/json");
+        assert_eq!(guess_mime("app.log"), "text/plain");
+        assert_eq!(guess_mime("noext"), "application/octet-stream");
+    }
+}
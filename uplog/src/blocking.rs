@@ -0,0 +1,131 @@
+//! 送信スレッドを使わずにその場で送る
+//!
+//! 短時間で終わるツールが、送り終わるまで待ってから終了できるようにする
+use std::{cell::RefCell, time::Instant};
+
+use crate::{protocol::CloseReason, transport::Transport, ws::WebsocketTransport, Builder, Record};
+
+thread_local! {
+    /// [`with_blocking_logger`]の間にマクロが書いたレコード
+    static CAPTURE: RefCell<Option<Vec<Vec<u8>>>> = const { RefCell::new(None) };
+}
+
+/// Sends `records` to the server configured by `builder` and returns once they are delivered.
+///
+/// Opens a connection, writes the records as one or more frames no larger than the swap
+/// buffer size, then closes and waits for the server to answer the close handshake. The
+/// whole exchange is bounded by [`Builder::blocking_timeout`], after which
+/// [`crate::Error::Timeout`] is returned. The global logger is not involved.
+///
+/// ```no_run
+/// let record = uplog::devlog!(uplog::Level::Info, "tool", "done");
+/// uplog::send_records_blocking(&uplog::Builder::default(), &[record]).unwrap();
+/// ```
+#[allow(clippy::result_large_err)]
+pub fn send_records_blocking(builder: &Builder, records: &[Record]) -> crate::Result<()> {
+    let encoded = records
+        .iter()
+        .map(serde_cbor::to_vec)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    send_encoded(builder, &encoded)
+}
+
+/// Runs `f` with the log macros of the current thread collecting their records, then sends
+/// them with [`send_records_blocking`] and returns the value of `f`.
+///
+/// Records go through the usual level, sampling and redaction checks, but bypass the global
+/// logger, so this works with or without one being initialized.
+///
+/// ```no_run
+/// let count = uplog::with_blocking_logger(&uplog::Builder::default(), || {
+///     uplog::info!("tool", "start");
+///     42
+/// })
+/// .unwrap();
+/// ```
+#[allow(clippy::result_large_err)]
+pub fn with_blocking_logger<R, F: FnOnce() -> R>(builder: &Builder, f: F) -> crate::Result<R> {
+    crate::session_init();
+    // 入れ子やpanicでも外側の記録に戻す
+    struct Restore(Option<Vec<Vec<u8>>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let outer = self.0.take();
+            CAPTURE.with(|x| *x.borrow_mut() = outer);
+        }
+    }
+    let restore = Restore(CAPTURE.with(|x| x.borrow_mut().replace(Vec::new())));
+    let value = f();
+    let records = CAPTURE.with(|x| x.borrow_mut().take()).unwrap_or_default();
+    drop(restore);
+    send_encoded(builder, &records)?;
+    Ok(value)
+}
+
+/// 記録中ならレコードを取っておく。取ったときはtrue
+pub(crate) fn capture<T: serde::Serialize>(record: &T) -> bool {
+    CAPTURE.with(|x| match x.borrow_mut().as_mut() {
+        Some(records) => match serde_cbor::to_vec(record) {
+            Ok(buf) => {
+                records.push(buf);
+                true
+            }
+            Err(_) => false,
+        },
+        None => false,
+    })
+}
+
+#[allow(clippy::result_large_err)]
+fn send_encoded(builder: &Builder, records: &[Vec<u8>]) -> crate::Result<()> {
+    builder.validate()?;
+    #[cfg(all(unix, feature = "uds"))]
+    if builder.uds_path.is_some() {
+        return Err(crate::BuilderError::ConflictingTransports("uds_path", "blocking").into());
+    }
+    let timeout = builder.blocking_timeout;
+    let deadline = Instant::now() + timeout;
+    let (url, codecs) = builder.endpoint();
+    let mut transport = WebsocketTransport::connect_timeout(&url, &codecs, timeout)?;
+    for frame in frames(records, builder.swap_buffer_size) {
+        if Instant::now() >= deadline {
+            return Err(crate::Error::Timeout(timeout));
+        }
+        transport.send(&frame)?;
+    }
+    transport.close_with(CloseReason::Flush)?;
+    transport.wait_closed(deadline, timeout)
+}
+
+/// レコードを切らずに`size`以下のフレームにまとめる。1つで超えるレコードはそのまま1フレームにする
+fn frames(records: &[Vec<u8>], size: usize) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    let mut frame: Vec<u8> = Vec::new();
+    for record in records {
+        if !frame.is_empty() && frame.len() + record.len() > size {
+            frames.push(std::mem::take(&mut frame));
+        }
+        frame.extend_from_slice(record);
+    }
+    if !frame.is_empty() {
+        frames.push(frame);
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::frames;
+
+    #[test]
+    fn test_frames() {
+        let records = vec![vec![1; 4], vec![2; 4], vec![3; 10], vec![4; 2]];
+        let sizes = frames(&records, 8)
+            .iter()
+            .map(|x| x.len())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [8, 10, 2]);
+        assert!(frames(&[], 8).is_empty());
+    }
+}
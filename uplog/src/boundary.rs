@@ -0,0 +1,64 @@
+//! セッションの区切り
+//!
+//! 長時間接続したままの機器でも、実行ごとにサーバー側でセッションを分けられるように
+//! 区切りを示すレコードを送る
+use std::time::Duration;
+
+use crate::{KVBorrow, Level, MetadataBorrow, RecordBorrow, ValueBorrow};
+
+/// category of the record written by [`mark_session_boundary`]
+pub const BOUNDARY_CATEGORY: &str = "uplog.boundary";
+
+/// Marks the start of a new logical run, e.g. `"run 42"`.
+///
+/// A server started with `--split-on-boundary` closes the current session on this record
+/// and writes the following records to a new one.
+/// The record is written regardless of the level and category filters.
+pub fn mark_session_boundary(label: &str) {
+    emit(
+        label,
+        crate::session::elapsed(),
+        crate::logger::submit_direct,
+    );
+}
+
+/// 区切りのレコードを作る。レベルやカテゴリの設定では落とさない
+fn emit<F: FnOnce(&RecordBorrow)>(label: &str, elapsed: Duration, f: F) {
+    let mut kv = KVBorrow::new();
+    kv.insert("label", ValueBorrow::Text(label));
+    let record = RecordBorrow {
+        metadata: MetadataBorrow::new(Level::Info, module_path!()),
+        elapsed,
+        category: BOUNDARY_CATEGORY,
+        module_path: Some(module_path!()),
+        file: Some(file!()),
+        line: Some(line!()),
+        message: label,
+        kv: Some(kv),
+    };
+    f(&record)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{emit, BOUNDARY_CATEGORY};
+    use crate::{Level, Record, Value};
+
+    #[test]
+    fn test_boundary_record() {
+        let mut buf = Vec::new();
+        emit("run 42", Duration::from_secs(1), |r| {
+            serde_cbor::to_writer(&mut buf, r).unwrap()
+        });
+        let record: Record = serde_cbor::from_slice(&buf).unwrap();
+        assert_eq!(record.category, BOUNDARY_CATEGORY);
+        assert_eq!(record.message, "run 42");
+        assert_eq!(record.level(), Level::Info);
+        assert_eq!(
+            record.kv.unwrap()["label"],
+            Value::Text("run 42".to_string())
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! カテゴリごとの送信量制限
+//!
+//! 巨大なバイナリを出力するサブシステムが他のログを押し出さないように
+//! カテゴリのprefix単位でトークンバケットを持ち、超過したレコードを破棄して数える。
+//! 破棄した量の集計は送信スレッドが周期ごとに取り出して書く
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{KVBorrow, Level, MetadataBorrow, RecordBorrow, ValueBorrow};
+
+/// 破棄したレコードの集計を出力するカテゴリ
+pub const BUDGET_CATEGORY: &str = "uplog.budget";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BUDGET: Mutex<Option<ByteBudget>> = Mutex::new(None);
+
+/// prefixごとの集計値
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetStats {
+    pub prefix: String,
+    pub bytes_per_second: u64,
+    pub admitted_records: u64,
+    pub admitted_bytes: u64,
+    pub dropped_records: u64,
+    pub dropped_bytes: u64,
+}
+
+/// 1つのprefixに対するトークンバケット
+#[derive(Debug)]
+struct CategoryBudget {
+    stats: BudgetStats,
+    /// バケットの大きさ。これより大きいレコードは通らない
+    burst_bytes: u64,
+    tokens: f64,
+    last_refill: Instant,
+    last_report: Instant,
+    // 前回の集計出力以降に破棄した量
+    unreported_records: u64,
+    unreported_bytes: u64,
+}
+
+impl CategoryBudget {
+    fn new(prefix: &str, bytes_per_second: u64, burst_bytes: u64, now: Instant) -> Self {
+        Self {
+            stats: BudgetStats {
+                prefix: prefix.to_string(),
+                bytes_per_second,
+                admitted_records: 0,
+                admitted_bytes: 0,
+                dropped_records: 0,
+                dropped_bytes: 0,
+            },
+            burst_bytes,
+            tokens: burst_bytes as f64,
+            last_refill: now,
+            last_report: now,
+            unreported_records: 0,
+            unreported_bytes: 0,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let rate = self.stats.bytes_per_second as f64;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate).min(self.burst_bytes as f64);
+        self.last_refill = now;
+    }
+
+    fn admit(&mut self, size: usize, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= size as f64 {
+            self.tokens -= size as f64;
+            self.stats.admitted_records += 1;
+            self.stats.admitted_bytes += size as u64;
+            true
+        } else {
+            self.stats.dropped_records += 1;
+            self.stats.dropped_bytes += size as u64;
+            self.unreported_records += 1;
+            self.unreported_bytes += size as u64;
+            false
+        }
+    }
+
+    fn take_report(&mut self, now: Instant, interval: Duration) -> Option<BudgetReport> {
+        if self.unreported_records == 0
+            || now.saturating_duration_since(self.last_report) < interval
+        {
+            return None;
+        }
+        let report = BudgetReport {
+            prefix: self.stats.prefix.clone(),
+            dropped_records: self.unreported_records,
+            dropped_bytes: self.unreported_bytes,
+        };
+        self.last_report = now;
+        self.unreported_records = 0;
+        self.unreported_bytes = 0;
+        Some(report)
+    }
+}
+
+/// 前回の出力以降に破棄したレコードの集計
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BudgetReport {
+    prefix: String,
+    dropped_records: u64,
+    dropped_bytes: u64,
+}
+
+impl BudgetReport {
+    /// 集計をレコードとして出力する
+    pub(crate) fn emit<F: FnOnce(&RecordBorrow)>(&self, elapsed: Duration, f: F) {
+        let mut kv = KVBorrow::new();
+        kv.insert("prefix", ValueBorrow::Text(&self.prefix));
+        kv.insert("dropped_records", ValueBorrow::U64(self.dropped_records));
+        kv.insert("dropped_bytes", ValueBorrow::U64(self.dropped_bytes));
+        let record = RecordBorrow {
+            metadata: MetadataBorrow::new(Level::Warn, module_path!()),
+            elapsed,
+            category: BUDGET_CATEGORY,
+            module_path: Some(module_path!()),
+            file: Some(file!()),
+            line: Some(line!()),
+            message: "budget exceeded",
+            kv: Some(kv),
+        };
+        f(&record)
+    }
+}
+
+/// 設定されたprefix全体の送信量制限
+#[derive(Debug)]
+pub(crate) struct ByteBudget {
+    budgets: Vec<CategoryBudget>,
+    report_interval: Duration,
+}
+
+impl ByteBudget {
+    const DEFAULT_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// `budgets`はprefix、1秒あたりのバイト数、バケットの大きさの組
+    pub(crate) fn new(budgets: &[(&str, u64, u64)], now: Instant) -> Self {
+        Self {
+            budgets: budgets
+                .iter()
+                .map(|(prefix, bps, burst)| CategoryBudget::new(prefix, *bps, *burst, now))
+                .collect(),
+            report_interval: Self::DEFAULT_REPORT_INTERVAL,
+        }
+    }
+
+    /// 最も長く一致するprefixのバケットを返す
+    fn find(&mut self, category: &str) -> Option<&mut CategoryBudget> {
+        self.budgets
+            .iter_mut()
+            .filter(|b| prefix_matches(&b.stats.prefix, category))
+            .max_by_key(|b| b.stats.prefix.len())
+    }
+
+    pub(crate) fn admit(&mut self, category: &str, size: usize, now: Instant) -> bool {
+        match self.find(category) {
+            Some(budget) => budget.admit(size, now),
+            None => true,
+        }
+    }
+
+    /// 前回の出力から間隔があいたバケットの集計を取り出す
+    pub(crate) fn take_reports(&mut self, now: Instant) -> Vec<BudgetReport> {
+        let interval = self.report_interval;
+        self.budgets
+            .iter_mut()
+            .filter_map(|b| b.take_report(now, interval))
+            .collect()
+    }
+
+    pub(crate) fn stats(&self) -> Vec<BudgetStats> {
+        self.budgets.iter().map(|b| b.stats.clone()).collect()
+    }
+}
+
+/// 同じカテゴリか、`.`で区切った下位のカテゴリ
+fn prefix_matches(prefix: &str, category: &str) -> bool {
+    category
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// 送信量の見積もり
+///
+/// 二重にシリアライズしないように文字列とバイト列の長さを足し合わせる
+pub(crate) fn estimate_size(message: &str, kv: Option<&KVBorrow>) -> usize {
+    message.len()
+        + kv.map(|kv| {
+            kv.iter()
+                .map(|(k, v)| k.len() + estimate_value_size(v))
+                .sum::<usize>()
+        })
+        .unwrap_or(0)
+}
+
+fn estimate_value_size(v: &ValueBorrow) -> usize {
+    match v {
+        ValueBorrow::Text(x) => x.len(),
+        ValueBorrow::Bytes(x) => x.len(),
+        ValueBorrow::Array(x) => x.iter().map(estimate_value_size).sum(),
+        ValueBorrow::Map(x) => x
+            .iter()
+            .map(|(k, v)| k.len() + estimate_value_size(v))
+            .sum(),
+        _ => 8,
+    }
+}
+
+/// バケットの大きさ。指定がなければ1秒分にし、1レコードの上限があればそれより小さくはしない
+fn burst_bytes(
+    bytes_per_second: u64,
+    burst_bytes: Option<u64>,
+    max_record_bytes: Option<usize>,
+) -> u64 {
+    burst_bytes
+        .unwrap_or(bytes_per_second)
+        .max(max_record_bytes.unwrap_or(0) as u64)
+}
+
+/// 送信量制限を設定する。空の場合は無効化する
+///
+/// `budgets`はprefix、1秒あたりのバイト数、バケットの大きさの組
+pub(crate) fn install(budgets: &[(&str, u64, Option<u64>)], max_record_bytes: Option<usize>) {
+    let mut global = BUDGET.lock().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    if budgets.is_empty() {
+        *global = None;
+        ENABLED.store(false, Ordering::Release);
+    } else {
+        let budgets = budgets
+            .iter()
+            .map(|(prefix, bps, burst)| {
+                (*prefix, *bps, burst_bytes(*bps, *burst, max_record_bytes))
+            })
+            .collect::<Vec<_>>();
+        *global = Some(ByteBudget::new(&budgets, Instant::now()));
+        ENABLED.store(true, Ordering::Release);
+    }
+}
+
+/// レコードを送信してよいか判定する
+pub(crate) fn check(category: &str, message: &str, kv: Option<&KVBorrow>) -> bool {
+    if !ENABLED.load(Ordering::Acquire) {
+        return true;
+    }
+    let size = estimate_size(message, kv);
+    let mut global = BUDGET.lock().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    match global.as_mut() {
+        Some(budget) => budget.admit(category, size, Instant::now()),
+        None => true,
+    }
+}
+
+/// 出力する時期になった集計を取り出す。送信スレッドが周期ごとに呼ぶ
+pub(crate) fn take_reports() -> Vec<BudgetReport> {
+    if !ENABLED.load(Ordering::Acquire) {
+        return vec![];
+    }
+    BUDGET
+        .lock()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+        .as_mut()
+        .map(|b| b.take_reports(Instant::now()))
+        .unwrap_or_default()
+}
+
+/// カテゴリごとの送信量の集計を返す
+pub fn category_budget_stats() -> Vec<BudgetStats> {
+    BUDGET
+        .lock()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+        .as_ref()
+        .map(|b| b.stats())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use std::collections::BTreeMap;
+
+    use super::{burst_bytes, estimate_size, prefix_matches, ByteBudget};
+    use crate::{KVBorrow, ValueBorrow};
+
+    #[test]
+    fn test_budget_admit_volume() {
+        let start = Instant::now();
+        let mut budget = ByteBudget::new(&[("blob", 10 * 1024, 10 * 1024)], start);
+        let record_size = 100;
+
+        // 1秒目で初期burst分を消費する
+        for i in 0..1000 {
+            let now = start + Duration::from_millis(i);
+            budget.admit("blob.camera", record_size, now);
+        }
+        let before = budget.stats()[0].admitted_bytes;
+
+        // 2秒目は概ね10KB分だけ通過する
+        for i in 1000..2000 {
+            let now = start + Duration::from_millis(i);
+            budget.admit("blob.camera", record_size, now);
+        }
+        let admitted = budget.stats()[0].admitted_bytes - before;
+        assert!(
+            (10 * 1024 - record_size as u64..=10 * 1024 + record_size as u64).contains(&admitted),
+            "admitted {}",
+            admitted
+        );
+        let stats = &budget.stats()[0];
+        assert_eq!(stats.admitted_records + stats.dropped_records, 2000);
+    }
+
+    #[test]
+    fn test_budget_prefix_and_report() {
+        let start = Instant::now();
+        let mut budget = ByteBudget::new(&[("blob", 100, 100), ("blob.small", 1000, 1000)], start);
+
+        // 対象外のカテゴリは常に通過する
+        assert!(budget.admit("other", 10_000, start));
+        // 区切りの途中では一致しない
+        assert!(budget.admit("blobby", 10_000, start));
+
+        // 長いprefixが優先される
+        assert!(budget.admit("blob.small.x", 500, start));
+        assert!(!budget.admit("blob.large", 500, start));
+
+        // 集計は間隔をあけて出力される
+        assert!(!budget.admit("blob.large", 500, start + Duration::from_millis(100)));
+        assert!(budget
+            .take_reports(start + Duration::from_millis(100))
+            .is_empty());
+        assert!(!budget.admit("blob.large", 500, start + Duration::from_millis(200)));
+        // 書くのをやめたカテゴリの分も周期ごとに取り出せる
+        let reports = budget.take_reports(start + Duration::from_secs(1));
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].prefix, "blob");
+        assert_eq!(reports[0].dropped_records, 3);
+        assert_eq!(reports[0].dropped_bytes, 1500);
+        assert!(budget
+            .take_reports(start + Duration::from_secs(3))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_budget_burst() {
+        let start = Instant::now();
+        let mut budget = ByteBudget::new(&[("blob", 100, 1000)], start);
+        // 1秒分より大きいレコードもバケットに収まれば通る
+        assert!(budget.admit("blob", 800, start));
+        assert!(!budget.admit("blob", 800, start));
+        // 1秒で100バイトずつ戻る
+        assert!(!budget.admit("blob", 800, start + Duration::from_secs(5)));
+        assert!(budget.admit("blob", 800, start + Duration::from_secs(7)));
+        // バケットより大きいレコードは通らない
+        assert!(!budget.admit("blob", 1001, start + Duration::from_secs(60)));
+
+        // 指定がなければ1秒分で、1レコードの上限より小さくはしない
+        assert_eq!(burst_bytes(100, None, None), 100);
+        assert_eq!(burst_bytes(100, Some(1000), None), 1000);
+        assert_eq!(burst_bytes(100, None, Some(4096)), 4096);
+        assert_eq!(burst_bytes(100, Some(1000), Some(4096)), 4096);
+        assert_eq!(burst_bytes(100, Some(8192), Some(4096)), 8192);
+    }
+
+    #[test]
+    fn test_prefix_matches() {
+        assert!(prefix_matches("blob", "blob"));
+        assert!(prefix_matches("blob", "blob.camera"));
+        assert!(!prefix_matches("blob", "blobby"));
+        assert!(!prefix_matches("blob.camera", "blob"));
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        let blob = vec![0_u8; 1024];
+        let mut kv = KVBorrow::new();
+        kv.insert("data", (&blob).into());
+        kv.insert("n", 1_u8.into());
+        assert_eq!(estimate_size("msg", Some(&kv)), 3 + 4 + 1024 + 1 + 8);
+        assert_eq!(estimate_size("msg", None), 3);
+
+        // 入れ子の値もキーと中身の長さを足す
+        let mut map = BTreeMap::new();
+        map.insert("data", ValueBorrow::Bytes(&blob));
+        map.insert("n", 1_u8.into());
+        let mut kv = KVBorrow::new();
+        kv.insert("map", ValueBorrow::Map(map));
+        kv.insert(
+            "list",
+            ValueBorrow::Array(vec![ValueBorrow::Text("abc"), 1_u8.into()]),
+        );
+        assert_eq!(
+            estimate_size("msg", Some(&kv)),
+            3 + 3 + (4 + 1024 + 1 + 8) + 4 + (3 + 8)
+        );
+    }
+}
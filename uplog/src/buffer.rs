@@ -5,6 +5,8 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use crate::ring::{ring_buffer, RingReader, RingWriter};
+
 #[derive(Debug)]
 pub(crate) struct SwapBufReader {
     buf: Vec<u8>,
@@ -23,6 +25,17 @@ impl SwapBufReader {
         self.read_cursor = 0;
     }
 
+    /// 未読のデータを参照する
+    pub(crate) fn unread(&self) -> &[u8] {
+        &self.buf[self.read_cursor..]
+    }
+
+    /// 未読のデータのうち先頭から指定の長さを読み済みにする
+    pub(crate) fn consume(&mut self, len: usize) {
+        debug_assert!(len <= self.residual_length_read());
+        self.read_cursor += min(len, self.residual_length_read());
+    }
+
     #[inline]
     fn read_from_buffer_unchecked(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         debug_assert!(!buf.is_empty());
@@ -51,15 +64,68 @@ impl Read for SwapBufReader {
     }
 }
 
+/// How the swap buffer handles a record that does not fit in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Growth {
+    /// Drops the record. The buffer keeps the size of [`crate::Builder::buffer_size`].
+    #[default]
+    Fixed,
+    /// Doubles the buffer until the record fits, up to `max` bytes.
+    /// The sender thread shrinks it back toward the initial size after quiet periods.
+    Doubling { max: usize },
+}
+
+/// 書き込みが初期サイズに収まる入れ替えがこの回数続いたら縮める
+const SHRINK_AFTER_QUIET_SWAPS: u32 = 8;
+
+/// 初期サイズを下回らない範囲で半分に縮める
+fn shrink_toward(buf: &mut Vec<u8>, initial: usize) {
+    if buf.capacity() > initial {
+        let target = (buf.capacity() / 2).max(initial).max(buf.len());
+        buf.shrink_to(target);
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct SwapBufWriter {
     buf: Vec<u8>,
+    growth: Growth,
+    /// 入れ替えてから[`LogWriter::write_record`]で書いたレコードの終わりの位置
+    ends: Vec<usize>,
 }
 
 impl SwapBufWriter {
-    pub(crate) fn new(capacity: usize) -> Self {
+    pub(crate) fn new(capacity: usize, growth: Growth) -> Self {
         Self {
             buf: Vec::with_capacity(capacity),
+            growth,
+            ends: Vec::new(),
+        }
+    }
+
+    /// 読み出し側が持ち越してよい大きさ。書き込み側が広がれる大きさまで
+    fn carry_limit(&self, capacity: usize) -> usize {
+        match self.growth {
+            Growth::Fixed => capacity,
+            Growth::Doubling { max } => max.max(capacity),
+        }
+    }
+
+    /// `additional`バイトを書くために広げる大きさ。広げられない場合はNone
+    fn grown_capacity(&self, additional: usize) -> Option<usize> {
+        match self.growth {
+            Growth::Fixed => None,
+            Growth::Doubling { max } => {
+                let need = self.buf.len().checked_add(additional)?;
+                if need > max {
+                    return None;
+                }
+                let mut capacity = self.buf.capacity().max(1);
+                while capacity < need {
+                    capacity = capacity.saturating_mul(2);
+                }
+                Some(capacity.min(max))
+            }
         }
     }
 
@@ -67,6 +133,7 @@ impl SwapBufWriter {
         unsafe {
             self.buf.set_len(0);
         }
+        self.ends.clear();
     }
 
     #[inline]
@@ -86,12 +153,24 @@ impl SwapBufWriter {
     fn spare_capacity_write(&self) -> usize {
         self.buf.capacity() - self.buf.len()
     }
+
+    /// 書き込み済みのバイト数
+    pub(crate) fn len(&self) -> usize {
+        self.buf.len()
+    }
 }
 
 impl Write for SwapBufWriter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         if buf.len() > self.spare_capacity_write() {
             use std::io::{Error, ErrorKind};
+            if let Some(capacity) = self.grown_capacity(buf.len()) {
+                // 再確保するのでunsafeなコピーは使わない
+                self.buf.reserve_exact(capacity - self.buf.len());
+                self.buf.extend_from_slice(buf);
+                crate::stats::set_buffer_capacity(self.buf.capacity());
+                return Ok(buf.len());
+            }
             Err(Error::new(
                 ErrorKind::OutOfMemory,
                 format!(
@@ -120,17 +199,32 @@ pub(crate) struct SwapBuffer {
     read: Arc<Mutex<SwapBufReader>>,
     write: Arc<Mutex<SwapBufWriter>>,
     capacity: usize,
+    growth: Growth,
+    /// 書き込みが初期サイズに収まった入れ替えの連続回数
+    quiet_swaps: u32,
 }
 
 impl SwapBuffer {
+    #[cfg(test)]
     pub(crate) fn new(capacity: usize) -> Self {
+        Self::with_growth(capacity, Growth::Fixed)
+    }
+
+    pub(crate) fn with_growth(capacity: usize, growth: Growth) -> Self {
         Self {
             capacity,
             read: Arc::new(Mutex::new(SwapBufReader::new(capacity))),
-            write: Arc::new(Mutex::new(SwapBufWriter::new(capacity))),
+            write: Arc::new(Mutex::new(SwapBufWriter::new(capacity, growth))),
+            growth,
+            quiet_swaps: 0,
         }
     }
 
+    /// 書き込まれたデータを読み出し側に移す
+    ///
+    /// 読み出し側に未読のデータが残っている場合は破棄せずに保持し、
+    /// その後ろに新しいデータを繋げる。繋げると書き込み側が広がれる大きさを超える場合は
+    /// 新しいデータをレコード単位で捨てて数える。戻り値は未読のデータの長さ
     pub(crate) fn swap(&mut self) -> usize {
         let mut wb = self
             .write
@@ -141,12 +235,37 @@ impl SwapBuffer {
             .lock()
             .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
 
-        // deref mutで中身を取り出してswapする
-        unsafe {
-            std::ptr::swap(&mut rb.buf, &mut wb.buf);
+        let written = wb.buf.len();
+        if rb.residual_length_read() > 0 {
+            // 読み残しがある場合は捨てずに先頭に残し、その後ろに新しいデータを繋げる
+            let cursor = rb.read_cursor;
+            rb.buf.drain(..cursor);
+            let limit = wb.carry_limit(self.capacity);
+            let dropped = carry_over(&mut rb, &wb, limit);
+            if dropped > 0 {
+                crate::health::record_dropped(dropped);
+            }
+        } else {
+            // deref mutで中身を取り出してswapする
+            unsafe {
+                std::ptr::swap(&mut rb.buf, &mut wb.buf);
+            }
         }
         rb.swap_reset();
         wb.swap_reset();
+        if let Growth::Doubling { .. } = self.growth {
+            // 広げた後に静かになったら少しずつ戻す
+            match written <= self.capacity {
+                true => self.quiet_swaps += 1,
+                false => self.quiet_swaps = 0,
+            }
+            if self.quiet_swaps >= SHRINK_AFTER_QUIET_SWAPS {
+                self.quiet_swaps = 0;
+                shrink_toward(&mut rb.buf, self.capacity);
+                shrink_toward(&mut wb.buf, self.capacity);
+                crate::stats::set_buffer_capacity(wb.buf.capacity());
+            }
+        }
         rb.buf.len()
     }
 
@@ -163,6 +282,105 @@ impl SwapBuffer {
     }
 }
 
+/// 書き込み側のデータを読み残しの後ろに`limit`バイトまで繋げ、捨てたレコード数を返す
+///
+/// 収まらない場合は先頭から収まるレコードまでを繋げ、残りのレコードを捨てる
+fn carry_over(rb: &mut SwapBufReader, wb: &SwapBufWriter, limit: usize) -> u64 {
+    let room = limit.saturating_sub(rb.buf.len());
+    if wb.buf.len() <= room {
+        rb.buf.extend_from_slice(&wb.buf);
+        return 0;
+    }
+    let kept = wb.ends.partition_point(|&end| end <= room);
+    let cut = kept.checked_sub(1).map_or(0, |i| wb.ends[i]);
+    rb.buf.extend_from_slice(&wb.buf[..cut]);
+    (wb.ends.len() - kept) as u64
+}
+
+/// 送信スレッドが読み出すバッファー
+pub(crate) enum LogBuffer {
+    Swap(SwapBuffer),
+    /// 書き込むスレッドが1つの場合。折り返した範囲は送るまで手元に移して持つ
+    Ring(RingReader, Vec<u8>),
+}
+
+impl LogBuffer {
+    /// 書き込み側と組にして作る
+    ///
+    /// `growth`はswap bufferだけに適用し、single producerのリングバッファーは固定長のまま
+    pub(crate) fn new(capacity: usize, single_producer: bool, growth: Growth) -> (Self, LogWriter) {
+        if single_producer {
+            let (writer, reader) = ring_buffer(capacity);
+            (Self::Ring(reader, Vec::new()), LogWriter::Ring(writer))
+        } else {
+            let buf = SwapBuffer::with_growth(capacity, growth);
+            let writer = buf.get_writer();
+            (Self::Swap(buf), LogWriter::Swap(writer))
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        match self {
+            Self::Swap(x) => x.capacity(),
+            Self::Ring(x, _) => x.capacity(),
+        }
+    }
+
+    /// 1メッセージで送る上限。広げたバッファーの中身はこの大きさに分けて送る
+    pub(crate) fn message_limit(&self) -> Option<usize> {
+        match self {
+            Self::Swap(x) if x.growth != Growth::Fixed => Some(x.capacity()),
+            _ => None,
+        }
+    }
+
+    /// 書き込まれたデータを読み出し側に移し、未読のデータを`f`に渡す
+    ///
+    /// `f`は読み済みにする長さを返す。残りは次回に先頭から渡す
+    pub(crate) fn read_with<F: FnOnce(&[u8]) -> usize>(&mut self, f: F) {
+        match self {
+            Self::Swap(buf) => {
+                buf.swap();
+                let reader = buf.get_reader();
+                let mut reader = reader.lock().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+                let len = f(reader.unread());
+                reader.consume(len);
+            }
+            Self::Ring(reader, pending) => reader.read_with(pending, f),
+        }
+    }
+}
+
+impl From<SwapBuffer> for LogBuffer {
+    fn from(buf: SwapBuffer) -> Self {
+        Self::Swap(buf)
+    }
+}
+
+/// ログを出力するスレッドが書き込む側
+pub(crate) enum LogWriter {
+    Swap(Arc<Mutex<SwapBufWriter>>),
+    Ring(RingWriter),
+}
+
+impl LogWriter {
+    /// レコード1つ分を書き込み、書き込み済みのバイト数を返す
+    ///
+    /// 収まらない場合は途中まで書かずにNoneを返す
+    pub(crate) fn write_record(&self, buf: &[u8]) -> Option<usize> {
+        match self {
+            Self::Swap(writer) => {
+                let mut writer = writer.lock().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+                writer.write_all(buf).ok()?;
+                let end = writer.len();
+                writer.ends.push(end);
+                Some(end)
+            }
+            Self::Ring(writer) => writer.push(buf),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -172,7 +390,7 @@ mod tests {
         thread,
     };
 
-    use crate::buffer::SwapBuffer;
+    use crate::buffer::{carry_over, Growth, LogWriter, SwapBufReader, SwapBuffer};
 
     // control test sequence
     #[derive(Debug)]
@@ -274,6 +492,173 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_swap_keeps_unread_data() {
+        let mut swbuf = SwapBuffer::new(64);
+        let reader = swbuf.get_reader();
+        let writer = swbuf.get_writer();
+        let first = "0123456789abcdef".as_bytes();
+        let second = "ghijklmn".as_bytes();
+
+        writer.lock().unwrap().write_all(first).unwrap();
+        assert_eq!(swbuf.swap(), first.len());
+
+        // 半分だけ読む
+        let mut read_buf = [0; 8];
+        assert_eq!(reader.lock().unwrap().read(&mut read_buf).unwrap(), 8);
+        assert_eq!(&read_buf, &first[..8]);
+
+        // 読み残しの後ろに新しいデータが続く
+        writer.lock().unwrap().write_all(second).unwrap();
+        assert_eq!(swbuf.swap(), 8 + second.len());
+        let mut rest = Vec::new();
+        reader.lock().unwrap().read_to_end(&mut rest).unwrap();
+        assert_eq!(&rest[..8], &first[8..]);
+        assert_eq!(&rest[8..], second);
+
+        // 書き込み側は空になっている
+        assert_eq!(swbuf.swap(), 0);
+    }
+
+    #[test]
+    fn test_swap_bounds_unread_data() {
+        let mut swbuf = SwapBuffer::new(64);
+        let reader = swbuf.get_reader();
+        let writer = LogWriter::Swap(swbuf.get_writer());
+
+        // 読み出さずに入れ替え続けても初期サイズを超えて持ち越さない
+        for x in 0..3 {
+            writer.write_record(&[x; 16]).unwrap();
+        }
+        assert_eq!(swbuf.swap(), 48);
+        // 収まる先頭の1レコードだけを繋げる
+        for x in 3..6 {
+            writer.write_record(&[x; 10]).unwrap();
+        }
+        assert_eq!(swbuf.swap(), 58);
+        writer.write_record(&[6; 10]).unwrap();
+        assert_eq!(swbuf.swap(), 58);
+
+        let mut rest = Vec::new();
+        reader.lock().unwrap().read_to_end(&mut rest).unwrap();
+        let expected = [[0_u8; 16], [1; 16], [2; 16]].concat();
+        assert_eq!(rest, [expected, vec![3; 10]].concat());
+    }
+
+    #[test]
+    fn test_carry_over_drops_whole_records() {
+        let swbuf = SwapBuffer::new(64);
+        let writer = LogWriter::Swap(swbuf.get_writer());
+        for x in 0..4 {
+            writer.write_record(&[x; 10]).unwrap();
+        }
+        let wb = swbuf.get_writer();
+        let wb = wb.lock().unwrap();
+
+        let mut rb = SwapBufReader::new(64);
+        rb.buf.extend_from_slice(&[9; 35]);
+        assert_eq!(carry_over(&mut rb, &wb, 64), 2);
+        assert_eq!(rb.buf.len(), 55);
+        assert_eq!(&rb.buf[35..], [[0_u8; 10], [1; 10]].concat());
+
+        // 1つも収まらなければすべて捨てる
+        let mut rb = SwapBufReader::new(64);
+        rb.buf.extend_from_slice(&[9; 60]);
+        assert_eq!(carry_over(&mut rb, &wb, 64), 4);
+        assert_eq!(rb.buf.len(), 60);
+
+        // すべて収まれば捨てない
+        let mut rb = SwapBufReader::new(64);
+        assert_eq!(carry_over(&mut rb, &wb, 64), 0);
+        assert_eq!(rb.buf.len(), 40);
+    }
+
+    #[test]
+    fn test_swap_buffer_slow_drain() {
+        let test_data = "Nkmm Drawings\n".as_bytes();
+        let mut swbuf = SwapBuffer::new(1024);
+        let reader = swbuf.get_reader();
+        let writer = swbuf.get_writer();
+        let mut received = Vec::new();
+
+        // 書き込みより遅く読み出しても失われない
+        for _ in 0..20 {
+            for _ in 0..3 {
+                writer.lock().unwrap().write_all(test_data).unwrap();
+            }
+            swbuf.swap();
+            let mut read_buf = [0; 20];
+            let size = reader.lock().unwrap().read(&mut read_buf).unwrap();
+            received.extend_from_slice(&read_buf[..size]);
+        }
+        swbuf.swap();
+        reader.lock().unwrap().read_to_end(&mut received).unwrap();
+
+        assert_eq!(received.len(), test_data.len() * 60);
+        for chunk in received.chunks(test_data.len()) {
+            assert_eq!(chunk, test_data);
+        }
+    }
+
+    #[test]
+    fn test_growth_burst() {
+        let record = [7_u8; 100];
+        // 固定長では初期サイズを超えると書けない
+        let fixed = SwapBuffer::new(1024);
+        let writer = fixed.get_writer();
+        let written = (0..100)
+            .filter(|_| writer.lock().unwrap().write_all(&record).is_ok())
+            .count();
+        assert_eq!(written, 10);
+
+        let mut swbuf = SwapBuffer::with_growth(1024, Growth::Doubling { max: 16 * 1024 });
+        let reader = swbuf.get_reader();
+        let writer = swbuf.get_writer();
+        for _ in 0..100 {
+            writer.lock().unwrap().write_all(&record).unwrap();
+        }
+        assert_eq!(writer.lock().unwrap().buf.capacity(), 16 * 1024);
+        // 上限を超える分は書けない
+        let large = vec![0_u8; 8 * 1024];
+        assert!(writer.lock().unwrap().write_all(&large).is_err());
+
+        assert_eq!(swbuf.swap(), 100 * record.len());
+        let mut received = Vec::new();
+        reader.lock().unwrap().read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), 100 * record.len());
+        assert!(received.iter().all(|x| *x == 7));
+    }
+
+    #[test]
+    fn test_growth_shrink_after_quiet() {
+        let initial = 1024;
+        let mut swbuf = SwapBuffer::with_growth(initial, Growth::Doubling { max: 64 * 1024 });
+        let reader = swbuf.get_reader();
+        let writer = swbuf.get_writer();
+        let capacities = || {
+            (
+                reader.lock().unwrap().buf.capacity(),
+                writer.lock().unwrap().buf.capacity(),
+            )
+        };
+        writer.lock().unwrap().write_all(&[1; 20_000]).unwrap();
+        swbuf.swap();
+        reader.lock().unwrap().read_to_end(&mut Vec::new()).unwrap();
+        assert!(capacities().0 >= 20_000);
+
+        // 初期サイズに収まる書き込みが続けば縮める
+        let mut swaps = 0;
+        while capacities() != (initial, initial) {
+            writer.lock().unwrap().write_all(&[2; 100]).unwrap();
+            swbuf.swap();
+            reader.lock().unwrap().read_to_end(&mut Vec::new()).unwrap();
+            swaps += 1;
+            assert!(swaps < 100, "not shrunk {:?}", capacities());
+        }
+        // 一度に戻さず半分ずつ縮める
+        assert!(swaps > super::SHRINK_AFTER_QUIET_SWAPS as usize);
+    }
+
     #[test]
     fn test_swap() {
         {
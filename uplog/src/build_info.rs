@@ -0,0 +1,138 @@
+//! ビルド情報
+//!
+//! どのバイナリが書いたセッションかを後から調べられるように、
+//! [`crate::build_info!`]で集めた値をハンドシェイクの[`crate::protocol::BUILD_INFO_HEADER`]と
+//! 最初のレコードで送る
+use std::sync::RwLock;
+
+use crate::{KVBorrow, Level, MetadataBorrow, RecordBorrow, Value, ValueBorrow, KV};
+
+/// category of the record written by [`crate::Builder::with_build_info`]
+pub const BUILD_CATEGORY: &str = "uplog.build";
+
+/// Target triple uplog was compiled for, the same as the one of the application.
+pub const TARGET: &str = env!("UPLOG_TARGET");
+
+/// 接続のたびにヘッダーに書く
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+static BUILD_INFO: RwLock<Option<KV>> = RwLock::new(None);
+
+#[doc(hidden)]
+pub fn __build_info(
+    name: &str,
+    version: &str,
+    git_hash: Option<&str>,
+    profile: Option<&str>,
+) -> KV {
+    let mut kv = KV::new();
+    kv.insert("name".to_string(), name.into());
+    kv.insert("version".to_string(), version.into());
+    if let Some(git_hash) = git_hash {
+        kv.insert("git_hash".to_string(), git_hash.into());
+    }
+    if let Some(profile) = profile {
+        kv.insert("profile".to_string(), profile.into());
+    }
+    if !TARGET.is_empty() {
+        kv.insert("target".to_string(), TARGET.into());
+    }
+    kv
+}
+
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+pub(crate) fn install(info: Option<KV>) {
+    *BUILD_INFO
+        .write()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK) = info;
+}
+
+/// ヘッダーの値。文字列の値だけをフォームの形式で並べる
+#[cfg(feature = "client-ws")]
+pub(crate) fn header_value() -> Option<String> {
+    let info = BUILD_INFO
+        .read()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    let info = info.as_ref()?;
+    let mut form = url::form_urlencoded::Serializer::new(String::new());
+    for (key, value) in info.iter() {
+        if let Value::Text(value) = value {
+            form.append_pair(key, value);
+        }
+    }
+    Some(form.finish())
+}
+
+/// 最初のレコードとして書く。レベルやカテゴリの設定では落とさない
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+pub(crate) fn emit_record(info: &KV) {
+    emit(info, crate::logger::submit_direct)
+}
+
+fn emit<F: FnOnce(&RecordBorrow)>(info: &KV, f: F) {
+    let kv = info
+        .iter()
+        .map(|(k, v)| (k.as_str(), ValueBorrow::from(v)))
+        .collect::<KVBorrow>();
+    let text = |key: &str| match info.get(key) {
+        Some(Value::Text(x)) => x.as_str(),
+        _ => "",
+    };
+    let message = format!("{} {}", text("name"), text("version"));
+    let record = RecordBorrow {
+        metadata: MetadataBorrow::new(Level::Info, module_path!()),
+        elapsed: crate::session::elapsed(),
+        category: BUILD_CATEGORY,
+        module_path: Some(module_path!()),
+        file: Some(file!()),
+        line: Some(line!()),
+        message: message.trim(),
+        kv: Some(kv),
+    };
+    f(&record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emit, BUILD_CATEGORY, TARGET};
+    use crate::{Record, Value};
+
+    #[test]
+    fn test_build_info_macro() {
+        let info = crate::build_info!();
+        assert_eq!(info["name"], Value::from("uplog"));
+        assert_eq!(info["version"], Value::from(env!("CARGO_PKG_VERSION")));
+        assert!(!TARGET.is_empty());
+        assert_eq!(info["target"], Value::from(TARGET));
+        assert_eq!(
+            info.contains_key("git_hash"),
+            option_env!("GIT_HASH").is_some()
+        );
+
+        let mut record = None;
+        emit(&info, |x| record = Some(Record::from(x)));
+        let record = record.unwrap();
+        assert_eq!(record.category, BUILD_CATEGORY);
+        assert_eq!(
+            record.message,
+            format!("uplog {}", env!("CARGO_PKG_VERSION"))
+        );
+        assert_eq!(record.kv.unwrap(), info);
+    }
+
+    #[cfg(feature = "client-ws")]
+    #[test]
+    fn test_build_info_header() {
+        let mut info = crate::build_info!();
+        info.insert("profile".to_string(), "release & debug".into());
+        super::install(Some(info));
+        let value = super::header_value().unwrap();
+        assert!(value.contains("profile=release+%26+debug"), "{}", value);
+        let pairs = url::form_urlencoded::parse(value.as_bytes())
+            .into_owned()
+            .collect::<Vec<_>>();
+        assert!(pairs.contains(&("name".to_string(), "uplog".to_string())));
+        assert!(pairs.contains(&("profile".to_string(), "release & debug".to_string())));
+        super::install(None);
+        assert_eq!(super::header_value(), None);
+    }
+}
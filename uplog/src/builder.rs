@@ -0,0 +1,1213 @@
+//! 送信先をwebsocketのサーバーとする設定
+
+use std::{sync::Arc, time::Duration};
+
+use url::Url;
+
+use crate::{
+    buffer::Growth,
+    category::CategoryPattern,
+    client::{
+        ClientConfig, Connector, ErrorCallback, LogClient, NiceMode, UrgentFlush,
+        DEFAULT_BUFFER_SIZE, DEFAULT_PROTOCOL_ERROR_BUDGET, DEFAULT_SWAP_DURATION,
+        DEFAULT_URGENT_INTERVAL, MIN_BUFFER_SIZE,
+    },
+    error::{BuilderError, InitError},
+    history::RingFileSink,
+    logger::{set_logger, SenderHandle},
+    precision::Precision,
+    preset::Preset,
+    protocol::{Codec, SESSION_QUERY},
+    redact::{RedactFn, Redactor},
+    session_init,
+    stats::{ObserverConfig, StatsObserver},
+    transport::Transport,
+    watchdog::DEFAULT_WATCHDOG_TICKS,
+    Level, Ordering, Value, INGEST_PATH, KV,
+};
+
+/// Port of the server, and the default of [`Builder::port`].
+pub const WS_DEFAULT_PORT: u16 = 8040;
+
+/// initialize the global logger
+/// # Example
+///
+/// ```
+/// /// initialize log
+/// uplog::try_init().unwrap();
+///
+/// // your program...
+///
+/// // Force recommend call finally flush()
+/// uplog::flush();
+/// ```
+pub fn try_init() -> Result<(), InitError> {
+    log::debug!("try_init");
+    let (logger, handle) = Builder::default().build()?;
+    set_logger(logger, handle)?;
+    Ok(())
+}
+
+/// initialize the global logger with logging server host
+///
+/// # Example
+///
+/// ```
+/// uplog::try_init_with_host("localhost").unwrap();
+/// ```
+pub fn try_init_with_host(host: &str) -> Result<(), InitError> {
+    log::debug!("try_init_with_host");
+    let (logger, handle) = Builder::default().host(host).build()?;
+    set_logger(logger, handle)?;
+    Ok(())
+}
+
+pub(crate) fn try_init_with_builder(builder: Builder) -> Result<(), InitError> {
+    log::debug!("try_init_with_builder");
+    let build_info = builder.build_info.clone();
+    let (logger, handle) = builder.build()?;
+    set_logger(logger, handle)?;
+    if let Some(info) = build_info.as_ref() {
+        crate::build_info::emit_record(info);
+    }
+    Ok(())
+}
+
+/// プリセットより優先する、個別に設定した項目
+const EXPLICIT_BUFFER_SIZE: u8 = 1;
+const EXPLICIT_GROWTH: u8 = 1 << 1;
+const EXPLICIT_DURATION: u8 = 1 << 2;
+const EXPLICIT_DEFLATE: u8 = 1 << 3;
+const EXPLICIT_NICE: u8 = 1 << 4;
+const EXPLICIT_WATCHDOG: u8 = 1 << 5;
+
+/// initialize the global logger with builder
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// uplog::Builder::default()
+///     .buffer_size(1024)
+///     .host("localhost")
+///     .port(8080)
+///     .duration(Duration::from_millis(1000))
+///     .category_byte_budget("camera.raw", 10 * 1024)
+///     .try_init()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Builder<'b> {
+    secure_connection: bool,
+    host: &'b str,
+    port: u16,
+    path: &'b str,
+    pub(crate) swap_buffer_size: usize,
+    buffer_growth: Growth,
+    swap_duration: Duration,
+    category_budgets: Vec<(&'b str, u64, Option<u64>)>,
+    nice_mode: bool,
+    nice_bytes_per_tick: usize,
+    nice_yield: bool,
+    on_error: Option<ErrorCallback>,
+    redactors: Vec<Redactor>,
+    category_filters: Vec<CategoryPattern>,
+    stats_observer: Option<ObserverConfig>,
+    level: Level,
+    deflate: bool,
+    dictionary: bool,
+    single_producer: bool,
+    max_record_bytes: Option<usize>,
+    oversize_surrogate: bool,
+    max_kv_entries: Option<usize>,
+    max_key_len: Option<usize>,
+    time_precision: Option<Precision>,
+    clock_offset_stamp: Option<Duration>,
+    emit_deltas: bool,
+    capture_panics: bool,
+    pub(crate) blocking_timeout: Duration,
+    watchdog_ticks: u32,
+    protocol_error_budget: u32,
+    priority_level: Option<Level>,
+    urgent_level: Option<Level>,
+    urgent_interval: Duration,
+    spill_path: Option<&'b std::path::Path>,
+    history: Option<Arc<RingFileSink>>,
+    flush_deadline: Option<Duration>,
+    ordering: Ordering,
+    build_info: Option<KV>,
+    preset: Option<Preset>,
+    /// プリセットで上書きしない、個別に設定した項目
+    explicit: u8,
+    #[cfg(all(unix, feature = "uds"))]
+    pub(crate) uds_path: Option<&'b std::path::Path>,
+}
+
+impl<'b> Builder<'b> {
+    const DEFAULT_NICE_BYTES_PER_TICK: usize = 64 * 1024;
+    const NICE_CHUNK_SIZE: usize = 8 * 1024;
+    const DEFAULT_BLOCKING_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Sets the swap buffer size.
+    ///
+    /// Maximum amount of buffer that can be stored until it is sent to the server
+    /// The amount actually reserved is twice this specified value (for sending and writing).
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.swap_buffer_size = size;
+        self.explicit |= EXPLICIT_BUFFER_SIZE;
+        self
+    }
+
+    /// Sets how the buffer handles a record that does not fit in [`Builder::buffer_size`].
+    ///
+    /// With [`Growth::Doubling`] the buffer grows up to `max` bytes instead of dropping the
+    /// record, and is sent in messages of at most the buffer size so the server limit still holds.
+    /// Ignored with [`Builder::single_producer`], whose ring buffer has a fixed size.
+    pub fn buffer_growth(mut self, growth: Growth) -> Self {
+        self.buffer_growth = growth;
+        self.explicit |= EXPLICIT_GROWTH;
+        self
+    }
+
+    /// Sets the swap suration.
+    ///
+    /// Swap the buffer every cycle specified here
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.swap_duration = duration;
+        self.explicit |= EXPLICIT_DURATION;
+        self
+    }
+
+    /// Sets how many swap cycles the sender thread may stall before it is restarted.
+    ///
+    /// When the sender makes no progress for this many [`Builder::duration`] periods, e.g.
+    /// because a write blocks on a dead peer, the connection is shut down and a new sender
+    /// thread continues with the same buffer. Restarts are counted in
+    /// [`crate::LoggerStats::sender_restarts`]. `0` disables the watchdog. Defaults to 20.
+    pub fn sender_watchdog(mut self, ticks: u32) -> Self {
+        self.watchdog_ticks = ticks;
+        self.explicit |= EXPLICIT_WATCHDOG;
+        self
+    }
+
+    /// Applies the settings of `preset`.
+    ///
+    /// Settings passed to their own setter, before or after this call, keep their value.
+    /// See [`Preset`] for what each preset sets.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use uplog::{Builder, Preset};
+    ///
+    /// let builder = Builder::default()
+    ///     .duration(Duration::from_millis(50))
+    ///     .preset(Preset::HighThroughput);
+    /// assert_eq!(builder.describe()["swap_duration_ms"], uplog::Value::U64(50));
+    /// ```
+    pub fn preset(mut self, preset: Preset) -> Self {
+        let values = preset.values();
+        let keep = |bit: u8| self.explicit & bit != 0;
+        if !keep(EXPLICIT_BUFFER_SIZE) {
+            self.swap_buffer_size = values.buffer_size;
+        }
+        if !keep(EXPLICIT_GROWTH) {
+            self.buffer_growth = values.growth;
+        }
+        if !keep(EXPLICIT_DURATION) {
+            self.swap_duration = values.swap_duration;
+        }
+        if !keep(EXPLICIT_DEFLATE) {
+            self.deflate = values.deflate;
+        }
+        if !keep(EXPLICIT_NICE) {
+            self.nice_mode = values.nice_mode;
+        }
+        if !keep(EXPLICIT_WATCHDOG) {
+            self.watchdog_ticks = values.watchdog_ticks;
+        }
+        self.preset = Some(preset);
+        self
+    }
+
+    /// Returns the effective configuration, e.g. to log it as the first record.
+    ///
+    /// Durations are in milliseconds and the overflow growth is `"fixed"` or the maximum size.
+    pub fn describe(&self) -> KV {
+        let mut kv = KV::new();
+        let mut set = |key: &str, value: Value| {
+            kv.insert(key.to_string(), value);
+        };
+        set(
+            "preset",
+            self.preset.map_or(Value::Null, |x| x.as_str().into()),
+        );
+        set("host", self.host.into());
+        set("port", self.port.into());
+        set("path", self.path.into());
+        set("tls", self.secure_connection.into());
+        set("buffer_size", (self.swap_buffer_size as u64).into());
+        set(
+            "buffer_growth",
+            match self.buffer_growth {
+                Growth::Fixed => "fixed".into(),
+                Growth::Doubling { max } => (max as u64).into(),
+            },
+        );
+        set(
+            "swap_duration_ms",
+            (self.swap_duration.as_millis() as u64).into(),
+        );
+        set("deflate", self.deflate.into());
+        set("dictionary", self.dictionary.into());
+        set("single_producer", self.single_producer.into());
+        set("nice_mode", self.nice_mode.into());
+        set("level", format!("{:?}", self.level).into());
+        set("sender_watchdog_ticks", self.watchdog_ticks.into());
+        set("protocol_error_budget", self.protocol_error_budget.into());
+        set(
+            "priority_level",
+            self.priority_level
+                .map_or(Value::Null, |x| format!("{:?}", x).into()),
+        );
+        set(
+            "urgent_level",
+            self.urgent_level
+                .map_or(Value::Null, |x| format!("{:?}", x).into()),
+        );
+        set(
+            "urgent_interval_ms",
+            (self.urgent_interval.as_millis() as u64).into(),
+        );
+        set(
+            "spill_file",
+            self.spill_path
+                .map_or(Value::Null, |x| x.display().to_string().into()),
+        );
+        set(
+            "history_capacity",
+            self.history
+                .as_ref()
+                .map_or(Value::Null, |x| x.capacity().into()),
+        );
+        set(
+            "flush_deadline_ms",
+            self.flush_deadline
+                .map_or(Value::Null, |x| (x.as_millis() as u64).into()),
+        );
+        set("ordering", self.ordering.as_str().into());
+        set(
+            "build_info",
+            self.build_info.clone().map_or(Value::Null, Value::Map),
+        );
+        set("emit_deltas", self.emit_deltas.into());
+        set(
+            "max_kv_entries",
+            self.max_kv_entries
+                .map_or(Value::Null, |x| (x as u64).into()),
+        );
+        set(
+            "max_key_len",
+            self.max_key_len.map_or(Value::Null, |x| (x as u64).into()),
+        );
+        #[cfg(all(unix, feature = "uds"))]
+        set(
+            "uds_path",
+            self.uds_path
+                .map_or(Value::Null, |x| x.display().to_string().into()),
+        );
+        kv
+    }
+
+    /// Sets how many unreadable messages from the server are ignored per minute.
+    ///
+    /// Messages that are not a known server message, e.g. from a newer or broken server, are
+    /// counted and skipped. Past this limit the client stops reading the connection, reports
+    /// [`crate::Error::ProtocolErrorBudget`] to [`Builder::on_error`] once and keeps sending.
+    /// Reading resumes after a reconnect. Defaults to 10.
+    pub fn protocol_error_budget(mut self, per_minute: u32) -> Self {
+        self.protocol_error_budget = per_minute;
+        self
+    }
+
+    /// Sends records at or above `level` ahead of the queued others.
+    ///
+    /// They go into a small separate buffer that the sender thread drains first on every tick,
+    /// even in [`Builder::nice_mode`], and a record written while nothing is left to send is
+    /// sent right away without waiting for the tick. Records keep their order within each
+    /// buffer and their original elapsed time, so the server can sort them again.
+    /// When the priority buffer is full, records go to the normal buffer.
+    pub fn priority_level(mut self, level: Level) -> Self {
+        self.priority_level = Some(level);
+        self
+    }
+
+    /// Sends records at or above `level` right after they are written, without waiting for the
+    /// tick, so they reach the server even if the process dies soon after.
+    ///
+    /// The record is written to the buffer as usual and the sender thread is woken to send
+    /// everything buffered so far, so records keep their order and nothing is sent twice.
+    /// The sender is woken at most once per [`Builder::urgent_interval`]; a record written
+    /// sooner is sent with the next tick or the next urgent send.
+    pub fn urgent_level(mut self, level: Level) -> Self {
+        self.urgent_level = Some(level);
+        self
+    }
+
+    /// Sets the minimum time between two sends triggered by [`Builder::urgent_level`].
+    /// Defaults to 50ms.
+    pub fn urgent_interval(mut self, interval: Duration) -> Self {
+        self.urgent_interval = interval;
+        self
+    }
+
+    /// Appends the records left unsent when [`crate::flush_with_deadline`] gives up to `path`.
+    ///
+    /// The file is created when needed and is a CBOR sequence of records, so it can be imported
+    /// into the server later.
+    pub fn spill_file(mut self, path: &'b std::path::Path) -> Self {
+        self.spill_path = Some(path);
+        self
+    }
+
+    /// Keeps every record written in `history` too, whether or not the client is connected.
+    ///
+    /// The server can ask for a range of it with [`crate::protocol::CMD_UPLOAD_HISTORY`],
+    /// which is sent as a separate session.
+    pub fn history(mut self, history: RingFileSink) -> Self {
+        self.history = Some(Arc::new(history));
+        self
+    }
+
+    /// Makes [`crate::FlushGuard`] wait for the sender thread at most `deadline` when dropped,
+    /// and spill the rest to [`Builder::spill_file`].
+    pub fn flush_deadline(mut self, deadline: Duration) -> Self {
+        self.flush_deadline = Some(deadline);
+        self
+    }
+
+    /// Sets the order of the records sent from several threads. Defaults to [`Ordering::Arrival`].
+    ///
+    /// With [`Ordering::Timestamp`] the sender thread sorts the records of each swap period by
+    /// elapsed before sending them, so every message is in elapsed order.
+    /// Records are not sorted across periods: a record whose thread took its timestamp before a
+    /// swap but wrote it after the swap is sent in the next period, earlier than records already
+    /// sent. Records of [`Builder::priority_level`] are not sorted either.
+    /// Each record takes 12 more bytes of the buffer.
+    pub fn ordering(mut self, ordering: Ordering) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
+    /// Sends `info`, usually [`crate::build_info!`], in the handshake of every connection
+    /// and as the first record with the category [`crate::BUILD_CATEGORY`].
+    ///
+    /// The server keeps it in the session metadata, so a session tells which binary wrote it.
+    ///
+    /// ```no_run
+    /// uplog::Builder::default()
+    ///     .with_build_info(uplog::build_info!())
+    ///     .try_init()
+    ///     .unwrap();
+    /// ```
+    pub fn with_build_info(mut self, info: KV) -> Self {
+        self.build_info = Some(info);
+        self
+    }
+
+    /// Sets the server host name
+    pub fn host(mut self, host: &'b str) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// Connects with `wss://` instead of `ws://`.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, enable: bool) -> Self {
+        self.secure_connection = enable;
+        self
+    }
+
+    /// Sets the server port
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Sets the path of the server to send records to. Defaults to [`crate::INGEST_PATH`].
+    ///
+    /// A server listening on several paths can label the sessions by the path.
+    pub fn path(mut self, path: &'b str) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Limits the bytes per second sent for categories starting with `prefix`.
+    ///
+    /// Records over the budget are dropped and counted,
+    /// and a summary record is written periodically under [`crate::BUDGET_CATEGORY`].
+    /// The size is estimated from the message and kv text/bytes lengths.
+    ///
+    /// Up to one second of budget can be sent at once, so without [`Builder::max_record_bytes`]
+    /// a record larger than `bytes_per_second` is always dropped. With a maximum record size
+    /// the burst is raised to it, and a large record passes once the budget has refilled.
+    /// See [`Builder::category_byte_budget_with_burst`] to allow larger bursts.
+    pub fn category_byte_budget(mut self, prefix: &'b str, bytes_per_second: u64) -> Self {
+        self.category_budgets.push((prefix, bytes_per_second, None));
+        self
+    }
+
+    /// Limits the bytes per second like [`Builder::category_byte_budget`], allowing up to
+    /// `burst_bytes` to be sent at once.
+    ///
+    /// A record larger than the burst is always dropped. A burst smaller than
+    /// [`Builder::max_record_bytes`] is raised to it, so that every record that can be written
+    /// also fits the budget.
+    pub fn category_byte_budget_with_burst(
+        mut self,
+        prefix: &'b str,
+        bytes_per_second: u64,
+        burst_bytes: u64,
+    ) -> Self {
+        self.category_budgets
+            .push((prefix, bytes_per_second, Some(burst_bytes)));
+        self
+    }
+
+    /// Sets the minimum level written.
+    ///
+    /// It can be changed at runtime by [`crate::set_level`] or a command from the server.
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Offers deflate compressed messages to the server.
+    ///
+    /// Servers that do not support it receive uncompressed CBOR as before.
+    pub fn deflate(mut self, deflate: bool) -> Self {
+        self.deflate = deflate;
+        self.explicit |= EXPLICIT_DEFLATE;
+        self
+    }
+
+    /// Offers the dictionary encoding of [`crate::wire`] to the server.
+    ///
+    /// Target, category, module path and file strings are sent once per connection
+    /// and referred to by id afterwards. The server restores the records before storing them.
+    /// Servers that do not support it receive plain CBOR as before.
+    /// Deflate is preferred by the server when both are offered.
+    pub fn dictionary(mut self, dictionary: bool) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+
+    /// Uses a lock-free ring buffer for applications logging from a single thread.
+    ///
+    /// Writing a record does not take a mutex and the sender thread reads without swapping.
+    /// Logging from other threads still works but they wait for each other.
+    /// Only [`Builder::buffer_size`] is reserved instead of twice of it.
+    pub fn single_producer(mut self, enable: bool) -> Self {
+        self.single_producer = enable;
+        self
+    }
+
+    /// Rejects records whose estimated size exceeds `size` before serializing them.
+    ///
+    /// The size is estimated by [`crate::estimate_record_size`] and rejected records are
+    /// counted in [`crate::LoggerStats::records_rejected_oversize`].
+    /// Records larger than the buffer are dropped after serialization even without this limit.
+    pub fn max_record_bytes(mut self, size: usize) -> Self {
+        self.max_record_bytes = Some(size);
+        self
+    }
+
+    /// Writes a record with the metadata and the estimated size in place of a rejected one.
+    ///
+    /// See [`Builder::max_record_bytes`].
+    pub fn oversize_surrogate(mut self, enable: bool) -> Self {
+        self.oversize_surrogate = enable;
+        self
+    }
+
+    /// Keeps at most `max` key-value entries of a record.
+    ///
+    /// Entries are kept in key order and the rest are dropped. The number of dropped entries is
+    /// written as [`crate::KV_TRUNCATED_KEY`], which does not count toward the limit.
+    /// A record within the limit is not copied.
+    pub fn max_kv_entries(mut self, max: usize) -> Self {
+        self.max_kv_entries = Some(max);
+        self
+    }
+
+    /// Truncates keys longer than `max` bytes.
+    ///
+    /// Truncated keys end with [`crate::KEY_TRUNCATED_MARKER`] and fit in `max` bytes with it.
+    /// When two keys are truncated to the same name, the first is kept and the other is counted
+    /// as dropped, see [`Builder::max_kv_entries`].
+    pub fn max_key_len(mut self, max: usize) -> Self {
+        self.max_key_len = Some(max);
+        self
+    }
+
+    /// Writes `elapsed` as a single integer in `precision` instead of seconds and nanoseconds.
+    ///
+    /// Values below the precision are truncated.
+    /// The precision is offered in the websocket handshake. When the server does not accept it,
+    /// the records are converted back before sending, so older servers keep working.
+    /// Records given to a custom [`Transport`] keep the integer form.
+    pub fn time_precision(mut self, precision: Precision) -> Self {
+        self.time_precision = Some(precision);
+        self
+    }
+
+    /// Adds [`crate::CLOCK_OFFSET_KEY`] with the offset from the server clock in milliseconds
+    /// to records while the offset is larger than `threshold`.
+    ///
+    /// The offset is measured from the acks of servers that send their time, and is also
+    /// reported by [`crate::health`] and [`crate::stats_snapshot`].
+    pub fn clock_offset_stamp(mut self, threshold: Duration) -> Self {
+        self.clock_offset_stamp = Some(threshold);
+        self
+    }
+
+    /// Adds [`crate::DELTA_KEY`] with the microseconds since the previous record of the same
+    /// category to each record.
+    ///
+    /// The first record of a category has no delta. The last time is kept for the 256 most
+    /// recently used categories, so a category unused for a while starts over.
+    pub fn emit_deltas(mut self, enable: bool) -> Self {
+        self.emit_deltas = enable;
+        self
+    }
+
+    /// Records panics as Error records with [`crate::capture_panics`] when the client starts.
+    pub fn capture_panics(mut self, enable: bool) -> Self {
+        self.capture_panics = enable;
+        self
+    }
+
+    /// Sets the time [`crate::send_records_blocking`] and [`crate::with_blocking_logger`] wait
+    /// for the connection, the send and the close handshake altogether. Defaults to 5 seconds.
+    pub fn blocking_timeout(mut self, timeout: Duration) -> Self {
+        self.blocking_timeout = timeout;
+        self
+    }
+
+    /// Sends to a server on the same host through a Unix domain socket instead of the websocket.
+    ///
+    /// The server must listen on the path with `--uds-path`.
+    /// The host, port and deflate settings are ignored.
+    #[cfg(all(unix, feature = "uds"))]
+    pub fn uds_path(mut self, path: &'b std::path::Path) -> Self {
+        self.uds_path = Some(path);
+        self
+    }
+
+    /// Only records whose category matches one of the added patterns are written.
+    ///
+    /// All records are written if no pattern is added.
+    ///
+    /// ```
+    /// let pattern = uplog::CategoryPattern::new("net.*.rx").unwrap();
+    /// uplog::Builder::default().category_filter(pattern);
+    /// ```
+    pub fn category_filter(mut self, pattern: CategoryPattern) -> Self {
+        self.category_filters.push(pattern);
+        self
+    }
+
+    /// Enables the self-throttling mode for embedded targets.
+    ///
+    /// The sender thread runs with a lower priority and sends at most
+    /// [`Builder::nice_bytes_per_tick`] per swap cycle, carrying the remainder to the next tick.
+    /// All remaining data is sent on [`crate::flush`].
+    pub fn nice_mode(mut self, enable: bool) -> Self {
+        self.nice_mode = enable;
+        self.explicit |= EXPLICIT_NICE;
+        self
+    }
+
+    /// Sets the maximum bytes sent per swap cycle in nice mode.
+    pub fn nice_bytes_per_tick(mut self, size: usize) -> Self {
+        self.nice_bytes_per_tick = size;
+        self
+    }
+
+    /// Sets whether to yield to other threads between chunked writes in nice mode.
+    pub fn nice_yield(mut self, enable: bool) -> Self {
+        self.nice_yield = enable;
+        self
+    }
+
+    /// Adds a hook called for every kv entry before it is written to the buffer.
+    ///
+    /// Hooks run in the order they are added.
+    ///
+    /// ```
+    /// uplog::Builder::default()
+    ///     .redact(|key, value| {
+    ///         if key.ends_with("_secret") {
+    ///             *value = uplog::Value::Null;
+    ///         }
+    ///     });
+    /// ```
+    pub fn redact(mut self, f: RedactFn) -> Self {
+        self.redactors.push(Redactor::Hook(f));
+        self
+    }
+
+    /// Replaces values of the given keys with [`crate::REDACTED`].
+    pub fn redact_keys<I, S>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.redactors
+            .push(Redactor::Keys(keys.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Replaces parts of text values matching the pattern with [`crate::REDACTED`].
+    #[cfg(feature = "redact-regex")]
+    pub fn redact_pattern(mut self, pattern: regex::Regex) -> Self {
+        self.redactors.push(Redactor::Pattern(pattern));
+        self
+    }
+
+    /// Sets the callback for errors in the sender thread.
+    ///
+    /// It is called from the sender thread when the server reports a decode error
+    /// and when the sender stops abnormally. See also [`crate::health`].
+    pub fn on_error(mut self, f: ErrorCallback) -> Self {
+        self.on_error = Some(f);
+        self
+    }
+
+    /// Sets the callback receiving a snapshot of [`crate::LoggerStats`] every `interval`.
+    ///
+    /// It is called from the sender thread, never while the writer lock is held,
+    /// so the interval is effectively rounded up to the swap duration.
+    /// Use [`crate::stats_snapshot`] for one-off reads.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// uplog::Builder::default().stats_observer(
+    ///     Box::new(|stats| println!("buffer {:.1}%", stats.buffer_fill_percent())),
+    ///     Duration::from_secs(10),
+    /// );
+    /// ```
+    pub fn stats_observer(mut self, f: StatsObserver, interval: Duration) -> Self {
+        self.stats_observer = Some(ObserverConfig {
+            f: Arc::from(f),
+            interval,
+        });
+        self
+    }
+
+    /// 送信スレッドとバッファーの設定
+    fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            buffer_size: self.swap_buffer_size,
+            growth: self.buffer_growth,
+            swap_duration: self.swap_duration,
+            single_producer: self.single_producer,
+            nice: self.nice(),
+            on_error: self.on_error,
+            stats_observer: self.stats_observer.clone(),
+            watchdog_ticks: self.watchdog_ticks,
+            protocol_error_budget: self.protocol_error_budget,
+            priority_level: self.priority_level,
+            spill_path: self.spill_path.map(ToOwned::to_owned),
+            ordering: self.ordering,
+            urgent: self.urgent_level.map(|level| UrgentFlush {
+                level,
+                interval: self.urgent_interval,
+            }),
+            history: self.history.clone(),
+        }
+    }
+
+    fn nice(&self) -> Option<NiceMode> {
+        self.nice_mode.then(|| NiceMode {
+            bytes_per_tick: self.nice_bytes_per_tick,
+            chunk_size: Self::NICE_CHUNK_SIZE.min(self.nice_bytes_per_tick),
+            yield_between_chunks: self.nice_yield,
+        })
+    }
+
+    fn url(&self) -> Url {
+        let protocol = match self.secure_connection {
+            true => "wss",
+            false => "ws",
+        };
+        let addr = format!("{}://{}:{}{}", protocol, self.host, self.port, self.path);
+        let mut url = Url::parse(&addr).expect("failed to parse url");
+        session_init();
+        url.query_pairs_mut()
+            .append_pair(SESSION_QUERY, crate::session::session_id());
+        url
+    }
+
+    fn connector(&self) -> Connector {
+        #[cfg(all(unix, feature = "uds"))]
+        if let Some(path) = self.uds_path {
+            log::debug!("create client [{}]", path.display());
+            session_init();
+            return Connector::Uds(path.to_owned());
+        }
+        let (url, codecs) = self.endpoint();
+        log::debug!("create client [{}]", &url);
+        Connector::Url(url, codecs)
+    }
+
+    /// 接続先と優先順に並べたcodec
+    pub(crate) fn endpoint(&self) -> (Url, Vec<Codec>) {
+        let mut codecs = Vec::new();
+        if self.deflate {
+            codecs.push(Codec::CborDeflate);
+        }
+        if self.dictionary {
+            codecs.push(Codec::CborDict);
+        }
+        codecs.push(Codec::Cbor);
+        (self.url(), codecs)
+    }
+
+    /// Checks the settings without starting anything.
+    ///
+    /// The `try_init*` functions call this first and return the error instead of starting
+    /// a sender thread that would fail later.
+    pub fn validate(&self) -> Result<(), BuilderError> {
+        if self.swap_buffer_size < MIN_BUFFER_SIZE {
+            return Err(BuilderError::BufferTooSmall {
+                size: self.swap_buffer_size,
+                min: MIN_BUFFER_SIZE,
+            });
+        }
+        if let Growth::Doubling { max } = self.buffer_growth {
+            if max < self.swap_buffer_size {
+                return Err(BuilderError::GrowthBelowBufferSize {
+                    max,
+                    size: self.swap_buffer_size,
+                });
+            }
+        }
+        if self.swap_duration.is_zero() {
+            return Err(BuilderError::ZeroDuration);
+        }
+        if self.nice_mode && self.nice_bytes_per_tick == 0 {
+            return Err(BuilderError::ZeroNiceBytesPerTick);
+        }
+        if self
+            .stats_observer
+            .as_ref()
+            .is_some_and(|x| x.interval.is_zero())
+        {
+            return Err(BuilderError::ZeroObserverInterval);
+        }
+        #[cfg(all(unix, feature = "uds"))]
+        if self.uds_path.is_some() {
+            // 接続先の設定はwebsocketだけのもの
+            if self.deflate {
+                return Err(BuilderError::ConflictingTransports("uds_path", "deflate"));
+            }
+            if self.dictionary {
+                return Err(BuilderError::ConflictingTransports(
+                    "uds_path",
+                    "dictionary",
+                ));
+            }
+            return Ok(());
+        }
+        if self.host.is_empty() {
+            return Err(BuilderError::EmptyHost);
+        }
+        url::Host::parse(self.host).map_err(|e| BuilderError::InvalidHost {
+            host: self.host.to_string(),
+            reason: e.to_string(),
+        })?;
+        if self.port == 0 {
+            return Err(BuilderError::ZeroPort);
+        }
+        if !self.path.starts_with('/') {
+            return Err(BuilderError::InvalidPath(self.path.to_string()));
+        }
+        Ok(())
+    }
+
+    fn build(self) -> Result<(LogClient, SenderHandle), BuilderError> {
+        self.validate()?;
+        Ok(self.build_with(None))
+    }
+
+    fn build_with(self, transport: Option<Box<dyn Transport>>) -> (LogClient, SenderHandle) {
+        crate::budget::install(&self.category_budgets, self.max_record_bytes);
+        crate::redact::install(self.redactors.clone());
+        crate::category::install(self.category_filters.clone());
+        crate::level::install(self.level);
+        crate::oversize::install(self.max_record_bytes, self.oversize_surrogate);
+        crate::kvlimit::install(self.max_kv_entries, self.max_key_len);
+        crate::precision::install(self.time_precision);
+        crate::clock::install(self.clock_offset_stamp);
+        crate::delta::install(self.emit_deltas);
+        crate::logger::install_flush_deadline(self.flush_deadline);
+        crate::build_info::install(self.build_info.clone());
+        if self.capture_panics {
+            crate::capture_panics();
+        }
+        let connector = match transport {
+            Some(x) => Connector::Transport(Some(x)),
+            None => self.connector(),
+        };
+        LogClient::new(connector, self.client_config())
+    }
+
+    /// try init uplog c;ient
+    pub fn try_init(self) -> Result<(), InitError> {
+        try_init_with_builder(self)
+    }
+
+    /// try init uplog client sending through the given transport instead of the websocket.
+    ///
+    /// The host and port settings are ignored.
+    pub fn try_init_with_transport<T: Transport + 'static>(
+        self,
+        transport: T,
+    ) -> Result<(), InitError> {
+        log::debug!("try_init_with_transport");
+        self.validate_with_transport()?;
+        let build_info = self.build_info.clone();
+        let (logger, handle) = self.build_with(Some(Box::new(transport)));
+        set_logger(logger, handle)?;
+        if let Some(info) = build_info.as_ref() {
+            crate::build_info::emit_record(info);
+        }
+        Ok(())
+    }
+
+    /// 渡された送信先を使う場合は接続先の設定を確認しない
+    fn validate_with_transport(&self) -> Result<(), BuilderError> {
+        #[cfg(all(unix, feature = "uds"))]
+        if self.uds_path.is_some() {
+            return Err(BuilderError::ConflictingTransports("uds_path", "transport"));
+        }
+        Builder {
+            host: "localhost",
+            port: WS_DEFAULT_PORT,
+            path: INGEST_PATH,
+            ..self.clone()
+        }
+        .validate()
+    }
+}
+
+impl<'b> Default for Builder<'b> {
+    fn default() -> Self {
+        Self {
+            secure_connection: false,
+            host: "localhost",
+            port: WS_DEFAULT_PORT,
+            path: INGEST_PATH,
+            swap_buffer_size: DEFAULT_BUFFER_SIZE,
+            buffer_growth: Growth::Fixed,
+            swap_duration: DEFAULT_SWAP_DURATION,
+            category_budgets: Vec::new(),
+            nice_mode: false,
+            nice_bytes_per_tick: Self::DEFAULT_NICE_BYTES_PER_TICK,
+            nice_yield: true,
+            on_error: None,
+            redactors: Vec::new(),
+            category_filters: Vec::new(),
+            stats_observer: None,
+            level: Level::Trace,
+            deflate: false,
+            dictionary: false,
+            single_producer: false,
+            max_record_bytes: None,
+            oversize_surrogate: false,
+            max_kv_entries: None,
+            max_key_len: None,
+            time_precision: None,
+            clock_offset_stamp: None,
+            emit_deltas: false,
+            capture_panics: false,
+            blocking_timeout: Self::DEFAULT_BLOCKING_TIMEOUT,
+            watchdog_ticks: DEFAULT_WATCHDOG_TICKS,
+            protocol_error_budget: DEFAULT_PROTOCOL_ERROR_BUDGET,
+            priority_level: None,
+            urgent_level: None,
+            urgent_interval: DEFAULT_URGENT_INTERVAL,
+            spill_path: None,
+            history: None,
+            flush_deadline: None,
+            ordering: Ordering::Arrival,
+            build_info: None,
+            preset: None,
+            explicit: 0,
+            #[cfg(all(unix, feature = "uds"))]
+            uds_path: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn test_builder_validate() {
+        use crate::{Builder, BuilderError, Growth, MockTransport, MIN_BUFFER_SIZE};
+
+        assert_eq!(Builder::default().validate(), Ok(()));
+        assert_eq!(Builder::default().host("127.0.0.1").validate(), Ok(()));
+        assert_eq!(Builder::default().host("[::1]").validate(), Ok(()));
+        assert_eq!(
+            Builder::default().buffer_size(MIN_BUFFER_SIZE).validate(),
+            Ok(())
+        );
+
+        let invalid_host = |host: &str| {
+            matches!(
+                Builder::default().host(host).validate(),
+                Err(BuilderError::InvalidHost { host: x, .. }) if x == host
+            )
+        };
+        assert!(invalid_host("localhost:8040"));
+        assert!(invalid_host("ws://localhost"));
+        assert!(invalid_host("local host"));
+        assert!(invalid_host("[::1"));
+
+        let observer: crate::StatsObserver = Box::new(|_| {});
+        let cases = [
+            (
+                Builder::default().buffer_size(0),
+                BuilderError::BufferTooSmall {
+                    size: 0,
+                    min: MIN_BUFFER_SIZE,
+                },
+            ),
+            (
+                Builder::default().buffer_size(MIN_BUFFER_SIZE - 1),
+                BuilderError::BufferTooSmall {
+                    size: MIN_BUFFER_SIZE - 1,
+                    min: MIN_BUFFER_SIZE,
+                },
+            ),
+            (
+                Builder::default()
+                    .buffer_size(4096)
+                    .buffer_growth(Growth::Doubling { max: 2048 }),
+                BuilderError::GrowthBelowBufferSize {
+                    max: 2048,
+                    size: 4096,
+                },
+            ),
+            (
+                Builder::default().duration(Duration::ZERO),
+                BuilderError::ZeroDuration,
+            ),
+            (Builder::default().host(""), BuilderError::EmptyHost),
+            (Builder::default().port(0), BuilderError::ZeroPort),
+            (
+                Builder::default().path("ingest"),
+                BuilderError::InvalidPath("ingest".to_string()),
+            ),
+            (
+                Builder::default().nice_mode(true).nice_bytes_per_tick(0),
+                BuilderError::ZeroNiceBytesPerTick,
+            ),
+            (
+                Builder::default().stats_observer(observer, Duration::ZERO),
+                BuilderError::ZeroObserverInterval,
+            ),
+        ];
+        for (builder, expected) in cases {
+            assert_eq!(builder.validate(), Err(expected));
+        }
+        // nice modeでなければ使わない
+        assert_eq!(Builder::default().nice_bytes_per_tick(0).validate(), Ok(()));
+
+        // 送信先を渡す場合は接続先の設定を使わないが、それ以外は確認する
+        assert_eq!(
+            Builder::default()
+                .host("")
+                .port(0)
+                .validate_with_transport(),
+            Ok(())
+        );
+        assert_eq!(
+            Builder::default()
+                .duration(Duration::ZERO)
+                .try_init_with_transport(MockTransport::new()),
+            Err(crate::InitError::InvalidConfig(BuilderError::ZeroDuration))
+        );
+    }
+
+    #[test]
+    fn test_builder_path() {
+        use crate::Builder;
+
+        let url = Builder::default().url();
+        assert_eq!(url.path(), crate::INGEST_PATH);
+        let url = Builder::default().port(9000).path("/staging").url();
+        assert_eq!(url.path(), "/staging");
+        assert_eq!(url.port(), Some(9000));
+        // 以前のパスも指定できる
+        let url = Builder::default().path(crate::WS_PATH).url();
+        assert_eq!(url.path(), crate::WS_PATH);
+    }
+
+    #[test]
+    fn test_builder_preset() {
+        use crate::{Builder, Growth, Preset, Value};
+
+        const KIB: usize = 1024;
+        const MIB: usize = 1024 * KIB;
+        let cases = [
+            (
+                Preset::LowLatency,
+                256 * KIB,
+                10,
+                Growth::Fixed,
+                false,
+                false,
+                100,
+            ),
+            (
+                Preset::HighThroughput,
+                8 * MIB,
+                100,
+                Growth::Doubling { max: 32 * MIB },
+                true,
+                false,
+                50,
+            ),
+            (
+                Preset::ConstrainedDevice,
+                16 * KIB,
+                1000,
+                Growth::Fixed,
+                false,
+                true,
+                30,
+            ),
+            (
+                Preset::Lossless,
+                2 * MIB,
+                100,
+                Growth::Doubling { max: 64 * MIB },
+                false,
+                false,
+                50,
+            ),
+        ];
+        for (preset, size, millis, growth, deflate, nice, ticks) in cases {
+            let builder = Builder::default().preset(preset);
+            assert_eq!(builder.swap_buffer_size, size, "{}", preset);
+            assert_eq!(builder.swap_duration, Duration::from_millis(millis));
+            assert_eq!(builder.buffer_growth, growth, "{}", preset);
+            assert_eq!(builder.deflate, deflate, "{}", preset);
+            assert_eq!(builder.nice_mode, nice, "{}", preset);
+            assert_eq!(builder.watchdog_ticks, ticks, "{}", preset);
+            assert_eq!(builder.validate(), Ok(()), "{}", preset);
+
+            let kv = builder.describe();
+            assert_eq!(kv["preset"], Value::from(preset.as_str()));
+            assert_eq!(kv["buffer_size"], Value::U64(size as u64));
+            assert_eq!(kv["swap_duration_ms"], Value::U64(millis));
+            assert_eq!(kv["deflate"], Value::Bool(deflate));
+        }
+        assert_eq!(Builder::default().describe()["preset"], Value::Null);
+        assert_eq!(
+            Builder::default().describe()["buffer_growth"],
+            Value::from("fixed")
+        );
+        let kv = Builder::default()
+            .spill_file(std::path::Path::new("spill.cbor"))
+            .flush_deadline(Duration::from_secs(8))
+            .with_build_info(crate::build_info!())
+            .ordering(crate::Ordering::Timestamp)
+            .urgent_level(crate::Level::Error)
+            .describe();
+        assert_eq!(kv["spill_file"], Value::from("spill.cbor"));
+        assert_eq!(kv["flush_deadline_ms"], Value::U64(8000));
+        assert_eq!(kv["build_info"], Value::Map(crate::build_info!()));
+        assert_eq!(kv["ordering"], Value::from("timestamp"));
+        assert_eq!(kv["urgent_level"], Value::from("Error"));
+        assert_eq!(kv["urgent_interval_ms"], Value::U64(50));
+        assert_eq!(kv["history_capacity"], Value::Null);
+    }
+
+    #[test]
+    fn test_builder_preset_precedence() {
+        use crate::{Builder, Growth, Preset};
+
+        // 個別の設定は呼ぶ順序によらずプリセットより優先する
+        let before = Builder::default()
+            .buffer_size(4096)
+            .duration(Duration::from_millis(20))
+            .deflate(false)
+            .preset(Preset::HighThroughput);
+        let after = Builder::default()
+            .preset(Preset::HighThroughput)
+            .buffer_size(4096)
+            .duration(Duration::from_millis(20))
+            .deflate(false);
+        for builder in [before, after] {
+            assert_eq!(builder.swap_buffer_size, 4096);
+            assert_eq!(builder.swap_duration, Duration::from_millis(20));
+            assert!(!builder.deflate);
+            // 指定していない項目はプリセットの値
+            assert_eq!(builder.buffer_growth, Growth::Doubling { max: 32 << 20 });
+            assert_eq!(builder.watchdog_ticks, 50);
+        }
+
+        // 後から別のプリセットを選んでも個別の設定は残る
+        let builder = Builder::default()
+            .nice_mode(false)
+            .sender_watchdog(7)
+            .buffer_growth(Growth::Fixed)
+            .preset(Preset::Lossless)
+            .preset(Preset::ConstrainedDevice);
+        assert_eq!(builder.preset, Some(Preset::ConstrainedDevice));
+        assert!(!builder.nice_mode);
+        assert_eq!(builder.watchdog_ticks, 7);
+        assert_eq!(builder.buffer_growth, Growth::Fixed);
+        assert_eq!(builder.swap_buffer_size, 16 * 1024);
+    }
+
+    #[cfg(all(unix, feature = "uds"))]
+    #[test]
+    fn test_builder_validate_uds() {
+        use crate::{Builder, BuilderError};
+
+        let path = std::path::Path::new("/tmp/uplog.sock");
+        // 接続先の設定は使わない
+        assert_eq!(
+            Builder::default()
+                .uds_path(path)
+                .host("")
+                .port(0)
+                .validate(),
+            Ok(())
+        );
+        for (builder, other) in [
+            (Builder::default().uds_path(path).deflate(true), "deflate"),
+            (
+                Builder::default().uds_path(path).dictionary(true),
+                "dictionary",
+            ),
+        ] {
+            assert_eq!(
+                builder.validate(),
+                Err(BuilderError::ConflictingTransports("uds_path", other))
+            );
+        }
+        assert_eq!(
+            Builder::default().uds_path(path).validate_with_transport(),
+            Err(BuilderError::ConflictingTransports("uds_path", "transport"))
+        );
+    }
+}
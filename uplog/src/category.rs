@@ -0,0 +1,278 @@
+//! カテゴリの照合
+//!
+//! カテゴリは`.`区切りの階層として扱う。
+//! `*`は1階層 (階層内で`eth*`のように部分一致にも使える)、`**`は0以上の任意の階層に一致する。
+//! 照合のたびに解析しないように設定時に[`CategoryPattern`]へ変換しておく
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static FILTERS: RwLock<Vec<CategoryPattern>> = RwLock::new(Vec::new());
+
+/// Compiled category pattern.
+///
+/// - `net.*.rx` matches `net.eth0.rx` but not `net.eth0.x.rx`
+/// - `net.**.rx` matches `net.rx`, `net.eth0.rx` and `net.eth0.x.rx`
+/// - `net.eth*.rx` matches `net.eth0.rx`
+/// - `/^net\.(eth|wlan)\d+$/` is a regex (requires the `category-regex` feature)
+///
+/// ```
+/// let p = uplog::CategoryPattern::new("net.*.rx").unwrap();
+/// assert!(p.matches("net.eth0.rx"));
+/// assert!(!p.matches("net.eth0.tx"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct CategoryPattern {
+    source: String,
+    kind: PatternKind,
+}
+
+#[derive(Debug, Clone)]
+enum PatternKind {
+    Glob(Vec<Segment>),
+    #[cfg(feature = "category-regex")]
+    Regex(regex::Regex),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `*`で区切った断片
+    Wildcard(Vec<String>),
+    /// `**`
+    AnyDepth,
+}
+
+impl Segment {
+    #[allow(clippy::result_large_err)]
+    fn parse(src: &str, pattern: &str) -> crate::Result<Self> {
+        if src == "**" {
+            Ok(Self::AnyDepth)
+        } else if src.contains("**") {
+            Err(crate::Error::InvalidPattern(format!(
+                "`**` must be a whole segment in {}",
+                pattern
+            )))
+        } else if src.contains('*') {
+            Ok(Self::Wildcard(src.split('*').map(str::to_string).collect()))
+        } else {
+            Ok(Self::Literal(src.to_string()))
+        }
+    }
+
+    /// 1階層分の照合
+    fn matches(&self, segment: &str) -> bool {
+        match self {
+            Segment::Literal(x) => x == segment,
+            Segment::Wildcard(parts) => {
+                let (first, rest) = parts.split_first().expect("wildcard has parts");
+                let (last, middle) = rest.split_last().expect("wildcard has parts");
+                let mut s = match segment.strip_prefix(first.as_str()) {
+                    Some(s) => s,
+                    None => return false,
+                };
+                for part in middle {
+                    match s.find(part.as_str()) {
+                        Some(pos) => s = &s[pos + part.len()..],
+                        None => return false,
+                    }
+                }
+                s.len() >= last.len() && s.ends_with(last.as_str())
+            }
+            Segment::AnyDepth => true,
+        }
+    }
+}
+
+fn match_segments(pattern: &[Segment], category: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => category.is_empty(),
+        Some((Segment::AnyDepth, rest)) => {
+            (0..=category.len()).any(|i| match_segments(rest, &category[i..]))
+        }
+        Some((seg, rest)) => match category.split_first() {
+            Some((head, tail)) => seg.matches(head) && match_segments(rest, tail),
+            None => false,
+        },
+    }
+}
+
+impl CategoryPattern {
+    /// Compiles a pattern.
+    ///
+    /// A pattern surrounded by `/` is a regex matched against the whole category string.
+    #[allow(clippy::result_large_err)]
+    pub fn new(pattern: &str) -> crate::Result<Self> {
+        if pattern.is_empty() {
+            return Err(crate::Error::InvalidPattern("empty pattern".to_string()));
+        }
+        let kind = match pattern.strip_prefix('/').and_then(|x| x.strip_suffix('/')) {
+            Some(re) => Self::compile_regex(re, pattern)?,
+            None => PatternKind::Glob(
+                pattern
+                    .split('.')
+                    .map(|x| Segment::parse(x, pattern))
+                    .collect::<crate::Result<_>>()?,
+            ),
+        };
+        Ok(Self {
+            source: pattern.to_string(),
+            kind,
+        })
+    }
+
+    #[cfg(feature = "category-regex")]
+    #[allow(clippy::result_large_err)]
+    fn compile_regex(re: &str, pattern: &str) -> crate::Result<PatternKind> {
+        regex::Regex::new(re)
+            .map(PatternKind::Regex)
+            .map_err(|e| crate::Error::InvalidPattern(format!("{}: {}", pattern, e)))
+    }
+
+    #[cfg(not(feature = "category-regex"))]
+    #[allow(clippy::result_large_err)]
+    fn compile_regex(_re: &str, pattern: &str) -> crate::Result<PatternKind> {
+        Err(crate::Error::InvalidPattern(format!(
+            "regex pattern {} requires the category-regex feature",
+            pattern
+        )))
+    }
+
+    /// Returns true if the category matches the pattern.
+    pub fn matches(&self, category: &str) -> bool {
+        match &self.kind {
+            PatternKind::Glob(segments) => {
+                let category = category.split('.').collect::<Vec<_>>();
+                match_segments(segments, &category)
+            }
+            #[cfg(feature = "category-regex")]
+            PatternKind::Regex(re) => re.is_match(category),
+        }
+    }
+
+    /// The pattern string this was compiled from.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+}
+
+impl Display for CategoryPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for CategoryPattern {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+/// 出力するカテゴリを設定する。空の場合は全て出力する
+pub(crate) fn install(filters: Vec<CategoryPattern>) {
+    let mut global = FILTERS
+        .write()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    ENABLED.store(!filters.is_empty(), Ordering::Release);
+    *global = filters;
+}
+
+/// 設定したパターンのいずれかに一致するか
+pub(crate) fn is_enabled(category: &str) -> bool {
+    if !ENABLED.load(Ordering::Acquire) {
+        return true;
+    }
+    FILTERS
+        .read()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+        .iter()
+        .any(|p| p.matches(category))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CategoryPattern;
+
+    const CATEGORIES: &[&str] = &[
+        "a",
+        "a.b",
+        "a.c",
+        "a.b.c",
+        "a.x.y.c",
+        "axb",
+        "b",
+        "x.b",
+        "net.eth0.rx",
+        "net.wlan0.rx",
+        "net.eth0.tx",
+    ];
+
+    fn matched(pattern: &str) -> Vec<&'static str> {
+        let p = CategoryPattern::new(pattern).unwrap();
+        CATEGORIES
+            .iter()
+            .copied()
+            .filter(|x| p.matches(x))
+            .collect()
+    }
+
+    #[test]
+    fn test_glob_patterns() {
+        assert_eq!(matched("a.*"), ["a.b", "a.c"]);
+        assert_eq!(matched("*.b"), ["a.b", "x.b"]);
+        assert_eq!(matched("*"), ["a", "axb", "b"]);
+        assert_eq!(matched("a.**.c"), ["a.c", "a.b.c", "a.x.y.c"]);
+        assert_eq!(matched("a.**"), ["a", "a.b", "a.c", "a.b.c", "a.x.y.c"]);
+        assert_eq!(matched("**").len(), CATEGORIES.len());
+        // `.`は任意の1文字ではない
+        assert_eq!(matched("a.b"), ["a.b"]);
+        assert_eq!(matched("net.*.rx"), ["net.eth0.rx", "net.wlan0.rx"]);
+        assert_eq!(matched("net.eth*.*"), ["net.eth0.rx", "net.eth0.tx"]);
+        assert_eq!(
+            matched("net.*0.*x"),
+            ["net.eth0.rx", "net.wlan0.rx", "net.eth0.tx"]
+        );
+        assert_eq!(matched("a*b"), ["axb"]);
+        assert!(matched("a.b.c.d").is_empty());
+    }
+
+    #[test]
+    fn test_filter_install() {
+        super::install(vec![
+            CategoryPattern::new("net.*.rx").unwrap(),
+            CategoryPattern::new("app.**").unwrap(),
+        ]);
+        assert!(super::is_enabled("net.eth0.rx"));
+        assert!(super::is_enabled("app.ui.button"));
+        assert!(!super::is_enabled("net.eth0.tx"));
+        super::install(Vec::new());
+        assert!(super::is_enabled("net.eth0.tx"));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(CategoryPattern::new("").is_err());
+        assert!(CategoryPattern::new("a.**b").is_err());
+        assert!(CategoryPattern::new("a***").is_err());
+        #[cfg(not(feature = "category-regex"))]
+        assert!(CategoryPattern::new("/a/").is_err());
+    }
+
+    #[cfg(feature = "category-regex")]
+    #[test]
+    fn test_regex_pattern() {
+        assert_eq!(
+            matched(r"/^net\.(eth|wlan)\d+\.rx$/"),
+            ["net.eth0.rx", "net.wlan0.rx"]
+        );
+        assert!(CategoryPattern::new("/(/").is_err());
+    }
+}
@@ -1,117 +1,841 @@
 /// logger実体
 use std::{
-    ops::DerefMut,
+    cell::RefCell,
+    io::Write,
+    path::PathBuf,
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{channel, Receiver, Sender},
         Arc, Mutex,
     },
-    thread::{self, JoinHandle},
+    thread,
     time::{Duration, Instant},
 };
-use tungstenite::Message;
-use url::Url;
 
 use crate::{
-    buffer::{SwapBufWriter, SwapBuffer},
-    logger::{set_boxed_logger, SetLoggerError},
-    session_init, Log, MetadataBorrow, RecordBorrow, WS_PATH,
+    buffer::{Growth, LogBuffer, LogWriter, SwapBuffer},
+    category::CategoryPattern,
+    error::InitError,
+    history::RingFileSink,
+    kv::{KVBorrow, ValueBorrow},
+    logger::{set_logger, FlushReport, SenderHandle},
+    order,
+    protocol::{CloseReason, ControlCommand, ServerMessage, CMD_SET_LEVEL, CMD_UPLOAD_HISTORY},
+    session_init,
+    stats::{ObserverConfig, StatsReporter},
+    transport::{MockTransport, Transport},
+    watchdog::Watchdog,
+    Level, Log, LogOutcome, MetadataBorrow, RecordBorrow,
 };
+#[cfg(feature = "client-ws")]
+use crate::{protocol::Codec, ws::WebsocketTransport};
 
-#[allow(dead_code)]
-pub const WS_DEFAULT_PORT: u16 = 8040;
-#[allow(dead_code)]
+/// Size of the swap buffer when it is not configured.
 pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024 * 2;
+/// Smallest buffer size accepted by `Builder::validate`.
+pub const MIN_BUFFER_SIZE: usize = 1024;
+/// 送信の周期の既定値
+pub(crate) const DEFAULT_SWAP_DURATION: Duration = Duration::from_millis(500);
+/// 優先するレコードのバッファーの大きさ。通常のバッファーより大きくはしない
+const PRIORITY_BUFFER_SIZE: usize = 64 * 1024;
+/// 周期を待たずに送る間隔の最小値の既定値
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+pub(crate) const DEFAULT_URGENT_INTERVAL: Duration = Duration::from_millis(50);
 
 /// initialize the global logger with noop
 pub fn init_noop() {
     session_init();
 }
 
-/// initialize the global logger
-/// # Example
+/// Initializes the global logger with the default settings, sending through `transport`.
 ///
-/// ```
-/// /// initialize log
-/// uplog::try_init().unwrap();
-///
-/// // your program...
+/// Needs no network support, so it is the way to start a sink such as
+/// [`MockTransport`] without the `client-ws` feature.
 ///
-/// // Force recommend call finally flush()
+/// ```
+/// let transport = uplog::MockTransport::capture();
+/// uplog::init_with_transport(transport.clone()).unwrap();
+/// uplog::info!("doc", "hello");
 /// uplog::flush();
+/// assert!(transport.sent_bytes() > 0);
 /// ```
-pub fn try_init() -> Result<(), SetLoggerError> {
-    log::debug!("try_init");
-    let (logger, handle) = Builder::default().build();
-    set_boxed_logger(Box::new(logger), handle)?;
+pub fn init_with_transport<T: Transport + 'static>(transport: T) -> Result<(), InitError> {
+    log::debug!("init_with_transport");
+    // 以前の初期化で入れた設定は戻す
+    crate::budget::install(&[], None);
+    crate::redact::install(Vec::new());
+    crate::category::install(Vec::new());
+    crate::level::install(Level::Trace);
+    crate::oversize::install(None, false);
+    crate::precision::install(None);
+    crate::clock::install(None);
+    let (logger, handle) = LogClient::new(
+        Connector::Transport(Some(Box::new(transport))),
+        ClientConfig::default(),
+    );
+    set_logger(logger, handle)?;
     Ok(())
 }
 
-/// initialize the global logger with logging server host
+/// Initializes the global logger to keep every record in memory.
 ///
-/// # Example
+/// Read the records from the returned transport after [`crate::flush`].
+pub fn init_capture() -> Result<MockTransport, InitError> {
+    let transport = MockTransport::capture();
+    init_with_transport(transport.clone())?;
+    Ok(transport)
+}
+
+/// 送信スレッドへの通知
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SenderEvent {
+    /// 送り残しを送って、この理由で接続を閉じる
+    Finish(CloseReason),
+    /// 優先するレコードか急ぐレコードが書かれたので、周期を待たずに送る
+    Wake,
+}
+
+impl From<CloseReason> for SenderEvent {
+    fn from(x: CloseReason) -> Self {
+        Self::Finish(x)
+    }
+}
+
+/// 優先するレコードのバッファー
 ///
-/// ```
-/// uplog::try_init_with_host("localhost").unwrap();
-/// ```
-pub fn try_init_with_host(host: &str) -> Result<(), SetLoggerError> {
-    log::debug!("try_init_with_host");
-    let (logger, handle) = Builder::default().host(host).build();
-    set_boxed_logger(Box::new(logger), handle)?;
-    Ok(())
+/// 送信スレッドは周期ごとに通常のバッファーより先に読み、送信量を絞っていても全て送る
+#[derive(Clone)]
+struct PriorityLane {
+    /// このレベル以上のレコードを入れる
+    level: Level,
+    buf: Arc<Mutex<LogBuffer>>,
+    /// 送信スレッドが送り残しなく待っている
+    idle: Arc<AtomicBool>,
 }
 
-pub(crate) fn try_init_with_builder(builder: Builder) -> Result<(), SetLoggerError> {
-    log::debug!("try_init_with_builder");
-    let (logger, handle) = builder.build();
-    set_boxed_logger(Box::new(logger), handle)?;
-    Ok(())
+impl PriorityLane {
+    fn new(level: Level, capacity: usize) -> (Self, LogWriter) {
+        let buf = SwapBuffer::with_growth(capacity, Growth::Fixed);
+        let writer = LogWriter::Swap(buf.get_writer());
+        let lane = Self {
+            level,
+            buf: Arc::new(Mutex::new(buf.into())),
+            idle: Arc::new(AtomicBool::new(false)),
+        };
+        (lane, writer)
+    }
+
+    /// 書かれたレコードを全て`out`に移し、そのバイト数を返す
+    fn drain_into(&self, out: &mut Vec<u8>) -> usize {
+        let mut len = 0;
+        self.buf
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+            .read_with(|unread| {
+                out.extend_from_slice(unread);
+                len = unread.len();
+                len
+            });
+        len
+    }
+}
+
+/// 書いたら周期を待たずに送信スレッドを起こすレコード
+///
+/// レコードは通常のバッファーに書くので、起こされた送信スレッドは溜まっている分をまとめて送る
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UrgentFlush {
+    /// このレベル以上のレコードで起こす
+    pub(crate) level: Level,
+    /// 前に起こしてからこの間は起こさず、次の周期に任せる
+    pub(crate) interval: Duration,
+}
+
+/// 送信スレッドがまだ読んでいないレコード
+///
+/// 終了の期限を過ぎたときに、送信スレッドを待たずにファイルに書き出す
+pub(crate) struct Unsent {
+    buf: Arc<Mutex<LogBuffer>>,
+    priority: Option<PriorityLane>,
+    ordering: order::Ordering,
+    spill_path: PathBuf,
+}
+
+impl std::fmt::Debug for Unsent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Unsent")
+            .field("spill_path", &self.spill_path)
+            .finish()
+    }
+}
+
+impl Unsent {
+    /// 残っているレコードを優先するものから順に追記し、そのレコード数を返す。
+    /// 送信中のレコードは送信スレッドが持っているので含まない
+    pub(crate) fn spill(&self) -> std::io::Result<u64> {
+        // 開けなければバッファーから取り出さない
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.spill_path)?;
+        let mut unsent = Vec::new();
+        if let Some(lane) = self.priority.as_ref() {
+            lane.drain_into(&mut unsent);
+        }
+        self.buf
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+            .read_with(|unread| order::take(self.ordering, unread, None, &mut unsent));
+        let count = count_records(&unsent);
+        if let Err(e) = file.write_all(&unsent).and_then(|()| file.sync_all()) {
+            crate::health::record_dropped(count);
+            return Err(e);
+        }
+        Ok(count)
+    }
+}
+
+/// 組み込み機器向けに送信処理の負荷を平準化する設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct NiceMode {
+    /// 1回のswapで送信する最大バイト数。超えた分は次回に持ち越す
+    pub(crate) bytes_per_tick: usize,
+    /// 1メッセージの最大バイト数
+    pub(crate) chunk_size: usize,
+    /// メッセージの送信ごとに他のスレッドに処理を譲る
+    pub(crate) yield_between_chunks: bool,
+}
+
+/// 送信スレッドが自身のストリームに書き込むレコードのカテゴリ
+pub const CLIENT_CATEGORY: &str = "uplog.client";
+
+/// 接続状態の変化
+///
+/// ログの欠落の理由がわかるように送信スレッドでレコードにして送信データの先頭に挟む
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionEvent {
+    Connected,
+    /// 切断したことは再接続した後に送る
+    Disconnected,
+    Reconnected,
+    /// 前回のswap以降に破棄したレコード数
+    Dropped(u64),
+}
+
+impl ConnectionEvent {
+    /// マクロを経由せずにエンコードする
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        let (level, message) = match self {
+            Self::Connected => (Level::Info, "connected".to_string()),
+            Self::Disconnected => (Level::Warn, "disconnected".to_string()),
+            Self::Reconnected => (Level::Info, "reconnected".to_string()),
+            Self::Dropped(n) => (Level::Warn, format!("dropped {} records", n)),
+        };
+        let kv = match self {
+            Self::Dropped(n) => {
+                let mut kv = KVBorrow::new();
+                kv.insert("dropped", ValueBorrow::U64(*n));
+                Some(kv)
+            }
+            _ => None,
+        };
+        let record = RecordBorrow {
+            metadata: MetadataBorrow::new(level, module_path!()),
+            elapsed: crate::session::elapsed(),
+            category: CLIENT_CATEGORY,
+            module_path: Some(module_path!()),
+            file: Some(file!()),
+            line: Some(line!()),
+            message: &message,
+            kv,
+        };
+        serde_cbor::to_writer(buf, &record).expect("serialize error");
+    }
+}
+
+/// 送信先への接続方法
+pub(crate) enum Connector {
+    /// 優先順に提示するcodec
+    #[cfg(feature = "client-ws")]
+    Url(url::Url, Vec<Codec>),
+    /// 外部から渡されたもの。再接続できない
+    Transport(Option<Box<dyn Transport>>),
+    #[cfg(all(unix, feature = "uds"))]
+    Uds(std::path::PathBuf),
+    /// 接続のたびに作る。送信スレッドのテスト用
+    #[cfg(test)]
+    Factory(Arc<dyn Fn() -> crate::Result<Box<dyn Transport>> + Send + Sync>),
+}
+
+impl Connector {
+    #[allow(clippy::result_large_err)]
+    fn connect(&mut self) -> crate::Result<Box<dyn Transport>> {
+        match self {
+            #[cfg(feature = "client-ws")]
+            Self::Url(url, codecs) => Ok(Box::new(WebsocketTransport::connect(url, codecs)?)),
+            #[cfg(all(unix, feature = "uds"))]
+            Self::Uds(path) => Ok(Box::new(crate::uds::UdsTransport::connect(path)?)),
+            #[cfg(test)]
+            Self::Factory(f) => f(),
+            Self::Transport(x) => x.take().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "transport can not be reconnected",
+                )
+                .into()
+            }),
+        }
+    }
+
+    fn can_reconnect(&self) -> bool {
+        !matches!(self, Self::Transport(_))
+    }
+
+    /// 送信スレッドを作り直すときに使う複製。外部から渡されたものは複製できない
+    fn try_clone(&self) -> Option<Self> {
+        match self {
+            #[cfg(feature = "client-ws")]
+            Self::Url(url, codecs) => Some(Self::Url(url.clone(), codecs.clone())),
+            #[cfg(all(unix, feature = "uds"))]
+            Self::Uds(path) => Some(Self::Uds(path.clone())),
+            #[cfg(test)]
+            Self::Factory(f) => Some(Self::Factory(f.clone())),
+            Self::Transport(_) => None,
+        }
+    }
+
+    /// 同じ送信先に別のセッションとして送る接続方法。外部から渡されたものは作れない
+    fn for_new_session(&self) -> Option<Self> {
+        match self {
+            #[cfg(feature = "client-ws")]
+            Self::Url(url, codecs) => {
+                let pairs = url
+                    .query_pairs()
+                    .filter(|(k, _)| k != crate::protocol::SESSION_QUERY)
+                    .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                    .collect::<Vec<_>>();
+                let mut url = url.clone();
+                url.query_pairs_mut()
+                    .clear()
+                    .extend_pairs(pairs)
+                    .append_pair(
+                        crate::protocol::SESSION_QUERY,
+                        &crate::session::new_session_id(),
+                    );
+                Some(Self::Url(url, codecs.clone()))
+            }
+            _ => self.try_clone(),
+        }
+    }
+}
+
+#[cfg(feature = "client-ws")]
+impl From<url::Url> for Connector {
+    fn from(url: url::Url) -> Self {
+        Self::Url(url, vec![Codec::Cbor])
+    }
 }
 
 /// メインスレッドと別に起動してバッファーを監視し
 /// 外部のログサーバーに対してログを送信し続けるクライアント
-#[derive(Debug)]
+///
+/// 送信に失敗した場合は次の周期で再接続する。
+/// 切断中はswapしないので書き込み側に溜まり、溢れた分は破棄して数える
 struct WebsocketClient {
-    url: url::Url,
-    buf: SwapBuffer,
+    connector: Connector,
+    /// 作り直した送信スレッドと共有する
+    buf: Arc<Mutex<LogBuffer>>,
     tick_duration: Duration,
-    finish_receiver: Receiver<()>,
+    /// 終了の要求と、接続を閉じるときにサーバーに伝える理由
+    finish_receiver: Arc<Mutex<Receiver<SenderEvent>>>,
+    priority: Option<PriorityLane>,
+    /// 周期ごとに並べ替えるか
+    ordering: order::Ordering,
+    nice: Option<NiceMode>,
+    on_error: Option<ErrorCallback>,
+    stats: StatsReporter,
+    /// 終了時の送信結果
+    report: FlushReport,
+    /// 終了を要求された時刻
+    finish_requested_at: Option<Instant>,
+    /// 見張りと、このスレッドの世代
+    watchdog: Option<(Arc<Watchdog>, u64)>,
+    read_budget: ReadBudget,
+    /// 書き込まれたレコードを残す端末の履歴
+    history: Option<Arc<RingFileSink>>,
+}
+
+/// 送信スレッドで発生したエラーの通知先
+pub type ErrorCallback = fn(&crate::Error);
+
+/// 履歴を送るときの1メッセージの大きさ
+const HISTORY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// 1分間に無視するサーバーからの解釈できないメッセージ数の既定値
+pub(crate) const DEFAULT_PROTOCOL_ERROR_BUDGET: u32 = 10;
+
+/// 解釈できないメッセージを数える期間
+const PROTOCOL_ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+/// サーバーからの解釈できないメッセージを数え、多すぎれば読むのをやめる
+#[derive(Debug)]
+struct ReadBudget {
+    /// 期間内に無視する数
+    limit: u32,
+    window_start: Instant,
+    count: u32,
+    stopped: bool,
+}
+
+impl ReadBudget {
+    fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            window_start: Instant::now(),
+            count: 0,
+            stopped: false,
+        }
+    }
+
+    /// 1つ数える。上限を超えて読むのをやめるときだけtrue
+    fn spend(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.window_start) >= PROTOCOL_ERROR_WINDOW {
+            self.window_start = now;
+            self.count = 0;
+        }
+        self.count = self.count.saturating_add(1);
+        if self.count > self.limit && !self.stopped {
+            self.stopped = true;
+            return true;
+        }
+        false
+    }
+
+    /// 接続し直したので数え直す
+    fn reset(&mut self) {
+        *self = Self::new(self.limit);
+    }
 }
 
 impl WebsocketClient {
-    fn builder(
-        url: url::Url,
-        buf: SwapBuffer,
-        finish_receiver: Receiver<()>,
+    #[cfg(all(test, feature = "client-ws"))]
+    fn builder<B: Into<LogBuffer>>(
+        connector: Connector,
+        buf: B,
+        finish_receiver: Receiver<SenderEvent>,
     ) -> WebsocketClientBuilder {
-        WebsocketClientBuilder::new(url, buf, finish_receiver)
+        WebsocketClientBuilder::new(
+            connector,
+            Arc::new(Mutex::new(buf.into())),
+            Arc::new(Mutex::new(finish_receiver)),
+        )
     }
 
+    /// 見張りが別のスレッドに作り直した後か
+    fn is_superseded(&self) -> bool {
+        self.watchdog
+            .as_ref()
+            .is_some_and(|(watchdog, generation)| !watchdog.is_current(*generation))
+    }
+
+    /// 接続して、止まったときに見張りが切れるようにする
+    #[allow(clippy::result_large_err)]
+    fn connect(&mut self) -> crate::Result<Box<dyn Transport>> {
+        let transport = self.connector.connect()?;
+        if let Some((watchdog, generation)) = self.watchdog.as_ref() {
+            watchdog.set_abort(*generation, transport.abort_handle());
+        }
+        Ok(transport)
+    }
+
+    /// 終了の要求を周期の間待つ。作り直された後ならErr
+    fn wait_finish(&self, timeout: Duration) -> Result<Option<CloseReason>, ()> {
+        let receiver = self
+            .finish_receiver
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+        if self.is_superseded() {
+            return Err(());
+        }
+        let finish = match receiver.recv_timeout(timeout) {
+            Ok(SenderEvent::Finish(reason)) => Some(reason),
+            Ok(SenderEvent::Wake) | Err(_) => None,
+        };
+        if let Some((watchdog, generation)) = self.watchdog.as_ref() {
+            watchdog.beat();
+            if let Some(reason) = finish {
+                watchdog.set_finish(*generation, reason);
+            }
+        }
+        Ok(finish)
+    }
+
+    #[allow(clippy::result_large_err)]
     fn run(&mut self) -> crate::Result<()> {
-        use std::io::Read;
-        use tungstenite::client::connect;
-        let (mut client, _) = connect(&self.url)?;
-        let mut read_buf = Vec::<u8>::with_capacity(self.buf.capacity());
-        let reader = self.buf.get_reader();
+        let mut transport = Some(self.connect()?);
+        crate::stats::set_connected(true);
+        if self.nice.is_some() {
+            crate::platform::lower_thread_priority();
+        }
+        let mut read_buf = Vec::<u8>::with_capacity(
+            self.buf
+                .lock()
+                .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+                .capacity(),
+        );
+        ConnectionEvent::Connected.write_to(&mut read_buf);
+        let mut dropped = crate::health::dropped_records();
         let mut next_duration = self.tick_duration;
+        let mut close_reason = CloseReason::Flush;
+        // 前のスレッドが終了の要求を受け取った後に止まった
+        let mut inherited = self.watchdog.as_ref().and_then(|x| x.0.inherited_finish());
+        // 前の周期で送りきれなかった
+        let mut carried = false;
         loop {
-            let is_finaly = matches!(self.finish_receiver.recv_timeout(next_duration), Ok(_));
+            if let Some(lane) = self.priority.as_ref() {
+                lane.idle
+                    .store(!carried && transport.is_some(), Ordering::Release);
+            }
+            let finish = match inherited.take() {
+                Some(x) => Some(x),
+                None => match self.wait_finish(next_duration) {
+                    Ok(x) => x,
+                    Err(()) => return Ok(()),
+                },
+            };
+            let is_finaly = finish.is_some();
+            close_reason = finish.unwrap_or(close_reason);
+            // 接続できなくても履歴には残す
+            self.flush_history();
             let start = Instant::now();
-            self.buf.swap();
-            {
-                let mut reader = reader.lock().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
-                reader.read_to_end(&mut read_buf)?;
+            if is_finaly {
+                self.finish_requested_at = Some(start);
+            }
+            next_duration = self.tick_duration;
+            self.stats.tick();
+            let sender = match transport {
+                Some(ref mut x) => x,
+                None => match self.connect() {
+                    Ok(x) => {
+                        log::info!("reconnected");
+                        self.read_budget.reset();
+                        crate::health::update(|h| h.reading_stopped = false);
+                        ConnectionEvent::Reconnected.write_to(&mut read_buf);
+                        crate::stats::reconnected();
+                        crate::stats::set_connected(true);
+                        transport.insert(x)
+                    }
+                    Err(e) => {
+                        log::debug!("failed to reconnect {}", e);
+                        if is_finaly {
+                            self.report.records_dropped = self.discard_unsent(&read_buf);
+                            break;
+                        }
+                        continue;
+                    }
+                },
+            };
+            let total = crate::health::dropped_records();
+            if total > dropped {
+                ConnectionEvent::Dropped(total - dropped).write_to(&mut read_buf);
+                dropped = total;
+            }
+            // 書くのをやめたカテゴリの分も残るように周期ごとに出力する
+            for report in crate::budget::take_reports() {
+                report.emit(crate::session::elapsed(), |r| {
+                    serde_cbor::to_writer(&mut read_buf, r).expect("serialize error");
+                });
+            }
+            let mut limit = match self.nice {
+                // 終了時は持ち越さずに全て送る
+                Some(ref nice) if !is_finaly => Some(nice.bytes_per_tick),
+                _ => None,
+            };
+            if let Some(lane) = self.priority.as_ref() {
+                // 優先するレコードは制限にかかわらず先に送り、その分だけ通常のレコードを減らす
+                let len = lane.drain_into(&mut read_buf);
+                limit = limit.map(|x| x.saturating_sub(len));
+            }
+            self.buf
+                .lock()
+                .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+                .read_with(|unread| {
+                    let len = order::take(self.ordering, unread, limit, &mut read_buf);
+                    carried = len < unread.len();
+                    len
+                });
+            crate::stats::buffer_swapped();
+            let result = self.send(sender.as_mut(), &read_buf);
+            if self.is_superseded() {
+                // 止まっている間に作り直されたので、読み出した分は送れたかわからない
+                crate::health::record_dropped(count_records(&read_buf));
+                return Ok(());
+            }
+            match result {
+                Ok(()) => {
+                    log::debug!("send {} Byte", read_buf.len());
+                    crate::stats::bytes_sent(read_buf.len());
+                    if is_finaly {
+                        self.report.bytes_sent = read_buf.len() as u64;
+                        self.report.records_sent = count_records(&read_buf);
+                        self.report.transport_ok = true;
+                    }
+                }
+                Err(e) if self.connector.can_reconnect() => {
+                    log::warn!("disconnected {}", e);
+                    crate::health::update(|h| h.last_error = Some(e.to_string()));
+                    self.notify_error(&e);
+                    let lost = count_records(&read_buf);
+                    crate::health::record_dropped(lost);
+                    if is_finaly {
+                        self.report.records_dropped = lost;
+                    }
+                    read_buf.clear();
+                    ConnectionEvent::Disconnected.write_to(&mut read_buf);
+                    crate::stats::set_connected(false);
+                    transport = None;
+                    if is_finaly {
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    if is_finaly {
+                        self.report.records_dropped = count_records(&read_buf);
+                    }
+                    return Err(e);
+                }
             }
-            client.write_message(Message::binary(&read_buf[..]))?;
-            log::debug!("send {} Byte", read_buf.len());
             read_buf.clear();
             if is_finaly {
                 break;
             }
-            next_duration = self.tick_duration - start.elapsed();
+            next_duration = self.tick_duration.saturating_sub(start.elapsed());
+        }
+        crate::stats::set_connected(false);
+        if let Some(mut x) = transport {
+            x.close_with(close_reason)?;
+            crate::health::record_close(close_reason, false);
         }
-        client.close(None)?;
         Ok(())
     }
+
+    /// 送れなかったレコードを数えて捨てる
+    fn discard_unsent(&mut self, read_buf: &[u8]) -> u64 {
+        let mut unsent = Vec::new();
+        if let Some(lane) = self.priority.as_ref() {
+            lane.drain_into(&mut unsent);
+        }
+        self.buf
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+            .read_with(|unread| order::take(self.ordering, unread, None, &mut unsent));
+        count_records(read_buf) + count_records(&unsent)
+    }
+
+    /// 終了時の送信結果を返す
+    fn finish_report(&mut self, result: &crate::Result<()>) -> FlushReport {
+        let mut report = std::mem::take(&mut self.report);
+        if result.is_err() {
+            report.transport_ok = false;
+            if self.finish_requested_at.is_none() {
+                // 終了を要求される前に止まったので、書き込まれていた分は送れない
+                report.records_dropped = self.discard_unsent(&[]);
+            }
+        }
+        report.duration = self
+            .finish_requested_at
+            .map_or(Duration::ZERO, |x| x.elapsed());
+        report
+    }
+
+    /// 送信してからサーバーからの通知を読めるだけ読む
+    #[allow(clippy::result_large_err)]
+    fn send(&mut self, transport: &mut dyn Transport, buf: &[u8]) -> crate::Result<()> {
+        let message_limit = self
+            .buf
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+            .message_limit();
+        match (self.nice.as_ref(), message_limit) {
+            (Some(nice), _) => send_chunked(transport, buf, nice)?,
+            // 広げたバッファーはサーバーが受け付ける大きさに分けて送る
+            (None, Some(limit)) if buf.len() > limit => {
+                let mut rest = buf;
+                while !rest.is_empty() {
+                    let len = record_boundary(rest, limit);
+                    transport.send(&rest[..len])?;
+                    rest = &rest[len..];
+                }
+            }
+            (None, _) => transport.send(buf)?,
+        }
+        // 読むのをやめても送信は続ける
+        while !self.read_budget.stopped {
+            match transport.poll()? {
+                Some(bin) => self.handle_server_message(&bin),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_server_message(&mut self, bin: &[u8]) {
+        match serde_cbor::from_slice::<ServerMessage>(bin) {
+            Ok(ServerMessage::DecodeError(report)) => {
+                log::warn!("server reported decode error {}", report);
+                crate::health::update(|h| {
+                    h.server_error_reports += 1;
+                    h.last_server_error = Some(report.clone());
+                });
+                self.notify_error(&crate::Error::ServerReport(report));
+            }
+            Ok(ServerMessage::Ack(ack)) => {
+                let offset = crate::clock::observe(ack.server_time_ms);
+                crate::health::update(|h| h.clock_offset_ms = Some(offset));
+            }
+            Ok(ServerMessage::Control(cmd)) => match match cmd.cmd.as_str() {
+                CMD_UPLOAD_HISTORY => self.upload_history(&cmd),
+                _ => apply_command(&cmd),
+            } {
+                Ok(()) => {
+                    log::info!("applied server command {:?}", cmd);
+                    crate::health::update(|h| h.applied_commands += 1);
+                }
+                Err(e) => {
+                    log::warn!("ignored server command {:?}, {}", cmd, e);
+                    crate::health::update(|h| h.ignored_commands += 1);
+                }
+            },
+            // 新しいサーバーからのメッセージかもしれないので数えて無視する
+            Err(e) => {
+                log::debug!("unknown server message {}", e);
+                crate::health::update(|h| h.ignored_commands += 1);
+                if self.read_budget.spend(Instant::now()) {
+                    log::warn!("too many unreadable server messages, stopped reading");
+                    crate::health::update(|h| h.reading_stopped = true);
+                    let limit = self.read_budget.limit;
+                    self.notify_error(&crate::Error::ProtocolErrorBudget(limit));
+                }
+            }
+        }
+    }
+
+    fn notify_error(&self, e: &crate::Error) {
+        if let Some(f) = self.on_error {
+            f(e);
+        }
+    }
+
+    fn flush_history(&self) {
+        if let Some(history) = self.history.as_ref() {
+            if let Err(e) = history.flush() {
+                log::debug!("failed to write history {}", e);
+            }
+        }
+    }
+
+    /// 履歴の指定された範囲を別のセッションとして送る
+    ///
+    /// 送っている間も通常の送信を続けられるように別のスレッドで送る
+    fn upload_history(&self, cmd: &ControlCommand) -> Result<(), String> {
+        let history = self.history.as_ref().ok_or("history is not enabled")?;
+        let elapsed = |x: Option<f64>| {
+            x.map(Duration::try_from_secs_f64)
+                .transpose()
+                .map_err(|e| e.to_string())
+        };
+        let from = elapsed(cmd.from_elapsed)?.unwrap_or(Duration::ZERO);
+        let to = elapsed(cmd.to_elapsed)?;
+        let records = history
+            .read_window(from, to)
+            .map_err(|e| format!("failed to read history {}", e))?;
+        let mut connector = self
+            .connector
+            .for_new_session()
+            .ok_or("history can not be sent through this transport")?;
+        thread::spawn(move || match send_history(&mut connector, &records) {
+            Ok(()) => log::info!("sent {} Byte of history", records.len()),
+            Err(e) => log::warn!("failed to send history {}", e),
+        });
+        Ok(())
+    }
+}
+
+/// 新しく接続して履歴を送り、閉じる
+#[allow(clippy::result_large_err)]
+fn send_history(connector: &mut Connector, records: &[u8]) -> crate::Result<()> {
+    let mut transport = connector.connect()?;
+    let mut rest = records;
+    while !rest.is_empty() {
+        let len = record_boundary(rest, HISTORY_CHUNK_SIZE);
+        transport.send(&rest[..len])?;
+        rest = &rest[len..];
+    }
+    transport.close_with(CloseReason::Flush)
+}
+
+/// サーバーからの設定変更を反映する
+fn apply_command(cmd: &ControlCommand) -> Result<(), String> {
+    match cmd.cmd.as_str() {
+        CMD_SET_LEVEL => {
+            let level = cmd.level.ok_or("level is required")?;
+            let category = match cmd.category.as_deref() {
+                Some(x) if !x.is_empty() => {
+                    Some(CategoryPattern::new(x).map_err(|e| e.to_string())?)
+                }
+                _ => None,
+            };
+            crate::level::set_level(level, category);
+            Ok(())
+        }
+        x => Err(format!("unknown command {}", x)),
+    }
+}
+
+/// レコードの区切りで分割して送信する
+#[allow(clippy::result_large_err)]
+fn send_chunked(transport: &mut dyn Transport, buf: &[u8], nice: &NiceMode) -> crate::Result<()> {
+    let mut rest = buf;
+    while !rest.is_empty() {
+        let len = record_boundary(rest, nice.chunk_size);
+        transport.send(&rest[..len])?;
+        rest = &rest[len..];
+        if nice.yield_between_chunks && !rest.is_empty() {
+            thread::yield_now();
+        }
+    }
+    Ok(())
+}
+
+/// 含まれるレコード数を数える
+fn count_records(buf: &[u8]) -> u64 {
+    use serde::de::IgnoredAny;
+    serde_cbor::Deserializer::from_slice(buf)
+        .into_iter::<IgnoredAny>()
+        .take_while(|x| x.is_ok())
+        .count() as u64
+}
+
+/// limitを超えない範囲でレコードの区切りとなるバイト数を返す
+///
+/// 先頭のレコードがlimitより大きい場合はそのレコードの終わりまでを返す。
+/// レコードとして解釈できない場合は全体を返す
+pub(crate) fn record_boundary(buf: &[u8], limit: usize) -> usize {
+    use serde::de::{Deserialize, IgnoredAny};
+    let mut de = serde_cbor::Deserializer::from_slice(buf);
+    let mut end = 0;
+    while end < buf.len() {
+        if IgnoredAny::deserialize(&mut de).is_err() {
+            return if end == 0 { buf.len() } else { end };
+        }
+        let offset = de.byte_offset();
+        if offset > limit && end > 0 {
+            break;
+        }
+        end = offset;
+    }
+    end
 }
 
 struct WebsocketClientBuilder {
@@ -119,13 +843,27 @@ struct WebsocketClientBuilder {
 }
 
 impl WebsocketClientBuilder {
-    fn new(url: url::Url, buf: SwapBuffer, finish_receiver: Receiver<()>) -> Self {
+    fn new(
+        connector: Connector,
+        buf: Arc<Mutex<LogBuffer>>,
+        finish_receiver: Arc<Mutex<Receiver<SenderEvent>>>,
+    ) -> Self {
         Self {
             inner: WebsocketClient {
-                url,
+                connector,
                 buf,
                 finish_receiver,
+                priority: None,
+                ordering: order::Ordering::Arrival,
                 tick_duration: Duration::from_millis(500),
+                nice: None,
+                on_error: None,
+                stats: StatsReporter::new(None),
+                report: FlushReport::default(),
+                finish_requested_at: None,
+                watchdog: None,
+                read_budget: ReadBudget::new(DEFAULT_PROTOCOL_ERROR_BUDGET),
+                history: None,
             },
         }
     }
@@ -135,150 +873,356 @@ impl WebsocketClientBuilder {
         self
     }
 
-    fn build(self) -> WebsocketClient {
-        self.inner
+    fn nice(mut self, nice: Option<NiceMode>) -> Self {
+        self.inner.nice = nice;
+        self
     }
-}
-
-/// initialize the global logger with builder
-///
-/// # Example
-///
-/// ```
-/// use std::time::Duration;
-///
-/// uplog::Builder::default()
-///     .buffer_size(1024)
-///     .host("localhost")
-///     .port(8080)
-///     .duration(Duration::from_millis(1000))
-///     .try_init()
-///     .unwrap();
-/// ```
-#[derive(Debug, Clone, Copy)]
-pub struct Builder<'b> {
-    secure_connection: bool,
-    host: &'b str,
-    port: u16,
-    swap_buffer_size: usize,
-    swap_duration: Duration,
-}
 
-impl<'b> Builder<'b> {
-    const DEFAULT_SWAP_DURATION_MILLIS: u64 = 500;
+    fn priority(mut self, lane: Option<PriorityLane>) -> Self {
+        self.inner.priority = lane;
+        self
+    }
 
-    /// Sets the swap buffer size.
-    ///
-    /// Maximum amount of buffer that can be stored until it is sent to the server
-    /// The amount actually reserved is twice this specified value (for sending and writing).
-    pub fn buffer_size(mut self, size: usize) -> Self {
-        self.swap_buffer_size = size;
+    fn ordering(mut self, ordering: order::Ordering) -> Self {
+        self.inner.ordering = ordering;
         self
     }
 
-    /// Sets the swap suration.
-    ///
-    /// Swap the buffer every cycle specified here
-    pub fn duration(mut self, duration: Duration) -> Self {
-        self.swap_duration = duration;
+    fn on_error(mut self, f: Option<ErrorCallback>) -> Self {
+        self.inner.on_error = f;
         self
     }
 
-    /// Sets the server host name
-    pub fn host(mut self, host: &'b str) -> Self {
-        self.host = host;
+    fn protocol_error_budget(mut self, per_minute: u32) -> Self {
+        self.inner.read_budget = ReadBudget::new(per_minute);
         self
     }
 
-    /// Sets the server port
-    pub fn port(mut self, port: u16) -> Self {
-        self.port = port;
+    fn stats_observer(mut self, observer: Option<ObserverConfig>) -> Self {
+        self.inner.stats = StatsReporter::new(observer);
         self
     }
 
-    fn url(&self) -> Url {
-        let protocol = match self.secure_connection {
-            true => "wss",
-            false => "ws",
-        };
-        let addr = format!("{}://{}:{}{}", protocol, self.host, self.port, WS_PATH);
-        Url::parse(&addr).expect("failed to parse url")
+    fn history(mut self, history: Option<Arc<RingFileSink>>) -> Self {
+        self.inner.history = history;
+        self
     }
 
-    fn build(self) -> (LogClient, JoinHandle<()>) {
-        let url = self.url();
-        log::debug!("create client [{}]", &url);
-        LogClient::new(url, self.swap_buffer_size, self.swap_duration)
+    fn watchdog(mut self, watchdog: Arc<Watchdog>, generation: u64) -> Self {
+        self.inner.watchdog = Some((watchdog, generation));
+        self
     }
 
-    /// try init uplog c;ient
-    pub fn try_init(self) -> Result<(), SetLoggerError> {
-        crate::client::try_init_with_builder(self)
+    fn build(self) -> WebsocketClient {
+        self.inner
     }
 }
 
-impl<'b> Default for Builder<'b> {
+/// [`LogClient`]の設定。[`crate::Builder`]から作る
+#[derive(Debug, Clone)]
+pub(crate) struct ClientConfig {
+    pub(crate) buffer_size: usize,
+    pub(crate) growth: Growth,
+    pub(crate) swap_duration: Duration,
+    pub(crate) single_producer: bool,
+    pub(crate) nice: Option<NiceMode>,
+    pub(crate) on_error: Option<ErrorCallback>,
+    pub(crate) stats_observer: Option<ObserverConfig>,
+    /// 0なら送信スレッドを見張らない
+    pub(crate) watchdog_ticks: u32,
+    pub(crate) protocol_error_budget: u32,
+    pub(crate) priority_level: Option<Level>,
+    pub(crate) spill_path: Option<PathBuf>,
+    pub(crate) ordering: order::Ordering,
+    pub(crate) urgent: Option<UrgentFlush>,
+    /// 送ったレコードを書き残す履歴
+    pub(crate) history: Option<Arc<RingFileSink>>,
+}
+
+impl Default for ClientConfig {
     fn default() -> Self {
         Self {
-            secure_connection: false,
-            host: "localhost",
-            port: WS_DEFAULT_PORT,
-            swap_buffer_size: DEFAULT_BUFFER_SIZE,
-            swap_duration: Duration::from_millis(Self::DEFAULT_SWAP_DURATION_MILLIS),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            growth: Growth::Fixed,
+            swap_duration: DEFAULT_SWAP_DURATION,
+            single_producer: false,
+            nice: None,
+            on_error: None,
+            stats_observer: None,
+            watchdog_ticks: 0,
+            protocol_error_budget: DEFAULT_PROTOCOL_ERROR_BUDGET,
+            priority_level: None,
+            spill_path: None,
+            ordering: order::Ordering::Arrival,
+            urgent: None,
+            history: None,
         }
     }
 }
 
 /// メインスレッドにログ出力の関数を提供するクライアント
 pub struct LogClient {
-    writer: Arc<Mutex<SwapBufWriter>>,
-    close_ch: Arc<Mutex<Sender<()>>>,
+    writer: LogWriter,
+    /// 優先するレコードの書き込み先
+    priority: Option<(PriorityLane, LogWriter)>,
+    /// [`order::Ordering::Timestamp`]では通常のバッファーに前置きを付けて書く
+    ordering: order::Ordering,
+    urgent: Option<UrgentFlush>,
+    /// 最後に急ぐレコードで送信スレッドを起こした時刻
+    last_urgent: Mutex<Option<Instant>>,
+    history: Option<Arc<RingFileSink>>,
+    close_ch: Arc<Mutex<Sender<SenderEvent>>>,
+    watchdog: Option<Arc<Watchdog>>,
 }
 
 impl LogClient {
-    pub fn new(url: Url, buffer_size: usize, swap_duration: Duration) -> (Self, JoinHandle<()>) {
+    pub(crate) fn new(connector: Connector, config: ClientConfig) -> (Self, SenderHandle) {
+        let ClientConfig {
+            buffer_size,
+            growth,
+            swap_duration,
+            single_producer,
+            nice,
+            on_error,
+            stats_observer,
+            watchdog_ticks,
+            protocol_error_budget,
+            priority_level,
+            spill_path,
+            ordering,
+            urgent,
+            history,
+        } = config;
         session_init();
         let (sender, receiver) = channel();
-        let buf = SwapBuffer::new(buffer_size);
-        let writer = buf.get_writer();
-        let mut client = WebsocketClient::builder(url, buf, receiver)
+        let (report_sender, report_receiver) = channel();
+        let (buf, writer) = LogBuffer::new(buffer_size, single_producer, growth);
+        crate::stats::set_buffer_capacity(buffer_size);
+        let priority = priority_level
+            .map(|level| PriorityLane::new(level, PRIORITY_BUFFER_SIZE.min(buffer_size)));
+        let lane = priority.as_ref().map(|(lane, _)| lane.clone());
+        let buf = Arc::new(Mutex::new(buf));
+        let unsent = spill_path.map(|spill_path| Unsent {
+            buf: buf.clone(),
+            priority: lane.clone(),
+            ordering,
+            spill_path,
+        });
+        let receiver = Arc::new(Mutex::new(receiver));
+        let template = connector.try_clone().filter(|_| watchdog_ticks > 0);
+        let builder = WebsocketClientBuilder::new(connector, buf.clone(), receiver.clone())
             .tick_duration(swap_duration)
-            .build();
+            .nice(nice)
+            .priority(lane.clone())
+            .ordering(ordering)
+            .on_error(on_error)
+            .stats_observer(stats_observer.clone())
+            .protocol_error_budget(protocol_error_budget)
+            .history(history.clone());
 
-        // run sender
-        let handle = thread::spawn(move || {
-            client.run().expect("abnormaly stop client");
+        let Some(template) = template else {
+            // 外から渡された接続は作り直せないので見張らない
+            let handle = spawn_sender(builder.build(), report_sender);
+            return (
+                Self {
+                    writer,
+                    priority,
+                    ordering,
+                    urgent,
+                    history,
+                    last_urgent: Mutex::new(None),
+                    close_ch: Arc::new(Mutex::new(sender)),
+                    watchdog: None,
+                },
+                SenderHandle::new(handle, report_receiver).with_unsent(unsent),
+            );
+        };
+        // 最初のスレッドは渡された接続を使い、作り直すときは複製を使う
+        let mut first = Some(builder);
+        let sender_history = history.clone();
+        let watchdog = Arc::new_cyclic(|weak: &std::sync::Weak<Watchdog>| {
+            let weak = weak.clone();
+            Watchdog::new(
+                swap_duration * watchdog_ticks,
+                Box::new(move |generation| {
+                    let builder = first.take().unwrap_or_else(|| {
+                        WebsocketClientBuilder::new(
+                            template.try_clone().expect("connector is reconnectable"),
+                            buf.clone(),
+                            receiver.clone(),
+                        )
+                        .tick_duration(swap_duration)
+                        .nice(nice)
+                        .priority(lane.clone())
+                        .ordering(ordering)
+                        .on_error(on_error)
+                        .stats_observer(stats_observer.clone())
+                        .protocol_error_budget(protocol_error_budget)
+                        .history(sender_history.clone())
+                    });
+                    let builder = match weak.upgrade() {
+                        Some(watchdog) => builder.watchdog(watchdog, generation),
+                        None => builder,
+                    };
+                    spawn_sender(builder.build(), report_sender.clone())
+                }),
+            )
         });
+        watchdog.start();
 
         (
             Self {
                 writer,
+                priority,
+                ordering,
+                urgent,
+                history,
+                last_urgent: Mutex::new(None),
                 close_ch: Arc::new(Mutex::new(sender)),
+                watchdog: Some(watchdog.clone()),
             },
-            handle,
+            SenderHandle::watched(watchdog, report_receiver).with_unsent(unsent),
         )
     }
 }
 
+/// 送信スレッドを起動する。作り直された古いスレッドは報告しない
+fn spawn_sender(
+    mut client: WebsocketClient,
+    report_sender: Sender<FlushReport>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let result = client.run();
+        if client.is_superseded() {
+            return;
+        }
+        if let Err(ref e) = result {
+            log::error!("abnormaly stop client {}", e);
+            crate::health::update(|h| h.last_error = Some(e.to_string()));
+            client.notify_error(e);
+        }
+        report_sender.send(client.finish_report(&result)).ok();
+    })
+}
+
+thread_local! {
+    static ENCODE_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// スレッドごとのエンコード用バッファーとして保持し続ける最大容量
+const ENCODE_BUFFER_RETAIN: usize = 64 * 1024;
+
+impl LogClient {
+    /// レコード単位で書き込む。バッファーに収まらない場合は破棄して数える
+    fn write_encoded(&self, buf: &mut Vec<u8>, record: &RecordBorrow) -> LogOutcome {
+        buf.clear();
+        let prefix = match self.ordering {
+            order::Ordering::Arrival => 0,
+            order::Ordering::Timestamp => {
+                order::reserve_prefix(buf);
+                order::PREFIX_LEN
+            }
+        };
+        serde_cbor::to_writer(&mut *buf, record).expect("serialize error");
+        if prefix > 0 {
+            order::write_prefix(buf, record.elapsed);
+        }
+        if let Some(history) = self.history.as_ref() {
+            history.append(&buf[prefix..]);
+        }
+        if let Some((lane, writer)) = self.priority.as_ref() {
+            // 優先するバッファーが一杯なら通常のバッファーに書く。優先するものは並べ替えない
+            if record.metadata.level >= lane.level && writer.write_record(&buf[prefix..]).is_some()
+            {
+                crate::stats::priority_record_written();
+                if lane.idle.swap(false, Ordering::AcqRel) {
+                    self.wake();
+                } else {
+                    // 送っている最中なら次の周期まで待たせない
+                    self.wake_urgent(record.metadata.level);
+                }
+                return LogOutcome::Accepted;
+            }
+        }
+        match self.writer.write_record(buf) {
+            Some(len) => {
+                crate::stats::record_written(len);
+                self.wake_urgent(record.metadata.level);
+                LogOutcome::Accepted
+            }
+            None => {
+                crate::health::record_dropped(1);
+                LogOutcome::DroppedFull
+            }
+        }
+    }
+
+    /// 周期を待たずに送らせる
+    fn wake(&self) {
+        self.close_ch
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+            .send(SenderEvent::Wake)
+            .ok();
+    }
+
+    /// 急ぐレコードなら送信スレッドを起こす。前に起こしてから間隔が空いていなければ何もしない
+    fn wake_urgent(&self, level: Level) {
+        let Some(urgent) = self.urgent.as_ref() else {
+            return;
+        };
+        if level < urgent.level {
+            return;
+        }
+        let now = Instant::now();
+        {
+            let mut last = self
+                .last_urgent
+                .lock()
+                .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+            if last.is_some_and(|x| now.saturating_duration_since(x) < urgent.interval) {
+                return;
+            }
+            *last = Some(now);
+        }
+        self.wake();
+    }
+}
+
 impl Log for LogClient {
     fn enabled(&self, _metadata: &MetadataBorrow) -> bool {
         true
     }
 
-    fn log(&self, record: &RecordBorrow) {
-        let mut writer = self
-            .writer
-            .lock()
-            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
-        serde_cbor::to_writer(writer.deref_mut(), record).expect("serialize error");
+    fn log(&self, record: &RecordBorrow) -> LogOutcome {
+        // シリアライズはロックの外で行い、ロック中はコピーだけにする
+        let outcome = ENCODE_BUFFER.with(|buf| match buf.try_borrow_mut() {
+            Ok(mut buf) => {
+                let outcome = self.write_encoded(&mut buf, record);
+                if buf.capacity() > ENCODE_BUFFER_RETAIN {
+                    buf.clear();
+                    buf.shrink_to(ENCODE_BUFFER_RETAIN);
+                }
+                outcome
+            }
+            // シリアライズ中に再入した場合
+            Err(_) => self.write_encoded(&mut Vec::new(), record),
+        });
+        if let Some(watchdog) = self.watchdog.as_ref() {
+            watchdog.check(record.elapsed);
+        }
+        outcome
     }
 
     fn flush(&self) {
+        if let Some(watchdog) = self.watchdog.as_ref() {
+            watchdog.check(crate::session::elapsed());
+        }
         let close = self
             .close_ch
             .lock()
             .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
-        close.send(()).ok();
+        close.send(CloseReason::Flush.into()).ok();
     }
 }
 
@@ -288,81 +1232,72 @@ impl Drop for LogClient {
             .close_ch
             .lock()
             .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
-        close.send(()).ok();
+        // flushしていればその理由で閉じている
+        close.send(CloseReason::ClientDropped.into()).ok();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
-    use std::net::{TcpListener, ToSocketAddrs};
     use std::sync::mpsc::channel;
-    use std::thread::{self, JoinHandle};
     use std::time::Duration;
-    use tungstenite::{accept, Message};
-    use url::Url;
 
-    use crate::buffer::SwapBuffer;
-    use crate::client::WebsocketClient;
+    use crate::buffer::Growth;
+    use crate::client::record_boundary;
+    use crate::Record;
 
-    /// テスト用の受信サーバー
-    fn ws_server<A: ToSocketAddrs>(addr: A) -> JoinHandle<Vec<u8>> {
-        use bytes::BufMut;
-        let server = TcpListener::bind(addr).unwrap();
-        let (sender, receiver) = channel();
-        // dummy server
-        let handle = thread::spawn(move || {
-            {
-                sender.send(()).unwrap();
-            }
-            let mut buf = Vec::new();
-            let (stream, addr) = server.accept().unwrap();
-            let mut ws = accept(stream).unwrap();
-            loop {
-                let msg = match ws.read_message() {
-                    Ok(x) => x,
-                    // close stream
-                    Err(_e) => {
-                        log::warn!("ws message error at {}, {:?}", &addr, _e);
-                        break;
-                    }
-                };
-                match msg {
-                    Message::Text(ref x) => {
-                        buf.put(x.as_bytes());
-                    }
-                    Message::Binary(x) => {
-                        buf.put(&x[..]);
-                    }
-                    Message::Close(_) => {
-                        break;
+    // サーバーに送るテストだけで使う
+    #[cfg(feature = "client-ws")]
+    use {
+        crate::buffer::SwapBuffer,
+        crate::client::{NiceMode, WebsocketClient},
+        crate::protocol::CloseReason,
+        crate::testing::TestCollector,
+        std::{io::Write, ops::DerefMut, thread},
+    };
+
+    /// テスト用の受信サーバーを待つ上限
+    #[cfg(feature = "client-ws")]
+    const WAIT: Duration = Duration::from_secs(5);
+
+    /// 送信スレッドが挟んだ接続状態のレコードを取り除く
+    fn strip_status_records(buf: &[u8]) -> Vec<u8> {
+        let mut rest = buf;
+        let mut result = Vec::new();
+        while !rest.is_empty() {
+            let mut de = serde_cbor::Deserializer::from_slice(rest);
+            let len = match serde::Deserialize::deserialize(&mut de) {
+                Ok(Record { category, .. }) => {
+                    let len = de.byte_offset();
+                    if category != super::CLIENT_CATEGORY {
+                        result.extend_from_slice(&rest[..len]);
                     }
-                    _ => unimplemented!(),
+                    len
                 }
-            }
-            buf
-        });
-
-        // wait server ready
-        receiver.recv().unwrap();
-        handle
+                // レコード以外のデータはそのまま残す
+                Err(_) => {
+                    result.extend_from_slice(rest);
+                    rest.len()
+                }
+            };
+            rest = &rest[len..];
+        }
+        result
     }
 
     /// 送信スレッドのテスト
+    #[cfg(feature = "client-ws")]
     #[test]
     fn test_websocket_client() {
-        // 並行してテスト実行できるようにportをずらす
-        let addr = "localhost:9002";
-        let server_addr = format!("ws://{}/", addr);
-        let handle = ws_server(addr);
+        let collector = TestCollector::start().unwrap();
         let test_data = "Nkmm Drawings\n".as_bytes();
 
         // build client
         let (sender, receiver) = channel();
-        let url = Url::parse(&server_addr).unwrap();
+        let url = collector.url();
         let buf = SwapBuffer::new(1024);
         let writer = buf.get_writer();
-        let mut client = WebsocketClient::builder(url, buf, receiver)
+        let mut client = WebsocketClient::builder(url.into(), buf, receiver)
             .tick_duration(Duration::from_millis(50))
             .build();
 
@@ -379,9 +1314,1057 @@ mod tests {
             );
             thread::sleep(Duration::from_millis(10));
         }
-        sender.send(()).unwrap();
+        sender.send(CloseReason::Flush.into()).unwrap();
         handle_client.join().unwrap();
-        let buf = handle.join().unwrap();
+        collector.wait_for_close(WAIT).unwrap();
+        let buf = strip_status_records(&collector.bytes());
         assert_eq!(buf.len(), test_data.len() * 20);
     }
+    #[test]
+    fn test_record_boundary() {
+        crate::session_init();
+        let mut buf = Vec::new();
+        let mut ends = Vec::new();
+        for i in 0..5_u8 {
+            let r = devlog!(crate::Level::Info, "cat", "msg", "i", i);
+            serde_cbor::to_writer(&mut buf, &r).unwrap();
+            ends.push(buf.len());
+        }
+        assert_eq!(record_boundary(&buf, buf.len()), buf.len());
+        assert_eq!(record_boundary(&buf, ends[2]), ends[2]);
+        assert_eq!(record_boundary(&buf, ends[2] + 1), ends[2]);
+        // 1レコードより小さい場合も先頭のレコードは返す
+        assert_eq!(record_boundary(&buf, 1), ends[0]);
+        assert_eq!(record_boundary(&[], 10), 0);
+    }
+
+    /// 送信量を絞っても、バッファーに収まる量なら欠落しないことを確認する
+    #[cfg(feature = "client-ws")]
+    #[test]
+    fn test_websocket_client_nice_mode() {
+        crate::session_init();
+        let collector = TestCollector::start().unwrap();
+
+        let (sender, receiver) = channel();
+        let url = collector.url();
+        // 送り残しは初期サイズまでしか持ち越さない
+        let buf = SwapBuffer::new(64 * 1024);
+        let writer = buf.get_writer();
+        let mut client = WebsocketClient::builder(url.into(), buf, receiver)
+            .tick_duration(Duration::from_millis(20))
+            .nice(Some(NiceMode {
+                bytes_per_tick: 128,
+                chunk_size: 64,
+                yield_between_chunks: true,
+            }))
+            .build();
+        let handle_client = thread::spawn(move || {
+            client.run().unwrap();
+        });
+
+        let count = 100_u32;
+        for i in 0..count {
+            let r = devlog!(crate::Level::Info, "cat", "msg", "i", i);
+            serde_cbor::to_writer(writer.lock().unwrap().deref_mut(), &r).unwrap();
+            if i % 10 == 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        sender.send(CloseReason::Flush.into()).unwrap();
+        handle_client.join().unwrap();
+        collector.wait_for_close(WAIT).unwrap();
+        let received = collector.records();
+        assert_eq!(received.len(), count as usize);
+        for (i, r) in received.iter().enumerate() {
+            assert_eq!(
+                r.key_values().unwrap().get("i"),
+                Some(&crate::Value::U64(i as u64))
+            );
+        }
+    }
+    /// サーバーからの報告がhealthとon_errorに伝わることを確認する
+    #[cfg(feature = "client-ws")]
+    #[test]
+    fn test_websocket_client_server_report() {
+        use crate::protocol::{DecodeErrorReport, ServerMessage};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static CALLED: AtomicUsize = AtomicUsize::new(0);
+
+        let collector = TestCollector::start().unwrap();
+        collector.send(&ServerMessage::DecodeError(DecodeErrorReport {
+            count: 1,
+            last_error: "broken".to_string(),
+            byte_offset: 3,
+        }));
+
+        let (sender, receiver) = channel();
+        let mut client =
+            WebsocketClient::builder(collector.url().into(), SwapBuffer::new(1024), receiver)
+                .tick_duration(Duration::from_millis(20))
+                .on_error(Some(|e| {
+                    assert!(matches!(e, crate::Error::ServerReport(_)));
+                    CALLED.fetch_add(1, Ordering::SeqCst);
+                }))
+                .build();
+        let handle_client = thread::spawn(move || {
+            client.run().unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        sender.send(CloseReason::Flush.into()).unwrap();
+        handle_client.join().unwrap();
+        collector.wait_for_close(WAIT).unwrap();
+
+        assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+        let health = crate::health();
+        assert!(health.server_error_reports >= 1);
+        assert_eq!(health.last_server_error.unwrap().last_error, "broken");
+    }
+
+    /// サーバーが解釈できないメッセージを混ぜてもレコードを送り続けることを確認する
+    #[cfg(feature = "client-ws")]
+    #[test]
+    fn test_websocket_client_garbage_frames() {
+        use crate::protocol::{Ack, ServerMessage};
+
+        crate::session_init();
+        let collector = TestCollector::start().unwrap();
+        let ignored = crate::health().ignored_commands;
+        let (sender, receiver) = channel();
+        let buf = SwapBuffer::new(4096);
+        let writer = buf.get_writer();
+        let mut client = WebsocketClient::builder(collector.url().into(), buf, receiver)
+            .tick_duration(Duration::from_millis(10))
+            .build();
+        let handle_client = thread::spawn(move || {
+            client.run().unwrap();
+        });
+
+        for i in 0..5_u32 {
+            collector.send_text("hello");
+            collector.send_ping(vec![1, 2, 3]);
+            collector.send_binary(vec![0xff, 0x00, i as u8]);
+            collector.send(&ServerMessage::Ack(Ack {
+                received: i as u64,
+                server_time_ms: crate::clock::now_unix_ms(),
+            }));
+            let r = devlog!(crate::Level::Info, "cat", "msg", "i", i);
+            serde_cbor::to_writer(writer.lock().unwrap().deref_mut(), &r).unwrap();
+            collector.wait_for_records(i as usize + 1, WAIT).unwrap();
+        }
+        sender.send(CloseReason::Flush.into()).unwrap();
+        handle_client.join().unwrap();
+        collector.wait_for_close(WAIT).unwrap();
+
+        assert_eq!(collector.records().len(), 5);
+        assert!(crate::health().ignored_commands >= ignored + 8);
+    }
+
+    /// 解釈できないメッセージが上限を超えたら一度だけ通知し、読むのをやめて送信は続ける
+    #[cfg(feature = "client-ws")]
+    #[test]
+    fn test_websocket_client_protocol_error_budget() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static CALLED: AtomicUsize = AtomicUsize::new(0);
+
+        crate::session_init();
+        let collector = TestCollector::start().unwrap();
+        for i in 0..5_u8 {
+            collector.send_binary(vec![0xff, i]);
+        }
+        let (sender, receiver) = channel();
+        let buf = SwapBuffer::new(4096);
+        let writer = buf.get_writer();
+        let mut client = WebsocketClient::builder(collector.url().into(), buf, receiver)
+            .tick_duration(Duration::from_millis(10))
+            .protocol_error_budget(2)
+            .on_error(Some(|e| {
+                assert!(matches!(e, crate::Error::ProtocolErrorBudget(2)), "{}", e);
+                CALLED.fetch_add(1, Ordering::SeqCst);
+            }))
+            .build();
+        let handle_client = thread::spawn(move || {
+            client.run().unwrap();
+        });
+
+        let start = std::time::Instant::now();
+        while CALLED.load(Ordering::SeqCst) == 0 {
+            assert!(start.elapsed() < WAIT, "budget was not exhausted");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // 読まなくなった後の不正なメッセージは通知しない
+        collector.send_text("more");
+        for i in 0..3_u32 {
+            let r = devlog!(crate::Level::Info, "cat", "after", "i", i);
+            serde_cbor::to_writer(writer.lock().unwrap().deref_mut(), &r).unwrap();
+        }
+        collector.wait_for_records(3, WAIT).unwrap();
+        sender.send(CloseReason::Flush.into()).unwrap();
+        handle_client.join().unwrap();
+        collector.wait_for_close(WAIT).unwrap();
+
+        assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+    }
+
+    /// サーバーの応答の時刻から時計のずれを求める
+    #[cfg(feature = "client-ws")]
+    #[test]
+    fn test_websocket_client_clock_offset() {
+        use crate::protocol::{Ack, ServerMessage, CLIENT_TIME_HEADER};
+
+        crate::session_init();
+        let collector = TestCollector::start().unwrap();
+        // サーバーの時計が5秒進んでいる
+        collector.send(&ServerMessage::Ack(Ack {
+            received: 1,
+            server_time_ms: crate::clock::now_unix_ms() + 5000,
+        }));
+
+        let (sender, receiver) = channel();
+        let mut client =
+            WebsocketClient::builder(collector.url().into(), SwapBuffer::new(1024), receiver)
+                .tick_duration(Duration::from_millis(20))
+                .build();
+        let handle_client = thread::spawn(move || {
+            client.run().unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        sender.send(CloseReason::Flush.into()).unwrap();
+        handle_client.join().unwrap();
+        collector.wait_for_close(WAIT).unwrap();
+        let client_time = collector
+            .handshake_header(CLIENT_TIME_HEADER)
+            .and_then(|x| x.parse::<i64>().ok())
+            .expect("client time header");
+        assert!((crate::clock::now_unix_ms() - client_time).abs() < 60_000);
+
+        let offset = crate::health().clock_offset_ms.unwrap();
+        assert!((offset - 5000).abs() < 1000, "{}", offset);
+        assert_eq!(crate::stats_snapshot().clock_offset_ms, Some(offset));
+
+        crate::clock::install(Some(Duration::from_secs(1)));
+        assert_eq!(crate::clock::stamp(), Some(offset));
+        crate::clock::install(Some(Duration::from_secs(10)));
+        assert_eq!(crate::clock::stamp(), None);
+        crate::clock::install(None);
+    }
+
+    /// subprotocolを返さない古いサーバーには従来のCBORで送る
+    #[cfg(feature = "client-ws")]
+    #[test]
+    fn test_old_server_fallback() {
+        use crate::{protocol::Codec, transport::Transport, ws::WebsocketTransport};
+        let collector = TestCollector::start().unwrap();
+        let mut transport = WebsocketTransport::connect(&collector.url(), &Codec::ALL).unwrap();
+        assert_eq!(transport.codec(), Codec::Cbor);
+
+        let r = devlog!(crate::Level::Info, "cat", "msg");
+        let data = serde_cbor::to_vec(&r).unwrap();
+        transport.send(&data).unwrap();
+        transport.close().unwrap();
+        collector.wait_for_close(WAIT).unwrap();
+        assert_eq!(collector.bytes(), data);
+    }
+
+    /// ロック外でのシリアライズで送信されるバイト列が変わらないことを確認する
+    #[test]
+    fn test_log_client_wire_bytes() {
+        // リングバッファーでも同じバイト列になる
+        for single_producer in [false, true] {
+            log_client_wire_bytes(single_producer);
+        }
+    }
+
+    fn log_client_wire_bytes(single_producer: bool) {
+        use crate::{Log, MockTransport};
+        crate::session_init();
+        let transport = MockTransport::capture();
+        let (client, handle) = super::LogClient::new(
+            super::Connector::Transport(Some(Box::new(transport.clone()))),
+            super::ClientConfig {
+                buffer_size: 64 * 1024,
+                swap_duration: Duration::from_millis(10),
+                single_producer,
+                ..Default::default()
+            },
+        );
+
+        let mut expected = Vec::new();
+        for i in 0..20_u32 {
+            let mut kv = crate::KVBorrow::new();
+            kv.insert("i", i.into());
+            let r = crate::RecordBorrow {
+                metadata: crate::MetadataBorrow::new(crate::Level::Info, "test"),
+                elapsed: Duration::from_millis(i as u64),
+                category: "cat",
+                module_path: Some(module_path!()),
+                file: Some(file!()),
+                line: Some(line!()),
+                message: "msg",
+                kv: Some(kv),
+            };
+            client.log(&r);
+            serde_cbor::to_writer(&mut expected, &r).unwrap();
+        }
+        // バッファーより大きいレコードは途中まで書かずに破棄する
+        let dropped = crate::health().dropped_records;
+        let blob = vec![0xa5_u8; 128 * 1024];
+        let mut kv = crate::KVBorrow::new();
+        kv.insert("data", (&blob).into());
+        client.log(&crate::RecordBorrow {
+            metadata: crate::MetadataBorrow::new(crate::Level::Info, "test"),
+            elapsed: Duration::from_millis(0),
+            category: "cat",
+            module_path: None,
+            file: None,
+            line: None,
+            message: "large",
+            kv: Some(kv),
+        });
+        assert!(crate::health().dropped_records > dropped);
+
+        client.flush();
+        handle.join().unwrap();
+        assert_eq!(strip_status_records(&transport.captured()), expected);
+    }
+
+    #[test]
+    fn test_log_client_growth() {
+        use crate::{Log, MockTransport};
+        crate::session_init();
+        let transport = MockTransport::capture();
+        let (client, handle) = super::LogClient::new(
+            super::Connector::Transport(Some(Box::new(transport.clone()))),
+            super::ClientConfig {
+                buffer_size: 1024,
+                growth: Growth::Doubling { max: 64 * 1024 },
+                swap_duration: Duration::from_secs(10),
+                ..Default::default()
+            },
+        );
+
+        // 入れ替えの前に初期サイズを超えて書いても破棄しない
+        let dropped = crate::health().dropped_records;
+        let mut expected = Vec::new();
+        for i in 0..100_u32 {
+            let mut kv = crate::KVBorrow::new();
+            kv.insert("i", i.into());
+            let r = crate::RecordBorrow {
+                metadata: crate::MetadataBorrow::new(crate::Level::Info, "test"),
+                elapsed: Duration::from_millis(i as u64),
+                category: "cat",
+                module_path: None,
+                file: None,
+                line: None,
+                message: "burst",
+                kv: Some(kv),
+            };
+            client.log(&r);
+            serde_cbor::to_writer(&mut expected, &r).unwrap();
+        }
+        assert!(expected.len() > 1024);
+        assert_eq!(crate::health().dropped_records, dropped);
+
+        client.flush();
+        handle.join().unwrap();
+        assert_eq!(strip_status_records(&transport.captured()), expected);
+        // 初期サイズごとに分けて送る
+        assert!(transport.messages() as usize >= expected.len() / 1024);
+    }
+
+    /// 複数のスレッドから書いても周期ごとに`elapsed`の順で送る
+    #[test]
+    fn test_log_client_timestamp_ordering() {
+        use crate::{Log, Transport};
+        use std::sync::{Arc, Mutex};
+
+        /// メッセージごとに残す通信路
+        #[derive(Clone, Default)]
+        struct Messages(Arc<Mutex<Vec<Vec<u8>>>>);
+        impl Transport for Messages {
+            fn send(&mut self, buf: &[u8]) -> crate::Result<()> {
+                self.0.lock().unwrap().push(buf.to_vec());
+                Ok(())
+            }
+        }
+
+        const THREADS: u64 = 8;
+        const RECORDS: u64 = 500;
+        crate::session_init();
+        let transport = Messages::default();
+        let (client, handle) = super::LogClient::new(
+            super::Connector::Transport(Some(Box::new(transport.clone()))),
+            super::ClientConfig {
+                buffer_size: 1024 * 1024,
+                swap_duration: Duration::from_millis(5),
+                ordering: crate::Ordering::Timestamp,
+                ..Default::default()
+            },
+        );
+        let client = Arc::new(client);
+        let threads = (0..THREADS)
+            .map(|t| {
+                let client = client.clone();
+                std::thread::spawn(move || {
+                    for i in 0..RECORDS {
+                        let mut kv = crate::KVBorrow::new();
+                        kv.insert("i", (t * RECORDS + i).into());
+                        let r = crate::RecordBorrow {
+                            metadata: crate::MetadataBorrow::new(crate::Level::Info, "test"),
+                            elapsed: crate::session::elapsed(),
+                            category: "cat",
+                            module_path: None,
+                            file: None,
+                            line: None,
+                            message: "ordered",
+                            kv: Some(kv),
+                        };
+                        client.log(&r);
+                        if i % 64 == 0 {
+                            std::thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+        client.flush();
+        handle.join().unwrap();
+
+        let messages = transport.0.lock().unwrap().clone();
+        let mut received = Vec::new();
+        for message in messages.iter() {
+            let records = serde_cbor::Deserializer::from_slice(&strip_status_records(message))
+                .into_iter::<Record>()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert!(
+                records.windows(2).all(|x| x[0].elapsed <= x[1].elapsed),
+                "{:?}",
+                records.iter().map(|x| x.elapsed).collect::<Vec<_>>()
+            );
+            received.extend(records.into_iter().map(|x| x.kv.unwrap()["i"].clone()));
+        }
+        // 欠けたり重複したりしない
+        received.sort_by_key(|x| match x {
+            crate::Value::U64(x) => *x,
+            _ => unreachable!(),
+        });
+        assert_eq!(
+            received,
+            (0..THREADS * RECORDS)
+                .map(crate::Value::U64)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    /// 遅い通信路で溜まったDebugより後のErrorを先に送る
+    #[test]
+    fn test_log_client_priority() {
+        use crate::{Log, MockTransport, Transport};
+
+        /// 1回の送信ごとに待つ通信路
+        struct Throttled(MockTransport);
+        impl Transport for Throttled {
+            fn send(&mut self, buf: &[u8]) -> crate::Result<()> {
+                std::thread::sleep(Duration::from_millis(2));
+                self.0.send(buf)
+            }
+        }
+
+        crate::session_init();
+        let transport = MockTransport::capture();
+        let (client, handle) = super::LogClient::new(
+            super::Connector::Transport(Some(Box::new(Throttled(transport.clone())))),
+            super::ClientConfig {
+                buffer_size: 64 * 1024,
+                swap_duration: Duration::from_millis(10),
+                nice: Some(super::NiceMode {
+                    bytes_per_tick: 256,
+                    chunk_size: 64,
+                    yield_between_chunks: false,
+                }),
+                priority_level: Some(crate::Level::Error),
+                ..Default::default()
+            },
+        );
+        let log = |level, message, i: u32| {
+            let mut kv = crate::KVBorrow::new();
+            kv.insert("i", i.into());
+            client.log(&crate::RecordBorrow {
+                metadata: crate::MetadataBorrow::new(level, "test"),
+                elapsed: crate::session::elapsed(),
+                category: "cat",
+                module_path: None,
+                file: None,
+                line: None,
+                message,
+                kv: Some(kv),
+            });
+        };
+        for i in 0..200 {
+            log(crate::Level::Debug, "noise", i);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        log(crate::Level::Error, "failed", 0);
+        client.flush();
+        handle.join().unwrap();
+
+        let received = strip_status_records(&transport.captured());
+        let records = serde_cbor::Deserializer::from_slice(&received)
+            .into_iter::<Record>()
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 201);
+        let error = records.iter().position(|x| x.message == "failed").unwrap();
+        // 後から書いたErrorが溜まったDebugより先に届き、時刻は書いたときのまま
+        assert!(error < 100, "error at {}", error);
+        assert!(records[error].elapsed > records[199].elapsed);
+        let debug = records
+            .iter()
+            .filter(|x| x.message == "noise")
+            .map(|x| x.kv.as_ref().unwrap()["i"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(debug, (0..200).collect::<Vec<_>>());
+    }
+
+    /// 待っている間に書いた優先するレコードは周期を待たずに送る
+    #[test]
+    fn test_log_client_priority_wake() {
+        use crate::{Log, MockTransport};
+        use std::time::Instant;
+
+        crate::session_init();
+        let transport = MockTransport::capture();
+        let (client, handle) = super::LogClient::new(
+            super::Connector::Transport(Some(Box::new(transport.clone()))),
+            super::ClientConfig {
+                buffer_size: 64 * 1024,
+                swap_duration: Duration::from_secs(10),
+                priority_level: Some(crate::Level::Warn),
+                ..Default::default()
+            },
+        );
+        let record = |level, message| crate::RecordBorrow {
+            metadata: crate::MetadataBorrow::new(level, "test"),
+            elapsed: crate::session::elapsed(),
+            category: "cat",
+            module_path: None,
+            file: None,
+            line: None,
+            message,
+            kv: None,
+        };
+        // 送信スレッドが最初の周期を待ち始めるまで待つ
+        std::thread::sleep(Duration::from_millis(50));
+        client.log(&record(crate::Level::Info, "later"));
+        client.log(&record(crate::Level::Warn, "now"));
+        let start = Instant::now();
+        while transport.messages() == 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "not sent before the tick"
+            );
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        // 起こされた周期では溜まっていた通常のレコードも後ろに続けて送る
+        let messages = transport
+            .records()
+            .into_iter()
+            .filter(|x| x.category != super::CLIENT_CATEGORY)
+            .map(|x| x.message)
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["now", "later"]);
+
+        client.flush();
+        handle.join().unwrap();
+    }
+
+    /// 急ぐレコードは通常のバッファーに書き、周期を待たずに溜まった分と一緒に送る
+    #[test]
+    fn test_log_client_urgent() {
+        use crate::{Log, MockTransport};
+        use std::time::Instant;
+
+        crate::session_init();
+        let transport = MockTransport::capture();
+        let (client, handle) = super::LogClient::new(
+            super::Connector::Transport(Some(Box::new(transport.clone()))),
+            super::ClientConfig {
+                buffer_size: 64 * 1024,
+                swap_duration: Duration::from_secs(10),
+                urgent: Some(super::UrgentFlush {
+                    level: crate::Level::Error,
+                    interval: Duration::from_secs(10),
+                }),
+                ..Default::default()
+            },
+        );
+        let record = |level, message| crate::RecordBorrow {
+            metadata: crate::MetadataBorrow::new(level, "test"),
+            elapsed: crate::session::elapsed(),
+            category: "cat",
+            module_path: None,
+            file: None,
+            line: None,
+            message,
+            kv: None,
+        };
+        let messages = || {
+            transport
+                .records()
+                .into_iter()
+                .filter(|x| x.category != super::CLIENT_CATEGORY)
+                .map(|x| x.message)
+                .collect::<Vec<_>>()
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        client.log(&record(crate::Level::Warn, "before"));
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(transport.messages(), 0);
+        let start = Instant::now();
+        client.log(&record(crate::Level::Error, "failed"));
+        while transport.messages() == 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "not sent before the tick"
+            );
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(messages(), ["before", "failed"]);
+
+        // 間隔の間は起こさない
+        client.log(&record(crate::Level::Error, "again"));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(messages(), ["before", "failed"]);
+
+        client.flush();
+        handle.join().unwrap();
+        assert_eq!(messages(), ["before", "failed", "again"]);
+    }
+
+    /// 終了時に送れたかどうかを送信スレッドから受け取る
+    #[cfg(feature = "client-ws")]
+    #[test]
+    fn test_flush_report() {
+        use crate::Log;
+        crate::session_init();
+        let log = |client: &super::LogClient| {
+            for _ in 0..3 {
+                client.log(&crate::RecordBorrow {
+                    metadata: crate::MetadataBorrow::new(crate::Level::Info, "test"),
+                    elapsed: Duration::from_millis(1),
+                    category: "cat",
+                    module_path: None,
+                    file: None,
+                    line: None,
+                    message: "flush",
+                    kv: None,
+                });
+            }
+        };
+        let new_client = |collector: &TestCollector| {
+            super::LogClient::new(
+                collector.url().into(),
+                super::ClientConfig {
+                    buffer_size: 64 * 1024,
+                    swap_duration: Duration::from_millis(20),
+                    ..Default::default()
+                },
+            )
+        };
+
+        // 受信中のサーバー
+        let collector = TestCollector::start().unwrap();
+        let (client, handle) = new_client(&collector);
+        log(&client);
+        client.flush();
+        let report = handle.finish(None).unwrap();
+        collector.wait_for_close(WAIT).unwrap();
+        assert!(report.transport_ok, "{}", report);
+        assert_eq!(report.records_dropped, 0);
+        assert!(report.records_sent >= 3, "{}", report);
+        assert!(report.bytes_sent > 0);
+        assert!(collector.bytes().len() as u64 >= report.bytes_sent);
+
+        // 接続した後に止まったサーバー
+        let collector = TestCollector::start().unwrap();
+        let (client, handle) = new_client(&collector);
+        collector.wait_for_connections(1, WAIT).unwrap();
+        drop(collector);
+        thread::sleep(Duration::from_millis(100));
+        log(&client);
+        client.flush();
+        let report = handle.finish(None).unwrap();
+        assert!(!report.transport_ok, "{}", report);
+        assert_eq!(report.records_sent, 0);
+        assert!(report.records_dropped >= 3, "{}", report);
+        assert!(report.to_string().starts_with("flush failed"));
+    }
+
+    /// 送信が戻らないまま期限を過ぎたら、残っているレコードを書き出す
+    #[test]
+    fn test_flush_deadline_spill() {
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Condvar, Mutex,
+        };
+
+        use crate::{transport::Transport, Log};
+
+        /// 送信を始めたことを知らせ、解放されるまで戻らない
+        struct Blackhole {
+            entered: Arc<AtomicBool>,
+            release: Arc<(Mutex<bool>, Condvar)>,
+        }
+        impl Transport for Blackhole {
+            fn send(&mut self, _buf: &[u8]) -> crate::Result<()> {
+                self.entered.store(true, Ordering::SeqCst);
+                let (released, cond) = &*self.release;
+                let _released = cond.wait_while(released.lock().unwrap(), |x| !*x).unwrap();
+                Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into())
+            }
+        }
+
+        crate::session_init();
+        let dir = std::env::temp_dir().join(format!("uplog-spill-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("spill.cbor");
+        std::fs::remove_file(&path).ok();
+
+        let entered = Arc::new(AtomicBool::new(false));
+        let release = Arc::new((Mutex::new(false), Condvar::new()));
+        let transport = Blackhole {
+            entered: entered.clone(),
+            release: release.clone(),
+        };
+        let (client, handle) = super::LogClient::new(
+            super::Connector::Transport(Some(Box::new(transport))),
+            super::ClientConfig {
+                buffer_size: 64 * 1024,
+                swap_duration: Duration::from_millis(10),
+                priority_level: Some(crate::Level::Error),
+                spill_path: Some(path.clone()),
+                ..Default::default()
+            },
+        );
+        let log = |level, message| {
+            client.log(&crate::RecordBorrow {
+                metadata: crate::MetadataBorrow::new(level, "test"),
+                elapsed: crate::session::elapsed(),
+                category: "cat",
+                module_path: None,
+                file: None,
+                line: None,
+                message,
+                kv: None,
+            });
+        };
+        // 最初の送信で止まるまで待つ
+        let start = std::time::Instant::now();
+        while !entered.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(5), "send not started");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        log(crate::Level::Info, "a");
+        log(crate::Level::Error, "urgent");
+        log(crate::Level::Info, "b");
+        client.flush();
+
+        let (report, handle) = handle.finish_or_spill(Duration::from_millis(50));
+        assert!(report.incomplete, "{}", report);
+        assert!(!report.transport_ok);
+        assert_eq!(report.records_spilled, 3);
+        assert!(report.to_string().starts_with("flush incomplete"));
+        // 優先するレコードが先
+        let buf = std::fs::read(&path).unwrap();
+        let messages = serde_cbor::Deserializer::from_slice(&buf)
+            .into_iter::<Record>()
+            .map(|x| x.unwrap().message)
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["urgent", "a", "b"]);
+
+        // 送信スレッドが終わっていれば書き出さない
+        *release.0.lock().unwrap() = true;
+        release.1.notify_all();
+        let (report, rest) = handle.unwrap().finish_or_spill(Duration::from_secs(5));
+        assert!(!report.incomplete);
+        assert!(rest.is_none());
+        assert_eq!(std::fs::read(&path).unwrap(), buf);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_apply_command() {
+        use crate::protocol::ControlCommand;
+        use crate::Level;
+        let unknown = ControlCommand {
+            cmd: "reboot".to_string(),
+            ..ControlCommand::upload_history(None, None)
+        };
+        assert!(super::apply_command(&unknown).is_err());
+        let no_level = ControlCommand {
+            level: None,
+            ..ControlCommand::set_level(Level::Info, None)
+        };
+        assert!(super::apply_command(&no_level).is_err());
+        let invalid = ControlCommand::set_level(Level::Info, Some("a.**b".to_string()));
+        assert!(super::apply_command(&invalid).is_err());
+
+        let cmd = ControlCommand::set_level(Level::Error, Some("apply_test.**".to_string()));
+        super::apply_command(&cmd).unwrap();
+        assert!(!crate::level_enabled(Level::Warn, "apply_test.x"));
+        let cmd = ControlCommand::set_level(Level::Trace, Some("apply_test.**".to_string()));
+        super::apply_command(&cmd).unwrap();
+    }
+
+    /// 送信周期ごとに統計値が通知され、カウンターが増えていくことを確認する
+    #[test]
+    fn test_stats_observer() {
+        use crate::{Log, LoggerStats, MockTransport};
+        use std::sync::Mutex;
+        crate::session_init();
+        let (sender, receiver) = channel::<LoggerStats>();
+        let sender = Mutex::new(sender);
+        let observer = crate::stats::ObserverConfig {
+            f: std::sync::Arc::new(move |stats: &LoggerStats| {
+                sender.lock().unwrap().send(stats.clone()).ok();
+            }),
+            interval: Duration::from_millis(20),
+        };
+        let (client, handle) = super::LogClient::new(
+            super::Connector::Transport(Some(Box::new(MockTransport::new()))),
+            super::ClientConfig {
+                buffer_size: 64 * 1024,
+                swap_duration: Duration::from_millis(10),
+                stats_observer: Some(observer),
+                ..Default::default()
+            },
+        );
+
+        let mut snapshots = Vec::new();
+        while snapshots.len() < 4 {
+            for i in 0..10_u32 {
+                client.log(&crate::RecordBorrow {
+                    metadata: crate::MetadataBorrow::new(crate::Level::Info, "test"),
+                    elapsed: Duration::from_millis(i as u64),
+                    category: "cat",
+                    module_path: None,
+                    file: None,
+                    line: None,
+                    message: "msg",
+                    kv: None,
+                });
+            }
+            snapshots.push(receiver.recv_timeout(Duration::from_secs(1)).unwrap());
+        }
+        client.flush();
+        handle.join().unwrap();
+
+        // 統計値はプロセス全体で共有するので、他のテストと並行しても崩れない値だけ確認する
+        for pair in snapshots.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            assert!(b.records_written > a.records_written, "{:?} {:?}", a, b);
+            assert!(b.bytes_sent > a.bytes_sent, "{:?} {:?}", a, b);
+        }
+        assert!(snapshots.iter().any(|x| x.records_per_sec > 0.0));
+        assert!(crate::stats_snapshot().records_written >= snapshots[3].records_written);
+    }
+
+    /// 頼まれた範囲の履歴を別の接続で送ることを確認する
+    #[cfg(feature = "client-ws")]
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn test_upload_history() {
+        use crate::{
+            history::RingFileSink,
+            protocol::{ControlCommand, ServerMessage},
+            MockTransport, Transport,
+        };
+        use std::{sync::Arc, time::Instant};
+        crate::session_init();
+        let path = std::env::temp_dir().join(format!("uplog-upload-{}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let history = Arc::new(RingFileSink::open(&path, 4096).unwrap());
+        for i in 0..20_u64 {
+            let mut r = devlog!(crate::Level::Info, "cat", "history", "i", i);
+            r.elapsed = Duration::from_secs(i);
+            history.append(&serde_cbor::to_vec(&r).unwrap());
+        }
+        let uploaded = MockTransport::capture();
+        let connector = {
+            let uploaded = uploaded.clone();
+            super::Connector::Factory(Arc::new(move || {
+                Ok(Box::new(uploaded.clone()) as Box<dyn Transport>)
+            }))
+        };
+        let (_sender, receiver) = channel();
+        let mut client = WebsocketClient::builder(connector, SwapBuffer::new(1024), receiver)
+            .history(Some(history))
+            .build();
+        let cmd = ServerMessage::Control(ControlCommand::upload_history(Some(5.0), Some(7.5)));
+        client.handle_server_message(&serde_cbor::to_vec(&cmd).unwrap());
+
+        let start = Instant::now();
+        while uploaded.records().len() < 3 && start.elapsed() < WAIT {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let elapsed = uploaded
+            .records()
+            .iter()
+            .map(|x| x.elapsed.as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(elapsed, [5, 6, 7]);
+        std::fs::remove_file(&path).ok();
+
+        // 別のセッションとして送る
+        let url = url::Url::parse("ws://localhost:8040/ingest?session=a&x=1").unwrap();
+        let Some(super::Connector::Url(url, _)) = super::Connector::from(url).for_new_session()
+        else {
+            panic!("url connector");
+        };
+        let query = url.query_pairs().into_owned().collect::<Vec<_>>();
+        assert_eq!(query[0], ("x".to_string(), "1".to_string()));
+        assert_eq!(query[1].0, crate::protocol::SESSION_QUERY);
+        assert_ne!(query[1].1, "a");
+    }
+
+    /// サーバーが切断しても再接続し、前後に接続状態のレコードが入ることを確認する
+    #[cfg(feature = "client-ws")]
+    #[test]
+    fn test_websocket_client_reconnect() {
+        crate::session_init();
+        let collector = TestCollector::start().unwrap();
+
+        let (sender, receiver) = channel();
+        let buf = SwapBuffer::new(4096);
+        let writer = buf.get_writer();
+        let mut client = WebsocketClient::builder(collector.url().into(), buf, receiver)
+            .tick_duration(Duration::from_millis(10))
+            .build();
+        let handle_client = thread::spawn(move || {
+            client.run().unwrap();
+        });
+
+        for i in 0..3_u32 {
+            let r = devlog!(crate::Level::Info, "cat", "before", "i", i);
+            serde_cbor::to_writer(writer.lock().unwrap().deref_mut(), &r).unwrap();
+        }
+        // 数レコード受け取ったら閉じずに切断する
+        collector.wait_for_records(3, WAIT).unwrap();
+        collector.disconnect();
+        // 切断を検知して再接続するまで待つ
+        collector.wait_for_connections(2, WAIT).unwrap();
+        for i in 0..3_u32 {
+            let r = devlog!(crate::Level::Info, "cat", "after", "i", i);
+            serde_cbor::to_writer(writer.lock().unwrap().deref_mut(), &r).unwrap();
+        }
+        sender.send(CloseReason::Flush.into()).unwrap();
+        handle_client.join().unwrap();
+        collector.wait_for_close(WAIT).unwrap();
+
+        let records = collector.all_records();
+        let messages = records
+            .iter()
+            .map(|x| x.message.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(messages[..4], ["connected", "before", "before", "before"]);
+        assert_eq!(records[0].category, super::CLIENT_CATEGORY);
+        let pos = |m: &str| messages.iter().position(|x| *x == m).unwrap();
+        assert!(pos("disconnected") < pos("reconnected"));
+        assert!(pos("reconnected") < pos("after"));
+        assert_eq!(messages.iter().filter(|x| **x == "after").count(), 3);
+    }
+
+    /// 書き込みが戻らない接続を見張りが切り、作り直したスレッドが続きを送ることを確認する
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn test_log_client_watchdog() {
+        use std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc, Condvar, Mutex,
+            },
+            thread,
+            time::Instant,
+        };
+
+        use crate::{
+            transport::{AbortHandle, Transport},
+            Log, MockTransport,
+        };
+        const WAIT: Duration = Duration::from_secs(5);
+
+        /// 切られるまで書き込みから戻らない
+        struct StuckTransport(Arc<(Mutex<bool>, Condvar)>);
+        impl Transport for StuckTransport {
+            fn send(&mut self, _buf: &[u8]) -> crate::Result<()> {
+                let (aborted, cond) = &*self.0;
+                let _aborted = cond.wait_while(aborted.lock().unwrap(), |x| !*x).unwrap();
+                Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted).into())
+            }
+            fn abort_handle(&self) -> Option<AbortHandle> {
+                let state = self.0.clone();
+                Some(Box::new(move || {
+                    *state.0.lock().unwrap() = true;
+                    state.1.notify_all();
+                }))
+            }
+        }
+
+        crate::session_init();
+        let transport = MockTransport::capture();
+        let connects = Arc::new(AtomicUsize::new(0));
+        let (t, c) = (transport.clone(), connects.clone());
+        let connector = super::Connector::Factory(Arc::new(move || {
+            let transport: Box<dyn Transport> = match c.fetch_add(1, Ordering::SeqCst) {
+                0 => Box::new(StuckTransport(Default::default())),
+                _ => Box::new(t.clone()),
+            };
+            Ok(transport)
+        }));
+        let (client, handle) = super::LogClient::new(
+            connector,
+            super::ClientConfig {
+                buffer_size: 64 * 1024,
+                swap_duration: Duration::from_millis(10),
+                watchdog_ticks: 5,
+                ..Default::default()
+            },
+        );
+        let log = |message| {
+            client.log(&crate::RecordBorrow {
+                metadata: crate::MetadataBorrow::new(crate::Level::Info, "test"),
+                elapsed: crate::session::elapsed(),
+                category: "cat",
+                module_path: None,
+                file: None,
+                line: None,
+                message,
+                kv: None,
+            });
+        };
+
+        let restarts = crate::stats_snapshot().sender_restarts;
+        let start = Instant::now();
+        while crate::stats_snapshot().sender_restarts == restarts {
+            assert!(start.elapsed() < WAIT, "sender was not restarted");
+            log("stuck");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+        log("after");
+        client.flush();
+        let report = handle.finish(Some(WAIT)).unwrap();
+        assert!(report.transport_ok);
+
+        let messages = transport
+            .records()
+            .into_iter()
+            .filter(|x| x.category != super::CLIENT_CATEGORY)
+            .map(|x| x.message)
+            .collect::<Vec<_>>();
+        assert_eq!(messages.last().map(String::as_str), Some("after"));
+    }
 }
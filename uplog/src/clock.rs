@@ -0,0 +1,113 @@
+//! サーバーとの時計のずれ
+//!
+//! サーバーは応答に自分の時刻を入れて返すので、受け取った時の手元の時刻との差を平滑化して持つ。
+//! 差には片道の遅延が含まれるが、時計のずれを見つける用途には十分とする
+use std::{
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// ずれが閾値を超えたときにレコードに付けるキー
+pub const CLOCK_OFFSET_KEY: &str = "_clock_offset_ms";
+
+/// 新しい観測値の重み
+const ALPHA: f64 = 0.2;
+/// 値がないことを表す
+const NONE: i64 = i64::MIN;
+
+static ESTIMATOR: Mutex<OffsetEstimator> = Mutex::new(OffsetEstimator::new());
+// ログ出力側から読むのでロックを取らない
+static OFFSET_MS: AtomicI64 = AtomicI64::new(NONE);
+static STAMP_THRESHOLD_MS: AtomicI64 = AtomicI64::new(NONE);
+
+/// サーバーの時刻から手元の時刻を引いた値の指数移動平均
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct OffsetEstimator {
+    ewma: Option<f64>,
+}
+
+impl OffsetEstimator {
+    pub(crate) const fn new() -> Self {
+        Self { ewma: None }
+    }
+
+    /// 観測値を取り込み、平滑化したずれをミリ秒で返す
+    pub(crate) fn observe(&mut self, server_ms: i64, local_ms: i64) -> i64 {
+        let sample = server_ms.saturating_sub(local_ms) as f64;
+        let ewma = match self.ewma {
+            // 最初の観測値はそのまま使う
+            None => sample,
+            Some(prev) => prev + ALPHA * (sample - prev),
+        };
+        self.ewma = Some(ewma);
+        ewma.round() as i64
+    }
+}
+
+/// 手元の時計のUNIX時刻(ミリ秒)
+pub(crate) fn now_unix_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// サーバーから受け取った時刻を取り込む
+pub(crate) fn observe(server_ms: i64) -> i64 {
+    let offset = ESTIMATOR
+        .lock()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+        .observe(server_ms, now_unix_ms());
+    OFFSET_MS.store(offset, Ordering::Release);
+    offset
+}
+
+/// サーバーの時計に対するずれ(ミリ秒)。サーバーの時刻から手元の時刻を引いた値
+///
+/// 時刻を返すサーバーに接続するまでは`None`
+pub(crate) fn offset_ms() -> Option<i64> {
+    Some(OFFSET_MS.load(Ordering::Acquire)).filter(|x| *x != NONE)
+}
+
+/// ずれをレコードに付ける閾値を設定する。`None`の場合は付けない
+pub(crate) fn install(threshold: Option<Duration>) {
+    let threshold = threshold.map_or(NONE, |x| x.as_millis().min(i64::MAX as u128) as i64);
+    STAMP_THRESHOLD_MS.store(threshold, Ordering::Release);
+}
+
+/// 閾値を超えている場合にレコードに付けるずれ
+pub(crate) fn stamp() -> Option<i64> {
+    let threshold = STAMP_THRESHOLD_MS.load(Ordering::Acquire);
+    if threshold == NONE {
+        return None;
+    }
+    offset_ms().filter(|x| x.unsigned_abs() > threshold as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OffsetEstimator;
+
+    #[test]
+    fn test_offset_estimator() {
+        // 手元の時計がサーバーより5秒遅れている
+        let mut estimator = OffsetEstimator::new();
+        let local = 1_700_000_000_000_i64;
+        assert_eq!(estimator.observe(local + 5000, local), 5000);
+
+        // 外れた観測値は一部だけ反映する
+        assert_eq!(estimator.observe(local + 6000, local), 5200);
+        let mut offset = 0;
+        for i in 0..50 {
+            offset = estimator.observe(local + i + 5000, local + i);
+        }
+        assert!((offset - 5000).abs() <= 1, "{}", offset);
+
+        // 手元の時計が進んでいる場合は負の値
+        let mut estimator = OffsetEstimator::new();
+        assert_eq!(estimator.observe(local - 5000, local), -5000);
+    }
+}
@@ -0,0 +1,144 @@
+//! 同じカテゴリの直前のレコードからの経過時間
+//!
+//! 後処理なしで記録の間隔のばらつきを見るために、レコードに付ける。
+//! カテゴリごとの直前の時刻は件数を制限し、最も長く使われていないものから忘れる
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// 直前のレコードからの経過時間(マイクロ秒)を付けるキー
+pub const DELTA_KEY: &str = "_delta_us";
+
+/// 直前の時刻を覚えておくカテゴリの数
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+pub(crate) const MAX_DELTA_CATEGORIES: usize = 256;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACKER: Mutex<Option<DeltaTracker>> = Mutex::new(None);
+
+/// カテゴリごとの直前のレコードの経過時間
+#[derive(Debug)]
+pub(crate) struct DeltaTracker {
+    capacity: usize,
+    /// カテゴリ -> (直前の経過時間, 最後に使った順番)
+    last: HashMap<String, (Duration, u64)>,
+    tick: u64,
+}
+
+impl DeltaTracker {
+    #[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            last: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// `elapsed`を記録し、同じカテゴリの直前のレコードからの時間を返す
+    ///
+    /// カテゴリの最初のレコードと、忘れた後の最初のレコードは`None`
+    pub(crate) fn delta(&mut self, category: &str, elapsed: Duration) -> Option<u64> {
+        self.tick += 1;
+        if let Some((last, used)) = self.last.get_mut(category) {
+            let delta = elapsed.saturating_sub(*last).as_micros() as u64;
+            *last = elapsed;
+            *used = self.tick;
+            return Some(delta);
+        }
+        if self.last.len() >= self.capacity {
+            // 件数が少ないので順番を全て見て最も古いものを探す
+            if let Some(oldest) = self
+                .last
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(k, _)| k.clone())
+            {
+                self.last.remove(&oldest);
+            }
+        }
+        self.last.insert(category.to_string(), (elapsed, self.tick));
+        None
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.last.len()
+    }
+}
+
+/// 経過時間を付けるかどうかを設定する。設定し直すと直前の時刻を忘れる
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+pub(crate) fn install(enable: bool) {
+    *TRACKER
+        .lock()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK) =
+        enable.then(|| DeltaTracker::new(MAX_DELTA_CATEGORIES));
+    ENABLED.store(enable, Ordering::Release);
+}
+
+/// 有効な場合にレコードに付ける経過時間
+pub(crate) fn stamp(category: &str, elapsed: Duration) -> Option<u64> {
+    if !ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+    TRACKER
+        .lock()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+        .as_mut()
+        .and_then(|x| x.delta(category, elapsed))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::DeltaTracker;
+
+    #[test]
+    fn test_delta_tracker() {
+        let ms = Duration::from_millis;
+        let mut tracker = DeltaTracker::new(256);
+        // 決めた時刻の並びで記録する
+        let script = [
+            ("net", ms(0), None),
+            ("net", ms(10), Some(10_000)),
+            ("app", ms(12), None),
+            ("net", ms(25), Some(15_000)),
+            ("app", ms(12), Some(0)),
+            ("net", Duration::from_micros(25_750), Some(750)),
+            // 戻った時刻は0にする
+            ("app", ms(5), Some(0)),
+        ];
+        for (i, (category, elapsed, expected)) in script.into_iter().enumerate() {
+            assert_eq!(tracker.delta(category, elapsed), expected, "step {}", i);
+        }
+    }
+
+    #[test]
+    fn test_delta_tracker_bounded() {
+        let ms = Duration::from_millis;
+        let mut tracker = DeltaTracker::new(2);
+        assert_eq!(tracker.delta("a", ms(1)), None);
+        assert_eq!(tracker.delta("b", ms(2)), None);
+        assert_eq!(tracker.delta("a", ms(3)), Some(2000));
+        // 最も長く使われていない"b"を忘れる
+        assert_eq!(tracker.delta("c", ms(4)), None);
+        assert_eq!(tracker.len(), 2);
+        assert_eq!(tracker.delta("a", ms(5)), Some(2000));
+        assert_eq!(tracker.delta("b", ms(6)), None);
+        assert_eq!(tracker.delta("a", ms(7)), Some(2000));
+        assert_eq!(tracker.len(), 2);
+
+        let mut tracker = DeltaTracker::new(super::MAX_DELTA_CATEGORIES);
+        for i in 0..1000 {
+            tracker.delta(&format!("c{}", i), ms(i));
+        }
+        assert_eq!(tracker.len(), super::MAX_DELTA_CATEGORIES);
+    }
+}
@@ -0,0 +1,178 @@
+//! 2つのKVの差分
+use std::collections::BTreeMap;
+
+use crate::{Value, KV};
+
+/// Returns the difference from `old` to `new`, to log configuration or state changes.
+///
+/// - a changed key `field` maps to `{from, to}`
+/// - an added key maps as `+field` to the new value
+/// - a removed key maps as `-field` to the old value
+///
+/// Unchanged keys are left out, so an empty result means no change. Bytes are compared by
+/// length and hash and reported as `{len, hash}` instead of their content. Arrays are
+/// compared by content but reported as `{len}` only.
+///
+/// ```
+/// use uplog::{kv_diff, kv_zip, Value};
+///
+/// let old = kv_zip!("port", 8040_u32, "host", "localhost");
+/// let new = kv_zip!("port", 9000_u32, "tls", true);
+/// let diff = kv_diff(&old, &new);
+/// assert_eq!(diff["port"].as_map().unwrap()["to"], Value::U64(9000));
+/// assert_eq!(diff["+tls"], Value::Bool(true));
+/// assert_eq!(diff["-host"], Value::from("localhost"));
+/// # uplog::session_init();
+/// uplog::info!("cfg", "changed", "diff", &diff);
+/// ```
+pub fn kv_diff(old: &KV, new: &KV) -> KV {
+    let mut diff = KV::new();
+    for (key, from) in old {
+        match new.get(key) {
+            Some(to) if same(from, to) => {}
+            Some(to) => {
+                let mut change = BTreeMap::new();
+                change.insert("from".to_string(), summary(from));
+                change.insert("to".to_string(), summary(to));
+                diff.insert(key.clone(), Value::Map(change));
+            }
+            None => {
+                diff.insert(format!("-{}", key), summary(from));
+            }
+        }
+    }
+    for (key, to) in new.iter().filter(|(k, _)| !old.contains_key(*k)) {
+        diff.insert(format!("+{}", key), summary(to));
+    }
+    diff
+}
+
+fn same(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Bytes(a), Value::Bytes(b)) => a.len() == b.len() && fnv1a(a) == fnv1a(b),
+        _ => a == b,
+    }
+}
+
+/// 大きくなりうる値は中身の代わりに長さなどで表す
+fn summary(value: &Value) -> Value {
+    let mut map = BTreeMap::new();
+    match value {
+        Value::Bytes(x) => {
+            map.insert("len".to_string(), Value::U64(x.len() as u64));
+            map.insert(
+                "hash".to_string(),
+                Value::Text(format!("{:016x}", fnv1a(x))),
+            );
+        }
+        Value::Array(x) => {
+            map.insert("len".to_string(), Value::U64(x.len() as u64));
+        }
+        x => return x.clone(),
+    }
+    Value::Map(map)
+}
+
+/// FNV-1a 64bit。実行ごとに変わらないのでログ同士で比べられる
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, x| {
+        (hash ^ *x as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fnv1a, kv_diff};
+    use crate::{Value, ValueBorrow, KV};
+
+    fn change(diff: &KV, key: &str) -> (Value, Value) {
+        let map = diff[key].as_map().unwrap();
+        assert_eq!(map.len(), 2);
+        (map["from"].clone(), map["to"].clone())
+    }
+
+    #[test]
+    fn test_kv_diff() {
+        let old = kv_zip!(
+            "level",
+            "info",
+            "port",
+            8040_u32,
+            "same",
+            1.5_f64,
+            "removed",
+            true,
+            "peers",
+            vec![1_u32, 2]
+        );
+        let new = kv_zip!(
+            "level",
+            "debug",
+            "port",
+            8040_i32,
+            "same",
+            1.5_f64,
+            "added",
+            vec![1_u32],
+            "peers",
+            vec![1_u32, 3]
+        );
+        let diff = kv_diff(&old, &new);
+        assert_eq!(diff.len(), 5, "{:?}", diff);
+        assert_eq!(
+            change(&diff, "level"),
+            (Value::from("info"), Value::from("debug"))
+        );
+        // 型が変われば値が同じでも変更
+        assert_eq!(change(&diff, "port"), (Value::U64(8040), Value::I64(8040)));
+        assert_eq!(diff["-removed"], Value::Bool(true));
+        // 配列は長さだけを報告する
+        let len = |n: u64| Value::Map([("len".to_string(), Value::U64(n))].into());
+        assert_eq!(diff["+added"], len(1));
+        assert_eq!(change(&diff, "peers"), (len(2), len(2)));
+
+        assert!(kv_diff(&old, &old).is_empty());
+        assert!(kv_diff(&KV::new(), &KV::new()).is_empty());
+
+        // レコードに付けられる
+        let record = kv_borrow_zip!("diff", &diff);
+        assert!(matches!(record["diff"], ValueBorrow::Map(ref x) if x.len() == 5));
+    }
+
+    #[test]
+    fn test_kv_diff_bytes() {
+        let large = vec![7_u8; 1 << 20];
+        let mut modified = large.clone();
+        modified[1000] = 8;
+        let old = kv_zip!("blob", large.clone(), "keep", large.clone());
+        let new = kv_zip!("blob", modified.clone(), "keep", large.clone());
+        let diff = kv_diff(&old, &new);
+        assert_eq!(diff.len(), 1);
+        let (from, to) = change(&diff, "blob");
+        let summary = |x: &[u8]| {
+            Value::Map(
+                [
+                    ("len".to_string(), Value::U64(1 << 20)),
+                    (
+                        "hash".to_string(),
+                        Value::Text(format!("{:016x}", fnv1a(x))),
+                    ),
+                ]
+                .into(),
+            )
+        };
+        assert_eq!(from, summary(&large));
+        assert_eq!(to, summary(&modified));
+        assert_ne!(from, to);
+
+        let diff = kv_diff(&KV::new(), &kv_zip!("blob", vec![1_u8, 2, 3]));
+        assert_eq!(
+            diff["+blob"].as_map().unwrap()["len"],
+            Value::U64(3),
+            "{:?}",
+            diff
+        );
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
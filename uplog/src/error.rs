@@ -4,10 +4,66 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(Error, Debug)]
 pub enum Error {
+    #[cfg(feature = "client-ws")]
     #[error("connection error")]
     Connection(#[from] tungstenite::Error),
     #[error("io error")]
     Io(#[from] std::io::Error),
+    #[error("server reported decode error: {0}")]
+    ServerReport(crate::protocol::DecodeErrorReport),
+    #[error("invalid category pattern: {0}")]
+    InvalidPattern(String),
+    #[error("handshake failed: {0}")]
+    Handshake(String),
+    #[error("timed out after {0:?}")]
+    Timeout(std::time::Duration),
+    #[error("invalid configuration: {0}")]
+    Config(#[from] BuilderError),
+    #[error("stopped reading server messages after more than {0} unreadable ones in a minute")]
+    ProtocolErrorBudget(u32),
+}
+
+/// A setting rejected by `Builder::validate`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BuilderError {
+    #[error("buffer size {size} is smaller than {min} bytes")]
+    BufferTooSmall { size: usize, min: usize },
+    #[error("maximum buffer growth {max} is smaller than the buffer size {size}")]
+    GrowthBelowBufferSize { max: usize, size: usize },
+    #[error("swap duration must not be zero")]
+    ZeroDuration,
+    #[error("host is empty")]
+    EmptyHost,
+    #[error("invalid host {host:?}: {reason}")]
+    InvalidHost { host: String, reason: String },
+    #[error("port must not be zero")]
+    ZeroPort,
+    #[error("path {0:?} does not start with '/'")]
+    InvalidPath(String),
+    #[error("nice bytes per tick must not be zero")]
+    ZeroNiceBytesPerTick,
+    #[error("stats observer interval must not be zero")]
+    ZeroObserverInterval,
+    #[error("{0} can not be combined with {1}")]
+    ConflictingTransports(&'static str, &'static str),
+}
+
+/// Error of the functions that initialize the global logger.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum InitError {
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(#[from] BuilderError),
+    #[error("already initialized")]
+    AlreadyInitialized,
+    /// the destination of the records could not be opened
+    #[error("failed to open the sink: {0}")]
+    Sink(String),
+}
+
+impl From<crate::logger::SetLoggerError> for InitError {
+    fn from(_: crate::logger::SetLoggerError) -> Self {
+        Self::AlreadyInitialized
+    }
 }
 
 pub(crate) const ERROR_MESSAGE_MUTEX_LOCK: &str = "failed to lock mutex";
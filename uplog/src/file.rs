@@ -0,0 +1,94 @@
+//! ファイルに書き出す送信先
+//!
+//! サーバーを使わずに記録する。書き出したファイルは1つのセッションのCBORシーケンスになる
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{error::InitError, transport::Transport};
+
+/// [`Transport`] that appends the records to a local file.
+///
+/// The file is a CBOR sequence of records, the same bytes a server would receive.
+#[derive(Debug)]
+pub struct FileTransport {
+    writer: BufWriter<File>,
+}
+
+impl FileTransport {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl Transport for FileTransport {
+    fn send(&mut self, buf: &[u8]) -> crate::Result<()> {
+        self.writer.write_all(buf)?;
+        // 送信周期ごとに書き出して、異常終了しても周期の分までは残す
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn close(&mut self) -> crate::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Initializes the global logger to append the records to the file at `path`.
+///
+/// ```no_run
+/// uplog::init_file("app.uplog").unwrap();
+/// uplog::info!("app", "hello");
+/// uplog::flush();
+/// ```
+pub fn init_file<P: AsRef<Path>>(path: P) -> Result<(), InitError> {
+    let transport = FileTransport::create(path.as_ref()).map_err(|e| {
+        InitError::Sink(format!("failed to open {}: {}", path.as_ref().display(), e))
+    })?;
+    crate::init_with_transport(transport)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{session_init, transport::Transport, Level, Record};
+
+    use super::FileTransport;
+
+    #[test]
+    fn test_file_transport() {
+        session_init();
+        let dir = std::env::temp_dir().join(format!("uplog-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("records.cbor");
+        std::fs::remove_file(&path).ok();
+
+        let mut expected = Vec::new();
+        for i in 0..2_u32 {
+            let mut transport = FileTransport::create(&path).unwrap();
+            let mut record = devlog!(Level::Info, "file", "msg", "i", i);
+            record.elapsed = Duration::from_millis(i as u64);
+            transport
+                .send(&serde_cbor::to_vec(&record).unwrap())
+                .unwrap();
+            transport.close().unwrap();
+            expected.push(record);
+        }
+        // 開き直しても追記する
+        let buf = std::fs::read(&path).unwrap();
+        let records = serde_cbor::Deserializer::from_slice(&buf)
+            .into_iter::<Record>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(records, expected);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,361 @@
+//! レコードの文字列表現
+//!
+//! `Display`と表示用のツールで同じ書式を使う。書き出し先に直接書き、途中で文字列を作らない
+use std::{
+    fmt::{self, Display, Formatter},
+    time::Duration,
+};
+
+use crate::Record;
+
+/// 省略した末尾に付ける
+const ELLIPSIS: char = '…';
+/// 複数行のkvの字下げ
+const KV_INDENT: &str = "    ";
+
+/// How [`RecordFormatter`] writes the elapsed time of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ElapsedStyle {
+    /// seconds with 4 decimals, `1234.5678`
+    #[default]
+    Seconds,
+    /// hours, minutes, seconds and milliseconds, `00:20:34.567`
+    Hms,
+}
+
+/// How [`RecordFormatter`] writes the key-values of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KvStyle {
+    /// `{k = v, }` after the message
+    #[default]
+    Inline,
+    /// one aligned `k = v` per line under the message
+    Multiline,
+}
+
+/// Layout of the text form of a [`Record`].
+///
+/// The default is the layout of `Display for Record`.
+///
+/// ```
+/// use uplog::{ElapsedStyle, RecordFormatter};
+///
+/// let formatter = RecordFormatter::new()
+///     .elapsed(ElapsedStyle::Hms)
+///     .location(false)
+///     .max_message_width(Some(40));
+/// # uplog::session_init();
+/// let record = uplog::devlog!(uplog::Level::Info, "app", "hello");
+/// println!("{}", formatter.format(&record));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RecordFormatter {
+    elapsed: ElapsedStyle,
+    hide_location: bool,
+    kv: KvStyle,
+    max_message_width: Option<usize>,
+}
+
+impl RecordFormatter {
+    pub const fn new() -> Self {
+        Self {
+            elapsed: ElapsedStyle::Seconds,
+            hide_location: false,
+            kv: KvStyle::Inline,
+            max_message_width: None,
+        }
+    }
+
+    pub fn elapsed(mut self, style: ElapsedStyle) -> Self {
+        self.elapsed = style;
+        self
+    }
+
+    /// Whether to write the `file:line` of the record. Defaults to true.
+    pub fn location(mut self, show: bool) -> Self {
+        self.hide_location = !show;
+        self
+    }
+
+    pub fn kv(mut self, style: KvStyle) -> Self {
+        self.kv = style;
+        self
+    }
+
+    /// Cuts messages longer than `width` characters, ending them with `…`.
+    pub fn max_message_width(mut self, width: Option<usize>) -> Self {
+        self.max_message_width = width;
+        self
+    }
+
+    pub fn elapsed_style(&self) -> ElapsedStyle {
+        self.elapsed
+    }
+
+    pub fn shows_location(&self) -> bool {
+        !self.hide_location
+    }
+
+    pub fn kv_style(&self) -> KvStyle {
+        self.kv
+    }
+
+    pub fn message_width(&self) -> Option<usize> {
+        self.max_message_width
+    }
+
+    /// Formats the whole record.
+    pub fn format<'a>(&'a self, record: &'a Record) -> FormattedRecord<'a> {
+        FormattedRecord {
+            formatter: self,
+            record,
+        }
+    }
+
+    /// Formats only the elapsed time. A width given to the format pads it on the left.
+    pub fn format_elapsed(&self, elapsed: Duration) -> FormattedElapsed {
+        FormattedElapsed {
+            style: self.elapsed,
+            elapsed,
+        }
+    }
+
+    /// Formats only the message, cut to the maximum width.
+    pub fn format_message<'a>(&self, message: &'a str) -> FormattedMessage<'a> {
+        FormattedMessage {
+            message,
+            max_width: self.max_message_width,
+        }
+    }
+}
+
+/// A record written by a [`RecordFormatter`].
+#[derive(Debug, Clone, Copy)]
+pub struct FormattedRecord<'a> {
+    formatter: &'a RecordFormatter,
+    record: &'a Record,
+}
+
+impl Display for FormattedRecord<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let record = self.record;
+        write!(
+            f,
+            "[{:?}] {} [{}] {}",
+            record.level(),
+            self.formatter.format_elapsed(record.elapsed),
+            record.category,
+            self.formatter.format_message(&record.message),
+        )?;
+        if self.formatter.shows_location() {
+            write!(
+                f,
+                " ({}:L{})",
+                record.file().map_or("", |x| x.as_str()),
+                record.line().unwrap_or(0)
+            )?;
+        }
+        if let Some(kv) = record.key_values() {
+            match self.formatter.kv {
+                KvStyle::Inline => {
+                    f.write_str(" {")?;
+                    for (k, v) in kv.iter() {
+                        write!(f, "{} = {}, ", k, v)?;
+                    }
+                    f.write_str("}")?;
+                }
+                KvStyle::Multiline => {
+                    let width = kv.keys().map(|x| x.chars().count()).max().unwrap_or(0);
+                    for (k, v) in kv.iter() {
+                        write!(f, "\n{}{:<width$} = {}", KV_INDENT, k, v, width = width)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The elapsed time written by a [`RecordFormatter`].
+#[derive(Debug, Clone, Copy)]
+pub struct FormattedElapsed {
+    style: ElapsedStyle,
+    elapsed: Duration,
+}
+
+/// 10進数での桁数
+fn digits(mut x: u64) -> usize {
+    let mut n = 1;
+    while x >= 10 {
+        x /= 10;
+        n += 1;
+    }
+    n
+}
+
+impl Display for FormattedElapsed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let width = f.width().unwrap_or(0);
+        match self.style {
+            ElapsedStyle::Seconds => {
+                write!(f, "{:>width$.4}", self.elapsed.as_secs_f64(), width = width)
+            }
+            ElapsedStyle::Hms => {
+                // 時間の桁と`:MM:SS.mmm`
+                let len = digits(self.elapsed.as_secs() / 3600).max(2) + 10;
+                for _ in len..width {
+                    f.write_str(" ")?;
+                }
+                let secs = self.elapsed.as_secs();
+                write!(
+                    f,
+                    "{:02}:{:02}:{:02}.{:03}",
+                    secs / 3600,
+                    secs / 60 % 60,
+                    secs % 60,
+                    self.elapsed.subsec_millis()
+                )
+            }
+        }
+    }
+}
+
+/// The message written by a [`RecordFormatter`].
+#[derive(Debug, Clone, Copy)]
+pub struct FormattedMessage<'a> {
+    message: &'a str,
+    max_width: Option<usize>,
+}
+
+impl Display for FormattedMessage<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let width = match self.max_width {
+            Some(x) => x,
+            None => return f.write_str(self.message),
+        };
+        // 省略記号の分を残して切る位置
+        match self.message.char_indices().nth(width) {
+            None => f.write_str(self.message),
+            Some(_) if width == 0 => Ok(()),
+            Some(_) => {
+                let (end, _) = self
+                    .message
+                    .char_indices()
+                    .nth(width - 1)
+                    .expect("shorter than the width");
+                f.write_str(&self.message[..end])?;
+                write!(f, "{}", ELLIPSIS)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{session_init, Level, Record};
+
+    use super::{ElapsedStyle, KvStyle, RecordFormatter};
+
+    fn fixture() -> Record {
+        session_init();
+        let mut record = devlog!(
+            Level::Warn,
+            "net.eth0",
+            "link down",
+            "retries",
+            3_u32,
+            "reason",
+            "timeout"
+        );
+        record.elapsed = Duration::from_millis(4_834_567);
+        record.file = Some("src/net.rs".to_string());
+        record.line = Some(42);
+        record
+    }
+
+    #[test]
+    fn test_format_default() {
+        let record = fixture();
+        let expect =
+            "[Warn] 4834.5670 [net.eth0] link down (src/net.rs:L42) {reason = \"timeout\", retries = 3, }";
+        assert_eq!(record.to_string(), expect);
+        assert_eq!(RecordFormatter::new().format(&record).to_string(), expect);
+
+        let mut record = record;
+        record.kv = None;
+        record.file = None;
+        record.line = None;
+        assert_eq!(
+            record.to_string(),
+            "[Warn] 4834.5670 [net.eth0] link down (:L0)"
+        );
+    }
+
+    #[test]
+    fn test_format_styles() {
+        let record = fixture();
+        let hms = RecordFormatter::new().elapsed(ElapsedStyle::Hms);
+        assert_eq!(
+            hms.format(&record).to_string(),
+            "[Warn] 01:20:34.567 [net.eth0] link down (src/net.rs:L42) {reason = \"timeout\", retries = 3, }"
+        );
+
+        let short = RecordFormatter::new()
+            .location(false)
+            .max_message_width(Some(6));
+        assert_eq!(
+            short.format(&record).to_string(),
+            "[Warn] 4834.5670 [net.eth0] link … {reason = \"timeout\", retries = 3, }"
+        );
+
+        let multiline = RecordFormatter::new()
+            .location(false)
+            .kv(KvStyle::Multiline);
+        assert_eq!(
+            multiline.format(&record).to_string(),
+            [
+                "[Warn] 4834.5670 [net.eth0] link down",
+                "    reason  = \"timeout\"",
+                "    retries = 3",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_format_parts() {
+        let f = RecordFormatter::new();
+        assert_eq!(
+            format!("{:>10}", f.format_elapsed(Duration::from_millis(1234))),
+            "    1.2340"
+        );
+        // 丸めで桁が増える場合
+        assert_eq!(
+            format!(
+                "{:>7}",
+                f.format_elapsed(Duration::from_nanos(9_999_990_000))
+            ),
+            "10.0000"
+        );
+
+        let f = f.elapsed(ElapsedStyle::Hms);
+        assert_eq!(
+            format!("{:>14}", f.format_elapsed(Duration::from_millis(1234))),
+            "  00:00:01.234"
+        );
+        assert_eq!(
+            format!("{}", f.format_elapsed(Duration::from_secs(100 * 3600 + 61))),
+            "100:01:01.000"
+        );
+
+        let f = RecordFormatter::new().max_message_width(Some(4));
+        assert_eq!(f.format_message("abcd").to_string(), "abcd");
+        assert_eq!(f.format_message("abcde").to_string(), "abc…");
+        assert_eq!(f.format_message("あいうえお").to_string(), "あいう…");
+        let f = f.max_message_width(Some(0));
+        assert_eq!(f.format_message("abc").to_string(), "");
+        assert_eq!(f.format_message("").to_string(), "");
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+use crate::protocol::{CloseReason, DecodeErrorReport};
+
+static HEALTH: Mutex<Health> = Mutex::new(Health::new());
+// ログ出力側から頻繁に更新されるのでロックを取らない
+static DROPPED_RECORDS: AtomicU64 = AtomicU64::new(0);
+
+/// 送信クライアントの状態
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    /// サーバーからデコード失敗の報告を受けた回数
+    pub server_error_reports: u64,
+    /// 最後に受けたデコード失敗の報告
+    pub last_server_error: Option<DecodeErrorReport>,
+    /// 送信スレッドで最後に発生したエラー
+    pub last_error: Option<String>,
+    /// バッファーに収まらなかったか、送信に失敗して破棄したレコード数
+    pub dropped_records: u64,
+    /// サーバーから受けて適用した設定変更の数
+    pub applied_commands: u64,
+    /// 解釈できずに無視したサーバーからのメッセージとコマンドの数
+    pub ignored_commands: u64,
+    /// 解釈できないメッセージが多すぎて今の接続からの読み込みを止めたか。送信は続ける
+    pub reading_stopped: bool,
+    /// サーバーの時計に対するずれ(ミリ秒)の移動平均。正の場合は手元の時計が遅れている
+    pub clock_offset_ms: Option<i64>,
+    /// 最後に閉じた接続の理由
+    pub last_close_reason: Option<CloseReason>,
+    /// 最後の接続をサーバーが閉じたか
+    pub closed_by_server: bool,
+}
+
+impl Health {
+    const fn new() -> Self {
+        Self {
+            server_error_reports: 0,
+            last_server_error: None,
+            last_error: None,
+            dropped_records: 0,
+            applied_commands: 0,
+            ignored_commands: 0,
+            reading_stopped: false,
+            clock_offset_ms: None,
+            last_close_reason: None,
+            closed_by_server: false,
+        }
+    }
+}
+
+/// 送信クライアントの状態を返す
+pub fn health() -> Health {
+    let mut health = HEALTH
+        .lock()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+        .clone();
+    health.dropped_records = dropped_records();
+    health
+}
+
+pub(crate) fn update<F: FnOnce(&mut Health)>(f: F) {
+    f(&mut HEALTH.lock().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK))
+}
+
+/// 接続を閉じた理由を残す
+pub(crate) fn record_close(reason: CloseReason, by_server: bool) {
+    update(|h| {
+        h.last_close_reason = Some(reason);
+        h.closed_by_server = by_server;
+    })
+}
+
+pub(crate) fn record_dropped(n: u64) {
+    DROPPED_RECORDS.fetch_add(n, Ordering::AcqRel);
+}
+
+pub(crate) fn dropped_records() -> u64 {
+    DROPPED_RECORDS.load(Ordering::Acquire)
+}
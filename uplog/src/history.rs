@@ -0,0 +1,383 @@
+//! 端末に残す循環する履歴
+//!
+//! 決まった大きさのファイルにレコードを書き続け、一杯になったら古いものから上書きする。
+//! 接続していなくても残るので、サーバーに頼まれたときに指定した範囲を後から送れる。
+//!
+//! ファイルはヘッダーとデータ領域からなる。書き込み位置は開き直しても続くように折り返さない通し番号で持ち、
+//! データ領域での位置はそれをデータ領域の大きさで割った余りになる。
+//! レコードは印と長さを前に付けて書き、上書きされて途中から始まるレコードは次の印まで読み飛ばす
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::Record;
+
+const MAGIC: &[u8; 8] = b"UPLGHIST";
+const VERSION: u32 = 1;
+/// magic, version, 予約, データ領域の大きさ, 書き込み位置
+const HEADER_LEN: u64 = 32;
+const CAPACITY_OFFSET: u64 = 16;
+const TAIL_OFFSET: u64 = 24;
+
+/// レコードの始まりの印。CBORの項目はbreakで始まらないので先頭を0xffにする
+const FRAME_MARK: [u8; 4] = [0xff, b'U', b'H', b'R'];
+/// 印と長さ
+const FRAME_HEADER_LEN: usize = 8;
+
+/// 書き込み待ちがこれを超えたら送信スレッドを待たずに書き出す
+const PENDING_LIMIT: usize = 64 * 1024;
+
+/// Smallest data size accepted by [`RingFileSink::open`].
+pub const MIN_HISTORY_CAPACITY: u64 = 1024;
+
+/// Fixed-size file keeping the latest records on the device, overwriting the oldest ones.
+///
+/// Set it with `Builder::history` to keep every record written, whether or not the client
+/// is connected. The server can then ask for a range of it with
+/// [`crate::protocol::CMD_UPLOAD_HISTORY`], which the client sends as a separate session.
+///
+/// The file keeps its records when it is opened again with the same capacity, so the history
+/// of an earlier run of the process is sent too when its elapsed times are in the range.
+///
+/// ```
+/// use std::time::Duration;
+/// use uplog::{Level, RingFileSink};
+///
+/// let path = std::env::temp_dir().join(format!("uplog-doc-history-{}", std::process::id()));
+/// let history = RingFileSink::open(&path, 4096).unwrap();
+/// let record = uplog::devlog!(Level::Info, "app", "hello");
+/// history.append(&serde_cbor::to_vec(&record).unwrap());
+/// let records = history.read_window(Duration::ZERO, None).unwrap();
+/// assert!(!records.is_empty());
+/// # std::fs::remove_file(&path).ok();
+/// ```
+#[derive(Debug)]
+pub struct RingFileSink {
+    capacity: u64,
+    inner: Mutex<RingFile>,
+}
+
+#[derive(Debug)]
+struct RingFile {
+    file: File,
+    /// 書き込み位置の通し番号
+    tail: u64,
+    /// まだファイルに書いていないフレーム
+    pending: Vec<u8>,
+}
+
+impl RingFileSink {
+    /// Opens the history file at `path` keeping `capacity` bytes of records.
+    ///
+    /// The file is created when missing. An existing file made with another capacity, or that
+    /// is not a history file, is cleared.
+    pub fn open<P: AsRef<Path>>(path: P, capacity: u64) -> io::Result<Self> {
+        if capacity < MIN_HISTORY_CAPACITY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "history capacity {} is smaller than {} bytes",
+                    capacity, MIN_HISTORY_CAPACITY
+                ),
+            ));
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let tail = match read_header(&mut file)? {
+            Some((x, tail)) if x == capacity => tail,
+            _ => {
+                file.set_len(0)?;
+                file.set_len(HEADER_LEN + capacity)?;
+                let mut header = Vec::with_capacity(HEADER_LEN as usize);
+                header.extend_from_slice(MAGIC);
+                header.extend_from_slice(&VERSION.to_le_bytes());
+                header.extend_from_slice(&[0; 4]);
+                header.extend_from_slice(&capacity.to_le_bytes());
+                header.extend_from_slice(&0_u64.to_le_bytes());
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(&header)?;
+                0
+            }
+        };
+        Ok(Self {
+            capacity,
+            inner: Mutex::new(RingFile {
+                file,
+                tail,
+                pending: Vec::new(),
+            }),
+        })
+    }
+
+    /// Size of the data part of the file in bytes.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Adds one CBOR encoded record.
+    ///
+    /// The record is buffered and written by the next [`RingFileSink::flush`], or right away
+    /// when many records are waiting. A record larger than the capacity is not kept.
+    pub fn append(&self, record: &[u8]) {
+        if (record.len() + FRAME_HEADER_LEN) as u64 > self.capacity {
+            return;
+        }
+        let mut inner = self.lock();
+        inner.pending.extend_from_slice(&FRAME_MARK);
+        inner
+            .pending
+            .extend_from_slice(&(record.len() as u32).to_le_bytes());
+        inner.pending.extend_from_slice(record);
+        if inner.pending.len() > PENDING_LIMIT.min(self.capacity as usize) {
+            if let Err(e) = inner.write_pending(self.capacity) {
+                log::debug!("failed to write history {}", e);
+            }
+        }
+    }
+
+    /// Writes the buffered records to the file.
+    pub fn flush(&self) -> io::Result<()> {
+        self.lock().write_pending(self.capacity)
+    }
+
+    /// Returns the kept records with an elapsed time from `from` to `to` inclusive, oldest
+    /// first, as a CBOR sequence. `None` has no upper bound.
+    ///
+    /// The oldest record partly overwritten by newer ones is skipped.
+    pub fn read_window(&self, from: Duration, to: Option<Duration>) -> io::Result<Vec<u8>> {
+        let data = {
+            let mut inner = self.lock();
+            inner.write_pending(self.capacity)?;
+            let start = inner.tail.saturating_sub(self.capacity);
+            let mut data = vec![0; (inner.tail - start) as usize];
+            inner.read_at(start, self.capacity, &mut data)?;
+            data
+        };
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while pos + FRAME_HEADER_LEN <= data.len() {
+            match frame_at(&data[pos..]) {
+                Some((payload, record)) => {
+                    if from <= record.elapsed && to.is_none_or(|to| record.elapsed <= to) {
+                        out.extend_from_slice(payload);
+                    }
+                    pos += FRAME_HEADER_LEN + payload.len();
+                }
+                // 上書きされた残りか壊れたところなので次の印を探す
+                None => pos += 1,
+            }
+        }
+        Ok(out)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RingFile> {
+        self.inner
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+    }
+}
+
+impl RingFile {
+    fn write_pending(&mut self, capacity: u64) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        // 一周を超える分は上書きされるので最後の一周だけ書く
+        let skip = pending.len().saturating_sub(capacity as usize);
+        let mut pos = self.tail + skip as u64;
+        let mut rest = &pending[skip..];
+        while !rest.is_empty() {
+            let offset = pos % capacity;
+            let len = rest.len().min((capacity - offset) as usize);
+            self.file.seek(SeekFrom::Start(HEADER_LEN + offset))?;
+            self.file.write_all(&rest[..len])?;
+            pos += len as u64;
+            rest = &rest[len..];
+        }
+        // データを書いてから位置を進める
+        self.tail += pending.len() as u64;
+        self.file.seek(SeekFrom::Start(TAIL_OFFSET))?;
+        self.file.write_all(&self.tail.to_le_bytes())?;
+        self.pending = pending;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// 通し番号startから折り返しをまたいで読む
+    fn read_at(&mut self, start: u64, capacity: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut pos = start;
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let offset = pos % capacity;
+            let len = rest.len().min((capacity - offset) as usize);
+            self.file.seek(SeekFrom::Start(HEADER_LEN + offset))?;
+            self.file.read_exact(&mut rest[..len])?;
+            pos += len as u64;
+            rest = &mut rest[len..];
+        }
+        Ok(())
+    }
+}
+
+/// 履歴のファイルならデータ領域の大きさと書き込み位置を返す
+fn read_header(file: &mut File) -> io::Result<Option<(u64, u64)>> {
+    let mut header = [0; HEADER_LEN as usize];
+    file.seek(SeekFrom::Start(0))?;
+    match file.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let u64_at = |offset: u64| {
+        let offset = offset as usize;
+        u64::from_le_bytes(header[offset..offset + 8].try_into().expect("8 bytes"))
+    };
+    let version = u32::from_le_bytes(header[8..12].try_into().expect("4 bytes"));
+    if &header[..8] != MAGIC || version != VERSION {
+        return Ok(None);
+    }
+    let capacity = u64_at(CAPACITY_OFFSET);
+    if file.metadata()?.len() < HEADER_LEN + capacity {
+        return Ok(None);
+    }
+    Ok(Some((capacity, u64_at(TAIL_OFFSET))))
+}
+
+/// 先頭が完全なレコードのフレームならその中身を返す
+fn frame_at(buf: &[u8]) -> Option<(&[u8], Record)> {
+    if buf[..4] != FRAME_MARK {
+        return None;
+    }
+    let len = u32::from_le_bytes(buf[4..FRAME_HEADER_LEN].try_into().ok()?) as usize;
+    let payload = buf.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN + len)?;
+    let record = serde_cbor::from_slice::<Record>(payload).ok()?;
+    Some((payload, record))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use crate::{Level, Record};
+
+    use super::{RingFileSink, FRAME_HEADER_LEN, HEADER_LEN};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("uplog-history-{}-{}", name, std::process::id()));
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    fn encoded(i: u64) -> Vec<u8> {
+        let mut record: Record = devlog!(Level::Info, "history", "record", "i", i);
+        record.elapsed = Duration::from_millis(i);
+        serde_cbor::to_vec(&record).unwrap()
+    }
+
+    fn indexes(buf: &[u8]) -> Vec<u64> {
+        serde_cbor::Deserializer::from_slice(buf)
+            .into_iter::<Record>()
+            .map(|x| x.unwrap().elapsed.as_millis() as u64)
+            .collect()
+    }
+
+    #[test]
+    fn test_ring_wrap() {
+        let path = temp_path("wrap");
+        let capacity = 1100;
+        let history = RingFileSink::open(&path, capacity).unwrap();
+        let size = |i| (encoded(i).len() + FRAME_HEADER_LEN) as u64;
+        // 何周かして、最後の一周の中ほどで止める
+        let mut count = 0;
+        let mut tail = 0;
+        while tail < 3 * capacity || tail % capacity < capacity / 2 {
+            history.append(&encoded(count));
+            tail += size(count);
+            count += 1;
+            if count % 7 == 0 {
+                history.flush().unwrap();
+            }
+        }
+        // 何周もしても大きさは変わらない
+        history.flush().unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            HEADER_LEN + capacity
+        );
+
+        let all = indexes(&history.read_window(Duration::ZERO, None).unwrap());
+        assert_eq!(*all.last().unwrap(), count - 1);
+        assert!(all.windows(2).all(|x| x[1] == x[0] + 1));
+        // 一部が上書きされた最も古いレコードは読み飛ばし、完全なものは全て読む
+        let kept = all.iter().map(|i| size(*i)).sum::<u64>();
+        assert!(kept <= capacity);
+        assert!(kept + size(all[0] - 1) > capacity);
+
+        // 折り返す位置をまたぐ範囲
+        let wrap = (0..count)
+            .find(|i| (0..=*i).map(size).sum::<u64>() > tail / capacity * capacity)
+            .unwrap();
+        assert!(all[0] < wrap && wrap < count - 1);
+        let window = indexes(
+            &history
+                .read_window(
+                    Duration::from_millis(wrap - 1),
+                    Some(Duration::from_millis(wrap + 1)),
+                )
+                .unwrap(),
+        );
+        assert_eq!(window, [wrap - 1, wrap, wrap + 1]);
+        drop(history);
+
+        // 開き直しても残る
+        let history = RingFileSink::open(&path, capacity).unwrap();
+        assert_eq!(
+            indexes(&history.read_window(Duration::ZERO, None).unwrap()),
+            all
+        );
+        history.append(&encoded(count));
+        let reopened = indexes(&history.read_window(Duration::ZERO, None).unwrap());
+        assert_eq!(*reopened.last().unwrap(), count);
+
+        // 大きさを変えると消える
+        drop(history);
+        let history = RingFileSink::open(&path, capacity * 2).unwrap();
+        assert!(history
+            .read_window(Duration::ZERO, None)
+            .unwrap()
+            .is_empty());
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_ring_limits() {
+        let path = temp_path("limits");
+        assert!(RingFileSink::open(&path, 16).is_err());
+        let history = RingFileSink::open(&path, 1024).unwrap();
+        // 大きすぎるレコードは残さない
+        let mut large: Record = devlog!(Level::Info, "history", "large");
+        large.message = "x".repeat(2048);
+        history.append(&serde_cbor::to_vec(&large).unwrap());
+        history.append(&encoded(1));
+        assert_eq!(
+            indexes(&history.read_window(Duration::ZERO, None).unwrap()),
+            [1]
+        );
+        // 書き込み待ちが多ければ待たずに書く
+        for i in 0..100 {
+            history.append(&encoded(i));
+        }
+        assert!(history.lock().tail > 0);
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -16,6 +16,7 @@ pub enum Value {
     Text(String),
     Bytes(Vec<u8>),
     Array(Vec<Value>),
+    Map(BTreeMap<String, Value>),
 }
 
 impl Display for Value {
@@ -29,11 +30,158 @@ impl Display for Value {
             Value::Bool(x) => write!(f, "{}", x),
             Value::Text(x) => write!(f, "\"{}\"", x),
             Value::Bytes(x) => write!(f, "bytes({})", x.len()),
-            Value::Array(x) => write!(f, "vec({}, len={})", x[0], x.len()),
+            Value::Array(x) => match x.first() {
+                // 型が混ざっている場合は先頭の値では代表できないので型を並べる
+                Some(first) if x.iter().any(|v| v.type_name() != first.type_name()) => {
+                    let mut names: Vec<&str> = Vec::new();
+                    for name in x.iter().map(Value::type_name) {
+                        if !names.contains(&name) {
+                            names.push(name);
+                        }
+                    }
+                    write!(f, "vec(mixed({}), len={})", names.join(", "), x.len())
+                }
+                Some(first) => write!(f, "vec({}, len={})", first, x.len()),
+                None => write!(f, "vec(len=0)"),
+            },
+            Value::Map(x) => write!(f, "map(len={})", x.len()),
         }
     }
 }
 
+/// f64で誤差なく表せる整数の最大値
+const F64_EXACT_INT: u64 = 1 << f64::MANTISSA_DIGITS;
+
+impl Value {
+    /// Name of the variant for diagnostics, e.g. `"u64"` or `"text"`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::I64(_) => "i64",
+            Value::U64(_) => "u64",
+            Value::F32(_) => "f32",
+            Value::F64(_) => "f64",
+            Value::Bool(_) => "bool",
+            Value::Text(_) => "text",
+            Value::Bytes(_) => "bytes",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// Integers that fit in i64. Floats are not converted.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::I64(x) => Some(*x),
+            Value::U64(x) => i64::try_from(*x).ok(),
+            _ => None,
+        }
+    }
+
+    /// Integers that fit in u64. Floats are not converted.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::I64(x) => u64::try_from(*x).ok(),
+            Value::U64(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Floats, and integers that f64 represents exactly.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::F32(x) => Some(*x as f64),
+            Value::F64(x) => Some(*x),
+            Value::I64(x) if x.unsigned_abs() <= F64_EXACT_INT => Some(*x as f64),
+            Value::U64(x) if *x <= F64_EXACT_INT => Some(*x as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Text(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&BTreeMap<String, Value>> {
+        match self {
+            Value::Map(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+/// Typed access to the values of a [`KV`].
+///
+/// Each method returns None when the key is missing or the value does not convert,
+/// following the accessors of [`Value`].
+pub trait KvExt {
+    fn get_i64(&self, key: &str) -> Option<i64>;
+    fn get_u64(&self, key: &str) -> Option<u64>;
+    fn get_f64(&self, key: &str) -> Option<f64>;
+    fn get_str(&self, key: &str) -> Option<&str>;
+    fn get_bool(&self, key: &str) -> Option<bool>;
+    fn get_bytes(&self, key: &str) -> Option<&[u8]>;
+    fn get_array(&self, key: &str) -> Option<&[Value]>;
+}
+
+impl KvExt for KV {
+    fn get_i64(&self, key: &str) -> Option<i64> {
+        self.get(key)?.as_i64()
+    }
+
+    fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key)?.as_u64()
+    }
+
+    fn get_f64(&self, key: &str) -> Option<f64> {
+        self.get(key)?.as_f64()
+    }
+
+    fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key)?.as_str()
+    }
+
+    fn get_bool(&self, key: &str) -> Option<bool> {
+        self.get(key)?.as_bool()
+    }
+
+    fn get_bytes(&self, key: &str) -> Option<&[u8]> {
+        self.get(key)?.as_bytes()
+    }
+
+    fn get_array(&self, key: &str) -> Option<&[Value]> {
+        self.get(key)?.as_array()
+    }
+}
+
 impl serde::Serialize for Value {
     #[inline]
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -49,6 +197,7 @@ impl serde::Serialize for Value {
             Value::Bool(v) => serializer.serialize_bool(*v),
             Value::Bytes(v) => serializer.serialize_bytes(v),
             Value::Array(v) => v.serialize(serializer),
+            Value::Map(v) => v.serialize(serializer),
             Value::Null => serializer.serialize_unit(),
         }
     }
@@ -171,11 +320,128 @@ impl<'de> serde::Deserialize<'de> for Value {
 
                 Ok(Value::Array(vec))
             }
+
+            #[inline]
+            fn visit_map<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
+            where
+                V: de::MapAccess<'de>,
+            {
+                let mut map = BTreeMap::new();
+
+                while let Some((MapKey(k), v)) = visitor.next_entry()? {
+                    map.insert(k, v);
+                }
+
+                Ok(Value::Map(map))
+            }
         }
         deserializer.deserialize_any(ValueVisitor)
     }
 }
 
+/// mapのキー
+///
+/// 他の実装のクライアントは文字列以外のキーを送ってくることがあるので、
+/// 数値などは文字列にして受け取る。配列やmapのキーはエラーにする
+struct MapKey(String);
+
+impl<'de> serde::Deserialize<'de> for MapKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de;
+        use std::fmt;
+        struct KeyVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for KeyVisitor {
+            type Value = MapKey;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt.write_str("a string, number, bool or bytes map key")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(MapKey(v.to_string()))
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+                Ok(MapKey(v))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(MapKey(v.to_string()))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(MapKey(v.to_string()))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                Ok(MapKey(v.to_string()))
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                Ok(MapKey(v.to_string()))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(MapKey(String::from_utf8_lossy(v).into_owned()))
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+                Ok(MapKey("null".to_string()))
+            }
+        }
+        deserializer.deserialize_any(KeyVisitor)
+    }
+}
+
+/// `Record::kv`のデシリアライズ。キーは[`MapKey`]と同じく文字列にする
+pub(crate) fn deserialize_kv<'de, D>(deserializer: D) -> Result<Option<KV>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de;
+    use std::fmt;
+    struct KVVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for KVVisitor {
+        type Value = Option<KV>;
+
+        fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt.write_str("a map or null")
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            deserializer.deserialize_map(self)
+        }
+
+        fn visit_map<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
+        where
+            V: de::MapAccess<'de>,
+        {
+            let mut map = KV::new();
+            while let Some((MapKey(k), v)) = visitor.next_entry()? {
+                map.insert(k, v);
+            }
+            Ok(Some(map))
+        }
+    }
+    deserializer.deserialize_option(KVVisitor)
+}
+
 // Primitive type from
 macro_rules! impl_from {
     ($for_type:ty) => {
@@ -252,6 +518,7 @@ pub enum ValueBorrow<'a> {
     Text(&'a str),
     Bytes(&'a [u8]),
     Array(Vec<ValueBorrow<'a>>),
+    Map(BTreeMap<&'a str, ValueBorrow<'a>>),
 }
 
 impl<'a> serde::Serialize for ValueBorrow<'a> {
@@ -275,6 +542,7 @@ impl<'a> serde::Serialize for ValueBorrow<'a> {
             ValueBorrow::Bool(v) => serializer.serialize_bool(*v),
             ValueBorrow::Bytes(v) => serializer.serialize_bytes(v),
             ValueBorrow::Array(v) => v.serialize(serializer),
+            ValueBorrow::Map(v) => v.serialize(serializer),
             ValueBorrow::Null => serializer.serialize_unit(),
         }
     }
@@ -365,9 +633,73 @@ macro_rules! vec_borrow_from {
 }
 vec_borrow_from!(str);
 
+impl From<&ValueBorrow<'_>> for Value {
+    fn from(v: &ValueBorrow<'_>) -> Self {
+        match v {
+            ValueBorrow::Null => Value::Null,
+            ValueBorrow::I8(x) => Value::I64(*x as i64),
+            ValueBorrow::I16(x) => Value::I64(*x as i64),
+            ValueBorrow::I32(x) => Value::I64(*x as i64),
+            ValueBorrow::I64(x) => Value::I64(*x),
+            ValueBorrow::U8(x) => Value::U64(*x as u64),
+            ValueBorrow::U16(x) => Value::U64(*x as u64),
+            ValueBorrow::U32(x) => Value::U64(*x as u64),
+            ValueBorrow::U64(x) => Value::U64(*x),
+            ValueBorrow::F32(x) => Value::F32(*x),
+            ValueBorrow::F64(x) => Value::F64(*x),
+            ValueBorrow::Bool(x) => Value::Bool(*x),
+            ValueBorrow::Text(x) => Value::Text(x.to_string()),
+            ValueBorrow::Bytes(x) => Value::Bytes(x.to_vec()),
+            ValueBorrow::Array(x) => Value::Array(x.iter().map(Value::from).collect()),
+            ValueBorrow::Map(x) => Value::Map(
+                x.iter()
+                    .map(|(k, v)| (k.to_string(), Value::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+impl From<KV> for Value {
+    fn from(v: KV) -> Self {
+        Self::Map(v)
+    }
+}
+
+impl<'a> From<&'a KV> for ValueBorrow<'a> {
+    fn from(v: &'a KV) -> Self {
+        Self::Map(
+            v.iter()
+                .map(|(k, v)| (k.as_str(), ValueBorrow::from(v)))
+                .collect(),
+        )
+    }
+}
+
+impl<'a> From<&'a Value> for ValueBorrow<'a> {
+    fn from(v: &'a Value) -> Self {
+        match v {
+            Value::Null => ValueBorrow::Null,
+            Value::I64(x) => ValueBorrow::I64(*x),
+            Value::U64(x) => ValueBorrow::U64(*x),
+            Value::F32(x) => ValueBorrow::F32(*x),
+            Value::F64(x) => ValueBorrow::F64(*x),
+            Value::Bool(x) => ValueBorrow::Bool(*x),
+            Value::Text(x) => ValueBorrow::Text(x),
+            Value::Bytes(x) => ValueBorrow::Bytes(x),
+            Value::Array(x) => ValueBorrow::Array(x.iter().map(ValueBorrow::from).collect()),
+            Value::Map(x) => ValueBorrow::Map(
+                x.iter()
+                    .map(|(k, v)| (k.as_str(), ValueBorrow::from(v)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::kv::{Value, KV};
+    use crate::kv::{KvExt, Value, KV};
     use float_cmp::approx_eq;
     use itertools::izip;
 
@@ -656,7 +988,7 @@ mod tests {
         if let Some(Value::Array(x)) = data.get("float_vec") {
             assert_eq!(x.len(), 3);
             if let Some(Value::Array(v)) = kv.get("float_vec") {
-                izip!(x, v).into_iter().for_each(|(actual, expect)| {
+                izip!(x, v).for_each(|(actual, expect)| {
                     if let Value::F32(actual) = actual {
                         if let Value::F32(expect) = expect {
                             assert!(approx_eq!(f32, *actual, *expect));
@@ -675,7 +1007,7 @@ mod tests {
             assert_eq!(x.len(), 3);
             // u16 -> u64になっているので解釈に注意
             if let Some(Value::Array(v)) = kv.get("uint_vec") {
-                izip!(x, v).into_iter().for_each(|(actual, expect)| {
+                izip!(x, v).for_each(|(actual, expect)| {
                     if let Value::U64(actual) = actual {
                         if let Value::U64(expect) = expect {
                             assert_eq!(actual, expect);
@@ -691,4 +1023,81 @@ mod tests {
             unreachable!();
         }
     }
+
+    #[test]
+    fn test_accessors() {
+        // 整数は範囲に収まれば符号の違う型でも読める
+        assert_eq!(Value::U64(7).as_i64(), Some(7));
+        assert_eq!(Value::U64(u64::MAX).as_i64(), None);
+        assert_eq!(Value::I64(-1).as_u64(), None);
+        assert_eq!(Value::I64(i64::MAX).as_u64(), Some(i64::MAX as u64));
+        assert_eq!(Value::F64(1.0).as_i64(), None);
+
+        // f64は誤差なく表せる整数だけ広げる
+        assert_eq!(Value::F32(0.5).as_f64(), Some(0.5));
+        assert_eq!(Value::I64(-(1 << 53)).as_f64(), Some(-9007199254740992.0));
+        assert_eq!(Value::I64(i64::MIN).as_f64(), None);
+        assert_eq!(Value::U64((1 << 53) + 1).as_f64(), None);
+        assert_eq!(Value::Text("1".to_string()).as_f64(), None);
+
+        let text = Value::Text("a".to_string());
+        assert_eq!(text.as_str(), Some("a"));
+        assert_eq!(text.as_bool(), None);
+        assert_eq!(text.as_bytes(), None);
+        assert_eq!(Value::Bytes(vec![1]).as_bytes(), Some(&[1_u8][..]));
+        assert_eq!(Value::Bool(true).as_bool(), Some(true));
+        assert_eq!(Value::Null.as_array(), None);
+        assert!(Value::Null.is_null());
+        assert_eq!(
+            Value::Array(vec![Value::Null]).as_array(),
+            Some(&[Value::Null][..])
+        );
+        assert_eq!(text.type_name(), "text");
+        assert_eq!(Value::Map(KV::new()).type_name(), "map");
+    }
+
+    #[test]
+    fn test_kv_ext() {
+        let kv = kv_zip!("n", 3_u32, "neg", -3_i32, "s", "x", "b", false);
+        assert_eq!(kv.get_u64("n"), Some(3));
+        assert_eq!(kv.get_i64("n"), Some(3));
+        assert_eq!(kv.get_f64("n"), Some(3.0));
+        assert_eq!(kv.get_u64("neg"), None);
+        assert_eq!(kv.get_i64("neg"), Some(-3));
+        assert_eq!(kv.get_str("s"), Some("x"));
+        assert_eq!(kv.get_str("n"), None);
+        assert_eq!(kv.get_bool("b"), Some(false));
+        assert_eq!(kv.get_bytes("missing"), None);
+        assert_eq!(kv.get_array("s"), None);
+    }
+
+    #[test]
+    fn test_display_mixed_array() {
+        let array = Value::Array(vec![
+            Value::U64(1),
+            Value::Text("a".to_string()),
+            Value::U64(2),
+            Value::Null,
+        ]);
+        assert_eq!(array.to_string(), "vec(mixed(u64, text, null), len=4)");
+    }
+
+    #[test]
+    fn test_map() {
+        let mut inner = KV::new();
+        inner.insert("len".to_string(), Value::U64(3));
+        inner.insert("name".to_string(), Value::Text("x".to_string()));
+        let mut kv = KV::new();
+        kv.insert("map".to_string(), Value::Map(inner.clone()));
+
+        let buf = serde_cbor::to_vec(&kv).unwrap();
+        let data: KV = serde_cbor::from_slice(buf.as_ref()).unwrap();
+        assert_eq!(data.get("map"), Some(&Value::Map(inner)));
+        // borrowからも同じバイト列になる
+        let borrow = kv
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.into()))
+            .collect::<crate::KVBorrow>();
+        assert_eq!(serde_cbor::to_vec(&borrow).unwrap(), buf);
+    }
 }
@@ -0,0 +1,171 @@
+//! kvの数とキーの長さの制限
+//!
+//! 信頼できない呼び出し元が大量のkvを付けるとレコードが大きくなるので、送る前に切り詰める。
+//! 制限しない場合と、制限以内の場合は確保しない
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{KVBorrow, ValueBorrow};
+
+/// 除いたkvの数を付けるキー
+pub const KV_TRUNCATED_KEY: &str = "_kv_truncated";
+/// 切り詰めたキーの末尾に付ける印
+pub const KEY_TRUNCATED_MARKER: &str = "~";
+
+// 0の場合は制限しない
+static MAX_KV_ENTRIES: AtomicUsize = AtomicUsize::new(0);
+static MAX_KEY_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// 上限を設定する。Noneの場合は制限しない
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+pub(crate) fn install(max_entries: Option<usize>, max_key_len: Option<usize>) {
+    MAX_KV_ENTRIES.store(max_entries.unwrap_or(0), Ordering::Release);
+    MAX_KEY_LEN.store(max_key_len.unwrap_or(0), Ordering::Release);
+}
+
+/// 印を含めて`max`バイト以内に切り詰めたキー。文字の途中では切らない
+pub(crate) fn truncate_key(key: &str, max: usize) -> String {
+    let mut end = max.saturating_sub(KEY_TRUNCATED_MARKER.len());
+    while !key.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &key[..end], KEY_TRUNCATED_MARKER)
+}
+
+/// 設定された上限。0は制限しない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KvLimits {
+    pub(crate) max_entries: usize,
+    pub(crate) max_key_len: usize,
+}
+
+/// 設定された上限。どちらも制限しなければNone
+pub(crate) fn installed() -> Option<KvLimits> {
+    let limits = KvLimits {
+        max_entries: MAX_KV_ENTRIES.load(Ordering::Acquire),
+        max_key_len: MAX_KEY_LEN.load(Ordering::Acquire),
+    };
+    (limits.max_entries > 0 || limits.max_key_len > 0).then_some(limits)
+}
+
+/// キーの順に上限の数だけ残し、除いた数を返す
+pub(crate) fn keep_first<K: Ord, V>(kv: &mut BTreeMap<K, V>, max: usize) -> usize {
+    let dropped = kv.len().saturating_sub(max);
+    if dropped > 0 {
+        let mut i = 0;
+        kv.retain(|_, _| {
+            i += 1;
+            i <= max
+        });
+    }
+    dropped
+}
+
+impl KvLimits {
+    fn is_long(&self, key: &str) -> bool {
+        self.max_key_len > 0 && key.len() > self.max_key_len
+    }
+
+    /// 長すぎるキーを切り詰めた名前。長すぎるキーがなければNone
+    ///
+    /// kvは借用したキーを持つので、切り詰めた名前は呼び出し側で[`KvLimits::apply`]より長く持っておく
+    pub(crate) fn truncated_keys(&self, kv: &KVBorrow) -> Option<Vec<String>> {
+        let keys = kv
+            .keys()
+            .filter(|x| self.is_long(x))
+            .map(|x| truncate_key(x, self.max_key_len))
+            .collect::<Vec<_>>();
+        (!keys.is_empty()).then_some(keys)
+    }
+
+    /// 長すぎるキーを`truncated`の名前に替え、上限を超えたkvを除く。
+    /// 除いた数は[`KV_TRUNCATED_KEY`]に付ける
+    ///
+    /// 切り詰めて同じ名前になったキーは最初のものを残し、残りは除いた数に含める
+    pub(crate) fn apply<'a>(&self, kv: &mut KVBorrow<'a>, truncated: Option<&'a [String]>) {
+        let mut dropped = 0;
+        if let Some(truncated) = truncated {
+            let long = kv
+                .keys()
+                .filter(|x| self.is_long(x))
+                .copied()
+                .collect::<Vec<_>>();
+            for (key, name) in long.into_iter().zip(truncated) {
+                let value = kv.remove(key).expect("key listed above");
+                if kv.contains_key(name.as_str()) {
+                    dropped += 1;
+                } else {
+                    kv.insert(name.as_str(), value);
+                }
+            }
+        }
+        if self.max_entries > 0 {
+            dropped += keep_first(kv, self.max_entries);
+        }
+        if dropped > 0 {
+            kv.insert(KV_TRUNCATED_KEY, ValueBorrow::U64(dropped as u64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keep_first, truncate_key, KvLimits, KV_TRUNCATED_KEY};
+    use crate::{KVBorrow, ValueBorrow};
+
+    #[test]
+    fn test_truncate_key() {
+        assert_eq!(truncate_key("request_header", 8), "request~");
+        // 文字の途中では切らない
+        assert_eq!(truncate_key("ヘッダー", 8), "ヘッ~");
+        assert_eq!(truncate_key("abc", 0), "~");
+    }
+
+    #[test]
+    fn test_keep_first() {
+        let mut kv = (0..10)
+            .map(|x| (x, x))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        assert_eq!(keep_first(&mut kv, 20), 0);
+        assert_eq!(keep_first(&mut kv, 3), 7);
+        assert_eq!(kv.keys().copied().collect::<Vec<_>>(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_apply() {
+        let keys = (0..6).map(|x| format!("key{}", x)).collect::<Vec<_>>();
+        let mut kv = keys
+            .iter()
+            .map(|x| (x.as_str(), ValueBorrow::Bool(true)))
+            .collect::<KVBorrow>();
+        kv.insert("long_key_a", ValueBorrow::U8(1));
+        kv.insert("long_key_b", ValueBorrow::U8(2));
+
+        let limits = KvLimits {
+            max_entries: 4,
+            max_key_len: 8,
+        };
+        let truncated = limits.truncated_keys(&kv);
+        limits.apply(&mut kv, truncated.as_deref());
+
+        assert_eq!(
+            truncated.as_deref(),
+            Some(&["long_ke~".to_string(), "long_ke~".to_string()][..])
+        );
+        // 同じ名前になったキーは最初だけ残し、キーの順に4つ残す
+        assert_eq!(
+            kv.keys().copied().collect::<Vec<_>>(),
+            [KV_TRUNCATED_KEY, "key0", "key1", "key2", "key3"]
+        );
+        assert_eq!(kv[KV_TRUNCATED_KEY], ValueBorrow::U64(4));
+
+        // 上限以内なら確保しない
+        let mut small = KVBorrow::new();
+        small.insert("a", ValueBorrow::U8(1));
+        assert_eq!(limits.truncated_keys(&small), None);
+        limits.apply(&mut small, None);
+        assert_eq!(small.len(), 1);
+    }
+}
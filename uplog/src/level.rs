@@ -0,0 +1,195 @@
+//! 実行時に変更できる出力レベル
+//!
+//! 全体の最低レベルと、カテゴリのパターンごとの最低レベルを持つ。
+//! 複数のパターンに一致する場合は後から設定したものを使う。
+//! ターゲットごとの最低レベルはカテゴリの設定より優先する
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    RwLock,
+};
+
+use crate::{category::CategoryPattern, Level};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RULES: RwLock<LevelRules> = RwLock::new(LevelRules {
+    default: Level::Trace,
+    categories: Vec::new(),
+    targets: Vec::new(),
+});
+
+#[derive(Debug)]
+struct LevelRules {
+    default: Level,
+    categories: Vec<(CategoryPattern, Level)>,
+    targets: Vec<(String, Level)>,
+}
+
+impl LevelRules {
+    fn is_trace_all(&self) -> bool {
+        self.default == Level::Trace && self.categories.is_empty() && self.targets.is_empty()
+    }
+
+    fn category_level(&self, category: &str) -> Level {
+        self.categories
+            .iter()
+            .rev()
+            .find(|(p, _)| p.matches(category))
+            .map_or(self.default, |(_, l)| *l)
+    }
+
+    fn target_level(&self, target: &str) -> Option<Level> {
+        self.targets
+            .iter()
+            .rev()
+            .find(|(t, _)| target_matches(t, target))
+            .map(|(_, l)| *l)
+    }
+}
+
+/// 同じターゲットか、`::`で区切った下位のターゲット
+fn target_matches(rule: &str, target: &str) -> bool {
+    target
+        .strip_prefix(rule)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Changes the minimum level written at runtime.
+///
+/// With a category pattern only the matching categories are changed,
+/// otherwise the default level for all categories is changed.
+///
+/// ```
+/// use uplog::{CategoryPattern, Level};
+///
+/// uplog::set_level(Level::Info, None);
+/// uplog::set_level(Level::Trace, Some(CategoryPattern::new("net.*").unwrap()));
+/// assert!(uplog::level_enabled(Level::Trace, "net.eth0"));
+/// assert!(!uplog::level_enabled(Level::Debug, "app"));
+/// # uplog::set_level(Level::Trace, None);
+/// ```
+pub fn set_level(level: Level, category: Option<CategoryPattern>) {
+    let mut rules = RULES.write().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    match category {
+        Some(pattern) => {
+            rules
+                .categories
+                .retain(|(p, _)| p.as_str() != pattern.as_str());
+            rules.categories.push((pattern, level));
+        }
+        None => rules.default = level,
+    }
+    ENABLED.store(!rules.is_trace_all(), Ordering::Release);
+}
+
+/// Changes the minimum level written for a target at runtime.
+///
+/// The target matches itself and the targets below it separated by `::`, e.g. `payments`
+/// matches `payments::card`. For a matching target the level is used instead of the levels of
+/// [`set_level`].
+///
+/// ```
+/// use uplog::Level;
+///
+/// uplog::set_target_level(Level::Error, "doc_target::net");
+/// assert!(!uplog::target_level_enabled(Level::Warn, "doc_target::net::eth0", "app"));
+/// assert!(uplog::target_level_enabled(Level::Warn, "doc_target::app", "app"));
+/// # uplog::set_target_level(Level::Trace, "doc_target::net");
+/// ```
+pub fn set_target_level(level: Level, target: &str) {
+    let mut rules = RULES.write().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    rules.targets.retain(|(t, _)| t != target);
+    rules.targets.push((target.to_string(), level));
+    ENABLED.store(!rules.is_trace_all(), Ordering::Release);
+}
+
+/// Returns true if a record of the level and category is written.
+pub fn level_enabled(level: Level, category: &str) -> bool {
+    if !ENABLED.load(Ordering::Acquire) {
+        return true;
+    }
+    let rules = RULES.read().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    level >= rules.category_level(category)
+}
+
+/// Returns true if a record of the level, target and category is written.
+pub fn target_level_enabled(level: Level, target: &str, category: &str) -> bool {
+    if !ENABLED.load(Ordering::Acquire) {
+        return true;
+    }
+    let rules = RULES.read().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    let min = rules
+        .target_level(target)
+        .unwrap_or_else(|| rules.category_level(category));
+    level >= min
+}
+
+/// 初期化時の設定。カテゴリとターゲットごとの設定は消す
+pub(crate) fn install(default: Level) {
+    let mut rules = RULES.write().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    rules.default = default;
+    rules.categories.clear();
+    rules.targets.clear();
+    ENABLED.store(!rules.is_trace_all(), Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{level_enabled, set_level, set_target_level, target_level_enabled};
+    use crate::{CategoryPattern, Level};
+
+    #[test]
+    fn test_category_level() {
+        // 全体の設定は他のテストに影響するのでカテゴリごとの設定だけ確認する
+        let pattern = |x: &str| Some(CategoryPattern::new(x).unwrap());
+        set_level(Level::Warn, pattern("level_test.**"));
+        set_level(Level::Trace, pattern("level_test.net.*"));
+        assert!(!level_enabled(Level::Info, "level_test.app"));
+        assert!(level_enabled(Level::Warn, "level_test.app"));
+        assert!(level_enabled(Level::Trace, "level_test.net.eth0"));
+
+        // 同じパターンは置き換える
+        set_level(Level::Error, pattern("level_test.net.*"));
+        assert!(!level_enabled(Level::Warn, "level_test.net.eth0"));
+        set_level(Level::Trace, pattern("level_test.net.*"));
+        set_level(Level::Trace, pattern("level_test.**"));
+        assert!(level_enabled(Level::Trace, "level_test.app"));
+    }
+
+    #[test]
+    fn test_target_level() {
+        let pattern = |x: &str| Some(CategoryPattern::new(x).unwrap());
+        set_level(Level::Warn, pattern("target_test.**"));
+        set_target_level(Level::Trace, "target_test::payments");
+        // ターゲットの設定がカテゴリの設定より優先する
+        assert!(target_level_enabled(
+            Level::Debug,
+            "target_test::payments",
+            "target_test.app"
+        ));
+        assert!(target_level_enabled(
+            Level::Debug,
+            "target_test::payments::card",
+            "target_test.app"
+        ));
+        // 名前の途中では区切らない
+        assert!(!target_level_enabled(
+            Level::Debug,
+            "target_test::payments_v2",
+            "target_test.app"
+        ));
+        assert!(!target_level_enabled(
+            Level::Info,
+            "target_test::other",
+            "target_test.app"
+        ));
+
+        set_target_level(Level::Error, "target_test::payments");
+        assert!(!target_level_enabled(
+            Level::Warn,
+            "target_test::payments",
+            "other"
+        ));
+        set_target_level(Level::Trace, "target_test::payments");
+        set_level(Level::Trace, pattern("target_test.**"));
+    }
+}
@@ -4,26 +4,110 @@ use serde::{Deserialize, Serialize};
 
 #[macro_use]
 mod macros;
+mod attachment;
+#[cfg(feature = "client-ws")]
+mod blocking;
+mod boundary;
+mod budget;
 mod buffer;
+mod build_info;
+#[cfg(feature = "client-ws")]
+mod builder;
+mod category;
 mod client;
+mod clock;
+mod delta;
+mod diff;
 pub mod error;
+#[cfg(feature = "file-sink")]
+mod file;
+mod format;
+mod health;
+mod history;
 mod kv;
+mod kvlimit;
+mod level;
 mod logger;
+mod order;
+mod oversize;
+mod panic;
+mod platform;
+pub mod precision;
+#[cfg(feature = "client-ws")]
+mod preset;
+pub mod protocol;
+mod record;
+mod redact;
+mod ring;
 mod session;
-/// recording path
+mod stats;
+#[cfg(feature = "test-util")]
+pub mod testing;
+#[cfg(feature = "tracing")]
+mod tracing_sink;
+mod transport;
+#[cfg(all(unix, feature = "uds"))]
+mod uds;
+mod watchdog;
+pub mod wire;
+#[cfg(feature = "client-ws")]
+mod ws;
+/// Path the server receives records on, and the default of `Builder::path`.
+pub const INGEST_PATH: &str = "/ingest";
+/// Former recording path, still accepted by the server as a deprecated alias of [`INGEST_PATH`].
 pub const WS_PATH: &str = "/logger";
 
 pub use {
+    attachment::{attach, Attachment, ATTACHMENT_CHUNK_SIZE, ATTACHMENT_KEY},
+    boundary::{mark_session_boundary, BOUNDARY_CATEGORY},
+    budget::{category_budget_stats, BudgetStats, BUDGET_CATEGORY},
+    buffer::Growth,
+    build_info::BUILD_CATEGORY,
+    category::CategoryPattern,
     client::{
-        init_noop, try_init, try_init_with_host, Builder, DEFAULT_BUFFER_SIZE, WS_DEFAULT_PORT,
+        init_capture, init_noop, init_with_transport, ErrorCallback, CLIENT_CATEGORY,
+        DEFAULT_BUFFER_SIZE, MIN_BUFFER_SIZE,
     },
-    error::{Error, Result},
-    kv::{KVBorrow, Value, ValueBorrow, KV},
-    logger::{flush, Log},
+    clock::CLOCK_OFFSET_KEY,
+    delta::DELTA_KEY,
+    diff::kv_diff,
+    error::{BuilderError, Error, InitError, Result},
+    format::{
+        ElapsedStyle, FormattedElapsed, FormattedMessage, FormattedRecord, KvStyle, RecordFormatter,
+    },
+    health::{health, Health},
+    history::{RingFileSink, MIN_HISTORY_CAPACITY},
+    kv::{KVBorrow, KvExt, Value, ValueBorrow, KV},
+    kvlimit::{KEY_TRUNCATED_MARKER, KV_TRUNCATED_KEY},
+    level::{level_enabled, set_level, set_target_level, target_level_enabled},
+    logger::{
+        flush, flush_guard, flush_with_deadline, try_flush, FlushGuard, FlushReport, Log,
+        LogOutcome, PREINIT_BUFFER_SIZE, PREINIT_ENV,
+    },
+    order::Ordering,
+    oversize::estimate_record_size,
+    panic::{capture_panics, PANIC_CATEGORY},
+    record::{RecordBuilder, RecordHead},
+    redact::{RedactFn, REDACTED},
     session::session_init,
-    session::start_at,
+    session::{session_id, start_at},
+    stats::{stats_snapshot, LoggerStats, StatsObserver},
+    transport::{MockTransport, Transport},
 };
 
+#[cfg(feature = "client-ws")]
+pub use blocking::{send_records_blocking, with_blocking_logger};
+#[doc(hidden)]
+pub use build_info::__build_info;
+#[cfg(feature = "client-ws")]
+pub use builder::{try_init, try_init_with_host, Builder, WS_DEFAULT_PORT};
+#[cfg(feature = "file-sink")]
+pub use file::{init_file, FileTransport};
+#[cfg(feature = "client-ws")]
+pub use preset::Preset;
+#[cfg(feature = "tracing")]
+pub use tracing_sink::{init_tracing, TracingTransport, TRACING_TARGET};
+
 /// 指定可能なログレベル
 #[repr(usize)]
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Deserialize, Serialize)]
@@ -70,6 +154,9 @@ impl Metadata {
 }
 
 /// logクレートと対応 ログ記録単位
+///
+/// The fields are public for compatibility. New code should build records with
+/// [`Record::builder`] and read them through the accessors.
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Record {
     pub metadata: Metadata,
@@ -80,10 +167,16 @@ pub struct Record {
     pub file: Option<String>,
     pub line: Option<u32>,
     pub message: String,
+    #[serde(default, deserialize_with = "kv::deserialize_kv")]
     pub kv: Option<KV>,
 }
 
 impl Record {
+    /// Starts a record for programmatic use. See [`RecordBuilder`].
+    pub fn builder() -> RecordBuilder {
+        RecordBuilder::default()
+    }
+
     #[inline]
     pub fn metadata(&self) -> &Metadata {
         &self.metadata
@@ -118,32 +211,40 @@ impl Record {
     pub fn key_values(&self) -> Option<&KV> {
         self.kv.as_ref()
     }
+
+    /// Time since the start of the session that wrote the record.
+    #[inline]
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    #[inline]
+    pub fn category(&self) -> &str {
+        &self.category
+    }
+
+    #[inline]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn set_elapsed(&mut self, elapsed: Duration) {
+        self.elapsed = elapsed;
+    }
+
+    pub fn set_message(&mut self, message: impl Into<String>) {
+        self.message = message.into();
+    }
+
+    /// Key-values of the record, created empty if it has none.
+    pub fn key_values_mut(&mut self) -> &mut KV {
+        self.kv.get_or_insert_with(KV::new)
+    }
 }
 
 impl Display for Record {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "[{:?}] {:.4} [{}] {} ({}:L{})",
-            self.level(),
-            self.elapsed.as_secs_f64(),
-            self.category,
-            self.message,
-            self.file().unwrap_or(&"".into()),
-            self.line().unwrap_or(0)
-        )?;
-        if let Some(ref kv) = self.kv {
-            write!(f, " {{")?;
-            for k in kv.keys() {
-                if let Some(v) = kv.get(k) {
-                    write!(f, "{} = {}, ", k, v)?;
-                } else {
-                    write!(f, "{} = ?, ", k)?;
-                }
-            }
-            write!(f, "}}")?;
-        }
-        Ok(())
+        Display::fmt(&RecordFormatter::new().format(self), f)
     }
 }
 
@@ -161,14 +262,6 @@ impl<'a> MetadataBorrow<'a> {
         Self { target, level }
     }
 
-    #[inline]
-    pub fn default() -> Self {
-        Self {
-            level: Level::Info,
-            target: "",
-        }
-    }
-
     #[inline]
     pub fn level(&self) -> Level {
         self.level
@@ -194,7 +287,7 @@ impl Default for MetadataBorrow<'_> {
 pub struct RecordBorrow<'a> {
     metadata: MetadataBorrow<'a>,
     // log detail
-    #[serde(with = "duration")]
+    #[serde(serialize_with = "precision::serialize_elapsed")]
     elapsed: Duration,
     category: &'a str,
     module_path: Option<&'a str>,
@@ -237,14 +330,52 @@ impl<'a> RecordBorrow<'a> {
     }
 
     #[inline]
-    pub fn key_values(&self) -> Option<&'a KVBorrow> {
+    pub fn key_values(&self) -> Option<&KVBorrow<'a>> {
         self.kv.as_ref()
     }
 }
 
+impl From<&RecordBorrow<'_>> for Record {
+    fn from(r: &RecordBorrow<'_>) -> Self {
+        Self {
+            metadata: Metadata::new(r.level(), r.target().to_string()),
+            elapsed: r.elapsed,
+            category: r.category.to_string(),
+            module_path: r.module_path.map(str::to_string),
+            file: r.file.map(str::to_string),
+            line: r.line,
+            message: r.message.to_string(),
+            kv: r.kv.as_ref().map(|x| {
+                x.iter()
+                    .map(|(k, v)| (k.to_string(), Value::from(v)))
+                    .collect()
+            }),
+        }
+    }
+}
+
+impl<'a> From<&'a Record> for RecordBorrow<'a> {
+    fn from(r: &'a Record) -> Self {
+        Self {
+            metadata: MetadataBorrow::new(r.level(), r.target()),
+            elapsed: r.elapsed,
+            category: &r.category,
+            module_path: r.module_path.as_deref(),
+            file: r.file.as_deref(),
+            line: r.line,
+            message: &r.message,
+            kv: r.kv.as_ref().map(|x| {
+                x.iter()
+                    .map(|(k, v)| (k.as_str(), ValueBorrow::from(v)))
+                    .collect()
+            }),
+        }
+    }
+}
+
 // durationは(デ)シリアライザが実装されていないのでmoduleで指定する
 mod duration {
-    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::{Deserializer, Serialize, Serializer};
     use std::time::Duration;
 
     pub fn serialize<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
@@ -254,12 +385,12 @@ mod duration {
         duration.serialize(serializer)
     }
 
+    /// 整数の場合は交渉した単位で読む
     pub fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let de = Duration::deserialize(deserializer)?;
-        Ok(de)
+        crate::precision::deserialize_elapsed(deserializer)
     }
 }
 
@@ -315,6 +446,8 @@ pub fn __encode_log<'a>(
     serde_cbor::to_writer(buf, &r).expect("serialize error");
 }
 
+/// `target`はマクロの`target:`で指定した論理的な出力先で、既定はモジュールパス。
+/// `module_path`は常に呼び出し元のモジュール
 #[doc(hidden)]
 #[allow(clippy::too_many_arguments)]
 pub fn __log_api<'a>(
@@ -326,27 +459,284 @@ pub fn __log_api<'a>(
     file: &'static str,
     line: u32,
     kv: Option<KVBorrow>,
-) {
+) -> LogOutcome {
+    if !level::target_level_enabled(level, target, category) || !category::is_enabled(category) {
+        return LogOutcome::FilteredLevel;
+    }
+    if !budget::check(category, message, kv.as_ref()) {
+        return LogOutcome::Sampled;
+    }
+    // 送信バッファに書き込む前に秘匿する
+    let redacted = redact::redact(kv.as_ref());
+    let kv = match redacted {
+        Some(ref x) => Some(redact::borrow_kv(x)),
+        None => kv,
+    };
+    // 内部で付けるキーより前に、呼び出し元のkvを制限する
+    let limits = kvlimit::installed();
+    let truncated = limits.and_then(|x| x.truncated_keys(kv.as_ref()?));
+    let mut kv = kv;
+    if let (Some(limits), Some(kv)) = (limits, kv.as_mut()) {
+        limits.apply(kv, truncated.as_deref());
+    }
+    // 時計のずれが大きい間はレコードに付ける
+    if let Some(offset) = clock::stamp() {
+        kv.get_or_insert_with(KVBorrow::new)
+            .insert(clock::CLOCK_OFFSET_KEY, ValueBorrow::I64(offset));
+    }
+    let elapsed = session::elapsed();
+    if let Some(delta) = delta::stamp(category, elapsed) {
+        kv.get_or_insert_with(KVBorrow::new)
+            .insert(delta::DELTA_KEY, ValueBorrow::U64(delta));
+    }
     let metadata = MetadataBorrow::new(level, target);
-
-    logger::logger().log(&RecordBorrow {
+    let record = RecordBorrow {
         metadata,
-        elapsed: session::elapsed(),
+        elapsed,
         category,
         message,
         module_path: Some(module_path),
         file: Some(file),
         line: Some(line),
         kv,
-    });
+    };
+    // シリアライズする前に大きさを見積もって除く
+    if !oversize::check(&record, |r| {
+        logger::logger().log(r);
+    }) {
+        return LogOutcome::Oversized;
+    }
+    #[cfg(feature = "client-ws")]
+    if blocking::capture(&record) {
+        return LogOutcome::Accepted;
+    }
+    logger::logger().log(&record)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+
+    use proptest::prelude::*;
     use serde_cbor::{from_slice, to_vec};
 
     use crate::*;
 
+    fn arb_level() -> impl Strategy<Value = Level> {
+        prop_oneof![
+            Just(Level::Trace),
+            Just(Level::Debug),
+            Just(Level::Info),
+            Just(Level::Warn),
+            Just(Level::Error),
+        ]
+    }
+
+    fn arb_value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<i64>().prop_map(Value::I64),
+            any::<u64>().prop_map(Value::U64),
+            any::<f32>()
+                .prop_filter("NaN is not equal to itself", |x| !x.is_nan())
+                .prop_map(Value::F32),
+            any::<f64>()
+                .prop_filter("NaN is not equal to itself", |x| !x.is_nan())
+                .prop_map(Value::F64),
+            any::<bool>().prop_map(Value::Bool),
+            any::<String>().prop_map(Value::Text),
+            prop::collection::vec(any::<u8>(), 0..64).prop_map(Value::Bytes),
+        ];
+        leaf.prop_recursive(8, 64, 8, |inner| {
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..8).prop_map(Value::Array),
+                prop::collection::btree_map(any::<String>(), inner, 0..8).prop_map(Value::Map),
+            ]
+        })
+    }
+
+    fn arb_record() -> impl Strategy<Value = Record> {
+        (
+            (
+                arb_level(),
+                any::<String>(),
+                any::<u32>(),
+                0..1_000_000_000_u32,
+            ),
+            (any::<String>(), any::<String>()),
+            (
+                any::<Option<String>>(),
+                any::<Option<String>>(),
+                any::<Option<u32>>(),
+            ),
+            prop::option::of(prop::collection::btree_map(
+                any::<String>(),
+                arb_value(),
+                0..8,
+            )),
+        )
+            .prop_map(
+                |(
+                    (level, target, secs, nanos),
+                    (category, message),
+                    (module_path, file, line),
+                    kv,
+                )| {
+                    Record {
+                        metadata: Metadata::new(level, target),
+                        elapsed: Duration::new(secs as u64, nanos),
+                        category,
+                        message,
+                        module_path,
+                        file,
+                        line,
+                        kv,
+                    }
+                },
+            )
+    }
+
+    /// CBORで値が詰められたものに揃える
+    ///
+    /// 0以上の整数は符号なし、f32で表せるf64はf32としてデコードされる
+    fn normalize(value: Value) -> Value {
+        match value {
+            Value::I64(x) if x >= 0 => Value::U64(x as u64),
+            Value::F64(x) if (x as f32) as f64 == x => Value::F32(x as f32),
+            Value::Array(x) => Value::Array(x.into_iter().map(normalize).collect()),
+            Value::Map(x) => Value::Map(x.into_iter().map(|(k, v)| (k, normalize(v))).collect()),
+            x => x,
+        }
+    }
+
+    fn normalize_record(mut record: Record) -> Record {
+        record.kv = record
+            .kv
+            .map(|kv| kv.into_iter().map(|(k, v)| (k, normalize(v))).collect());
+        record
+    }
+
+    proptest! {
+        #[test]
+        fn prop_value_roundtrip(value in arb_value()) {
+            let encoded = to_vec(&value).unwrap();
+            let decoded: Value = from_slice(&encoded).unwrap();
+            prop_assert_eq!(decoded, normalize(value.clone()));
+            // 表示で落ちない
+            let _ = value.to_string();
+        }
+
+        #[test]
+        fn prop_record_roundtrip(record in arb_record()) {
+            let encoded = to_vec(&record).unwrap();
+            let decoded: Record = from_slice(&encoded).unwrap();
+            prop_assert_eq!(decoded, normalize_record(record.clone()));
+            let _ = record.to_string();
+        }
+
+        /// 壊れたデータはエラーになり、panicしない
+        #[test]
+        fn prop_mutated_record(
+            record in arb_record(),
+            mutations in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+            truncate in any::<prop::sample::Index>(),
+        ) {
+            let mut encoded = to_vec(&record).unwrap();
+            for (index, byte) in mutations {
+                let i = index.index(encoded.len());
+                encoded[i] = byte;
+            }
+            for buf in [&encoded[..], &encoded[..truncate.index(encoded.len())]] {
+                for decoded in serde_cbor::Deserializer::from_slice(buf).into_iter::<Record>() {
+                    match decoded {
+                        Ok(r) => {
+                            let _ = r.to_string();
+                            to_vec(&r).unwrap();
+                        }
+                        Err(_) => break,
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn prop_large_array(len in 0..20_000_usize) {
+            let value = Value::Array(vec![Value::U64(1); len]);
+            let decoded: Value = from_slice(&to_vec(&value).unwrap()).unwrap();
+            prop_assert_eq!(decoded, value);
+        }
+    }
+
+    fn nested(depth: usize) -> Value {
+        (0..depth).fold(Value::Null, |acc, _| Value::Array(vec![acc]))
+    }
+
+    #[test]
+    fn test_nested_depth() {
+        let value = nested(100);
+        let decoded: Value = from_slice(&to_vec(&value).unwrap()).unwrap();
+        assert_eq!(decoded, value);
+        let _ = value.to_string();
+
+        // 深すぎるものはエラーにする
+        let encoded = to_vec(&nested(1000)).unwrap();
+        assert!(from_slice::<Value>(&encoded).is_err());
+        let mut encoded = vec![0x81; 100_000];
+        encoded.push(0xf6);
+        assert!(from_slice::<Value>(&encoded).is_err());
+    }
+
+    /// 文字列以外のキーは文字列にし、配列などのキーはエラーにする
+    #[test]
+    fn test_non_string_keys() {
+        use serde_cbor::Value as Cbor;
+
+        devinit!();
+        let record = devlog!(Level::Info, "cat", "msg", "a", 1_u8);
+        let with_keys = |keys: Vec<Cbor>| {
+            let mut encoded: BTreeMap<Cbor, Cbor> =
+                serde_cbor::value::from_value(serde_cbor::value::to_value(&record).unwrap())
+                    .unwrap();
+            let kv = keys
+                .into_iter()
+                .map(|k| {
+                    (
+                        k,
+                        Cbor::Map(BTreeMap::from([(Cbor::Integer(-2), Cbor::Null)])),
+                    )
+                })
+                .collect();
+            encoded.insert(Cbor::Text("kv".to_string()), Cbor::Map(kv));
+            to_vec(&encoded).unwrap()
+        };
+
+        let buf = with_keys(vec![
+            Cbor::Integer(1),
+            Cbor::Bool(true),
+            Cbor::Text("text".to_string()),
+        ]);
+        let decoded: Record = from_slice(&buf).unwrap();
+        let kv = decoded.kv.unwrap();
+        assert_eq!(
+            kv.keys().map(String::as_str).collect::<Vec<_>>(),
+            ["1", "text", "true"]
+        );
+        let nested = BTreeMap::from([("-2".to_string(), Value::Null)]);
+        assert_eq!(kv["1"], Value::Map(nested));
+
+        let buf = with_keys(vec![Cbor::Array(vec![Cbor::Integer(1)])]);
+        assert!(from_slice::<Record>(&buf).is_err());
+    }
+
+    #[test]
+    fn test_display_empty_array() {
+        assert_eq!(Value::Array(vec![]).to_string(), "vec(len=0)");
+        assert_eq!(
+            Value::Array(vec![Value::U64(3), Value::U64(4)]).to_string(),
+            "vec(3, len=2)"
+        );
+    }
+
     #[test]
     fn test_metadata() {
         let target = "xxx";
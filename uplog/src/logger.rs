@@ -1,19 +1,156 @@
 /// crate logとintarfaceを近づける実装
 use std::{
-    cell::Cell,
+    collections::VecDeque,
     error,
     fmt::{self, Display},
+    ptr,
+    sync::{
+        atomic::{AtomicPtr, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex, OnceLock,
+    },
     thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
-use crate::{MetadataBorrow, RecordBorrow};
+use crate::{
+    client::{LogClient, Unsent},
+    watchdog::Watchdog,
+    Level, MetadataBorrow, Record, RecordBorrow, ValueBorrow, CLIENT_CATEGORY,
+};
 
 pub trait Log: Sync + Send {
     fn enabled(&self, metadata: &MetadataBorrow) -> bool;
-    fn log(&self, record: &RecordBorrow);
+    fn log(&self, record: &RecordBorrow) -> LogOutcome;
     fn flush(&self);
 }
 
+/// What happened to a record passed to the log macros.
+///
+/// The macros evaluate to this value, so a call site can check whether a record was kept:
+///
+/// ```
+/// uplog::init_capture().unwrap();
+/// if !uplog::error!("app", "disk full").is_accepted() {
+///     eprintln!("disk full");
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogOutcome {
+    /// written to the send buffer
+    Accepted,
+    /// below the level or outside the categories that are written
+    FilteredLevel,
+    /// dropped by the byte budget of the category
+    Sampled,
+    /// dropped because the send buffer, or the buffer for records logged before initialization,
+    /// was full
+    DroppedFull,
+    /// dropped because the record is over the maximum record size
+    Oversized,
+    /// dropped because no logger is installed and the buffer for records logged before
+    /// initialization is turned off with [`PREINIT_ENV`]
+    NotInitialized,
+}
+
+impl LogOutcome {
+    pub fn is_accepted(&self) -> bool {
+        *self == Self::Accepted
+    }
+}
+
+/// Bytes of encoded records kept from before the logger is initialized, unless [`PREINIT_ENV`]
+/// sets another size.
+pub const PREINIT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Environment variable setting the size of the buffer for records logged before initialization.
+///
+/// Records logged before [`crate::try_init`] are kept up to [`PREINIT_BUFFER_SIZE`] bytes,
+/// dropping the oldest first, and written to the logger with their original time when it is
+/// installed. A number sets another size in bytes, and `0` or `off` turns the buffer off so
+/// logging before initialization costs nothing.
+pub const PREINIT_ENV: &str = "UPLOG_PREINIT_BUFFER";
+
+/// 初期化前のレコードを溜める大きさ。最初に書くときに環境変数から決める
+static PREINIT_CAPACITY: OnceLock<usize> = OnceLock::new();
+
+fn preinit_capacity() -> usize {
+    *PREINIT_CAPACITY
+        .get_or_init(|| parse_preinit_capacity(std::env::var(PREINIT_ENV).ok().as_deref()))
+}
+
+/// 設定がないか数でなければ既定の大きさにする
+fn parse_preinit_capacity(value: Option<&str>) -> usize {
+    match value {
+        Some("off") => 0,
+        Some(x) => x.parse().unwrap_or(PREINIT_BUFFER_SIZE),
+        None => PREINIT_BUFFER_SIZE,
+    }
+}
+
+/// 初期化前のレコード。ロガーを設定したら閉じる
+struct PreInit {
+    records: VecDeque<Vec<u8>>,
+    bytes: usize,
+    dropped: u64,
+    closed: bool,
+}
+
+static PREINIT: Mutex<PreInit> = Mutex::new(PreInit {
+    records: VecDeque::new(),
+    bytes: 0,
+    dropped: 0,
+    closed: false,
+});
+
+impl PreInit {
+    /// `capacity`バイトに収まるように古いものから捨てる
+    fn push(&mut self, record: &RecordBorrow, capacity: usize) -> LogOutcome {
+        // 送るときの単位は決まっていないのでDurationのまま持つ
+        let encoded = match serde_cbor::to_vec(&Record::from(record)) {
+            Ok(x) if x.len() <= capacity => x,
+            _ => {
+                self.dropped += 1;
+                return LogOutcome::DroppedFull;
+            }
+        };
+        while self.bytes + encoded.len() > capacity {
+            let oldest = self.records.pop_front().expect("bytes counts the records");
+            self.bytes -= oldest.len();
+            self.dropped += 1;
+        }
+        self.bytes += encoded.len();
+        self.records.push_back(encoded);
+        LogOutcome::Accepted
+    }
+
+    /// 溜めたレコードを`logger`に書いて閉じる
+    fn drain_into(&mut self, logger: &dyn Log) {
+        self.closed = true;
+        self.bytes = 0;
+        for encoded in self.records.drain(..) {
+            if let Ok(record) = serde_cbor::from_slice::<Record>(&encoded) {
+                logger.log(&RecordBorrow::from(&record));
+            }
+        }
+        if self.dropped > 0 {
+            let kv = kv_borrow_zip!("dropped", ValueBorrow::U64(self.dropped));
+            logger.log(&RecordBorrow {
+                metadata: MetadataBorrow::new(Level::Warn, CLIENT_CATEGORY),
+                elapsed: crate::session::elapsed(),
+                category: CLIENT_CATEGORY,
+                module_path: None,
+                file: None,
+                line: None,
+                message: "records logged before initialization were dropped",
+                kv: Some(kv),
+            });
+            self.dropped = 0;
+        }
+    }
+}
+
+/// 初期化前のレコードを溜める
 struct NopLogger;
 
 impl Log for NopLogger {
@@ -21,41 +158,234 @@ impl Log for NopLogger {
         false
     }
 
-    fn log(&self, _: &RecordBorrow) {}
+    fn log(&self, record: &RecordBorrow) -> LogOutcome {
+        // 溜めない場合は符号化もロックもしない
+        let capacity = preinit_capacity();
+        if capacity == 0 {
+            return LogOutcome::NotInitialized;
+        }
+        let mut preinit = PREINIT
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+        if preinit.closed {
+            // 呼び出し元がロガーを読んだ後に初期化された
+            drop(preinit);
+            return logger().log(record);
+        }
+        preinit.push(record, capacity)
+    }
     fn flush(&self) {}
 }
 
-// global logger
-static mut LOGGER: &dyn Log = &NopLogger;
-static mut HANDLE: Cell<Option<JoinHandle<()>>> = Cell::new(None);
+/// global logger
+///
+/// flushの後に設定し直せるので、リークしたロガーを指すポインタを差し替える。nullなら[`NopLogger`]
+static LOGGER: AtomicPtr<LogClient> = AtomicPtr::new(ptr::null_mut());
+static HANDLE: Mutex<Option<SenderHandle>> = Mutex::new(None);
+/// [`FlushGuard`]が送信スレッドを待つ期限の既定値
+static FLUSH_DEADLINE: Mutex<Option<Duration>> = Mutex::new(None);
+
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+pub(crate) fn install_flush_deadline(deadline: Option<Duration>) {
+    *FLUSH_DEADLINE
+        .lock()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK) = deadline;
+}
+
+/// 送信スレッドと、終了時にそのスレッドが返す送信結果
+#[derive(Debug)]
+pub struct SenderHandle {
+    /// 見張りがあるときは見張りが今のスレッドを持つ
+    thread: Option<JoinHandle<()>>,
+    report: Receiver<FlushReport>,
+    watchdog: Option<Arc<Watchdog>>,
+    /// 期限を過ぎたときの書き出し先を設定していれば、送信スレッドがまだ読んでいないレコード
+    unsent: Option<Unsent>,
+}
+
+impl SenderHandle {
+    pub(crate) fn new(thread: JoinHandle<()>, report: Receiver<FlushReport>) -> Self {
+        Self {
+            thread: Some(thread),
+            report,
+            watchdog: None,
+            unsent: None,
+        }
+    }
+
+    /// 見張りが作り直す送信スレッド
+    pub(crate) fn watched(watchdog: Arc<Watchdog>, report: Receiver<FlushReport>) -> Self {
+        Self {
+            thread: None,
+            report,
+            watchdog: Some(watchdog),
+            unsent: None,
+        }
+    }
+
+    /// 送信スレッドの終了を待つ。終了の要求は済ませておく
+    ///
+    /// `timeout`までに終わらなければ自身を返す
+    pub(crate) fn finish(mut self, timeout: Option<Duration>) -> Result<FlushReport, Self> {
+        let report = match self.watchdog.as_ref() {
+            Some(watchdog) => match self.wait_watched(watchdog, timeout) {
+                Some(x) => x,
+                None => return Err(self),
+            },
+            None => match timeout {
+                Some(timeout) => match self.report.recv_timeout(timeout) {
+                    Ok(x) => Some(x),
+                    Err(RecvTimeoutError::Timeout) => return Err(self),
+                    Err(RecvTimeoutError::Disconnected) => None,
+                },
+                None => self.report.recv().ok(),
+            },
+        };
+        if let Some(thread) = self.take_thread() {
+            thread.join().ok();
+        }
+        // 報告する前にスレッドが止まった場合は送れたかわからない
+        Ok(report.unwrap_or_default())
+    }
+
+    pub(crate) fn with_unsent(mut self, unsent: Option<Unsent>) -> Self {
+        self.unsent = unsent;
+        self
+    }
+
+    /// `deadline`まで送信スレッドの終了を待ち、終わらなければ残っているレコードを書き出す
+    ///
+    /// 終わらなかった場合は報告と一緒に自身を返す
+    pub(crate) fn finish_or_spill(self, deadline: Duration) -> (FlushReport, Option<Self>) {
+        let start = Instant::now();
+        let handle = match self.finish(Some(deadline)) {
+            Ok(report) => return (report, None),
+            Err(x) => x,
+        };
+        let mut report = FlushReport {
+            incomplete: true,
+            ..Default::default()
+        };
+        match handle.unsent.as_ref().map(Unsent::spill) {
+            Some(Ok(count)) => report.records_spilled = count,
+            Some(Err(e)) => eprintln!("uplog: failed to spill unsent records: {}", e),
+            None => {}
+        }
+        report.duration = start.elapsed();
+        (report, Some(handle))
+    }
 
-pub fn set_boxed_logger(
-    logger: Box<dyn Log>,
-    handle: JoinHandle<()>,
-) -> Result<(), SetLoggerError> {
+    /// 止まっていれば作り直しながら報告を待つ。`timeout`までに終わらなければNone
+    fn wait_watched(
+        &self,
+        watchdog: &Watchdog,
+        timeout: Option<Duration>,
+    ) -> Option<Option<FlushReport>> {
+        let deadline = timeout.map(|x| Instant::now() + x);
+        loop {
+            let wait = deadline.map_or(watchdog.stall(), |x| {
+                x.saturating_duration_since(Instant::now())
+                    .min(watchdog.stall())
+            });
+            match self.report.recv_timeout(wait) {
+                Ok(x) => return Some(Some(x)),
+                Err(RecvTimeoutError::Disconnected) => return Some(None),
+                Err(RecvTimeoutError::Timeout) => {
+                    // 見張りが送信側を持っているので切断されない。終わったスレッドは待たない
+                    if !watchdog.check(crate::session::elapsed()) && watchdog.is_finished() {
+                        return Some(self.report.try_recv().ok());
+                    }
+                    if deadline.is_some_and(|x| Instant::now() >= x) {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn take_thread(&mut self) -> Option<JoinHandle<()>> {
+        match self.watchdog.as_ref() {
+            Some(watchdog) => watchdog.take_thread(),
+            None => self.thread.take(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn join(mut self) -> std::thread::Result<()> {
+        self.take_thread().map_or(Ok(()), |x| x.join())
+    }
+}
+
+/// Result of the final send made by [`flush`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FlushReport {
+    /// bytes sent by the final send
+    pub bytes_sent: u64,
+    /// records sent by the final send, including the records the client writes about the connection
+    pub records_sent: u64,
+    /// records left in the buffer that could not be sent
+    pub records_dropped: u64,
+    /// whether the final send and closing the connection succeeded
+    pub transport_ok: bool,
+    /// time from the flush request to the end of the sender thread
+    pub duration: Duration,
+    /// whether [`flush_with_deadline`] returned before the sender thread finished
+    pub incomplete: bool,
+    /// records written to the spill file by [`flush_with_deadline`] instead of being sent
+    pub records_spilled: u64,
+}
+
+impl Display for FlushReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.incomplete {
+            return write!(
+                f,
+                "flush incomplete ({} records spilled in {:?})",
+                self.records_spilled, self.duration
+            );
+        }
+        write!(
+            f,
+            "{} ({} records, {} bytes sent, {} records dropped in {:?})",
+            if self.transport_ok {
+                "flushed"
+            } else {
+                "flush failed"
+            },
+            self.records_sent,
+            self.bytes_sent,
+            self.records_dropped,
+            self.duration
+        )
+    }
+}
+
+pub fn set_logger(logger: LogClient, handle: SenderHandle) -> Result<(), SetLoggerError> {
     set_therad_handle(handle)?;
-    set_logger_inner(|| Box::leak(logger))
+    set_logger_inner(|| Box::leak(Box::new(logger)))
 }
 
 fn set_logger_inner<F>(make_logger: F) -> Result<(), SetLoggerError>
 where
-    F: FnOnce() -> &'static dyn Log,
+    F: FnOnce() -> &'static mut LogClient,
 {
-    unsafe {
-        LOGGER = make_logger();
-    }
+    // 溜めたレコードを先に書き、その間に来たレコードはその後に書く
+    let mut preinit = PREINIT
+        .lock()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    let logger = make_logger();
+    preinit.drain_into(logger);
+    LOGGER.store(logger, Ordering::Release);
     Ok(())
 }
 
-pub(crate) fn set_therad_handle(handle: JoinHandle<()>) -> Result<(), SetLoggerError> {
-    unsafe {
-        let glocal_handle = HANDLE.get_mut();
-        match glocal_handle {
-            Some(_) => Err(SetLoggerError),
-            None => {
-                *glocal_handle = Some(handle);
-                Ok(())
-            }
+pub(crate) fn set_therad_handle(handle: SenderHandle) -> Result<(), SetLoggerError> {
+    let mut glocal_handle = HANDLE.lock().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    match *glocal_handle {
+        Some(_) => Err(SetLoggerError),
+        None => {
+            *glocal_handle = Some(handle);
+            Ok(())
         }
     }
 }
@@ -71,19 +401,180 @@ impl Display for SetLoggerError {
 impl error::Error for SetLoggerError {}
 
 pub fn logger() -> &'static dyn Log {
-    unsafe { LOGGER }
+    // 指す先はリークしたロガーなので解放されない
+    match unsafe { LOGGER.load(Ordering::Acquire).as_ref() } {
+        Some(logger) => logger,
+        None => &NopLogger,
+    }
+}
+
+/// レベルやカテゴリの設定を通さずにレコードを書く
+pub(crate) fn submit_direct(record: &RecordBorrow) {
+    logger().log(record);
 }
 
 /// flush swapbuffer and closing sender thread
 ///
 /// It is highly recommended to call it before the end of the program
 /// to completely send the data in the buffer.
-pub fn flush() {
-    unsafe {
-        LOGGER.flush();
-        let glocal_handle = HANDLE.get_mut();
-        if let Some(x) = glocal_handle.take() {
-            x.join().ok();
+///
+/// Returns the result of the final send, or `None` when no sender thread is running,
+/// e.g. before initialization or after the first flush.
+pub fn flush() -> Option<FlushReport> {
+    logger().flush();
+    let handle = HANDLE
+        .lock()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+        .take();
+    handle.and_then(|x| x.finish(None).ok())
+}
+
+/// Same as [`flush`], but waits for the sender thread at most `timeout`.
+///
+/// Returns `None` when the sender thread did not finish in time. The thread keeps sending,
+/// and a later [`flush`] or `try_flush` waits for it again.
+pub fn try_flush(timeout: Duration) -> Option<FlushReport> {
+    logger().flush();
+    let mut global_handle = HANDLE.lock().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    match global_handle.take()?.finish(Some(timeout)) {
+        Ok(report) => Some(report),
+        Err(handle) => {
+            *global_handle = Some(handle);
+            None
         }
     }
 }
+
+/// Same as [`flush`], but gives up waiting for the sender thread after `deadline`.
+///
+/// Meant for a shutdown with a time limit, e.g. the grace period of a container. When the
+/// sender thread does not finish in time, the records it has not taken from the buffer yet
+/// are appended to the file of `Builder::spill_file`, if set, and the report is marked
+/// [`FlushReport::incomplete`]. The records being sent at that moment are not spilled.
+/// The spill file is a CBOR sequence of records, the same as written by `FileTransport`.
+///
+/// Returns `None` when no sender thread is running.
+pub fn flush_with_deadline(deadline: Duration) -> Option<FlushReport> {
+    logger().flush();
+    let mut global_handle = HANDLE.lock().expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+    let (report, handle) = global_handle.take()?.finish_or_spill(deadline);
+    // 送り続けているスレッドは後のflushで待てるように残す
+    *global_handle = handle;
+    Some(report)
+}
+
+/// Calls [`flush`] when dropped, and writes the report to stderr when the final send failed.
+///
+/// With a deadline, from [`FlushGuard::deadline`] or else `Builder::flush_deadline`, it calls
+/// [`flush_with_deadline`] instead.
+///
+/// # Example
+///
+/// ```
+/// uplog::try_init().unwrap();
+/// let _guard = uplog::flush_guard();
+/// // your program...
+/// ```
+#[must_use = "flushes when dropped"]
+#[derive(Debug)]
+pub struct FlushGuard {
+    deadline: Option<Duration>,
+}
+
+/// Returns a guard that flushes when dropped.
+pub fn flush_guard() -> FlushGuard {
+    FlushGuard { deadline: None }
+}
+
+impl FlushGuard {
+    /// Waits for the sender thread at most `deadline` when dropped.
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+}
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        let deadline = self.deadline.or(*FLUSH_DEADLINE
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK));
+        let report = match deadline {
+            Some(x) => flush_with_deadline(x),
+            None => flush(),
+        };
+        if let Some(report) = report.filter(|x| !x.transport_ok) {
+            eprintln!("uplog: {}", report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use super::{parse_preinit_capacity, Log, LogOutcome, PreInit, PREINIT_BUFFER_SIZE};
+    use crate::{Level, MetadataBorrow, Record, RecordBorrow, CLIENT_CATEGORY};
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<Record>>);
+
+    impl Log for Collect {
+        fn enabled(&self, _: &MetadataBorrow) -> bool {
+            true
+        }
+        fn log(&self, record: &RecordBorrow) -> LogOutcome {
+            self.0.lock().unwrap().push(Record::from(record));
+            LogOutcome::Accepted
+        }
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_preinit_capacity() {
+        // 設定しなければ既定の大きさで溜める
+        assert_eq!(parse_preinit_capacity(None), PREINIT_BUFFER_SIZE);
+        assert_eq!(parse_preinit_capacity(Some("")), PREINIT_BUFFER_SIZE);
+        assert_eq!(parse_preinit_capacity(Some("4096")), 4096);
+        assert_eq!(parse_preinit_capacity(Some("0")), 0);
+        assert_eq!(parse_preinit_capacity(Some("off")), 0);
+    }
+
+    #[test]
+    fn test_preinit_ring() {
+        crate::session_init();
+        let records = (0..4_u32)
+            .map(|i| devlog!(Level::Info, "test.preinit", "record", "i", i))
+            .collect::<Vec<_>>();
+        let size = serde_cbor::to_vec(&records[0]).unwrap().len();
+        let mut preinit = PreInit {
+            records: VecDeque::new(),
+            bytes: 0,
+            dropped: 0,
+            closed: false,
+        };
+        for record in records.iter() {
+            assert_eq!(
+                preinit.push(&RecordBorrow::from(record), size * 2),
+                LogOutcome::Accepted
+            );
+        }
+        // 容量より大きいレコードは溜めない
+        let large = devlog!(Level::Info, "test.preinit", "x".repeat(size * 2).as_str());
+        assert_eq!(
+            preinit.push(&RecordBorrow::from(&large), size * 2),
+            LogOutcome::DroppedFull
+        );
+
+        let logger = Collect::default();
+        preinit.drain_into(&logger);
+        assert!(preinit.closed);
+        let logged = logger.0.into_inner().unwrap();
+        // 古いものから捨てる
+        assert_eq!(logged[..2], records[2..]);
+        assert_eq!(logged.len(), 3);
+        assert_eq!(logged[2].category(), CLIENT_CATEGORY);
+        assert_eq!(logged[2].level(), Level::Warn);
+        assert_eq!(logged[2].key_values().unwrap()["dropped"], 3_u64.into());
+    }
+}
@@ -1,10 +1,13 @@
 /// log
+///
+/// The target of the record is the module path unless it is given by `target:`,
+/// e.g. `log!(target: "payments", Level::Info, "category", "message")`.
 #[macro_export(local_inner_macros)]
 macro_rules! log {
-    ($level:expr, $category:expr, $message:expr, $kv:expr) => {
+    (target: $target:expr, $level:expr, $category:expr, $message:expr, $kv:expr) => {
         $crate::__log_api(
             $level,
-            __log_module_path!(),
+            $target,
             $category,
             $message,
             __log_module_path!(),
@@ -13,6 +16,16 @@ macro_rules! log {
             $kv,
         )
     };
+    (target: $target:expr, $level:expr, $category:expr, $message:expr) => {
+        log!(target: $target, $level, $category, $message, None)
+    };
+    (target: $target:expr, $level:expr, $category:expr, $message:expr, $($k:expr, $v:expr),+) => ({
+        let kv = kv_borrow_zip!($($k, $v),*);
+        log!(target: $target, $level, $category, $message, Some(kv))
+    });
+    ($level:expr, $category:expr, $message:expr, $kv:expr) => {
+        log!(target: __log_module_path!(), $level, $category, $message, $kv)
+    };
     ($level:expr, $category:expr, $message:expr) => {
         log!($level, $category, $message, None)
     };
@@ -25,6 +38,12 @@ macro_rules! log {
 /// error log
 #[macro_export(local_inner_macros)]
 macro_rules! error {
+    (target: $target:expr, $category:expr, $message:expr, $($k:expr, $v:expr),+) => (
+        log!(target: $target, $crate::Level::Error, $category, $message, $($k, $v),+)
+    );
+    (target: $target:expr, $category:expr, $message:expr) => {
+        log!(target: $target, $crate::Level::Error, $category, $message, None)
+    };
     ($category:expr, $message:expr, $($k:expr, $v:expr),+) => (
         log!($crate::Level::Error, $category, $message, $($k, $v),+)
     );
@@ -36,6 +55,12 @@ macro_rules! error {
 /// warn log
 #[macro_export(local_inner_macros)]
 macro_rules! warn {
+    (target: $target:expr, $category:expr, $message:expr, $($k:expr, $v:expr),+) => (
+        log!(target: $target, $crate::Level::Warn, $category, $message, $($k, $v),+)
+    );
+    (target: $target:expr, $category:expr, $message:expr) => {
+        log!(target: $target, $crate::Level::Warn, $category, $message, None)
+    };
     ($category:expr, $message:expr, $($k:expr, $v:expr),+) => (
         log!($crate::Level::Warn, $category, $message, $($k, $v),+)
     );
@@ -47,6 +72,12 @@ macro_rules! warn {
 /// info log
 #[macro_export(local_inner_macros)]
 macro_rules! info {
+    (target: $target:expr, $category:expr, $message:expr, $($k:expr, $v:expr),+) => (
+        log!(target: $target, $crate::Level::Info, $category, $message, $($k, $v),+)
+    );
+    (target: $target:expr, $category:expr, $message:expr) => {
+        log!(target: $target, $crate::Level::Info, $category, $message, None)
+    };
     ($category:expr, $message:expr, $($k:expr, $v:expr),+) => (
         log!($crate::Level::Info, $category, $message, $($k, $v),+)
     );
@@ -58,6 +89,12 @@ macro_rules! info {
 /// debug log
 #[macro_export(local_inner_macros)]
 macro_rules! debug {
+    (target: $target:expr, $category:expr, $message:expr, $($k:expr, $v:expr),+) => (
+        log!(target: $target, $crate::Level::Debug, $category, $message, $($k, $v),+)
+    );
+    (target: $target:expr, $category:expr, $message:expr) => {
+        log!(target: $target, $crate::Level::Debug, $category, $message, None)
+    };
     ($category:expr, $message:expr, $($k:expr, $v:expr),+) => (
         log!($crate::Level::Debug, $category, $message, $($k, $v),+)
     );
@@ -69,6 +106,12 @@ macro_rules! debug {
 /// trace log
 #[macro_export(local_inner_macros)]
 macro_rules! trace {
+    (target: $target:expr, $category:expr, $message:expr, $($k:expr, $v:expr),+) => (
+        log!(target: $target, $crate::Level::Trace, $category, $message, $($k, $v),+)
+    );
+    (target: $target:expr, $category:expr, $message:expr) => {
+        log!(target: $target, $crate::Level::Trace, $category, $message, None)
+    };
     ($category:expr, $message:expr, $($k:expr, $v:expr),+) => (
         log!($crate::Level::Trace, $category, $message, $($k, $v),+)
     );
@@ -77,13 +120,35 @@ macro_rules! trace {
     };
 }
 
+/// Collects the build information of the calling crate for [`crate::Builder::with_build_info`].
+///
+/// The KV has the `name` and `version` of the package, the `target` triple, and
+/// `git_hash` and `profile` when a build script sets the `GIT_HASH` and `PROFILE` envs, e.g.
+/// `println!("cargo:rustc-env=PROFILE={}", std::env::var("PROFILE").unwrap())`.
+///
+/// ```
+/// let info = uplog::build_info!();
+/// assert_eq!(info["name"], uplog::Value::from(env!("CARGO_PKG_NAME")));
+/// ```
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::__build_info(
+            ::std::env!("CARGO_PKG_NAME"),
+            ::std::env!("CARGO_PKG_VERSION"),
+            ::std::option_env!("GIT_HASH"),
+            ::std::option_env!("PROFILE"),
+        )
+    };
+}
+
 /// build record macro for development
 #[macro_export(local_inner_macros)]
 macro_rules! devlog {
-    ($level:expr, $category:expr, $message:expr, $kv:expr) => {
+    (target: $target:expr, $level:expr, $category:expr, $message:expr, $kv:expr) => {
         $crate::__build_record(
             $level,
-            __log_module_path!(),
+            $target,
             $category,
             $message,
             __log_module_path!(),
@@ -92,6 +157,16 @@ macro_rules! devlog {
             $kv,
         )
     };
+    (target: $target:expr, $level:expr, $category:expr, $message:expr) => {
+        devlog!(target: $target, $level, $category, $message, None)
+    };
+    (target: $target:expr, $level:expr, $category:expr, $message:expr, $($k:expr, $v:expr),+) => ({
+        let kv = kv_zip!($($k, $v),*);
+        devlog!(target: $target, $level, $category, $message, Some(kv))
+    });
+    ($level:expr, $category:expr, $message:expr, $kv:expr) => {
+        devlog!(target: __log_module_path!(), $level, $category, $message, $kv)
+    };
     ($level:expr, $category:expr, $message:expr) => {
         devlog!($level, $category, $message, None)
     };
@@ -0,0 +1,168 @@
+//! スレッドをまたいだレコードの順序
+//!
+//! 複数のスレッドが同時に書くと、バッファー内の順序はロックを取った順になり
+//! `elapsed`の順とわずかにずれる。[`Ordering::Timestamp`]ではバッファーに
+//! 長さと`elapsed`を前置きして書き、送信スレッドが周期ごとにCBORをデコードせずに並べ替える
+use std::time::Duration;
+
+/// Order of the records sent in one swap period, see [`crate::Builder::ordering`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Ordering {
+    /// in the order the records entered the buffer
+    #[default]
+    Arrival,
+    /// sorted by elapsed within each swap period
+    Timestamp,
+}
+
+impl Ordering {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Arrival => "arrival",
+            Self::Timestamp => "timestamp",
+        }
+    }
+}
+
+/// 前置きの長さ。CBORの長さ(u32)と`elapsed`のナノ秒(u64)をビッグエンディアンで書く
+pub(crate) const PREFIX_LEN: usize = 12;
+
+/// エンコードを始める前に前置きの場所を空けておく
+pub(crate) fn reserve_prefix(buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&[0; PREFIX_LEN]);
+}
+
+/// 後に続くCBORの長さと`elapsed`を前置きに書く
+pub(crate) fn write_prefix(buf: &mut [u8], elapsed: Duration) {
+    let len = u32::try_from(buf.len() - PREFIX_LEN).unwrap_or(u32::MAX);
+    let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+    buf[..4].copy_from_slice(&len.to_be_bytes());
+    buf[4..PREFIX_LEN].copy_from_slice(&nanos.to_be_bytes());
+}
+
+/// 前置き付きのレコード
+struct Framed<'a> {
+    nanos: u64,
+    record: &'a [u8],
+}
+
+/// 先頭から読める前置き付きのレコード。途中で切れたものは含まない
+fn frames(buf: &[u8]) -> impl Iterator<Item = Framed<'_>> {
+    let mut rest = buf;
+    std::iter::from_fn(move || {
+        let prefix = rest.get(..PREFIX_LEN)?;
+        let len = u32::from_be_bytes(prefix[..4].try_into().ok()?) as usize;
+        let nanos = u64::from_be_bytes(prefix[4..].try_into().ok()?);
+        let record = rest.get(PREFIX_LEN..PREFIX_LEN + len)?;
+        rest = &rest[PREFIX_LEN + len..];
+        Some(Framed { nanos, record })
+    })
+}
+
+/// バッファーから読み出し、送る形式のCBOR Sequenceを`out`に追加する。読み済みにする長さを返す
+///
+/// `limit`を指定した場合は追加するバイト数がそれを超えない範囲のレコードの区切りまで読む。
+/// 先頭のレコードがlimitより大きい場合はそのレコードだけを読む
+pub(crate) fn take(
+    ordering: Ordering,
+    unread: &[u8],
+    limit: Option<usize>,
+    out: &mut Vec<u8>,
+) -> usize {
+    match ordering {
+        Ordering::Arrival => {
+            let len = match limit {
+                Some(0) => 0,
+                Some(x) => crate::client::record_boundary(unread, x),
+                None => unread.len(),
+            };
+            out.extend_from_slice(&unread[..len]);
+            len
+        }
+        Ordering::Timestamp => {
+            let mut consumed = 0;
+            let mut taken = 0;
+            let mut records = Vec::new();
+            for frame in frames(unread) {
+                if let Some(limit) = limit {
+                    if taken + frame.record.len() > limit && (limit == 0 || !records.is_empty()) {
+                        break;
+                    }
+                }
+                consumed += PREFIX_LEN + frame.record.len();
+                taken += frame.record.len();
+                records.push(frame);
+            }
+            // 同じ時刻なら書かれた順を保つ
+            records.sort_by_key(|x| x.nanos);
+            out.reserve(taken);
+            for frame in records {
+                out.extend_from_slice(frame.record);
+            }
+            consumed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{reserve_prefix, take, write_prefix, Ordering, PREFIX_LEN};
+
+    fn framed(records: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (millis, record) in records {
+            let mut frame = Vec::new();
+            reserve_prefix(&mut frame);
+            frame.extend_from_slice(record);
+            write_prefix(&mut frame, Duration::from_millis(*millis));
+            buf.extend_from_slice(&frame);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_take_sorted() {
+        let buf = framed(&[(3, b"c"), (1, b"a1"), (2, b"b"), (1, b"a2")]);
+        let mut out = Vec::new();
+        assert_eq!(take(Ordering::Timestamp, &buf, None, &mut out), buf.len());
+        assert_eq!(out, b"a1a2bc");
+
+        // 途中で切れたレコードは残す
+        let mut out = Vec::new();
+        let len = take(Ordering::Timestamp, &buf[..buf.len() - 1], None, &mut out);
+        assert_eq!(len, buf.len() - PREFIX_LEN - 2);
+        assert_eq!(out, b"a1bc");
+    }
+
+    #[test]
+    fn test_take_limit() {
+        let buf = framed(&[(3, b"ccc"), (1, b"aaa"), (2, b"bbb")]);
+        let mut out = Vec::new();
+        let len = take(Ordering::Timestamp, &buf, Some(7), &mut out);
+        assert_eq!(len, 2 * (PREFIX_LEN + 3));
+        assert_eq!(out, b"aaaccc");
+
+        // 先頭が大きくても1件は読む
+        let mut out = Vec::new();
+        let len = take(Ordering::Timestamp, &buf, Some(1), &mut out);
+        assert_eq!(len, PREFIX_LEN + 3);
+        assert_eq!(out, b"ccc");
+
+        let mut out = Vec::new();
+        assert_eq!(take(Ordering::Timestamp, &buf, Some(0), &mut out), 0);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_take_arrival() {
+        let mut buf = Vec::new();
+        for i in 0..3_u32 {
+            serde_cbor::to_writer(&mut buf, &i).unwrap();
+        }
+        let mut out = Vec::new();
+        assert_eq!(take(Ordering::Arrival, &buf, None, &mut out), buf.len());
+        assert_eq!(out, buf);
+    }
+}
@@ -0,0 +1,264 @@
+//! シリアライズする前に大きすぎるレコードを除く
+//!
+//! バッファーに収まらないレコードはシリアライズした後に破棄されるが、
+//! 理由がわかりにくいので見積もった大きさで先に判定して数える
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{KVBorrow, Level, MetadataBorrow, RecordBorrow, ValueBorrow};
+
+// 0の場合は制限しない
+static MAX_RECORD_BYTES: AtomicUsize = AtomicUsize::new(0);
+static SURROGATE: AtomicBool = AtomicBool::new(false);
+
+/// 代わりに書き込むレコードのメッセージ
+pub(crate) const SURROGATE_MESSAGE: &str = "oversized record rejected";
+
+/// CBORの項目の先頭の長さ
+fn header(n: u64) -> usize {
+    match n {
+        0..=23 => 1,
+        24..=0xff => 2,
+        0x100..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+fn text(s: &str) -> usize {
+    header(s.len() as u64) + s.len()
+}
+
+fn opt_text(s: Option<&str>) -> usize {
+    s.map_or(1, text)
+}
+
+fn int(n: i64) -> usize {
+    match n {
+        0.. => header(n as u64),
+        _ => header(!n as u64),
+    }
+}
+
+fn value(v: &ValueBorrow) -> usize {
+    match v {
+        ValueBorrow::Null | ValueBorrow::Bool(_) => 1,
+        ValueBorrow::I8(x) => int(*x as i64),
+        ValueBorrow::I16(x) => int(*x as i64),
+        ValueBorrow::I32(x) => int(*x as i64),
+        ValueBorrow::I64(x) => int(*x),
+        ValueBorrow::U8(x) => header(*x as u64),
+        ValueBorrow::U16(x) => header(*x as u64),
+        ValueBorrow::U32(x) => header(*x as u64),
+        ValueBorrow::U64(x) => header(*x),
+        // 精度を落とさずに短くできる場合もあるので長い方で見積もる
+        ValueBorrow::F32(_) => 5,
+        ValueBorrow::F64(_) => 9,
+        ValueBorrow::Text(x) => text(x),
+        ValueBorrow::Bytes(x) => header(x.len() as u64) + x.len(),
+        ValueBorrow::Array(x) => header(x.len() as u64) + x.iter().map(value).sum::<usize>(),
+        ValueBorrow::Map(x) => kv(x),
+    }
+}
+
+fn kv(kv: &KVBorrow) -> usize {
+    header(kv.len() as u64) + kv.iter().map(|(k, v)| text(k) + value(v)).sum::<usize>()
+}
+
+fn level(level: Level) -> usize {
+    text(match level {
+        Level::Trace => "Trace",
+        Level::Debug => "Debug",
+        Level::Info => "Info",
+        Level::Warn => "Warn",
+        Level::Error => "Error",
+    })
+}
+
+/// Estimates the encoded size of the record without serializing it.
+///
+/// The result matches the CBOR written to the buffer except for floats,
+/// which are counted at their full width.
+pub fn estimate_record_size(record: &RecordBorrow) -> usize {
+    let elapsed = record.elapsed;
+    let metadata =
+        header(2) + text("level") + level(record.level()) + text("target") + text(record.target());
+    let elapsed = match crate::precision::installed() {
+        Some(precision) => header(precision.to_units(elapsed)),
+        None => {
+            header(2)
+                + text("secs")
+                + header(elapsed.as_secs())
+                + text("nanos")
+                + header(elapsed.subsec_nanos() as u64)
+        }
+    };
+    header(8)
+        + text("metadata")
+        + metadata
+        + text("elapsed")
+        + elapsed
+        + text("category")
+        + text(record.category)
+        + text("module_path")
+        + opt_text(record.module_path())
+        + text("file")
+        + opt_text(record.file())
+        + text("line")
+        + record.line().map_or(1, |x| header(x as u64))
+        + text("message")
+        + text(record.message)
+        + text("kv")
+        + record.key_values().map_or(1, kv)
+}
+
+/// 上限を設定する。Noneの場合は制限しない
+pub(crate) fn install(max_record_bytes: Option<usize>, surrogate: bool) {
+    MAX_RECORD_BYTES.store(max_record_bytes.unwrap_or(0), Ordering::Release);
+    SURROGATE.store(surrogate, Ordering::Release);
+}
+
+/// 設定された上限
+pub(crate) fn max_record_bytes() -> Option<usize> {
+    Some(MAX_RECORD_BYTES.load(Ordering::Acquire)).filter(|x| *x > 0)
+}
+
+/// 上限を超える場合は数えて、設定されていれば代わりのレコードを`f`に渡す
+///
+/// 書き込んでよい場合はtrueを返す
+pub(crate) fn check<F: FnOnce(&RecordBorrow)>(record: &RecordBorrow, f: F) -> bool {
+    let max = MAX_RECORD_BYTES.load(Ordering::Acquire);
+    if max == 0 {
+        return true;
+    }
+    let size = estimate_record_size(record);
+    if size <= max {
+        return true;
+    }
+    crate::stats::record_rejected_oversize();
+    if SURROGATE.load(Ordering::Acquire) {
+        let mut kv = KVBorrow::new();
+        kv.insert("estimated_bytes", ValueBorrow::U64(size as u64));
+        kv.insert("max_record_bytes", ValueBorrow::U64(max as u64));
+        f(&RecordBorrow {
+            metadata: MetadataBorrow::new(record.level(), record.target()),
+            elapsed: record.elapsed,
+            category: record.category,
+            module_path: record.module_path(),
+            file: record.file(),
+            line: record.line(),
+            message: SURROGATE_MESSAGE,
+            kv: Some(kv),
+        });
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::{check, estimate_record_size, install, SURROGATE_MESSAGE};
+    use crate::{KVBorrow, Level, MetadataBorrow, RecordBorrow, ValueBorrow};
+
+    fn record<'a>(message: &'a str, kv: Option<KVBorrow<'a>>) -> RecordBorrow<'a> {
+        RecordBorrow {
+            metadata: MetadataBorrow::new(Level::Info, "oversize"),
+            elapsed: Duration::new(12345, 678_000),
+            category: "app.camera",
+            module_path: Some(module_path!()),
+            file: Some(file!()),
+            line: Some(line!()),
+            message,
+            kv,
+        }
+    }
+
+    fn assert_estimate(record: &RecordBorrow) {
+        let actual = serde_cbor::to_vec(record).unwrap().len();
+        let estimated = estimate_record_size(record);
+        // 浮動小数点数だけ長めに見積もる
+        assert!(
+            estimated >= actual && estimated <= actual + 16,
+            "estimated {} actual {}",
+            estimated,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_estimate_record_size() {
+        assert_estimate(&record("", None));
+        assert_estimate(&record(&"m".repeat(300), None));
+
+        let blob = vec![0_u8; 70_000];
+        let text = "t".repeat(24);
+        let mut nested = BTreeMap::new();
+        nested.insert("inner", ValueBorrow::I64(-1_000_000));
+        let mut kv = KVBorrow::new();
+        kv.insert("u8", ValueBorrow::U8(200));
+        kv.insert("u64", ValueBorrow::U64(u64::MAX));
+        kv.insert("i8", ValueBorrow::I8(-100));
+        kv.insert("i32", ValueBorrow::I32(i32::MIN));
+        kv.insert("f32", ValueBorrow::F32(0.5));
+        kv.insert("f64", ValueBorrow::F64(1.0e300));
+        kv.insert("bool", ValueBorrow::Bool(true));
+        kv.insert("null", ValueBorrow::Null);
+        kv.insert("text", ValueBorrow::Text(&text));
+        kv.insert("bytes", ValueBorrow::Bytes(&blob));
+        kv.insert(
+            "array",
+            ValueBorrow::Array(vec![ValueBorrow::U16(1000), ValueBorrow::Text("a")]),
+        );
+        kv.insert("map", ValueBorrow::Map(nested));
+        assert_estimate(&record("msg", Some(kv)));
+
+        let mut r = record("msg", None);
+        r.module_path = None;
+        r.file = None;
+        r.line = None;
+        r.elapsed = Duration::ZERO;
+        assert_estimate(&r);
+        // 整数だけなら一致する
+        assert_eq!(
+            estimate_record_size(&r),
+            serde_cbor::to_vec(&r).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_reject_oversize() {
+        let blob = vec![0_u8; 4096];
+        let mut kv = KVBorrow::new();
+        kv.insert("data", ValueBorrow::Bytes(&blob));
+        let large = record("large", Some(kv));
+        let small = record("small", None);
+        let rejected = || crate::stats_snapshot().records_rejected_oversize;
+
+        // 設定しなければ制限しない
+        assert!(check(&large, |_| panic!("unexpected surrogate")));
+
+        install(Some(1024), false);
+        let before = rejected();
+        assert!(check(&small, |_| panic!("unexpected surrogate")));
+        assert!(!check(&large, |_| panic!("unexpected surrogate")));
+        assert!(rejected() > before);
+
+        // 代わりにメタデータと大きさだけのレコードを書く
+        install(Some(1024), true);
+        let mut surrogate = None;
+        assert!(!check(&large, |r| {
+            surrogate = Some(serde_cbor::to_vec(r).unwrap())
+        }));
+        let surrogate: crate::Record = serde_cbor::from_slice(&surrogate.unwrap()).unwrap();
+        assert_eq!(surrogate.message, SURROGATE_MESSAGE);
+        assert_eq!(surrogate.category, "app.camera");
+        assert_eq!(surrogate.line, large.line);
+        let kv = surrogate.kv.unwrap();
+        assert_eq!(
+            kv["estimated_bytes"],
+            crate::Value::U64(estimate_record_size(&large) as u64)
+        );
+        assert_eq!(kv["max_record_bytes"], crate::Value::U64(1024));
+        install(None, false);
+    }
+}
@@ -0,0 +1,160 @@
+//! panicをErrorのレコードとして送る
+//!
+//! panic hookでレコードを書き、送信スレッドを止めて送り切ってから元のhookを呼ぶ。
+//! 送信中のpanicなどでhookに再び入った場合は記録せずに元のhookだけを呼ぶ
+use std::{
+    backtrace::{Backtrace, BacktraceStatus},
+    cell::Cell,
+    panic::{self, Location, PanicHookInfo},
+    sync::Once,
+    time::Duration,
+};
+
+use crate::{KVBorrow, Level, MetadataBorrow, RecordBorrow, ValueBorrow};
+
+/// category of the records written by [`capture_panics`]
+pub const PANIC_CATEGORY: &str = "uplog.panic";
+
+static INSTALL: Once = Once::new();
+
+thread_local! {
+    /// このスレッドでhookを実行中か
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Records every panic as an Error record in the category [`PANIC_CATEGORY`].
+///
+/// The record has the panic message, the thread name, the location and, when `RUST_BACKTRACE`
+/// is enabled, the backtrace. It is written regardless of the level and category filters,
+/// then the client is flushed like [`crate::flush`] before the previous hook runs.
+/// Records logged after a panic are not sent, so use this for panics that end the application.
+///
+/// Calling this more than once installs the hook only once.
+pub fn capture_panics() {
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !IN_HOOK.with(|x| x.replace(true)) {
+                let backtrace = Backtrace::capture();
+                emit(
+                    info,
+                    crate::session::elapsed(),
+                    &backtrace,
+                    crate::logger::submit_direct,
+                );
+                crate::flush();
+                IN_HOOK.with(|x| x.set(false));
+            }
+            previous(info);
+        }));
+    });
+}
+
+fn payload_message<'a>(info: &'a PanicHookInfo) -> &'a str {
+    let payload = info.payload();
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+/// panicのレコードを作る
+fn emit<F: FnOnce(&RecordBorrow)>(
+    info: &PanicHookInfo,
+    elapsed: Duration,
+    backtrace: &Backtrace,
+    f: F,
+) {
+    let thread = std::thread::current();
+    let thread_name = thread
+        .name()
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:?}", thread.id()));
+    let location = info.location().map(Location::to_string);
+    let backtrace = match backtrace.status() {
+        BacktraceStatus::Captured => Some(backtrace.to_string()),
+        _ => None,
+    };
+    let mut kv = KVBorrow::new();
+    kv.insert("thread", ValueBorrow::Text(&thread_name));
+    if let Some(location) = location.as_deref() {
+        kv.insert("location", ValueBorrow::Text(location));
+    }
+    if let Some(backtrace) = backtrace.as_deref() {
+        kv.insert("backtrace", ValueBorrow::Text(backtrace));
+    }
+    let record = RecordBorrow {
+        metadata: MetadataBorrow::new(Level::Error, module_path!()),
+        elapsed,
+        category: PANIC_CATEGORY,
+        module_path: None,
+        file: info.location().map(Location::file),
+        line: info.location().map(Location::line),
+        message: payload_message(info),
+        kv: Some(kv),
+    };
+    f(&record)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        backtrace::Backtrace,
+        panic,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{emit, PANIC_CATEGORY};
+    use crate::{Level, Record, Value};
+
+    #[test]
+    fn test_panic_record() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let hook_records = records.clone();
+        // このテストの間だけhookを差し替える
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let captured = Backtrace::force_capture();
+            for backtrace in [&captured, &Backtrace::disabled()] {
+                emit(info, Duration::from_secs(1), backtrace, |r| {
+                    let buf = serde_cbor::to_vec(r).unwrap();
+                    let record: Record = serde_cbor::from_slice(&buf).unwrap();
+                    hook_records.lock().unwrap().push(record);
+                });
+            }
+        }));
+        let line = line!() + 3;
+        let result = std::thread::Builder::new()
+            .name("worker".to_string())
+            .spawn(|| panic!("broken {}", 42))
+            .unwrap()
+            .join();
+        panic::set_hook(previous);
+        assert!(result.is_err());
+
+        // 並行する他のテストのpanicは除く
+        let records = records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|x| x.message == "broken 42")
+            .cloned()
+            .collect::<Vec<_>>();
+        let record = &records[0];
+        assert_eq!(record.category, PANIC_CATEGORY);
+        assert_eq!(record.level(), Level::Error);
+        assert_eq!(record.message, "broken 42");
+        assert_eq!(record.file.as_deref(), Some(file!()));
+        assert_eq!(record.line, Some(line));
+        let kv = record.kv.as_ref().unwrap();
+        assert_eq!(kv["thread"], Value::Text("worker".to_string()));
+        assert!(
+            matches!(&kv["location"], Value::Text(x) if x.starts_with(&format!("{}:{}:", file!(), line)))
+        );
+        assert!(matches!(&kv["backtrace"], Value::Text(x) if !x.is_empty()));
+        // バックトレースを取らない設定では付けない
+        assert!(!records[1].kv.as_ref().unwrap().contains_key("backtrace"));
+    }
+}
@@ -0,0 +1,17 @@
+//! OSごとの差異を吸収する
+
+/// 送信スレッドの優先度を下げる
+///
+/// linuxのsetpriorityは呼び出したスレッドのみに効く。その他のOSではプロセス全体が
+/// 対象になるので何もしない
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn lower_thread_priority() {
+    const NICE: libc::c_int = 10;
+    let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, NICE) };
+    if ret != 0 {
+        log::debug!("failed to set priority {}", std::io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn lower_thread_priority() {}
@@ -0,0 +1,331 @@
+//! `elapsed`を単位を決めた整数で送る
+//!
+//! `Duration`はsecsとnanosのmapになり20バイト前後を使う。
+//! [`crate::Builder::time_precision`]を設定すると1つの整数で書く。
+//! 単位はハンドシェイクの[`crate::protocol::TIME_PRECISION_HEADER`]で伝え、
+//! サーバーが同じ値を返さなかった場合は送信前に`Duration`の形式に戻す。
+//! 整数をデコードする場合は[`decode_with`]で単位を与える
+use std::{
+    cell::Cell,
+    fmt::{self, Display},
+    io,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+    time::Duration,
+};
+
+use serde::{de, Deserializer, Serialize, Serializer};
+
+use crate::Record;
+
+/// Unit of `elapsed` sent as a single integer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Precision {
+    Nanos,
+    Micros,
+    Millis,
+}
+
+impl Precision {
+    /// Value of [`crate::protocol::TIME_PRECISION_HEADER`].
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Nanos => "ns",
+            Self::Micros => "us",
+            Self::Millis => "ms",
+        }
+    }
+
+    /// 切り捨てる。u64に収まらない場合は最大値にする
+    pub fn to_units(self, d: Duration) -> u64 {
+        let units = match self {
+            Self::Nanos => d.as_nanos(),
+            Self::Micros => d.as_micros(),
+            Self::Millis => d.as_millis(),
+        };
+        u64::try_from(units).unwrap_or(u64::MAX)
+    }
+
+    pub fn from_units(self, units: u64) -> Duration {
+        match self {
+            Self::Nanos => Duration::from_nanos(units),
+            Self::Micros => Duration::from_micros(units),
+            Self::Millis => Duration::from_millis(units),
+        }
+    }
+
+    fn to_u8(precision: Option<Self>) -> u8 {
+        match precision {
+            None => 0,
+            Some(Self::Nanos) => 1,
+            Some(Self::Micros) => 2,
+            Some(Self::Millis) => 3,
+        }
+    }
+
+    fn from_u8(x: u8) -> Option<Self> {
+        match x {
+            1 => Some(Self::Nanos),
+            2 => Some(Self::Micros),
+            3 => Some(Self::Millis),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "ns" => Ok(Self::Nanos),
+            "us" => Ok(Self::Micros),
+            "ms" => Ok(Self::Millis),
+            _ => Err(format!(
+                "unknown time precision {}, expected ns, us or ms",
+                s
+            )),
+        }
+    }
+}
+
+// 0の場合はDurationの形式で書く
+static WIRE_PRECISION: AtomicU8 = AtomicU8::new(0);
+
+/// 書き込むレコードの単位を設定する
+pub(crate) fn install(precision: Option<Precision>) {
+    WIRE_PRECISION.store(Precision::to_u8(precision), Ordering::Release);
+}
+
+/// 書き込むレコードの単位
+pub(crate) fn installed() -> Option<Precision> {
+    Precision::from_u8(WIRE_PRECISION.load(Ordering::Acquire))
+}
+
+/// [`crate::RecordBorrow`]の`elapsed`を書く
+pub(crate) fn serialize_elapsed<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    match installed() {
+        Some(precision) => s.serialize_u64(precision.to_units(*d)),
+        None => d.serialize(s),
+    }
+}
+
+thread_local! {
+    static DECODE_PRECISION: Cell<Option<Precision>> = const { Cell::new(None) };
+}
+
+/// 抜けるときに前の単位に戻す
+struct DecodeGuard(Option<Precision>);
+
+impl Drop for DecodeGuard {
+    fn drop(&mut self) {
+        DECODE_PRECISION.with(|x| x.set(self.0));
+    }
+}
+
+/// Runs `f` decoding integer `elapsed` values of [`Record`] in `precision`.
+///
+/// Records with `elapsed` as `Duration` decode regardless of the precision.
+/// Without a precision, an integer `elapsed` is an error.
+pub fn decode_with<T, F: FnOnce() -> T>(precision: Option<Precision>, f: F) -> T {
+    let _guard = DecodeGuard(DECODE_PRECISION.with(|x| x.replace(precision)));
+    f()
+}
+
+/// [`Record`]の`elapsed`を読む。整数の場合は[`decode_with`]の単位を使う
+pub(crate) fn deserialize_elapsed<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    struct ElapsedVisitor;
+
+    impl<'de> de::Visitor<'de> for ElapsedVisitor {
+        type Value = Duration;
+
+        fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt.write_str("a Duration or an integer in the negotiated precision")
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+            match DECODE_PRECISION.with(|x| x.get()) {
+                Some(precision) => Ok(precision.from_units(v)),
+                None => Err(E::custom(
+                    "elapsed is an integer but no time precision was negotiated",
+                )),
+            }
+        }
+
+        fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut secs = None;
+            let mut nanos = None;
+            while let Some(key) = map.next_key::<String>()? {
+                match key.as_str() {
+                    "secs" => secs = Some(map.next_value::<u64>()?),
+                    "nanos" => nanos = Some(map.next_value::<u32>()?),
+                    _ => {
+                        map.next_value::<de::IgnoredAny>()?;
+                    }
+                }
+            }
+            let secs = secs.ok_or_else(|| de::Error::missing_field("secs"))?;
+            let nanos = nanos.ok_or_else(|| de::Error::missing_field("nanos"))?;
+            duration(secs, nanos)
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let secs = seq
+                .next_element::<u64>()?
+                .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+            let nanos = seq
+                .next_element::<u32>()?
+                .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+            duration(secs, nanos)
+        }
+    }
+
+    d.deserialize_any(ElapsedVisitor)
+}
+
+/// Durationと同じく桁あふれはエラーにする
+fn duration<E: de::Error>(secs: u64, nanos: u32) -> Result<Duration, E> {
+    secs.checked_add((nanos / 1_000_000_000) as u64)
+        .map(|_| Duration::new(secs, nanos))
+        .ok_or_else(|| E::custom("overflow deserializing Duration"))
+}
+
+/// 整数の`elapsed`を`Duration`の形式に書き直す。単位を交渉できなかった接続で使う
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+pub(crate) fn normalize(buf: &[u8], precision: Precision) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(buf.len() + buf.len() / 4);
+    decode_with(Some(precision), || {
+        for record in serde_cbor::Deserializer::from_slice(buf).into_iter::<Record>() {
+            let record = record.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            serde_cbor::to_writer(&mut out, &record)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(out)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_cbor::Value;
+
+    use super::{decode_with, normalize, Precision};
+    use crate::{Level, Record};
+
+    /// mapの順番が変わるので比べる場合はこちらで書く
+    fn plain(record: &Record) -> Vec<u8> {
+        serde_cbor::to_vec(&serde_cbor::value::to_value(record).unwrap()).unwrap()
+    }
+
+    /// `elapsed`を整数で書いたレコード
+    fn compact(record: &Record, precision: Precision) -> Vec<u8> {
+        let mut value: Value = serde_cbor::value::to_value(record).unwrap();
+        if let Value::Map(map) = &mut value {
+            map.insert(
+                Value::Text("elapsed".to_string()),
+                Value::Integer(precision.to_units(record.elapsed) as i128),
+            );
+        }
+        serde_cbor::to_vec(&value).unwrap()
+    }
+
+    fn record(elapsed: Duration) -> Record {
+        crate::session_init();
+        let mut r = devlog!(Level::Info, "app", "msg", "i", 1_u32);
+        r.elapsed = elapsed;
+        r
+    }
+
+    #[test]
+    fn test_precision_units() {
+        let d = Duration::new(3600, 123_456_789);
+        assert_eq!(Precision::Nanos.to_units(d), 3_600_123_456_789);
+        assert_eq!(Precision::Micros.to_units(d), 3_600_123_456);
+        assert_eq!(Precision::Millis.to_units(d), 3_600_123);
+        assert_eq!(
+            Precision::Micros.from_units(3_600_123_456),
+            Duration::new(3600, 123_456_000)
+        );
+        assert_eq!(Precision::Nanos.to_units(Duration::MAX), u64::MAX);
+        for p in [Precision::Nanos, Precision::Micros, Precision::Millis] {
+            assert_eq!(p.as_str().parse(), Ok(p));
+        }
+        assert!("s".parse::<Precision>().is_err());
+    }
+
+    #[test]
+    fn test_compact_elapsed_size() {
+        // 1時間経過したセッションのレコード
+        let r = record(Duration::new(3600, 123_456_789));
+        let plain_len = plain(&r).len();
+        let elapsed = serde_cbor::to_vec(&r.elapsed).unwrap().len();
+        assert_eq!(elapsed, 20);
+        for (precision, bytes) in [
+            (Precision::Nanos, 9),
+            (Precision::Micros, 5),
+            (Precision::Millis, 5),
+        ] {
+            let buf = compact(&r, precision);
+            assert_eq!(buf.len(), plain_len - elapsed + bytes, "{}", precision);
+            let decoded: Record =
+                decode_with(Some(precision), || serde_cbor::from_slice(&buf)).unwrap();
+            assert_eq!(
+                decoded.elapsed,
+                precision.from_units(precision.to_units(r.elapsed))
+            );
+            assert_eq!(decoded.message, r.message);
+        }
+        // 開始直後は1バイトで済む
+        let r = record(Duration::from_micros(20));
+        assert_eq!(
+            compact(&r, Precision::Micros).len(),
+            plain(&r).len() - serde_cbor::to_vec(&r.elapsed).unwrap().len() + 1
+        );
+    }
+
+    #[test]
+    fn test_decode_old_records() {
+        let r = record(Duration::new(12, 345));
+        let plain = serde_cbor::to_vec(&r).unwrap();
+        // 単位に関わらずDurationの形式は読める
+        for precision in [None, Some(Precision::Millis)] {
+            let decoded: Record =
+                decode_with(precision, || serde_cbor::from_slice(&plain)).unwrap();
+            assert_eq!(decoded, r);
+        }
+        // 単位がわからない整数は読めない
+        let buf = compact(&r, Precision::Micros);
+        assert!(serde_cbor::from_slice::<Record>(&buf).is_err());
+        // 抜けた後は前の単位に戻す
+        decode_with(Some(Precision::Micros), || {
+            decode_with(None, || {});
+            assert!(serde_cbor::from_slice::<Record>(&buf).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_normalize() {
+        let records = [
+            record(Duration::from_millis(1500)),
+            record(Duration::from_millis(2500)),
+        ];
+        let buf = records
+            .iter()
+            .flat_map(|x| compact(x, Precision::Millis))
+            .collect::<Vec<_>>();
+        let normalized = normalize(&buf, Precision::Millis).unwrap();
+        let expect = records
+            .iter()
+            .flat_map(|x| serde_cbor::to_vec(x).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(normalized, expect);
+        assert!(normalize(b"\xff", Precision::Millis).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! 用途ごとの設定の組み合わせ
+use std::{fmt::Display, time::Duration};
+
+use crate::buffer::Growth;
+
+/// A coherent set of settings for a common deployment, applied by [`crate::Builder::preset`].
+///
+/// A preset sets the buffer size, swap duration, overflow growth, deflate, nice mode and the
+/// sender watchdog. Settings given to their own setters win over the preset regardless of the
+/// order of the calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Interactive debugging where records should show up on the server within tens of
+    /// milliseconds. Small buffer swapped every 10 ms, no compression, records that do not fit
+    /// are dropped rather than delaying the next swap.
+    LowLatency,
+    /// Bulk logging from busy processes. Large deflate compressed messages every 100 ms and a
+    /// buffer that grows under bursts instead of dropping.
+    HighThroughput,
+    /// Embedded targets with little memory and CPU. Small fixed buffer, no compression, and the
+    /// nice mode spreading sends over swap cycles at a lower thread priority.
+    ConstrainedDevice,
+    /// Tests and audits where losing a record is worse than using memory. The buffer grows to
+    /// 64 MiB before dropping, and the sender gets 5 seconds per send before it is restarted.
+    Lossless,
+}
+
+/// プリセットが決める値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PresetValues {
+    pub(crate) buffer_size: usize,
+    pub(crate) swap_duration: Duration,
+    pub(crate) growth: Growth,
+    pub(crate) deflate: bool,
+    pub(crate) nice_mode: bool,
+    pub(crate) watchdog_ticks: u32,
+}
+
+impl Preset {
+    pub(crate) fn values(self) -> PresetValues {
+        const KIB: usize = 1024;
+        const MIB: usize = 1024 * KIB;
+        match self {
+            Self::LowLatency => PresetValues {
+                buffer_size: 256 * KIB,
+                swap_duration: Duration::from_millis(10),
+                growth: Growth::Fixed,
+                deflate: false,
+                nice_mode: false,
+                watchdog_ticks: 100,
+            },
+            Self::HighThroughput => PresetValues {
+                buffer_size: 8 * MIB,
+                swap_duration: Duration::from_millis(100),
+                growth: Growth::Doubling { max: 32 * MIB },
+                deflate: true,
+                nice_mode: false,
+                watchdog_ticks: 50,
+            },
+            Self::ConstrainedDevice => PresetValues {
+                buffer_size: 16 * KIB,
+                swap_duration: Duration::from_secs(1),
+                growth: Growth::Fixed,
+                deflate: false,
+                nice_mode: true,
+                watchdog_ticks: 30,
+            },
+            Self::Lossless => PresetValues {
+                buffer_size: 2 * MIB,
+                swap_duration: Duration::from_millis(100),
+                growth: Growth::Doubling { max: 64 * MIB },
+                deflate: false,
+                nice_mode: false,
+                watchdog_ticks: 50,
+            },
+        }
+    }
+
+    /// Name used by [`crate::Builder::describe`], e.g. `"low_latency"`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::LowLatency => "low_latency",
+            Self::HighThroughput => "high_throughput",
+            Self::ConstrainedDevice => "constrained_device",
+            Self::Lossless => "lossless",
+        }
+    }
+}
+
+impl Display for Preset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    io::{self, Read, Write},
+};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{Deserialize, Serialize};
+
+use crate::Level;
+
+/// 接続時にクライアントのセッションIDを送るクエリパラメーター
+pub const SESSION_QUERY: &str = "session";
+
+/// ハンドシェイクで`elapsed`の単位を伝えるヘッダー。値は[`crate::precision::Precision::as_str`]
+///
+/// 受け付けたサーバーは同じ値を返す
+pub const TIME_PRECISION_HEADER: &str = "X-Uplog-Time-Precision";
+
+/// ハンドシェイクでクライアントの時刻を伝えるヘッダー。値はUNIX時刻(ミリ秒)
+///
+/// これを送ったクライアントにはサーバーが受け取ったメッセージごとに[`ServerMessage::Ack`]を返す
+pub const CLIENT_TIME_HEADER: &str = "X-Uplog-Client-Time";
+
+/// ハンドシェイクでビルド情報を伝えるヘッダー。値は[`crate::build_info!`]の文字列の値をフォームの形式で並べたもの
+///
+/// 例: `name=app&target=x86_64-unknown-linux-gnu&version=0.1.0`
+pub const BUILD_INFO_HEADER: &str = "X-Uplog-Build-Info";
+
+/// サーバーからクライアントへ送るメッセージ
+///
+/// クライアントからはRecordのCBOR Sequenceを送り、
+/// サーバーからは1メッセージにつき1つのServerMessageをCBORで送る
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ServerMessage {
+    /// クライアントから受け取ったデータを解釈できなかった
+    DecodeError(DecodeErrorReport),
+    /// 実行時の設定変更
+    Control(ControlCommand),
+    /// メッセージを受け取った
+    Ack(Ack),
+}
+
+/// 受け取ったメッセージへの応答
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ack {
+    /// この接続で受け取ったメッセージ数
+    pub received: u64,
+    /// 応答を送ったときのサーバーのUNIX時刻(ミリ秒)
+    pub server_time_ms: i64,
+}
+
+/// 出力レベルを変更するコマンド名
+pub const CMD_SET_LEVEL: &str = "set_level";
+
+/// 端末に残した履歴を別のセッションとして送らせるコマンド名
+pub const CMD_UPLOAD_HISTORY: &str = "upload_history";
+
+/// サーバーからクライアントへの設定変更
+///
+/// 新しいコマンドを古いクライアントが受け取っても解釈できるように
+/// コマンド名は文字列で持ち、知らないものは無視する
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlCommand {
+    pub cmd: String,
+    #[serde(default)]
+    pub level: Option<Level>,
+    /// カテゴリのパターン。ない場合は全体
+    #[serde(default)]
+    pub category: Option<String>,
+    /// 送らせる履歴の範囲の始まり。セッション開始からの秒数で、ない場合は最も古いものから
+    #[serde(default)]
+    pub from_elapsed: Option<f64>,
+    /// 送らせる履歴の範囲の終わり。ない場合は最新まで
+    #[serde(default)]
+    pub to_elapsed: Option<f64>,
+}
+
+impl ControlCommand {
+    pub fn set_level(level: Level, category: Option<String>) -> Self {
+        Self {
+            cmd: CMD_SET_LEVEL.to_string(),
+            level: Some(level),
+            category,
+            from_elapsed: None,
+            to_elapsed: None,
+        }
+    }
+
+    pub fn upload_history(from_elapsed: Option<f64>, to_elapsed: Option<f64>) -> Self {
+        Self {
+            cmd: CMD_UPLOAD_HISTORY.to_string(),
+            level: None,
+            category: None,
+            from_elapsed,
+            to_elapsed,
+        }
+    }
+}
+
+/// デコード失敗の報告
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DecodeErrorReport {
+    /// 連続して失敗したメッセージ数
+    pub count: u64,
+    /// 最後のエラー
+    pub last_error: String,
+    /// 最後に失敗したメッセージ内の位置
+    pub byte_offset: u64,
+}
+
+impl Display for DecodeErrorReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (count={}, offset={})",
+            self.last_error, self.count, self.byte_offset
+        )
+    }
+}
+
+/// WebSocketのサブプロトコルを交渉するヘッダー
+pub const SUBPROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+/// RecordのCBOR Sequence。サブプロトコルのない接続と同じ
+pub const SUBPROTOCOL_CBOR: &str = "uplog.cbor.v1";
+/// メッセージごとにdeflateで圧縮したRecordのCBOR Sequence
+pub const SUBPROTOCOL_CBOR_DEFLATE: &str = "uplog.cbor.deflate.v1";
+/// 接続ごとの文字列の表で繰り返しを省いたレコード。形式は[`crate::wire`]を参照
+pub const SUBPROTOCOL_CBOR_DICT: &str = "uplog.cbor.dict.v1";
+
+/// Encoding of the record messages sent from the client.
+///
+/// It is negotiated as a WebSocket subprotocol on connect.
+/// Messages from the server are always plain CBOR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Cbor,
+    CborDeflate,
+    /// the records are converted by [`crate::wire`] before and after this codec
+    CborDict,
+}
+
+impl Codec {
+    /// All codecs, most preferred first.
+    pub const ALL: [Codec; 3] = [Codec::CborDeflate, Codec::CborDict, Codec::Cbor];
+
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            Codec::Cbor => SUBPROTOCOL_CBOR,
+            Codec::CborDeflate => SUBPROTOCOL_CBOR_DEFLATE,
+            Codec::CborDict => SUBPROTOCOL_CBOR_DICT,
+        }
+    }
+
+    pub fn from_subprotocol(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|x| x.subprotocol() == name.trim())
+    }
+
+    /// Picks the first of `supported` listed in the comma separated `offered` header.
+    pub fn negotiate(offered: &str, supported: &[Codec]) -> Option<Self> {
+        let offered = offered
+            .split(',')
+            .filter_map(Self::from_subprotocol)
+            .collect::<Vec<_>>();
+        supported.iter().copied().find(|x| offered.contains(x))
+    }
+
+    /// Value of the subprotocol header to offer `codecs` in order.
+    pub fn offer(codecs: &[Codec]) -> String {
+        codecs
+            .iter()
+            .map(|x| x.subprotocol())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    pub fn encode(self, buf: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+        match self {
+            Codec::Cbor | Codec::CborDict => Ok(Cow::Borrowed(buf)),
+            Codec::CborDeflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+                encoder.write_all(buf)?;
+                Ok(Cow::Owned(encoder.finish()?))
+            }
+        }
+    }
+
+    /// 展開後が`limit`バイトを超える場合は展開せずにエラーにする
+    pub fn decode(self, buf: &[u8], limit: usize) -> io::Result<Cow<'_, [u8]>> {
+        match self {
+            Codec::Cbor | Codec::CborDict => Ok(Cow::Borrowed(buf)),
+            Codec::CborDeflate => {
+                let mut out = Vec::new();
+                DeflateDecoder::new(buf)
+                    .take(limit as u64 + 1)
+                    .read_to_end(&mut out)?;
+                if out.len() > limit {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("inflated message exceeds {} bytes", limit),
+                    ));
+                }
+                Ok(Cow::Owned(out))
+            }
+        }
+    }
+}
+
+/// Unix domain socketでの1メッセージの長さを表すヘッダーのバイト数
+///
+/// WebSocketのメッセージの代わりに、u32 big endianの長さに続けて
+/// WebSocketのBinaryメッセージと同じ内容を送る
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// メッセージの長さのヘッダー
+pub fn frame_header(len: usize) -> io::Result<[u8; FRAME_HEADER_LEN]> {
+    u32::try_from(len).map(u32::to_be_bytes).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes is too large", len),
+        )
+    })
+}
+
+/// ヘッダーが示すメッセージの長さ
+pub fn frame_len(header: [u8; FRAME_HEADER_LEN]) -> usize {
+    u32::from_be_bytes(header) as usize
+}
+
+/// Why a connection was closed, sent by either side in the WebSocket close frame.
+///
+/// The codes are in the range 4000-4999 kept for applications. A code from a newer peer
+/// that this version does not know is kept as [`CloseReason::Unknown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseReason {
+    /// the client flushed and exited
+    Flush,
+    /// the client logger was dropped without a flush
+    ClientDropped,
+    /// the client could not follow the server, e.g. an unexpected subprotocol
+    ProtocolError,
+    /// the server closed a connection that sent nothing for a while
+    IdleTimeout,
+    /// the connection sent more than the server accepts
+    Quota,
+    /// the server is stopping
+    Shutdown,
+    /// the server could not decode the messages
+    DecodeError,
+    /// the server refused the connection before its session started
+    Rejected,
+    /// a newer connection of the same client took over the session
+    TakenOver,
+    /// the server storage is almost full and does not accept more records
+    StorageFull,
+    /// an operator of the server closed the connection
+    Disconnected,
+    /// a code this version does not know
+    Unknown(u16),
+}
+
+impl CloseReason {
+    /// 送る側が使う理由。Unknownは受け取るだけ
+    pub const ALL: [CloseReason; 11] = [
+        Self::Flush,
+        Self::ClientDropped,
+        Self::ProtocolError,
+        Self::IdleTimeout,
+        Self::Quota,
+        Self::Shutdown,
+        Self::DecodeError,
+        Self::Rejected,
+        Self::TakenOver,
+        Self::StorageFull,
+        Self::Disconnected,
+    ];
+
+    /// Code of the close frame.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::Flush => 4000,
+            Self::ClientDropped => 4001,
+            Self::ProtocolError => 4002,
+            Self::IdleTimeout => 4100,
+            Self::Quota => 4101,
+            Self::Shutdown => 4102,
+            Self::DecodeError => 4103,
+            Self::Rejected => 4104,
+            Self::TakenOver => 4105,
+            Self::StorageFull => 4106,
+            Self::Disconnected => 4107,
+            Self::Unknown(x) => *x,
+        }
+    }
+
+    /// Reason of a received close frame. Codes outside the uplog range are also `Unknown`.
+    pub fn from_code(code: u16) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|x| x.code() == code)
+            .unwrap_or(Self::Unknown(code))
+    }
+
+    /// Name of the reason, also sent as the description of the close frame.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Flush => "flush",
+            Self::ClientDropped => "client_dropped",
+            Self::ProtocolError => "protocol_error",
+            Self::IdleTimeout => "idle_timeout",
+            Self::Quota => "quota",
+            Self::Shutdown => "shutdown",
+            Self::DecodeError => "decode_error",
+            Self::Rejected => "rejected",
+            Self::TakenOver => "taken_over",
+            Self::StorageFull => "storage_full",
+            Self::Disconnected => "disconnected",
+            Self::Unknown(_) => "unknown",
+        }
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unknown(code) => write!(f, "unknown({})", code),
+            x => f.write_str(x.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CloseReason, Codec};
+
+    #[test]
+    fn test_close_reason() {
+        for reason in CloseReason::ALL {
+            assert_eq!(CloseReason::from_code(reason.code()), reason);
+            assert!((4000..5000).contains(&reason.code()));
+        }
+        assert_eq!(CloseReason::from_code(4999), CloseReason::Unknown(4999));
+        assert_eq!(CloseReason::from_code(1000), CloseReason::Unknown(1000));
+        assert_eq!(CloseReason::from_code(4999).to_string(), "unknown(4999)");
+        assert_eq!(CloseReason::Quota.to_string(), "quota");
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let offered = "uplog.cbor.deflate.v1, uplog.cbor.v1";
+        assert_eq!(
+            Codec::negotiate(offered, &Codec::ALL),
+            Some(Codec::CborDeflate)
+        );
+        assert_eq!(
+            Codec::negotiate("uplog.cbor.dict.v1, uplog.cbor.v1", &Codec::ALL),
+            Some(Codec::CborDict)
+        );
+        // サーバーの優先順で選ぶ
+        assert_eq!(
+            Codec::negotiate(offered, &[Codec::Cbor, Codec::CborDeflate]),
+            Some(Codec::Cbor)
+        );
+        assert_eq!(Codec::negotiate("uplog.msgpack.v1", &Codec::ALL), None);
+        assert_eq!(
+            Codec::offer(&Codec::ALL),
+            "uplog.cbor.deflate.v1, uplog.cbor.dict.v1, uplog.cbor.v1"
+        );
+    }
+
+    #[test]
+    fn test_codec() {
+        let data = b"record ".repeat(100);
+        for codec in Codec::ALL {
+            let encoded = codec.encode(&data).unwrap();
+            let decoded = codec.decode(&encoded, data.len()).unwrap();
+            assert_eq!(decoded.as_ref(), &data[..]);
+        }
+        let encoded = Codec::CborDeflate.encode(&data).unwrap();
+        assert!(encoded.len() < data.len());
+        assert!(Codec::CborDeflate.decode(&encoded, data.len() - 1).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! マクロを使わずにレコードを作る
+use std::time::Duration;
+
+use serde::{
+    de::{IgnoredAny, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+use crate::{kv::KV, Level, Metadata, Record, Value};
+
+/// Builds a [`Record`] without the log macros, e.g. for importers, replay tools and fixtures.
+///
+/// Unset fields default to level [`Level::Info`], the category as the target, empty
+/// category and message, no source location, no key-values and the current session time.
+///
+/// ```
+/// use std::time::Duration;
+/// use uplog::{Level, Record};
+///
+/// let record = Record::builder()
+///     .level(Level::Warn)
+///     .category("net.rx")
+///     .message("retry")
+///     .elapsed(Duration::from_millis(1500))
+///     .kv("attempt", 3_u32)
+///     .build();
+/// assert_eq!(record.target(), "net.rx");
+/// assert_eq!(record.elapsed(), Duration::from_millis(1500));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordBuilder {
+    level: Level,
+    target: Option<String>,
+    category: String,
+    message: String,
+    module_path: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+    /// Noneなら作ったときのセッション経過時間
+    elapsed: Option<Duration>,
+    kv: Option<KV>,
+}
+
+impl Default for RecordBuilder {
+    fn default() -> Self {
+        Self {
+            level: Level::Info,
+            target: None,
+            category: String::new(),
+            message: String::new(),
+            module_path: None,
+            file: None,
+            line: None,
+            elapsed: None,
+            kv: None,
+        }
+    }
+}
+
+impl RecordBuilder {
+    pub fn level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Sets the target. Defaults to the category.
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn category(mut self, category: impl Into<String>) -> Self {
+        self.category = category.into();
+        self
+    }
+
+    pub fn message(mut self, message: impl Into<String>) -> Self {
+        self.message = message.into();
+        self
+    }
+
+    pub fn module_path(mut self, module_path: impl Into<String>) -> Self {
+        self.module_path = Some(module_path.into());
+        self
+    }
+
+    pub fn file(mut self, file: impl Into<String>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn line(mut self, line: u32) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    /// Sets the time since the start of the session.
+    pub fn elapsed(mut self, elapsed: Duration) -> Self {
+        self.elapsed = Some(elapsed);
+        self
+    }
+
+    /// Stamps the record with the session time at [`RecordBuilder::build`]. This is the default.
+    pub fn now(mut self) -> Self {
+        self.elapsed = None;
+        self
+    }
+
+    /// Adds a key-value entry, replacing an earlier one with the same key.
+    pub fn kv(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.kv
+            .get_or_insert_with(KV::new)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    pub fn build(self) -> Record {
+        let elapsed = self.elapsed.unwrap_or_else(|| {
+            crate::session_init();
+            crate::session::elapsed()
+        });
+        Record {
+            metadata: Metadata::new(
+                self.level,
+                self.target.unwrap_or_else(|| self.category.clone()),
+            ),
+            elapsed,
+            category: self.category,
+            module_path: self.module_path,
+            file: self.file,
+            line: self.line,
+            message: self.message,
+            kv: self.kv,
+        }
+    }
+}
+
+/// A [`Record`] decoded without the values of its key-values.
+///
+/// The key-values are skipped instead of being built, which is most of the cost of decoding a
+/// typical record. Keys starting with `_`, which annotate the record like
+/// [`ATTACHMENT_KEY`](crate::ATTACHMENT_KEY), are kept. Readers that show only the other
+/// fields can decode the key-values later from the same bytes when they are needed.
+///
+/// ```
+/// use uplog::{Record, RecordHead};
+///
+/// let record = Record::builder().message("hello").kv("id", 7_u32).build();
+/// let buf = serde_cbor::to_vec(&record).unwrap();
+/// let head = serde_cbor::from_slice::<RecordHead>(&buf).unwrap();
+/// assert_eq!(head.record.message(), "hello");
+/// assert!(head.record.key_values().unwrap().is_empty());
+/// assert!(head.kv_skipped);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordHead {
+    /// the record with only the key-values whose keys start with `_`
+    pub record: Record,
+    /// whether some key-values of the encoded record were skipped
+    pub kv_skipped: bool,
+}
+
+impl<'de> Deserialize<'de> for RecordHead {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        /// kv以外は[`Record`]と同じ
+        #[derive(Deserialize)]
+        struct Head {
+            metadata: Metadata,
+            #[serde(with = "crate::duration")]
+            elapsed: Duration,
+            category: String,
+            module_path: Option<String>,
+            file: Option<String>,
+            line: Option<u32>,
+            message: String,
+            #[serde(default)]
+            kv: Option<HeadKv>,
+        }
+        let head = Head::deserialize(deserializer)?;
+        let (kv, kv_skipped) = match head.kv {
+            Some(x) => (Some(x.kept), x.skipped),
+            None => (None, false),
+        };
+        Ok(Self {
+            record: Record {
+                metadata: head.metadata,
+                elapsed: head.elapsed,
+                category: head.category,
+                module_path: head.module_path,
+                file: head.file,
+                line: head.line,
+                message: head.message,
+                kv,
+            },
+            kv_skipped,
+        })
+    }
+}
+
+/// `_`で始まるキーだけを読み、ほかの値は飛ばしたkv
+struct HeadKv {
+    kept: KV,
+    skipped: bool,
+}
+
+impl<'de> Deserialize<'de> for HeadKv {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct HeadKvVisitor;
+
+        impl<'de> Visitor<'de> for HeadKvVisitor {
+            type Value = HeadKv;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map of key-values")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<HeadKv, A::Error> {
+                let mut kv = HeadKv {
+                    kept: KV::new(),
+                    skipped: false,
+                };
+                while let Some(key) = map.next_key::<String>()? {
+                    if key.starts_with('_') {
+                        let value = map.next_value::<Value>()?;
+                        kv.kept.insert(key, value);
+                    } else {
+                        map.next_value::<IgnoredAny>()?;
+                        kv.skipped = true;
+                    }
+                }
+                Ok(kv)
+            }
+        }
+
+        deserializer.deserialize_map(HeadKvVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{Level, Record, RecordHead, Value};
+
+    #[test]
+    fn test_record_builder_defaults() {
+        crate::session_init();
+        let before = crate::session::elapsed();
+        let record = Record::builder().category("app.db").build();
+        assert_eq!(record.level(), Level::Info);
+        assert_eq!(record.target(), "app.db");
+        assert_eq!(record.category(), "app.db");
+        assert_eq!(record.message(), "");
+        assert_eq!(record.module_path(), None);
+        assert_eq!(record.file(), None);
+        assert_eq!(record.line(), None);
+        assert_eq!(record.key_values(), None);
+        assert!(record.elapsed() >= before);
+
+        // 時刻を指定した後でも現在時刻に戻せる
+        let record = Record::builder()
+            .elapsed(Duration::from_secs(3600))
+            .now()
+            .build();
+        assert!(record.elapsed() < Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_record_builder() {
+        let record = Record::builder()
+            .level(Level::Error)
+            .target("importer")
+            .category("net")
+            .message("lost")
+            .module_path("app::net")
+            .file("src/net.rs")
+            .line(42)
+            .elapsed(Duration::from_millis(1500))
+            .kv("retries", 3_u32)
+            .kv("peer", "10.0.0.1")
+            .kv("retries", 4_u32)
+            .build();
+        assert_eq!(record.level(), Level::Error);
+        assert_eq!(record.target(), "importer");
+        assert_eq!(record.message(), "lost");
+        assert_eq!(record.module_path().map(String::as_str), Some("app::net"));
+        assert_eq!(record.file().map(String::as_str), Some("src/net.rs"));
+        assert_eq!(record.line(), Some(42));
+        assert_eq!(record.elapsed(), Duration::from_millis(1500));
+        let kv = record.key_values().unwrap();
+        assert_eq!(kv.len(), 2);
+        assert_eq!(kv["retries"], Value::U64(4));
+        assert_eq!(kv["peer"], Value::Text("10.0.0.1".into()));
+
+        // シリアライズしても同じレコードに戻る
+        let buf = serde_cbor::to_vec(&record).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Record>(&buf).unwrap(), record);
+    }
+
+    #[test]
+    fn test_record_head() {
+        let record = Record::builder()
+            .level(Level::Warn)
+            .category("net")
+            .message("lost")
+            .line(7)
+            .elapsed(Duration::from_millis(1500))
+            .kv("peer", "10.0.0.1")
+            .build();
+        let buf = serde_cbor::to_vec(&record).unwrap();
+        let head = serde_cbor::from_slice::<RecordHead>(&buf).unwrap();
+        assert!(head.kv_skipped);
+        assert_eq!(
+            head.record,
+            Record {
+                kv: Some(Default::default()),
+                ..record.clone()
+            }
+        );
+
+        // `_`で始まるキーは残す
+        let annotated = Record::builder()
+            .message("lost")
+            .kv("peer", "10.0.0.1")
+            .kv("_origin", "server")
+            .build();
+        let buf = serde_cbor::to_vec(&annotated).unwrap();
+        let head = serde_cbor::from_slice::<RecordHead>(&buf).unwrap();
+        assert!(head.kv_skipped);
+        let kv = head.record.key_values().unwrap();
+        assert_eq!(kv.keys().collect::<Vec<_>>(), ["_origin"]);
+        assert_eq!(kv["_origin"], Value::Text("server".to_string()));
+
+        let record = Record { kv: None, ..record };
+        let buf = serde_cbor::to_vec(&record).unwrap();
+        let head = serde_cbor::from_slice::<RecordHead>(&buf).unwrap();
+        assert!(!head.kv_skipped);
+        assert_eq!(head.record, record);
+    }
+}
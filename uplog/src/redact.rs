@@ -0,0 +1,187 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    RwLock,
+};
+
+use crate::{KVBorrow, Value, ValueBorrow, KV};
+
+/// 秘匿した値の代わりに出力する文字列
+pub const REDACTED: &str = "<redacted>";
+
+/// kvのキーと値を受け取り、必要に応じて値を書き換える
+pub type RedactFn = fn(&str, &mut Value);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REDACTORS: RwLock<Redactors> = RwLock::new(Redactors(Vec::new()));
+
+/// 設定は`Builder`からだけ行う
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub(crate) enum Redactor {
+    /// 任意の処理
+    Hook(RedactFn),
+    /// 指定したキーの値を置き換える
+    Keys(Vec<String>),
+    /// 文字列の値のうち一致した部分を置き換える
+    #[cfg(feature = "redact-regex")]
+    Pattern(regex::Regex),
+}
+
+impl Redactor {
+    fn apply(&self, key: &str, value: &mut Value) {
+        match self {
+            Redactor::Hook(f) => f(key, value),
+            Redactor::Keys(keys) => {
+                if keys.iter().any(|k| k == key) {
+                    *value = Value::Text(REDACTED.to_string());
+                }
+            }
+            #[cfg(feature = "redact-regex")]
+            Redactor::Pattern(re) => scrub(re, value),
+        }
+    }
+}
+
+#[cfg(feature = "redact-regex")]
+fn scrub(re: &regex::Regex, value: &mut Value) {
+    match value {
+        Value::Text(x) => {
+            if let std::borrow::Cow::Owned(replaced) = re.replace_all(x, REDACTED) {
+                *x = replaced;
+            }
+        }
+        Value::Array(x) => x.iter_mut().for_each(|v| scrub(re, v)),
+        Value::Map(x) => x.values_mut().for_each(|v| scrub(re, v)),
+        _ => {}
+    }
+}
+
+/// 登録順に適用する
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Redactors(Vec<Redactor>);
+
+impl Redactors {
+    pub(crate) fn new(redactors: Vec<Redactor>) -> Self {
+        Self(redactors)
+    }
+
+    /// 全てのkvに適用した結果を返す
+    pub(crate) fn redact(&self, kv: &KVBorrow) -> KV {
+        kv.iter()
+            .map(|(k, v)| {
+                let mut value = Value::from(v);
+                for r in self.0.iter() {
+                    r.apply(k, &mut value);
+                }
+                (k.to_string(), value)
+            })
+            .collect()
+    }
+}
+
+/// 秘匿処理を設定する。空の場合は無効化する
+pub(crate) fn install(redactors: Vec<Redactor>) {
+    let enabled = !redactors.is_empty();
+    *REDACTORS
+        .write()
+        .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK) = Redactors::new(redactors);
+    ENABLED.store(enabled, Ordering::Release);
+}
+
+/// 秘匿処理が設定されていれば適用したkvを返す
+pub(crate) fn redact(kv: Option<&KVBorrow>) -> Option<KV> {
+    if !ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+    kv.map(|kv| {
+        REDACTORS
+            .read()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+            .redact(kv)
+    })
+}
+
+/// 所有型のkvを借用型として参照する
+pub(crate) fn borrow_kv(kv: &KV) -> KVBorrow<'_> {
+    kv.iter()
+        .map(|(k, v)| (k.as_str(), ValueBorrow::from(v)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{borrow_kv, Redactor, Redactors, REDACTED};
+    use crate::{Level, MetadataBorrow, Record, RecordBorrow, Value};
+
+    fn encode(redactors: &Redactors, kv: Option<crate::KVBorrow>) -> Record {
+        let owned = kv.as_ref().map(|kv| redactors.redact(kv));
+        let kv = owned.as_ref().map(borrow_kv);
+        let r = RecordBorrow {
+            metadata: MetadataBorrow::new(Level::Info, "target"),
+            elapsed: std::time::Duration::from_millis(1),
+            category: "cat",
+            module_path: None,
+            file: None,
+            line: None,
+            message: "msg",
+            kv,
+        };
+        let buf = serde_cbor::to_vec(&r).unwrap();
+        serde_cbor::from_slice(&buf).unwrap()
+    }
+
+    #[test]
+    fn test_redact_keys() {
+        let redactors = Redactors::new(vec![Redactor::Keys(vec![
+            "password".to_string(),
+            "token".to_string(),
+        ])]);
+        let kv = kv_borrow_zip!("password", "hunter2", "token", 42_u32, "user", "alice");
+        let record = encode(&redactors, Some(kv));
+        let kv = record.key_values().unwrap();
+        assert_eq!(kv.get("password"), Some(&Value::Text(REDACTED.into())));
+        assert_eq!(kv.get("token"), Some(&Value::Text(REDACTED.into())));
+        assert_eq!(kv.get("user"), Some(&Value::Text("alice".into())));
+    }
+
+    #[test]
+    fn test_redact_hook() {
+        static CALLED: AtomicUsize = AtomicUsize::new(0);
+        let redactors = Redactors::new(vec![Redactor::Hook(|k, v| {
+            CALLED.fetch_add(1, Ordering::SeqCst);
+            if k == "email" {
+                *v = Value::Null;
+            }
+        })]);
+
+        // kvがなければ呼ばれない
+        let record = encode(&redactors, None);
+        assert!(record.key_values().is_none());
+        assert_eq!(CALLED.load(Ordering::SeqCst), 0);
+
+        let bytes = vec![1_u8, 2];
+        let kv = kv_borrow_zip!("email", "alice@example.com", "n", &bytes);
+        let record = encode(&redactors, Some(kv));
+        assert_eq!(CALLED.load(Ordering::SeqCst), 2);
+        let kv = record.key_values().unwrap();
+        assert_eq!(kv.get("email"), Some(&Value::Null));
+        assert_eq!(kv.get("n"), Some(&Value::Bytes(vec![1, 2])));
+    }
+
+    #[cfg(feature = "redact-regex")]
+    #[test]
+    fn test_redact_pattern() {
+        let re = regex::Regex::new(r"[\w.]+@[\w.]+").unwrap();
+        let redactors = Redactors::new(vec![Redactor::Pattern(re)]);
+        let kv = kv_borrow_zip!("note", "contact alice@example.com now", "n", 1_u8);
+        let record = encode(&redactors, Some(kv));
+        let kv = record.key_values().unwrap();
+        assert_eq!(
+            kv.get("note"),
+            Some(&Value::Text(format!("contact {} now", REDACTED)))
+        );
+        assert_eq!(kv.get("n"), Some(&Value::U64(1)));
+    }
+}
@@ -0,0 +1,293 @@
+//! 書き込むスレッドが1つの場合に使うロックを取らないバッファー
+//!
+//! 書き込み位置と読み出し位置をatomicで共有するバイト列のリングバッファー。
+//! 書き込み側はレコード単位で書き込み、収まらない場合は何も書かずに失敗する。
+//! 読み出し側は書き込み済みの範囲だけを読むので、書きかけのレコードは見えない
+use std::{
+    cell::UnsafeCell,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+struct Ring {
+    buf: Box<[UnsafeCell<u8>]>,
+    /// 書き込み済みの位置。書き込み側だけが進める。容量で割らずに増やし続ける
+    head: AtomicUsize,
+    /// 読み出し済みの位置。読み出し側だけが進める
+    tail: AtomicUsize,
+    /// 書き込み中の印。複数のスレッドから書き込まれても壊れないように順番にする
+    writing: AtomicBool,
+}
+
+// headとtailの間は読み出し側だけが、それ以外は書き込み中の印を持つスレッドだけが触る
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        UnsafeCell::raw_get(self.buf.as_ptr())
+    }
+
+    /// `pos`から`len`バイトの範囲を、折り返しの前後に分けた(開始位置, 長さ)で返す
+    fn segments(&self, pos: usize, len: usize) -> [(usize, usize); 2] {
+        let start = pos % self.capacity();
+        let first = len.min(self.capacity() - start);
+        [(start, first), (0, len - first)]
+    }
+}
+
+/// 書き込み側。`Log`の実装から共有する
+#[derive(Clone)]
+pub(crate) struct RingWriter {
+    ring: Arc<Ring>,
+}
+
+/// 読み出し側。送信スレッドだけが持つ
+pub(crate) struct RingReader {
+    ring: Arc<Ring>,
+}
+
+/// 書き込み中の印を外す
+struct WritingGuard<'a>(&'a AtomicBool);
+
+impl Drop for WritingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+pub(crate) fn ring_buffer(capacity: usize) -> (RingWriter, RingReader) {
+    let ring = Arc::new(Ring {
+        buf: (0..capacity).map(|_| UnsafeCell::new(0)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        writing: AtomicBool::new(false),
+    });
+    (RingWriter { ring: ring.clone() }, RingReader { ring })
+}
+
+impl RingWriter {
+    /// 1つのスレッドから書き込む場合は競合しないので待たない
+    fn begin(&self) -> WritingGuard<'_> {
+        let writing = &self.ring.writing;
+        while writing
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            thread::yield_now();
+        }
+        WritingGuard(writing)
+    }
+
+    /// 全て書き込めた場合は書き込み済みのバイト数を返す。収まらない場合は何も書かない
+    pub(crate) fn push(&self, data: &[u8]) -> Option<usize> {
+        let ring = &self.ring;
+        let _guard = self.begin();
+        let head = ring.head.load(Ordering::Relaxed);
+        let tail = ring.tail.load(Ordering::Acquire);
+        let used = head.wrapping_sub(tail);
+        if data.len() > ring.capacity() - used {
+            return None;
+        }
+        if !data.is_empty() {
+            let mut src = data.as_ptr();
+            for (start, len) in ring.segments(head, data.len()) {
+                unsafe {
+                    ptr::copy_nonoverlapping(src, ring.ptr().add(start), len);
+                    src = src.add(len);
+                }
+            }
+        }
+        ring.head
+            .store(head.wrapping_add(data.len()), Ordering::Release);
+        Some(used + data.len())
+    }
+}
+
+impl RingReader {
+    pub(crate) fn capacity(&self) -> usize {
+        self.ring.capacity()
+    }
+
+    /// 書き込み済みのデータを全て`out`の後ろに移す。戻り値は移したバイト数
+    pub(crate) fn read_to_end(&mut self, out: &mut Vec<u8>) -> usize {
+        let ring = &self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let head = ring.head.load(Ordering::Acquire);
+        let len = head.wrapping_sub(tail);
+        if len == 0 {
+            return 0;
+        }
+        out.reserve(len);
+        for (start, len) in ring.segments(tail, len) {
+            let old_len = out.len();
+            unsafe {
+                ptr::copy_nonoverlapping(ring.ptr().add(start), out.as_mut_ptr().add(old_len), len);
+                out.set_len(old_len + len);
+            }
+        }
+        // 読み終えてから書き込み側に領域を返す
+        ring.tail.store(head, Ordering::Release);
+        len
+    }
+
+    /// 書き込み済みのデータを`f`に渡し、`f`が返した長さを読み済みにする
+    ///
+    /// `pending`が空で折り返していなければリングの中をそのまま渡す。
+    /// それ以外は`pending`の後ろに移してから渡し、読み済みの分を`pending`から取り除く
+    pub(crate) fn read_with<F: FnOnce(&[u8]) -> usize>(&mut self, pending: &mut Vec<u8>, f: F) {
+        let ring = &self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let head = ring.head.load(Ordering::Acquire);
+        let len = head.wrapping_sub(tail);
+        match ring.segments(tail, len) {
+            [(start, len), (_, 0)] if pending.is_empty() => {
+                // tailを進めるまで書き込み側はこの範囲を触らない
+                let data = unsafe { std::slice::from_raw_parts(ring.ptr().add(start), len) };
+                let consumed = f(data);
+                ring.tail
+                    .store(tail.wrapping_add(consumed), Ordering::Release);
+            }
+            _ => {
+                self.read_to_end(pending);
+                let consumed = f(pending);
+                pending.drain(..consumed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::ring_buffer;
+
+    #[test]
+    fn test_ring_buffer() {
+        let (writer, mut reader) = ring_buffer(16);
+        let mut out = Vec::new();
+        assert_eq!(reader.read_to_end(&mut out), 0);
+
+        assert_eq!(writer.push(b"0123456789"), Some(10));
+        // 収まらない場合は途中まで書かない
+        assert_eq!(writer.push(b"abcdefghij"), None);
+        assert_eq!(writer.push(b""), Some(10));
+        assert_eq!(reader.read_to_end(&mut out), 10);
+        assert_eq!(out, b"0123456789");
+
+        // 末尾で折り返す
+        assert_eq!(writer.push(b"abcdefghij"), Some(10));
+        assert_eq!(writer.push(b"klmnop"), Some(16));
+        assert_eq!(writer.push(b"q"), None);
+        out.clear();
+        assert_eq!(reader.read_to_end(&mut out), 16);
+        assert_eq!(out, b"abcdefghijklmnop");
+        assert_eq!(reader.read_to_end(&mut out), 0);
+
+        // 容量が0の場合は空のデータだけ書ける
+        let (writer, mut reader) = ring_buffer(0);
+        assert_eq!(writer.push(b"a"), None);
+        assert_eq!(writer.push(b""), Some(0));
+        assert_eq!(reader.read_to_end(&mut out), 0);
+    }
+
+    #[test]
+    fn test_ring_buffer_read_with() {
+        let (writer, mut reader) = ring_buffer(16);
+        let mut pending = Vec::new();
+        writer.push(b"0123456789").unwrap();
+        // 折り返していなければ手元に移さずに渡す
+        reader.read_with(&mut pending, |data| {
+            assert_eq!(data, b"0123456789");
+            4
+        });
+        assert!(pending.is_empty());
+        reader.read_with(&mut pending, |data| {
+            assert_eq!(data, b"456789");
+            6
+        });
+
+        // 折り返した範囲は手元に移し、読み残しは次に先頭から渡す
+        writer.push(b"abcdefghij").unwrap();
+        reader.read_with(&mut pending, |data| {
+            assert_eq!(data, b"abcdefghij");
+            3
+        });
+        assert_eq!(pending, b"defghij");
+        writer.push(b"klm").unwrap();
+        reader.read_with(&mut pending, |data| {
+            assert_eq!(data, b"defghijklm");
+            10
+        });
+        assert!(pending.is_empty());
+        reader.read_with(&mut pending, |data| {
+            assert!(data.is_empty());
+            0
+        });
+    }
+
+    /// 連番のレコードを書き込みながら読み出し、欠落や途中までのレコードがないことを確認する
+    fn stress(producers: usize, count: u32, capacity: usize) {
+        let (writer, mut reader) = ring_buffer(capacity);
+        let handles = (0..producers)
+            .map(|p| {
+                let writer = writer.clone();
+                thread::spawn(move || {
+                    for i in 0..count {
+                        // 長さの違うレコードで折り返しの位置をずらす
+                        let len = 1 + (i as usize % 13);
+                        let mut record = vec![p as u8, len as u8];
+                        record.extend_from_slice(&i.to_le_bytes());
+                        record.resize(6 + len, i as u8);
+                        // 読み出しが追いつくまで待つ
+                        while writer.push(&record).is_none() {
+                            thread::sleep(Duration::from_micros(10));
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut next = vec![0_u32; producers];
+        let mut pending = Vec::new();
+        while next.iter().any(|x| *x < count) {
+            // 送信スレッドと同じく折り返しの有無で読み方が変わる
+            reader.read_with(&mut pending, |data| {
+                let mut rest = data;
+                while !rest.is_empty() {
+                    let (p, len) = (rest[0] as usize, rest[1] as usize);
+                    let i = u32::from_le_bytes(rest[2..6].try_into().unwrap());
+                    assert_eq!(i, next[p], "producer {}", p);
+                    assert!(rest[6..6 + len].iter().all(|x| *x == i as u8));
+                    next[p] += 1;
+                    rest = &rest[6 + len..];
+                }
+                data.len()
+            });
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(reader.read_to_end(&mut pending), 0);
+    }
+
+    #[test]
+    fn test_ring_buffer_stress() {
+        stress(1, 200_000, 64);
+        stress(1, 200_000, 4096);
+    }
+
+    /// 想定外に複数のスレッドから書き込んでもレコードが混ざらない
+    #[test]
+    fn test_ring_buffer_multiple_producers() {
+        stress(4, 50_000, 256);
+    }
+}
@@ -1,59 +1,91 @@
 use std::{
-    sync::Once,
+    collections::hash_map::RandomState,
+    hash::BuildHasher,
+    sync::OnceLock,
     time::{Duration, Instant},
 };
 
 use chrono::{DateTime, Utc};
 
-static mut SESSION: Option<SesstionInfo> = None;
-static INIT: Once = Once::new();
+static SESSION: OnceLock<SesstionInfo> = OnceLock::new();
 
 /// 1 Recordの一意性のための時刻と経過時間を記録する
 pub(crate) struct SesstionInfo {
     start_at: DateTime<Utc>,
     instant: Instant,
+    /// 再接続しても同じクライアントだとわかるようにサーバーへ送るID
+    id: String,
 }
 
 impl SesstionInfo {
     fn new() -> Self {
+        let start_at = Utc::now();
         Self {
-            start_at: Utc::now(),
+            start_at,
             instant: Instant::now(),
+            id: new_id(&start_at),
         }
     }
 }
 
+/// uuid v4の形式のID
+///
+/// RandomStateはプロセスごとにOSの乱数で初期化されるのでそれを使う
+fn new_id(start_at: &DateTime<Utc>) -> String {
+    let state = RandomState::new();
+    let mut bytes = [0_u8; 16];
+    for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+        let h = state.hash_one((i, start_at.timestamp_micros(), std::process::id()));
+        chunk.copy_from_slice(&h.to_be_bytes());
+    }
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    let hex = bytes
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
 #[doc(hidden)]
 pub fn session_init() {
-    unsafe {
-        INIT.call_once(|| {
-            SESSION = Some(SesstionInfo::new());
-        });
-    }
+    session();
+}
+
+/// 最初に使ったときに始まる。初期化前のログ出力でも時刻が決まる
+fn session() -> &'static SesstionInfo {
+    SESSION.get_or_init(SesstionInfo::new)
 }
 
 pub(crate) fn elapsed() -> Duration {
-    unsafe {
-        SESSION
-            .as_ref()
-            .expect("need to call session_init() before")
-            .instant
-            .elapsed()
-    }
+    session().instant.elapsed()
+}
+
+/// 同じプロセスから別のセッションとして送るときのID
+#[cfg_attr(not(feature = "client-ws"), allow(dead_code))]
+pub(crate) fn new_session_id() -> String {
+    new_id(&Utc::now())
+}
+
+/// ID of this process sent to the server on every (re)connection
+pub fn session_id() -> &'static str {
+    &session().id
 }
 
 pub fn start_at() -> DateTime<Utc> {
-    unsafe {
-        SESSION
-            .as_ref()
-            .expect("need to call session_init() before")
-            .start_at
-    }
+    session().start_at
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::session::{elapsed, session_init, start_at};
+    use crate::session::{elapsed, new_id, session_id, session_init, start_at};
     use std::thread;
     use std::time::Duration;
 
@@ -64,6 +96,19 @@ mod tests {
         let start_time = start_at();
         thread::sleep(Duration::from_millis(1));
         assert!(elapsed() > start_duration);
-        assert_eq!(start_time, start_at())
+        assert_eq!(start_time, start_at());
+        assert_eq!(session_id(), session_id());
+    }
+
+    #[test]
+    fn test_session_id() {
+        let now = chrono::Utc::now();
+        let id = new_id(&now);
+        assert_eq!(id.len(), 36);
+        let groups = id.split('-').map(|x| x.len()).collect::<Vec<_>>();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert!(id[14..15] == *"4");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"));
+        assert_ne!(id, new_id(&now));
     }
 }
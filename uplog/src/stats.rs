@@ -0,0 +1,174 @@
+//! 送信クライアントの統計値
+//!
+//! ホストアプリケーションのメトリクスに取り込めるように、
+//! 送信スレッドから定期的に通知するか[`stats_snapshot`]で取得する
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// ログ出力側から頻繁に更新されるのでロックを取らない
+static RECORDS_WRITTEN: AtomicU64 = AtomicU64::new(0);
+static BUFFER_USED: AtomicU64 = AtomicU64::new(0);
+static BUFFER_CAPACITY: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static RECONNECTS: AtomicU64 = AtomicU64::new(0);
+static SENDER_RESTARTS: AtomicU64 = AtomicU64::new(0);
+static REJECTED_OVERSIZE: AtomicU64 = AtomicU64::new(0);
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+// f64のビット列
+static RECORDS_PER_SEC: AtomicU64 = AtomicU64::new(0);
+
+/// 送信クライアントの統計値
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoggerStats {
+    /// 書き込み側バッファーの容量
+    pub buffer_capacity: u64,
+    /// 書き込み側バッファーに溜まっているバイト数
+    pub buffer_used: u64,
+    /// バッファーに書き込んだレコード数
+    pub records_written: u64,
+    /// 直前の送信周期で書き込まれたレコード数/秒
+    pub records_per_sec: f64,
+    /// サーバーに送信したバイト数
+    pub bytes_sent: u64,
+    /// バッファーに収まらなかったか、送信に失敗して破棄したレコード数
+    pub dropped_records: u64,
+    /// 切断後に再接続した回数
+    pub reconnects: u64,
+    /// 止まった送信スレッドを見張りが作り直した回数
+    pub sender_restarts: u64,
+    /// `Builder::max_record_bytes`を超えるためシリアライズせずに除いたレコード数
+    pub records_rejected_oversize: u64,
+    /// サーバーに接続しているか
+    pub connected: bool,
+    /// サーバーの時計に対するずれ(ミリ秒)の移動平均。時刻を返すサーバーに接続するまでは`None`
+    pub clock_offset_ms: Option<i64>,
+}
+
+impl LoggerStats {
+    /// 書き込み側バッファーの使用率 (0-100)
+    pub fn buffer_fill_percent(&self) -> f64 {
+        if self.buffer_capacity == 0 {
+            return 0.0;
+        }
+        self.buffer_used as f64 * 100.0 / self.buffer_capacity as f64
+    }
+}
+
+/// 統計値を受け取る関数
+pub type StatsObserver = Box<dyn Fn(&LoggerStats) + Send + Sync>;
+
+/// 現在の統計値を返す
+pub fn stats_snapshot() -> LoggerStats {
+    LoggerStats {
+        buffer_capacity: BUFFER_CAPACITY.load(Ordering::Acquire),
+        buffer_used: BUFFER_USED.load(Ordering::Acquire),
+        records_written: RECORDS_WRITTEN.load(Ordering::Acquire),
+        records_per_sec: f64::from_bits(RECORDS_PER_SEC.load(Ordering::Acquire)),
+        bytes_sent: BYTES_SENT.load(Ordering::Acquire),
+        dropped_records: crate::health::dropped_records(),
+        reconnects: RECONNECTS.load(Ordering::Acquire),
+        sender_restarts: SENDER_RESTARTS.load(Ordering::Acquire),
+        records_rejected_oversize: REJECTED_OVERSIZE.load(Ordering::Acquire),
+        connected: CONNECTED.load(Ordering::Acquire),
+        clock_offset_ms: crate::clock::offset_ms(),
+    }
+}
+
+/// 書き込みに成功したときに書き込み側の使用量と合わせて記録する
+pub(crate) fn record_written(buffer_used: usize) {
+    RECORDS_WRITTEN.fetch_add(1, Ordering::AcqRel);
+    BUFFER_USED.store(buffer_used as u64, Ordering::Release);
+}
+
+/// 優先するバッファーへの書き込み。使用量は通常のバッファーの分だけを記録する
+pub(crate) fn priority_record_written() {
+    RECORDS_WRITTEN.fetch_add(1, Ordering::AcqRel);
+}
+
+pub(crate) fn set_buffer_capacity(capacity: usize) {
+    BUFFER_CAPACITY.store(capacity as u64, Ordering::Release);
+}
+
+/// swapで書き込み側が空になった
+pub(crate) fn buffer_swapped() {
+    BUFFER_USED.store(0, Ordering::Release);
+}
+
+pub(crate) fn bytes_sent(n: usize) {
+    BYTES_SENT.fetch_add(n as u64, Ordering::AcqRel);
+}
+
+pub(crate) fn set_connected(connected: bool) {
+    CONNECTED.store(connected, Ordering::Release);
+}
+
+pub(crate) fn reconnected() {
+    RECONNECTS.fetch_add(1, Ordering::AcqRel);
+}
+
+pub(crate) fn sender_restarted() {
+    SENDER_RESTARTS.fetch_add(1, Ordering::AcqRel);
+}
+
+pub(crate) fn record_rejected_oversize() {
+    REJECTED_OVERSIZE.fetch_add(1, Ordering::AcqRel);
+}
+
+/// 送信スレッドで書き込み速度を計算し、間隔ごとに通知する
+pub(crate) struct StatsReporter {
+    observer: Option<ObserverConfig>,
+    last_tick: Instant,
+    last_written: u64,
+}
+
+impl StatsReporter {
+    pub(crate) fn new(observer: Option<ObserverConfig>) -> Self {
+        Self {
+            observer,
+            last_tick: Instant::now(),
+            last_written: RECORDS_WRITTEN.load(Ordering::Acquire),
+        }
+    }
+
+    /// 送信周期ごとに呼ぶ。書き込み側のロックを持ったまま呼んではいけない
+    ///
+    /// 書き込み速度は通知先があれば通知の間隔、なければ送信周期での平均
+    pub(crate) fn tick(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(self.last_tick);
+        if matches!(&self.observer, Some(x) if elapsed < x.interval) {
+            return;
+        }
+        let written = RECORDS_WRITTEN.load(Ordering::Acquire);
+        if !elapsed.is_zero() {
+            let rate = written.saturating_sub(self.last_written) as f64 / elapsed.as_secs_f64();
+            RECORDS_PER_SEC.store(rate.to_bits(), Ordering::Release);
+        }
+        self.last_tick = now;
+        self.last_written = written;
+        if let Some(observer) = &self.observer {
+            (observer.f)(&stats_snapshot());
+        }
+    }
+}
+
+/// Builderで保持するための型。Debugを実装するためにラップする
+#[derive(Clone)]
+pub(crate) struct ObserverConfig {
+    pub(crate) f: Arc<dyn Fn(&LoggerStats) + Send + Sync>,
+    pub(crate) interval: Duration,
+}
+
+impl Debug for ObserverConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObserverConfig")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
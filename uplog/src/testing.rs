@@ -0,0 +1,388 @@
+//! テスト用の受信サーバー
+//!
+//! 別スレッドでWebSocketの接続を順に受け付け、受け取ったデータを溜めておく。
+//! subprotocolを返さないので、クライアントは従来のCBORで送ってくる
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tungstenite::{
+    handshake::server::{Request, Response},
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message, WebSocket,
+};
+use url::Url;
+
+use crate::{
+    protocol::{CloseReason, ServerMessage},
+    Record, CLIENT_CATEGORY, INGEST_PATH,
+};
+
+/// 停止や切断の要求を確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Returned when a [`TestCollector`] did not see what it waited for in time.
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("timed out after {waited:?} waiting for {expected}, received {received} records")]
+pub struct WaitTimeout {
+    pub waited: Duration,
+    pub expected: String,
+    /// records received so far, excluding the client's connection records
+    pub received: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    bytes: Vec<u8>,
+    connections: usize,
+    /// クライアントが閉じた接続の数
+    closed: usize,
+    /// 最後の接続のハンドシェイクのヘッダー
+    headers: Vec<(String, String)>,
+    /// 最後の接続のハンドシェイクのパス
+    path: Option<String>,
+    /// 次の機会にクライアントへ送るメッセージ
+    outgoing: Vec<Message>,
+    /// 最後にクライアントが閉じたときの理由
+    close_reason: Option<CloseReason>,
+    /// 次の機会にこの理由で接続を閉じる
+    close_request: Option<CloseReason>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    stop: AtomicBool,
+    disconnect: AtomicBool,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+    }
+
+    fn update<F: FnOnce(&mut State)>(&self, f: F) {
+        f(&mut self.lock());
+        self.changed.notify_all();
+    }
+}
+
+/// An in-process websocket server that collects the records sent by uplog clients.
+///
+/// Connections are accepted one after another, so a client that reconnects keeps
+/// writing to the same collector.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use uplog::testing::TestCollector;
+///
+/// let collector = TestCollector::start().unwrap();
+/// uplog::Builder::default()
+///     .port(collector.port())
+///     .try_init()
+///     .unwrap();
+/// uplog::info!("app", "hello");
+/// uplog::flush();
+///
+/// let records = collector.wait_for_records(1, Duration::from_secs(5)).unwrap();
+/// assert_eq!(records[0].message, "hello");
+/// ```
+#[derive(Debug)]
+pub struct TestCollector {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl TestCollector {
+    /// Listens on a free port of 127.0.0.1.
+    pub fn start() -> io::Result<Self> {
+        Self::bind("127.0.0.1:0")
+    }
+
+    /// Listens on `addr`.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared::default());
+        let handle = {
+            let shared = shared.clone();
+            thread::spawn(move || serve(listener, &shared))
+        };
+        Ok(Self {
+            addr,
+            shared,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// URL to connect to, the same as the one [`crate::Builder`] makes for this port.
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("ws://{}{}", self.addr, INGEST_PATH)).expect("valid url")
+    }
+
+    /// Every byte received so far.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.shared.lock().bytes.clone()
+    }
+
+    /// Records received so far, without the records the client writes about the connection.
+    pub fn records(&self) -> Vec<Record> {
+        self.all_records()
+            .into_iter()
+            .filter(|x| x.category != CLIENT_CATEGORY)
+            .collect()
+    }
+
+    /// Records received so far, including the records in [`crate::CLIENT_CATEGORY`].
+    ///
+    /// Stops at the first bytes that can not be decoded as a record.
+    pub fn all_records(&self) -> Vec<Record> {
+        decode_records(&self.shared.lock().bytes)
+    }
+
+    /// Number of connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.shared.lock().connections
+    }
+
+    /// Path requested in the handshake of the last connection.
+    pub fn handshake_path(&self) -> Option<String> {
+        self.shared.lock().path.clone()
+    }
+
+    /// Value of a header sent in the handshake of the last connection.
+    pub fn handshake_header(&self, name: &str) -> Option<String> {
+        self.shared
+            .lock()
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+    }
+
+    /// Waits until `count` records are received, and returns them as [`TestCollector::records`].
+    pub fn wait_for_records(
+        &self,
+        count: usize,
+        timeout: Duration,
+    ) -> Result<Vec<Record>, WaitTimeout> {
+        let mut records = Vec::new();
+        self.wait(timeout, &format!("{} records", count), |state| {
+            records = decode_records(&state.bytes)
+                .into_iter()
+                .filter(|x| x.category != CLIENT_CATEGORY)
+                .collect();
+            records.len() >= count
+        })?;
+        Ok(records)
+    }
+
+    /// Waits until a client closes its connection, e.g. by [`crate::flush`].
+    pub fn wait_for_close(&self, timeout: Duration) -> Result<(), WaitTimeout> {
+        self.wait(timeout, "the client to close", |state| state.closed > 0)
+    }
+
+    /// Waits until `count` connections are accepted in total.
+    pub fn wait_for_connections(&self, count: usize, timeout: Duration) -> Result<(), WaitTimeout> {
+        self.wait(timeout, &format!("{} connections", count), |state| {
+            state.connections >= count
+        })
+    }
+
+    /// Sends `msg` to the connected client, or to the next one if none is connected.
+    pub fn send(&self, msg: &ServerMessage) {
+        let buf = serde_cbor::to_vec(msg).expect("serialize error");
+        self.send_binary(buf);
+    }
+
+    /// Sends `buf` as is in a binary message, e.g. to test how a client handles garbage.
+    pub fn send_binary(&self, buf: Vec<u8>) {
+        self.shared
+            .update(|state| state.outgoing.push(Message::Binary(buf)));
+    }
+
+    /// Sends a text message, which uplog servers never send.
+    pub fn send_text(&self, text: &str) {
+        self.shared
+            .update(|state| state.outgoing.push(Message::Text(text.to_string())));
+    }
+
+    /// Sends a ping message.
+    pub fn send_ping(&self, payload: Vec<u8>) {
+        self.shared
+            .update(|state| state.outgoing.push(Message::Ping(payload)));
+    }
+
+    /// Reason sent by the client that closed a connection last.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.shared.lock().close_reason
+    }
+
+    /// Closes the current connection, or the next one, with `reason` as a server would.
+    pub fn close_with(&self, reason: CloseReason) {
+        self.shared
+            .update(|state| state.close_request = Some(reason));
+    }
+
+    /// Drops the current connection without a close handshake, as a crashed server would.
+    pub fn disconnect(&self) {
+        self.shared.disconnect.store(true, Ordering::Release);
+    }
+
+    fn wait<F: FnMut(&State) -> bool>(
+        &self,
+        timeout: Duration,
+        expected: &str,
+        mut done: F,
+    ) -> Result<(), WaitTimeout> {
+        let start = Instant::now();
+        let mut state = self.shared.lock();
+        while !done(&state) {
+            let left = match timeout.checked_sub(start.elapsed()) {
+                Some(x) if !x.is_zero() => x,
+                _ => {
+                    return Err(WaitTimeout {
+                        waited: timeout,
+                        expected: expected.to_string(),
+                        received: decode_records(&state.bytes)
+                            .iter()
+                            .filter(|x| x.category != CLIENT_CATEGORY)
+                            .count(),
+                    })
+                }
+            };
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, left)
+                .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+                .0;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TestCollector {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+        if let Some(x) = self.handle.take() {
+            x.join().ok();
+        }
+    }
+}
+
+fn decode_records(buf: &[u8]) -> Vec<Record> {
+    serde_cbor::Deserializer::from_slice(buf)
+        .into_iter::<Record>()
+        .map_while(Result::ok)
+        .collect()
+}
+
+/// 停止するまで接続を順に受け付ける
+fn serve(listener: TcpListener, shared: &Shared) {
+    while !shared.stop.load(Ordering::Acquire) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = receive(stream, shared) {
+                    log::debug!("collector connection error {}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                log::warn!("collector accept error {}", e);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+/// 1つの接続から受け取る
+#[allow(clippy::result_large_err)]
+fn receive(stream: TcpStream, shared: &Shared) -> tungstenite::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut headers = Vec::new();
+    let mut path = None;
+    let mut ws = tungstenite::accept_hdr(stream.try_clone()?, |req: &Request, res: Response| {
+        path = Some(req.uri().path().to_string());
+        headers = req
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_string()))
+            .collect();
+        Ok(res)
+    })
+    .map_err(|e| io::Error::other(e.to_string()))?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    shared.disconnect.store(false, Ordering::Release);
+    shared.update(|state| {
+        state.connections += 1;
+        state.headers = headers;
+        state.path = path;
+    });
+    loop {
+        if shared.stop.load(Ordering::Acquire) || shared.disconnect.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        flush_outgoing(&mut ws, shared)?;
+        if let Some(reason) = shared.lock().close_request.take() {
+            ws.close(Some(CloseFrame {
+                code: CloseCode::from(reason.code()),
+                reason: reason.as_str().into(),
+            }))?;
+        }
+        match ws.read_message() {
+            Ok(Message::Binary(x)) => shared.update(|state| state.bytes.extend_from_slice(&x)),
+            Ok(Message::Text(x)) => {
+                shared.update(|state| state.bytes.extend_from_slice(x.as_bytes()))
+            }
+            Ok(Message::Close(frame)) => {
+                // 閉じる応答を返してから数える
+                ws.write_pending().ok();
+                shared.update(|state| {
+                    state.closed += 1;
+                    state.close_reason = frame.map(|x| CloseReason::from_code(x.code.into()));
+                });
+                return Ok(());
+            }
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn flush_outgoing(ws: &mut WebSocket<TcpStream>, shared: &Shared) -> tungstenite::Result<()> {
+    let outgoing = std::mem::take(&mut shared.lock().outgoing);
+    for message in outgoing {
+        ws.write_message(message)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! tracingに渡す送信先
+//!
+//! 送信スレッドでレコードを戻してtracingのイベントにする。ターゲットは固定なのでカテゴリはフィールドに入れる
+use crate::{error::InitError, transport::Transport, Level, Record};
+
+/// target of the events made by [`TracingTransport`]
+pub const TRACING_TARGET: &str = "uplog";
+
+/// [`Transport`] that emits the records as events of the current `tracing` dispatcher.
+///
+/// Events are emitted on the sender thread, with the fields `category`, `elapsed`,
+/// `location` and `kv`.
+#[derive(Debug, Default)]
+pub struct TracingTransport {
+    _private: (),
+}
+
+impl TracingTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Transport for TracingTransport {
+    fn send(&mut self, buf: &[u8]) -> crate::Result<()> {
+        for record in serde_cbor::Deserializer::from_slice(buf).into_iter::<Record>() {
+            match record {
+                Ok(record) => emit(&record),
+                Err(e) => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e).into())
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// tracingのレベルは定数で渡す必要があるので分ける
+fn emit(record: &Record) {
+    macro_rules! event {
+        ($level:expr) => {
+            tracing::event!(
+                target: TRACING_TARGET,
+                $level,
+                category = %record.category,
+                elapsed = ?record.elapsed,
+                location = %format_args!(
+                    "{}:{}",
+                    record.file().map_or("", |x| x.as_str()),
+                    record.line().unwrap_or(0)
+                ),
+                kv = ?record.key_values(),
+                "{}",
+                record.message
+            )
+        };
+    }
+    match record.level() {
+        Level::Trace => event!(tracing::Level::TRACE),
+        Level::Debug => event!(tracing::Level::DEBUG),
+        Level::Info => event!(tracing::Level::INFO),
+        Level::Warn => event!(tracing::Level::WARN),
+        Level::Error => event!(tracing::Level::ERROR),
+    }
+}
+
+/// Initializes the global logger to forward the records to `tracing`.
+pub fn init_tracing() -> Result<(), InitError> {
+    crate::init_with_transport(TracingTransport::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    use crate::{session_init, transport::Transport, Level};
+
+    use super::{TracingTransport, TRACING_TARGET};
+
+    type Fields = Vec<(String, String)>;
+
+    /// 受け取ったイベントのレベルとフィールドを残す
+    #[derive(Default, Clone)]
+    struct Collect(Arc<Mutex<Vec<(tracing::Level, Fields)>>>);
+
+    struct Visitor<'a>(&'a mut Fields);
+
+    impl Visit for Visitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    impl Subscriber for Collect {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == TRACING_TARGET
+        }
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Vec::new();
+            event.record(&mut Visitor(&mut fields));
+            self.0
+                .lock()
+                .unwrap()
+                .push((*event.metadata().level(), fields));
+        }
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn test_tracing_transport() {
+        session_init();
+        let mut buf = Vec::new();
+        serde_cbor::to_writer(
+            &mut buf,
+            &devlog!(Level::Warn, "net", "down", "retry", 3_u32),
+        )
+        .unwrap();
+        serde_cbor::to_writer(&mut buf, &devlog!(Level::Debug, "app", "tick")).unwrap();
+
+        let collect = Collect::default();
+        tracing::subscriber::with_default(collect.clone(), || {
+            TracingTransport::new().send(&buf).unwrap();
+        });
+        let events = collect.0.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        let field = |i: usize, name: &str| {
+            events[i]
+                .1
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        assert_eq!(events[0].0, tracing::Level::WARN);
+        assert_eq!(field(0, "message"), "down");
+        assert_eq!(field(0, "category"), "net");
+        assert!(field(0, "kv").contains("retry"));
+        assert_eq!(events[1].0, tracing::Level::DEBUG);
+        assert_eq!(field(1, "message"), "tick");
+
+        assert!(TracingTransport::new().send(&[0xff]).is_err());
+    }
+}
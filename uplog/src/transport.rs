@@ -0,0 +1,125 @@
+//! 送信スレッドとログサーバーの間の通信路
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use crate::{protocol::CloseReason, Record};
+
+/// Closes a connection from another thread, making a [`Transport::send`] blocked on it return.
+pub type AbortHandle = Box<dyn FnOnce() + Send>;
+
+/// Channel used by the sender thread to deliver encoded records.
+///
+/// Each call of [`Transport::send`] carries a concatenation of whole CBOR records.
+pub trait Transport: Send {
+    /// Sends one message.
+    #[allow(clippy::result_large_err)]
+    fn send(&mut self, buf: &[u8]) -> crate::Result<()>;
+
+    /// Returns a message from the server if one has arrived, without blocking.
+    #[allow(clippy::result_large_err)]
+    fn poll(&mut self) -> crate::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Closes the channel. Called once after the last [`Transport::send`].
+    #[allow(clippy::result_large_err)]
+    fn close(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    /// Closes the channel telling the peer why. Transports without a way to send the reason
+    /// call [`Transport::close`].
+    #[allow(clippy::result_large_err)]
+    fn close_with(&mut self, reason: CloseReason) -> crate::Result<()> {
+        let _ = reason;
+        self.close()
+    }
+
+    /// Returns a handle that aborts this connection, used to recover a sender thread stuck in
+    /// a write. Transports that can not be aborted return `None`.
+    fn abort_handle(&self) -> Option<AbortHandle> {
+        None
+    }
+}
+
+/// In-memory [`Transport`] for tests and benchmarks.
+///
+/// Clones share the counters, so keep one to inspect what the sender thread sent.
+///
+/// ```
+/// let transport = uplog::MockTransport::capture();
+/// uplog::init_with_transport(transport.clone()).unwrap();
+/// uplog::info!("doc", "hello");
+/// uplog::flush();
+/// assert!(transport.sent_bytes() > 0);
+/// assert_eq!(transport.records().last().unwrap().message, "hello");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    messages: Arc<AtomicU64>,
+    bytes: Arc<AtomicU64>,
+    captured: Option<Arc<Mutex<Vec<u8>>>>,
+}
+
+impl MockTransport {
+    /// Creates a transport that only counts messages and bytes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a transport that also keeps every sent byte.
+    pub fn capture() -> Self {
+        Self {
+            captured: Some(Arc::new(Mutex::new(Vec::new()))),
+            ..Self::default()
+        }
+    }
+
+    /// Number of messages sent so far.
+    pub fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Acquire)
+    }
+
+    /// Number of bytes sent so far.
+    pub fn sent_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Acquire)
+    }
+
+    /// Bytes sent so far. Empty unless created by [`MockTransport::capture`].
+    pub fn captured(&self) -> Vec<u8> {
+        self.captured
+            .as_ref()
+            .map(|x| {
+                x.lock()
+                    .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+                    .clone()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Records sent so far, including the records in [`crate::CLIENT_CATEGORY`].
+    ///
+    /// Stops at the first bytes that can not be decoded as a record.
+    pub fn records(&self) -> Vec<Record> {
+        serde_cbor::Deserializer::from_slice(&self.captured())
+            .into_iter::<Record>()
+            .map_while(Result::ok)
+            .collect()
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&mut self, buf: &[u8]) -> crate::Result<()> {
+        if let Some(captured) = self.captured.as_ref() {
+            captured
+                .lock()
+                .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+                .extend_from_slice(buf);
+        }
+        self.messages.fetch_add(1, Ordering::AcqRel);
+        self.bytes.fetch_add(buf.len() as u64, Ordering::AcqRel);
+        Ok(())
+    }
+}
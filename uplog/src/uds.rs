@@ -0,0 +1,155 @@
+//! 同じホストのサーバーにUnix domain socketで送信する
+//!
+//! WebSocketのメッセージの代わりに長さのヘッダーを付けて送る。
+//! 形式は[`crate::protocol::FRAME_HEADER_LEN`]を参照
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+
+use crate::{
+    protocol::{frame_header, frame_len, FRAME_HEADER_LEN},
+    transport::{AbortHandle, Transport},
+};
+
+pub(crate) struct UdsTransport {
+    stream: UnixStream,
+    /// 読みかけのサーバーからのメッセージ
+    read_buf: Vec<u8>,
+}
+
+impl UdsTransport {
+    const SERVER_MESSAGE_READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn connect(path: &Path) -> crate::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        // サーバーからの通知を待たずに読めるようにする
+        stream.set_read_timeout(Some(Self::SERVER_MESSAGE_READ_TIMEOUT))?;
+        Ok(Self {
+            stream,
+            read_buf: Vec::new(),
+        })
+    }
+
+    /// 揃ったメッセージがあれば取り出す
+    fn take_frame(&mut self) -> Option<Vec<u8>> {
+        let header = self.read_buf.get(..FRAME_HEADER_LEN)?;
+        let len = frame_len(header.try_into().expect("header length"));
+        if self.read_buf.len() < FRAME_HEADER_LEN + len {
+            return None;
+        }
+        let frame = self.read_buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+        self.read_buf.drain(..FRAME_HEADER_LEN + len);
+        Some(frame)
+    }
+}
+
+impl Transport for UdsTransport {
+    fn send(&mut self, buf: &[u8]) -> crate::Result<()> {
+        // 単位を伝える手段がないので常にDurationの形式で送る
+        let normalized;
+        let buf = match crate::precision::installed() {
+            Some(precision) => {
+                normalized = crate::precision::normalize(buf, precision)?;
+                &normalized[..]
+            }
+            None => buf,
+        };
+        self.stream.write_all(&frame_header(buf.len())?)?;
+        self.stream.write_all(buf)?;
+        Ok(())
+    }
+
+    fn poll(&mut self) -> crate::Result<Option<Vec<u8>>> {
+        let mut buf = [0_u8; 4096];
+        loop {
+            if let Some(frame) = self.take_frame() {
+                return Ok(Some(frame));
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(
+                        std::io::Error::new(ErrorKind::UnexpectedEof, "closed by server").into(),
+                    )
+                }
+                Ok(n) => self.read_buf.extend_from_slice(&buf[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn close(&mut self) -> crate::Result<()> {
+        self.stream.shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+
+    fn abort_handle(&self) -> Option<AbortHandle> {
+        let stream = self.stream.try_clone().ok()?;
+        Some(Box::new(move || {
+            stream.shutdown(Shutdown::Both).ok();
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixListener,
+        thread,
+    };
+
+    use super::UdsTransport;
+    use crate::{
+        protocol::{frame_header, frame_len, FRAME_HEADER_LEN},
+        transport::Transport,
+    };
+
+    #[test]
+    fn test_uds_frames() {
+        let dir = std::env::temp_dir().join(format!("uplog-uds-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.sock");
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // 2つのメッセージを1回で返す
+            let mut reply = Vec::new();
+            for x in [&b"first"[..], b"second"] {
+                reply.extend_from_slice(&frame_header(x.len()).unwrap());
+                reply.extend_from_slice(x);
+            }
+            stream.write_all(&reply).unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let mut transport = UdsTransport::connect(&path).unwrap();
+        transport.send(b"hello").unwrap();
+        transport.send(b"").unwrap();
+        let mut frames = Vec::new();
+        while frames.len() < 2 {
+            if let Some(x) = transport.poll().unwrap() {
+                frames.push(x);
+            }
+        }
+        assert_eq!(frames, vec![b"first".to_vec(), b"second".to_vec()]);
+        assert_eq!(transport.poll().unwrap(), None);
+        transport.close().unwrap();
+
+        let received = handle.join().unwrap();
+        assert_eq!(frame_len(received[..4].try_into().unwrap()), 5);
+        assert_eq!(&received[FRAME_HEADER_LEN..9], b"hello");
+        assert_eq!(&received[9..], &[0, 0, 0, 0]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,220 @@
+//! 止まった送信スレッドの見張り
+//!
+//! 送信スレッドは周期ごとに時刻を残す。書き込み側とflushは時刻が古すぎないかを確認し、
+//! 止まっていれば接続を切って同じバッファーを読む送信スレッドを作り直す。
+//! 古いスレッドは戻ってきたときに世代が変わっていることを見て何もせずに抜ける
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{protocol::CloseReason, transport::AbortHandle};
+
+/// 送信の周期がこの回数分止まったら作り直す既定値
+#[cfg(feature = "client-ws")]
+pub(crate) const DEFAULT_WATCHDOG_TICKS: u32 = 20;
+
+/// 世代を受け取って送信スレッドを起動する
+pub(crate) type Respawn = Box<dyn FnMut(u64) -> JoinHandle<()> + Send>;
+
+pub(crate) struct Watchdog {
+    /// 送信スレッドが最後に周期を終えた時刻。セッション開始からのミリ秒
+    last_beat_ms: AtomicU64,
+    /// 動いている送信スレッドの世代
+    generation: AtomicU64,
+    /// 止まったとみなす時間
+    stall: Duration,
+    /// 今の接続を切る手段
+    abort: Mutex<Option<AbortHandle>>,
+    /// 今の送信スレッド。作り直したら入れ替える
+    thread: Mutex<Option<JoinHandle<()>>>,
+    /// 終了を受け取った後に止まった場合に、次のスレッドに引き継ぐ理由
+    finish: Mutex<Option<CloseReason>>,
+    respawn: Mutex<Respawn>,
+}
+
+impl std::fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("generation", &self.generation)
+            .field("stall", &self.stall)
+            .finish()
+    }
+}
+
+impl Watchdog {
+    pub(crate) fn new(stall: Duration, respawn: Respawn) -> Self {
+        Self {
+            last_beat_ms: AtomicU64::new(crate::session::elapsed().as_millis() as u64),
+            generation: AtomicU64::new(0),
+            stall,
+            abort: Mutex::new(None),
+            thread: Mutex::new(None),
+            finish: Mutex::new(None),
+            respawn: Mutex::new(respawn),
+        }
+    }
+
+    pub(crate) fn stall(&self) -> Duration {
+        self.stall
+    }
+
+    /// 最初の送信スレッドを起動する
+    pub(crate) fn start(&self) {
+        let mut respawn = self
+            .respawn
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+        let handle = (*respawn)(0);
+        *self
+            .thread
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK) = Some(handle);
+    }
+
+    pub(crate) fn is_current(&self, generation: u64) -> bool {
+        self.generation.load(Ordering::Acquire) == generation
+    }
+
+    /// 送信スレッドが周期を終えた
+    pub(crate) fn beat(&self) {
+        self.last_beat_ms.store(
+            crate::session::elapsed().as_millis() as u64,
+            Ordering::Release,
+        );
+    }
+
+    /// 接続を切る手段を入れ替える。作り直された後の古いスレッドからは受け付けない
+    pub(crate) fn set_abort(&self, generation: u64, abort: Option<AbortHandle>) {
+        let mut slot = self
+            .abort
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+        if self.is_current(generation) {
+            *slot = abort;
+        }
+    }
+
+    /// 送信スレッドが終了の要求を受け取った
+    pub(crate) fn set_finish(&self, generation: u64, reason: CloseReason) {
+        let mut slot = self
+            .finish
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK);
+        if self.is_current(generation) {
+            *slot = Some(reason);
+        }
+    }
+
+    /// 作り直したスレッドが引き継ぐ終了の要求
+    pub(crate) fn inherited_finish(&self) -> Option<CloseReason> {
+        *self
+            .finish
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+    }
+
+    /// 今の送信スレッド
+    pub(crate) fn take_thread(&self) -> Option<JoinHandle<()>> {
+        self.thread
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+            .take()
+    }
+
+    /// 今の送信スレッドが終了しているか
+    pub(crate) fn is_finished(&self) -> bool {
+        self.thread
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+            .as_ref()
+            .is_none_or(|x| x.is_finished())
+    }
+
+    /// `now`はセッション開始からの時間。止まっていれば作り直してtrueを返す
+    pub(crate) fn check(&self, now: Duration) -> bool {
+        let last = self.last_beat_ms.load(Ordering::Acquire);
+        let now = now.as_millis() as u64;
+        if now.saturating_sub(last) < self.stall.as_millis() as u64 {
+            return false;
+        }
+        // 同時に気付いたスレッドのうち1つだけが作り直す
+        let Ok(mut respawn) = self.respawn.try_lock() else {
+            return false;
+        };
+        if self.last_beat_ms.load(Ordering::Acquire) != last {
+            return false;
+        }
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        self.last_beat_ms.store(now, Ordering::Release);
+        log::warn!(
+            "sender thread made no progress for {} ms, restarting",
+            now - last
+        );
+        let abort = self
+            .abort
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK)
+            .take();
+        if let Some(abort) = abort {
+            abort();
+        }
+        let handle = (*respawn)(generation);
+        // 古いスレッドは戻ってきたら自分で抜けるので待たない
+        *self
+            .thread
+            .lock()
+            .expect(crate::error::ERROR_MESSAGE_MUTEX_LOCK) = Some(handle);
+        crate::stats::sender_restarted();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use super::Watchdog;
+
+    #[test]
+    fn test_watchdog_check() {
+        crate::session_init();
+        let spawned = Arc::new(Mutex::new(Vec::new()));
+        let s = spawned.clone();
+        let watchdog = Watchdog::new(
+            Duration::from_millis(100),
+            Box::new(move |generation| {
+                s.lock().unwrap().push(generation);
+                std::thread::spawn(|| {})
+            }),
+        );
+        watchdog.start();
+        let aborted = Arc::new(AtomicBool::new(false));
+        let a = aborted.clone();
+        watchdog.set_abort(0, Some(Box::new(move || a.store(true, Ordering::SeqCst))));
+        watchdog.beat();
+
+        let now = crate::session::elapsed();
+        assert!(!watchdog.check(now + Duration::from_millis(50)));
+        assert!(watchdog.check(now + Duration::from_millis(200)));
+        assert!(aborted.load(Ordering::SeqCst));
+        assert!(!watchdog.is_current(0));
+        assert!(watchdog.is_current(1));
+        // 作り直した時点から数え直す
+        assert!(!watchdog.check(now + Duration::from_millis(250)));
+        // 古い世代からの登録は受け付けない
+        watchdog.set_abort(0, Some(Box::new(|| panic!("stale abort"))));
+        assert!(watchdog.check(now + Duration::from_millis(400)));
+        assert_eq!(*spawned.lock().unwrap(), [0, 1, 2]);
+    }
+}
@@ -0,0 +1,313 @@
+//! 文字列の辞書を使ったレコードの送信形式
+//!
+//! `module_path`や`file`は全てのレコードで同じ長い文字列を繰り返すため、
+//! 接続ごとの表に一度だけ送ってidで参照する。
+//! [`crate::protocol::SUBPROTOCOL_CBOR_DICT`]で交渉した接続でだけ使い、
+//! 表は接続ごとに作り直す。サーバーは通常の[`Record`]に戻してから保存する
+//!
+//! 1メッセージは[`WireItem`]のCBOR Sequenceで、構造体とenumは名前の代わりに番号で書く
+use std::{collections::HashMap, fmt, io, time::Duration};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Level, Metadata, Record, KV};
+
+/// Maximum number of strings in the table of a connection.
+///
+/// Strings seen after the table is full are sent inline.
+pub const MAX_TABLE_STRINGS: usize = 4096;
+
+/// A string sent inline or as an id of the connection table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireStr {
+    Id(u32),
+    Text(String),
+}
+
+impl Serialize for WireStr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Id(x) => serializer.serialize_u32(*x),
+            Self::Text(x) => serializer.serialize_str(x),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for WireStr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct WireStrVisitor;
+
+        impl<'de> de::Visitor<'de> for WireStrVisitor {
+            type Value = WireStr;
+
+            fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt.write_str("a string id or a string")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                u32::try_from(v)
+                    .map(WireStr::Id)
+                    .map_err(|_| E::custom(format!("string id {} is out of range", v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(WireStr::Text(v.to_string()))
+            }
+
+            fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+                Ok(WireStr::Text(v))
+            }
+        }
+
+        deserializer.deserialize_any(WireStrVisitor)
+    }
+}
+
+/// [`Record`] whose repeated strings refer to the table.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WireRecord {
+    pub level: Level,
+    pub target: WireStr,
+    /// seconds and nanoseconds, as `Duration` does not accept field numbers
+    pub elapsed: (u64, u32),
+    pub category: WireStr,
+    pub module_path: Option<WireStr>,
+    pub file: Option<WireStr>,
+    pub line: Option<u32>,
+    pub message: String,
+    #[serde(default, deserialize_with = "crate::kv::deserialize_kv")]
+    pub kv: Option<KV>,
+}
+
+/// An element of a message in the dictionary encoding.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WireItem {
+    /// assigns the next id of the table to the string
+    Define(u32, String),
+    Record(WireRecord),
+}
+
+fn invalid<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Converts CBOR records to the dictionary encoding. Create one per connection.
+#[derive(Debug, Default)]
+pub struct WireEncoder {
+    table: HashMap<String, u32>,
+}
+
+impl WireEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 表になければ割り当てを書く。表が一杯の場合はそのまま送る
+    fn intern(&mut self, s: String, out: &mut Vec<u8>) -> io::Result<WireStr> {
+        if let Some(id) = self.table.get(&s) {
+            return Ok(WireStr::Id(*id));
+        }
+        if self.table.len() >= MAX_TABLE_STRINGS {
+            return Ok(WireStr::Text(s));
+        }
+        let id = self.table.len() as u32;
+        write_item(out, &WireItem::Define(id, s.clone()))?;
+        self.table.insert(s, id);
+        Ok(WireStr::Id(id))
+    }
+
+    /// `buf`のRecordのCBOR Sequenceを1メッセージ分に変換する
+    pub fn encode(&mut self, buf: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(buf.len());
+        for record in serde_cbor::Deserializer::from_slice(buf).into_iter::<Record>() {
+            let record = record.map_err(invalid)?;
+            let item = WireRecord {
+                level: record.level(),
+                target: self.intern(record.metadata.target().to_string(), &mut out)?,
+                elapsed: (record.elapsed.as_secs(), record.elapsed.subsec_nanos()),
+                category: self.intern(record.category, &mut out)?,
+                module_path: match record.module_path {
+                    Some(x) => Some(self.intern(x, &mut out)?),
+                    None => None,
+                },
+                file: match record.file {
+                    Some(x) => Some(self.intern(x, &mut out)?),
+                    None => None,
+                },
+                line: record.line,
+                message: record.message,
+                kv: record.kv,
+            };
+            write_item(&mut out, &WireItem::Record(item))?;
+        }
+        Ok(out)
+    }
+}
+
+fn write_item(out: &mut Vec<u8>, item: &WireItem) -> io::Result<()> {
+    let mut ser = serde_cbor::Serializer::new(out).packed_format();
+    item.serialize(&mut ser).map_err(invalid)
+}
+
+/// Restores records from the dictionary encoding. Create one per connection.
+#[derive(Debug, Default)]
+pub struct WireDecoder {
+    table: Vec<String>,
+}
+
+impl WireDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn resolve(&self, s: WireStr) -> io::Result<String> {
+        match s {
+            WireStr::Text(x) => Ok(x),
+            WireStr::Id(id) => self
+                .table
+                .get(id as usize)
+                .cloned()
+                .ok_or_else(|| invalid(format!("undefined string id {}", id))),
+        }
+    }
+
+    /// 割り当ては表に加えてNoneを返す。レコードは文字列を戻して返す
+    pub fn apply(&mut self, item: WireItem) -> io::Result<Option<Record>> {
+        match item {
+            WireItem::Define(id, text) => {
+                if id as usize != self.table.len() || self.table.len() >= MAX_TABLE_STRINGS {
+                    return Err(invalid(format!(
+                        "unexpected string id {}, table has {} strings",
+                        id,
+                        self.table.len()
+                    )));
+                }
+                self.table.push(text);
+                Ok(None)
+            }
+            WireItem::Record(x) => Ok(Some(Record {
+                metadata: Metadata::new(x.level, self.resolve(x.target)?),
+                elapsed: Duration::new(x.elapsed.0, x.elapsed.1),
+                category: self.resolve(x.category)?,
+                module_path: x.module_path.map(|x| self.resolve(x)).transpose()?,
+                file: x.file.map(|x| self.resolve(x)).transpose()?,
+                line: x.line,
+                message: x.message,
+                kv: x.kv,
+            })),
+        }
+    }
+
+    /// 1メッセージ分を全て戻す
+    pub fn decode(&mut self, buf: &[u8]) -> io::Result<Vec<Record>> {
+        let mut records = Vec::new();
+        for item in serde_cbor::Deserializer::from_slice(buf).into_iter::<WireItem>() {
+            if let Some(x) = self.apply(item.map_err(invalid)?)? {
+                records.push(x);
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WireDecoder, WireEncoder, WireItem, WireStr, MAX_TABLE_STRINGS};
+    use crate::{Level, Record};
+
+    fn records(count: u32) -> (Vec<Record>, Vec<u8>) {
+        crate::session_init();
+        let records = (0..count)
+            .map(|i| match i % 3 {
+                0 => devlog!(Level::Info, "app.net", "sent", "i", i),
+                1 => devlog!(Level::Warn, "app.camera", "frame", "size", 3_u32),
+                _ => devlog!(Level::Debug, "app.net", "received"),
+            })
+            .collect::<Vec<_>>();
+        let buf = records
+            .iter()
+            .flat_map(|x| serde_cbor::to_vec(x).unwrap())
+            .collect();
+        (records, buf)
+    }
+
+    #[test]
+    fn test_wire_roundtrip() {
+        let (expect, buf) = records(30);
+        let mut encoder = WireEncoder::new();
+        let mut decoder = WireDecoder::new();
+        // 表は接続の間は引き継ぐ
+        let first = encoder.encode(&buf).unwrap();
+        let second = encoder.encode(&buf).unwrap();
+        assert!(second.len() < first.len());
+        assert!(
+            second.len() * 2 < buf.len(),
+            "{} {}",
+            second.len(),
+            buf.len()
+        );
+        assert_eq!(decoder.decode(&first).unwrap(), expect);
+        assert_eq!(decoder.decode(&second).unwrap(), expect);
+
+        // 2回目は割り当てを含まない
+        let items = serde_cbor::Deserializer::from_slice(&second)
+            .into_iter::<WireItem>()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(items.iter().all(|x| matches!(x, WireItem::Record(_))));
+        assert_eq!(encoder.encode(&[]).unwrap(), Vec::<u8>::new());
+    }
+
+    /// 再接続すると表は作り直すので、新しい接続は前の表を参照しない
+    #[test]
+    fn test_wire_table_reset() {
+        let (expect, buf) = records(6);
+        let mut encoder = WireEncoder::new();
+        encoder.encode(&buf).unwrap();
+        let continued = encoder.encode(&buf).unwrap();
+        // 前の表を知らないサーバーは戻せない
+        assert!(WireDecoder::new().decode(&continued).is_err());
+
+        let mut encoder = WireEncoder::new();
+        let mut decoder = WireDecoder::new();
+        assert_eq!(
+            decoder.decode(&encoder.encode(&buf).unwrap()).unwrap(),
+            expect
+        );
+    }
+
+    #[test]
+    fn test_wire_invalid() {
+        let mut decoder = WireDecoder::new();
+        // idは0から順に割り当てる
+        assert!(decoder.apply(WireItem::Define(1, "a".to_string())).is_err());
+        assert!(decoder
+            .apply(WireItem::Define(0, "a".to_string()))
+            .unwrap()
+            .is_none());
+        assert!(decoder.resolve(WireStr::Id(1)).is_err());
+        assert_eq!(decoder.resolve(WireStr::Id(0)).unwrap(), "a");
+        assert!(decoder.decode(b"\xff").is_err());
+    }
+
+    #[test]
+    fn test_wire_table_full() {
+        crate::session_init();
+        let categories = (0..MAX_TABLE_STRINGS + 10)
+            .map(|i| format!("cat{}", i))
+            .collect::<Vec<_>>();
+        let mut buf = Vec::new();
+        let mut expect = Vec::new();
+        for x in categories.iter() {
+            let mut r = devlog!(Level::Info, "", "msg");
+            r.category = x.clone();
+            serde_cbor::to_writer(&mut buf, &r).unwrap();
+            expect.push(r);
+        }
+        let mut encoder = WireEncoder::new();
+        let encoded = encoder.encode(&buf).unwrap();
+        // 表が一杯になった後はそのまま送る
+        assert_eq!(encoder.table.len(), MAX_TABLE_STRINGS);
+        assert_eq!(WireDecoder::new().decode(&encoded).unwrap(), expect);
+    }
+}
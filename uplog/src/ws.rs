@@ -0,0 +1,286 @@
+//! websocketでサーバーに送る通信路
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use tungstenite::{
+    client::IntoClientRequest,
+    handshake::{
+        client::{Request, Response},
+        HandshakeError,
+    },
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    stream::MaybeTlsStream,
+    Message, WebSocket,
+};
+use url::Url;
+
+use crate::{
+    precision::{self, Precision},
+    protocol::{
+        CloseReason, Codec, BUILD_INFO_HEADER, CLIENT_TIME_HEADER, SUBPROTOCOL_HEADER,
+        TIME_PRECISION_HEADER,
+    },
+    transport::{AbortHandle, Transport},
+    wire::WireEncoder,
+};
+
+/// websocketでサーバーに送信する
+pub(crate) struct WebsocketTransport {
+    socket: WebSocket<MaybeTlsStream<TcpStream>>,
+    codec: Codec,
+    /// 文字列の表。接続ごとに作るので再接続すると空から始める
+    wire: Option<WireEncoder>,
+    /// 書き込んだレコードの`elapsed`の単位
+    precision: Option<Precision>,
+    /// サーバーが単位を受け付けなかったので`Duration`の形式に戻す
+    normalize: bool,
+}
+
+impl WebsocketTransport {
+    const SERVER_MESSAGE_READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+    /// `codecs`を優先順に提示して接続する
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn connect(url: &Url, codecs: &[Codec]) -> crate::Result<Self> {
+        Self::open(url, codecs, None)
+    }
+
+    /// 接続とハンドシェイクを`timeout`で打ち切る
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn connect_timeout(
+        url: &Url,
+        codecs: &[Codec],
+        timeout: Duration,
+    ) -> crate::Result<Self> {
+        Self::open(url, codecs, Some(timeout))
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn open(url: &Url, codecs: &[Codec], timeout: Option<Duration>) -> crate::Result<Self> {
+        let mut request = url.as_str().into_client_request()?;
+        if !codecs.is_empty() {
+            let offer = Codec::offer(codecs)
+                .parse()
+                .expect("subprotocol names are valid header values");
+            request.headers_mut().insert(SUBPROTOCOL_HEADER, offer);
+        }
+        let precision = precision::installed();
+        if let Some(precision) = precision {
+            let value = precision.as_str().parse().expect("valid header value");
+            request.headers_mut().insert(TIME_PRECISION_HEADER, value);
+        }
+        if let Some(value) = crate::build_info::header_value().and_then(|x| x.parse().ok()) {
+            request.headers_mut().insert(BUILD_INFO_HEADER, value);
+        }
+        let now = crate::clock::now_unix_ms().to_string();
+        request
+            .headers_mut()
+            .insert(CLIENT_TIME_HEADER, now.parse().expect("valid header value"));
+        let (mut socket, response) = match timeout {
+            Some(timeout) => handshake_timeout(request, timeout)?,
+            None => tungstenite::client::connect(request)?,
+        };
+        let codec = match negotiated(&response, codecs) {
+            Ok(x) => x,
+            Err(e) => {
+                // 続けられないことをサーバーに伝えておく
+                let reason = CloseReason::ProtocolError;
+                socket.close(Some(close_frame(reason))).ok();
+                crate::health::record_close(reason, false);
+                return Err(e);
+            }
+        };
+        let accepted = response
+            .headers()
+            .get(TIME_PRECISION_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse::<Precision>().ok());
+        log::debug!("connected with {}", codec.subprotocol());
+        // サーバーからの通知を待たずに読めるようにする
+        if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+            stream.set_read_timeout(Some(Self::SERVER_MESSAGE_READ_TIMEOUT))?;
+        }
+        Ok(Self {
+            socket,
+            codec,
+            wire: (codec == Codec::CborDict).then(WireEncoder::new),
+            precision,
+            normalize: precision.is_some() && accepted != precision,
+        })
+    }
+
+    /// 閉じる応答が届くまで読み捨てる。サーバーが返す受信の応答もここで読む
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn wait_closed(
+        &mut self,
+        deadline: Instant,
+        timeout: Duration,
+    ) -> crate::Result<()> {
+        loop {
+            if Instant::now() >= deadline {
+                return Err(crate::Error::Timeout(timeout));
+            }
+            match self.socket.read_message() {
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+                Err(tungstenite::Error::Io(e)) if is_timeout(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn codec(&self) -> Codec {
+        self.codec
+    }
+}
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// 読み書きにも上限を付けたソケットでハンドシェイクする
+#[allow(clippy::result_large_err)]
+fn handshake_timeout(request: Request, timeout: Duration) -> crate::Result<(Socket, Response)> {
+    let deadline = Instant::now() + timeout;
+    let uri = request.uri();
+    let host = uri.host().unwrap_or_default();
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("wss") {
+            443
+        } else {
+            80
+        });
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, host.to_string()))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| match e.kind() {
+        std::io::ErrorKind::TimedOut => crate::Error::Timeout(timeout),
+        _ => e.into(),
+    })?;
+    stream.set_nodelay(true)?;
+    let rest = deadline
+        .saturating_duration_since(Instant::now())
+        .max(Duration::from_millis(1));
+    stream.set_read_timeout(Some(rest))?;
+    stream.set_write_timeout(Some(rest))?;
+    #[cfg(feature = "tls")]
+    let result = tungstenite::client_tls(request, stream);
+    #[cfg(not(feature = "tls"))]
+    let result = tungstenite::client(request, MaybeTlsStream::Plain(stream));
+    result.map_err(|e| match e {
+        HandshakeError::Failure(tungstenite::Error::Io(e)) if is_timeout(&e) => {
+            crate::Error::Timeout(timeout)
+        }
+        HandshakeError::Failure(e) => e.into(),
+        // 読み込みの上限で止まった
+        HandshakeError::Interrupted(_) => crate::Error::Timeout(timeout),
+    })
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+fn close_frame(reason: CloseReason) -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::from(reason.code()),
+        reason: reason.as_str().into(),
+    }
+}
+
+/// サーバーが選んだcodec。古いサーバーは何も返さないので従来のCBORとする
+#[allow(clippy::result_large_err)]
+fn negotiated(response: &Response, offered: &[Codec]) -> crate::Result<Codec> {
+    let selected = match response.headers().get(SUBPROTOCOL_HEADER) {
+        Some(x) => x,
+        None => return Ok(Codec::Cbor),
+    };
+    selected
+        .to_str()
+        .ok()
+        .and_then(Codec::from_subprotocol)
+        .filter(|x| offered.contains(x))
+        .ok_or_else(|| {
+            crate::Error::Handshake(format!(
+                "server selected subprotocol {:?} which was not offered ({})",
+                selected,
+                Codec::offer(offered)
+            ))
+        })
+}
+
+impl Transport for WebsocketTransport {
+    fn send(&mut self, buf: &[u8]) -> crate::Result<()> {
+        let normalized;
+        let buf = match (self.normalize, self.precision) {
+            (true, Some(precision)) => {
+                normalized = precision::normalize(buf, precision)?;
+                &normalized[..]
+            }
+            _ => buf,
+        };
+        let encoded;
+        let buf = match self.wire.as_mut() {
+            Some(wire) => {
+                encoded = precision::decode_with(self.precision, || wire.encode(buf))?;
+                &encoded[..]
+            }
+            None => buf,
+        };
+        let frame = self.codec.encode(buf)?;
+        self.socket
+            .write_message(Message::binary(frame.into_owned()))?;
+        Ok(())
+    }
+
+    fn poll(&mut self) -> crate::Result<Option<Vec<u8>>> {
+        loop {
+            match self.socket.read_message() {
+                Ok(Message::Binary(bin)) => return Ok(Some(bin)),
+                Ok(Message::Close(frame)) => {
+                    // 知らない理由も番号のまま残す
+                    let reason = frame.map_or(CloseReason::Unknown(1005), |x| {
+                        CloseReason::from_code(x.code.into())
+                    });
+                    log::info!("closed by server: {}", reason);
+                    crate::health::record_close(reason, true);
+                }
+                // サーバーはテキストを送らない。CBORとして読めないので受け取った側で数える
+                Ok(Message::Text(text)) => return Ok(Some(text.into_bytes())),
+                // 応答はtungsteniteが返す
+                Ok(Message::Ping(_)) | Ok(Message::Pong(_)) => {}
+                // UTF-8でないテキストも読めないメッセージとして扱う
+                Err(tungstenite::Error::Utf8) => return Ok(Some(Vec::new())),
+                Err(tungstenite::Error::Io(e)) if is_timeout(&e) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn close(&mut self) -> crate::Result<()> {
+        self.close_with(CloseReason::Flush)
+    }
+
+    fn close_with(&mut self, reason: CloseReason) -> crate::Result<()> {
+        self.socket.close(Some(close_frame(reason)))?;
+        Ok(())
+    }
+
+    fn abort_handle(&self) -> Option<AbortHandle> {
+        let stream = match self.socket.get_ref() {
+            MaybeTlsStream::Plain(x) => x.try_clone().ok()?,
+            #[cfg(feature = "tls")]
+            MaybeTlsStream::NativeTls(x) => x.get_ref().try_clone().ok()?,
+            _ => return None,
+        };
+        Some(Box::new(move || {
+            stream.shutdown(std::net::Shutdown::Both).ok();
+        }))
+    }
+}
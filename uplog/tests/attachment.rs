@@ -0,0 +1,49 @@
+//! 添付ファイルを上限に収まる部分に分けて送ることを確認する
+#![cfg(feature = "client-ws")]
+use std::{collections::BTreeMap, time::Duration};
+
+use uplog::{testing::TestCollector, Attachment, ATTACHMENT_KEY};
+
+#[test]
+fn test_attach_chunks() {
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .max_record_bytes(1024)
+        .try_init()
+        .unwrap();
+    let dump = (0..1500_u32).map(|x| x as u8).collect::<Vec<_>>();
+    let attachment = Attachment::new("core.dmp", &dump).chunk_size(600);
+    assert_eq!(attachment.parts(), 3);
+    assert!(attachment.send("test.attach").is_accepted());
+    // 部分の大きさを決めなければ上限に収める
+    let config = vec![b'x'; 3000];
+    assert!(uplog::attach("test.attach", "config.txt", &config).is_accepted());
+    uplog::flush();
+    collector.wait_for_close(Duration::from_secs(5)).unwrap();
+
+    let mut files = BTreeMap::<String, Vec<(u64, Vec<u8>)>>::new();
+    for record in collector.records() {
+        if let Some(map) = record
+            .kv
+            .as_ref()
+            .and_then(|x| x.get(ATTACHMENT_KEY))
+            .and_then(|x| x.as_map())
+        {
+            files
+                .entry(map["filename"].as_str().unwrap().to_string())
+                .or_default()
+                .push((
+                    map["part"].as_u64().unwrap(),
+                    map["bytes"].as_bytes().unwrap().to_vec(),
+                ));
+        }
+    }
+    let join =
+        |parts: &[(u64, Vec<u8>)]| parts.iter().flat_map(|x| x.1.clone()).collect::<Vec<_>>();
+    assert_eq!(files["core.dmp"].len(), 3);
+    assert_eq!(join(&files["core.dmp"]), dump);
+    assert!(files["config.txt"].len() > 3);
+    assert_eq!(join(&files["config.txt"]), config);
+    assert_eq!(uplog::stats_snapshot().records_rejected_oversize, 0);
+}
@@ -0,0 +1,62 @@
+//! 送信スレッドを使わずに送る関数が戻った時点でサーバーに届いていることを確認する
+#![cfg(feature = "client-ws")]
+use std::{
+    net::TcpListener,
+    time::{Duration, Instant},
+};
+
+use uplog::{devlog, info, protocol::CloseReason, testing::TestCollector, Builder, Error, Level};
+
+#[test]
+fn test_send_records_blocking() {
+    uplog::session_init();
+    let collector = TestCollector::start().unwrap();
+    // 最小のバッファに収まらない数を送って複数のフレームに分ける
+    let records = (0..200_u32)
+        .map(|i| devlog!(Level::Info, "test.blocking", "record", "i", i))
+        .collect::<Vec<_>>();
+    let builder = Builder::default()
+        .port(collector.port())
+        .buffer_size(uplog::MIN_BUFFER_SIZE);
+    uplog::send_records_blocking(&builder, &records).unwrap();
+
+    assert_eq!(collector.records(), records);
+    assert_eq!(collector.close_reason(), Some(CloseReason::Flush));
+}
+
+#[test]
+fn test_with_blocking_logger() {
+    let collector = TestCollector::start().unwrap();
+    let builder = Builder::default().port(collector.port());
+    let value = uplog::with_blocking_logger(&builder, || {
+        info!("test.blocking", "first");
+        info!("test.blocking", "second", "n", 2_u32);
+        42
+    })
+    .unwrap();
+    assert_eq!(value, 42);
+
+    let messages = collector
+        .records()
+        .into_iter()
+        .map(|x| x.message)
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["first", "second"]);
+}
+
+#[test]
+fn test_blocking_timeout() {
+    // 接続は受けるがハンドシェイクに答えない
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let handle = std::thread::spawn(move || listener.accept().map(|(stream, _)| stream));
+
+    let builder = Builder::default()
+        .port(port)
+        .blocking_timeout(Duration::from_millis(200));
+    let start = Instant::now();
+    let result = uplog::send_records_blocking(&builder, &[]);
+    assert!(matches!(result, Err(Error::Timeout(_))), "{:?}", result);
+    assert!(start.elapsed() < Duration::from_secs(2));
+    drop(handle.join().unwrap());
+}
@@ -0,0 +1,31 @@
+//! 書くのをやめたカテゴリも破棄した量の集計が届くことを確認する
+#![cfg(feature = "client-ws")]
+use std::time::Duration;
+
+use uplog::{info, testing::TestCollector, BUDGET_CATEGORY};
+
+#[test]
+fn test_budget_report_after_burst() {
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .category_byte_budget("test.burst", 16)
+        .duration(Duration::from_millis(50))
+        .try_init()
+        .unwrap();
+    let payload = "x".repeat(32);
+    for _ in 0..5 {
+        info!("test.burst", "burst", "payload", payload.as_str());
+    }
+    // 以降はこのカテゴリに書かない
+    let report = collector
+        .wait_for_records(1, Duration::from_secs(5))
+        .unwrap()
+        .into_iter()
+        .find(|x| x.category == BUDGET_CATEGORY)
+        .expect("budget report");
+    let kv = report.kv.unwrap();
+    assert_eq!(kv["prefix"], uplog::Value::Text("test.burst".to_string()));
+    assert_eq!(kv["dropped_records"], uplog::Value::U64(5));
+    uplog::flush();
+}
@@ -0,0 +1,30 @@
+//! websocketを使わずに初期化して、書いたレコードがそのまま戻ることを確認する
+//!
+//! 既定の機能を外した構成でも動くことを確かめるテストなので、サーバーに関わるものは使わない
+use uplog::{info, warn, Level, Value, CLIENT_CATEGORY};
+
+#[test]
+fn test_capture_round_trip() {
+    let transport = uplog::init_capture().unwrap();
+    assert_eq!(
+        uplog::init_capture().unwrap_err(),
+        uplog::InitError::AlreadyInitialized
+    );
+    info!("app", "started");
+    warn!("app.net", "retry", "count", 3_u32, "host", "example");
+    let report = uplog::flush().unwrap();
+    assert!(report.transport_ok, "{}", report);
+
+    let records = transport
+        .records()
+        .into_iter()
+        .filter(|x| x.category != CLIENT_CATEGORY)
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].message, "started");
+    assert_eq!(records[1].level(), Level::Warn);
+    assert_eq!(records[1].category, "app.net");
+    let kv = records[1].key_values().unwrap();
+    assert_eq!(kv["count"], Value::U64(3));
+    assert_eq!(kv["host"], Value::Text("example".to_string()));
+}
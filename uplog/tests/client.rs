@@ -0,0 +1,36 @@
+//! マクロで書いたレコードがサーバーに届くことを確認する
+#![cfg(feature = "client-ws")]
+use std::time::Duration;
+
+use uplog::{debug, error, info, testing::TestCollector, trace, warn};
+
+#[test]
+fn test_client() {
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .try_init()
+        .unwrap();
+    trace!("test.base", "hello", "cats", "meow", "nekomimi", true);
+    debug!("test.base", "hello", "cats", "meow");
+    info!("test.base", "hello", "cat", "mii");
+    warn!("test.base", "hello", "cat", "aooo");
+    error!("test.base", "hello", "cat", "grrr");
+    let report = uplog::flush().unwrap();
+    assert!(report.transport_ok, "{}", report);
+    collector.wait_for_close(Duration::from_secs(5)).unwrap();
+
+    let records = collector.records();
+    assert_eq!(records.len(), 5);
+    for r in records.iter() {
+        assert_eq!(r.category.as_str(), "test.base");
+        assert_eq!(r.message.as_str(), "hello");
+        assert!(!r.kv.as_ref().unwrap().is_empty());
+    }
+    // 送信スレッドが接続状態のレコードを挟む
+    assert!(collector.all_records().len() > records.len());
+    assert_eq!(
+        collector.handshake_path().as_deref(),
+        Some(uplog::INGEST_PATH)
+    );
+}
@@ -0,0 +1,51 @@
+//! 接続を閉じた理由がサーバーとクライアントの間で伝わることを確認する
+#![cfg(feature = "client-ws")]
+use std::time::{Duration, Instant};
+
+use uplog::{info, protocol::CloseReason, testing::TestCollector};
+
+/// クライアントの状態に閉じた理由が残るまで待つ
+fn wait_for_close_reason(reason: CloseReason) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while uplog::health().last_close_reason != Some(reason) {
+        assert!(Instant::now() < deadline, "{:?}", uplog::health());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn test_close_reason() {
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .duration(Duration::from_millis(20))
+        .try_init()
+        .unwrap();
+    info!("test.close", "first");
+    collector
+        .wait_for_records(1, Duration::from_secs(5))
+        .unwrap();
+
+    // サーバーが理由を付けて閉じると記録して再接続する
+    collector.close_with(CloseReason::Quota);
+    wait_for_close_reason(CloseReason::Quota);
+    assert!(uplog::health().closed_by_server);
+    collector
+        .wait_for_connections(2, Duration::from_secs(5))
+        .unwrap();
+
+    // 知らない番号も番号のまま受け取る
+    collector.close_with(CloseReason::Unknown(4999));
+    wait_for_close_reason(CloseReason::Unknown(4999));
+    collector
+        .wait_for_connections(3, Duration::from_secs(5))
+        .unwrap();
+
+    info!("test.close", "last");
+    uplog::flush();
+    collector.wait_for_close(Duration::from_secs(5)).unwrap();
+    assert_eq!(collector.close_reason(), Some(CloseReason::Flush));
+    let health = uplog::health();
+    assert_eq!(health.last_close_reason, Some(CloseReason::Flush));
+    assert!(!health.closed_by_server);
+}
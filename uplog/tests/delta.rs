@@ -0,0 +1,40 @@
+//! 同じカテゴリの直前のレコードからの経過時間を付けることを確認する
+#![cfg(feature = "client-ws")]
+use std::time::Duration;
+
+use uplog::{info, testing::TestCollector, DELTA_KEY};
+
+#[test]
+fn test_emit_deltas() {
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .emit_deltas(true)
+        .try_init()
+        .unwrap();
+    info!("test.delta", "first");
+    std::thread::sleep(Duration::from_millis(20));
+    info!("test.delta", "second");
+    info!("test.other", "other");
+    uplog::flush();
+    collector.wait_for_close(Duration::from_secs(5)).unwrap();
+
+    let deltas = collector
+        .records()
+        .into_iter()
+        .filter(|x| x.category.starts_with("test."))
+        .map(|x| {
+            let delta = x.kv.as_ref().and_then(|kv| kv.get(DELTA_KEY));
+            (x.message, delta.and_then(|x| x.as_u64()))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(deltas.len(), 3, "{:?}", deltas);
+    assert_eq!(deltas[0], ("first".to_string(), None));
+    assert!(
+        matches!(deltas[1].1, Some(x) if x >= 20_000),
+        "{:?}",
+        deltas
+    );
+    // カテゴリごとに数える
+    assert_eq!(deltas[2], ("other".to_string(), None));
+}
@@ -0,0 +1,64 @@
+//! 機能ごとにビルドできることを確認する
+//!
+//! `cargo hack --each-feature`と同じことを行う。依存をビルドし直すので時間がかかり、既定では実行しない
+//!
+//! ```sh
+//! cargo test -p uplog --test features -- --ignored
+//! ```
+use std::{path::Path, process::Command};
+
+/// 単独で有効にする機能。既定の`client-ws`は機能なしの構成で外す
+const FEATURES: &[&str] = &[
+    "client-ws",
+    "tls",
+    "file-sink",
+    "tracing",
+    "redact-regex",
+    "category-regex",
+    "uds",
+    "test-util",
+];
+
+fn cargo(args: &[&str]) {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    // 実行中のテストのビルドと競合しないよう別の出力先を使う
+    let target = manifest.join("../target/feature-check");
+    let status = Command::new(env!("CARGO"))
+        .current_dir(manifest)
+        .env("CARGO_TARGET_DIR", target)
+        .args(args)
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "cargo {}", args.join(" "));
+}
+
+#[test]
+#[ignore]
+fn test_each_feature() {
+    cargo(&["check", "--lib", "--no-default-features"]);
+    for feature in FEATURES {
+        cargo(&[
+            "check",
+            "--lib",
+            "--no-default-features",
+            "--features",
+            feature,
+        ]);
+    }
+    cargo(&["check", "--lib", "--all-features"]);
+}
+
+/// ネットワークの機能なしでもコアのテストが通る
+#[test]
+#[ignore]
+fn test_core_without_default_features() {
+    cargo(&[
+        "test",
+        "--no-default-features",
+        "--lib",
+        "--test",
+        "capture",
+        "--test",
+        "macros",
+    ]);
+}
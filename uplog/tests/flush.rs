@@ -0,0 +1,22 @@
+//! レコードを書かずに終了しても接続を閉じて報告を返すことを確認する
+#![cfg(feature = "client-ws")]
+use std::time::Duration;
+
+use uplog::testing::TestCollector;
+
+#[test]
+fn test_flush_without_records() {
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .try_init()
+        .unwrap();
+    let report = uplog::flush().unwrap();
+    assert!(report.transport_ok, "{}", report);
+    assert_eq!(report.records_dropped, 0);
+    collector.wait_for_close(Duration::from_secs(5)).unwrap();
+    assert!(collector.records().is_empty());
+
+    // 送信スレッドは終わっている
+    assert!(uplog::flush().is_none());
+}
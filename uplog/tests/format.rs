@@ -0,0 +1,65 @@
+//! レコードを文字列にする間に確保が起きないことを確認する
+//!
+//! 確保を数えるアロケーターを使うので、他のテストと別のバイナリにしている
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use uplog::{devlog, ElapsedStyle, KvStyle, Level, RecordFormatter};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn test_format_without_allocation() {
+    uplog::session_init();
+    let mut record = devlog!(
+        Level::Info,
+        "app",
+        "a long message",
+        "id",
+        1_u64,
+        "ok",
+        true
+    );
+    // 位置がないレコードでも文字列を作らない
+    record.file = None;
+    let formatters = [
+        RecordFormatter::new(),
+        RecordFormatter::new()
+            .elapsed(ElapsedStyle::Hms)
+            .location(false)
+            .kv(KvStyle::Multiline)
+            .max_message_width(Some(6)),
+    ];
+    let mut buf = String::with_capacity(1024);
+    for formatter in formatters.iter() {
+        buf.clear();
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        write!(buf, "{}", formatter.format(&record)).unwrap();
+        let allocated = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        assert_eq!(allocated, 0, "{:?} allocated for {}", formatter, buf);
+    }
+
+    buf.clear();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    write!(buf, "{}", record).unwrap();
+    assert_eq!(ALLOCATIONS.load(Ordering::Relaxed) - before, 0);
+}
@@ -0,0 +1,58 @@
+//! kvの数とキーの長さを制限し、印を付けることを確認する
+#![cfg(feature = "client-ws")]
+use std::time::Duration;
+
+use uplog::{info, testing::TestCollector, KV_TRUNCATED_KEY};
+
+#[test]
+fn test_kv_limits() {
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .max_kv_entries(3)
+        .max_key_len(8)
+        .try_init()
+        .unwrap();
+    info!("test.small", "small", "a", 1_u8, "b", 2_u8);
+    info!(
+        "test.large",
+        "large", "a", 1_u8, "b", 2_u8, "c", 3_u8, "d", 4_u8, "e", 5_u8
+    );
+    info!("test.long", "long", "request_header", "x", "z", 0_u8);
+    uplog::flush();
+    collector.wait_for_close(Duration::from_secs(5)).unwrap();
+
+    let keys = collector
+        .records()
+        .into_iter()
+        .filter(|x| x.category.starts_with("test."))
+        .map(|x| {
+            let kv = x.kv.unwrap_or_default();
+            let truncated = kv.get(KV_TRUNCATED_KEY).and_then(|x| x.as_u64());
+            (x.message, kv.into_keys().collect::<Vec<_>>(), truncated)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(keys.len(), 3, "{:?}", keys);
+    // 上限以内ならそのまま
+    assert_eq!(
+        keys[0],
+        ("small".to_string(), vec!["a".into(), "b".into()], None)
+    );
+    // キーの順に残し、除いた数を付ける
+    assert_eq!(
+        keys[1],
+        (
+            "large".to_string(),
+            vec![KV_TRUNCATED_KEY.into(), "a".into(), "b".into(), "c".into()],
+            Some(2)
+        )
+    );
+    assert_eq!(
+        keys[2],
+        (
+            "long".to_string(),
+            vec!["request~".into(), "z".into()],
+            None
+        )
+    );
+}
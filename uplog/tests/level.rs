@@ -0,0 +1,38 @@
+//! 出力レベルより低いレコードは送らないことを確認する
+#![cfg(feature = "client-ws")]
+use std::time::Duration;
+
+use uplog::{debug, error, info, testing::TestCollector, trace, warn, Level};
+
+#[test]
+fn test_level_filter() {
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .duration(Duration::from_millis(20))
+        .level(Level::Info)
+        .try_init()
+        .unwrap();
+    trace!("test.level", "trace");
+    debug!("test.level", "debug");
+    info!("test.level", "info");
+    warn!("test.level", "warn");
+    error!("test.level", "error");
+    collector
+        .wait_for_records(3, Duration::from_secs(5))
+        .unwrap();
+
+    // 実行中に変更したレベルに従う
+    uplog::set_level(Level::Error, None);
+    warn!("test.level", "warn after");
+    error!("test.level", "error after");
+    uplog::flush();
+    collector.wait_for_close(Duration::from_secs(5)).unwrap();
+
+    let messages = collector
+        .records()
+        .into_iter()
+        .map(|x| x.message)
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["info", "warn", "error", "error after"]);
+}
@@ -0,0 +1,12 @@
+//! クライアントを初期化せずにマクロを呼べることを確認する
+use uplog::{debug, info, trace, warn};
+
+#[test]
+fn test_macros_without_client() {
+    uplog::session_init();
+    info!("test.base", "hello");
+    warn!("test.base", "hello");
+    debug!("test.base", "hello", "cats", "meow");
+    trace!("test.base", "hello", "cats", "meow", "nekomimi", true);
+    assert!(uplog::flush().is_none());
+}
@@ -0,0 +1,42 @@
+//! マクロが返す値でレコードの扱いがわかることを確認する
+#![cfg(feature = "client-ws")]
+use std::time::Duration;
+
+use uplog::{debug, info, LogOutcome, MockTransport};
+
+#[test]
+fn test_log_outcome() {
+    let transport = MockTransport::capture();
+    uplog::Builder::default()
+        .level(uplog::Level::Info)
+        .category_byte_budget("test.sampled", 1)
+        .max_record_bytes(256)
+        // 送信しないのでバッファーはいずれ埋まる
+        .duration(Duration::from_secs(3600))
+        .try_init_with_transport(transport.clone())
+        .unwrap();
+
+    assert_eq!(info!("test.outcome", "accepted"), LogOutcome::Accepted);
+    assert!(info!("test.outcome", "kv", "key", 1_u32).is_accepted());
+    assert_eq!(
+        debug!("test.outcome", "filtered"),
+        LogOutcome::FilteredLevel
+    );
+    let large = "x".repeat(1024);
+    assert_eq!(
+        info!("test.outcome", "large", "payload", large.as_str()),
+        LogOutcome::Oversized
+    );
+    // 1レコードの上限の分だけ続けて書ける
+    let sampled = (0..100)
+        .map(|_| info!("test.sampled", "sampled"))
+        .collect::<Vec<_>>();
+    assert!(sampled.contains(&LogOutcome::Sampled), "{:?}", sampled);
+
+    let payload = "x".repeat(64);
+    let full = (0..100_000)
+        .map(|_| info!("test.outcome", "fill", "payload", payload.as_str()))
+        .find(|x| *x != LogOutcome::Accepted);
+    assert_eq!(full, Some(LogOutcome::DroppedFull));
+    uplog::flush();
+}
@@ -0,0 +1,29 @@
+//! 上限を超えるレコードは送らずに数えることを確認する
+#![cfg(feature = "client-ws")]
+use std::time::Duration;
+
+use uplog::{info, testing::TestCollector};
+
+#[test]
+fn test_oversized_record_rejection() {
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .max_record_bytes(256)
+        .try_init()
+        .unwrap();
+    let large = "x".repeat(1024);
+    info!("test.oversize", "small");
+    info!("test.oversize", "large", "payload", large.as_str());
+    info!("test.oversize", "small");
+    uplog::flush();
+    collector.wait_for_close(Duration::from_secs(5)).unwrap();
+
+    let messages = collector
+        .records()
+        .into_iter()
+        .map(|x| x.message)
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["small", "small"]);
+    assert_eq!(uplog::stats_snapshot().records_rejected_oversize, 1);
+}
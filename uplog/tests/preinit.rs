@@ -0,0 +1,31 @@
+//! 初期化前に書いたレコードが初期化後に最初に届くことを確認する
+#![cfg(feature = "client-ws")]
+use std::{thread, time::Duration};
+
+use uplog::{info, testing::TestCollector, warn, LogOutcome};
+
+#[test]
+fn test_records_before_init() {
+    // 既定の設定のまま、session_initを呼ばずに書く
+    assert_eq!(info!("test.preinit", "first"), LogOutcome::Accepted);
+    warn!("test.preinit", "second", "n", 2_u32);
+    info!("test.preinit", "third");
+    thread::sleep(Duration::from_millis(20));
+
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .try_init()
+        .unwrap();
+    info!("test.preinit", "after");
+    uplog::flush().unwrap();
+
+    let records = collector.records();
+    let messages = records.iter().map(|x| x.message()).collect::<Vec<_>>();
+    assert_eq!(messages, ["first", "second", "third", "after"]);
+    assert_eq!(records[1].key_values().unwrap()["n"], uplog::Value::U64(2));
+    // 書いたときの時刻のまま
+    assert!(records[2].elapsed() < Duration::from_millis(20));
+    assert!(records[3].elapsed() >= Duration::from_millis(20));
+    assert!(records.windows(2).all(|x| x[0].elapsed() <= x[1].elapsed()));
+}
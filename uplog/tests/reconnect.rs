@@ -0,0 +1,42 @@
+//! サーバーが切断しても再接続して送り続けることを確認する
+#![cfg(feature = "client-ws")]
+use std::time::Duration;
+
+use uplog::{info, testing::TestCollector, CLIENT_CATEGORY};
+
+#[test]
+fn test_reconnect() {
+    let collector = TestCollector::start().unwrap();
+    uplog::Builder::default()
+        .port(collector.port())
+        .duration(Duration::from_millis(20))
+        .try_init()
+        .unwrap();
+    info!("test.reconnect", "before");
+    collector
+        .wait_for_records(1, Duration::from_secs(5))
+        .unwrap();
+
+    collector.disconnect();
+    collector
+        .wait_for_connections(2, Duration::from_secs(5))
+        .unwrap();
+    info!("test.reconnect", "after");
+    uplog::flush();
+    collector.wait_for_close(Duration::from_secs(5)).unwrap();
+
+    let messages = collector
+        .records()
+        .into_iter()
+        .map(|x| x.message)
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["before", "after"]);
+    let status = collector
+        .all_records()
+        .into_iter()
+        .filter(|x| x.category == CLIENT_CATEGORY)
+        .map(|x| x.message)
+        .collect::<Vec<_>>();
+    assert!(status.iter().any(|x| x == "reconnected"), "{:?}", status);
+    assert!(uplog::stats_snapshot().reconnects >= 1);
+}
@@ -0,0 +1,82 @@
+//! マクロで指定したターゲットがモジュールパスと別に記録され、ターゲットで絞れることを確認する
+use std::collections::BTreeMap;
+
+use serde_cbor::Value;
+use uplog::{devlog, info, warn, Level, Record, CLIENT_CATEGORY};
+
+/// シリアライズしたレコードのフィールド
+fn fields(record: &Record) -> BTreeMap<String, Value> {
+    let Value::Map(map) = serde_cbor::value::to_value(record).unwrap() else {
+        panic!("record is not a map");
+    };
+    map.into_iter()
+        .map(|(k, v)| match k {
+            Value::Text(k) => (k, v),
+            _ => panic!("key is not text"),
+        })
+        .collect()
+}
+
+fn target_of(record: &Record) -> Value {
+    let Value::Map(metadata) = &fields(record)["metadata"] else {
+        panic!("metadata is not a map");
+    };
+    metadata[&Value::Text("target".into())].clone()
+}
+
+#[test]
+fn test_devlog_target() {
+    let r: Record = devlog!(Level::Info, "app", "plain");
+    assert_eq!(r.target(), module_path!());
+    assert_eq!(target_of(&r), Value::Text(module_path!().into()));
+
+    let r: Record = devlog!(target: "payments", Level::Info, "app", "charged", "amount", 3);
+    assert_eq!(r.target(), "payments");
+    assert_eq!(r.module_path().map(String::as_str), Some(module_path!()));
+    assert_eq!(r.key_values().unwrap()["amount"], uplog::Value::I64(3));
+    let fields = fields(&r);
+    assert_eq!(target_of(&r), Value::Text("payments".into()));
+    assert_eq!(fields["module_path"], Value::Text(module_path!().into()));
+    assert_eq!(fields["category"], Value::Text("app".into()));
+}
+
+#[test]
+fn test_log_target() {
+    let transport = uplog::init_capture().unwrap();
+    info!("app", "plain");
+    info!(target: "payments", "app", "charged");
+    warn!(target: "payments::card", "app", "declined", "code", 51_u32);
+    // ターゲットの設定がカテゴリの設定より優先する
+    uplog::set_target_level(Level::Warn, "payments");
+    info!(target: "payments::card", "app", "filtered");
+    info!(target: "payments_v2", "app", "other target");
+    uplog::log!(target: "payments", Level::Error, "app", "failed");
+    uplog::flush().unwrap();
+
+    let records = transport
+        .records()
+        .into_iter()
+        .filter(|x| x.category != CLIENT_CATEGORY)
+        .collect::<Vec<_>>();
+    let summary = records
+        .iter()
+        .map(|x| (x.message.as_str(), x.target()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        [
+            ("plain", module_path!()),
+            ("charged", "payments"),
+            ("declined", "payments::card"),
+            ("other target", "payments_v2"),
+            ("failed", "payments"),
+        ]
+    );
+    assert!(records
+        .iter()
+        .all(|x| x.module_path().map(String::as_str) == Some(module_path!())));
+    assert_eq!(
+        records[2].key_values().unwrap()["code"],
+        uplog::Value::U64(51)
+    );
+}